@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::time::{Duration, Instant};
 use text_generation_client::{
     Batch, CachedBatch, ClientError, NextTokenChooserParameters, Request, ShardedClient,
@@ -149,8 +150,17 @@ async fn prefill(
                 max_new_tokens: decode_length,
                 stop_sequences: vec![],
                 ignore_eos_token: true, // Will not stop even if a eos token is generated
+                stop_after_tool_call: false,
+                deadline: None,
+                eos_probability_threshold: None,
+                eos_probability_window: 0,
             }),
             top_n_tokens: top_n_tokens.unwrap_or(0),
+            skip_special_tokens: true,
+            extensions: HashMap::new(),
+            prefill_group: 0,
+            adapter_id: String::new(),
+            adapter_parameters: None,
         })
         .collect();
 
@@ -47,6 +47,9 @@ pub async fn run(
         watermark,
         grammar: String::new(),
         grammar_type: GrammarType::None as i32,
+        suppressed_tokens: vec![],
+        temperature_schedule: vec![],
+        logit_bias: std::collections::HashMap::new(),
     };
 
     // Initialize terminal properties
@@ -68,7 +68,7 @@ class Client:
         self,
         messages: List[Message],
         frequency_penalty: Optional[float] = None,
-        logit_bias: Optional[List[float]] = None,
+        logit_bias: Optional[Dict[int, float]] = None,
         logprobs: Optional[bool] = None,
         top_logprobs: Optional[int] = None,
         max_tokens: Optional[int] = None,
@@ -90,8 +90,9 @@ class Client:
             frequency_penalty (`float`):
                 The parameter for frequency penalty. 0.0 means no penalty. See [this
                 paper](https://arxiv.org/pdf/1909.05858.pdf) for more details.
-            logit_bias (`List[float]`):
-                Adjust the likelihood of specified tokens
+            logit_bias (`Dict[int, float]`):
+                Bias added to the logits of the given token ids, from -100 (banned) to
+                100 (exclusively selected)
             logprobs (`bool`):
                 Include log probabilities in the response
             top_logprobs (`int`):
@@ -436,7 +437,7 @@ class AsyncClient:
         self,
         messages: List[Message],
         frequency_penalty: Optional[float] = None,
-        logit_bias: Optional[List[float]] = None,
+        logit_bias: Optional[Dict[int, float]] = None,
         logprobs: Optional[bool] = None,
         top_logprobs: Optional[int] = None,
         max_tokens: Optional[int] = None,
@@ -458,8 +459,9 @@ class AsyncClient:
             frequency_penalty (`float`):
                 The parameter for frequency penalty. 0.0 means no penalty. See [this
                 paper](https://arxiv.org/pdf/1909.05858.pdf) for more details.
-            logit_bias (`List[float]`):
-                Adjust the likelihood of specified tokens
+            logit_bias (`Dict[int, float]`):
+                Bias added to the logits of the given token ids, from -100 (banned) to
+                100 (exclusively selected)
             logprobs (`bool`):
                 Include log probabilities in the response
             top_logprobs (`int`):
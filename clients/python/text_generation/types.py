@@ -1,6 +1,6 @@
 from enum import Enum
 from pydantic import BaseModel, validator
-from typing import Optional, List, Union, Any
+from typing import Dict, Optional, List, Union, Any
 
 from text_generation.errors import ValidationError
 
@@ -111,8 +111,8 @@ class ChatRequest(BaseModel):
     messages: List[Message]
     # Penalty for frequency of new tokens
     frequency_penalty: Optional[float] = None
-    # Bias values for token selection
-    logit_bias: Optional[List[float]] = None
+    # Bias added to the logits of the given token ids, from -100 to 100
+    logit_bias: Optional[Dict[int, float]] = None
     # Whether to return log probabilities
     logprobs: Optional[bool] = None
     # Number of most likely tokens to return at each position
@@ -314,6 +314,16 @@ class FinishReason(str, Enum):
     EndOfSequenceToken = "eos_token"
     # the model generated a text included in `stop_sequences`
     StopSequence = "stop_sequence"
+    # the end of sequence token was likely enough over the last steps
+    EndOfSequenceProbability = "eos_probability"
+    # the generation was aborted through the admin API
+    Aborted = "aborted"
+    # the generation was cancelled by its client
+    Cancelled = "cancelled"
+    # the generation was ended by the server shutdown
+    Shutdown = "shutdown"
+    # the generation was preempted by the scheduler
+    Preempted = "preempted"
 
 
 # Additional sequences when using the `best_of` parameter
@@ -164,6 +164,21 @@ struct Args {
     #[clap(long, env)]
     speculate: Option<usize>,
 
+    /// LoRA adapters (hub ids or local paths) loaded next to the base model, selected by the
+    /// `adapter_id` parameter of the requests so that a single deployment serves several
+    /// fine-tunes, or blended with the weights of the `adapter_parameters` of the requests. The
+    /// requests without `adapter_id` use the base model. Only supported by the models running
+    /// on the generic `transformers` implementation. Adapters can also be loaded and evicted at
+    /// runtime from `/admin/adapters`.
+    #[clap(long, env, value_delimiter = ',')]
+    lora_adapters: Vec<String>,
+
+    /// Adapters (hub ids or local paths) that may be loaded at runtime from `/admin/adapters`,
+    /// comma separated. Any adapter can be loaded when unset. Only the safetensors weights of
+    /// the adapters are loaded.
+    #[clap(long, env, value_delimiter = ',')]
+    adapter_allowlist: Vec<String>,
+
     /// The dtype to be forced upon the model. This option cannot be used with `--quantize`.
     #[clap(long, env, value_enum)]
     dtype: Option<Dtype>,
@@ -186,6 +201,12 @@ struct Args {
     #[clap(default_value = "2", long, env)]
     max_best_of: usize,
 
+    /// This is the maximum allowed value for clients to set `n` on the `/generate_samples`
+    /// route. Each sample is an independent generation of the same prompt with its own
+    /// derived seed.
+    #[clap(default_value = "8", long, env)]
+    max_samples: usize,
+
     /// This is the maximum allowed value for clients to set `stop_sequences`.
     /// Stop sequences are used to allow the model to stop on more than just
     /// the EOS token, and enable more complex "prompting" where users can preprompt
@@ -198,10 +219,26 @@ struct Args {
     /// `top_n_tokens is used to return information about the the `n` most likely
     /// tokens at each generation step, instead of just the sampled token. This
     /// information can be used for downstream tasks like for classification or
-    /// ranking.
+    /// ranking. It is also the default cap of the chat and completions endpoints.
     #[clap(default_value = "5", long, env)]
     max_top_n_tokens: u32,
 
+    /// Maximum `top_logprobs` of the `/v1/chat/completions` requests. Defaults to
+    /// `--max-top-n-tokens`.
+    #[clap(long, env)]
+    max_chat_top_logprobs: Option<u32>,
+
+    /// Maximum `logprobs` of the `/v1/completions` requests. Defaults to
+    /// `--max-top-n-tokens`.
+    #[clap(long, env)]
+    max_completions_logprobs: Option<u32>,
+
+    /// Budget of the log probabilities returned by a request: requests whose
+    /// `top_n_tokens` × `max_new_tokens` exceeds it fail with a validation error.
+    /// Disabled when unset.
+    #[clap(long, env)]
+    max_logprob_payload: Option<u32>,
+
     /// This is the maximum allowed input length (expressed in number of tokens)
     /// for users. The larger this value, the longer prompt users can send which
     /// can impact the overall memory required to handle the load.
@@ -279,6 +316,12 @@ struct Args {
     #[clap(default_value = "20", long, env)]
     max_waiting_tokens: usize,
 
+    /// `max_waiting_tokens` applied while requests with the `latency` quality of service are
+    /// waiting: they join the running batch after fewer decode steps than the `throughput`
+    /// requests.
+    #[clap(default_value = "4", long, env)]
+    latency_max_waiting_tokens: usize,
+
     /// Enforce a maximum number of requests per batch
     /// Specific flag for hardware targets that do not support unpadded inference
     #[clap(long, env)]
@@ -358,6 +401,20 @@ struct Args {
     #[clap(long, env)]
     otlp_endpoint: Option<String>,
 
+    /// Ratio of the requests traced by the router. The shards trace the same requests.
+    #[clap(default_value = "1.0", long, env)]
+    otlp_sample_ratio: f64,
+
+    /// Follow the sampling decision of the callers sending a `traceparent` header, instead of
+    /// sampling their requests with `--otlp-sample-ratio`.
+    #[clap(long, env)]
+    otlp_parent_based_sampling: bool,
+
+    /// Ratios of the requests traced per route, comma separated `ROUTE=RATIO` entries overriding
+    /// `--otlp-sample-ratio`, e.g. `/generate_stream=0.01,/health=0`.
+    #[clap(long, env, value_delimiter = ',')]
+    otlp_route_sample_ratios: Vec<String>,
+
     #[clap(long, env)]
     cors_allow_origin: Vec<String>,
     #[clap(long, env)]
@@ -365,6 +422,22 @@ struct Args {
     #[clap(long, env)]
     watermark_delta: Option<f32>,
 
+    /// Experimental: path to a WASM module biasing the logits at every decoding step.
+    /// The module receives the top-k logits of each sequence and returns a bias for each of
+    /// them. It runs sandboxed in the shards, without access to the host. The logits are
+    /// left untouched when the module fails or runs out of fuel.
+    #[clap(long, env)]
+    logits_plugin: Option<String>,
+
+    /// Number of the top logits of each sequence sent to the `--logits-plugin` module.
+    #[clap(default_value = "32", long, env)]
+    logits_plugin_top_k: usize,
+
+    /// Fuel budget (number of WASM instructions) of each call to the `--logits-plugin` module.
+    /// Unlike a wall-clock timeout, it gives the same outcome on every shard.
+    #[clap(default_value = "1000000", long, env)]
+    logits_plugin_fuel: u64,
+
     /// Enable ngrok tunneling
     #[clap(long, env)]
     ngrok: bool,
@@ -387,6 +460,416 @@ struct Args {
     #[clap(long, env)]
     disable_grammar_support: bool,
 
+    /// Shard memory pressure (fraction of the KV cache in use) above which the router
+    /// stops admitting requests with large prompts. Requests with small prompts are still served.
+    #[clap(long, env)]
+    memory_pressure_threshold: Option<f32>,
+
+    /// Maximum number of input tokens a request can have to be admitted while the shards
+    /// are above `memory_pressure_threshold`.
+    #[clap(default_value = "1024", long, env)]
+    memory_pressure_max_prefill_tokens: u32,
+
+    /// W3C baggage keys (e.g. `tenant`, `experiment_id`) forwarded from the incoming
+    /// requests to the shards, where they are recorded on the server spans.
+    #[clap(long, env, value_delimiter = ',')]
+    baggage_keys: Vec<String>,
+
+    /// Base url of a secondary deployment. Requests to `/generate` that are expected to spend
+    /// more than `hedging_latency_slo` in the queue are also sent to this deployment; the first
+    /// response wins and the other request is cancelled.
+    #[clap(long, env)]
+    hedging_endpoint: Option<String>,
+
+    /// Expected queue time (in milliseconds) above which a request is hedged.
+    #[clap(default_value = "1000", long, env)]
+    hedging_latency_slo: u64,
+
+    /// Maximum number of in-flight hedged requests.
+    #[clap(default_value = "8", long, env)]
+    hedging_max_concurrent: usize,
+
+    /// Maximum ratio of hedged requests.
+    #[clap(default_value = "0.1", long, env)]
+    hedging_max_ratio: f32,
+
+    /// Path to a JSON file describing canary experiments. Each experiment enrolls a percentage
+    /// of the chat/completions requests and sets default generation parameters for them, among
+    /// `temperature`, `top_p`, `top_k`, `repetition_penalty`, `frequency_penalty`, `grammar`
+    /// and `adapter_id`, e.g. `[{"name": "low-temp", "percentage": 5, "routes": ["chat"], "parameters": {"temperature": 0.2}}]`.
+    /// Enrolled responses are tagged with an `x-experiment` header.
+    #[clap(long, env)]
+    experiments_config: Option<String>,
+
+    /// Number of completed requests kept in memory by the audit store. When > 0, the full
+    /// details of `/generate` requests can be fetched page by page from
+    /// `/results/{request_id}/details`, using the `x-request-id` response header, by the client
+    /// that sent them (same tenant or IP address), and the completed requests can be listed from
+    /// `/admin/requests`.
+    #[clap(default_value = "0", long, env)]
+    audit_store_size: usize,
+
+    /// File of the AES-256-GCM keys encrypting the generation details kept by the audit store,
+    /// as `<key id>:<64 hex characters>` entries separated by commas or new lines. The first
+    /// key encrypts the new entries and the other ones decrypt the entries stored before a
+    /// rotation. The file is read again every minute. The keys can also be given directly with
+    /// the `AUDIT_ENCRYPTION_KEYS` environment variable.
+    #[clap(long, env)]
+    audit_encryption_keys_file: Option<String>,
+
+    /// Render the errors of the OpenAI compatible `/v1/*` routes in OpenAI's error envelope
+    /// (`{"error": {"message", "type", "param", "code"}}`) with OpenAI's status codes: 400 for
+    /// validation errors and 429 for rate limits.
+    #[clap(long, env)]
+    openai_error_format: bool,
+
+    /// Other names accepted in the `model` field of the OpenAI compatible routes and listed by
+    /// `/v1/models`, e.g. `gpt-3.5-turbo`. They all resolve to the served model.
+    #[clap(long, env, value_delimiter = ',')]
+    model_aliases: Vec<String>,
+
+    /// Reject the OpenAI compatible requests whose `model` field is neither the served model id
+    /// nor one of the `--model-aliases` with a 404 `model_not_found` error.
+    #[clap(long, env)]
+    enforce_model_name: bool,
+
+    /// Error rate of the shard RPCs (between 0 and 1) above which the router opens its circuit
+    /// breaker: requests fail fast with a 503 `upstream_unhealthy` error and `/health` reports
+    /// the router as unhealthy until the end of the cool-down. Disabled when unset.
+    #[clap(long, env)]
+    circuit_breaker_error_rate: Option<f32>,
+
+    /// Number of the last shard RPCs the circuit breaker error rate is computed on.
+    #[clap(default_value = "20", long, env)]
+    circuit_breaker_window: usize,
+
+    /// Time in seconds the circuit breaker stays open before letting requests through again.
+    #[clap(default_value = "30", long, env)]
+    circuit_breaker_cooldown: u64,
+
+    /// Maximum number of concurrent streams of a client, identified by its tenant (a known API key
+    /// or a trusted `x-tenant-id` header) or else by its IP address. Streams over the limit fail
+    /// with a 429 `too_many_streams` error.
+    /// This limit is independent of `--max-concurrent-requests`. Disabled when unset.
+    #[clap(long, env)]
+    max_concurrent_streams_per_client: Option<usize>,
+
+    /// Maximum number of times a request ending without its last token (an
+    /// `incomplete_generation` error) is transparently generated again. Streaming requests are
+    /// only retried before their first token.
+    #[clap(default_value = "0", long, env)]
+    incomplete_generation_retries: usize,
+
+    /// Scan the generated texts for personally identifiable information (emails, phone numbers,
+    /// credit card numbers and US social security numbers) and `tag` the responses with the
+    /// categories found in their `safety` field, `mask` the detections or `block` the responses.
+    /// Streamed tokens are sent before the scan: only the final `generated_text` of a stream is
+    /// masked, and a blocked stream ends with an error event. Disabled when unset.
+    #[clap(long, env)]
+    pii_action: Option<String>,
+
+    /// Categories of personally identifiable information scanned with `--pii-action`, comma
+    /// separated among `email`, `phone`, `credit_card` and `national_id`. All of them by default.
+    #[clap(long, env, value_delimiter = ',')]
+    pii_categories: Vec<String>,
+
+    /// Maximum size, in bytes, of the request bodies of the `/vertex` route (and of
+    /// `AIP_PREDICT_ROUTE`). Defaults to the 2 MB limit of the other routes.
+    #[clap(long, env)]
+    vertex_max_body_size: Option<usize>,
+
+    /// Maximum number of instances of a `/vertex` request. Requests with more instances fail with
+    /// a validation error. Disabled when unset.
+    #[clap(long, env)]
+    vertex_max_instances: Option<usize>,
+
+    /// Maximum number of concurrent requests on the `/vertex` route. Requests over the limit fail
+    /// with a 429 `route_overloaded` error, leaving the capacity of the native API to its
+    /// clients. Disabled when unset.
+    #[clap(long, env)]
+    vertex_max_concurrent_requests: Option<usize>,
+
+    /// Maximum size, in bytes, of the request bodies of the SageMaker `/invocations` route.
+    /// Defaults to the 2 MB limit of the other routes.
+    #[clap(long, env)]
+    invocations_max_body_size: Option<usize>,
+
+    /// Maximum number of concurrent requests, streams included, on the SageMaker `/invocations`
+    /// route. Requests over the limit fail with a 429 `route_overloaded` error. Disabled when
+    /// unset.
+    #[clap(long, env)]
+    invocations_max_concurrent_requests: Option<usize>,
+
+    /// Base url of a second deployment serving another model, e.g. a smaller one. The requests
+    /// to `/`, `/generate`, `/v1/chat/completions` and `/v1/completions` failing with a shard
+    /// error, or arriving while the circuit breaker of the shards is open, are re-issued to it.
+    /// The `x-served-model` header of the responses names the model that served them. Requires
+    /// `--fallback-model-id`.
+    #[clap(long, env)]
+    fallback_endpoint: Option<String>,
+
+    /// Model served by the `--fallback-endpoint` deployment.
+    #[clap(long, env)]
+    fallback_model_id: Option<String>,
+
+    /// Generate the identical deterministic requests (greedy or with the same seed) arriving
+    /// concurrently once, and fan the response out to all of them, e.g. for frontends retrying
+    /// on timeouts. Streams are not coalesced. The coalesced requests are counted by the
+    /// `tgi_request_coalesced` metric.
+    #[clap(long, env)]
+    coalesce_requests: bool,
+
+    /// Time to first token threshold, in milliseconds, of the streams meeting the latency SLO.
+    /// The fraction of the streams under it and the burn rate of the error budget are exported
+    /// over 1m, 5m and 1h rolling windows by the `tgi_slo_good_ratio` and `tgi_slo_burn_rate`
+    /// gauges, with `sli="first_token"`.
+    #[clap(long, env)]
+    slo_first_token_ms: Option<u64>,
+
+    /// Mean time per generated token threshold, in milliseconds, of the requests meeting the
+    /// latency SLO. Exported like `--slo-first-token-ms`, with `sli="time_per_token"`.
+    #[clap(long, env)]
+    slo_time_per_token_ms: Option<u64>,
+
+    /// Target fraction of the requests meeting the latency SLOs. The burn rate is the fraction of
+    /// the requests over the thresholds divided by the error budget `1 - slo_objective`.
+    #[clap(default_value = "0.99", long, env)]
+    slo_objective: f64,
+
+    /// Size of the waiting room of the non-streamed generations. Instead of failing with a 429,
+    /// the requests arriving while the `--max-concurrent-requests` are all in flight are accepted
+    /// with a 202 holding a ticket, its position and ETA. The request is generated once capacity
+    /// frees, and its response is collected by polling `GET /waiting_room/{ticket_id}`, with
+    /// `?wait=<seconds>` to long-poll. The responses not collected yet count in the size until
+    /// they expire after 5 minutes. The requests over the size still fail with a 429.
+    /// Disabled when unset.
+    #[clap(long, env)]
+    waiting_room_size: Option<usize>,
+
+    /// Memory cap, in MB, of the least recently used cache of the input encodings. Requests with
+    /// the same inputs, e.g. the same system prompt and few-shot examples, skip their
+    /// tokenization. Hits and misses are counted by the `tgi_tokenization_cache_requests` metric.
+    /// Disabled when unset.
+    #[clap(long, env)]
+    tokenization_cache_size_mb: Option<usize>,
+
+    /// Price of a thousand input tokens. With the price of the generated tokens, the router
+    /// returns the estimated cost of the requests in the `x-estimated-cost` header, the details
+    /// of the last streamed token and the usage of the OpenAI responses, and accounts it per
+    /// tenant.
+    #[clap(long, env)]
+    input_token_price_per_1k: Option<f64>,
+
+    /// Price of a thousand generated tokens, see `--input-token-price-per-1k`.
+    #[clap(long, env)]
+    output_token_price_per_1k: Option<f64>,
+
+    /// Endpoints turned off, comma separated among `compat_generate` (`POST /`), `generate`,
+    /// `generate_stream`, `generate_samples`, `chat_completions`, `completions`, `vertex`,
+    /// `invocations`, `tokenize`, `mcp`, `models`, `details`, `batches`, `batch_files`,
+    /// `waiting_room`, `abort`, `admin`, `metrics`, `docs` and `playground`. They are not served
+    /// nor documented in the OpenAPI specification. The health checks can not be disabled.
+    #[clap(long, env, value_delimiter = ',')]
+    disable_endpoints: Vec<String>,
+
+    /// Experimental: serve a Model Context Protocol (MCP) endpoint at `/mcp` so that MCP agent
+    /// clients can use the model through its `generate` and `chat` tools. The chat tool can call
+    /// the tools of the `--tools-file`.
+    #[clap(long, env)]
+    enable_mcp: bool,
+
+    /// Directory of translated error messages, one `<language>.json` file per language (e.g.
+    /// `fr.json`, `pt-BR.json`) mapping the codes of the validation errors (e.g.
+    /// `max_new_tokens`) to message templates with `{0}`, `{1}`... placeholders. The messages
+    /// follow the `Accept-Language` header of the requests, `error_type` is not translated.
+    #[clap(long, env)]
+    error_catalogs_dir: Option<String>,
+
+    /// Developer chaos mode: inject random queue delays, synthetic shard errors and dropped
+    /// stream chunks, configured at runtime through the `/admin/chaos` endpoint, to test the
+    /// retry logic of the clients. No fault is injected until configured. Do not use in
+    /// production.
+    #[clap(long, env)]
+    chaos: bool,
+
+    /// TOML file of named presets of decoding parameters, one table per preset (e.g.
+    /// `[support]` followed by `temperature = 0.3`), selected with the `preset` parameter of the
+    /// requests. The parameters set by a request take precedence over its preset. A preset named
+    /// `creative` or `precise` replaces the built-in one.
+    #[clap(long, env)]
+    presets_file: Option<String>,
+
+    /// Number of scheduling decisions of the queue kept in memory. When > 0, the last decisions
+    /// (the batched entries, why the others waited and the token budgets) can be fetched from
+    /// `/admin/flight_recorder` to debug long queue times without verbose logging.
+    #[clap(default_value = "0", long, env)]
+    flight_recorder_size: usize,
+
+    /// Name the tenants of the requests after their `x-tenant-id` header, set by a trusted
+    /// proxy. Otherwise the tenants are only identified by their API key, see
+    /// `--tenant-api-keys-file`.
+    #[clap(long, env)]
+    trust_tenant_header: bool,
+
+    /// File of the API keys identifying the tenants, one key per line, sent as
+    /// `Authorization: Bearer <key>`. The requests with another key are anonymous, their limits
+    /// applying per IP address.
+    #[clap(long, env)]
+    tenant_api_keys_file: Option<String>,
+
+    /// Maximum rate of the inference requests of a client, identified by its tenant (a known API
+    /// key or a trusted `x-tenant-id` header) or else by its IP address, with bursts of one
+    /// second of requests.
+    /// Requests over the limit fail with a 429 `rate_limited` error and a `Retry-After` header.
+    /// Disabled when unset.
+    #[clap(long, env)]
+    rate_limit_requests_per_second: Option<f64>,
+
+    /// Maximum number of prompt and generated tokens of a client per minute. The tokens of a
+    /// request are accounted once generated: the requests of a client over its budget fail
+    /// with a 429 `rate_limited` error until it is refilled. Disabled when unset.
+    #[clap(long, env)]
+    rate_limit_tokens_per_minute: Option<u64>,
+
+    /// Number of failing requests whose payload can be kept in memory. When > 0, the capture is
+    /// armed with `PUT /admin/debug_capture` for the next requests failing with a status code,
+    /// e.g. 424, and their payloads, the PII masked, are fetched from `/admin/debug_capture`.
+    #[clap(default_value = "0", long, env)]
+    debug_capture_size: usize,
+
+    /// TOML file of ordered regex replacements of the generated text, one `[[transform]]` table
+    /// per transform with its `pattern`, `replacement` (`$1` expanding to a capture group) and
+    /// `window`, the maximum number of characters of a match (32 by default). The streamed
+    /// tokens are transformed on the fly, the text of a potential match being held back until
+    /// the next tokens, e.g. to strip `<|assistant|>` remnants.
+    #[clap(long, env)]
+    stream_transforms_file: Option<String>,
+
+    /// Interval, in seconds, of the synthetic canary generations. When set, a tiny generation
+    /// runs through the validation, the queue and the shards at this interval and its outcome
+    /// and latency are exported in the `tgi_canary_success`, `tgi_canary_failure`,
+    /// `tgi_canary_duration` and `tgi_canary_up` metrics, to alert on regressions between real
+    /// traffic. The canaries are excluded from the usage of the tenants.
+    #[clap(long, env)]
+    canary_interval: Option<u64>,
+
+    /// Memory cap, in MB, of the least recently used cache of the rendered and tokenized chat
+    /// conversations. The next turn of a cached conversation only tokenizes its new messages
+    /// instead of its whole history. Hits and misses are counted by the
+    /// `tgi_template_cache_requests` metric. Disabled when unset.
+    #[clap(long, env)]
+    template_cache_size_mb: Option<usize>,
+
+    /// Handling of the chat requests whose system prompt would fall outside the sliding attention
+    /// window of the model (`window_size` in `/info`) before the end of their generation: `warn`
+    /// adds a warning to the response and `reject` fails the request with a validation error.
+    /// Ignored for the models attending to the whole sequence. Disabled when unset.
+    #[clap(long, env)]
+    system_prompt_outside_window: Option<String>,
+
+    /// Serve the OpenMetrics exposition to the `/metrics` scrapes accepting
+    /// `application/openmetrics-text`, with the traces of the latest requests as exemplars of
+    /// the `tgi_request_duration` and `tgi_request_first_token_duration` buckets. The counters
+    /// are typed `unknown` to keep their names. The Prometheus text exposition is served when
+    /// unset.
+    #[clap(long, env)]
+    metrics_exemplars: bool,
+
+    /// Maximum router memory, in MB, the tokens of a response may hold. It is estimated from
+    /// `max_new_tokens`, `top_n_tokens`, `best_of` and `decoder_input_details`: requests above
+    /// the limit fail with a validation error instead of risking a router OOM under load.
+    /// Disabled when unset.
+    #[clap(long, env)]
+    max_request_memory_mb: Option<usize>,
+
+    /// Maximum size, in bytes, of the `json` or `regex` grammar of a request. Larger grammars
+    /// fail with a validation error instead of stalling the serialization and the shards
+    /// compilation.
+    #[clap(default_value = "262144", long, env)]
+    max_grammar_size: usize,
+
+    /// Maximum size, in bytes, of the compiled program of a `regex` grammar, bounding its
+    /// complexity, e.g. large repetitions of unicode classes.
+    #[clap(default_value = "1048576", long, env)]
+    max_grammar_regex_size: usize,
+
+    /// Maximum nesting depth of the objects and arrays of a `json` grammar.
+    #[clap(default_value = "32", long, env)]
+    max_grammar_schema_depth: usize,
+
+    /// Keep-warm window, in milliseconds, of the conversational sessions identified by the
+    /// `x-session-id` request header. A request of a session whose previous response finished
+    /// less than the window ago is queued before the other requests. Hits and misses are
+    /// counted by the `tgi_sticky_session_hit` and `tgi_sticky_session_miss` metrics. Disabled
+    /// when unset.
+    #[clap(long, env)]
+    sticky_session_window: Option<u64>,
+
+    /// Tokens never generated, whatever the request parameters, e.g. chat control tokens.
+    /// Given as token ids or as tokens of the vocabulary (e.g. `<|im_start|>`), comma
+    /// separated. The tokens are suppressed at sampling for every request.
+    #[clap(long, env, value_delimiter = ',')]
+    suppress_tokens: Vec<String>,
+
+    /// Path to a JSON file declaring commonly used tools, in the OpenAI `tools` format. Their
+    /// grammars are compiled on the shards at startup so the first function-calling requests do
+    /// not pay the compilation, and chat requests can reference them by name only
+    /// (`"tools": ["get_weather"]`). Listed by `/info`.
+    #[clap(long, env)]
+    tools_file: Option<String>,
+
+    /// Completion time SLO (in milliseconds). The completion time of each request is estimated at
+    /// admission from its queue time, its `max_new_tokens` and the recent time per token, and
+    /// returned in the `x-estimated-completion-time` header. Requests estimated to complete after
+    /// this bound are rejected with a 429 `completion_time` error.
+    #[clap(long, env)]
+    completion_time_slo: Option<u64>,
+
+    /// Debug mode re-tokenizing the text generated for each request and comparing it with the
+    /// generated tokens. Drifts, e.g. from a tokenizer not matching the model, are flagged in
+    /// the `retokenization` field of the response details and counted by the
+    /// `tgi_request_retokenization_drift` metric.
+    #[clap(long, env)]
+    retokenization_check: bool,
+
+    /// Object store locations the job-style requests may write their generated text to, comma
+    /// separated (e.g. `s3://datasets/synthetic/,gs://outputs/`). A request setting an
+    /// `output_destination` under one of them has its text uploaded in parts while it is
+    /// generated, and only receives a manifest. S3 credentials are read from the `AWS_*`
+    /// environment variables, GCS ones from `GCS_HMAC_ACCESS_ID` and `GCS_HMAC_SECRET`.
+    #[clap(long, env, value_delimiter = ',')]
+    output_destination_prefixes: Vec<String>,
+
+    /// Time (in seconds) the webserver waits for the in-flight requests after a shutdown signal.
+    /// Once elapsed, the running generations end at their next token with the `shutdown` finish
+    /// reason, the other streams receive a final `shutdown` error event, the sequences of the
+    /// shards are cancelled and the webserver exits. By default, the webserver waits for all
+    /// the requests.
+    #[clap(long, env)]
+    shutdown_grace_period: Option<u64>,
+
+    /// Webhook receiving a JSON POST when the shards fail or disconnect, when generations end
+    /// incomplete and when the circuit breaker opens. The alerts are batched by kind:
+    /// `{"alerts": [{"kind", "message", "count", "first_at", "last_at"}]}`.
+    #[clap(long, env)]
+    alert_webhook_url: Option<String>,
+
+    /// Minimum number of seconds between two calls to the alert webhook
+    #[clap(default_value = "60", long, env)]
+    alert_webhook_interval: u64,
+
+    /// Secondary tokenizer (a local directory or a Hugging Face hub repository) compared with
+    /// the served tokenizer to validate a tokenizer upgrade before cutting over. The inputs of a
+    /// sample of the requests are tokenized with both in the background: the mismatches are
+    /// logged and counted by the `tgi_shadow_tokenizer_mismatch` metric. The requests are not
+    /// affected.
+    #[clap(long, env)]
+    shadow_tokenizer: Option<String>,
+
+    /// Fraction of the requests tokenized with the `--shadow-tokenizer`
+    #[clap(default_value = "0.01", long, env)]
+    shadow_tokenizer_sample_rate: f32,
+
     /// Display a lot of information about your runtime environment
     #[clap(long, short, action)]
     env: bool,
@@ -404,6 +887,7 @@ fn shard_manager(
     revision: Option<String>,
     quantize: Option<Quantization>,
     speculate: Option<usize>,
+    lora_adapters: Vec<String>,
     dtype: Option<Dtype>,
     trust_remote_code: bool,
     uds_path: String,
@@ -416,6 +900,9 @@ fn shard_manager(
     disable_custom_kernels: bool,
     watermark_gamma: Option<f32>,
     watermark_delta: Option<f32>,
+    logits_plugin: Option<String>,
+    logits_plugin_top_k: usize,
+    logits_plugin_fuel: u64,
     enable_cuda_graphs: bool,
     cuda_memory_fraction: f32,
     rope_scaling: Option<RopeScaling>,
@@ -568,6 +1055,24 @@ fn shard_manager(
         envs.push(("WATERMARK_DELTA".into(), watermark_delta.to_string().into()))
     }
 
+    // LoRA adapters
+    if !lora_adapters.is_empty() {
+        envs.push(("LORA_ADAPTERS".into(), lora_adapters.join(",").into()));
+    }
+
+    // Logits plugin
+    if let Some(logits_plugin) = logits_plugin {
+        envs.push(("LOGITS_PLUGIN_PATH".into(), logits_plugin.into()));
+        envs.push((
+            "LOGITS_PLUGIN_TOP_K".into(),
+            logits_plugin_top_k.to_string().into(),
+        ));
+        envs.push((
+            "LOGITS_PLUGIN_FUEL".into(),
+            logits_plugin_fuel.to_string().into(),
+        ));
+    }
+
     // Start process
     tracing::info!("Starting shard");
     let mut p = match Command::new("text-generation-server")
@@ -935,12 +1440,16 @@ fn spawn_shards(
         let otlp_endpoint = args.otlp_endpoint.clone();
         let quantize = args.quantize;
         let speculate = args.speculate;
+        let lora_adapters = args.lora_adapters.clone();
         let dtype = args.dtype;
         let trust_remote_code = args.trust_remote_code;
         let master_port = args.master_port;
         let disable_custom_kernels = args.disable_custom_kernels;
         let watermark_gamma = args.watermark_gamma;
         let watermark_delta = args.watermark_delta;
+        let logits_plugin = args.logits_plugin.clone();
+        let logits_plugin_top_k = args.logits_plugin_top_k;
+        let logits_plugin_fuel = args.logits_plugin_fuel;
         let enable_cuda_graphs = args.enable_cuda_graphs;
         let cuda_memory_fraction = args.cuda_memory_fraction;
         let rope_scaling = args.rope_scaling;
@@ -951,6 +1460,7 @@ fn spawn_shards(
                 revision,
                 quantize,
                 speculate,
+                lora_adapters,
                 dtype,
                 trust_remote_code,
                 uds_path,
@@ -963,6 +1473,9 @@ fn spawn_shards(
                 disable_custom_kernels,
                 watermark_gamma,
                 watermark_delta,
+                logits_plugin,
+                logits_plugin_top_k,
+                logits_plugin_fuel,
                 enable_cuda_graphs,
                 cuda_memory_fraction,
                 rope_scaling,
@@ -1030,6 +1543,8 @@ fn spawn_webserver(
         args.max_concurrent_requests.to_string(),
         "--max-best-of".to_string(),
         args.max_best_of.to_string(),
+        "--max-samples".to_string(),
+        args.max_samples.to_string(),
         "--max-stop-sequences".to_string(),
         args.max_stop_sequences.to_string(),
         "--max-top-n-tokens".to_string(),
@@ -1044,6 +1559,8 @@ fn spawn_webserver(
         args.waiting_served_ratio.to_string(),
         "--max-waiting-tokens".to_string(),
         args.max_waiting_tokens.to_string(),
+        "--latency-max-waiting-tokens".to_string(),
+        args.latency_max_waiting_tokens.to_string(),
         "--validation-workers".to_string(),
         args.validation_workers.to_string(),
         "--hostname".to_string(),
@@ -1054,13 +1571,332 @@ fn spawn_webserver(
         format!("{}-0", args.shard_uds_path),
         "--tokenizer-name".to_string(),
         args.model_id,
+        "--memory-pressure-max-prefill-tokens".to_string(),
+        args.memory_pressure_max_prefill_tokens.to_string(),
+        "--hedging-latency-slo".to_string(),
+        args.hedging_latency_slo.to_string(),
+        "--hedging-max-concurrent".to_string(),
+        args.hedging_max_concurrent.to_string(),
+        "--hedging-max-ratio".to_string(),
+        args.hedging_max_ratio.to_string(),
+        "--audit-store-size".to_string(),
+        args.audit_store_size.to_string(),
+        "--circuit-breaker-window".to_string(),
+        args.circuit_breaker_window.to_string(),
+        "--circuit-breaker-cooldown".to_string(),
+        args.circuit_breaker_cooldown.to_string(),
     ];
 
+    // Audit details encryption
+    if let Some(audit_encryption_keys_file) = args.audit_encryption_keys_file {
+        router_args.push("--audit-encryption-keys-file".to_string());
+        router_args.push(audit_encryption_keys_file);
+    }
+
+    // OpenAI error envelope
+    if args.openai_error_format {
+        router_args.push("--openai-error-format".to_string());
+    }
+
+    // Model name enforcement and aliases
+    for model_alias in &args.model_aliases {
+        router_args.push("--model-aliases".to_string());
+        router_args.push(model_alias.to_string());
+    }
+    if args.enforce_model_name {
+        router_args.push("--enforce-model-name".to_string());
+    }
+
+    // Circuit breaker around the shard RPCs
+    if let Some(circuit_breaker_error_rate) = args.circuit_breaker_error_rate {
+        router_args.push("--circuit-breaker-error-rate".to_string());
+        router_args.push(circuit_breaker_error_rate.to_string());
+    }
+
+    // Per-client concurrent stream limit
+    if let Some(max_concurrent_streams_per_client) = args.max_concurrent_streams_per_client {
+        router_args.push("--max-concurrent-streams-per-client".to_string());
+        router_args.push(max_concurrent_streams_per_client.to_string());
+    }
+
+    // Retries of the incomplete generations
+    if args.incomplete_generation_retries > 0 {
+        router_args.push("--incomplete-generation-retries".to_string());
+        router_args.push(args.incomplete_generation_retries.to_string());
+    }
+
+    // Scan of the responses for personally identifiable information
+    if let Some(pii_action) = args.pii_action {
+        router_args.push("--pii-action".to_string());
+        router_args.push(pii_action);
+    }
+    for pii_category in &args.pii_categories {
+        router_args.push("--pii-categories".to_string());
+        router_args.push(pii_category.to_string());
+    }
+
+    // Limits of the cloud front door routes
+    if let Some(vertex_max_body_size) = args.vertex_max_body_size {
+        router_args.push("--vertex-max-body-size".to_string());
+        router_args.push(vertex_max_body_size.to_string());
+    }
+    if let Some(vertex_max_instances) = args.vertex_max_instances {
+        router_args.push("--vertex-max-instances".to_string());
+        router_args.push(vertex_max_instances.to_string());
+    }
+    if let Some(vertex_max_concurrent_requests) = args.vertex_max_concurrent_requests {
+        router_args.push("--vertex-max-concurrent-requests".to_string());
+        router_args.push(vertex_max_concurrent_requests.to_string());
+    }
+    if let Some(invocations_max_body_size) = args.invocations_max_body_size {
+        router_args.push("--invocations-max-body-size".to_string());
+        router_args.push(invocations_max_body_size.to_string());
+    }
+    if let Some(invocations_max_concurrent_requests) = args.invocations_max_concurrent_requests {
+        router_args.push("--invocations-max-concurrent-requests".to_string());
+        router_args.push(invocations_max_concurrent_requests.to_string());
+    }
+
+    // Fallback model
+    if let Some(fallback_endpoint) = &args.fallback_endpoint {
+        router_args.push("--fallback-endpoint".to_string());
+        router_args.push(fallback_endpoint.to_string());
+    }
+    if let Some(fallback_model_id) = &args.fallback_model_id {
+        router_args.push("--fallback-model-id".to_string());
+        router_args.push(fallback_model_id.to_string());
+    }
+
+    // Request coalescing
+    if args.coalesce_requests {
+        router_args.push("--coalesce-requests".to_string());
+    }
+
+    // Latency SLOs
+    if let Some(slo_first_token_ms) = args.slo_first_token_ms {
+        router_args.push("--slo-first-token-ms".to_string());
+        router_args.push(slo_first_token_ms.to_string());
+    }
+    if let Some(slo_time_per_token_ms) = args.slo_time_per_token_ms {
+        router_args.push("--slo-time-per-token-ms".to_string());
+        router_args.push(slo_time_per_token_ms.to_string());
+    }
+    router_args.push("--slo-objective".to_string());
+    router_args.push(args.slo_objective.to_string());
+
+    // Waiting room
+    if let Some(waiting_room_size) = args.waiting_room_size {
+        router_args.push("--waiting-room-size".to_string());
+        router_args.push(waiting_room_size.to_string());
+    }
+
+    // Tokenization cache
+    if let Some(tokenization_cache_size_mb) = args.tokenization_cache_size_mb {
+        router_args.push("--tokenization-cache-size-mb".to_string());
+        router_args.push(tokenization_cache_size_mb.to_string());
+    }
+
+    // Cost estimation
+    if let Some(input_token_price_per_1k) = args.input_token_price_per_1k {
+        router_args.push("--input-token-price-per-1k".to_string());
+        router_args.push(input_token_price_per_1k.to_string());
+    }
+    if let Some(output_token_price_per_1k) = args.output_token_price_per_1k {
+        router_args.push("--output-token-price-per-1k".to_string());
+        router_args.push(output_token_price_per_1k.to_string());
+    }
+
+    // Disabled endpoints
+    for endpoint in &args.disable_endpoints {
+        router_args.push("--disable-endpoints".to_string());
+        router_args.push(endpoint.to_string());
+    }
+
+    // Experimental MCP endpoint
+    if args.enable_mcp {
+        router_args.push("--enable-mcp".to_string());
+    }
+
+    // Translated error messages
+    if let Some(error_catalogs_dir) = args.error_catalogs_dir {
+        router_args.push("--error-catalogs-dir".to_string());
+        router_args.push(error_catalogs_dir);
+    }
+
+    // Chaos mode
+    if args.chaos {
+        router_args.push("--chaos".to_string());
+    }
+
+    // Presets of decoding parameters
+    if let Some(presets_file) = args.presets_file {
+        router_args.push("--presets-file".to_string());
+        router_args.push(presets_file);
+    }
+
+    // Flight recorder of the scheduling decisions
+    if args.flight_recorder_size > 0 {
+        router_args.push("--flight-recorder-size".to_string());
+        router_args.push(args.flight_recorder_size.to_string());
+    }
+
+    // Tenants named by a trusted proxy
+    if args.trust_tenant_header {
+        router_args.push("--trust-tenant-header".to_string());
+    }
+
+    // Tenants identified by their API key
+    if let Some(tenant_api_keys_file) = args.tenant_api_keys_file {
+        router_args.push("--tenant-api-keys-file".to_string());
+        router_args.push(tenant_api_keys_file);
+    }
+
+    // Per-client rate limits
+    if let Some(rate_limit_requests_per_second) = args.rate_limit_requests_per_second {
+        router_args.push("--rate-limit-requests-per-second".to_string());
+        router_args.push(rate_limit_requests_per_second.to_string());
+    }
+    if let Some(rate_limit_tokens_per_minute) = args.rate_limit_tokens_per_minute {
+        router_args.push("--rate-limit-tokens-per-minute".to_string());
+        router_args.push(rate_limit_tokens_per_minute.to_string());
+    }
+
+    // Debug capture of the failing requests
+    if args.debug_capture_size > 0 {
+        router_args.push("--debug-capture-size".to_string());
+        router_args.push(args.debug_capture_size.to_string());
+    }
+
+    // Transforms of the generated text
+    if let Some(stream_transforms_file) = args.stream_transforms_file {
+        router_args.push("--stream-transforms-file".to_string());
+        router_args.push(stream_transforms_file);
+    }
+
+    // Synthetic canary generations
+    if let Some(canary_interval) = args.canary_interval {
+        router_args.push("--canary-interval".to_string());
+        router_args.push(canary_interval.to_string());
+    }
+
+    // Conversation template cache
+    if let Some(template_cache_size_mb) = args.template_cache_size_mb {
+        router_args.push("--template-cache-size-mb".to_string());
+        router_args.push(template_cache_size_mb.to_string());
+    }
+
+    // System prompts outside the attention window
+    if let Some(system_prompt_outside_window) = args.system_prompt_outside_window {
+        router_args.push("--system-prompt-outside-window".to_string());
+        router_args.push(system_prompt_outside_window);
+    }
+
+    // OpenMetrics exemplars
+    if args.metrics_exemplars {
+        router_args.push("--metrics-exemplars".to_string());
+    }
+
+    // Per-request router memory limit
+    if let Some(max_request_memory_mb) = args.max_request_memory_mb {
+        router_args.push("--max-request-memory-mb".to_string());
+        router_args.push(max_request_memory_mb.to_string());
+    }
+
+    // Grammar limits
+    router_args.push("--max-grammar-size".to_string());
+    router_args.push(args.max_grammar_size.to_string());
+    router_args.push("--max-grammar-regex-size".to_string());
+    router_args.push(args.max_grammar_regex_size.to_string());
+    router_args.push("--max-grammar-schema-depth".to_string());
+    router_args.push(args.max_grammar_schema_depth.to_string());
+
+    // Top tokens caps of the chat and completions endpoints, and log probabilities budget
+    if let Some(max_chat_top_logprobs) = args.max_chat_top_logprobs {
+        router_args.push("--max-chat-top-logprobs".to_string());
+        router_args.push(max_chat_top_logprobs.to_string());
+    }
+    if let Some(max_completions_logprobs) = args.max_completions_logprobs {
+        router_args.push("--max-completions-logprobs".to_string());
+        router_args.push(max_completions_logprobs.to_string());
+    }
+    if let Some(max_logprob_payload) = args.max_logprob_payload {
+        router_args.push("--max-logprob-payload".to_string());
+        router_args.push(max_logprob_payload.to_string());
+    }
+
+    // Queue priority of the conversational sessions
+    if let Some(sticky_session_window) = args.sticky_session_window {
+        router_args.push("--sticky-session-window".to_string());
+        router_args.push(sticky_session_window.to_string());
+    }
+
+    // Tokens suppressed at sampling
+    for suppress_token in &args.suppress_tokens {
+        router_args.push("--suppress-tokens".to_string());
+        router_args.push(suppress_token.to_string());
+    }
+
+    // Declared tools
+    if let Some(tools_file) = args.tools_file {
+        router_args.push("--tools-file".to_string());
+        router_args.push(tools_file);
+    }
+
+    // Completion time SLO
+    if let Some(completion_time_slo) = args.completion_time_slo {
+        router_args.push("--completion-time-slo".to_string());
+        router_args.push(completion_time_slo.to_string());
+    }
+
+    // Re-tokenization consistency check
+    if args.retokenization_check {
+        router_args.push("--retokenization-check".to_string());
+    }
+
+    // Object store outputs
+    for adapter_id in &args.adapter_allowlist {
+        router_args.push("--adapter-allowlist".to_string());
+        router_args.push(adapter_id.to_string());
+    }
+
+    for output_destination_prefix in &args.output_destination_prefixes {
+        router_args.push("--output-destination-prefixes".to_string());
+        router_args.push(output_destination_prefix.to_string());
+    }
+
+    // Shutdown grace period
+    if let Some(shutdown_grace_period) = args.shutdown_grace_period {
+        router_args.push("--shutdown-grace-period".to_string());
+        router_args.push(shutdown_grace_period.to_string());
+    }
+
+    // Alert webhook
+    if let Some(alert_webhook_url) = &args.alert_webhook_url {
+        router_args.push("--alert-webhook-url".to_string());
+        router_args.push(alert_webhook_url.to_string());
+        router_args.push("--alert-webhook-interval".to_string());
+        router_args.push(args.alert_webhook_interval.to_string());
+    }
+
+    // Shadow tokenizer
+    if let Some(shadow_tokenizer) = &args.shadow_tokenizer {
+        router_args.push("--shadow-tokenizer".to_string());
+        router_args.push(shadow_tokenizer.to_string());
+        router_args.push("--shadow-tokenizer-sample-rate".to_string());
+        router_args.push(args.shadow_tokenizer_sample_rate.to_string());
+    }
+
     // Grammar support
     if args.disable_grammar_support {
         router_args.push("--disable-grammar-support".to_string());
     }
 
+    // Large prefill shedding under memory pressure
+    if let Some(memory_pressure_threshold) = args.memory_pressure_threshold {
+        router_args.push("--memory-pressure-threshold".to_string());
+        router_args.push(memory_pressure_threshold.to_string());
+    }
+
     // Tokenizer config path
     if let Some(ref tokenizer_config_path) = args.tokenizer_config_path {
         router_args.push("--tokenizer-config-path".to_string());
@@ -1094,6 +1930,15 @@ fn spawn_webserver(
         router_args.push("--otlp-endpoint".to_string());
         router_args.push(otlp_endpoint);
     }
+    router_args.push("--otlp-sample-ratio".to_string());
+    router_args.push(args.otlp_sample_ratio.to_string());
+    if args.otlp_parent_based_sampling {
+        router_args.push("--otlp-parent-based-sampling".to_string());
+    }
+    for route_sample_ratio in &args.otlp_route_sample_ratios {
+        router_args.push("--otlp-route-sample-ratios".to_string());
+        router_args.push(route_sample_ratio.to_string());
+    }
 
     // CORS origins
     for origin in args.cors_allow_origin.into_iter() {
@@ -1101,6 +1946,24 @@ fn spawn_webserver(
         router_args.push(origin);
     }
 
+    // Hedging
+    if let Some(hedging_endpoint) = args.hedging_endpoint {
+        router_args.push("--hedging-endpoint".to_string());
+        router_args.push(hedging_endpoint);
+    }
+
+    // Experiments
+    if let Some(experiments_config) = args.experiments_config {
+        router_args.push("--experiments-config".to_string());
+        router_args.push(experiments_config);
+    }
+
+    // Baggage keys
+    for key in args.baggage_keys.into_iter() {
+        router_args.push("--baggage-keys".to_string());
+        router_args.push(key);
+    }
+
     // Ngrok
     if args.ngrok {
         router_args.push("--ngrok".to_string());
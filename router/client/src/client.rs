@@ -4,6 +4,7 @@ use crate::pb::generate::v2::*;
 use crate::Result;
 use grpc_metadata::InjectTelemetryContext;
 use std::cmp::min;
+use std::collections::HashMap;
 use std::time::Duration;
 use tonic::transport::{Channel, Uri};
 use tracing::instrument;
@@ -72,6 +73,30 @@ impl Client {
         Ok(response)
     }
 
+    /// Get the resident LoRA adapters
+    #[instrument(skip(self))]
+    pub async fn adapters(&mut self) -> Result<Vec<AdapterInfo>> {
+        let request = tonic::Request::new(AdaptersRequest {}).inject_context();
+        let response = self.stub.adapters(request).await?.into_inner();
+        Ok(response.adapters)
+    }
+
+    /// Load a LoRA adapter
+    #[instrument(skip(self))]
+    pub async fn load_adapter(&mut self, adapter_id: String) -> Result<Vec<AdapterInfo>> {
+        let request = tonic::Request::new(LoadAdapterRequest { adapter_id }).inject_context();
+        let response = self.stub.load_adapter(request).await?.into_inner();
+        Ok(response.adapters)
+    }
+
+    /// Evict a LoRA adapter
+    #[instrument(skip(self))]
+    pub async fn unload_adapter(&mut self, adapter_id: String) -> Result<Vec<AdapterInfo>> {
+        let request = tonic::Request::new(UnloadAdapterRequest { adapter_id }).inject_context();
+        let response = self.stub.unload_adapter(request).await?.into_inner();
+        Ok(response.adapters)
+    }
+
     /// Clear the past generations cache
     #[instrument(skip(self))]
     pub async fn clear_cache(&mut self, batch_id: Option<u64>) -> Result<()> {
@@ -130,14 +155,26 @@ impl Client {
                     watermark: true,
                     grammar: String::new(),
                     grammar_type: GrammarType::None as i32,
+                    suppressed_tokens: vec![],
+                    temperature_schedule: vec![],
+                    logit_bias: HashMap::new(),
                 }),
                 stopping_parameters: Some(StoppingCriteriaParameters {
                     max_new_tokens: max_total_tokens - truncate,
                     stop_sequences: vec![],
                     ignore_eos_token: true,
+                    stop_after_tool_call: false,
+                    deadline: None,
+                    eos_probability_threshold: None,
+                    eos_probability_window: 0,
                 }),
                 prefill_logprobs: true,
                 top_n_tokens: 20,
+                skip_special_tokens: true,
+                extensions: HashMap::new(),
+                prefill_group: 0,
+                adapter_id: String::new(),
+                adapter_parameters: None,
             });
             n_tokens += max_input_length;
 
@@ -179,7 +216,12 @@ impl Client {
         Ok((
             response.generations,
             response.batch,
-            PrefillTimings::new(response.forward_ns, response.decode_ns, response.total_ns),
+            PrefillTimings::new(
+                response.grammar_compile_ns,
+                response.forward_ns,
+                response.decode_ns,
+                response.total_ns,
+            ),
         ))
     }
 
@@ -208,14 +250,21 @@ impl Client {
 }
 
 pub struct PrefillTimings {
+    pub grammar_compile: Option<Duration>,
     pub forward: Duration,
     pub decode: Duration,
     pub total: Duration,
 }
 
 impl PrefillTimings {
-    fn new(forward_ns: u64, decode_ns: u64, total_ns: u64) -> Self {
+    fn new(
+        grammar_compile_ns: Option<u64>,
+        forward_ns: u64,
+        decode_ns: u64,
+        total_ns: u64,
+    ) -> Self {
         Self {
+            grammar_compile: grammar_compile_ns.map(Duration::from_nanos),
             forward: Duration::from_nanos(forward_ns),
             decode: Duration::from_nanos(decode_ns),
             total: Duration::from_nanos(total_ns),
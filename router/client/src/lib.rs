@@ -7,10 +7,12 @@ mod sharded_client;
 
 pub use client::Client;
 pub use pb::generate::v2::HealthResponse;
+pub use pb::generate::v2::AdapterInfo;
 pub use pb::generate::v2::InfoResponse as ShardInfo;
 pub use pb::generate::v2::{
-    Batch, CachedBatch, FinishReason, GeneratedText, Generation, GrammarType,
-    NextTokenChooserParameters, Request, StoppingCriteriaParameters, Tokens,
+    AdapterParameters, Batch, CachedBatch, FinishReason, GeneratedText, Generation, GrammarType,
+    NextTokenChooserParameters, Request, StoppingCriteriaParameters, TemperatureSchedulePoint,
+    Tokens,
 };
 pub use sharded_client::ShardedClient;
 use thiserror::Error;
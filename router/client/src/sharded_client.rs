@@ -1,6 +1,6 @@
 use crate::client::{DecodeTimings, PrefillTimings};
 /// Multi shard Client
-use crate::{Batch, CachedBatch, Client, Generation, HealthResponse, ShardInfo};
+use crate::{AdapterInfo, Batch, CachedBatch, Client, Generation, HealthResponse, ShardInfo};
 use crate::{ClientError, Result};
 use futures::future::join_all;
 use tonic::transport::Uri;
@@ -39,6 +39,12 @@ impl ShardedClient {
         Self::from_master_client(master_client).await
     }
 
+    /// Returns a client without any shard, for routers started without a backend.
+    /// Batch calls fail with `ClientError::EmptyResults`.
+    pub fn without_shards() -> Self {
+        Self::new(vec![])
+    }
+
     /// Get the model info
     #[instrument(skip(self))]
     pub async fn info(&mut self) -> Result<ShardInfo> {
@@ -58,7 +64,79 @@ impl ShardedClient {
             .iter_mut()
             .map(|client| client.health())
             .collect();
-        join_all(futures).await.pop().unwrap()
+        let results = join_all(futures)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<HealthResponse>>>()?;
+        // Report the memory pressure of the most loaded shard
+        let memory_pressure = results
+            .into_iter()
+            .filter_map(|response| response.memory_pressure)
+            .reduce(f32::max);
+        Ok(HealthResponse {
+            memory_pressure,
+            ..Default::default()
+        })
+    }
+
+    /// GRPC health check of each shard
+    #[instrument(skip(self))]
+    pub async fn shards_health(&mut self) -> Vec<Result<HealthResponse>> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| client.health())
+            .collect();
+        join_all(futures).await
+    }
+
+    /// Get the resident LoRA adapters
+    #[instrument(skip(self))]
+    pub async fn adapters(&mut self) -> Result<Vec<AdapterInfo>> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| client.adapters())
+            .collect();
+        merge_adapters(join_all(futures).await)
+    }
+
+    /// Load a LoRA adapter on every shard. When some shards fail to load it, the adapter is
+    /// unloaded from the others so that all the shards keep the same adapters
+    #[instrument(skip(self))]
+    pub async fn load_adapter(&mut self, adapter_id: String) -> Result<Vec<AdapterInfo>> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| Box::pin(client.load_adapter(adapter_id.clone())))
+            .collect();
+        let results = join_all(futures).await;
+        if results.iter().any(Result::is_err) {
+            let futures: Vec<_> = self
+                .clients
+                .iter_mut()
+                .zip(&results)
+                .filter(|(_, result)| result.is_ok())
+                .map(|(client, _)| Box::pin(client.unload_adapter(adapter_id.clone())))
+                .collect();
+            for result in join_all(futures).await {
+                if let Err(err) = result {
+                    tracing::error!("Failed to unload the partially loaded adapter: {err}");
+                }
+            }
+        }
+        merge_adapters(results)
+    }
+
+    /// Evict a LoRA adapter from every shard
+    #[instrument(skip(self))]
+    pub async fn unload_adapter(&mut self, adapter_id: String) -> Result<Vec<AdapterInfo>> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| Box::pin(client.unload_adapter(adapter_id.clone())))
+            .collect();
+        merge_adapters(join_all(futures).await)
     }
 
     /// Clear the past generations cache
@@ -185,3 +263,19 @@ impl ShardedClient {
         Ok((generations, next_batch, timings))
     }
 }
+
+/// Adapters resident on the shards, with the memory of their weights summed over the shards
+fn merge_adapters(results: Vec<Result<Vec<AdapterInfo>>>) -> Result<Vec<AdapterInfo>> {
+    let mut results = results.into_iter().collect::<Result<Vec<_>>>()?.into_iter();
+    let mut adapters = results.next().ok_or(ClientError::EmptyResults)?;
+    for shard_adapters in results {
+        for adapter in &mut adapters {
+            adapter.memory_bytes += shard_adapters
+                .iter()
+                .filter(|shard_adapter| shard_adapter.adapter_id == adapter.adapter_id)
+                .map(|shard_adapter| shard_adapter.memory_bytes)
+                .sum::<u64>();
+        }
+    }
+    Ok(adapters)
+}
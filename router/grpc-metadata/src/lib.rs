@@ -1,8 +1,9 @@
 //! A crate to extract and inject a OpenTelemetry context from and to a gRPC request.
 //! Inspired by: https://github.com/open-telemetry/opentelemetry-rust gRPC examples
 
-use opentelemetry::global;
+use opentelemetry::baggage::BaggageExt;
 use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::{global, Context, KeyValue};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 
 /// Extract context metadata from a gRPC request's metadata
@@ -40,13 +41,17 @@ impl<'a> Injector for MetadataInjector<'a> {
     }
 }
 
-/// Get a context from the global context and inject the span into a gRPC request's metadata.
+/// Get a context from the global context and inject the span and the current baggage into a
+/// gRPC request's metadata.
 fn inject(metadata: &mut tonic::metadata::MetadataMap) {
+    let baggage: Vec<KeyValue> = Context::current()
+        .baggage()
+        .iter()
+        .map(|(key, (value, _))| KeyValue::new(key.clone(), value.clone()))
+        .collect();
+    let context = tracing::Span::current().context().with_baggage(baggage);
     global::get_text_map_propagator(|propagator| {
-        propagator.inject_context(
-            &tracing::Span::current().context(),
-            &mut MetadataInjector(metadata),
-        )
+        propagator.inject_context(&context, &mut MetadataInjector(metadata))
     })
 }
 
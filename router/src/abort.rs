@@ -0,0 +1,188 @@
+/// Abort of the in-flight generations by request id, e.g. by a moderation system watching the
+/// streams
+use crate::infer::{InferError, InferStreamResponse};
+use crate::ErrorResponse;
+use axum::body::Body;
+use axum::extract::Extension;
+use axum::http::header::AUTHORIZATION;
+use axum::http::{HeaderMap, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use opentelemetry::Context;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use text_generation_client::FinishReason;
+use tokio::sync::mpsc;
+
+type ResponseSender = mpsc::UnboundedSender<Result<InferStreamResponse, InferError>>;
+
+/// Request id sent in the `x-request-id` header, stored in the current OpenTelemetry context
+#[derive(Clone, Debug)]
+struct RequestId(String);
+
+/// Context of a request with the given `x-request-id`
+pub(crate) fn with_request_id(request_id: String) -> Context {
+    Context::current_with_value(RequestId(request_id))
+}
+
+/// Request id of the current OpenTelemetry context
+pub(crate) fn request_id() -> Option<String> {
+    Context::current()
+        .get::<RequestId>()
+        .map(|request_id| request_id.0.clone())
+}
+
+/// Finish reason set by the router to end a generation before its stopping criteria. The
+/// batching task ends the generation at its next token, with the text generated so far
+#[derive(Clone, Debug)]
+pub(crate) struct Interruption(Arc<AtomicI32>);
+
+impl Default for Interruption {
+    fn default() -> Self {
+        Self(Arc::new(AtomicI32::new(-1)))
+    }
+}
+
+impl Interruption {
+    /// Interrupt the generation, the first reason is kept
+    pub(crate) fn interrupt(&self, finish_reason: FinishReason) {
+        let _ =
+            self.0
+                .compare_exchange(-1, finish_reason as i32, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    pub(crate) fn finish_reason(&self) -> Option<FinishReason> {
+        FinishReason::try_from(self.0.load(Ordering::SeqCst)).ok()
+    }
+}
+
+/// Response channels of the in-flight generations by request id. A request generating several
+/// sequences (`best_of`, `n`) has a channel per sequence.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Aborts {
+    senders: Arc<Mutex<HashMap<String, Vec<(ResponseSender, Interruption)>>>>,
+}
+
+impl Aborts {
+    pub(crate) fn register(
+        &self,
+        request_id: String,
+        sender: ResponseSender,
+        interruption: Interruption,
+    ) {
+        let mut senders = self.senders.lock().unwrap();
+        // The channels of the finished generations are closed
+        senders.retain(|_, request_senders| {
+            request_senders.retain(|(sender, _)| !sender.is_closed());
+            !request_senders.is_empty()
+        });
+        senders
+            .entry(request_id)
+            .or_default()
+            .push((sender, interruption));
+    }
+
+    /// End the generations of `request_id` at their next token with the `aborted` finish
+    /// reason. Returns whether an in-flight generation was found.
+    pub(crate) fn abort(&self, request_id: &str) -> bool {
+        let senders = self.senders.lock().unwrap().remove(request_id);
+        let mut found = false;
+        for (sender, interruption) in senders.into_iter().flatten() {
+            // Closed if the generation already finished
+            if !sender.is_closed() {
+                interruption.interrupt(FinishReason::Aborted);
+                found = true;
+            }
+        }
+        if found {
+            metrics::increment_counter!("tgi_request_aborted");
+            tracing::info!("Aborted request {request_id}");
+        }
+        found
+    }
+}
+
+/// API key of the abort requests
+#[derive(Clone)]
+pub(crate) struct AbortApiKey(pub String);
+
+/// API key of the `/admin` routes
+#[derive(Clone)]
+pub(crate) struct AdminApiKey(pub String);
+
+/// Reject the `/admin` requests without the admin API key
+pub(crate) async fn authorize_admin(
+    Extension(AdminApiKey(api_key)): Extension<AdminApiKey>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    if !authorized(request.headers(), &api_key) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(ErrorResponse {
+                error: "Invalid admin API key".to_string(),
+                error_type: "unauthorized".to_string(),
+            }),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// Check the `Authorization: Bearer <key>` header of an abort or admin request
+pub(crate) fn authorized(headers: &HeaderMap, api_key: &str) -> bool {
+    let Some(token) = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    // Constant time comparison
+    token.len() == api_key.len()
+        && token
+            .bytes()
+            .zip(api_key.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_abort() {
+        let aborts = Aborts::default();
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        let interruption = Interruption::default();
+        aborts.register("request".to_string(), sender, interruption.clone());
+
+        assert!(!aborts.abort("other"));
+        assert_eq!(interruption.finish_reason(), None);
+        assert!(aborts.abort("request"));
+        assert_eq!(interruption.finish_reason(), Some(FinishReason::Aborted));
+        // Only aborted once, with its first reason
+        assert!(!aborts.abort("request"));
+        interruption.interrupt(FinishReason::Cancelled);
+        assert_eq!(interruption.finish_reason(), Some(FinishReason::Aborted));
+
+        // Finished generations are not found
+        let (sender, receiver) = mpsc::unbounded_channel();
+        aborts.register("finished".to_string(), sender, Interruption::default());
+        drop(receiver);
+        assert!(!aborts.abort("finished"));
+    }
+
+    #[test]
+    fn test_authorized() {
+        let mut headers = HeaderMap::new();
+        assert!(!authorized(&headers, "key"));
+        headers.insert(AUTHORIZATION, "Bearer other".parse().unwrap());
+        assert!(!authorized(&headers, "key"));
+        headers.insert(AUTHORIZATION, "Bearer key".parse().unwrap());
+        assert!(authorized(&headers, "key"));
+    }
+}
@@ -0,0 +1,186 @@
+/// Runtime loading and eviction of the LoRA adapters of the shards from the admin API
+use crate::infer::Infer;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use text_generation_client::{AdapterInfo, ClientError, ShardedClient};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct LoadAdapterRequest {
+    /// Hub id or local path of the adapter
+    #[schema(example = "my-org/support-lora")]
+    pub adapter_id: String,
+}
+
+/// LoRA adapter resident on the shards
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub(crate) struct Adapter {
+    #[schema(example = "my-org/support-lora")]
+    pub adapter_id: String,
+    /// Memory of the adapter weights summed over the shards, in bytes
+    #[schema(example = 6291456)]
+    pub memory_bytes: u64,
+}
+
+impl From<AdapterInfo> for Adapter {
+    fn from(adapter: AdapterInfo) -> Self {
+        Self {
+            adapter_id: adapter.adapter_id,
+            memory_bytes: adapter.memory_bytes,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum AdapterError {
+    #[error("`adapter_id` must not be empty")]
+    EmptyId,
+    #[error("LoRA adapter `{0}` is not in the adapter allowlist")]
+    NotAllowed(String),
+    #[error("LoRA adapter `{0}` is not loaded")]
+    NotLoaded(String),
+    #[error("LoRA adapter `{0}` is used by {1} requests in flight")]
+    InUse(String, usize),
+    #[error("Shards failed to update the adapters: {0}")]
+    Shard(#[from] ClientError),
+}
+
+impl AdapterError {
+    pub(crate) fn error_type(&self) -> &str {
+        match self {
+            AdapterError::EmptyId => "validation",
+            AdapterError::NotAllowed(_) => "adapter_not_allowed",
+            AdapterError::NotLoaded(_) => "not_found",
+            AdapterError::InUse(_, _) => "adapter_in_use",
+            AdapterError::Shard(_) => "adapter",
+        }
+    }
+}
+
+/// Loads and evicts the adapters of the shards, one operation at a time, and updates the
+/// adapters accepted by the validation
+#[derive(Clone)]
+pub(crate) struct Adapters {
+    client: Arc<Mutex<ShardedClient>>,
+    /// Adapters that may be loaded, any adapter when empty
+    allowlist: Arc<Vec<String>>,
+}
+
+impl Adapters {
+    pub(crate) fn new(client: ShardedClient, allowlist: Vec<String>) -> Self {
+        Self {
+            client: Arc::new(Mutex::new(client)),
+            allowlist: Arc::new(allowlist),
+        }
+    }
+
+    /// Resident adapters and their memory
+    pub(crate) async fn list(&self, infer: &Infer) -> Result<Vec<Adapter>, AdapterError> {
+        let adapters = self.client.lock().await.adapters().await?;
+        Ok(update(infer, adapters))
+    }
+
+    /// Load an adapter, the requests selecting it being accepted once it is resident on all
+    /// the shards
+    pub(crate) async fn load(
+        &self,
+        infer: &Infer,
+        adapter_id: String,
+    ) -> Result<Vec<Adapter>, AdapterError> {
+        if adapter_id.is_empty() {
+            return Err(AdapterError::EmptyId);
+        }
+        if !self.allowlist.is_empty() && !self.allowlist.contains(&adapter_id) {
+            return Err(AdapterError::NotAllowed(adapter_id));
+        }
+        let mut client = self.client.lock().await;
+        tracing::info!("Loading LoRA adapter {adapter_id}");
+        // The adapter is only accepted once loaded by all the shards
+        let adapters = client.load_adapter(adapter_id).await?;
+        Ok(update(infer, adapters))
+    }
+
+    /// Evict an adapter and reject the new requests selecting it. The adapters still used by
+    /// queued or running requests are not evicted: the shards would finish them with the base
+    /// model
+    pub(crate) async fn unload(
+        &self,
+        infer: &Infer,
+        adapter_id: String,
+    ) -> Result<Vec<Adapter>, AdapterError> {
+        let mut client = self.client.lock().await;
+        if !infer.adapters().contains(&adapter_id) {
+            return Err(AdapterError::NotLoaded(adapter_id));
+        }
+        // Rejected before the eviction, even if some shards fail to evict it
+        infer
+            .remove_adapter(&adapter_id)
+            .map_err(|in_flight| AdapterError::InUse(adapter_id.clone(), in_flight))?;
+        tracing::info!("Unloading LoRA adapter {adapter_id}");
+        let adapters = client.unload_adapter(adapter_id).await?;
+        Ok(update(infer, adapters))
+    }
+}
+
+/// Requests in flight of each LoRA adapter
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AdapterLeases(Arc<std::sync::Mutex<HashMap<String, usize>>>);
+
+impl AdapterLeases {
+    pub(crate) fn acquire(&self, adapter_id: &str) -> Arc<AdapterLease> {
+        *self
+            .0
+            .lock()
+            .unwrap()
+            .entry(adapter_id.to_string())
+            .or_default() += 1;
+        Arc::new(AdapterLease {
+            adapter_id: adapter_id.to_string(),
+            leases: self.clone(),
+        })
+    }
+
+    pub(crate) fn in_flight(&self, adapter_id: &str) -> usize {
+        self.0
+            .lock()
+            .unwrap()
+            .get(adapter_id)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Use of a LoRA adapter by a queued or running request, shared by the clones of the request
+#[derive(Debug)]
+pub(crate) struct AdapterLease {
+    adapter_id: String,
+    leases: AdapterLeases,
+}
+
+impl Drop for AdapterLease {
+    fn drop(&mut self) {
+        let mut leases = self.leases.0.lock().unwrap();
+        if let Some(count) = leases.get_mut(&self.adapter_id) {
+            *count -= 1;
+            if *count == 0 {
+                leases.remove(&self.adapter_id);
+            }
+        }
+    }
+}
+
+/// Accept the resident adapters in the requests
+fn update(infer: &Infer, adapters: Vec<AdapterInfo>) -> Vec<Adapter> {
+    let adapters: Vec<Adapter> = adapters.into_iter().map(Adapter::from).collect();
+    infer.set_adapters(
+        adapters
+            .iter()
+            .map(|adapter| adapter.adapter_id.clone())
+            .collect(),
+    );
+    metrics::gauge!("tgi_adapter_count", adapters.len() as f64);
+    adapters
+}
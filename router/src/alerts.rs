@@ -0,0 +1,183 @@
+/// Webhook paging the operators on serious errors before the metric alerts fire
+use axum::http::header::CONTENT_TYPE;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+/// Maximum number of pending alerts; new alerts are dropped while the webhook lags behind
+const ALERT_CHANNEL_SIZE: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AlertKind {
+    /// The router could not reach a shard
+    ShardDisconnected,
+    /// A shard failed a prefill or decode RPC
+    ShardError,
+    /// A request ended without its last token
+    IncompleteGeneration,
+    /// The circuit breaker around the shard RPCs opened
+    CircuitBreakerOpened,
+}
+
+#[derive(Debug)]
+struct AlertEvent {
+    kind: AlertKind,
+    message: String,
+    /// Seconds since the UNIX epoch
+    at: u64,
+}
+
+/// Alerts of the same kind raised during a batching interval
+#[derive(Debug, PartialEq, Serialize)]
+struct Alert {
+    kind: AlertKind,
+    /// Message of the last alert
+    message: String,
+    count: u64,
+    first_at: u64,
+    last_at: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct AlertPayload {
+    alerts: Vec<Alert>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Alerts {
+    sender: mpsc::Sender<AlertEvent>,
+}
+
+impl Alerts {
+    /// Spawn the background task posting the alerts to `url` at most once per `interval`
+    pub(crate) fn new(url: String, interval: Duration) -> Self {
+        let (sender, receiver) = mpsc::channel(ALERT_CHANNEL_SIZE);
+        tokio::spawn(alert_task(reqwest::Client::new(), url, interval, receiver));
+        Self { sender }
+    }
+
+    /// Raise an alert without waiting for the webhook
+    pub(crate) fn alert(&self, kind: AlertKind, message: impl Into<String>) {
+        let event = AlertEvent {
+            kind,
+            message: message.into(),
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+        if self.sender.try_send(event).is_err() {
+            metrics::increment_counter!("tgi_alert_dropped");
+        }
+    }
+}
+
+/// Merge an event into the alerts of the current interval
+fn coalesce(alerts: &mut BTreeMap<AlertKind, Alert>, event: AlertEvent) {
+    alerts
+        .entry(event.kind)
+        .and_modify(|alert| {
+            alert.count += 1;
+            alert.last_at = event.at;
+        })
+        .or_insert_with(|| Alert {
+            kind: event.kind,
+            message: String::new(),
+            count: 1,
+            first_at: event.at,
+            last_at: event.at,
+        })
+        .message = event.message;
+}
+
+/// Wait for a first alert, batch all the alerts raised during `interval` and post them
+async fn alert_task(
+    client: reqwest::Client,
+    url: String,
+    interval: Duration,
+    mut receiver: mpsc::Receiver<AlertEvent>,
+) {
+    while let Some(event) = receiver.recv().await {
+        let mut alerts = BTreeMap::new();
+        coalesce(&mut alerts, event);
+
+        let window = tokio::time::sleep(interval);
+        tokio::pin!(window);
+        loop {
+            tokio::select! {
+                _ = &mut window => break,
+                event = receiver.recv() => match event {
+                    Some(event) => coalesce(&mut alerts, event),
+                    None => break,
+                },
+            }
+        }
+
+        let payload = AlertPayload {
+            alerts: alerts.into_values().collect(),
+        };
+        let body = serde_json::to_vec(&payload).expect("alerts are serializable");
+        let response = client
+            .post(&url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match response {
+            Ok(_) => metrics::increment_counter!("tgi_alert_webhook_success"),
+            Err(err) => {
+                metrics::increment_counter!("tgi_alert_webhook_failure");
+                tracing::error!("Could not send the alerts to the webhook: {err}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coalesce() {
+        let mut alerts = BTreeMap::new();
+        let events = [
+            (AlertKind::ShardError, "first", 10),
+            (AlertKind::CircuitBreakerOpened, "opened", 11),
+            (AlertKind::ShardError, "second", 12),
+        ];
+        for (kind, message, at) in events {
+            coalesce(
+                &mut alerts,
+                AlertEvent {
+                    kind,
+                    message: message.to_string(),
+                    at,
+                },
+            );
+        }
+
+        let alerts: Vec<Alert> = alerts.into_values().collect();
+        assert_eq!(
+            alerts,
+            vec![
+                Alert {
+                    kind: AlertKind::ShardError,
+                    message: "second".to_string(),
+                    count: 2,
+                    first_at: 10,
+                    last_at: 12,
+                },
+                Alert {
+                    kind: AlertKind::CircuitBreakerOpened,
+                    message: "opened".to_string(),
+                    count: 1,
+                    first_at: 11,
+                    last_at: 11,
+                },
+            ]
+        );
+    }
+}
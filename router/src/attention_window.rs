@@ -0,0 +1,139 @@
+/// Sliding attention windows: the models only attending to the last `window_size` tokens, e.g.
+/// Mistral, no longer see the start of the longer sequences, such as their system prompt
+use crate::validation::ValidationError;
+use crate::warnings;
+use opentelemetry::trace::{FutureExt, WithContext};
+use opentelemetry::Context;
+use std::future::Future;
+
+/// What is done with the chat requests whose system prompt would fall outside the attention
+/// window before the end of their generation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SystemPromptPolicy {
+    /// Add a warning to the response
+    Warn,
+    /// Reject the request
+    Reject,
+}
+
+impl std::str::FromStr for SystemPromptPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "warn" => Ok(SystemPromptPolicy::Warn),
+            "reject" => Ok(SystemPromptPolicy::Reject),
+            _ => Err(format!(
+                "unknown system prompt policy `{policy}`, expected `warn` or `reject`"
+            )),
+        }
+    }
+}
+
+/// Marks the chat requests starting with a system prompt, stored in the current OpenTelemetry
+/// context
+#[derive(Clone, Copy, Debug)]
+struct SystemPrompt;
+
+/// Create the future of `generation` marked as starting with a system prompt if
+/// `system_prompt`, keeping the values of the current context. The context is attached to the
+/// future, polled after this returns
+pub(crate) fn with_system_prompt<F: Future>(
+    system_prompt: bool,
+    generation: impl FnOnce() -> F,
+) -> WithContext<F> {
+    let context = match system_prompt {
+        true => Context::current_with_value(SystemPrompt),
+        false => Context::current(),
+    };
+    let _guard = context.clone().attach();
+    generation().with_context(context)
+}
+
+/// Number of prompt tokens outside the attention window once `generated_tokens` are generated,
+/// if any
+pub(crate) fn tokens_outside(
+    window_size: Option<u32>,
+    input_length: u32,
+    generated_tokens: u32,
+) -> Option<u32> {
+    let outside = input_length
+        .saturating_add(generated_tokens)
+        .saturating_sub(window_size?)
+        .min(input_length);
+    (outside > 0).then_some(outside)
+}
+
+/// Attention window of the model and the handling of the system prompts falling outside of it
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct AttentionWindow {
+    pub window_size: u32,
+    pub policy: SystemPromptPolicy,
+}
+
+impl AttentionWindow {
+    /// Warn about or reject the chat request of the current context if its system prompt falls
+    /// outside the window before `max_new_tokens` are generated
+    pub(crate) fn check(
+        &self,
+        input_length: u32,
+        max_new_tokens: u32,
+    ) -> Result<(), ValidationError> {
+        if Context::current().get::<SystemPrompt>().is_none() {
+            return Ok(());
+        }
+        let Some(outside) = tokens_outside(Some(self.window_size), input_length, max_new_tokens)
+        else {
+            return Ok(());
+        };
+        metrics::increment_counter!("tgi_request_system_prompt_outside_window");
+        match self.policy {
+            SystemPromptPolicy::Warn => {
+                warnings::warn(format!(
+                    "the system prompt falls outside the attention window of {} tokens: up to {outside} prompt tokens are not attended by the end of the generation",
+                    self.window_size
+                ));
+                Ok(())
+            }
+            SystemPromptPolicy::Reject => Err(ValidationError::SystemPromptOutsideWindow(
+                self.window_size,
+                input_length,
+                max_new_tokens,
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_outside() {
+        // Full attention
+        assert_eq!(tokens_outside(None, 5000, 1000), None);
+        // Within the window
+        assert_eq!(tokens_outside(Some(4096), 3000, 1000), None);
+        // The window slid over the start of the prompt
+        assert_eq!(tokens_outside(Some(4096), 4000, 100), Some(4));
+        // Over the whole prompt
+        assert_eq!(tokens_outside(Some(4096), 100, 5000), Some(100));
+    }
+
+    #[test]
+    fn test_system_prompt_policy() {
+        let window = AttentionWindow {
+            window_size: 4096,
+            policy: "reject".parse().unwrap(),
+        };
+        // Only the chat requests starting with a system prompt are checked
+        assert!(window.check(4000, 1000).is_ok());
+        let _guard = Context::current_with_value(SystemPrompt).attach();
+        assert!(window.check(3000, 1000).is_ok());
+        assert!(matches!(
+            window.check(4000, 1000),
+            Err(ValidationError::SystemPromptOutsideWindow(4096, 4000, 1000))
+        ));
+        assert!("drop".parse::<SystemPromptPolicy>().is_err());
+    }
+}
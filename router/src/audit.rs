@@ -0,0 +1,416 @@
+/// In-memory store of the completed requests
+use crate::audit_keys::{AuditKeys, Sealed};
+use crate::tenant::Tenant;
+use crate::{Details, ErrorResponse};
+use axum::body::{Bytes, Full, HttpBody};
+use axum::extract::Extension;
+use axum::http::{Method, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
+
+/// Default number of requests returned by `/admin/requests`
+const DEFAULT_REQUESTS_LIMIT: usize = 100;
+/// Maximum number of requests returned by `/admin/requests`
+const MAX_REQUESTS_LIMIT: usize = 1000;
+
+/// Bounded store of the generation details of the last completed requests
+#[derive(Clone)]
+pub(crate) struct AuditStore {
+    state: Arc<Mutex<AuditState>>,
+    /// Keys encrypting the stored details
+    keys: Option<AuditKeys>,
+}
+
+/// Generation details, encrypted when the store has keys
+enum StoredDetails {
+    Plain(Details),
+    Encrypted(Sealed),
+}
+
+struct AuditState {
+    /// Maximum number of stored requests
+    capacity: usize,
+    /// Clients and request ids of the stored details, in insertion order
+    order: VecDeque<(String, String)>,
+    /// Generation details by client and request id: the request ids are set by the clients, a
+    /// client can only read its own requests
+    details: HashMap<(String, String), StoredDetails>,
+    /// Last completed requests, oldest first
+    records: VecDeque<RequestRecord>,
+    /// Sequence number of the next record
+    next_seq: u64,
+}
+
+/// Completed request, as listed by `/admin/requests`
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct RequestRecord {
+    #[serde(skip)]
+    seq: u64,
+    #[schema(example = "0af7651916cd43dd8448eb211c80319c")]
+    pub request_id: String,
+    #[schema(example = "/generate")]
+    pub route: String,
+    #[schema(example = 424)]
+    pub status: u16,
+    /// `error_type` of the error response
+    #[schema(nullable = true, example = "generation")]
+    pub error_type: Option<String>,
+    #[schema(example = 1532)]
+    pub duration_ms: u64,
+    /// Tenant of the request, identified by its API key or named by a trusted proxy
+    #[schema(nullable = true, example = "team-a")]
+    pub tenant: Option<String>,
+    /// Unix timestamp (in seconds) of the request start
+    #[schema(example = 1706270835)]
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RequestStatus {
+    Success,
+    Error,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub(crate) struct RequestsQuery {
+    /// Only return the successful or the failed requests
+    pub status: Option<RequestStatus>,
+    /// Only return the requests started at or after this unix timestamp (in seconds)
+    pub since: Option<u64>,
+    /// Maximum number of requests returned
+    pub limit: Option<usize>,
+    /// `next_cursor` of the previous page
+    pub cursor: Option<u64>,
+}
+
+/// Page of completed requests, newest first
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct RequestsPage {
+    pub requests: Vec<RequestRecord>,
+    /// Cursor of the next page, null on the last page
+    #[schema(nullable = true, example = 1042)]
+    pub next_cursor: Option<u64>,
+}
+
+impl AuditStore {
+    pub(crate) fn new(capacity: usize, keys: Option<AuditKeys>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(AuditState {
+                capacity,
+                order: VecDeque::with_capacity(capacity),
+                details: HashMap::with_capacity(capacity),
+                records: VecDeque::with_capacity(capacity),
+                next_seq: 0,
+            })),
+            keys,
+        }
+    }
+
+    /// Store the details of a request of `client`, evicting the oldest request if the store is
+    /// full. With keys, the details are encrypted and bound to the request id.
+    pub(crate) fn insert_details(&self, client: String, request_id: String, details: Details) {
+        let details = match &self.keys {
+            None => StoredDetails::Plain(details),
+            Some(keys) => {
+                let sealed = serde_json::to_vec(&details)
+                    .map_err(|err| err.to_string())
+                    .and_then(|content| keys.encrypt(request_id.as_bytes(), &content));
+                match sealed {
+                    Ok(sealed) => StoredDetails::Encrypted(sealed),
+                    Err(err) => {
+                        tracing::warn!("Could not encrypt the details of {request_id}: {err}");
+                        return;
+                    }
+                }
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let key = (client, request_id);
+        // A request id sent again replaces the details of the previous request
+        if state.details.insert(key.clone(), details).is_some() {
+            return;
+        }
+        if state.order.len() == state.capacity {
+            if let Some(oldest) = state.order.pop_front() {
+                state.details.remove(&oldest);
+            }
+        }
+        state.order.push_back(key);
+    }
+
+    /// Get the details of a request of `client`
+    pub(crate) fn details(&self, client: &str, request_id: &str) -> Option<Details> {
+        let key = (client.to_string(), request_id.to_string());
+        let sealed = match self.state.lock().unwrap().details.get(&key)? {
+            StoredDetails::Plain(details) => return Some(details.clone()),
+            StoredDetails::Encrypted(sealed) => sealed.clone(),
+        };
+
+        let keys = self.keys.as_ref()?;
+        let details = keys
+            .decrypt(request_id.as_bytes(), &sealed)
+            .and_then(|content| serde_json::from_slice(&content).map_err(|err| err.to_string()));
+        match details {
+            Ok(details) => Some(details),
+            Err(err) => {
+                tracing::warn!("Could not decrypt the details of {request_id}: {err}");
+                None
+            }
+        }
+    }
+
+    /// Store a completed request, evicting the oldest record if the store is full
+    pub(crate) fn insert_record(&self, mut record: RequestRecord) {
+        let mut state = self.state.lock().unwrap();
+        if state.records.len() == state.capacity {
+            state.records.pop_front();
+        }
+        record.seq = state.next_seq;
+        state.next_seq += 1;
+        state.records.push_back(record);
+    }
+
+    /// List the completed requests, newest first.
+    /// Cursors are record sequence numbers: pages stay stable while new requests are stored.
+    pub(crate) fn requests(&self, query: &RequestsQuery) -> RequestsPage {
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_REQUESTS_LIMIT)
+            .clamp(1, MAX_REQUESTS_LIMIT);
+
+        let state = self.state.lock().unwrap();
+        let mut matching = state
+            .records
+            .iter()
+            .rev()
+            .filter(|record| query.cursor.map_or(true, |cursor| record.seq < cursor))
+            .filter(|record| query.since.map_or(true, |since| record.timestamp >= since))
+            .filter(|record| match query.status {
+                None => true,
+                Some(RequestStatus::Success) => record.status < 400,
+                Some(RequestStatus::Error) => record.status >= 400,
+            });
+
+        let requests: Vec<RequestRecord> = matching.by_ref().take(limit).cloned().collect();
+        let next_cursor = match matching.next() {
+            Some(_) => requests.last().map(|record| record.seq),
+            None => None,
+        };
+        RequestsPage {
+            requests,
+            next_cursor,
+        }
+    }
+}
+
+/// Middleware storing the outcome of the inference requests
+pub(crate) async fn record<B>(
+    Extension(audit): Extension<AuditStore>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if request.method() != Method::POST {
+        return next.run(request).await;
+    }
+
+    let start_time = Instant::now();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0);
+    let route = request.uri().path().to_string();
+    let tenant = request
+        .extensions()
+        .get::<Tenant>()
+        .filter(|tenant| tenant.identified)
+        .map(|tenant| tenant.id.clone());
+
+    let response = next.run(request).await;
+    let status = response.status();
+    let request_id = response
+        .headers()
+        .get("x-request-id")
+        .and_then(|request_id| request_id.to_str().ok())
+        .map(String::from)
+        .unwrap_or_else(|| format!("{:032x}", thread_rng().gen::<u128>()));
+
+    // Read the error type from the error body
+    let (response, error_type) = if status.is_client_error() || status.is_server_error() {
+        let (parts, mut body) = response.into_parts();
+        let mut content = Vec::new();
+        while let Some(Ok(chunk)) = body.data().await {
+            content.extend_from_slice(&chunk);
+        }
+        let error_type = serde_json::from_slice::<ErrorResponse>(&content)
+            .ok()
+            .map(|error| error.error_type);
+        let body = axum::body::boxed(Full::from(Bytes::from(content)));
+        (Response::from_parts(parts, body), error_type)
+    } else {
+        (response, None)
+    };
+
+    audit.insert_record(RequestRecord {
+        seq: 0,
+        request_id,
+        route,
+        status: status.as_u16(),
+        error_type,
+        duration_ms: start_time.elapsed().as_millis() as u64,
+        tenant,
+        timestamp,
+    });
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FinishReason;
+
+    fn details(generated_tokens: u32) -> Details {
+        Details {
+            finish_reason: FinishReason::Length,
+            generated_tokens,
+            seed: None,
+            prefill: vec![],
+            tokens: vec![],
+            best_of_sequences: None,
+            top_tokens: vec![],
+            scheduling: None,
+            speculation: None,
+            retokenization: None,
+            normalization: None,
+            statistics: None,
+            tokens_outside_window: None,
+        }
+    }
+
+    fn record(request_id: &str, status: u16, timestamp: u64) -> RequestRecord {
+        RequestRecord {
+            seq: 0,
+            request_id: request_id.to_string(),
+            route: "/generate".to_string(),
+            status,
+            error_type: None,
+            duration_ms: 10,
+            tenant: None,
+            timestamp,
+        }
+    }
+
+    fn ids(page: &RequestsPage) -> Vec<&str> {
+        page.requests
+            .iter()
+            .map(|record| record.request_id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_audit_store_eviction() {
+        let store = AuditStore::new(2, None);
+        store.insert_details("ip:1".to_string(), "a".to_string(), details(1));
+        store.insert_details("ip:1".to_string(), "b".to_string(), details(2));
+        // The details of a request id sent again are replaced, not stored twice
+        store.insert_details("ip:1".to_string(), "b".to_string(), details(4));
+        store.insert_details("ip:1".to_string(), "c".to_string(), details(3));
+
+        assert!(store.details("ip:1", "a").is_none());
+        assert_eq!(store.details("ip:1", "b").unwrap().generated_tokens, 4);
+        assert_eq!(store.details("ip:1", "c").unwrap().generated_tokens, 3);
+        assert_eq!(store.state.lock().unwrap().order.len(), 2);
+    }
+
+    #[test]
+    fn test_audit_store_clients() {
+        let store = AuditStore::new(2, None);
+        store.insert_details("tenant:a".to_string(), "x".to_string(), details(1));
+        store.insert_details("tenant:b".to_string(), "x".to_string(), details(2));
+
+        // Clients only read the details of their own requests
+        assert_eq!(store.details("tenant:a", "x").unwrap().generated_tokens, 1);
+        assert_eq!(store.details("tenant:b", "x").unwrap().generated_tokens, 2);
+        assert!(store.details("ip:1", "x").is_none());
+    }
+
+    #[test]
+    fn test_audit_store_encryption() {
+        let keys = AuditKeys::from_value(
+            "a:000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+        )
+        .unwrap();
+        let store = AuditStore::new(2, Some(keys));
+        store.insert_details("ip:1".to_string(), "a".to_string(), details(1));
+
+        assert!(matches!(
+            store
+                .state
+                .lock()
+                .unwrap()
+                .details
+                .get(&("ip:1".to_string(), "a".to_string())),
+            Some(StoredDetails::Encrypted(_))
+        ));
+        assert_eq!(store.details("ip:1", "a").unwrap().generated_tokens, 1);
+        assert!(store.details("ip:1", "b").is_none());
+    }
+
+    #[test]
+    fn test_audit_store_requests_pagination() {
+        let store = AuditStore::new(10, None);
+        for (i, id) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+            store.insert_record(record(id, 200, i as u64));
+        }
+
+        let query = RequestsQuery {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let page = store.requests(&query);
+        assert_eq!(ids(&page), vec!["e", "d"]);
+
+        // New requests do not shift the next pages
+        store.insert_record(record("f", 200, 5));
+        let page = store.requests(&RequestsQuery {
+            cursor: page.next_cursor,
+            ..query.clone()
+        });
+        assert_eq!(ids(&page), vec!["c", "b"]);
+
+        let page = store.requests(&RequestsQuery {
+            cursor: page.next_cursor,
+            ..query
+        });
+        assert_eq!(ids(&page), vec!["a"]);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn test_audit_store_requests_filters() {
+        let store = AuditStore::new(10, None);
+        store.insert_record(record("a", 424, 100));
+        store.insert_record(record("b", 200, 200));
+        store.insert_record(record("c", 429, 300));
+
+        let page = store.requests(&RequestsQuery {
+            status: Some(RequestStatus::Error),
+            ..Default::default()
+        });
+        assert_eq!(ids(&page), vec!["c", "a"]);
+
+        let page = store.requests(&RequestsQuery {
+            status: Some(RequestStatus::Error),
+            since: Some(200),
+            ..Default::default()
+        });
+        assert_eq!(ids(&page), vec!["c"]);
+    }
+}
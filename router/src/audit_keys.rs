@@ -0,0 +1,234 @@
+/// Encryption of the generation details kept by the audit store
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use rand::{thread_rng, Rng};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Interval between two reads of the keys file
+const KEYS_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// AES-256-GCM keys of the audit store.
+/// Keys are given as `<key id>:<64 hex characters>` entries separated by commas or new lines.
+/// The first key encrypts the new entries, the other ones are only used to decrypt the entries
+/// stored before a rotation.
+#[derive(Clone)]
+pub struct AuditKeys {
+    keyring: Arc<RwLock<Keyring>>,
+    /// File the keys are reloaded from
+    path: Option<PathBuf>,
+}
+
+struct Keyring {
+    /// Key ids and ciphers, active key first
+    keys: Vec<(String, Aes256Gcm)>,
+    /// Key ids and keys, to tell whether a reloaded file changed them
+    material: Vec<(String, Vec<u8>)>,
+}
+
+/// Encrypted payload
+#[derive(Clone, Debug)]
+pub(crate) struct Sealed {
+    /// Id of the key used to encrypt the payload
+    pub key_id: String,
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+
+impl Keyring {
+    fn parse(content: &str) -> Result<Self, String> {
+        let mut keys: Vec<(String, Aes256Gcm)> = Vec::new();
+        let mut material = Vec::new();
+        for entry in content.split(|c| c == ',' || c == '\n') {
+            let entry = entry.trim();
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+            let (key_id, key) = entry
+                .split_once(':')
+                .ok_or_else(|| "keys must be given as `<key id>:<hex key>`".to_string())?;
+            if key_id.is_empty() {
+                return Err("key ids cannot be empty".to_string());
+            }
+            if keys.iter().any(|(id, _)| id == key_id) {
+                return Err(format!("duplicate key id `{key_id}`"));
+            }
+            let key = decode_hex(key)
+                .filter(|key| key.len() == 32)
+                .ok_or_else(|| format!("key `{key_id}` must be 32 bytes in hex"))?;
+            let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+            keys.push((key_id.to_string(), cipher));
+            material.push((key_id.to_string(), key));
+        }
+        if keys.is_empty() {
+            return Err("no key given".to_string());
+        }
+        Ok(Self { keys, material })
+    }
+}
+
+impl AuditKeys {
+    pub fn from_value(value: &str) -> Result<Self, String> {
+        Ok(Self {
+            keyring: Arc::new(RwLock::new(Keyring::parse(value)?)),
+            path: None,
+        })
+    }
+
+    /// Keys read from a file, e.g. written by a KMS agent. The file is read again every minute
+    /// to pick up rotated keys.
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+        Ok(Self {
+            keyring: Arc::new(RwLock::new(Keyring::parse(&content)?)),
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    /// Id of the key encrypting the new entries
+    pub(crate) fn active_key_id(&self) -> String {
+        self.keyring.read().unwrap().keys[0].0.clone()
+    }
+
+    /// Encrypt `plaintext`. `aad` is authenticated but not encrypted: the payload can only be
+    /// decrypted with the same `aad`.
+    pub(crate) fn encrypt(&self, aad: &[u8], plaintext: &[u8]) -> Result<Sealed, String> {
+        let keyring = self.keyring.read().unwrap();
+        let (key_id, cipher) = &keyring.keys[0];
+        let nonce: [u8; 12] = thread_rng().gen();
+        let ciphertext = cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| "encryption failed".to_string())?;
+        Ok(Sealed {
+            key_id: key_id.clone(),
+            nonce,
+            ciphertext,
+        })
+    }
+
+    pub(crate) fn decrypt(&self, aad: &[u8], sealed: &Sealed) -> Result<Vec<u8>, String> {
+        let keyring = self.keyring.read().unwrap();
+        let (_, cipher) = keyring
+            .keys
+            .iter()
+            .find(|(key_id, _)| *key_id == sealed.key_id)
+            .ok_or_else(|| format!("key `{}` was removed", sealed.key_id))?;
+        cipher
+            .decrypt(
+                Nonce::from_slice(&sealed.nonce),
+                Payload {
+                    msg: &sealed.ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| "decryption failed".to_string())
+    }
+
+    /// Read the keys file again
+    fn reload(&self) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let content = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let reloaded = Keyring::parse(&content)?;
+
+        let mut keyring = self.keyring.write().unwrap();
+        // A key id may be given a new key
+        if reloaded.material != keyring.material {
+            tracing::info!(
+                "Audit encryption keys reloaded, active key: {}",
+                reloaded.keys[0].0
+            );
+            *keyring = reloaded;
+        }
+        Ok(())
+    }
+
+    /// Reload the keys file in the background
+    pub(crate) fn watch(&self) {
+        if self.path.is_none() {
+            return;
+        }
+        let keys = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(KEYS_RELOAD_INTERVAL);
+            // The first tick completes immediately
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(err) = keys.reload() {
+                    tracing::warn!("Could not reload the audit encryption keys: {err}");
+                }
+            }
+        });
+    }
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if value.len() % 2 != 0 {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_A: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const KEY_B: &str = "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+    #[test]
+    fn test_audit_keys_parse() {
+        assert!(AuditKeys::from_value("").is_err());
+        assert!(AuditKeys::from_value(KEY_A).is_err());
+        assert!(AuditKeys::from_value("a:00ff").is_err());
+        assert!(AuditKeys::from_value(&format!("a:{KEY_A},a:{KEY_B}")).is_err());
+
+        let keys = AuditKeys::from_value(&format!("# rotated\nb:{KEY_B}\na:{KEY_A}\n")).unwrap();
+        assert_eq!(keys.active_key_id(), "b");
+    }
+
+    #[test]
+    fn test_audit_keys_rotation() {
+        let dir = std::env::temp_dir().join(format!("audit-keys-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("keys");
+        std::fs::write(&path, format!("a:{KEY_A}")).unwrap();
+
+        let keys = AuditKeys::from_file(&path).unwrap();
+        let sealed = keys.encrypt(b"request-1", b"payload").unwrap();
+        assert_eq!(sealed.key_id, "a");
+        // The payload is bound to its request id
+        assert!(keys.decrypt(b"request-2", &sealed).is_err());
+
+        // Entries encrypted with the previous key can still be decrypted
+        std::fs::write(&path, format!("b:{KEY_B},a:{KEY_A}")).unwrap();
+        keys.reload().unwrap();
+        assert_eq!(keys.encrypt(b"request-2", b"").unwrap().key_id, "b");
+        assert_eq!(keys.decrypt(b"request-1", &sealed).unwrap(), b"payload");
+
+        // Until the previous key is removed
+        std::fs::write(&path, format!("b:{KEY_B}")).unwrap();
+        keys.reload().unwrap();
+        assert!(keys.decrypt(b"request-1", &sealed).is_err());
+
+        // A key replaced under the same id is reloaded too
+        let sealed = keys.encrypt(b"request-3", b"payload").unwrap();
+        std::fs::write(&path, format!("b:{KEY_A}")).unwrap();
+        keys.reload().unwrap();
+        assert!(keys.decrypt(b"request-3", &sealed).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,118 @@
+/// W3C Baggage propagation
+use crate::queue::Entry;
+use axum::extract::Extension;
+use axum::http::{HeaderMap, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use nohash_hasher::IntMap;
+use opentelemetry::baggage::BaggageExt;
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::sdk::propagation::BaggagePropagator;
+use opentelemetry::trace::FutureExt;
+use opentelemetry::{Context, KeyValue};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// Baggage keys forwarded from the incoming requests to the shards
+#[derive(Clone, Debug)]
+pub(crate) struct BaggageKeys(pub Arc<Vec<String>>);
+
+/// Middleware attaching the selected baggage entries of the request to the current
+/// OpenTelemetry context
+pub(crate) async fn propagate<B>(
+    Extension(keys): Extension<BaggageKeys>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let entries = extract(request.headers(), &keys.0);
+    if entries.is_empty() {
+        return next.run(request).await;
+    }
+    let context = Context::current_with_baggage(entries);
+    next.run(request).with_context(context).await
+}
+
+/// Parse the `baggage` headers and keep the entries in `keys`. The values are percent-decoded
+/// by the propagator that encodes them again for the shards
+fn extract(headers: &HeaderMap, keys: &[String]) -> Vec<KeyValue> {
+    let propagator = BaggagePropagator::new();
+    headers
+        .get_all("baggage")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| {
+            let carrier = HashMap::from([("baggage".to_string(), value.to_string())]);
+            propagator
+                .extract(&carrier)
+                .baggage()
+                .iter()
+                .filter(|(key, _)| keys.iter().any(|k| k == key.as_str()))
+                .map(|(key, (value, _))| KeyValue::new(key.clone(), value.clone()))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Get the baggage entries of the current OpenTelemetry context
+pub(crate) fn current() -> Vec<(String, String)> {
+    Context::current()
+        .baggage()
+        .iter()
+        .map(|(key, (value, _))| (key.to_string(), value.as_str().to_string()))
+        .collect()
+}
+
+/// Merge the baggage of all `entries` in a context used for the batch gRPC calls.
+/// Distinct values of the same key are joined with `|`.
+pub(crate) fn batch_context(entries: &IntMap<u64, Entry>) -> Context {
+    let mut baggage: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for entry in entries.values() {
+        for (key, value) in entry.baggage.iter() {
+            let values = baggage.entry(key).or_default();
+            if !values.contains(&value.as_str()) {
+                values.push(value);
+            }
+        }
+    }
+    Context::current_with_baggage(
+        baggage
+            .into_iter()
+            .map(|(key, values)| KeyValue::new(key.to_string(), values.join("|"))),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "baggage",
+            "tenant=acme;ttl=3, experiment_id=exp-1,user=someone"
+                .parse()
+                .unwrap(),
+        );
+        headers.append("baggage", "team=search%2Cranking%20v2".parse().unwrap());
+        let keys = vec![
+            "tenant".to_string(),
+            "experiment_id".to_string(),
+            "team".to_string(),
+        ];
+
+        let mut entries: Vec<(String, String)> = extract(&headers, &keys)
+            .into_iter()
+            .map(|kv| (kv.key.to_string(), kv.value.as_str().to_string()))
+            .collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec![
+                ("experiment_id".to_string(), "exp-1".to_string()),
+                ("team".to_string(), "search,ranking v2".to_string()),
+                ("tenant".to_string(), "acme".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,466 @@
+/// Offline processing of batch files: the requests of a CSV or JSONL file go through the queue
+/// in the background, and their results are downloaded as a JSONL file once all completed
+use crate::infer::Infer;
+use crate::object_store::{ObjectStore, ObjectStoreError};
+use crate::{ErrorResponse, FinishReason, GenerateRequest};
+use axum::body::Bytes;
+use futures::{Future, StreamExt};
+use opentelemetry::trace::{FutureExt, WithContext};
+use opentelemetry::Context;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use utoipa::ToSchema;
+
+/// Maximum size of an uploaded batch file (in bytes)
+pub(crate) const MAX_BATCH_FILE_SIZE: usize = 100 * 1024 * 1024;
+/// Maximum number of requests in a batch file
+pub(crate) const MAX_BATCH_FILE_LINES: usize = 10_000;
+/// Maximum number of batch files kept, the oldest completed ones are evicted first
+const MAX_BATCH_FILES: usize = 64;
+/// Requests of a batch file in flight at the same time
+pub(crate) const BATCH_FILE_CONCURRENCY: usize = 32;
+
+/// Marks the requests of the batch files, batched after the interactive requests
+struct Background;
+
+/// Run `future` with the low queue priority of the batch files
+pub(crate) fn in_background<F: Future>(future: F) -> WithContext<F> {
+    future.with_context(Context::current_with_value(Background))
+}
+
+/// Whether the current request comes from a batch file
+pub(crate) fn is_background() -> bool {
+    Context::current().get::<Background>().is_some()
+}
+
+/// Batch files submitted to the router
+#[derive(Clone, Debug, Default)]
+pub(crate) struct BatchFiles {
+    files: Arc<Mutex<HashMap<String, BatchFile>>>,
+}
+
+#[derive(Debug)]
+struct BatchFile {
+    status: BatchFileStatus,
+    /// JSONL lines of the results, in the order of the requests
+    results: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BatchFileState {
+    InProgress,
+    Completed,
+}
+
+/// Progress of a batch file
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct BatchFileStatus {
+    #[schema(example = "batch_5f0e3c4b2a1d4e6f8a9b0c1d2e3f4a5b")]
+    pub id: String,
+    pub status: BatchFileState,
+    /// Number of requests of the file
+    #[schema(example = 100)]
+    pub total: usize,
+    /// Number of successful requests
+    #[schema(example = 42)]
+    pub completed: usize,
+    /// Number of failed requests
+    #[schema(example = 1)]
+    pub failed: usize,
+    /// Unix timestamp (in seconds) of the submission
+    #[schema(example = 1706270835)]
+    pub created_at: u64,
+}
+
+/// Batch file read from an object store, instead of being uploaded
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct BatchFileSource {
+    /// Location of the file under one of the allowed object store prefixes. Files ending with
+    /// `.csv` are read as CSV, the others as JSONL
+    #[schema(example = "s3://datasets/prompts/run-1.jsonl")]
+    pub input_uri: String,
+}
+
+/// A request of a batch file. JSONL lines are generate requests with an optional `custom_id`,
+/// CSV rows have an `inputs` column, an optional `custom_id` column and the parameters as the
+/// other columns
+#[derive(Debug, Deserialize)]
+pub(crate) struct BatchFileLine {
+    #[serde(default)]
+    custom_id: Option<String>,
+    #[serde(flatten)]
+    request: GenerateRequest,
+}
+
+/// Result of a request of a batch file, as a line of the results file
+#[derive(Serialize)]
+struct BatchFileResult {
+    /// Index of the request in the batch file
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    custom_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response: Option<BatchFileResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorResponse>,
+}
+
+#[derive(Serialize)]
+struct BatchFileResponse {
+    generated_text: String,
+    finish_reason: FinishReason,
+    generated_tokens: u32,
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum BatchFileError {
+    #[error("Invalid batch file: {0}")]
+    Input(String),
+    #[error("Batch files support up to {0} requests. Given: {1}")]
+    TooManyLines(usize, usize),
+    #[error("Too many batch files in progress, retry later")]
+    TooManyInProgress,
+    #[error("Reading the batch files from an object store is not enabled on this server")]
+    InputUriDisabled,
+    #[error("`input_uri` must start with one of: {0}")]
+    InputUri(String),
+    #[error("Could not read the batch file: {0}")]
+    ObjectStore(ObjectStoreError),
+    #[error("Unknown batch file")]
+    NotFound,
+    #[error("Batch file is still in progress")]
+    InProgress,
+}
+
+impl BatchFileError {
+    pub(crate) fn error_type(&self) -> &str {
+        match self {
+            BatchFileError::Input(_) | BatchFileError::TooManyLines(_, _) => "validation",
+            BatchFileError::TooManyInProgress => "overloaded",
+            BatchFileError::InputUriDisabled | BatchFileError::InputUri(_) => "validation",
+            BatchFileError::ObjectStore(_) => "object_store",
+            BatchFileError::NotFound => "not_found",
+            BatchFileError::InProgress => "in_progress",
+        }
+    }
+}
+
+/// Read the requests of a batch file uploaded as `body`, or of the file at the `input_uri` of
+/// a JSON body
+pub(crate) async fn read_lines(
+    content_type: Option<&str>,
+    body: Bytes,
+    object_store: Option<&ObjectStore>,
+) -> Result<Vec<BatchFileLine>, BatchFileError> {
+    let content_type = content_type.unwrap_or_default();
+    let (content, csv) = if content_type.starts_with("application/json") {
+        let source: BatchFileSource =
+            serde_json::from_slice(&body).map_err(|err| BatchFileError::Input(err.to_string()))?;
+        let object_store = object_store.ok_or(BatchFileError::InputUriDisabled)?;
+        let content = object_store
+            .read(&source.input_uri)
+            .await
+            .map_err(|err| match err {
+                ObjectStoreError::Destination(prefixes) => BatchFileError::InputUri(prefixes),
+                err => BatchFileError::ObjectStore(err),
+            })?;
+        (content, source.input_uri.ends_with(".csv"))
+    } else {
+        let content = String::from_utf8(body.to_vec())
+            .map_err(|_| BatchFileError::Input("the file must be UTF-8 encoded".to_string()))?;
+        (content, content_type.starts_with("text/csv"))
+    };
+
+    let lines = match csv {
+        true => parse_csv(&content)?,
+        false => parse_jsonl(&content)?,
+    };
+    if lines.is_empty() {
+        return Err(BatchFileError::Input("the file has no request".to_string()));
+    }
+    if lines.len() > MAX_BATCH_FILE_LINES {
+        return Err(BatchFileError::TooManyLines(
+            MAX_BATCH_FILE_LINES,
+            lines.len(),
+        ));
+    }
+    Ok(lines)
+}
+
+fn parse_jsonl(content: &str) -> Result<Vec<BatchFileLine>, BatchFileError> {
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .map_err(|err| BatchFileError::Input(format!("line {}: {err}", i + 1)))
+        })
+        .collect()
+}
+
+fn parse_csv(content: &str) -> Result<Vec<BatchFileLine>, BatchFileError> {
+    let mut rows = csv_rows(content)
+        .map_err(BatchFileError::Input)?
+        .into_iter()
+        .filter(|row| row.iter().any(|field| !field.is_empty()));
+    let header = rows.next().unwrap_or_default();
+    if !header.iter().any(|name| name == "inputs") {
+        return Err(BatchFileError::Input(
+            "the CSV header must have an `inputs` column".to_string(),
+        ));
+    }
+    rows.enumerate()
+        .map(|(i, row)| {
+            let mut line = Map::new();
+            let mut parameters = Map::new();
+            for (name, field) in header.iter().zip(row) {
+                match name.as_str() {
+                    "inputs" | "custom_id" => {
+                        line.insert(name.clone(), Value::String(field));
+                    }
+                    // Empty cells keep the default of the parameter
+                    _ if field.is_empty() => {}
+                    // Numbers, booleans and lists are JSON, the other cells are strings
+                    _ => {
+                        let value = serde_json::from_str(&field).unwrap_or(Value::String(field));
+                        parameters.insert(name.clone(), value);
+                    }
+                }
+            }
+            line.insert("parameters".to_string(), Value::Object(parameters));
+            serde_json::from_value(Value::Object(line))
+                .map_err(|err| BatchFileError::Input(format!("row {}: {err}", i + 1)))
+        })
+        .collect()
+}
+
+/// Rows of a CSV document. Quoted fields may hold commas, newlines and doubled quotes
+fn csv_rows(content: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            (true, '"') => quoted = false,
+            (true, c) => field.push(c),
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => row.push(std::mem::take(&mut field)),
+            (false, '\r') => {}
+            (false, '\n') => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            (false, c) => field.push(c),
+        }
+    }
+    if quoted {
+        return Err("unterminated quoted CSV field".to_string());
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+impl BatchFileLine {
+    async fn run(self, index: usize, infer: &Infer) -> BatchFileResult {
+        let mut generated_text = match self.request.parameters.return_full_text {
+            Some(true) => self.request.inputs.clone(),
+            _ => String::new(),
+        };
+        let (response, error) = match infer.generate(self.request).await {
+            Ok(response) => {
+                generated_text.push_str(&response.generated_text.text);
+                let response = BatchFileResponse {
+                    generated_text,
+                    finish_reason: FinishReason::from(response.generated_text.finish_reason),
+                    generated_tokens: response.generated_text.generated_tokens,
+                };
+                (Some(response), None)
+            }
+            Err(err) => {
+                let error = ErrorResponse {
+                    error: err.to_string(),
+                    error_type: err.error_type().to_string(),
+                };
+                (None, Some(error))
+            }
+        };
+        BatchFileResult {
+            index,
+            custom_id: self.custom_id,
+            response,
+            error,
+        }
+    }
+}
+
+impl BatchFiles {
+    /// Process the requests of a batch file in the background
+    pub(crate) fn submit(
+        &self,
+        infer: Infer,
+        lines: Vec<BatchFileLine>,
+    ) -> Result<BatchFileStatus, BatchFileError> {
+        let status = BatchFileStatus {
+            id: format!("batch_{:032x}", thread_rng().gen::<u128>()),
+            status: BatchFileState::InProgress,
+            total: lines.len(),
+            completed: 0,
+            failed: 0,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_secs())
+                .unwrap_or(0),
+        };
+        {
+            let mut files = self.files.lock().unwrap();
+            if files.len() >= MAX_BATCH_FILES {
+                let oldest = files
+                    .values()
+                    .filter(|file| file.status.status == BatchFileState::Completed)
+                    .min_by_key(|file| file.status.created_at)
+                    .map(|file| file.status.id.clone())
+                    .ok_or(BatchFileError::TooManyInProgress)?;
+                files.remove(&oldest);
+            }
+            files.insert(
+                status.id.clone(),
+                BatchFile {
+                    status: status.clone(),
+                    results: Vec::new(),
+                },
+            );
+        }
+
+        let files = self.clone();
+        let id = status.id.clone();
+        // The requests keep the context of the upload, e.g. its tenant
+        tokio::spawn(
+            async move { files.process(&id, infer, lines).await }.with_context(Context::current()),
+        );
+        Ok(status)
+    }
+
+    async fn process(&self, id: &str, infer: Infer, lines: Vec<BatchFileLine>) {
+        let mut results = futures::stream::iter(lines.into_iter().enumerate())
+            .map(|(index, line)| {
+                let infer = infer.clone();
+                in_background(async move { line.run(index, &infer).await })
+            })
+            .buffered(BATCH_FILE_CONCURRENCY);
+        while let Some(result) = results.next().await {
+            self.update(id, |file| {
+                match result.error {
+                    Some(_) => file.status.failed += 1,
+                    None => file.status.completed += 1,
+                }
+                // Unwrap is safe here as the result only holds strings and numbers
+                file.results.push(serde_json::to_string(&result).unwrap());
+            });
+        }
+        self.update(id, |file| file.status.status = BatchFileState::Completed);
+        tracing::info!("Batch file {id} completed");
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut BatchFile)) {
+        if let Some(file) = self.files.lock().unwrap().get_mut(id) {
+            f(file);
+        }
+    }
+
+    pub(crate) fn status(&self, id: &str) -> Result<BatchFileStatus, BatchFileError> {
+        self.files
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|file| file.status.clone())
+            .ok_or(BatchFileError::NotFound)
+    }
+
+    /// JSONL results of a completed batch file
+    pub(crate) fn results(&self, id: &str) -> Result<String, BatchFileError> {
+        let files = self.files.lock().unwrap();
+        let file = files.get(id).ok_or(BatchFileError::NotFound)?;
+        if file.status.status != BatchFileState::Completed {
+            return Err(BatchFileError::InProgress);
+        }
+        let mut results = file.results.join("\n");
+        results.push('\n');
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv_rows() {
+        let rows =
+            csv_rows("inputs,stop\r\n\"Hello, \"\"world\"\"\",\"[\"\"\n\"\"]\"\nBye,").unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                vec!["inputs", "stop"],
+                vec!["Hello, \"world\"", "[\"\n\"]"],
+                vec!["Bye", ""]
+            ]
+        );
+        assert!(csv_rows("\"unterminated").is_err());
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let lines = parse_csv(
+            "custom_id,inputs,max_new_tokens,temperature,stop\n\
+            a,Hello,20,0.5,\"[\"\"!\"\"]\"\n\
+            b,Bye,,,\n",
+        )
+        .unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].custom_id.as_deref(), Some("a"));
+        assert_eq!(lines[0].request.inputs, "Hello");
+        assert_eq!(lines[0].request.parameters.max_new_tokens, Some(20));
+        assert_eq!(lines[0].request.parameters.temperature, Some(0.5));
+        assert_eq!(lines[0].request.parameters.stop, vec!["!"]);
+        // Empty cells keep the defaults
+        assert_eq!(lines[1].request.parameters.temperature, None);
+
+        assert!(parse_csv("prompt\nHello\n").is_err());
+        assert!(parse_csv("inputs,max_new_tokens\nHello,many\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_jsonl() {
+        let lines = parse_jsonl(
+            "{\"custom_id\": \"a\", \"inputs\": \"Hello\", \"parameters\": {\"max_new_tokens\": 5}}\n\
+            \n\
+            {\"inputs\": \"Bye\"}\n",
+        )
+        .unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].custom_id.as_deref(), Some("a"));
+        assert_eq!(lines[0].request.parameters.max_new_tokens, Some(5));
+        assert_eq!(lines[1].custom_id, None);
+
+        match parse_jsonl("{\"inputs\": \"Hello\"}\n{\"prompt\": \"Bye\"}") {
+            Err(BatchFileError::Input(err)) => assert!(err.starts_with("line 2")),
+            _ => panic!("Unexpected valid batch file"),
+        }
+    }
+}
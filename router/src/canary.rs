@@ -0,0 +1,62 @@
+/// Synthetic canary: a tiny generation run periodically through the validation, the queue, the
+/// batching and the shards, catching the quality-of-service regressions between real traffic
+use crate::infer::Infer;
+use crate::{GenerateParameters, GenerateRequest};
+use std::time::{Duration, Instant};
+use tracing::{info_span, Instrument};
+
+/// Prompt of the canary generations
+const CANARY_INPUTS: &str = "The capital of France is";
+/// Number of tokens generated by the canary
+const CANARY_MAX_NEW_TOKENS: u32 = 2;
+
+fn canary_request() -> GenerateRequest {
+    GenerateRequest {
+        inputs: CANARY_INPUTS.to_string(),
+        parameters: GenerateParameters {
+            max_new_tokens: Some(CANARY_MAX_NEW_TOKENS),
+            ..Default::default()
+        },
+    }
+}
+
+/// Canary generations
+/// Will be launched in a background Tokio task
+///
+/// Runs a generation every `interval` and exports its outcome and latency in the
+/// `tgi_canary_*` metrics. The generations have no tenant: they are excluded from the usage and
+/// the billing of the tenants, and from the request metrics of the handlers
+pub(crate) async fn canary_task(infer: Infer, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    // Skip the ticks missed by a slow generation instead of bursting
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The first tick completes immediately: the first canary runs one interval after the start
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        let start = Instant::now();
+        let result = infer
+            .generate(canary_request())
+            .instrument(info_span!("canary"))
+            .await;
+        let duration = start.elapsed();
+        match result {
+            Ok(response) if response.generated_text.generated_tokens > 0 => {
+                metrics::increment_counter!("tgi_canary_success");
+                metrics::histogram!("tgi_canary_duration", duration.as_secs_f64());
+                metrics::gauge!("tgi_canary_up", 1.0);
+                tracing::debug!("Canary generation succeeded in {duration:?}");
+            }
+            Ok(_) => {
+                metrics::increment_counter!("tgi_canary_failure", "err" => "empty");
+                metrics::gauge!("tgi_canary_up", 0.0);
+                tracing::warn!("Canary generation returned no token");
+            }
+            Err(err) => {
+                metrics::increment_counter!("tgi_canary_failure", "err" => err.error_type().to_string());
+                metrics::gauge!("tgi_canary_up", 0.0);
+                tracing::warn!("Canary generation failed: {err}");
+            }
+        }
+    }
+}
@@ -0,0 +1,117 @@
+/// Developer chaos mode: faults injected in the router, e.g. to test the retry logic of the clients
+/// and the error paths of the router without a flaky backend. Enabled with `--chaos` and
+/// configured at runtime through `/admin/chaos`: no fault is injected until then
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use utoipa::ToSchema;
+
+/// Faults injected by the chaos mode
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize, ToSchema)]
+pub(crate) struct ChaosConfig {
+    /// Ratio of the requests delayed before being queued
+    #[serde(default)]
+    #[schema(example = 0.1)]
+    pub queue_delay_ratio: f64,
+    /// Maximum delay of a request, in milliseconds
+    #[serde(default)]
+    #[schema(example = 2000)]
+    pub max_queue_delay_ms: u64,
+    /// Ratio of the generations (forward passes) of a request failing with a synthetic shard error
+    #[serde(default)]
+    #[schema(example = 0.01)]
+    pub shard_error_ratio: f64,
+    /// Ratio of the streamed tokens dropped from the streams
+    #[serde(default)]
+    #[schema(example = 0.05)]
+    pub dropped_chunk_ratio: f64,
+}
+
+impl ChaosConfig {
+    fn validate(&self) -> Result<(), String> {
+        for (name, ratio) in [
+            ("queue_delay_ratio", self.queue_delay_ratio),
+            ("shard_error_ratio", self.shard_error_ratio),
+            ("dropped_chunk_ratio", self.dropped_chunk_ratio),
+        ] {
+            if !(0.0..=1.0).contains(&ratio) {
+                return Err(format!("`{name}` must be >= 0 and <= 1. Given: {ratio}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Chaos mode state, shared by the router and the batching task
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Chaos(Arc<RwLock<ChaosConfig>>);
+
+impl Chaos {
+    /// Faults currently injected
+    pub(crate) fn config(&self) -> ChaosConfig {
+        *self.0.read().unwrap()
+    }
+
+    /// Replace the injected faults
+    pub(crate) fn configure(&self, new_config: ChaosConfig) -> Result<ChaosConfig, String> {
+        new_config.validate()?;
+        *self.0.write().unwrap() = new_config;
+        tracing::warn!("Chaos mode faults: {new_config:?}");
+        Ok(new_config)
+    }
+
+    fn sample(&self, ratio: impl Fn(&ChaosConfig) -> f64) -> Option<ChaosConfig> {
+        let config = self.config();
+        let ratio = ratio(&config);
+        (ratio > 0.0 && thread_rng().gen_bool(ratio)).then_some(config)
+    }
+
+    /// Delay of a request before being queued
+    pub(crate) fn queue_delay(&self) -> Option<Duration> {
+        let config = self.sample(|config| config.queue_delay_ratio)?;
+        let delay = thread_rng().gen_range(0..=config.max_queue_delay_ms);
+        metrics::increment_counter!("tgi_chaos_fault", "fault" => "queue_delay");
+        Some(Duration::from_millis(delay))
+    }
+
+    /// Whether a generation fails with a synthetic shard error
+    pub(crate) fn shard_error(&self) -> bool {
+        let fault = self.sample(|config| config.shard_error_ratio).is_some();
+        if fault {
+            metrics::increment_counter!("tgi_chaos_fault", "fault" => "shard_error");
+        }
+        fault
+    }
+
+    /// Whether a streamed token is dropped
+    pub(crate) fn drop_chunk(&self) -> bool {
+        let fault = self.sample(|config| config.dropped_chunk_ratio).is_some();
+        if fault {
+            metrics::increment_counter!("tgi_chaos_fault", "fault" => "dropped_chunk");
+        }
+        fault
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let config = ChaosConfig {
+            shard_error_ratio: 0.5,
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+        let config = ChaosConfig {
+            dropped_chunk_ratio: 1.5,
+            ..Default::default()
+        };
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "`dropped_chunk_ratio` must be >= 0 and <= 1. Given: 1.5"
+        );
+    }
+}
@@ -0,0 +1,111 @@
+/// Truncation of the chat history by message priority
+use crate::infer::{Infer, InferError};
+use crate::{GenerateParameters, GenerateRequest, Message};
+
+/// Order in which the turns are dropped: lowest priority first, then oldest first. A turn is a
+/// user message with the assistant and tool messages answering it, its priority the highest set
+/// on its messages. The latest message is never dropped.
+fn drop_order(messages: &[Message]) -> Vec<Vec<usize>> {
+    let mut turns: Vec<Vec<usize>> = Vec::new();
+    for (index, message) in messages[..messages.len().saturating_sub(1)]
+        .iter()
+        .enumerate()
+    {
+        let answer = message.role != "user" && message.role != "system";
+        match turns.last_mut() {
+            Some(turn) if answer && messages[turn[0]].role == "user" => turn.push(index),
+            _ => turns.push(vec![index]),
+        }
+    }
+    turns.sort_by_key(|turn| {
+        let priority = turn
+            .iter()
+            .filter_map(|&index| messages[index].priority)
+            .max()
+            .unwrap_or_default();
+        (priority, turn[0])
+    });
+    turns
+}
+
+/// Drop whole turns until the prompt rendered by `render` fits in `budget` tokens. Return the
+/// prompt and the sorted indices of the dropped messages
+pub(crate) async fn truncate_messages(
+    infer: &Infer,
+    messages: Vec<Message>,
+    budget: usize,
+    render: impl Fn(Vec<Message>) -> Result<String, InferError>,
+) -> Result<(String, Vec<usize>), InferError> {
+    let mut order = drop_order(&messages).into_iter();
+    let mut dropped = Vec::new();
+    loop {
+        let kept = messages
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !dropped.contains(index))
+            .map(|(_, message)| message.clone())
+            .collect();
+        let inputs = render(kept)?;
+        let encoding = infer
+            .tokenize(GenerateRequest {
+                inputs: inputs.clone(),
+                parameters: GenerateParameters::default(),
+            })
+            .await?;
+        // Without a fast tokenizer the length of the prompt is unknown
+        let fits = encoding.map_or(true, |encoding| encoding.len() <= budget);
+
+        match order.next() {
+            Some(turn) if !fits => dropped.extend(turn),
+            // Either the prompt fits or the validation rejects it
+            _ => {
+                dropped.sort_unstable();
+                if !dropped.is_empty() {
+                    metrics::histogram!("tgi_chat_dropped_messages", dropped.len() as f64);
+                }
+                return Ok((inputs, dropped));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drop_order() {
+        let message = |role: &str, priority: Option<i32>| Message {
+            role: role.to_string(),
+            content: Some(String::new()),
+            name: None,
+            tool_calls: None,
+            priority,
+            parsed: None,
+        };
+        let messages = vec![
+            message("system", Some(10)),
+            message("user", None),
+            message("assistant", None),
+            message("user", Some(-1)),
+            message("assistant", Some(1)),
+            message("user", None),
+        ];
+
+        // The oldest turn goes first, the second one keeping the priority of its answer; the
+        // system prompt is kept as long as possible and the latest question is never dropped
+        assert_eq!(drop_order(&messages), vec![vec![1, 2], vec![3, 4], vec![0]]);
+
+        // The low priority turns go before the older ones
+        let messages = vec![
+            message("user", None),
+            message("assistant", None),
+            message("user", Some(-1)),
+            message("assistant", None),
+            message("tool", None),
+            message("user", None),
+        ];
+        assert_eq!(drop_order(&messages), vec![vec![2, 3, 4], vec![0, 1]]);
+        assert!(drop_order(&[]).is_empty());
+    }
+}
@@ -0,0 +1,183 @@
+/// Circuit breaker around the shard RPCs
+use crate::alerts::{AlertKind, Alerts};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum CircuitState {
+    /// Requests are admitted
+    Closed,
+    /// Requests are rejected until the end of the cool-down
+    Open,
+    /// Requests are admitted; the outcome of the next RPC closes or re-opens the circuit
+    HalfOpen,
+}
+
+impl CircuitState {
+    fn gauge(&self) -> f64 {
+        match self {
+            CircuitState::Closed => 0.0,
+            CircuitState::Open => 1.0,
+            CircuitState::HalfOpen => 2.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct CircuitBreaker {
+    state: Arc<Mutex<BreakerState>>,
+    /// Error rate over the window above which the circuit opens
+    error_rate: f32,
+    /// Number of RPC outcomes the error rate is computed on
+    window: usize,
+    /// Time the circuit stays open before letting requests through again
+    cooldown: Duration,
+    /// Webhook paged when the circuit opens
+    alerts: Option<Alerts>,
+}
+
+#[derive(Debug)]
+struct BreakerState {
+    /// Outcomes of the last RPCs (true on failure)
+    outcomes: VecDeque<bool>,
+    /// Number of failures in `outcomes`
+    failures: usize,
+    /// Time at which the circuit opened
+    opened_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(error_rate: f32, window: usize, cooldown: Duration) -> Self {
+        metrics::gauge!("tgi_circuit_breaker_state", CircuitState::Closed.gauge());
+        Self {
+            state: Arc::new(Mutex::new(BreakerState {
+                outcomes: VecDeque::with_capacity(window),
+                failures: 0,
+                opened_at: None,
+            })),
+            error_rate,
+            window,
+            cooldown,
+            alerts: None,
+        }
+    }
+
+    pub(crate) fn with_alerts(mut self, alerts: Option<Alerts>) -> Self {
+        self.alerts = alerts;
+        self
+    }
+
+    pub(crate) fn state(&self) -> CircuitState {
+        let state = self.state.lock().unwrap();
+        self.current_state(&state)
+    }
+
+    fn current_state(&self, state: &BreakerState) -> CircuitState {
+        let circuit_state = match state.opened_at {
+            None => CircuitState::Closed,
+            Some(opened_at) if opened_at.elapsed() < self.cooldown => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        };
+        metrics::gauge!("tgi_circuit_breaker_state", circuit_state.gauge());
+        circuit_state
+    }
+
+    /// Whether a new request can be sent to the shards
+    pub(crate) fn allow(&self) -> bool {
+        self.state() != CircuitState::Open
+    }
+
+    /// Record the outcome of a shard RPC
+    pub(crate) fn record(&self, success: bool) {
+        let mut state = self.state.lock().unwrap();
+        match self.current_state(&state) {
+            CircuitState::Closed => {
+                state.outcomes.push_back(!success);
+                if !success {
+                    state.failures += 1;
+                }
+                if state.outcomes.len() > self.window {
+                    if let Some(true) = state.outcomes.pop_front() {
+                        state.failures -= 1;
+                    }
+                }
+                if state.outcomes.len() == self.window
+                    && state.failures as f32 >= self.error_rate * self.window as f32
+                {
+                    self.open(&mut state);
+                }
+            }
+            // Outcomes of the RPCs started before the circuit opened
+            CircuitState::Open => {}
+            CircuitState::HalfOpen => {
+                if success {
+                    state.opened_at = None;
+                    metrics::gauge!("tgi_circuit_breaker_state", CircuitState::Closed.gauge());
+                    tracing::info!("Circuit breaker closed");
+                } else {
+                    self.open(&mut state);
+                }
+            }
+        }
+    }
+
+    fn open(&self, state: &mut BreakerState) {
+        state.outcomes.clear();
+        state.failures = 0;
+        state.opened_at = Some(Instant::now());
+        metrics::increment_counter!("tgi_circuit_breaker_opened");
+        metrics::gauge!("tgi_circuit_breaker_state", CircuitState::Open.gauge());
+        tracing::error!("Circuit breaker opened for {:?}", self.cooldown);
+        if let Some(alerts) = &self.alerts {
+            alerts.alert(
+                AlertKind::CircuitBreakerOpened,
+                format!("Circuit breaker opened for {:?}", self.cooldown),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker_opens() {
+        let breaker = CircuitBreaker::new(0.5, 4, Duration::from_secs(60));
+        breaker.record(true);
+        breaker.record(false);
+        breaker.record(false);
+        // The window is not full yet
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        breaker.record(true);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn test_circuit_breaker_window() {
+        let breaker = CircuitBreaker::new(0.5, 4, Duration::from_secs(60));
+        breaker.record(false);
+        for _ in 0..4 {
+            breaker.record(true);
+        }
+        // The failure slid out of the window
+        breaker.record(false);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_circuit_breaker_half_open() {
+        let breaker = CircuitBreaker::new(0.5, 2, Duration::ZERO);
+        breaker.record(false);
+        breaker.record(false);
+        // No cool-down: the circuit is directly half-open
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(breaker.allow());
+
+        breaker.record(true);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+}
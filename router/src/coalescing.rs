@@ -0,0 +1,236 @@
+/// Coalescing of the identical deterministic requests arriving concurrently, e.g. the retries of
+/// a frontend: the generation runs once and its response is fanned out to all the requests
+use crate::infer::{InferError, InferResponse};
+use crate::GenerateRequest;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// Response channels of the in-flight generations, by request key
+type InFlight = Arc<Mutex<HashMap<String, broadcast::Sender<InferResponse>>>>;
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Coalescer {
+    in_flight: InFlight,
+}
+
+/// Key of the deterministic requests, greedy or seeded: the whole canonical request, not a hash
+/// that two different requests could share. Sampled requests without a seed are never coalesced
+fn key(request: &GenerateRequest) -> Option<String> {
+    let parameters = &request.parameters;
+    let greedy = !parameters.do_sample
+        && parameters.temperature.is_none()
+        && parameters.temperature_schedule.is_none()
+        && parameters.top_k.is_none()
+        && parameters.top_p.is_none()
+        && parameters.typical_p.is_none();
+    if !greedy && parameters.seed.is_none() {
+        return None;
+    }
+    // Any field of the request may change the response. The maps are hashed in the order of
+    // their keys: the iteration order of a `HashMap` is random
+    let mut request = request.clone();
+    let logit_bias: Option<BTreeMap<u32, f32>> = request
+        .parameters
+        .logit_bias
+        .take()
+        .map(|logit_bias| logit_bias.into_iter().collect());
+    let extensions: BTreeMap<String, serde_json::Value> =
+        std::mem::take(&mut request.parameters.extensions)
+            .into_iter()
+            .collect();
+    Some(format!("{request:?} {logit_bias:?} {extensions:?}"))
+}
+
+impl Coalescer {
+    /// Run `generate`, or wait for the response of an identical request already generating. If
+    /// that generation fails or is cancelled, `generate` runs for this request. Returns whether
+    /// the response was shared by another request, whose usage was accounted instead
+    pub(crate) async fn generate<F, Fut>(
+        &self,
+        request: GenerateRequest,
+        generate: F,
+    ) -> Result<(InferResponse, bool), InferError>
+    where
+        F: FnOnce(GenerateRequest) -> Fut,
+        Fut: Future<Output = Result<InferResponse, InferError>>,
+    {
+        let Some(key) = key(&request) else {
+            return Ok((generate(request).await?, false));
+        };
+
+        let receiver = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(sender) => Some(sender.subscribe()),
+                None => {
+                    in_flight.insert(key.clone(), broadcast::channel(1).0);
+                    None
+                }
+            }
+        };
+
+        match receiver {
+            Some(mut receiver) => match receiver.recv().await {
+                Ok(response) => {
+                    metrics::increment_counter!("tgi_request_coalesced");
+                    Ok((response, true))
+                }
+                Err(_) => {
+                    metrics::increment_counter!("tgi_request_coalescing_failed");
+                    Ok((generate(request).await?, false))
+                }
+            },
+            None => {
+                let leader = Leader {
+                    key,
+                    in_flight: self.in_flight.clone(),
+                };
+                let response = generate(request).await?;
+                if let Some(sender) = leader.finish() {
+                    // No receiver when no identical request arrived
+                    let _ = sender.send(response.clone());
+                }
+                Ok((response, false))
+            }
+        }
+    }
+}
+
+/// Generation run for the requests of a key. The key is released when it finishes or when the
+/// leading request is cancelled: the waiting requests then generate on their own
+struct Leader {
+    key: String,
+    in_flight: InFlight,
+}
+
+impl Leader {
+    fn finish(&self) -> Option<broadcast::Sender<InferResponse>> {
+        self.in_flight.lock().unwrap().remove(&self.key)
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GenerateParameters;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use text_generation_client::GeneratedText;
+    use tokio::time::{Duration, Instant};
+
+    fn request(parameters: GenerateParameters) -> GenerateRequest {
+        GenerateRequest {
+            inputs: "What is Deep Learning?".to_string(),
+            parameters,
+        }
+    }
+
+    fn response() -> InferResponse {
+        InferResponse {
+            _input_length: 5,
+            prefill: Vec::new(),
+            tokens: Vec::new(),
+            generated_text: GeneratedText {
+                text: "Deep Learning is".to_string(),
+                generated_tokens: 3,
+                finish_reason: 0,
+                seed: None,
+            },
+            queued: Instant::now(),
+            start: Instant::now(),
+            batching_cycles: 0,
+            batch_id: 0,
+            speculation: None,
+            retokenization: None,
+            normalization: None,
+            top_tokens: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_key() {
+        let greedy = request(GenerateParameters::default());
+        assert_eq!(key(&greedy), key(&greedy.clone()));
+
+        let sampled = request(GenerateParameters {
+            temperature: Some(0.7),
+            ..Default::default()
+        });
+        assert!(key(&sampled).is_none());
+
+        let seeded = request(GenerateParameters {
+            temperature: Some(0.7),
+            seed: Some(42),
+            ..Default::default()
+        });
+        assert!(key(&seeded).is_some());
+        assert_ne!(key(&seeded), key(&greedy));
+        let other = GenerateRequest {
+            inputs: "What is Machine Learning?".to_string(),
+            ..greedy.clone()
+        };
+        assert_ne!(key(&other), key(&greedy));
+
+        // Independent of the iteration order of the maps
+        let logit_bias = |ids: Vec<u32>| {
+            request(GenerateParameters {
+                logit_bias: Some(ids.into_iter().map(|id| (id, -100.0)).collect()),
+                ..Default::default()
+            })
+        };
+        let ids: Vec<u32> = (0..64).collect();
+        assert_eq!(
+            key(&logit_bias(ids.clone())),
+            key(&logit_bias(ids.into_iter().rev().collect()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_coalescer() {
+        let coalescer = Coalescer::default();
+        let generations = &AtomicUsize::new(0);
+        let generate = move |_: GenerateRequest| async move {
+            generations.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(response())
+        };
+
+        let responses = futures::future::join_all(
+            (0..4).map(|_| coalescer.generate(request(GenerateParameters::default()), generate)),
+        )
+        .await;
+        assert_eq!(generations.load(Ordering::SeqCst), 1);
+        let mut shared = 0;
+        for response in responses {
+            let (response, coalesced) = response.unwrap();
+            assert_eq!(response.generated_text.text, "Deep Learning is");
+            shared += coalesced as usize;
+        }
+        assert_eq!(shared, 3);
+        assert!(coalescer.in_flight.lock().unwrap().is_empty());
+
+        // The waiting requests generate on their own when the generation fails
+        let failures = &AtomicUsize::new(0);
+        let fail_once = move |_: GenerateRequest| async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            match failures.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(InferError::GenerationError("CUDA OOM".to_string())),
+                _ => Ok(response()),
+            }
+        };
+        let responses = futures::future::join_all(
+            (0..3).map(|_| coalescer.generate(request(GenerateParameters::default()), fail_once)),
+        )
+        .await;
+        assert!(responses[0].is_err());
+        assert!(responses[1..].iter().all(|response| response.is_ok()));
+        assert_eq!(failures.load(Ordering::SeqCst), 3);
+    }
+}
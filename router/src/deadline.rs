@@ -0,0 +1,96 @@
+/// Latency budget propagation: upstream gateways set an absolute deadline on the requests
+use axum::http::{HeaderMap, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::trace::FutureExt;
+use opentelemetry::Context;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Header holding the deadline of a request, as a Unix timestamp in milliseconds
+const DEADLINE_HEADER: &str = "x-deadline";
+
+/// Deadline of the request, stored in the current OpenTelemetry context
+#[derive(Clone, Copy, Debug)]
+struct Deadline(SystemTime);
+
+/// Middleware attaching the deadline of the request to the current OpenTelemetry context
+pub(crate) async fn propagate<B>(request: Request<B>, next: Next<B>) -> Response {
+    match extract(request.headers()) {
+        Some(deadline) => {
+            let context = Context::current_with_value(Deadline(deadline));
+            next.run(request).with_context(context).await
+        }
+        None => next.run(request).await,
+    }
+}
+
+/// Parse the `x-deadline` header, ignoring malformed values
+fn extract(headers: &HeaderMap) -> Option<SystemTime> {
+    let value = headers.get(DEADLINE_HEADER)?;
+    match value
+        .to_str()
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+    {
+        Some(millis) => Some(UNIX_EPOCH + Duration::from_millis(millis)),
+        None => {
+            tracing::warn!("Ignoring malformed `{DEADLINE_HEADER}` header: {value:?}");
+            None
+        }
+    }
+}
+
+/// Get the deadline of the current OpenTelemetry context
+pub(crate) fn current() -> Option<SystemTime> {
+    Context::current()
+        .get::<Deadline>()
+        .map(|deadline| deadline.0)
+}
+
+/// Remaining latency budget of the current request. `Duration::ZERO` once exceeded.
+pub(crate) fn remaining() -> Option<Duration> {
+    current().map(|deadline| {
+        deadline
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO)
+    })
+}
+
+/// Deadline of the current request as a Unix timestamp in milliseconds, forwarded to the shards
+pub(crate) fn current_millis() -> Option<u64> {
+    current().map(|deadline| {
+        deadline
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_millis() as u64
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(extract(&headers), None);
+
+        headers.insert(DEADLINE_HEADER, "1700000000123".parse().unwrap());
+        assert_eq!(
+            extract(&headers),
+            Some(UNIX_EPOCH + Duration::from_millis(1700000000123))
+        );
+
+        headers.insert(DEADLINE_HEADER, "in 5 seconds".parse().unwrap());
+        assert_eq!(extract(&headers), None);
+    }
+
+    #[test]
+    fn test_current() {
+        let deadline = UNIX_EPOCH + Duration::from_millis(1700000000123);
+        let _guard = Context::current_with_value(Deadline(deadline)).attach();
+        assert_eq!(current(), Some(deadline));
+        assert_eq!(current_millis(), Some(1700000000123));
+        assert_eq!(remaining(), Some(Duration::ZERO));
+    }
+}
@@ -0,0 +1,407 @@
+/// Debug capture of the failing requests: once armed from the admin API, the payloads of the
+/// next requests failing with the chosen status code are kept in memory, scrubbed of their PII,
+/// to reproduce the errors reported by the users
+use crate::ndjson::EventParser;
+use crate::pii::PiiScanner;
+use crate::route_limits::{buffer_body, DEFAULT_MAX_BODY_SIZE};
+use crate::ErrorResponse;
+use axum::body::{Body, Bytes, Full, HttpBody, StreamBody};
+use axum::extract::Extension;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::trace::FutureExt;
+use opentelemetry::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
+
+/// Capture requested from the admin API
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, ToSchema)]
+pub(crate) struct DebugCaptureConfig {
+    /// Status code of the failing requests to capture
+    #[schema(example = 424)]
+    pub status: u16,
+    /// Number of failing requests to capture, 0 to disarm the capture
+    #[schema(example = 10)]
+    pub count: usize,
+}
+
+/// Payload of a failing request
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct CapturedRequest {
+    #[schema(nullable = true, example = "0123456789abcdef0123456789abcdef")]
+    pub request_id: Option<String>,
+    #[schema(example = "/generate")]
+    pub route: String,
+    #[schema(example = 424)]
+    pub status: u16,
+    #[schema(nullable = true, example = "generation")]
+    pub error_type: Option<String>,
+    /// Unix timestamp in seconds
+    #[schema(example = 1706270835)]
+    pub timestamp: u64,
+    /// Request body, its PII masked
+    #[schema(value_type = Object)]
+    pub payload: Value,
+}
+
+/// Armed capture and the captured requests, oldest first
+#[derive(Clone, Debug, Default, Serialize, ToSchema)]
+pub(crate) struct DebugCaptureState {
+    /// Status code captured, null when disarmed
+    #[schema(nullable = true, example = 424)]
+    pub status: Option<u16>,
+    /// Number of failing requests left to capture
+    #[schema(example = 8)]
+    pub remaining: usize,
+    pub captures: Vec<CapturedRequest>,
+}
+
+#[derive(Debug, Default)]
+struct Captures {
+    armed: Option<DebugCaptureConfig>,
+    captures: VecDeque<CapturedRequest>,
+}
+
+/// Bounded store of the captured requests
+#[derive(Clone)]
+pub(crate) struct DebugCapture {
+    size: usize,
+    /// Body limits of the routes accepting more than `DEFAULT_MAX_BODY_SIZE`, by path
+    body_limits: Arc<HashMap<String, usize>>,
+    /// Masks the PII of the payloads, with the categories of `--pii-categories`
+    scrubber: PiiScanner,
+    captures: Arc<Mutex<Captures>>,
+}
+
+impl DebugCapture {
+    pub(crate) fn new(size: usize, scrubber: PiiScanner) -> Self {
+        Self {
+            size,
+            body_limits: Arc::new(HashMap::new()),
+            scrubber,
+            captures: Arc::new(Mutex::new(Captures::default())),
+        }
+    }
+
+    /// Buffer the bodies of the routes with a `DefaultBodyLimit` of their own up to it, e.g. the
+    /// batch files
+    pub(crate) fn with_body_limits(mut self, body_limits: HashMap<String, usize>) -> Self {
+        self.body_limits = Arc::new(body_limits);
+        self
+    }
+
+    fn body_limit(&self, route: &str) -> usize {
+        self.body_limits
+            .get(route)
+            .copied()
+            .unwrap_or(DEFAULT_MAX_BODY_SIZE)
+    }
+
+    /// Arm the capture, replacing the previous one
+    pub(crate) fn configure(
+        &self,
+        config: DebugCaptureConfig,
+    ) -> Result<DebugCaptureState, String> {
+        if !(400..600).contains(&config.status) {
+            return Err(format!(
+                "`status` must be an error status code (4xx or 5xx). Given: {}",
+                config.status
+            ));
+        }
+        let armed = (config.count > 0).then_some(config);
+        match armed {
+            Some(config) => tracing::warn!(
+                "Capturing the payloads of the next {} requests failing with {}",
+                config.count,
+                config.status
+            ),
+            None => tracing::info!("Debug capture disarmed"),
+        }
+        self.captures.lock().unwrap().armed = armed;
+        Ok(self.state())
+    }
+
+    pub(crate) fn state(&self) -> DebugCaptureState {
+        let captures = self.captures.lock().unwrap();
+        DebugCaptureState {
+            status: captures.armed.map(|armed| armed.status),
+            remaining: captures.armed.map(|armed| armed.count).unwrap_or(0),
+            captures: captures.captures.iter().cloned().collect(),
+        }
+    }
+
+    fn armed_status(&self) -> Option<u16> {
+        self.captures
+            .lock()
+            .unwrap()
+            .armed
+            .map(|armed| armed.status)
+    }
+
+    /// Capture a failing request, its body scrubbed
+    fn record(
+        &self,
+        request_id: Option<String>,
+        route: String,
+        status: u16,
+        error_type: Option<String>,
+        body: &[u8],
+    ) {
+        let captured = self.insert(CapturedRequest {
+            request_id,
+            route,
+            status,
+            error_type,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_secs())
+                .unwrap_or(0),
+            payload: self.scrub(body),
+        });
+        if captured {
+            metrics::increment_counter!("tgi_debug_capture_count");
+        }
+    }
+
+    /// Keep a failing request if the capture is still armed for its status code
+    fn insert(&self, capture: CapturedRequest) -> bool {
+        let mut captures = self.captures.lock().unwrap();
+        let Some(armed) = captures.armed.as_mut() else {
+            return false;
+        };
+        if armed.status != capture.status {
+            return false;
+        }
+        armed.count -= 1;
+        if armed.count == 0 {
+            captures.armed = None;
+            tracing::info!("Debug capture complete");
+        }
+        if captures.captures.len() >= self.size {
+            captures.captures.pop_front();
+        }
+        captures.captures.push_back(capture);
+        true
+    }
+
+    /// Request body with the PII of its strings masked
+    fn scrub(&self, body: &[u8]) -> Value {
+        match serde_json::from_slice(body) {
+            Ok(payload) => self.scrub_value(payload),
+            Err(_) => Value::String(self.scrubber.mask(&String::from_utf8_lossy(body))),
+        }
+    }
+
+    fn scrub_value(&self, value: Value) -> Value {
+        match value {
+            Value::String(text) => Value::String(self.scrubber.mask(&text)),
+            Value::Array(values) => Value::Array(
+                values
+                    .into_iter()
+                    .map(|value| self.scrub_value(value))
+                    .collect(),
+            ),
+            Value::Object(fields) => Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(key, value)| (key, self.scrub_value(value)))
+                    .collect(),
+            ),
+            value => value,
+        }
+    }
+}
+
+/// Middleware capturing the payloads of the inference requests failing with the armed status
+/// code, or streaming an error event of an error with this status code. The bodies are only
+/// buffered while the capture is armed, up to the body limit of their route
+pub(crate) async fn capture(
+    Extension(debug_capture): Extension<DebugCapture>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    if request.method() != Method::POST {
+        return next.run(request).await;
+    }
+    let Some(armed_status) = debug_capture.armed_status() else {
+        return next.run(request).await;
+    };
+
+    let route = request.uri().path().to_string();
+    let (parts, body) = request.into_parts();
+    let body = match buffer_body(body, debug_capture.body_limit(&route)).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+    let stream_error = StreamErrorStatus::default();
+    let response = next
+        .run(Request::from_parts(parts, Body::from(body.clone())))
+        .with_context(Context::current_with_value(stream_error.clone()))
+        .await;
+    let status = response.status().as_u16();
+    let request_id = response
+        .headers()
+        .get("x-request-id")
+        .and_then(|request_id| request_id.to_str().ok())
+        .map(String::from);
+    let is_stream = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .map(|content_type| content_type.as_bytes().starts_with(b"text/event-stream"))
+        .unwrap_or(false);
+
+    // Look for the first error event of the streams, sent with a 200
+    if is_stream && response.status().is_success() {
+        let (parts, mut response_body) = response.into_parts();
+        let stream = async_stream::stream! {
+            let mut parser = Some(EventParser::default());
+            while let Some(chunk) = response_body.data().await {
+                if let (Ok(chunk), Some(events)) = (&chunk, parser.as_mut()) {
+                    let error = events
+                        .push(chunk)
+                        .lines()
+                        .find_map(|data| serde_json::from_str::<ErrorResponse>(data).ok());
+                    if let Some(error) = error {
+                        parser = None;
+                        if stream_error.take() == Some(armed_status) {
+                            debug_capture.record(
+                                request_id.clone(),
+                                route.clone(),
+                                armed_status,
+                                Some(error.error_type),
+                                &body,
+                            );
+                        }
+                    }
+                }
+                yield chunk;
+            }
+        };
+        return Response::from_parts(parts, axum::body::boxed(StreamBody::new(stream)));
+    }
+    if status != armed_status {
+        return response;
+    }
+
+    // Read the error type from the error body
+    let (parts, mut response_body) = response.into_parts();
+    let mut content = Vec::new();
+    while let Some(Ok(chunk)) = response_body.data().await {
+        content.extend_from_slice(&chunk);
+    }
+    let error_type = serde_json::from_slice::<ErrorResponse>(&content)
+        .ok()
+        .map(|error| error.error_type);
+    let response = Response::from_parts(parts, axum::body::boxed(Full::from(Bytes::from(content))));
+
+    debug_capture.record(request_id, route, status, error_type, &body);
+    response
+}
+
+/// Status code of the error sent in an event of the stream of a request: the status code of the
+/// same error returned before the stream started. Stored in the OpenTelemetry context of its
+/// generation
+#[derive(Clone, Debug, Default)]
+struct StreamErrorStatus(Arc<Mutex<Option<u16>>>);
+
+impl StreamErrorStatus {
+    fn take(&self) -> Option<u16> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// Record the status code of the error sent in an event of the stream of the current request
+pub(crate) fn stream_error(status: StatusCode) {
+    if let Some(stream_error) = Context::current().get::<StreamErrorStatus>() {
+        *stream_error.0.lock().unwrap() = Some(status.as_u16());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn capture(status: u16, payload: Value) -> CapturedRequest {
+        CapturedRequest {
+            request_id: None,
+            route: "/generate".to_string(),
+            status,
+            error_type: None,
+            timestamp: 0,
+            payload,
+        }
+    }
+
+    #[test]
+    fn test_debug_capture() {
+        let debug_capture = DebugCapture::new(2, PiiScanner::new("tag", &[]).unwrap());
+        assert!(!debug_capture.insert(capture(424, json!({}))));
+        assert!(debug_capture
+            .configure(DebugCaptureConfig {
+                status: 200,
+                count: 1
+            })
+            .is_err());
+
+        let state = debug_capture
+            .configure(DebugCaptureConfig {
+                status: 424,
+                count: 3,
+            })
+            .unwrap();
+        assert_eq!((state.status, state.remaining), (Some(424), 3));
+
+        // The PII is masked whatever the action of the scanner
+        let payload = debug_capture.scrub(
+            br#"{"inputs": "Mail john.doe@example.com", "parameters": {"max_new_tokens": 10}}"#,
+        );
+        assert_eq!(
+            payload,
+            json!({"inputs": "Mail [EMAIL]", "parameters": {"max_new_tokens": 10}})
+        );
+        assert_eq!(
+            debug_capture.scrub(b"SSN 123-45-6789"),
+            json!("SSN [NATIONAL_ID]")
+        );
+
+        // Only the armed status code is captured, in the limits of the store
+        assert!(!debug_capture.insert(capture(422, json!(0))));
+        assert!(debug_capture.insert(capture(424, json!(1))));
+        assert!(debug_capture.insert(capture(424, json!(2))));
+        assert!(debug_capture.insert(capture(424, json!(3))));
+        assert!(!debug_capture.insert(capture(424, json!(4))));
+        let state = debug_capture.state();
+        assert_eq!((state.status, state.remaining), (None, 0));
+        let payloads: Vec<Value> = state
+            .captures
+            .into_iter()
+            .map(|capture| capture.payload)
+            .collect();
+        assert_eq!(payloads, vec![json!(2), json!(3)]);
+    }
+
+    #[test]
+    fn test_debug_capture_limits() {
+        let debug_capture = DebugCapture::new(2, PiiScanner::new("tag", &[]).unwrap())
+            .with_body_limits(HashMap::from([("/batch_files".to_string(), 1024)]));
+        assert_eq!(debug_capture.body_limit("/batch_files"), 1024);
+        assert_eq!(debug_capture.body_limit("/generate"), DEFAULT_MAX_BODY_SIZE);
+
+        // The error events of the streams are captured with the status of the same error
+        let stream_error_status = StreamErrorStatus::default();
+        stream_error(StatusCode::TOO_MANY_REQUESTS);
+        {
+            let _guard = Context::current_with_value(stream_error_status.clone()).attach();
+            stream_error(StatusCode::FAILED_DEPENDENCY);
+        }
+        assert_eq!(stream_error_status.take(), Some(424));
+        assert_eq!(stream_error_status.take(), None);
+    }
+}
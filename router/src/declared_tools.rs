@@ -0,0 +1,165 @@
+/// Tools declared at startup: their grammars are compiled before serving and the chat requests
+/// can reference them by name
+use crate::infer::Infer;
+use crate::tool_choice;
+use crate::{GenerateParameters, GenerateRequest, GrammarType, Tool, Tools};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Tools loaded from the `--tools-file`
+#[derive(Clone, Debug, Default)]
+pub struct DeclaredTools(Arc<Vec<Tool>>);
+
+impl DeclaredTools {
+    pub fn from_file(filename: &std::path::Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(filename).map_err(|err| err.to_string())?;
+        Self::from_json(&content)
+    }
+
+    fn from_json(content: &str) -> Result<Self, String> {
+        let tools: Vec<Tool> = serde_json::from_str(content).map_err(|err| err.to_string())?;
+        for (i, tool) in tools.iter().enumerate() {
+            if !tool.function.parameters.is_object() {
+                return Err(format!(
+                    "tool `{}` must declare the JSON schema of its parameters",
+                    tool.function.name
+                ));
+            }
+            if tools[..i]
+                .iter()
+                .any(|other| other.function.name == tool.function.name)
+            {
+                return Err(format!("duplicate tool `{}`", tool.function.name));
+            }
+        }
+        Ok(Self(Arc::new(tools)))
+    }
+
+    /// Names of the declared tools
+    pub(crate) fn names(&self) -> Vec<String> {
+        self.0
+            .iter()
+            .map(|tool| tool.function.name.clone())
+            .collect()
+    }
+
+    /// Replace the tools of a request given by name only by their declaration
+    pub(crate) fn resolve(&self, tools: Vec<Tool>) -> Result<Vec<Tool>, String> {
+        tools
+            .into_iter()
+            .map(|tool| {
+                if !tool.function.parameters.is_null() {
+                    return Ok(tool);
+                }
+                self.0
+                    .iter()
+                    .find(|declared| declared.function.name == tool.function.name)
+                    .cloned()
+                    .ok_or_else(|| {
+                        format!(
+                            "Tool `{}` is not declared by the server and has no parameters",
+                            tool.function.name
+                        )
+                    })
+            })
+            .collect()
+    }
+
+    /// Grammars of the requests using a single declared tool, all of them, or all of them with
+    /// the default `auto` tool choice
+    fn grammars(&self) -> Vec<GrammarType> {
+        if self.0.is_empty() {
+            return Vec::new();
+        }
+        let mut grammars: Vec<GrammarType> = self
+            .0
+            .iter()
+            .map(|tool| GrammarType::Json(serde_json::json!(Tools::new(&[tool.clone()]))))
+            .collect();
+        if self.0.len() > 1 {
+            grammars.push(GrammarType::Json(serde_json::json!(Tools::new(&self.0))));
+        }
+        let mut auto = self.0.to_vec();
+        auto.push(tool_choice::no_tool());
+        grammars.push(GrammarType::Json(serde_json::json!(Tools::new(&auto))));
+        grammars
+    }
+
+    /// Compile the grammars of the declared tools on the shards by generating a single token
+    /// constrained by each of them
+    pub(crate) async fn prewarm(&self, infer: &Infer) {
+        let start = Instant::now();
+        let grammars = self.grammars();
+        let n_grammars = grammars.len();
+        for grammar in grammars {
+            let request = GenerateRequest {
+                inputs: "{".to_string(),
+                parameters: GenerateParameters {
+                    max_new_tokens: Some(1),
+                    grammar: Some(grammar),
+                    ..Default::default()
+                },
+            };
+            if let Err(err) = infer.generate(request).await {
+                tracing::warn!("Could not prewarm a declared tool grammar: {err}");
+            }
+        }
+        tracing::info!(
+            "Prewarmed {n_grammars} declared tool grammars in {:?}",
+            start.elapsed()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOOLS: &str = r#"[
+        {"type": "function", "function": {"name": "get_weather", "parameters": {"type": "object", "properties": {"location": {"type": "string"}}}}},
+        {"type": "function", "function": {"name": "get_time", "parameters": {"type": "object", "properties": {}}}}
+    ]"#;
+
+    #[test]
+    fn test_declared_tools_file() {
+        assert!(DeclaredTools::from_json("[]").is_ok());
+        assert!(DeclaredTools::from_json(
+            r#"[{"type": "function", "function": {"name": "get_weather"}}]"#
+        )
+        .is_err());
+        let duplicate = r#"{"type": "function", "function": {"name": "a", "parameters": {}}}"#;
+        assert!(DeclaredTools::from_json(&format!("[{duplicate}, {duplicate}]")).is_err());
+
+        let tools = DeclaredTools::from_json(TOOLS).unwrap();
+        assert_eq!(tools.names(), vec!["get_weather", "get_time"]);
+        // One grammar per tool, one for all of them and one for the `auto` tool choice
+        assert_eq!(tools.grammars().len(), 4);
+        assert!(DeclaredTools::from_json("[]")
+            .unwrap()
+            .grammars()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_declared_tools_resolve() {
+        let tools = DeclaredTools::from_json(TOOLS).unwrap();
+        let request_tools: Vec<Tool> = serde_json::from_str(
+            r#"[
+                {"type": "function", "function": {"name": "get_time"}},
+                {"type": "function", "function": {"name": "search", "parameters": {"type": "object"}}}
+            ]"#,
+        )
+        .unwrap();
+        let resolved = tools.resolve(request_tools).unwrap();
+        assert_eq!(
+            resolved[0].function.parameters,
+            serde_json::json!({"type": "object", "properties": {}})
+        );
+        assert_eq!(resolved[1].function.name, "search");
+
+        let unknown: Vec<Tool> =
+            serde_json::from_str(r#"[{"type": "function", "function": {"name": "unknown"}}]"#)
+                .unwrap();
+        assert!(tools.resolve(unknown).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+/// Incremental detokenization of the streamed tokens
+use std::sync::Arc;
+use tokenizers::Tokenizer;
+
+const REPLACEMENT_CHARACTER: char = '\u{FFFD}';
+
+/// Buffers the tokens holding partial UTF-8 sequences (e.g. byte-level pieces of an emoji)
+/// until the text they decode to is complete.
+pub(crate) struct IncrementalDetokenizer {
+    tokenizer: Arc<Tokenizer>,
+    /// Generated token ids
+    ids: Vec<u32>,
+    /// Start of the ids decoded as context for the buffered ids
+    prefix_offset: usize,
+    /// Start of the buffered ids
+    read_offset: usize,
+}
+
+impl IncrementalDetokenizer {
+    pub(crate) fn new(tokenizer: Arc<Tokenizer>) -> Self {
+        Self {
+            tokenizer,
+            ids: Vec::new(),
+            prefix_offset: 0,
+            read_offset: 0,
+        }
+    }
+
+    /// Text to emit for a new token. Empty while the token is buffered
+    pub(crate) fn next(&mut self, id: u32, text: &str) -> String {
+        self.ids.push(id);
+
+        // Nothing is buffered and the shard text is complete: keep the token as context
+        if self.read_offset + 1 == self.ids.len() && !text.contains(REPLACEMENT_CHARACTER) {
+            self.prefix_offset = self.read_offset;
+            self.read_offset = self.ids.len();
+            return text.to_string();
+        }
+
+        match self.decode() {
+            Some(text) if !text.ends_with(REPLACEMENT_CHARACTER) => {
+                self.prefix_offset = self.read_offset;
+                self.read_offset = self.ids.len();
+                text
+            }
+            _ => String::new(),
+        }
+    }
+
+    /// Text of the tokens still buffered at the end of the generation
+    pub(crate) fn flush(&mut self) -> String {
+        if self.read_offset == self.ids.len() {
+            return String::new();
+        }
+        let text = self.decode().unwrap_or_default();
+        self.prefix_offset = self.read_offset;
+        self.read_offset = self.ids.len();
+        text
+    }
+
+    /// Decode the buffered ids, using the previous ids as context
+    fn decode(&self) -> Option<String> {
+        let prefix_text = self
+            .tokenizer
+            .decode(&self.ids[self.prefix_offset..self.read_offset], false)
+            .ok()?;
+        let new_text = self
+            .tokenizer
+            .decode(&self.ids[self.prefix_offset..], false)
+            .ok()?;
+        if new_text.len() <= prefix_text.len() {
+            return None;
+        }
+        new_text.get(prefix_text.len()..).map(String::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::get_tokenizer;
+
+    #[tokio::test]
+    async fn test_incremental_detokenizer() {
+        let tokenizer = Arc::new(get_tokenizer().await);
+        let inputs = "Hello 😀 world 🤗!";
+        let ids = tokenizer.encode(inputs, false).unwrap().get_ids().to_vec();
+
+        let mut detokenizer = IncrementalDetokenizer::new(tokenizer.clone());
+        let mut output = String::new();
+        for id in ids {
+            // Byte-level tokens decode to replacement characters on their own
+            let token_text = tokenizer.decode(&[id], false).unwrap();
+            let text = detokenizer.next(id, &token_text);
+            assert!(!text.contains(REPLACEMENT_CHARACTER));
+            output.push_str(&text);
+        }
+        output.push_str(&detokenizer.flush());
+
+        assert_eq!(output, inputs);
+    }
+}
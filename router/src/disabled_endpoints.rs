@@ -0,0 +1,197 @@
+/// Endpoints turned off at launch: their routes are not served and their paths are removed from
+/// the OpenAPI documentation, shrinking the surface of the deployments that do not use them
+use utoipa::openapi::OpenApi;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Endpoint {
+    /// `POST /`
+    CompatGenerate,
+    Generate,
+    GenerateStream,
+    GenerateSamples,
+    ChatCompletions,
+    Completions,
+    Vertex,
+    Invocations,
+    Tokenize,
+    /// `POST /mcp`, served with `--enable-mcp`
+    Mcp,
+    Models,
+    Details,
+    Batches,
+    BatchFiles,
+    WaitingRoom,
+    Abort,
+    Admin,
+    Metrics,
+    Docs,
+    Playground,
+}
+
+impl Endpoint {
+    const ALL: [Endpoint; 20] = [
+        Endpoint::CompatGenerate,
+        Endpoint::Generate,
+        Endpoint::GenerateStream,
+        Endpoint::GenerateSamples,
+        Endpoint::ChatCompletions,
+        Endpoint::Completions,
+        Endpoint::Vertex,
+        Endpoint::Invocations,
+        Endpoint::Tokenize,
+        Endpoint::Mcp,
+        Endpoint::Models,
+        Endpoint::Details,
+        Endpoint::Batches,
+        Endpoint::BatchFiles,
+        Endpoint::WaitingRoom,
+        Endpoint::Abort,
+        Endpoint::Admin,
+        Endpoint::Metrics,
+        Endpoint::Docs,
+        Endpoint::Playground,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Endpoint::CompatGenerate => "compat_generate",
+            Endpoint::Generate => "generate",
+            Endpoint::GenerateStream => "generate_stream",
+            Endpoint::GenerateSamples => "generate_samples",
+            Endpoint::ChatCompletions => "chat_completions",
+            Endpoint::Completions => "completions",
+            Endpoint::Vertex => "vertex",
+            Endpoint::Invocations => "invocations",
+            Endpoint::Tokenize => "tokenize",
+            Endpoint::Mcp => "mcp",
+            Endpoint::Models => "models",
+            Endpoint::Details => "details",
+            Endpoint::Batches => "batches",
+            Endpoint::BatchFiles => "batch_files",
+            Endpoint::WaitingRoom => "waiting_room",
+            Endpoint::Abort => "abort",
+            Endpoint::Admin => "admin",
+            Endpoint::Metrics => "metrics",
+            Endpoint::Docs => "docs",
+            Endpoint::Playground => "playground",
+        }
+    }
+
+    /// Documented paths of the endpoint. The health checks share `/` with `compat_generate` and
+    /// are not documented
+    fn paths(&self) -> &'static [&'static str] {
+        match self {
+            Endpoint::CompatGenerate => &["/"],
+            Endpoint::Generate => &["/generate"],
+            Endpoint::GenerateStream => &["/generate_stream"],
+            Endpoint::GenerateSamples => &["/generate_samples"],
+            Endpoint::ChatCompletions => &["/v1/chat/completions"],
+            Endpoint::Completions => &["/v1/completions"],
+            Endpoint::Vertex => &["/vertex"],
+            Endpoint::Invocations => &["/invocations"],
+            Endpoint::Tokenize => &["/tokenize"],
+            Endpoint::Mcp => &["/mcp"],
+            Endpoint::Models => &["/v1/models", "/v1/models/{model_id}"],
+            Endpoint::Details => &["/results/{request_id}/details"],
+            Endpoint::Batches => &[
+                "/v1/files",
+                "/v1/files/{file_id}",
+                "/v1/files/{file_id}/content",
+                "/v1/batches",
+                "/v1/batches/{batch_id}",
+                "/v1/batches/{batch_id}/cancel",
+            ],
+            Endpoint::BatchFiles => &[
+                "/batch_files",
+                "/batch_files/{batch_id}",
+                "/batch_files/{batch_id}/results",
+            ],
+            Endpoint::WaitingRoom => &["/waiting_room/{ticket_id}"],
+            Endpoint::Abort => &["/abort/{request_id}"],
+            Endpoint::Admin => &[
+                "/admin/shards",
+                "/admin/requests",
+                "/admin/tenants",
+                "/admin/tokenizer/reload",
+                "/admin/chaos",
+                "/admin/flight_recorder",
+                "/admin/debug_capture",
+                "/admin/adapters",
+                "/admin/adapters/{adapter_id}",
+            ],
+            Endpoint::Metrics => &["/metrics"],
+            Endpoint::Docs | Endpoint::Playground => &[],
+        }
+    }
+}
+
+impl std::str::FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Endpoint::ALL
+            .into_iter()
+            .find(|endpoint| endpoint.name() == name)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Endpoint::ALL.iter().map(Endpoint::name).collect();
+                format!(
+                    "unknown endpoint `{name}`, expected one of `{}`",
+                    names.join("`, `")
+                )
+            })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DisabledEndpoints {
+    endpoints: Vec<Endpoint>,
+}
+
+impl DisabledEndpoints {
+    pub fn new(names: &[String]) -> Result<Self, String> {
+        let endpoints = names
+            .iter()
+            .map(|name| name.trim().parse())
+            .collect::<Result<_, _>>()?;
+        Ok(Self { endpoints })
+    }
+
+    pub(crate) fn contains(&self, endpoint: Endpoint) -> bool {
+        self.endpoints.contains(&endpoint)
+    }
+
+    /// Remove the paths of the disabled endpoints from the documentation
+    pub(crate) fn undocument(&self, doc: &mut OpenApi) {
+        for endpoint in &self.endpoints {
+            for path in endpoint.paths() {
+                doc.paths.paths.remove(*path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disabled_endpoints() {
+        let disabled = DisabledEndpoints::new(&[
+            "generate_stream".to_string(),
+            " vertex".to_string(),
+            "mcp".to_string(),
+        ])
+        .unwrap();
+        assert!(disabled.contains(Endpoint::GenerateStream));
+        assert!(disabled.contains(Endpoint::Vertex));
+        assert!(disabled.contains(Endpoint::Mcp));
+        assert!(!disabled.contains(Endpoint::Generate));
+
+        let err = DisabledEndpoints::new(&["generate_streams".to_string()]).unwrap_err();
+        assert!(err.starts_with("unknown endpoint `generate_streams`"));
+
+        for endpoint in Endpoint::ALL {
+            assert_eq!(endpoint.name().parse::<Endpoint>(), Ok(endpoint));
+        }
+    }
+}
@@ -0,0 +1,179 @@
+/// Translated error messages: the validation errors are rendered in the language of the
+/// `Accept-Language` header of the request when a catalog has it. `error_type` is not translated
+use crate::infer::InferError;
+use axum::http::{header, HeaderMap, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Extension;
+use opentelemetry::trace::FutureExt;
+use opentelemetry::Context;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Message templates by error code, by lowercase language tag
+#[derive(Clone, Debug)]
+pub struct ErrorCatalogs(Arc<HashMap<String, HashMap<String, String>>>);
+
+impl ErrorCatalogs {
+    /// Load the catalogs of a directory, one `<language>.json` file per language, e.g. `fr.json`
+    /// or `pt-BR.json`, mapping the error codes to message templates
+    pub fn from_dir(dir: &Path) -> Result<Self, String> {
+        let entries = std::fs::read_dir(dir).map_err(|err| format!("{}: {err}", dir.display()))?;
+        let mut catalogs = HashMap::new();
+        for entry in entries {
+            let path = entry.map_err(|err| err.to_string())?.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+                continue;
+            }
+            let Some(language) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let content = std::fs::read_to_string(&path)
+                .map_err(|err| format!("{}: {err}", path.display()))?;
+            let catalog: HashMap<String, String> = serde_json::from_str(&content)
+                .map_err(|err| format!("{}: {err}", path.display()))?;
+            catalogs.insert(language.to_lowercase(), catalog);
+        }
+        if catalogs.is_empty() {
+            return Err(format!("no `.json` catalog in {}", dir.display()));
+        }
+        Ok(Self(Arc::new(catalogs)))
+    }
+
+    /// Template of an error code in the first language having it. A regional language, e.g.
+    /// `fr-CA`, falls back to its primary language
+    fn template(&self, languages: &[String], code: &str) -> Option<&str> {
+        languages.iter().find_map(|language| {
+            let primary = language.split('-').next().unwrap_or_default();
+            [language.as_str(), primary]
+                .into_iter()
+                .find_map(|language| {
+                    self.0
+                        .get(language)
+                        .and_then(|catalog| catalog.get(code))
+                        .map(String::as_str)
+                })
+        })
+    }
+}
+
+/// Languages accepted by the client, by preference, with the catalogs to translate the errors
+/// in, stored in the current OpenTelemetry context
+#[derive(Clone, Debug)]
+struct AcceptedLanguages {
+    languages: Vec<String>,
+    catalogs: ErrorCatalogs,
+}
+
+/// Middleware attaching the languages of the `Accept-Language` header to the current
+/// OpenTelemetry context
+pub(crate) async fn negotiate<B>(
+    Extension(catalogs): Extension<ErrorCatalogs>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let languages = accepted_languages(request.headers());
+    if languages.is_empty() {
+        return next.run(request).await;
+    }
+    let context = Context::current_with_value(AcceptedLanguages {
+        languages,
+        catalogs,
+    });
+    next.run(request).with_context(context).await
+}
+
+/// Language tags of the `Accept-Language` header, lowercase, by decreasing quality. The
+/// wildcard and the refused languages (`q=0`) are skipped
+fn accepted_languages(headers: &HeaderMap) -> Vec<String> {
+    let Some(value) = headers
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return Vec::new();
+    };
+    let mut languages: Vec<(String, f32)> = value
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let language = parts.next()?.trim().to_lowercase();
+            let quality = parts
+                .find_map(|parameter| parameter.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |quality| quality.trim().parse().ok())?;
+            (!language.is_empty() && language != "*" && quality > 0.0)
+                .then_some((language, quality))
+        })
+        .collect();
+    // Stable: the languages of the same quality keep their order
+    languages.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    languages
+        .into_iter()
+        .map(|(language, _)| language)
+        .collect()
+}
+
+/// Message of an error, translated in the language of the current request if possible
+pub(crate) fn message(err: &InferError) -> String {
+    let InferError::ValidationError(validation_err) = err else {
+        return err.to_string();
+    };
+    let context = Context::current();
+    let translated = context.get::<AcceptedLanguages>().and_then(|accepted| {
+        let (code, values) = validation_err.message_code();
+        let template = accepted.catalogs.template(&accepted.languages, code)?;
+        Some(render(template, &values))
+    });
+    translated.unwrap_or_else(|| err.to_string())
+}
+
+/// Replace the `{0}`, `{1}`... placeholders of a template
+fn render(template: &str, values: &[String]) -> String {
+    values
+        .iter()
+        .enumerate()
+        .fold(template.to_string(), |message, (i, value)| {
+            message.replace(&format!("{{{i}}}"), value)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::ValidationError;
+
+    #[test]
+    fn test_accepted_languages() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCEPT_LANGUAGE,
+            "en;q=0.5, fr-CA, *;q=0.1, de;q=0, pt-BR;q=0.8"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(accepted_languages(&headers), vec!["fr-ca", "pt-br", "en"]);
+        assert!(accepted_languages(&HeaderMap::new()).is_empty());
+    }
+
+    #[test]
+    fn test_template() {
+        let catalogs = ErrorCatalogs(Arc::new(HashMap::from([(
+            "fr".to_string(),
+            HashMap::from([(
+                "max_new_tokens".to_string(),
+                "`max_new_tokens` doit être <= {0}. Reçu : {1}".to_string(),
+            )]),
+        )])));
+        let (code, values) = ValidationError::MaxNewTokens(512, 1024).message_code();
+        let template = catalogs
+            .template(&["de".to_string(), "fr-ca".to_string()], code)
+            .unwrap();
+        assert_eq!(
+            render(template, &values),
+            "`max_new_tokens` doit être <= 512. Reçu : 1024"
+        );
+
+        let (code, _) = ValidationError::TopP.message_code();
+        assert!(catalogs.template(&["fr".to_string()], code).is_none());
+    }
+}
@@ -0,0 +1,234 @@
+/// OpenMetrics exemplars linking the latency histograms to example traces. The Prometheus
+/// exporter has no exemplar support: once enabled, the exemplars are added to its output on the
+/// OpenMetrics scrapes.
+use opentelemetry::trace::{TraceContextExt, TraceId};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Content type of the OpenMetrics scrapes
+pub(crate) const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Histograms with exemplars
+const EXEMPLAR_METRICS: [&str; 2] = ["tgi_request_duration", "tgi_request_first_token_duration"];
+
+#[derive(Clone, Debug, PartialEq)]
+struct Exemplar {
+    trace_id: TraceId,
+    value: f64,
+    /// Seconds since the UNIX epoch
+    timestamp: f64,
+}
+
+/// Labels of a series as rendered by the Prometheus exporter, sorted by name and without `le`
+fn label_set<'a>(labels: impl IntoIterator<Item = (&'a str, String)>) -> String {
+    let mut labels: Vec<_> = labels
+        .into_iter()
+        .filter(|(name, _)| *name != "le")
+        .map(|(name, value)| format!("{name}=\"{value}\""))
+        .collect();
+    labels.sort();
+    labels.join(",")
+}
+
+/// Escape a label value like the Prometheus exporter
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Labels of a `{name="value",...}` series, their values still escaped
+fn parse_labels(labels: &str) -> Option<Vec<(&str, String)>> {
+    let mut parsed = Vec::new();
+    let mut rest = labels;
+    while !rest.is_empty() {
+        let (name, value) = rest.trim_start_matches(',').split_once("=\"")?;
+        let mut escaped = false;
+        let end = value.char_indices().find_map(|(index, c)| {
+            let end = !escaped && c == '"';
+            escaped = !escaped && c == '\\';
+            end.then_some(index)
+        })?;
+        parsed.push((name, value[..end].to_string()));
+        rest = &value[end + 1..];
+    }
+    Some(parsed)
+}
+
+/// Histogram name and label set of a series
+type Series = (&'static str, String);
+
+/// Latest exemplar of each bucket of the histograms
+#[derive(Clone, Debug)]
+pub(crate) struct Exemplars {
+    /// Upper bounds of the histogram buckets, without `+Inf`
+    buckets: Arc<Vec<f64>>,
+    exemplars: Arc<Mutex<HashMap<Series, Vec<Option<Exemplar>>>>>,
+}
+
+impl Exemplars {
+    /// Exemplars of the histograms with the upper bounds `buckets`
+    pub(crate) fn new(buckets: Vec<f64>) -> Self {
+        Self {
+            buckets: Arc::new(buckets),
+            exemplars: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn record(&self, name: &'static str, labels: String, exemplar: Exemplar) {
+        let bucket = self
+            .buckets
+            .iter()
+            .position(|&le| exemplar.value <= le)
+            .unwrap_or(self.buckets.len());
+        self.exemplars
+            .lock()
+            .unwrap()
+            .entry((name, labels))
+            .or_insert_with(|| vec![None; self.buckets.len() + 1])[bucket] = Some(exemplar);
+    }
+
+    /// Exemplar of the bucket with the upper bound `le` of the series with the `labels`
+    fn get(&self, name: &'static str, labels: String, le: &str) -> Option<Exemplar> {
+        let bucket = match le {
+            "+Inf" => self.buckets.len(),
+            le => {
+                let le: f64 = le.parse().ok()?;
+                self.buckets.iter().position(|&bound| bound == le)?
+            }
+        };
+        self.exemplars.lock().unwrap().get(&(name, labels))?[bucket].clone()
+    }
+
+    /// Convert the Prometheus text exposition to OpenMetrics, with the exemplars of the buckets
+    pub(crate) fn annotate(&self, rendered: &str) -> String {
+        let mut output = String::with_capacity(rendered.len());
+        for line in rendered.lines() {
+            // OpenMetrics counters must end with `_total`: keep the names of the dashboards
+            if let Some(family) = line
+                .strip_prefix("# TYPE ")
+                .and_then(|line| line.strip_suffix(" counter"))
+            {
+                output.push_str(&format!("# TYPE {family} unknown\n"));
+                continue;
+            }
+            output.push_str(line);
+            if let Some(exemplar) = self.bucket_exemplar(line) {
+                output.push_str(&format!(
+                    " # {{trace_id=\"{}\"}} {} {:.3}",
+                    exemplar.trace_id, exemplar.value, exemplar.timestamp
+                ));
+            }
+            output.push('\n');
+        }
+        output.push_str("# EOF\n");
+        output
+    }
+
+    /// Exemplar of a `<name>_bucket{...,le="<le>"} <count>` line
+    fn bucket_exemplar(&self, line: &str) -> Option<Exemplar> {
+        let (name, series) = line.split_once("_bucket{")?;
+        let name = EXEMPLAR_METRICS
+            .into_iter()
+            .find(|metric| *metric == name)?;
+        let (labels, _) = series.rsplit_once('}')?;
+        let labels = parse_labels(labels)?;
+        let le = labels.iter().find(|(label, _)| *label == "le")?.1.clone();
+        self.get(name, label_set(labels), &le)
+    }
+}
+
+/// Record `value` in the `name` histogram series with the `labels`, with the trace of `span` as
+/// exemplar if enabled
+pub(crate) fn histogram(
+    exemplars: Option<&Exemplars>,
+    name: &'static str,
+    labels: &[(&'static str, String)],
+    value: f64,
+    span: &tracing::Span,
+) {
+    metrics::histogram!(name, value, labels);
+    let Some(exemplars) = exemplars else {
+        return;
+    };
+    let trace_id = span.context().span().span_context().trace_id();
+    // Requests without a trace have nothing to link to
+    if trace_id == TraceId::INVALID {
+        return;
+    }
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let labels = label_set(labels.iter().map(|(label, value)| (*label, escape(value))));
+    exemplars.record(
+        name,
+        labels,
+        Exemplar {
+            trace_id,
+            value,
+            timestamp,
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_annotate() {
+        let exemplars = Exemplars::new(vec![0.5, 1.0]);
+        let trace_id = TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap();
+        exemplars.record(
+            "tgi_request_duration",
+            String::new(),
+            Exemplar {
+                trace_id,
+                value: 0.7,
+                timestamp: 1700000000.0,
+            },
+        );
+        // Another series of the histogram
+        exemplars.record(
+            "tgi_request_duration",
+            label_set([("route", escape("/v1/\"chat\""))]),
+            Exemplar {
+                trace_id,
+                value: 0.2,
+                timestamp: 1700000001.0,
+            },
+        );
+
+        let rendered = "\
+# TYPE tgi_request_count counter
+tgi_request_count 3
+# TYPE tgi_request_duration histogram
+tgi_request_duration_bucket{le=\"0.5\"} 0
+tgi_request_duration_bucket{le=\"1\"} 1
+tgi_request_duration_bucket{le=\"+Inf\"} 1
+tgi_request_duration_sum 0.7
+tgi_request_duration_count 1
+tgi_request_duration_bucket{route=\"/v1/\\\"chat\\\"\",le=\"0.5\"} 1
+";
+        assert_eq!(
+            exemplars.annotate(rendered),
+            "\
+# TYPE tgi_request_count unknown
+tgi_request_count 3
+# TYPE tgi_request_duration histogram
+tgi_request_duration_bucket{le=\"0.5\"} 0
+tgi_request_duration_bucket{le=\"1\"} 1 # {trace_id=\"4bf92f3577b34da6a3ce929d0e0e4736\"} 0.7 1700000000.000
+tgi_request_duration_bucket{le=\"+Inf\"} 1
+tgi_request_duration_sum 0.7
+tgi_request_duration_count 1
+tgi_request_duration_bucket{route=\"/v1/\\\"chat\\\"\",le=\"0.5\"} 1 # {trace_id=\"4bf92f3577b34da6a3ce929d0e0e4736\"} 0.2 1700000001.000
+# EOF
+"
+        );
+    }
+}
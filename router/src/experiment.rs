@@ -0,0 +1,202 @@
+/// Canary parameter overrides
+use crate::{GenerateParameters, GrammarType};
+use axum::http::HeaderValue;
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// Routes an experiment can be enabled on
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ExperimentRoute {
+    Chat,
+    Completions,
+}
+
+/// Parameters used when the client did not set them
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ExperimentParameters {
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    top_k: Option<i32>,
+    repetition_penalty: Option<f32>,
+    frequency_penalty: Option<f32>,
+    grammar: Option<GrammarType>,
+    /// LoRA adapter of the requests not choosing one
+    adapter_id: Option<String>,
+}
+
+impl ExperimentParameters {
+    fn apply(&self, parameters: &mut GenerateParameters) {
+        parameters.temperature = parameters.temperature.or(self.temperature);
+        parameters.top_p = parameters.top_p.or(self.top_p);
+        parameters.top_k = parameters.top_k.or(self.top_k);
+        parameters.repetition_penalty = parameters.repetition_penalty.or(self.repetition_penalty);
+        parameters.frequency_penalty = parameters.frequency_penalty.or(self.frequency_penalty);
+        if parameters.grammar.is_none() {
+            parameters.grammar = self.grammar.clone();
+        }
+        if parameters.adapter_id.is_none() && parameters.adapter_parameters.is_none() {
+            parameters.adapter_id = self.adapter_id.clone();
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Experiment {
+    /// Name sent in the `x-experiment` header and used as metrics label
+    name: String,
+    /// Percentage of the requests enrolled in this experiment
+    percentage: f32,
+    /// Routes the experiment is enabled on. All routes if empty
+    #[serde(default)]
+    routes: Vec<ExperimentRoute>,
+    #[serde(default)]
+    parameters: ExperimentParameters,
+}
+
+/// Experiments loaded from the `--experiments-config` file
+#[derive(Clone, Debug, Default)]
+pub struct Experiments(Arc<Vec<Experiment>>);
+
+impl Experiments {
+    pub fn from_file(filename: &std::path::Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(filename).map_err(|err| err.to_string())?;
+        Self::from_json(&content)
+    }
+
+    fn from_json(content: &str) -> Result<Self, String> {
+        let experiments: Vec<Experiment> =
+            serde_json::from_str(content).map_err(|err| err.to_string())?;
+
+        let mut total = 0.0;
+        for experiment in experiments.iter() {
+            if HeaderValue::from_str(&experiment.name).is_err() {
+                return Err(format!(
+                    "experiment name `{}` is not a valid header value",
+                    experiment.name
+                ));
+            }
+            if !(0.0..=100.0).contains(&experiment.percentage) {
+                return Err(format!(
+                    "`percentage` of experiment `{}` must be between 0 and 100. Given: {}",
+                    experiment.name, experiment.percentage
+                ));
+            }
+            total += experiment.percentage;
+        }
+        if total > 100.0 {
+            return Err(format!(
+                "the sum of the experiments percentages must be <= 100. Given: {total}"
+            ));
+        }
+        Ok(Self(Arc::new(experiments)))
+    }
+
+    /// Enroll a request in at most one experiment and apply its parameters
+    pub(crate) fn assign(
+        &self,
+        route: ExperimentRoute,
+        parameters: &mut GenerateParameters,
+    ) -> Option<String> {
+        if self.0.is_empty() {
+            return None;
+        }
+        self.assign_with(thread_rng().gen_range(0.0..100.0), route, parameters)
+    }
+
+    fn assign_with(
+        &self,
+        mut draw: f32,
+        route: ExperimentRoute,
+        parameters: &mut GenerateParameters,
+    ) -> Option<String> {
+        for experiment in self.0.iter() {
+            if draw < experiment.percentage {
+                if !experiment.routes.is_empty() && !experiment.routes.contains(&route) {
+                    return None;
+                }
+                experiment.parameters.apply(parameters);
+                metrics::increment_counter!("tgi_request_experiment", "experiment" => experiment.name.clone());
+                return Some(experiment.name.clone());
+            }
+            draw -= experiment.percentage;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::default_parameters;
+
+    #[test]
+    fn test_experiments_config() {
+        assert!(Experiments::from_json("[]").is_ok());
+        assert!(Experiments::from_json(
+            r#"[{"name": "a", "percentage": 60}, {"name": "b", "percentage": 50}]"#
+        )
+        .is_err());
+        assert!(Experiments::from_json(r#"[{"name": "a", "percentage": -1}]"#).is_err());
+        assert!(Experiments::from_json(r#"[{"name": "a", "percentage": 5, "foo": 1}]"#).is_err());
+    }
+
+    #[test]
+    fn test_experiments_assign() {
+        let experiments = Experiments::from_json(
+            r#"[
+                {"name": "low-temp", "percentage": 5, "routes": ["chat"], "parameters": {"temperature": 0.2, "top_p": 0.9}},
+                {"name": "top-k", "percentage": 10, "parameters": {"top_k": 10, "adapter_id": "my-org/support-lora"}}
+            ]"#,
+        )
+        .unwrap();
+
+        let mut parameters = GenerateParameters {
+            top_p: Some(0.5),
+            ..default_parameters()
+        };
+        assert_eq!(
+            experiments.assign_with(1.0, ExperimentRoute::Chat, &mut parameters),
+            Some("low-temp".to_string())
+        );
+        assert_eq!(parameters.temperature, Some(0.2));
+        // Parameters set by the client are kept
+        assert_eq!(parameters.top_p, Some(0.5));
+
+        // The first experiment is not enabled on completions
+        let mut parameters = default_parameters();
+        assert_eq!(
+            experiments.assign_with(1.0, ExperimentRoute::Completions, &mut parameters),
+            None
+        );
+        assert_eq!(parameters.temperature, None);
+
+        let mut parameters = default_parameters();
+        assert_eq!(
+            experiments.assign_with(10.0, ExperimentRoute::Completions, &mut parameters),
+            Some("top-k".to_string())
+        );
+        assert_eq!(parameters.top_k, Some(10));
+        assert_eq!(
+            parameters.adapter_id.as_deref(),
+            Some("my-org/support-lora")
+        );
+
+        // The adapter chosen by the client is kept
+        let mut parameters = GenerateParameters {
+            adapter_id: Some("my-org/legal-lora".to_string()),
+            ..default_parameters()
+        };
+        experiments.assign_with(10.0, ExperimentRoute::Completions, &mut parameters);
+        assert_eq!(parameters.adapter_id.as_deref(), Some("my-org/legal-lora"));
+
+        let mut parameters = default_parameters();
+        assert_eq!(
+            experiments.assign_with(20.0, ExperimentRoute::Chat, &mut parameters),
+            None
+        );
+    }
+}
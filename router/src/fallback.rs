@@ -0,0 +1,182 @@
+/// Fallback to a second deployment serving another model when the primary model fails
+use crate::health::Health;
+use crate::hedging::forwarded_headers;
+use crate::infer::InferError;
+use crate::route_limits::{buffer_body, DEFAULT_MAX_BODY_SIZE};
+use crate::{ErrorResponse, Info};
+use axum::body::{Body, Bytes, StreamBody};
+use axum::extract::Extension;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use std::time::Duration;
+
+/// Header naming the model that served the request
+const SERVED_MODEL_HEADER: &str = "x-served-model";
+/// Time the fallback deployment has to answer, the streams then being read as they come
+const FALLBACK_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Debug)]
+pub(crate) struct Fallback {
+    /// HTTP client used to reach the fallback deployment
+    client: reqwest::Client,
+    /// Base url of the fallback deployment
+    endpoint: String,
+    /// Model served by the fallback deployment
+    model_id: String,
+}
+
+impl Fallback {
+    pub(crate) fn new(endpoint: String, model_id: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            model_id,
+        }
+    }
+
+    /// Send the request body to the same route of the fallback deployment. The response body is
+    /// streamed back as it is received
+    async fn forward(
+        &self,
+        path: &str,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Result<Response, axum::BoxError> {
+        let request = self
+            .client
+            .post(format!("{}{path}", self.endpoint))
+            .headers(headers)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .send();
+        let mut response = tokio::time::timeout(FALLBACK_TIMEOUT, request)
+            .await??
+            .error_for_status()?;
+
+        let status = response.status();
+        let content_type = response.headers().get(CONTENT_TYPE).cloned();
+        let body = async_stream::stream! {
+            loop {
+                match response.chunk().await {
+                    Ok(Some(chunk)) => yield Ok(chunk),
+                    Ok(None) => break,
+                    Err(err) => {
+                        yield Err(err);
+                        break;
+                    }
+                }
+            }
+        };
+
+        let mut response = (status, StreamBody::new(body)).into_response();
+        if let Some(content_type) = content_type {
+            response.headers_mut().insert(CONTENT_TYPE, content_type);
+        }
+        Ok(response)
+    }
+}
+
+/// Failures of the primary model worth re-issuing to the fallback model: shard errors,
+/// unhealthy shards, incomplete generations and shutdowns. Validation errors and overload
+/// answers are returned as is
+fn is_retryable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::FAILED_DEPENDENCY
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::SERVICE_UNAVAILABLE
+    )
+}
+
+fn tag(response: &mut Response, model_id: &str) {
+    if let Ok(model_id) = HeaderValue::from_str(model_id) {
+        response.headers_mut().insert(SERVED_MODEL_HEADER, model_id);
+    }
+}
+
+/// Middleware re-issuing the requests failing on the primary model to the fallback model. The
+/// primary model is skipped while its circuit breaker is open. The errors of the streams are
+/// sent in the stream: streams are only re-issued while the primary model is skipped
+pub(crate) async fn fallback(
+    Extension(fallback): Extension<Fallback>,
+    Extension(health): Extension<Health>,
+    Extension(info): Extension<Info>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|path| path.to_string())
+        .unwrap_or_default();
+    let (parts, body) = request.into_parts();
+    let body = match buffer_body(body, DEFAULT_MAX_BODY_SIZE).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+    let headers = forwarded_headers(&parts.headers);
+
+    let (reason, primary_response) = match health.circuit_open() {
+        true => ("unhealthy", None),
+        false => {
+            let mut response = next
+                .run(Request::from_parts(parts, Body::from(body.clone())))
+                .await;
+            if !is_retryable(response.status()) {
+                tag(&mut response, &info.model_id);
+                return response;
+            }
+            ("error", Some(response))
+        }
+    };
+
+    match fallback.forward(&path, headers, body).await {
+        Ok(mut response) => {
+            metrics::increment_counter!("tgi_request_fallback", "reason" => reason, "outcome" => "success");
+            tracing::warn!("Request served by the fallback model {}", fallback.model_id);
+            tag(&mut response, &fallback.model_id);
+            response
+        }
+        Err(err) => {
+            metrics::increment_counter!("tgi_request_fallback", "reason" => reason, "outcome" => "failure");
+            tracing::error!("Fallback model request failed: {err}");
+            let mut response = primary_response.unwrap_or_else(|| {
+                metrics::increment_counter!("tgi_request_failure", "err" => "upstream_unhealthy");
+                <(StatusCode, Json<ErrorResponse>)>::from(InferError::UpstreamUnhealthy)
+                    .into_response()
+            });
+            tag(&mut response, &info.model_id);
+            response
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fallback() {
+        let fallback = Fallback::new(
+            "http://fallback:8080/".to_string(),
+            "mistralai/Mistral-7B-Instruct-v0.2".to_string(),
+        );
+        assert_eq!(fallback.endpoint, "http://fallback:8080");
+
+        assert!(is_retryable(StatusCode::FAILED_DEPENDENCY));
+        assert!(is_retryable(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!is_retryable(StatusCode::OK));
+        assert!(!is_retryable(StatusCode::UNPROCESSABLE_ENTITY));
+        assert!(!is_retryable(StatusCode::TOO_MANY_REQUESTS));
+
+        let mut response = StatusCode::OK.into_response();
+        tag(&mut response, &fallback.model_id);
+        assert_eq!(
+            response.headers()[SERVED_MODEL_HEADER],
+            "mistralai/Mistral-7B-Instruct-v0.2"
+        );
+    }
+}
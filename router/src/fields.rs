@@ -0,0 +1,153 @@
+/// Server-side pruning of the JSON responses to the fields requested by the client
+use axum::body::{Bytes, Full, HttpBody};
+use axum::extract::Query;
+use axum::http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/// Header listing the fields to keep, e.g. `generated_text,details.finish_reason`
+const FIELDS_HEADER: &str = "x-fields";
+
+#[derive(Deserialize)]
+struct FieldsQuery {
+    fields: Option<String>,
+}
+
+/// Middleware keeping only the fields listed in the `x-fields` header or the `fields` query
+/// parameter in the successful JSON responses. Nested fields are selected with dots, and a path
+/// going through an array applies to each of its items.
+/// Streams and errors are left untouched.
+pub(crate) async fn filter_fields<B>(request: Request<B>, next: Next<B>) -> Response {
+    let fields = request
+        .headers()
+        .get(FIELDS_HEADER)
+        .and_then(|fields| fields.to_str().ok())
+        .map(String::from)
+        .or_else(|| {
+            Query::<FieldsQuery>::try_from_uri(request.uri())
+                .ok()
+                .and_then(|query| query.0.fields)
+        });
+    let Some(fields) = fields else {
+        return next.run(request).await;
+    };
+    let paths = parse_fields(&fields);
+    if paths.is_empty() {
+        return next.run(request).await;
+    }
+
+    let response = next.run(request).await;
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .map(|content_type| content_type.as_bytes().starts_with(b"application/json"))
+        .unwrap_or(false);
+    if !response.status().is_success() || !is_json {
+        return response;
+    }
+
+    let (mut parts, mut body) = response.into_parts();
+    let mut content = Vec::new();
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) => content.extend_from_slice(&chunk),
+            Err(_) => break,
+        }
+    }
+    let content = match serde_json::from_slice::<Value>(&content) {
+        Ok(value) => serde_json::to_vec(&select(value, &paths)).unwrap_or(content),
+        Err(_) => content,
+    };
+    parts.headers.remove(CONTENT_LENGTH);
+    Response::from_parts(parts, axum::body::boxed(Full::from(Bytes::from(content))))
+}
+
+/// Split the comma separated fields into their dotted paths
+fn parse_fields(fields: &str) -> Vec<Vec<&str>> {
+    fields
+        .split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .map(|field| field.split('.').collect())
+        .collect()
+}
+
+/// Keep the fields of `value` selected by one of the `paths`
+fn select(value: Value, paths: &[Vec<&str>]) -> Value {
+    // A path ending here selects the whole value
+    if paths.iter().any(|path| path.is_empty()) {
+        return value;
+    }
+    match value {
+        Value::Array(items) => {
+            Value::Array(items.into_iter().map(|item| select(item, paths)).collect())
+        }
+        Value::Object(object) => {
+            let mut selected = Map::new();
+            for (key, value) in object {
+                let nested: Vec<Vec<&str>> = paths
+                    .iter()
+                    .filter(|path| path[0] == key)
+                    .map(|path| path[1..].to_vec())
+                    .collect();
+                if !nested.is_empty() {
+                    selected.insert(key, select(value, &nested));
+                }
+            }
+            Value::Object(selected)
+        }
+        // Scalars have no fields to select
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_select_fields() {
+        let response = json!({
+            "generated_text": "Deep Learning is",
+            "details": {
+                "finish_reason": "length",
+                "generated_tokens": 3,
+                "tokens": [
+                    {"id": 1, "text": "Deep", "logprob": -0.1},
+                    {"id": 2, "text": " Learning", "logprob": -0.2}
+                ]
+            }
+        });
+
+        let paths = parse_fields("generated_text, details.finish_reason,");
+        assert_eq!(
+            select(response.clone(), &paths),
+            json!({"generated_text": "Deep Learning is", "details": {"finish_reason": "length"}})
+        );
+
+        // Paths going through arrays apply to each item
+        let paths = parse_fields("details.tokens.text");
+        assert_eq!(
+            select(response.clone(), &paths),
+            json!({"details": {"tokens": [{"text": "Deep"}, {"text": " Learning"}]}})
+        );
+
+        // A parent path keeps all its children
+        let paths = parse_fields("details.tokens.id,details");
+        assert_eq!(
+            select(response.clone(), &paths),
+            json!({"details": response["details"]})
+        );
+
+        // Top-level arrays, e.g. the compat route with best_of
+        let paths = parse_fields("generated_text,unknown");
+        assert_eq!(
+            select(json!([response.clone(), response]), &paths),
+            json!([{"generated_text": "Deep Learning is"}, {"generated_text": "Deep Learning is"}])
+        );
+    }
+}
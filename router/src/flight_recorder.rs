@@ -0,0 +1,160 @@
+/// Flight recorder of the scheduling decisions of the queue: which entries were batched, why the
+/// others waited and the budgets of each decision, to debug the queue times without verbose logs
+use crate::queue::Entry;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
+
+/// Bounded buffer of the last scheduling decisions
+#[derive(Clone, Debug)]
+pub(crate) struct FlightRecorder {
+    capacity: usize,
+    decisions: Arc<Mutex<VecDeque<SchedulingDecision>>>,
+}
+
+impl FlightRecorder {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            decisions: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    /// Record a decision, dropping the oldest one once full
+    pub(crate) fn record(&self, decision: SchedulingDecision) {
+        let mut decisions = self.decisions.lock().unwrap();
+        if decisions.len() == self.capacity {
+            decisions.pop_front();
+        }
+        decisions.push_back(decision);
+    }
+
+    /// Recorded decisions, newest first
+    pub(crate) fn decisions(&self) -> Vec<SchedulingDecision> {
+        self.decisions
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DecisionOutcome {
+    /// A new batch was created
+    Batched,
+    /// Fewer queued entries than the minimum batch size
+    NotEnoughEntries,
+    /// The entries fitting in the budgets were fewer than the minimum batch size
+    BatchTooSmall,
+    /// No entry fit in the budgets, or all of them were dropped by their clients
+    NoEntry,
+}
+
+/// Why the entries left in the queue were not batched
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum WaitReason {
+    /// The next entry exceeded the prefill token budget
+    PrefillTokenBudget,
+    /// The next entry exceeded the total token budget
+    TokenBudget,
+    /// The batch reached its maximum size
+    MaxBatchSize,
+}
+
+/// Queue entry at the time of a decision
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct DecisionEntry {
+    #[schema(example = 42)]
+    pub id: u64,
+    #[schema(example = 512)]
+    pub input_length: u32,
+    #[schema(example = 256)]
+    pub max_new_tokens: u32,
+    /// Time spent in the queue, in milliseconds
+    #[schema(example = 4000)]
+    pub queue_time_ms: u64,
+}
+
+impl DecisionEntry {
+    pub(crate) fn new(id: u64, entry: &Entry) -> Self {
+        Self {
+            id,
+            input_length: entry.request.input_length,
+            max_new_tokens: entry.request.stopping_parameters.max_new_tokens,
+            queue_time_ms: entry.queue_time.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+/// Scheduling decision, as listed by `/admin/flight_recorder`
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct SchedulingDecision {
+    /// Unix timestamp, in milliseconds
+    #[schema(example = 1706000000000_u64)]
+    pub timestamp: u64,
+    pub outcome: DecisionOutcome,
+    /// Number of queued entries before the decision
+    #[schema(example = 3)]
+    pub queued: usize,
+    /// Budgets requested by the batching task
+    #[schema(nullable = true, example = 2)]
+    pub min_size: Option<usize>,
+    #[schema(nullable = true, example = "null")]
+    pub max_size: Option<usize>,
+    #[schema(example = 4096)]
+    pub prefill_token_budget: u32,
+    #[schema(example = 16000)]
+    pub token_budget: u32,
+    /// Id of the created batch
+    #[schema(nullable = true, example = 7)]
+    pub batch_id: Option<u64>,
+    /// Entries added to the batch, or put back in the queue when it was too small
+    pub batched: Vec<DecisionEntry>,
+    /// Prefill and decode tokens counted against the budgets, padding and the entry exceeding
+    /// them included
+    #[schema(example = 1024)]
+    pub prefill_tokens: u32,
+    #[schema(example = 512)]
+    pub decode_tokens: u32,
+    pub wait_reason: Option<WaitReason>,
+    /// First entry left in the queue
+    pub next_entry: Option<DecisionEntry>,
+}
+
+impl SchedulingDecision {
+    pub(crate) fn new(
+        outcome: DecisionOutcome,
+        queued: usize,
+        min_size: Option<usize>,
+        max_size: Option<usize>,
+        prefill_token_budget: u32,
+        token_budget: u32,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        Self {
+            timestamp,
+            outcome,
+            queued,
+            min_size,
+            max_size,
+            prefill_token_budget,
+            token_budget,
+            batch_id: None,
+            batched: Vec::new(),
+            prefill_tokens: 0,
+            decode_tokens: 0,
+            wait_reason: None,
+            next_entry: None,
+        }
+    }
+}
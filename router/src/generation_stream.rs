@@ -0,0 +1,273 @@
+/// State machine of a streamed generation, from the responses of the batching task to the events
+/// rendered by the streaming handlers
+use crate::chaos::Chaos;
+use crate::infer::{InferError, InferStreamResponse};
+use crate::{NormalizationReport, Token};
+use futures::{Stream, StreamExt};
+use std::time::Instant;
+use text_generation_client::GeneratedText;
+use tokio::sync::OwnedSemaphorePermit;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum StreamState {
+    /// Waiting for the first response of the batching task
+    Queued,
+    /// The prompt was prefilled
+    Prefill,
+    /// Tokens are being generated
+    Decoding,
+    /// The last token was generated
+    Finished,
+    /// The generation failed, was aborted or ended without its last token
+    Failed,
+}
+
+/// Event of a streamed generation
+#[derive(Debug)]
+pub(crate) enum StreamEvent {
+    /// A generated token, before the last one
+    Token {
+        index: u32,
+        token: Token,
+        top_tokens: Vec<Token>,
+    },
+    /// The last token, with the generated text
+    End {
+        index: u32,
+        token: Token,
+        top_tokens: Vec<Token>,
+        generated_text: GeneratedText,
+        start: Instant,
+        queued: Instant,
+        generations: u32,
+        normalization: Option<NormalizationReport>,
+    },
+    /// The generation failed: no other event follows
+    Error(InferError),
+}
+
+/// Streamed generation. The concurrency permit of the request is released as soon as the
+/// generation is finished or failed, or when the stream is dropped by a disconnected client
+pub(crate) struct GenerationStream<S> {
+    state: StreamState,
+    responses: S,
+    permit: Option<OwnedSemaphorePermit>,
+    /// Number of responses received
+    index: u32,
+    /// Faults injected by the chaos mode, if enabled
+    chaos: Option<Chaos>,
+}
+
+impl<S> GenerationStream<S>
+where
+    S: Stream<Item = Result<InferStreamResponse, InferError>> + Unpin,
+{
+    pub(crate) fn new(permit: OwnedSemaphorePermit, responses: S, chaos: Option<Chaos>) -> Self {
+        Self {
+            state: StreamState::Queued,
+            responses,
+            permit: Some(permit),
+            index: 0,
+            chaos,
+        }
+    }
+
+    pub(crate) fn state(&self) -> StreamState {
+        self.state
+    }
+
+    /// Next event of the generation, `None` once it is finished or failed
+    pub(crate) async fn next(&mut self) -> Option<StreamEvent> {
+        while !matches!(self.state, StreamState::Finished | StreamState::Failed) {
+            let Some(response) = self.responses.next().await else {
+                let err = InferError::IncompleteGeneration;
+                metrics::increment_counter!("tgi_request_failure", "err" => "incomplete");
+                tracing::error!("{err}");
+                return Some(self.fail(err));
+            };
+            self.index += 1;
+            match response {
+                Ok(InferStreamResponse::Prefill { .. }) => self.state = StreamState::Prefill,
+                Ok(InferStreamResponse::Intermediate { token, top_tokens }) => {
+                    self.state = StreamState::Decoding;
+                    // Chaos mode: the token is lost, leaving a gap in the indices
+                    if self.chaos.as_ref().is_some_and(Chaos::drop_chunk) {
+                        continue;
+                    }
+                    return Some(StreamEvent::Token {
+                        index: self.index,
+                        token,
+                        top_tokens,
+                    });
+                }
+                Ok(InferStreamResponse::End {
+                    token,
+                    top_tokens,
+                    generated_text,
+                    start,
+                    queued,
+                    generations,
+                    normalization,
+                    ..
+                }) => {
+                    self.release(StreamState::Finished);
+                    return Some(StreamEvent::End {
+                        index: self.index,
+                        token,
+                        top_tokens,
+                        generated_text,
+                        start,
+                        queued,
+                        generations,
+                        normalization,
+                    });
+                }
+                Err(err) => return Some(self.fail(err)),
+            }
+        }
+        None
+    }
+
+    fn fail(&mut self, err: InferError) -> StreamEvent {
+        self.release(StreamState::Failed);
+        StreamEvent::Error(err)
+    }
+
+    /// Enter a final state and give the permit back to the other requests
+    fn release(&mut self, state: StreamState) {
+        self.state = state;
+        self.permit = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use std::sync::Arc;
+    use tokio::sync::Semaphore;
+
+    fn token(id: u32) -> Token {
+        Token {
+            id,
+            text: format!("t{id}"),
+            logprob: -0.1,
+            special: false,
+        }
+    }
+
+    fn intermediate(id: u32) -> Result<InferStreamResponse, InferError> {
+        Ok(InferStreamResponse::Intermediate {
+            token: token(id),
+            top_tokens: Vec::new(),
+        })
+    }
+
+    fn end(id: u32) -> Result<InferStreamResponse, InferError> {
+        Ok(InferStreamResponse::End {
+            token: token(id),
+            top_tokens: Vec::new(),
+            generated_text: GeneratedText {
+                text: "t1t2".to_string(),
+                generated_tokens: 2,
+                finish_reason: 0,
+                seed: None,
+            },
+            start: Instant::now(),
+            queued: Instant::now(),
+            batching_cycles: 0,
+            batch_id: 0,
+            generations: 2,
+            normalization: None,
+        })
+    }
+
+    fn start<S>(responses: S) -> (Arc<Semaphore>, GenerationStream<S>)
+    where
+        S: Stream<Item = Result<InferStreamResponse, InferError>> + Unpin,
+    {
+        let semaphore = Arc::new(Semaphore::new(1));
+        let permit = semaphore.clone().try_acquire_owned().unwrap();
+        (semaphore, GenerationStream::new(permit, responses, None))
+    }
+
+    #[tokio::test]
+    async fn test_generation_stream_finished() {
+        let (semaphore, mut generation) =
+            start(stream::iter(vec![intermediate(1), end(2), intermediate(3)]));
+        assert_eq!(generation.state(), StreamState::Queued);
+
+        let Some(StreamEvent::Token {
+            index: 1, token, ..
+        }) = generation.next().await
+        else {
+            panic!("expected a token");
+        };
+        assert_eq!(token.id, 1);
+        assert_eq!(generation.state(), StreamState::Decoding);
+        assert_eq!(semaphore.available_permits(), 0);
+
+        let Some(StreamEvent::End {
+            index: 2,
+            generated_text,
+            ..
+        }) = generation.next().await
+        else {
+            panic!("expected the last token");
+        };
+        assert_eq!(generated_text.text, "t1t2");
+        assert_eq!(generation.state(), StreamState::Finished);
+        // The permit is released before the client reads the last event
+        assert_eq!(semaphore.available_permits(), 1);
+
+        // Nothing follows the last token
+        assert!(generation.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_generation_stream_failed() {
+        // Shard error
+        let (semaphore, mut generation) = start(stream::iter(vec![
+            intermediate(1),
+            Err(InferError::GenerationError("CUDA OOM".to_string())),
+        ]));
+        assert!(matches!(
+            generation.next().await,
+            Some(StreamEvent::Token { .. })
+        ));
+        assert!(matches!(
+            generation.next().await,
+            Some(StreamEvent::Error(InferError::GenerationError(_)))
+        ));
+        assert_eq!(generation.state(), StreamState::Failed);
+        assert_eq!(semaphore.available_permits(), 1);
+        assert!(generation.next().await.is_none());
+
+        // Deadline of the upstream gateway reached while queued
+        let (_, mut generation) = start(stream::iter(vec![Err(InferError::DeadlineExceeded)]));
+        assert!(matches!(
+            generation.next().await,
+            Some(StreamEvent::Error(InferError::DeadlineExceeded))
+        ));
+
+        // Responses ending without the last token
+        let (semaphore, mut generation) = start(stream::iter(vec![intermediate(1)]));
+        generation.next().await;
+        assert!(matches!(
+            generation.next().await,
+            Some(StreamEvent::Error(InferError::IncompleteGeneration))
+        ));
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_generation_stream_cancelled() {
+        // The client disconnects while the tokens are generated
+        let (semaphore, mut generation) =
+            start(stream::iter(vec![intermediate(1)]).chain(stream::pending()));
+        generation.next().await;
+        assert_eq!(semaphore.available_permits(), 0);
+        drop(generation);
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+}
@@ -1,5 +1,9 @@
+use crate::circuit_breaker::{CircuitBreaker, CircuitState};
+use crate::ShardStatus;
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 use text_generation_client::GrammarType as ProtoGrammarType;
 use text_generation_client::{
     Batch, NextTokenChooserParameters, Request, ShardedClient, StoppingCriteriaParameters,
@@ -13,16 +17,78 @@ const BATCH_ID: u64 = u64::MAX;
 pub(crate) struct Health {
     client: ShardedClient,
     generation_health: Arc<AtomicBool>,
+    /// Last start time and number of restarts of each shard
+    shard_starts: Arc<Mutex<HashMap<usize, (u64, u32)>>>,
+    circuit_breaker: Option<CircuitBreaker>,
 }
 
 impl Health {
-    pub(crate) fn new(client: ShardedClient, generation_health: Arc<AtomicBool>) -> Self {
+    pub(crate) fn new(
+        client: ShardedClient,
+        generation_health: Arc<AtomicBool>,
+        circuit_breaker: Option<CircuitBreaker>,
+    ) -> Self {
         Self {
             client,
             generation_health,
+            shard_starts: Arc::new(Mutex::new(HashMap::new())),
+            circuit_breaker,
         }
     }
 
+    /// Whether the circuit breaker is rejecting requests
+    pub(crate) fn circuit_open(&self) -> bool {
+        self.circuit_breaker
+            .as_ref()
+            .map(|circuit_breaker| circuit_breaker.state() == CircuitState::Open)
+            .unwrap_or(false)
+    }
+
+    /// Status of each shard. Restarts are detected when the start time reported by a shard
+    /// changes between two calls.
+    pub(crate) async fn shards(&mut self) -> Vec<ShardStatus> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs())
+            .unwrap_or(0);
+        let responses = self.client.shards_health().await;
+
+        let mut shard_starts = self.shard_starts.lock().unwrap();
+        responses
+            .into_iter()
+            .enumerate()
+            .map(|(index, response)| match response {
+                Ok(response) => {
+                    let restarts = match shard_starts.get(&index) {
+                        Some((start_time, restarts)) if *start_time != response.start_time => {
+                            restarts + 1
+                        }
+                        Some((_, restarts)) => *restarts,
+                        None => 0,
+                    };
+                    shard_starts.insert(index, (response.start_time, restarts));
+                    ShardStatus {
+                        rank: response.rank,
+                        healthy: true,
+                        last_error: response.last_error,
+                        restarts,
+                        uptime_secs: Some(now.saturating_sub(response.start_time)),
+                    }
+                }
+                Err(err) => ShardStatus {
+                    rank: index as u32,
+                    healthy: false,
+                    last_error: Some(err.to_string()),
+                    restarts: shard_starts
+                        .get(&index)
+                        .map(|(_, restarts)| *restarts)
+                        .unwrap_or(0),
+                    uptime_secs: None,
+                },
+            })
+            .collect()
+    }
+
     pub(crate) async fn check(&mut self) -> bool {
         if self.generation_health.load(Ordering::SeqCst) {
             // Generation is healthy, we only check that the shards are answering gRPC calls
@@ -48,13 +114,25 @@ impl Health {
                     watermark: false,
                     grammar: String::new(),
                     grammar_type: ProtoGrammarType::None as i32,
+                    suppressed_tokens: vec![],
+                    temperature_schedule: vec![],
+                    logit_bias: HashMap::new(),
                 }),
                 stopping_parameters: Some(StoppingCriteriaParameters {
                     max_new_tokens: 1,
                     stop_sequences: vec![],
                     ignore_eos_token: false,
+                    stop_after_tool_call: false,
+                    deadline: None,
+                    eos_probability_threshold: None,
+                    eos_probability_window: 0,
                 }),
                 top_n_tokens: 0,
+                skip_special_tokens: true,
+                extensions: HashMap::new(),
+                prefill_group: 0,
+                adapter_id: String::new(),
+                adapter_parameters: None,
             };
             let batch = Batch {
                 id: BATCH_ID,
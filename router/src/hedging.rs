@@ -0,0 +1,239 @@
+/// Queue-aware request hedging to a secondary deployment
+use crate::infer::Infer;
+use crate::route_limits::{buffer_body, DEFAULT_MAX_BODY_SIZE};
+use crate::tenant::TENANT_HEADER;
+use axum::body::{Body, Bytes};
+use axum::extract::Extension;
+use axum::http::header::{ACCEPT_LANGUAGE, AUTHORIZATION, CONTENT_TYPE};
+use axum::http::{HeaderMap, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Time the secondary deployment has to answer a hedged request
+const HEDGING_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Headers of a request sent along to another deployment: its credentials, tenant and language.
+/// The other headers are specific to the connection with the router
+pub(crate) fn forwarded_headers(headers: &HeaderMap) -> HeaderMap {
+    [
+        AUTHORIZATION.as_str(),
+        TENANT_HEADER,
+        ACCEPT_LANGUAGE.as_str(),
+    ]
+    .into_iter()
+    .flat_map(|name| {
+        headers
+            .get_all(name)
+            .into_iter()
+            .map(move |value| (name.parse().unwrap(), value.clone()))
+    })
+    .collect()
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Hedging {
+    /// HTTP client used to reach the secondary deployment
+    client: reqwest::Client,
+    /// Base url of the secondary deployment
+    endpoint: String,
+    /// Requests expected to spend more than this time in the queue are hedged
+    latency_slo: Duration,
+    /// Limit the number of in-flight hedged requests
+    in_flight: Arc<Semaphore>,
+    /// Maximum ratio of hedged requests
+    max_ratio: f64,
+    /// Number of requests seen
+    requests: Arc<AtomicU64>,
+    /// Number of hedged requests
+    hedged: Arc<AtomicU64>,
+}
+
+impl Hedging {
+    pub(crate) fn new(
+        endpoint: String,
+        latency_slo: Duration,
+        max_concurrent: usize,
+        max_ratio: f32,
+    ) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            latency_slo,
+            in_flight: Arc::new(Semaphore::new(max_concurrent)),
+            max_ratio: max_ratio as f64,
+            requests: Arc::new(AtomicU64::new(0)),
+            hedged: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Acquire the hedging budget if a request is expected to exceed the latency SLO
+    fn acquire(&self, estimated_queue_time: Duration) -> Option<OwnedSemaphorePermit> {
+        let requests = self.requests.fetch_add(1, Ordering::SeqCst) + 1;
+        if estimated_queue_time <= self.latency_slo {
+            return None;
+        }
+
+        let permit = self.in_flight.clone().try_acquire_owned().ok()?;
+        // Never hedge more than `max_ratio` of the requests
+        self.hedged
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |hedged| {
+                ((hedged + 1) as f64 <= self.max_ratio * requests as f64).then_some(hedged + 1)
+            })
+            .ok()?;
+        Some(permit)
+    }
+
+    /// Send the request body to the same route of the secondary deployment
+    async fn forward(
+        &self,
+        path: &str,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Result<Response, reqwest::Error> {
+        let response = self
+            .client
+            .post(format!("{}{path}", self.endpoint))
+            .headers(headers)
+            .header(CONTENT_TYPE, "application/json")
+            .timeout(HEDGING_TIMEOUT)
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let status = response.status();
+        let content_type = response.headers().get(CONTENT_TYPE).cloned();
+        let body = response.bytes().await?;
+
+        let mut response = (status, body).into_response();
+        if let Some(content_type) = content_type {
+            response.headers_mut().insert(CONTENT_TYPE, content_type);
+        }
+        Ok(response)
+    }
+}
+
+/// Whether the secondary deployment may still answer a request failing locally, e.g. when the
+/// local queue is full
+fn retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Response of the secondary deployment, winning the race
+fn secondary_response(mut response: Response) -> Response {
+    metrics::increment_counter!("tgi_request_hedged", "winner" => "secondary");
+    response
+        .headers_mut()
+        .insert("x-hedged", HeaderValue::from_static("secondary"));
+    response
+}
+
+/// Middleware racing the local inference against the secondary deployment for requests that
+/// are expected to exceed the latency SLO.
+/// The first response wins and the other request is cancelled, unless the local request fails
+/// with a retryable error: the secondary response is then awaited, the local error only being
+/// returned if both fail.
+pub(crate) async fn hedge(
+    Extension(hedging): Extension<Hedging>,
+    Extension(infer): Extension<Infer>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let _permit = match hedging.acquire(infer.estimated_queue_time()) {
+        Some(permit) => permit,
+        None => return next.run(request).await,
+    };
+
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|path| path.to_string())
+        .unwrap_or_default();
+    let (parts, body) = request.into_parts();
+    let body = match buffer_body(body, DEFAULT_MAX_BODY_SIZE).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+
+    let headers = forwarded_headers(&parts.headers);
+    let local = next.run(Request::from_parts(parts, Body::from(body.clone())));
+    let secondary = hedging.forward(&path, headers, body);
+    tokio::pin!(local, secondary);
+
+    // Dropping the losing future cancels it.
+    // If the secondary deployment fails, we keep waiting for the local response.
+    let response = tokio::select! {
+        response = &mut local => {
+            if retryable(response.status()) {
+                if let Ok(secondary) = secondary.await {
+                    return secondary_response(secondary);
+                }
+            }
+            response
+        }
+        result = &mut secondary => match result {
+            Ok(response) => return secondary_response(response),
+            Err(_) => local.await,
+        },
+    };
+    metrics::increment_counter!("tgi_request_hedged", "winner" => "local");
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hedging_budget() {
+        let hedging = Hedging::new(
+            "http://localhost:8080/".to_string(),
+            Duration::from_millis(100),
+            1,
+            0.5,
+        );
+        assert_eq!(hedging.endpoint, "http://localhost:8080");
+
+        // Under the SLO
+        assert!(hedging.acquire(Duration::from_millis(10)).is_none());
+
+        let permit = hedging.acquire(Duration::from_secs(1));
+        assert!(permit.is_some());
+        // No in-flight budget left
+        assert!(hedging.acquire(Duration::from_secs(1)).is_none());
+        drop(permit);
+
+        // 2 hedged requests out of 4
+        assert!(hedging.acquire(Duration::from_secs(1)).is_some());
+        // 3 hedged requests out of 5 would exceed the ratio
+        assert!(hedging.acquire(Duration::from_secs(1)).is_none());
+        // 3 hedged requests out of 6
+        assert!(hedging.acquire(Duration::from_secs(1)).is_some());
+    }
+
+    #[test]
+    fn test_retryable() {
+        assert!(retryable(StatusCode::TOO_MANY_REQUESTS));
+        assert!(retryable(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!retryable(StatusCode::OK));
+        assert!(!retryable(StatusCode::UNPROCESSABLE_ENTITY));
+    }
+
+    #[test]
+    fn test_forwarded_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, "Bearer key".parse().unwrap());
+        headers.insert(TENANT_HEADER, "acme".parse().unwrap());
+        headers.insert("cookie", "session=secret".parse().unwrap());
+        headers.insert("host", "router:3000".parse().unwrap());
+
+        let forwarded = forwarded_headers(&headers);
+        assert_eq!(forwarded.len(), 2);
+        assert_eq!(forwarded[AUTHORIZATION], "Bearer key");
+        assert_eq!(forwarded[TENANT_HEADER], "acme");
+    }
+}
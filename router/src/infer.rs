@@ -1,22 +1,52 @@
 /// Batching and inference logic
+use crate::abort::{self, Aborts, Interruption};
+use crate::alerts::{AlertKind, Alerts};
+use crate::attention_window;
+use crate::baggage;
+use crate::batch_files;
+use crate::chaos::Chaos;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::coalescing::Coalescer;
+use crate::deadline;
+use crate::detokenizer::IncrementalDetokenizer;
+use crate::exemplars::Exemplars;
+use crate::flight_recorder::FlightRecorder;
+use crate::object_store::ObjectStoreError;
+use crate::pii::{PiiCategory, PiiScanner, StreamMasker};
+use crate::prefill_group;
+use crate::presets::Presets;
+use crate::pricing::Pricing;
+use crate::rate_limit;
+use crate::slo::Slo;
+use crate::sticky;
+use crate::stream_transforms::{StreamTransformer, StreamTransforms};
+use crate::tenant;
 use crate::validation::{Validation, ValidationError};
+use crate::waiting_room;
+use crate::warnings;
 use crate::{
-    ChatTemplateInputs, Entry, GenerateRequest, GenerateStreamResponse, HubTokenizerConfig,
-    Message, PrefillToken, Queue, Token,
+    ChatTemplateInputs, Entry, FinishReason, GenerateParameters, GenerateRequest,
+    GenerateStreamResponse, HubTokenizerConfig, Info, Message, NormalizationReport, PrefillToken,
+    Queue, Retokenization, SafetyReport, Speculation, Token,
 };
 use futures::future::try_join_all;
-use minijinja::{Environment, ErrorKind, Template};
+use minijinja::{Environment, ErrorKind};
 use nohash_hasher::IntMap;
+use opentelemetry::trace::FutureExt;
+use rand::{thread_rng, Rng};
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+    atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    Arc, RwLock,
 };
+use std::time::Duration;
 use text_generation_client::{
-    Batch, CachedBatch, ClientError, GeneratedText, Generation, ShardedClient, Tokens,
+    Batch, CachedBatch, ClientError, FinishReason as ProtoFinishReason, GeneratedText, Generation,
+    GrammarType as ProtoGrammarType, ShardedClient, Tokens,
 };
 use thiserror::Error;
+use tokenizers::Tokenizer;
 use tokio::sync::mpsc::error::SendError;
-use tokio::sync::{mpsc, Notify, Semaphore, TryAcquireError};
+use tokio::sync::{mpsc, Notify, OwnedSemaphorePermit, Semaphore, TryAcquireError};
 use tokio::time::Instant;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use tokio_stream::StreamExt;
@@ -31,16 +61,127 @@ pub struct Infer {
     queue: Queue,
     /// Shared state
     shared: Arc<Shared>,
-    /// Chat template
-    chat_template: Option<ChatTemplate>,
+    /// Chat template, swapped on tokenizer reloads
+    chat_template: Arc<RwLock<Option<ChatTemplate>>>,
+    /// Number of tokenizer reloads
+    tokenizer_reloads: Arc<AtomicU64>,
     /// Inference limit
     limit_concurrent_requests: Arc<Semaphore>,
+    /// Large prefill shedding under memory pressure
+    prefill_shedding: Option<PrefillShedding>,
+    /// Fail fast while the shards are failing
+    circuit_breaker: Option<CircuitBreaker>,
+    /// Reject the requests estimated to complete after this bound
+    completion_time_slo: Option<Duration>,
+    /// Re-tokenize the generated texts to check their consistency with the generated tokens
+    retokenization_check: bool,
+    /// Number of speculative tokens proposed at each forward pass
+    speculate: u32,
+    /// Speculator proposing the speculative tokens
+    speculator: Option<String>,
+    /// Sliding attention window of the model, in tokens
+    window_size: Option<u32>,
+    /// Whether the shards prefill the `best_of` candidates once
+    shared_prefill: bool,
+    /// Maximum number of times a generation ending without its last token is retried
+    incomplete_generation_retries: usize,
+    /// Scan of the generated texts for personally identifiable information
+    pii_scanner: Option<PiiScanner>,
+    /// Replacements of the generated texts, applied before the scan
+    stream_transforms: Option<StreamTransforms>,
+    /// Exemplars of the latency histograms, if enabled
+    exemplars: Option<Exemplars>,
+    /// Token prices the cost of the requests is estimated from, if configured
+    pricing: Option<Pricing>,
+    /// Named presets of decoding parameters
+    presets: Presets,
+    /// SLOs tracked over rolling windows, if configured
+    slo: Option<Arc<Slo>>,
+    /// Generate the identical deterministic requests arriving concurrently once
+    coalescer: Option<Coalescer>,
+}
+
+/// Interval between two shard memory pressure polls
+const MEMORY_PRESSURE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reject large prefills when the shards report memory pressure
+#[derive(Clone)]
+struct PrefillShedding {
+    /// Last memory pressure reported by the shards (f32 bits)
+    memory_pressure: Arc<AtomicU32>,
+    /// Memory pressure above which large prefills are rejected
+    threshold: f32,
+    /// Maximum number of input tokens admitted above the threshold
+    max_prefill_tokens: u32,
+}
+
+impl PrefillShedding {
+    fn should_shed(&self, input_length: u32) -> bool {
+        let memory_pressure = f32::from_bits(self.memory_pressure.load(Ordering::SeqCst));
+        memory_pressure > self.threshold && input_length > self.max_prefill_tokens
+    }
 }
 
 /// Infer shared state
 struct Shared {
     /// Batching background Tokio task notifier
     batching_task: Notify,
+    /// Decoding statistics updated by the batching task
+    decode_stats: DecodeStats,
+    /// Set once the shutdown grace period elapsed: the requests are aborted
+    shutdown: AtomicBool,
+    /// Webhook paging the operators on serious errors
+    alerts: Option<Alerts>,
+    /// Response channels of the in-flight requests, by request id
+    aborts: Aborts,
+    /// Faults injected by the chaos mode, if enabled
+    chaos: Option<Chaos>,
+}
+
+/// Decoding statistics used to estimate the completion time of new requests
+#[derive(Debug, Default)]
+struct DecodeStats {
+    /// Exponential moving average of the time per generated token of the last finished requests
+    /// (in microseconds)
+    time_per_token: AtomicU64,
+}
+
+impl DecodeStats {
+    fn update(&self, inference_time: Duration, generated_tokens: u32) {
+        if generated_tokens == 0 {
+            return;
+        }
+        let sample = (inference_time / generated_tokens).as_micros() as u64;
+        // Only updated by the batching task
+        let time_per_token = match self.time_per_token.load(Ordering::Relaxed) {
+            0 => sample,
+            time_per_token => (time_per_token * 7 + sample) / 8,
+        };
+        self.time_per_token.store(time_per_token, Ordering::Relaxed);
+    }
+
+    /// Time to generate `max_new_tokens` at the recent time per token, unknown until a request
+    /// finished
+    fn estimated_decode_time(&self, max_new_tokens: u32) -> Option<Duration> {
+        match self.time_per_token.load(Ordering::Relaxed) {
+            0 => None,
+            time_per_token => Some(Duration::from_micros(
+                time_per_token * max_new_tokens as u64,
+            )),
+        }
+    }
+
+    /// Number of tokens generated within `budget` at the recent time per token, at least one.
+    /// Unknown until a request finished
+    fn max_new_tokens(&self, budget: Duration) -> Option<u32> {
+        match self.time_per_token.load(Ordering::Relaxed) {
+            0 => None,
+            time_per_token => {
+                let tokens = budget.as_micros() as u64 / time_per_token;
+                Some(tokens.clamp(1, u32::MAX as u64) as u32)
+            }
+        }
+    }
 }
 
 /// Raise a exception (custom function) used in the chat templates
@@ -62,13 +203,57 @@ impl Infer {
         requires_padding: bool,
         window_size: Option<u32>,
         speculate: u32,
+        speculator: Option<String>,
+        shared_prefill: bool,
         generation_health: Arc<AtomicBool>,
         tokenizer_config: HubTokenizerConfig,
+        memory_pressure_threshold: Option<f32>,
+        memory_pressure_max_prefill_tokens: u32,
+        circuit_breaker: Option<CircuitBreaker>,
+        completion_time_slo: Option<Duration>,
+        retokenization_check: bool,
+        latency_max_waiting_tokens: usize,
+        alerts: Option<Alerts>,
+        incomplete_generation_retries: usize,
+        pii_scanner: Option<PiiScanner>,
+        stream_transforms: Option<StreamTransforms>,
+        chaos: Option<Chaos>,
+        exemplars: Option<Exemplars>,
+        pricing: Option<Pricing>,
+        presets: Presets,
+        slo: Option<Arc<Slo>>,
+        coalesce_requests: bool,
+        flight_recorder: Option<FlightRecorder>,
     ) -> Self {
         // Infer shared state
-        let queue = Queue::new(requires_padding, 16, window_size, speculate);
+        let queue = Queue::new(
+            requires_padding,
+            16,
+            window_size,
+            speculate,
+            flight_recorder,
+        );
         let shared = Arc::new(Shared {
             batching_task: Notify::new(),
+            decode_stats: DecodeStats::default(),
+            shutdown: AtomicBool::new(false),
+            alerts,
+            aborts: Aborts::default(),
+            chaos,
+        });
+
+        // Spawn memory pressure polling background task if shedding is enabled
+        let prefill_shedding = memory_pressure_threshold.map(|threshold| {
+            let memory_pressure = Arc::new(AtomicU32::new(0.0f32.to_bits()));
+            tokio::spawn(memory_pressure_task(
+                client.clone(),
+                memory_pressure.clone(),
+            ));
+            PrefillShedding {
+                memory_pressure,
+                threshold,
+                max_prefill_tokens: memory_pressure_max_prefill_tokens,
+            }
         });
 
         // Spawn batching background task that contains all the inference logic
@@ -78,15 +263,16 @@ impl Infer {
             max_batch_prefill_tokens,
             max_batch_total_tokens,
             max_waiting_tokens,
+            latency_max_waiting_tokens,
             max_batch_size,
             queue.clone(),
             shared.clone(),
             generation_health,
+            circuit_breaker.clone(),
+            window_size,
         ));
 
-        let chat_template = tokenizer_config
-            .chat_template
-            .map(|t| ChatTemplate::new(t, tokenizer_config.bos_token, tokenizer_config.eos_token));
+        let chat_template = ChatTemplate::from_config(tokenizer_config).unwrap();
 
         // Inference limit with a semaphore
         let semaphore = Arc::new(Semaphore::new(max_concurrent_requests));
@@ -95,48 +281,353 @@ impl Infer {
             validation,
             queue,
             shared,
-            chat_template,
+            chat_template: Arc::new(RwLock::new(chat_template)),
+            tokenizer_reloads: Arc::new(AtomicU64::new(0)),
             limit_concurrent_requests: semaphore,
+            prefill_shedding,
+            circuit_breaker,
+            completion_time_slo,
+            retokenization_check,
+            speculate,
+            speculator,
+            window_size,
+            shared_prefill,
+            incomplete_generation_retries,
+            pii_scanner,
+            stream_transforms: stream_transforms.filter(|transforms| !transforms.is_empty()),
+            exemplars,
+            pricing,
+            presets,
+            slo,
+            coalescer: coalesce_requests.then(Coalescer::default),
+        }
+    }
+
+    /// Speculative decoding of a request, if the shards speculate
+    pub(crate) fn speculation(
+        &self,
+        generated_tokens: u32,
+        generations: u32,
+    ) -> Option<Speculation> {
+        if self.speculate == 0 {
+            return None;
         }
+        let speculation = Speculation::new(
+            self.speculator.clone(),
+            self.speculate,
+            generated_tokens,
+            generations,
+        );
+        if speculation.proposed_tokens > 0 {
+            metrics::histogram!(
+                "tgi_request_speculative_acceptance_rate",
+                speculation.acceptance_rate as f64
+            );
+        }
+        Some(speculation)
+    }
+
+    /// Number of prompt tokens that fell outside the attention window of the model during the
+    /// generation, if any
+    pub(crate) fn tokens_outside_window(
+        &self,
+        input_length: u32,
+        generated_tokens: u32,
+    ) -> Option<u32> {
+        attention_window::tokens_outside(self.window_size, input_length, generated_tokens)
+    }
+
+    /// Re-tokenize the generated text and compare it with the ids of the generated (non special)
+    /// tokens, if the check is enabled and we have a fast tokenizer
+    pub(crate) fn retokenization(
+        &self,
+        text: &str,
+        generated_ids: &[u32],
+    ) -> Option<Retokenization> {
+        if !self.retokenization_check {
+            return None;
+        }
+        let encoding = self
+            .validation
+            .tokenizer()?
+            .encode(text, false)
+            .map_err(|err| tracing::warn!("Could not re-tokenize the generated text: {err}"))
+            .ok()?;
+        let retokenization = Retokenization::new(generated_ids, encoding.get_ids());
+        if !retokenization.consistent {
+            metrics::increment_counter!("tgi_request_retokenization_drift");
+            tracing::warn!(
+                "Generated text does not re-tokenize to the generated tokens (first mismatch at token {:?})",
+                retokenization.first_mismatch
+            );
+        }
+        Some(retokenization)
+    }
+
+    /// Faults injected by the chaos mode, if enabled
+    pub(crate) fn chaos(&self) -> Option<Chaos> {
+        self.shared.chaos.clone()
+    }
+
+    /// Exemplars of the latency histograms, if enabled
+    pub(crate) fn exemplars(&self) -> Option<&Exemplars> {
+        self.exemplars.as_ref()
+    }
+
+    /// SLOs tracked over rolling windows, if configured
+    pub(crate) fn slo(&self) -> Option<&Slo> {
+        self.slo.as_deref()
+    }
+
+    /// Token prices, if configured
+    pub(crate) fn pricing(&self) -> Option<Pricing> {
+        self.pricing
+    }
+
+    /// Estimated cost of a request, if prices are configured
+    pub(crate) fn estimate_cost(&self, input_tokens: u32, generated_tokens: u32) -> Option<f64> {
+        Some(self.pricing?.estimate(input_tokens, generated_tokens))
+    }
+
+    /// Transform a generated text, if stream transforms are configured
+    pub(crate) fn transform_output(&self, text: String) -> String {
+        match &self.stream_transforms {
+            Some(stream_transforms) => stream_transforms.apply(text),
+            None => text,
+        }
+    }
+
+    /// Transformer of a streamed generation, if stream transforms are configured
+    pub(crate) fn stream_transformer(&self) -> Option<StreamTransformer> {
+        Some(self.stream_transforms.as_ref()?.transformer())
+    }
+
+    /// Scan the generated text for personally identifiable information, if enabled: the text is
+    /// returned masked or rejected depending on the configured action
+    pub(crate) fn scan_output(
+        &self,
+        text: String,
+    ) -> Result<(String, Option<SafetyReport>), InferError> {
+        let Some(pii_scanner) = &self.pii_scanner else {
+            return Ok((text, None));
+        };
+        match pii_scanner.scan(text) {
+            Ok((text, report)) => Ok((text, Some(report))),
+            Err(pii) => Err(self.pii_blocked(pii)),
+        }
+    }
+
+    /// Scan a whole response: its text as with `scan_output`, its tokens and top tokens masked
+    pub(crate) fn scan_response(
+        &self,
+        response: &mut InferResponse,
+    ) -> Result<Option<SafetyReport>, InferError> {
+        let (text, safety) = self.scan_output(std::mem::take(&mut response.generated_text.text))?;
+        response.generated_text.text = text;
+        if let Some(pii_scanner) = &self.pii_scanner {
+            pii_scanner.mask_tokens(&mut response.tokens, &mut response.top_tokens);
+        }
+        Ok(safety)
+    }
+
+    /// Masker of the streamed tokens, if the personally identifiable information is masked or
+    /// blocked
+    pub(crate) fn pii_masker(&self) -> Option<StreamMasker> {
+        self.pii_scanner.as_ref()?.stream_masker()
+    }
+
+    /// Error of a generation blocked for the personally identifiable information it contains
+    pub(crate) fn pii_blocked(&self, pii: Vec<PiiCategory>) -> InferError {
+        let pii: Vec<&str> = pii.iter().map(|category| category.name()).collect();
+        let err = InferError::PiiBlocked(pii.join(", "));
+        metrics::increment_counter!("tgi_request_failure", "err" => "pii");
+        tracing::warn!("{err}");
+        err
+    }
+
+    /// Apply the preset of the parameters, the parameters set by the request taking precedence
+    pub(crate) fn expand_preset(
+        &self,
+        parameters: &mut GenerateParameters,
+    ) -> Result<(), ValidationError> {
+        self.presets.expand(parameters).map_err(|err| {
+            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            tracing::error!("{err}");
+            err
+        })
     }
 
     /// Add a new request to the queue and return a stream of InferStreamResponse
     #[instrument(skip_all)]
     pub(crate) async fn generate_stream(
+        &self,
+        mut request: GenerateRequest,
+    ) -> Result<GenerateStreamResponse, InferError> {
+        self.expand_preset(&mut request.parameters)?;
+        self.generate_stream_with_retries(request, self.incomplete_generation_retries)
+            .await
+    }
+
+    /// Add a new request to the queue and return a stream of InferStreamResponse. The request is
+    /// queued again, at most `retries` times, when it ends without its last token before any
+    /// token was streamed.
+    async fn generate_stream_with_retries(
         &self,
         request: GenerateRequest,
+        retries: usize,
     ) -> Result<GenerateStreamResponse, InferError> {
-        // Limit concurrent requests by acquiring a permit from the semaphore
-        let permit = self
-            .clone()
-            .limit_concurrent_requests
-            .try_acquire_owned()
-            .map_err(|err| {
-                metrics::increment_counter!("tgi_request_failure", "err" => "overloaded");
+        // Fail fast while the circuit breaker is open
+        if let Some(circuit_breaker) = &self.circuit_breaker {
+            if !circuit_breaker.allow() {
+                let err = InferError::UpstreamUnhealthy;
+                metrics::increment_counter!("tgi_request_failure", "err" => "upstream_unhealthy");
                 tracing::error!("{err}");
-                err
-            })?;
+                return Err(err);
+            }
+        }
+
+        // Do not start new requests once the in-flight ones are aborted
+        if self.shared.shutdown.load(Ordering::SeqCst) {
+            let err = InferError::Shutdown;
+            metrics::increment_counter!("tgi_request_failure", "err" => "shutdown");
+            tracing::error!("{err}");
+            return Err(err);
+        }
+
+        // Fail fast when the deadline set by the upstream gateway already passed
+        if deadline::remaining() == Some(Duration::ZERO) {
+            let err = InferError::DeadlineExceeded;
+            metrics::increment_counter!("tgi_request_failure", "err" => "deadline_exceeded");
+            tracing::error!("{err}");
+            return Err(err);
+        }
+
+        // Limit concurrent requests by acquiring a permit from the semaphore, unless the request
+        // already acquired one in the waiting room
+        let permit = match waiting_room::take_permit() {
+            Some(permit) => permit,
+            // The requests of the batch files wait for a permit instead of failing
+            None if batch_files::is_background() => self.wait_for_capacity().await,
+            None => self
+                .clone()
+                .limit_concurrent_requests
+                .try_acquire_owned()
+                .map_err(|err| {
+                    metrics::increment_counter!("tgi_request_failure", "err" => "overloaded");
+                    tracing::error!("{err}");
+                    err
+                })?,
+        };
 
         // Validate request
-        let valid_request = self.validation.validate(request).await.map_err(|err| {
+        let mut valid_request = self.validation.validate(request).await.map_err(|err| {
             metrics::increment_counter!("tgi_request_failure", "err" => "validation");
             tracing::error!("{err}");
             err
         })?;
 
+        // Cap the generated tokens to the remaining latency budget of the request
+        if let Some(remaining) = deadline::remaining() {
+            let budget = remaining.saturating_sub(self.estimated_queue_time());
+            if let Some(max_new_tokens) = self.shared.decode_stats.max_new_tokens(budget) {
+                let stopping_parameters = &mut valid_request.stopping_parameters;
+                if max_new_tokens < stopping_parameters.max_new_tokens {
+                    tracing::debug!(
+                        "Capping max_new_tokens from {} to {max_new_tokens} to meet the deadline",
+                        stopping_parameters.max_new_tokens
+                    );
+                    warnings::warn(format!(
+                        "`max_new_tokens` was capped from {} to {max_new_tokens} to meet the deadline",
+                        stopping_parameters.max_new_tokens
+                    ));
+                    stopping_parameters.max_new_tokens = max_new_tokens;
+                }
+            }
+        }
+
+        // Reject large prefills while the shards are under memory pressure
+        if let Some(prefill_shedding) = &self.prefill_shedding {
+            if prefill_shedding.should_shed(valid_request.input_length) {
+                let err = InferError::PrefillCapacity(prefill_shedding.max_prefill_tokens);
+                metrics::increment_counter!("tgi_request_failure", "err" => "capacity_prefill");
+                tracing::error!("{err}");
+                return Err(err);
+            }
+        }
+
+        // Reject the requests that would complete after the completion time SLO instead of
+        // discovering it after minutes of generation
+        if let Some(completion_time_slo) = self.completion_time_slo {
+            let estimate =
+                self.estimated_completion_time(valid_request.stopping_parameters.max_new_tokens);
+            if let Some(estimate) = estimate.filter(|estimate| *estimate > completion_time_slo) {
+                let err = InferError::CompletionTime(estimate, completion_time_slo);
+                metrics::increment_counter!("tgi_request_failure", "err" => "completion_time");
+                tracing::error!("{err}");
+                return Err(err);
+            }
+        }
+
         // MPSC channel to communicate with the background batching task
         let (response_tx, response_rx) = mpsc::unbounded_channel();
         let input_length = valid_request.input_length;
+        // Shared by the entries of the retries
+        let interruption = Interruption::default();
+        if let Some(request_id) = abort::request_id() {
+            self.shared
+                .aborts
+                .register(request_id, response_tx.clone(), interruption.clone());
+        }
 
-        // Append the request to the queue
-        self.queue.append(Entry {
-            request: valid_request,
+        // Queue entry of the request, created again on retries
+        let span = Span::current();
+        let baggage = baggage::current();
+        let priority = sticky::has_priority();
+        let background = batch_files::is_background();
+        let tenant = tenant::current();
+        let prefill_group = prefill_group::current();
+        let rate_limited_client = rate_limit::current();
+        let new_entry = move |response_tx| Entry {
+            request: valid_request.clone(),
             response_tx,
-            span: Span::current(),
+            span: span.clone(),
             temp_span: None,
             queue_time: Instant::now(),
             batch_time: None,
-        });
+            batching_cycles: 0,
+            batch_id: None,
+            generations: 0,
+            baggage: baggage.clone(),
+            priority,
+            background,
+            tenant: tenant.clone(),
+            prefill_group,
+            rate_limited_client: rate_limited_client.clone(),
+            interruption: interruption.clone(),
+            generated_tokens: 0,
+        };
+
+        // Chaos mode: delay the request before queuing it
+        if let Some(delay) = self.shared.chaos.as_ref().and_then(Chaos::queue_delay) {
+            tokio::time::sleep(delay).await;
+        }
+
+        // Append the request to the queue
+        match retries {
+            0 => self.queue.append(new_entry(response_tx)),
+            _ => {
+                let (entry_tx, entry_rx) = mpsc::unbounded_channel();
+                self.queue.append(new_entry(entry_tx));
+                tokio::spawn(retry_incomplete(
+                    self.queue.clone(),
+                    self.shared.clone(),
+                    new_entry,
+                    entry_rx,
+                    response_tx,
+                    retries,
+                ));
+            }
+        }
 
         // Notify the background task that we have a new entry in the queue that needs
         // to be batched
@@ -172,10 +663,71 @@ impl Infer {
         Ok(encoding.map(|(encoding, _)| encoding))
     }
 
+    /// Abort the in-flight generations of `request_id`. Returns whether one was found
+    pub(crate) fn abort(&self, request_id: &str) -> bool {
+        self.shared.aborts.abort(request_id)
+    }
+
+    /// End the running generations with the `shutdown` finish reason, and fail the queued and
+    /// new requests with a `shutdown` error
+    pub(crate) fn abort_all(&self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        self.shared.batching_task.notify_one();
+    }
+
+    /// Whether a new request would get a concurrency permit
+    pub(crate) fn has_capacity(&self) -> bool {
+        self.limit_concurrent_requests.available_permits() > 0
+    }
+
+    /// Wait, in arrival order, for a concurrency permit, handed to the request through
+    /// `waiting_room::take_permit`
+    pub(crate) async fn wait_for_capacity(&self) -> OwnedSemaphorePermit {
+        self.limit_concurrent_requests
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("The concurrency semaphore is never closed")
+    }
+
+    /// Estimated time a new request will spend in the queue
+    pub(crate) fn estimated_queue_time(&self) -> Duration {
+        self.queue.estimated_queue_time()
+    }
+
+    /// Estimated time before a new request generates its `max_new_tokens`: its estimated queue
+    /// time and its decode time at the recent time per token
+    pub(crate) fn estimated_completion_time(&self, max_new_tokens: u32) -> Option<Duration> {
+        self.shared
+            .decode_stats
+            .estimated_decode_time(max_new_tokens)
+            .map(|decode_time| self.estimated_queue_time() + decode_time)
+    }
+
+    /// Text of the generated (non special) tokens of an interrupted generation, empty if we do
+    /// not have a fast tokenizer
+    pub(crate) fn decode(&self, generated_ids: &[u32]) -> String {
+        self.validation
+            .tokenizer()
+            .and_then(|tokenizer| tokenizer.decode(generated_ids, true).ok())
+            .unwrap_or_default()
+    }
+
+    /// Incremental detokenizer for the streamed tokens, if we have a fast tokenizer
+    pub(crate) fn detokenizer(&self) -> Option<IncrementalDetokenizer> {
+        self.validation.detokenizer()
+    }
+
     /// Apply the chat template to the chat request
     #[instrument(skip_all)]
     pub(crate) fn apply_chat_template(&self, messages: Vec<Message>) -> Result<String, InferError> {
-        self.chat_template
+        let start_time = Instant::now();
+        let num_messages = messages.len();
+        self.chat_support()?;
+        let inputs = self
+            .chat_template
+            .read()
+            .unwrap()
             .as_ref()
             .ok_or_else(|| InferError::TemplateError(ErrorKind::TemplateNotFound.into()))?
             .apply(messages)
@@ -183,19 +735,169 @@ impl Infer {
                 metrics::increment_counter!("tgi_request_failure", "err" => "template");
                 tracing::error!("{e}");
                 e
-            })
+            })?;
+
+        let duration = start_time.elapsed();
+        metrics::histogram!("tgi_chat_template_duration", duration.as_secs_f64());
+        tracing::debug!(
+            ?duration,
+            num_messages,
+            characters = inputs.len(),
+            "Chat template applied"
+        );
+        Ok(inputs)
+    }
+
+    /// Whether the chat requests can be templated: a model without tokenizer has no tokenizer
+    /// config to read a chat template from
+    pub(crate) fn chat_support(&self) -> Result<(), InferError> {
+        if self.chat_template.read().unwrap().is_some() {
+            return Ok(());
+        }
+        match self.fast_tokenizer() {
+            true => Err(InferError::ChatTemplateNotFound),
+            false => Err(InferError::TokenizerNotFound),
+        }
+    }
+
+    /// Whether the chat requests can call tools, constrained by a grammar
+    pub(crate) fn tools_support(&self) -> bool {
+        self.chat_support().is_ok() && self.validation.grammar_support()
+    }
+
+    /// Maximum number of prompt tokens leaving room for `max_new_tokens`
+    pub(crate) fn input_budget(&self, max_new_tokens: u32) -> usize {
+        self.validation.input_budget(max_new_tokens)
+    }
+
+    /// Whether a fast tokenizer is loaded
+    pub(crate) fn fast_tokenizer(&self) -> bool {
+        self.validation.detokenizer().is_some()
+    }
+
+    /// Swap the tokenizer and the chat template, without dropping the in-flight requests.
+    /// Nothing is swapped if the new chat template does not compile.
+    pub(crate) fn reload_tokenizer(
+        &self,
+        tokenizer: Option<Tokenizer>,
+        tokenizer_config: HubTokenizerConfig,
+    ) -> Result<(), InferError> {
+        let chat_template =
+            ChatTemplate::from_config(tokenizer_config).map_err(InferError::TemplateError)?;
+        self.validation.reload_tokenizer(tokenizer);
+        *self.chat_template.write().unwrap() = chat_template;
+        self.tokenizer_reloads.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// LoRA adapters resident on the shards
+    pub(crate) fn adapters(&self) -> Vec<String> {
+        self.validation.adapters()
     }
 
-    /// Add a new request to the queue and return a InferResponse
+    /// Accept the adapters loaded at runtime and reject the evicted ones
+    pub(crate) fn set_adapters(&self, adapters: Vec<String>) {
+        self.validation.set_adapters(adapters);
+    }
+
+    /// Reject the new requests selecting an adapter about to be evicted, unless requests in
+    /// flight still use it: their number is returned
+    pub(crate) fn remove_adapter(&self, adapter_id: &str) -> Result<(), usize> {
+        self.validation.remove_adapter(adapter_id)
+    }
+
+    /// OpenAI `system_fingerprint`, changed by every tokenizer reload
+    pub(crate) fn system_fingerprint(&self, info: &Info) -> String {
+        let fingerprint = format!("{}-{}", info.version, info.docker_label.unwrap_or("native"));
+        match self.tokenizer_reloads.load(Ordering::SeqCst) {
+            0 => fingerprint,
+            reloads => format!("{fingerprint}-{reloads}"),
+        }
+    }
+
+    /// Add a new request to the queue and return a InferResponse. The request shares the
+    /// generation of an identical request in flight when requests are coalesced
     #[instrument(skip_all)]
     pub(crate) async fn generate(
+        &self,
+        mut request: GenerateRequest,
+    ) -> Result<InferResponse, InferError> {
+        self.expand_preset(&mut request.parameters)?;
+        match &self.coalescer {
+            Some(coalescer) => {
+                let (response, shared) = coalescer
+                    .generate(request, |request| self.generate_with_retries(request))
+                    .await?;
+                // The requests sharing a generation are accounted to their own tenant and
+                // rate limit
+                if shared {
+                    let generated_tokens = response.generated_text.generated_tokens;
+                    if let Some(tenant) = tenant::current() {
+                        tenant.record_usage(response._input_length, generated_tokens);
+                    }
+                    if let Some(client) = rate_limit::current() {
+                        client.record_tokens(response._input_length, generated_tokens);
+                    }
+                }
+                Ok(response)
+            }
+            None => self.generate_with_retries(request).await,
+        }
+    }
+
+    /// Add a new request to the queue and return a InferResponse. The request is generated
+    /// again, at most `incomplete_generation_retries` times, when it ends without its last token
+    async fn generate_with_retries(
         &self,
         request: GenerateRequest,
     ) -> Result<InferResponse, InferError> {
+        let retries = self.incomplete_generation_retries;
+        let mut attempt = 0;
+        loop {
+            match self.generate_once(request.clone()).await {
+                Err(InferError::IncompleteGeneration) if attempt < retries => {
+                    attempt += 1;
+                    metrics::increment_counter!("tgi_request_incomplete_retry");
+                    tracing::warn!("Retrying incomplete generation ({attempt}/{retries})");
+                }
+                Err(InferError::IncompleteGeneration) => {
+                    if attempt > 0 {
+                        metrics::increment_counter!("tgi_request_incomplete_retried", "outcome" => "failure");
+                    }
+                    let err = InferError::IncompleteGeneration;
+                    metrics::increment_counter!("tgi_request_failure", "err" => "incomplete");
+                    tracing::error!("{err}");
+                    if let Some(alerts) = &self.shared.alerts {
+                        alerts.alert(AlertKind::IncompleteGeneration, err.to_string());
+                    }
+                    return Err(err);
+                }
+                response => {
+                    if attempt > 0 {
+                        let outcome = if response.is_ok() {
+                            "success"
+                        } else {
+                            "failure"
+                        };
+                        metrics::increment_counter!("tgi_request_incomplete_retried", "outcome" => outcome);
+                    }
+                    return response;
+                }
+            }
+        }
+    }
+
+    /// Add a new request to the queue and return a InferResponse, or an `IncompleteGeneration`
+    /// error if the request ended without its last token
+    async fn generate_once(&self, request: GenerateRequest) -> Result<InferResponse, InferError> {
         let use_top_tokens = request.parameters.top_n_tokens.is_some_and(|x| x > 0);
+        let skip_special_tokens = request.parameters.skip_special_tokens;
+        // Prefill-only scoring requests only return the prefill details
+        let prefill_only = request.parameters.max_new_tokens == Some(0);
 
         // Create stream and keep semaphore permit as long as generate lives
-        let (_permit, _input_length, mut stream) = self.generate_stream(request).await?;
+        let (_permit, _input_length, mut stream) =
+            self.generate_stream_with_retries(request, 0).await?;
 
         // Return values
         let mut result_prefill = Vec::new();
@@ -204,20 +906,30 @@ impl Infer {
         let mut result_generated_text = None;
         let mut result_start = None;
         let mut result_queued = None;
+        let mut result_scheduling = None;
+        let mut result_speculation = None;
+        let mut result_retokenization = None;
+        let mut result_normalization = None;
 
         // Iterate on stream
         while let Some(response) = stream.next().await {
             match response? {
                 // Add prefill tokens
-                InferStreamResponse::Prefill(tokens) => {
+                InferStreamResponse::Prefill { tokens, top_tokens } => {
                     // Create Token objects
                     // We do that here instead of in the Python code as Rust for loops are faster
+                    let mut top_tokens = top_tokens.iter().map(top_n_tokens);
                     result_prefill = tokens
                         .ids
                         .into_iter()
                         .zip(tokens.logprobs.into_iter())
                         .zip(tokens.texts.into_iter())
-                        .map(|((id, logprob), text)| PrefillToken { id, text, logprob })
+                        .map(|((id, logprob), text)| PrefillToken {
+                            id,
+                            text,
+                            logprob,
+                            top_tokens: top_tokens.next().unwrap_or_default(),
+                        })
                         .collect();
                 }
                 // Push last token
@@ -229,24 +941,60 @@ impl Infer {
                 // Set return values
                 InferStreamResponse::End {
                     token,
-                    generated_text,
+                    mut generated_text,
                     start,
                     queued,
                     top_tokens,
+                    batching_cycles,
+                    batch_id,
+                    generations,
+                    normalization,
                 } => {
-                    result_tokens.push(token);
-                    result_top_tokens.push(top_tokens);
+                    if prefill_only {
+                        // Drop the token the shards generated anyway
+                        generated_text.text.clear();
+                        generated_text.generated_tokens = 0;
+                    } else {
+                        result_tokens.push(token);
+                        result_top_tokens.push(top_tokens);
+                    }
+                    if interrupted(&generated_text) {
+                        generated_text.text = result_tokens
+                            .iter()
+                            .filter(|token| !(token.special && skip_special_tokens))
+                            .map(|token| token.text.as_str())
+                            .collect();
+                    }
+                    result_speculation =
+                        self.speculation(generated_text.generated_tokens, generations);
+                    let generated_ids: Vec<u32> = result_tokens
+                        .iter()
+                        .filter(|token| !token.special)
+                        .map(|token| token.id)
+                        .collect();
+                    result_retokenization =
+                        self.retokenization(&generated_text.text, &generated_ids);
                     result_generated_text = Some(generated_text);
                     result_start = Some(start);
-                    result_queued = Some(queued)
+                    result_queued = Some(queued);
+                    result_scheduling = Some((batching_cycles, batch_id));
+                    result_normalization = normalization;
                 }
             }
         }
 
         // Check that we received a `InferStreamResponse::End` message
-        if let (Some(generated_text), Some(queued), Some(start)) =
-            (result_generated_text, result_queued, result_start)
-        {
+        if let (
+            Some(generated_text),
+            Some(queued),
+            Some(start),
+            Some((batching_cycles, batch_id)),
+        ) = (
+            result_generated_text,
+            result_queued,
+            result_start,
+            result_scheduling,
+        ) {
             Ok(InferResponse {
                 prefill: result_prefill,
                 _input_length,
@@ -254,6 +1002,11 @@ impl Infer {
                 generated_text,
                 queued,
                 start,
+                batching_cycles,
+                batch_id,
+                speculation: result_speculation,
+                retokenization: result_retokenization,
+                normalization: result_normalization,
                 top_tokens: if use_top_tokens {
                     result_top_tokens
                 } else {
@@ -261,10 +1014,7 @@ impl Infer {
                 },
             })
         } else {
-            let err = InferError::IncompleteGeneration;
-            metrics::increment_counter!("tgi_request_failure", "err" => "incomplete");
-            tracing::error!("{err}");
-            Err(err)
+            Err(InferError::IncompleteGeneration)
         }
     }
     /// Add best_of new requests to the queue and return a InferResponse of the sequence with
@@ -278,9 +1028,12 @@ impl Infer {
         // validate  best_of parameter separately
         let best_of = self.validation.validate_best_of(best_of)?;
 
-        // create multiple generate requests
-        let mut infer_responses: Vec<InferResponse> =
-            try_join_all((0..best_of).map(|_| self.generate(request.clone()))).await?;
+        // create multiple generate requests, prefilled once by the shards supporting it
+        let generate = self.generate_best_of_candidates(request, best_of);
+        let mut infer_responses = match self.shared_prefill {
+            true => prefill_group::in_group(prefill_group::new_group(), generate).await?,
+            false => generate.await?,
+        };
 
         // get the sequence with the highest log probability per token
         let mut max_index = 0;
@@ -304,44 +1057,153 @@ impl Infer {
         let best_response = infer_responses.remove(max_index);
         Ok((best_response, infer_responses))
     }
+
+    /// Add the `best_of` candidates of a request to the queue
+    async fn generate_best_of_candidates(
+        &self,
+        request: GenerateRequest,
+        best_of: usize,
+    ) -> Result<Vec<InferResponse>, InferError> {
+        try_join_all((0..best_of).map(|_| self.generate(request.clone()))).await
+    }
+
+    /// Add `n` new requests to the queue, each one with a distinct seed derived from the request
+    /// seed, and return all their InferResponses
+    #[instrument(skip(self, request))]
+    pub(crate) async fn generate_samples(
+        &self,
+        mut request: GenerateRequest,
+        n: usize,
+    ) -> Result<Vec<InferResponse>, InferError> {
+        self.expand_preset(&mut request.parameters)?;
+        // validate n parameter separately
+        let n = self.validation.validate_samples(n, &request.parameters)?;
+
+        // derive the seed of each sample from the base seed
+        let seed = request
+            .parameters
+            .seed
+            .unwrap_or_else(|| thread_rng().gen());
+        request.parameters.best_of = None;
+
+        // all requests are appended to the queue together so they can be batched together
+        try_join_all((0..n).map(|i| {
+            let mut request = request.clone();
+            request.parameters.seed = Some(seed.wrapping_add(i as u64));
+            self.generate(request)
+        }))
+        .await
+    }
 }
 
+/// Chat template of a tokenizer config. The templates of the reloaded tokenizers are dropped
+/// with their environment
 #[derive(Clone)]
 struct ChatTemplate {
-    template: Template<'static, 'static>,
+    env: Arc<Environment<'static>>,
+    template: Arc<str>,
     bos_token: Option<String>,
     eos_token: Option<String>,
 }
 
 impl ChatTemplate {
-    fn new(template: String, bos_token: Option<String>, eos_token: Option<String>) -> Self {
-        let mut env = Box::new(Environment::new());
-        let template_str = template.into_boxed_str();
+    fn new(
+        template: String,
+        bos_token: Option<String>,
+        eos_token: Option<String>,
+    ) -> Result<Self, minijinja::Error> {
+        let mut env = Environment::new();
         env.add_function("raise_exception", raise_exception);
-        // leaking env and template_str as read-only, static resources for performance.
-        let template = Box::leak(env)
-            .template_from_str(Box::leak(template_str))
-            .unwrap();
+        // Fail on the syntax errors when the template is loaded rather than on every request
+        env.template_from_str(&template)?;
 
-        Self {
-            template,
+        Ok(Self {
+            env: Arc::new(env),
+            template: template.into(),
             bos_token,
             eos_token,
-        }
+        })
+    }
+
+    /// Compile the chat template of a tokenizer config, if any
+    fn from_config(tokenizer_config: HubTokenizerConfig) -> Result<Option<Self>, minijinja::Error> {
+        tokenizer_config
+            .chat_template
+            .map(|t| Self::new(t, tokenizer_config.bos_token, tokenizer_config.eos_token))
+            .transpose()
     }
 
     fn apply(&self, messages: Vec<Message>) -> Result<String, InferError> {
-        self.template
-            .render(ChatTemplateInputs {
-                messages,
-                bos_token: self.bos_token.as_deref(),
-                eos_token: self.eos_token.as_deref(),
-                add_generation_prompt: true,
-            })
+        self.env
+            .render_str(
+                &self.template,
+                ChatTemplateInputs {
+                    messages,
+                    bos_token: self.bos_token.as_deref(),
+                    eos_token: self.eos_token.as_deref(),
+                    add_generation_prompt: true,
+                },
+            )
             .map_err(InferError::TemplateError)
     }
 }
 
+/// Forward the responses of a request to `response_tx`. When the request ends without its last
+/// token before any token was forwarded, queue it again with `new_entry`, at most `retries` times
+async fn retry_incomplete(
+    queue: Queue,
+    shared: Arc<Shared>,
+    new_entry: impl Fn(mpsc::UnboundedSender<Result<InferStreamResponse, InferError>>) -> Entry,
+    mut entry_rx: mpsc::UnboundedReceiver<Result<InferStreamResponse, InferError>>,
+    response_tx: mpsc::UnboundedSender<Result<InferStreamResponse, InferError>>,
+    retries: usize,
+) {
+    let mut attempt = 0;
+    loop {
+        let mut streamed = false;
+        loop {
+            let response = tokio::select! {
+                // Dropping `entry_rx` lets the batching task filter the request out
+                _ = response_tx.closed() => return,
+                response = entry_rx.recv() => response,
+            };
+            let Some(response) = response else {
+                break;
+            };
+            streamed |= matches!(response, Ok(InferStreamResponse::Intermediate { .. }));
+            let outcome = match &response {
+                Ok(InferStreamResponse::End { .. }) => Some("success"),
+                Err(_) => Some("failure"),
+                _ => None,
+            };
+            if response_tx.send(response).is_err() {
+                return;
+            }
+            if let Some(outcome) = outcome {
+                if attempt > 0 {
+                    metrics::increment_counter!("tgi_request_incomplete_retried", "outcome" => outcome);
+                }
+                return;
+            }
+        }
+
+        // The request ended without its last token
+        if streamed || attempt == retries {
+            if attempt > 0 {
+                metrics::increment_counter!("tgi_request_incomplete_retried", "outcome" => "failure");
+            }
+            return;
+        }
+        attempt += 1;
+        metrics::increment_counter!("tgi_request_incomplete_retry");
+        tracing::warn!("Retrying incomplete generation ({attempt}/{retries})");
+        let (entry_tx, receiver) = mpsc::unbounded_channel();
+        queue.append(new_entry(entry_tx));
+        shared.batching_task.notify_one();
+        entry_rx = receiver;
+    }
+}
+
 /// Batching logic
 /// Will be launched in a background Tokio task
 ///
@@ -353,10 +1215,13 @@ async fn batching_task(
     max_batch_prefill_tokens: u32,
     max_batch_total_tokens: u32,
     max_waiting_tokens: usize,
+    latency_max_waiting_tokens: usize,
     max_batch_size: Option<usize>,
     queue: Queue,
     shared: Arc<Shared>,
     generation_health: Arc<AtomicBool>,
+    circuit_breaker: Option<CircuitBreaker>,
+    window_size: Option<u32>,
 ) {
     // Infinite loop
     loop {
@@ -375,10 +1240,27 @@ async fn batching_task(
             )
             .await
         {
-            let mut cached_batch = prefill(&mut client, batch, &mut entries, &generation_health)
-                .instrument(span)
-                .await;
+            // Drain the queue once the requests are aborted
+            if shared.shutdown.load(Ordering::SeqCst) {
+                send_shutdown(&mut entries);
+                continue;
+            }
+
+            let mut cached_batch = prefill(
+                &mut client,
+                batch,
+                &mut entries,
+                &generation_health,
+                &circuit_breaker,
+                &shared.decode_stats,
+                &shared.alerts,
+                &shared.chaos,
+                window_size,
+            )
+            .instrument(span)
+            .await;
             let mut waiting_tokens = 1;
+            let mut interrupted = false;
 
             // We loop until we do not receive any cached batch from the inference server (== until
             // all requests have met their stopping criteria)
@@ -390,6 +1272,11 @@ async fn batching_task(
                 metrics::gauge!("tgi_batch_current_size", batch_size as f64);
                 metrics::gauge!("tgi_batch_current_max_tokens", batch_max_tokens as f64);
 
+                // Onboard the waiting requests of the latency class sooner
+                let max_waiting_tokens = match queue.latency_waiting() {
+                    true => latency_max_waiting_tokens.min(max_waiting_tokens),
+                    false => max_waiting_tokens,
+                };
                 let min_size = if waiting_tokens >= max_waiting_tokens {
                     // If we didn't onboard any new requests since >= max_waiting_tokens, we try
                     // to add a new batch even though its size might be small
@@ -402,11 +1289,17 @@ async fn batching_task(
                 let token_budget = max_batch_total_tokens.saturating_sub(batch_max_tokens);
                 let max_size = max_batch_size.map(|max_size| max_size - batch_size as usize);
 
-                // Try to get a new batch
-                if let Some((mut new_entries, new_batch, span)) = queue
-                    .next_batch(min_size, max_size, max_batch_prefill_tokens, token_budget)
-                    .await
-                {
+                // Try to get a new batch, the queued requests failing once the requests are
+                // aborted
+                let new_batch = match shared.shutdown.load(Ordering::SeqCst) {
+                    true => None,
+                    false => {
+                        queue
+                            .next_batch(min_size, max_size, max_batch_prefill_tokens, token_budget)
+                            .await
+                    }
+                };
+                if let Some((mut new_entries, new_batch, span)) = new_batch {
                     // Tracking metrics
                     if min_size.is_some() {
                         metrics::increment_counter!("tgi_batch_concat", "reason" => "backpressure");
@@ -426,10 +1319,19 @@ async fn batching_task(
                     });
 
                     // Generate one token for this new batch to have the attention past in cache
-                    let new_cached_batch =
-                        prefill(&mut client, new_batch, &mut new_entries, &generation_health)
-                            .instrument(span)
-                            .await;
+                    let new_cached_batch = prefill(
+                        &mut client,
+                        new_batch,
+                        &mut new_entries,
+                        &generation_health,
+                        &circuit_breaker,
+                        &shared.decode_stats,
+                        &shared.alerts,
+                        &shared.chaos,
+                        window_size,
+                    )
+                    .instrument(span)
+                    .await;
                     // Reset waiting counter
                     waiting_tokens = 1;
                     // Extend current batch with the new batch
@@ -453,10 +1355,40 @@ async fn batching_task(
                     entry.temp_span = Some(entry_batch_span);
                 });
 
-                cached_batch = decode(&mut client, batches, &mut entries, &generation_health)
-                    .instrument(next_batch_span)
-                    .await;
+                cached_batch = decode(
+                    &mut client,
+                    batches,
+                    &mut entries,
+                    &generation_health,
+                    &circuit_breaker,
+                    &shared.decode_stats,
+                    &shared.alerts,
+                    &shared.chaos,
+                    window_size,
+                )
+                .instrument(next_batch_span)
+                .await;
                 waiting_tokens += 1;
+
+                // Once the requests are aborted, the running generations end at their next token
+                // with the `shutdown` finish reason. The ones still running after it fail with a
+                // `shutdown` error and their sequences are cancelled on the shards
+                if shared.shutdown.load(Ordering::SeqCst) {
+                    match interrupted {
+                        false => {
+                            entries.values().for_each(|entry| {
+                                entry.interruption.interrupt(ProtoFinishReason::Shutdown)
+                            });
+                            interrupted = true;
+                        }
+                        true => {
+                            send_shutdown(&mut entries);
+                            if let Some(batch) = cached_batch.take() {
+                                let _ = client.clear_cache(Some(batch.id)).await;
+                            }
+                        }
+                    }
+                }
             }
             metrics::gauge!("tgi_batch_current_size", 0.0);
             metrics::gauge!("tgi_batch_current_max_tokens", 0.0);
@@ -464,30 +1396,68 @@ async fn batching_task(
     }
 }
 
+/// Memory pressure polling
+/// Will be launched in a background Tokio task
+///
+/// Periodically asks the shards for their memory pressure
+async fn memory_pressure_task(mut client: ShardedClient, memory_pressure: Arc<AtomicU32>) {
+    let mut interval = tokio::time::interval(MEMORY_PRESSURE_POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        // Keep the last known value if the shards cannot be reached
+        if let Ok(response) = client.health().await {
+            let value = response.memory_pressure.unwrap_or(0.0);
+            memory_pressure.store(value.to_bits(), Ordering::SeqCst);
+            metrics::gauge!("tgi_shard_memory_pressure", value as f64);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 #[instrument(skip_all)]
 async fn prefill(
     client: &mut ShardedClient,
     batch: Batch,
     entries: &mut IntMap<u64, Entry>,
     generation_health: &Arc<AtomicBool>,
+    circuit_breaker: &Option<CircuitBreaker>,
+    decode_stats: &DecodeStats,
+    alerts: &Option<Alerts>,
+    chaos: &Option<Chaos>,
+    window_size: Option<u32>,
 ) -> Option<CachedBatch> {
     let start_time = Instant::now();
     let batch_id = batch.id;
     metrics::increment_counter!("tgi_batch_inference_count", "method" => "prefill");
+    let grammar = grammar_batch_label(entries);
 
-    match client.prefill(batch).await {
+    match client
+        .prefill(batch)
+        .with_context(baggage::batch_context(entries))
+        .await
+    {
         Ok((generations, next_batch, timings)) => {
             // Update health
             generation_health.store(true, Ordering::SeqCst);
+            if let Some(circuit_breaker) = circuit_breaker {
+                circuit_breaker.record(true);
+            }
 
             let start_filtering_time = Instant::now();
             // Send generated tokens and filter stopped entries
-            filter_send_generations(generations, entries);
+            filter_send_generations(generations, entries, decode_stats, chaos, window_size);
 
             // Filter next batch and remove requests that were stopped
             let next_batch = filter_batch(client, next_batch, entries).await;
 
+            if let Some(grammar_compile_duration) = timings.grammar_compile {
+                metrics::histogram!(
+                    "tgi_grammar_compile_duration",
+                    grammar_compile_duration.as_secs_f64()
+                );
+            }
             metrics::histogram!("tgi_batch_forward_duration", timings.forward.as_secs_f64(), "method" => "prefill");
+            metrics::histogram!("tgi_grammar_batch_forward_duration", timings.forward.as_secs_f64(), "method" => "prefill", "grammar" => grammar);
             metrics::histogram!("tgi_batch_decode_duration", timings.decode.as_secs_f64(), "method" => "prefill");
             metrics::histogram!("tgi_batch_filter_duration", start_filtering_time.elapsed().as_secs_f64(), "method" => "prefill");
             metrics::histogram!("tgi_batch_inference_duration", start_time.elapsed().as_secs_f64(), "method" => "prefill");
@@ -498,33 +1468,63 @@ async fn prefill(
         Err(err) => {
             // Update health
             generation_health.store(false, Ordering::SeqCst);
+            if let Some(circuit_breaker) = circuit_breaker {
+                circuit_breaker.record(false);
+            }
             let _ = client.clear_cache(Some(batch_id)).await;
-            send_errors(err, entries);
+            send_errors(err, entries, alerts);
             metrics::increment_counter!("tgi_batch_inference_failure", "method" => "prefill");
             None
         }
     }
 }
 
+/// Label a batch by its share of grammar constrained requests
+fn grammar_batch_label(entries: &IntMap<u64, Entry>) -> &'static str {
+    let constrained = entries
+        .values()
+        .filter(|entry| entry.request.parameters.grammar_type != ProtoGrammarType::None as i32)
+        .count();
+    match constrained {
+        0 => "none",
+        n if n == entries.len() => "all",
+        _ => "mixed",
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 #[instrument(skip_all)]
 async fn decode(
     client: &mut ShardedClient,
     batches: Vec<CachedBatch>,
     entries: &mut IntMap<u64, Entry>,
     generation_health: &Arc<AtomicBool>,
+    circuit_breaker: &Option<CircuitBreaker>,
+    decode_stats: &DecodeStats,
+    alerts: &Option<Alerts>,
+    chaos: &Option<Chaos>,
+    window_size: Option<u32>,
 ) -> Option<CachedBatch> {
     let start_time = Instant::now();
     let batch_ids: Vec<u64> = batches.iter().map(|b| b.id).collect();
     metrics::increment_counter!("tgi_batch_inference_count", "method" => "decode");
+    let grammar = grammar_batch_label(entries);
 
-    match client.decode(batches).await {
+    match client
+        .decode(batches)
+        .with_context(baggage::batch_context(entries))
+        .await
+    {
         Ok((generations, next_batch, timings)) => {
             // Update health
             generation_health.store(true, Ordering::SeqCst);
+            if let Some(circuit_breaker) = circuit_breaker {
+                circuit_breaker.record(true);
+            }
 
             let start_filtering_time = Instant::now();
             // Send generated tokens and filter stopped entries
-            filter_send_generations(generations, entries);
+            filter_send_generations(generations, entries, decode_stats, chaos, window_size);
 
             // Filter next batch and remove requests that were stopped
             let next_batch = filter_batch(client, next_batch, entries).await;
@@ -533,6 +1533,7 @@ async fn decode(
                 metrics::histogram!("tgi_batch_concat_duration", concat_duration.as_secs_f64(), "method" => "decode");
             }
             metrics::histogram!("tgi_batch_forward_duration", timings.forward.as_secs_f64(), "method" => "decode");
+            metrics::histogram!("tgi_grammar_batch_forward_duration", timings.forward.as_secs_f64(), "method" => "decode", "grammar" => grammar);
             metrics::histogram!("tgi_batch_decode_duration", timings.decode.as_secs_f64(), "method" => "decode");
             metrics::histogram!("tgi_batch_filter_duration", start_filtering_time.elapsed().as_secs_f64(), "method" => "decode");
             metrics::histogram!("tgi_batch_inference_duration", start_time.elapsed().as_secs_f64(), "method" => "decode");
@@ -542,10 +1543,13 @@ async fn decode(
         // If we have an error, we discard the whole batch
         Err(err) => {
             generation_health.store(false, Ordering::SeqCst);
+            if let Some(circuit_breaker) = circuit_breaker {
+                circuit_breaker.record(false);
+            }
             for id in batch_ids {
                 let _ = client.clear_cache(Some(id)).await;
             }
-            send_errors(err, entries);
+            send_errors(err, entries, alerts);
             metrics::increment_counter!("tgi_batch_inference_failure", "method" => "decode");
             None
         }
@@ -588,21 +1592,37 @@ async fn filter_batch(
 /// Send one or multiple `InferStreamResponse` to Infer for all `entries`
 /// and filter entries
 #[instrument(skip_all)]
-fn filter_send_generations(generations: Vec<Generation>, entries: &mut IntMap<u64, Entry>) {
+fn filter_send_generations(
+    generations: Vec<Generation>,
+    entries: &mut IntMap<u64, Entry>,
+    decode_stats: &DecodeStats,
+    chaos: &Option<Chaos>,
+    window_size: Option<u32>,
+) {
     generations.into_iter().for_each(|generation| {
         let id = generation.request_id;
         // Get entry
         // We can `expect` here as the request id should always be in the entries
         let entry = entries
-            .get(&id)
+            .get_mut(&id)
             .expect("ID not found in entries. This is a bug.");
+        entry.generations += 1;
 
         // Create and enter a span to link this function back to the entry
         let _span = info_span!(parent: entry.temp_span.as_ref().expect("batch_span is None. This is a bug."), "send_generation", generation = ?generation).entered();
+        // Chaos mode: fail the request as if its shard failed
+        if chaos.as_ref().is_some_and(Chaos::shard_error) {
+            let err = InferError::GenerationError("Chaos: synthetic shard error".to_string());
+            metrics::increment_counter!("tgi_request_failure", "err" => "generation");
+            tracing::error!("{err}");
+            entry.response_tx.send(Err(err)).unwrap_or(());
+            entries.remove(&id).expect("ID not found in entries. This is a bug.");
+            return;
+        }
         // Send generation responses back to the infer task
         // If the receive an error from the Flume channel, it means that the client dropped the
         // request and we need to stop generating hence why we unwrap_or(true)
-        let stopped = send_responses(generation, entry).map_err(|err| {
+        let stopped = send_responses(generation, entry, decode_stats, window_size).map_err(|err| {
             tracing::error!("Entry response channel error.");
             metrics::increment_counter!("tgi_request_failure", "err" => "dropped");
             err
@@ -616,7 +1636,9 @@ fn filter_send_generations(generations: Vec<Generation>, entries: &mut IntMap<u6
 /// Send responses through the `entry` response channel
 fn send_responses(
     generation: Generation,
-    entry: &Entry,
+    entry: &mut Entry,
+    decode_stats: &DecodeStats,
+    window_size: Option<u32>,
 ) -> Result<bool, Box<SendError<Result<InferStreamResponse, InferError>>>> {
     // Return directly if the channel is disconnected
     if entry.response_tx.is_closed() {
@@ -628,15 +1650,33 @@ fn send_responses(
 
     if let Some(prefill_tokens) = generation.prefill_tokens {
         // Send message
-        entry
-            .response_tx
-            .send(Ok(InferStreamResponse::Prefill(prefill_tokens)))?;
+        entry.response_tx.send(Ok(InferStreamResponse::Prefill {
+            tokens: prefill_tokens,
+            top_tokens: generation.prefill_top_tokens,
+        }))?;
     }
 
     // Create last Token
     let tokens_ = generation.tokens.expect("Non empty tokens in generation");
     let n = tokens_.ids.len();
     metrics::histogram!("tgi_request_skipped_tokens", (n - 1) as f64);
+
+    // The generations interrupted by the router end at this token. Their text is rebuilt from
+    // the streamed tokens by the consumers, see `interrupted`
+    entry.generated_tokens += n as u32;
+    let generated_text = generation.generated_text.or_else(|| {
+        let finish_reason = entry.interruption.finish_reason()?;
+        let label = FinishReason::from(finish_reason as i32).to_string();
+        metrics::increment_counter!("tgi_request_failure", "err" => label);
+        let parameters = &entry.request.parameters;
+        Some(GeneratedText {
+            text: String::new(),
+            generated_tokens: entry.generated_tokens,
+            finish_reason: finish_reason as i32,
+            seed: parameters.do_sample.then_some(parameters.seed),
+        })
+    });
+
     let mut iterator = tokens_
         .ids
         .into_iter()
@@ -652,27 +1692,37 @@ fn send_responses(
             logprob,
             special,
         };
-        let top_tokens = if let Some(top_tokens_) = generation.top_tokens.get(i) {
-            top_tokens_
-                .ids
-                .iter()
-                .zip(top_tokens_.logprobs.iter())
-                .zip(top_tokens_.texts.iter())
-                .zip(top_tokens_.is_special.iter())
-                .map(|(((&id, &logprob), text), &special)| Token {
-                    id,
-                    text: text.to_string(),
-                    logprob,
-                    special,
-                })
-                .collect()
-        } else {
-            vec![]
-        };
-        match (&generation.generated_text, iterator.peek()) {
+        let top_tokens = generation
+            .top_tokens
+            .get(i)
+            .map(top_n_tokens)
+            .unwrap_or_default();
+        match (&generated_text, iterator.peek()) {
             (Some(generated_text), None) => {
                 // Generation has ended
                 stopped = true;
+                if let Some(tenant) = &entry.tenant {
+                    tenant
+                        .record_usage(entry.request.input_length, generated_text.generated_tokens);
+                }
+                if let Some(client) = &entry.rate_limited_client {
+                    client
+                        .record_tokens(entry.request.input_length, generated_text.generated_tokens);
+                }
+                decode_stats.update(
+                    entry.batch_time.unwrap().elapsed(),
+                    generated_text.generated_tokens,
+                );
+                // Counted for every generation, with or without details
+                if attention_window::tokens_outside(
+                    window_size,
+                    entry.request.input_length,
+                    generated_text.generated_tokens,
+                )
+                .is_some()
+                {
+                    metrics::increment_counter!("tgi_request_prompt_outside_window");
+                }
                 // Send message
                 entry.response_tx.send(Ok(InferStreamResponse::End {
                     token,
@@ -680,6 +1730,10 @@ fn send_responses(
                     generated_text: generated_text.clone(),
                     queued: entry.queue_time,
                     start: entry.batch_time.unwrap(),
+                    batching_cycles: entry.batching_cycles,
+                    batch_id: entry.batch_id.unwrap(),
+                    generations: entry.generations,
+                    normalization: entry.request.normalization.clone(),
                 }))?;
             }
             _ => {
@@ -696,7 +1750,14 @@ fn send_responses(
 
 /// Send errors to Infer for all `entries`
 #[instrument(skip_all)]
-fn send_errors(error: ClientError, entries: &mut IntMap<u64, Entry>) {
+fn send_errors(error: ClientError, entries: &mut IntMap<u64, Entry>, alerts: &Option<Alerts>) {
+    if let Some(alerts) = alerts {
+        let kind = match error {
+            ClientError::Connection(_) => AlertKind::ShardDisconnected,
+            _ => AlertKind::ShardError,
+        };
+        alerts.alert(kind, error.to_string());
+    }
     entries.drain().for_each(|(_, entry)| {
         // Create and enter a span to link this function back to the entry
         let _send_error_span = info_span!(parent: entry.temp_span.as_ref().expect("batch_span is None. This is a bug."), "send_error").entered();
@@ -712,10 +1773,54 @@ fn send_errors(error: ClientError, entries: &mut IntMap<u64, Entry>) {
     });
 }
 
+/// Whether the router ended the generation before its stopping criteria, e.g. aborted it: the
+/// text of its last response is empty and rebuilt from its tokens
+pub(crate) fn interrupted(generated_text: &GeneratedText) -> bool {
+    matches!(
+        ProtoFinishReason::try_from(generated_text.finish_reason),
+        Ok(ProtoFinishReason::Aborted
+            | ProtoFinishReason::Cancelled
+            | ProtoFinishReason::Shutdown
+            | ProtoFinishReason::Preempted)
+    )
+}
+
+/// Send a `shutdown` error to all `entries`
+fn send_shutdown(entries: &mut IntMap<u64, Entry>) {
+    entries.drain().for_each(|(_, entry)| {
+        metrics::increment_counter!("tgi_request_failure", "err" => "shutdown");
+        // unwrap_or is valid here as we don't care if the receiver is gone.
+        entry
+            .response_tx
+            .send(Err(InferError::Shutdown))
+            .unwrap_or(());
+    });
+}
+
+/// Create the Token objects of the top tokens of a position
+fn top_n_tokens(top_tokens: &Tokens) -> Vec<Token> {
+    top_tokens
+        .ids
+        .iter()
+        .zip(top_tokens.logprobs.iter())
+        .zip(top_tokens.texts.iter())
+        .zip(top_tokens.is_special.iter())
+        .map(|(((&id, &logprob), text), &special)| Token {
+            id,
+            text: text.to_string(),
+            logprob,
+            special,
+        })
+        .collect()
+}
+
 #[derive(Debug)]
 pub(crate) enum InferStreamResponse {
     // Optional first message
-    Prefill(Tokens),
+    Prefill {
+        tokens: Tokens,
+        top_tokens: Vec<Tokens>,
+    },
     // Intermediate messages
     Intermediate {
         token: Token,
@@ -728,10 +1833,14 @@ pub(crate) enum InferStreamResponse {
         generated_text: GeneratedText,
         start: Instant,
         queued: Instant,
+        batching_cycles: u32,
+        batch_id: u64,
+        generations: u32,
+        normalization: Option<NormalizationReport>,
     },
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub(crate) struct InferResponse {
     /// input_length is the input as perceived by the rust tokenizer in the
     /// validation pathway. It is redundant with prefill.len() but prefill
@@ -742,6 +1851,11 @@ pub(crate) struct InferResponse {
     pub(crate) generated_text: GeneratedText,
     pub(crate) queued: Instant,
     pub(crate) start: Instant,
+    pub(crate) batching_cycles: u32,
+    pub(crate) batch_id: u64,
+    pub(crate) speculation: Option<Speculation>,
+    pub(crate) retokenization: Option<Retokenization>,
+    pub(crate) normalization: Option<NormalizationReport>,
     pub(crate) top_tokens: Vec<Vec<Token>>,
 }
 
@@ -751,12 +1865,32 @@ pub enum InferError {
     GenerationError(String),
     #[error("Model is overloaded")]
     Overloaded(#[from] TryAcquireError),
+    #[error("Model is under memory pressure: inputs must have at most {0} tokens")]
+    PrefillCapacity(u32),
     #[error("Input validation error: {0}")]
     ValidationError(#[from] ValidationError),
     #[error("Incomplete generation")]
     IncompleteGeneration,
     #[error("Template error: {0}")]
     TemplateError(#[from] minijinja::Error),
+    #[error("Chat is not supported: the model has no tokenizer to read a chat template from")]
+    TokenizerNotFound,
+    #[error("Chat is not supported: the tokenizer config of the model has no chat template")]
+    ChatTemplateNotFound,
+    #[error("Model shards are unhealthy, retry later")]
+    UpstreamUnhealthy,
+    #[error("Request would complete in about {0:?}, over the {1:?} completion time SLO: request fewer `max_new_tokens` or retry later")]
+    CompletionTime(Duration, Duration),
+    #[error("Request deadline exceeded")]
+    DeadlineExceeded,
+    #[error("Server is shutting down")]
+    Shutdown,
+    #[error("Could not write the output: {0}")]
+    ObjectStore(#[from] ObjectStoreError),
+    #[error(
+        "Response blocked: the generated text contains personally identifiable information ({0})"
+    )]
+    PiiBlocked(String),
 }
 
 impl InferError {
@@ -764,9 +1898,18 @@ impl InferError {
         match self {
             InferError::GenerationError(_) => "generation",
             InferError::Overloaded(_) => "overloaded",
+            InferError::PrefillCapacity(_) => "capacity_prefill",
             InferError::ValidationError(_) => "validation",
             InferError::IncompleteGeneration => "incomplete_generation",
             InferError::TemplateError(_) => "template_error",
+            InferError::TokenizerNotFound => "tokenizer_not_found",
+            InferError::ChatTemplateNotFound => "chat_template_not_found",
+            InferError::UpstreamUnhealthy => "upstream_unhealthy",
+            InferError::CompletionTime(_, _) => "completion_time",
+            InferError::DeadlineExceeded => "deadline_exceeded",
+            InferError::Shutdown => "shutdown",
+            InferError::ObjectStore(_) => "object_store",
+            InferError::PiiBlocked(_) => "pii_blocked",
         }
     }
 }
@@ -774,10 +1917,67 @@ impl InferError {
 // tests
 #[cfg(test)]
 mod tests {
-    use crate::infer::raise_exception;
+    use crate::infer::{raise_exception, DecodeStats, PrefillShedding};
     use crate::ChatTemplateInputs;
     use crate::Message;
     use minijinja::Environment;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_prefill_shedding() {
+        let memory_pressure = Arc::new(AtomicU32::new(0.5f32.to_bits()));
+        let prefill_shedding = PrefillShedding {
+            memory_pressure: memory_pressure.clone(),
+            threshold: 0.9,
+            max_prefill_tokens: 10,
+        };
+        assert!(!prefill_shedding.should_shed(100));
+
+        memory_pressure.store(0.95f32.to_bits(), Ordering::SeqCst);
+        assert!(prefill_shedding.should_shed(100));
+        // Small prefills are still admitted
+        assert!(!prefill_shedding.should_shed(10));
+    }
+
+    #[test]
+    fn test_decode_stats() {
+        let decode_stats = DecodeStats::default();
+        assert_eq!(decode_stats.estimated_decode_time(10), None);
+
+        decode_stats.update(Duration::from_millis(100), 10);
+        assert_eq!(
+            decode_stats.estimated_decode_time(100),
+            Some(Duration::from_secs(1))
+        );
+        // Moving average of the time per token
+        decode_stats.update(Duration::from_millis(180), 10);
+        assert_eq!(
+            decode_stats.estimated_decode_time(100),
+            Some(Duration::from_millis(1100))
+        );
+        // Requests stopped before generating are ignored
+        decode_stats.update(Duration::from_millis(100), 0);
+        assert_eq!(
+            decode_stats.estimated_decode_time(100),
+            Some(Duration::from_millis(1100))
+        );
+    }
+
+    #[test]
+    fn test_decode_stats_max_new_tokens() {
+        let decode_stats = DecodeStats::default();
+        assert_eq!(decode_stats.max_new_tokens(Duration::from_secs(1)), None);
+
+        decode_stats.update(Duration::from_millis(100), 10);
+        assert_eq!(
+            decode_stats.max_new_tokens(Duration::from_secs(1)),
+            Some(100)
+        );
+        // Always generate at least one token
+        assert_eq!(decode_stats.max_new_tokens(Duration::ZERO), Some(1));
+    }
 
     #[test]
     fn test_chat_template() {
@@ -815,24 +2015,32 @@ mod tests {
                     content: Some("Hi!".to_string()),
                     name: None,
                     tool_calls: None,
+                    priority: None,
+                    parsed: None,
                 },
                 Message {
                     role: "assistant".to_string(),
                     content: Some("Hello how can I help?".to_string()),
                     name: None,
                     tool_calls: None,
+                    priority: None,
+                    parsed: None,
                 },
                 Message {
                     role: "user".to_string(),
                     content: Some("What is Deep Learning?".to_string()),
                     name: None,
                     tool_calls: None,
+                    priority: None,
+                    parsed: None,
                 },
                 Message {
                     role: "assistant".to_string(),
                     content: Some("magic!".to_string()),
                     name: None,
                     tool_calls: None,
+                    priority: None,
+                    parsed: None,
                 },
             ],
             bos_token: Some("[BOS]"),
@@ -884,30 +2092,40 @@ mod tests {
                     content: Some("Hi!".to_string()),
                     name: None,
                     tool_calls: None,
+                    priority: None,
+                    parsed: None,
                 },
                 Message {
                     role: "user".to_string(),
                     content: Some("Hi again!".to_string()),
                     name: None,
                     tool_calls: None,
+                    priority: None,
+                    parsed: None,
                 },
                 Message {
                     role: "assistant".to_string(),
                     content: Some("Hello how can I help?".to_string()),
                     name: None,
                     tool_calls: None,
+                    priority: None,
+                    parsed: None,
                 },
                 Message {
                     role: "user".to_string(),
                     content: Some("What is Deep Learning?".to_string()),
                     name: None,
                     tool_calls: None,
+                    priority: None,
+                    parsed: None,
                 },
                 Message {
                     role: "assistant".to_string(),
                     content: Some("magic!".to_string()),
                     name: None,
                     tool_calls: None,
+                    priority: None,
+                    parsed: None,
                 },
             ],
             bos_token: Some("[BOS]"),
@@ -964,24 +2182,32 @@ mod tests {
                     content: Some("Hi!".to_string()),
                     name: None,
                     tool_calls: None,
+                    priority: None,
+                    parsed: None,
                 },
                 Message {
                     role: "assistant".to_string(),
                     content: Some("Hello how can I help?".to_string()),
                     name: None,
                     tool_calls: None,
+                    priority: None,
+                    parsed: None,
                 },
                 Message {
                     role: "user".to_string(),
                     content: Some("What is Deep Learning?".to_string()),
                     name: None,
                     tool_calls: None,
+                    priority: None,
+                    parsed: None,
                 },
                 Message {
                     role: "assistant".to_string(),
                     content: Some("magic!".to_string()),
                     name: None,
                     tool_calls: None,
+                    priority: None,
+                    parsed: None,
                 },
             ],
             bos_token: Some("[BOS]"),
@@ -1022,24 +2248,32 @@ mod tests {
                     content: Some("Hi!".to_string()),
                     name: None,
                     tool_calls: None,
+                    priority: None,
+                    parsed: None,
                 },
                 Message {
                     role: "assistant".to_string(),
                     content: Some("Hello how can I help?".to_string()),
                     name: None,
                     tool_calls: None,
+                    priority: None,
+                    parsed: None,
                 },
                 Message {
                     role: "user".to_string(),
                     content: Some("What is Deep Learning?".to_string()),
                     name: None,
                     tool_calls: None,
+                    priority: None,
+                    parsed: None,
                 },
                 Message {
                     role: "assistant".to_string(),
                     content: Some("magic!".to_string()),
                     name: None,
                     tool_calls: None,
+                    priority: None,
+                    parsed: None,
                 },
             ],
             bos_token: Some("[BOS]"),
@@ -1,15 +1,89 @@
+mod abort;
+mod adapters;
+mod alerts;
+mod attention_window;
+mod audit;
+mod audit_keys;
+mod baggage;
+mod batch_files;
+mod canary;
+mod chaos;
+mod chat_truncation;
+mod circuit_breaker;
+mod coalescing;
+mod deadline;
+mod debug_capture;
+mod declared_tools;
+mod detokenizer;
+mod disabled_endpoints;
+mod error_catalog;
+mod exemplars;
+mod experiment;
+mod fallback;
+mod fields;
+mod flight_recorder;
+mod generation_stream;
 mod health;
+mod hedging;
 /// Text Generation Inference Webserver
 mod infer;
+mod mcp;
+mod ndjson;
+mod no_backend;
+mod normalization;
+mod object_store;
+mod openai_batch;
+mod openai_error;
+mod pii;
+#[cfg(feature = "playground")]
+mod playground;
+mod prefill_group;
+mod presets;
+mod pricing;
 mod queue;
+mod rate_limit;
+mod route_limits;
+mod served_model;
 pub mod server;
+mod shadow_tokenizer;
+mod slo;
+mod sticky;
+mod stream_limit;
+mod stream_transforms;
+mod template_cache;
+mod tenant;
+mod tokenization_cache;
+mod tokenizer_source;
+mod tool_arguments;
+mod tool_choice;
+mod top_n_tokens;
+mod trace_sampling;
 mod validation;
-
+mod waiting_room;
+mod warnings;
+
+pub use attention_window::SystemPromptPolicy;
+pub use audit_keys::AuditKeys;
+pub use declared_tools::DeclaredTools;
+pub use disabled_endpoints::DisabledEndpoints;
+pub use error_catalog::ErrorCatalogs;
+pub use experiment::Experiments;
 use infer::{Infer, InferError, InferStreamResponse};
+pub use object_store::ObjectStore;
+pub use pii::PiiScanner;
+use pii::{PiiAction, PiiCategory};
+use presets::PresetParameters;
+pub use presets::Presets;
+use pricing::Pricing;
 use queue::{Entry, Queue};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+pub use stream_transforms::StreamTransforms;
+pub use tenant::TenantKeys;
+pub use tokenizer_source::TokenizerSource;
 use tokio::sync::OwnedSemaphorePermit;
 use tokio_stream::wrappers::UnboundedReceiverStream;
+pub use trace_sampling::TraceSampler;
 use utoipa::ToSchema;
 use validation::Validation;
 
@@ -39,6 +113,24 @@ pub(crate) struct VertexResponse {
     pub predictions: Vec<String>,
 }
 
+/// Prediction of one instance, sent as a JSON line as soon as it completes to the clients
+/// accepting `application/x-ndjson`
+#[derive(Deserialize, ToSchema, Serialize)]
+pub(crate) struct VertexPrediction {
+    /// Index of the instance in the request
+    #[schema(example = 0)]
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(
+        nullable = true,
+        example = "Deep Learning is a subset of machine learning"
+    )]
+    pub prediction: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true)]
+    pub error: Option<ErrorResponse>,
+}
+
 /// Hub type
 #[derive(Clone, Debug, Deserialize)]
 pub struct HubModelInfo {
@@ -79,6 +171,16 @@ pub(crate) enum GrammarType {
     Regex(String),
 }
 
+/// Temperature reached after `token` generated tokens. The temperature is interpolated linearly
+/// between the points of a schedule and stays at the last one after it
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct TemperatureStep {
+    #[schema(example = 0)]
+    pub token: u32,
+    #[schema(exclusive_minimum = 0.0, example = 1.0)]
+    pub temperature: f32,
+}
+
 mod token_serde {
     use super::*;
     use serde::de;
@@ -127,6 +229,17 @@ pub struct Info {
     pub max_best_of: usize,
     #[schema(example = "4")]
     pub max_stop_sequences: usize,
+    /// Maximum `top_n_tokens` of `/generate`, `top_logprobs` of `/v1/chat/completions` and
+    /// `logprobs` of `/v1/completions`
+    #[schema(example = "5")]
+    pub max_top_n_tokens: u32,
+    #[schema(example = "5")]
+    pub max_chat_top_logprobs: u32,
+    #[schema(example = "5")]
+    pub max_completions_logprobs: u32,
+    /// Maximum `top_n_tokens` × `max_new_tokens` of a request
+    #[schema(nullable = true, example = "null")]
+    pub max_logprob_payload: Option<u32>,
     #[schema(example = "1024")]
     pub max_input_length: usize,
     #[schema(example = "2048")]
@@ -141,6 +254,29 @@ pub struct Info {
     pub max_batch_size: Option<usize>,
     #[schema(example = "2")]
     pub validation_workers: usize,
+    /// Speculative decoding
+    #[schema(example = "2")]
+    pub speculate: u32,
+    #[schema(nullable = true, example = "n-gram")]
+    pub speculator: Option<String>,
+    /// Sliding attention window of the model, in tokens: the prompt tokens further away from the
+    /// generated tokens are not attended. Null when the model attends to the whole sequence
+    #[schema(nullable = true, example = 4096)]
+    pub window_size: Option<u32>,
+    /// Tools declared by the server, referenced by name in the chat requests
+    #[schema(example = "[\"get_weather\"]")]
+    pub tools: Vec<String>,
+    /// Whether `/v1/chat/completions` is served: the model has a tokenizer with a chat template
+    #[schema(example = true)]
+    pub chat_enabled: bool,
+    /// Whether the chat requests can call tools
+    #[schema(example = true)]
+    pub tools_enabled: bool,
+    /// Presets of decoding parameters, by name
+    pub presets: std::collections::BTreeMap<String, PresetParameters>,
+    /// LoRA adapters selected by the `adapter_id` of the requests
+    #[schema(example = "[\"predibase/customer_support\"]")]
+    pub adapters: Vec<String>,
     /// Router Info
     #[schema(example = "0.5.0")]
     pub version: &'static str,
@@ -150,7 +286,18 @@ pub struct Info {
     pub docker_label: Option<&'static str>,
 }
 
-#[derive(Clone, Debug, Deserialize, ToSchema, Default)]
+/// LoRA adapters blended with the given weights, e.g. to mix domain adapters per request
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub(crate) struct AdapterParameters {
+    /// Adapters listed by `/info`
+    #[schema(example = "[\"my-org/support-lora\", \"my-org/legal-lora\"]")]
+    pub ids: Vec<String>,
+    /// Weight of each adapter
+    #[schema(example = "[0.7, 0.3]")]
+    pub weights: Vec<f32>,
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
 pub(crate) struct GenerateParameters {
     #[serde(default)]
     #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 1)]
@@ -163,6 +310,15 @@ pub(crate) struct GenerateParameters {
         example = 0.5
     )]
     pub temperature: Option<f32>,
+    /// Piecewise linear temperature over the generated tokens, e.g. a hot start decaying to a
+    /// stable finish. Cannot be combined with `temperature`
+    #[serde(default)]
+    #[schema(
+        nullable = true,
+        default = "null",
+        example = json ! ([{"token": 0, "temperature": 1.0}, {"token": 50, "temperature": 0.7}])
+    )]
+    pub temperature_schedule: Option<Vec<TemperatureStep>>,
     #[serde(default)]
     #[schema(
         exclusive_minimum = 0.0,
@@ -179,6 +335,11 @@ pub(crate) struct GenerateParameters {
         example = 0.1
     )]
     pub frequency_penalty: Option<f32>,
+    /// Bias added to the logits of the given token ids before sampling, from -100 (banned) to
+    /// 100 (exclusively selected)
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json ! ({"50256": -100.0}))]
+    pub logit_bias: Option<std::collections::HashMap<u32, f32>>,
     #[serde(default)]
     #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 10)]
     pub top_k: Option<i32>,
@@ -203,6 +364,8 @@ pub(crate) struct GenerateParameters {
     #[serde(default)]
     #[schema(default = "false", example = true)]
     pub do_sample: bool,
+    /// Maximum number of generated tokens. `0` only scores the inputs: the response holds the
+    /// prefill details and no generated token
     #[serde(default = "default_max_new_tokens")]
     #[schema(nullable = true, default = "100", example = "20")]
     pub max_new_tokens: Option<u32>,
@@ -237,18 +400,196 @@ pub(crate) struct GenerateParameters {
     pub top_n_tokens: Option<u32>,
     #[serde(default)]
     pub grammar: Option<GrammarType>,
+    #[serde(default = "default_skip_special_tokens")]
+    #[schema(default = "true", example = true)]
+    pub skip_special_tokens: bool,
+    /// Stream the token texts as decoded by the model shards, without buffering the tokens
+    /// holding partial UTF-8 sequences
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub raw_tokens: bool,
+    /// Stop the generation as soon as a complete JSON object has been generated. Requires a
+    /// `json` grammar
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub stop_after_tool_call: bool,
+    /// Stop the generation once the probability mass of the end of sequence token over the last
+    /// `eos_probability_window` steps exceeds this threshold, for the models that keep writing
+    /// instead of ending the sequence. Finishes with the `eos_probability` finish reason
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        nullable = true,
+        default = "null",
+        example = 1.5
+    )]
+    pub eos_probability_threshold: Option<f32>,
+    /// Number of steps of `eos_probability_threshold`
+    #[serde(default)]
+    #[schema(minimum = 1, maximum = 64, nullable = true, default = "4", example = 4)]
+    pub eos_probability_window: Option<u32>,
+    /// Include the scheduling timeline of the request in the details
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub scheduling: bool,
+    /// Write the generated text to this object (`s3://bucket/key` or `gs://bucket/key`) while it
+    /// is generated, and only return a manifest. The destination must be allowed by the server
+    #[serde(default)]
+    #[schema(
+        nullable = true,
+        default = "null",
+        example = "s3://datasets/synthetic/run-1.txt"
+    )]
+    pub output_destination: Option<String>,
+    /// Batching class of the request. `latency` requests join the running batch after fewer
+    /// decode steps, `throughput` requests (the default) wait for larger batches.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "latency")]
+    pub quality_of_service: Option<QualityOfService>,
+    /// Opt-in normalization of the inputs, e.g. text extracted from PDFs. What was changed is
+    /// reported in the details.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null")]
+    pub normalize_inputs: Option<InputNormalization>,
+    /// Include aggregate statistics of the generated tokens log probabilities in the details,
+    /// e.g. for hallucination risk scoring
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub return_statistics: bool,
+    /// Include the generated text parsed as JSON in `parsed`, once validated against the schema
+    /// of the grammar. Requires a `json` grammar, rejected when streaming
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub return_parsed: bool,
+    /// Named preset of decoding parameters, e.g. `creative` or `precise`, used for the
+    /// parameters the request does not set. The presets are listed by `/info`
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "creative")]
+    pub preset: Option<String>,
+    /// LoRA adapter generating the request, one of the adapters listed by `/info`. The base
+    /// model when unset
+    #[serde(default)]
+    #[schema(
+        nullable = true,
+        default = "null",
+        example = "predibase/customer_support"
+    )]
+    pub adapter_id: Option<String>,
+    /// Weighted blend of the adapters listed by `/info` generating the request, instead of
+    /// `adapter_id`
+    #[serde(default)]
+    #[schema(nullable = true, default = "null")]
+    pub adapter_parameters: Option<AdapterParameters>,
+    /// Vendor extension parameters forwarded as is to the shards, toggling experimental
+    /// backend features without a router release
+    #[serde(default)]
+    #[schema(example = json ! ({"my_kernel": {"enabled": true}}))]
+    pub extensions: std::collections::HashMap<String, serde_json::Value>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, ToSchema)]
+pub(crate) struct InputNormalization {
+    /// Unicode NFC normalization, composing the combining characters
+    #[serde(default)]
+    #[schema(default = "false", example = true)]
+    pub nfc: bool,
+    /// Remove the byte order marks
+    #[serde(default)]
+    #[schema(default = "false", example = true)]
+    pub strip_bom: bool,
+    /// Remove the control characters, except tabs and newlines
+    #[serde(default)]
+    #[schema(default = "false", example = true)]
+    pub strip_control_chars: bool,
+    /// Replace the typographic quotes with ASCII quotes
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub ascii_quotes: bool,
+}
+
+/// What `normalize_inputs` changed in the inputs
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+pub(crate) struct NormalizationReport {
+    #[schema(example = 1)]
+    pub bom_removed: usize,
+    #[schema(example = 0)]
+    pub control_chars_removed: usize,
+    #[schema(example = 4)]
+    pub quotes_replaced: usize,
+    /// Whether NFC normalization changed the inputs
+    #[schema(example = false)]
+    pub nfc_applied: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum QualityOfService {
+    /// Interactive requests, e.g. chat
+    Latency,
+    /// Bulk requests, e.g. summarization
+    #[default]
+    Throughput,
 }
 
 fn default_max_new_tokens() -> Option<u32> {
     Some(100)
 }
 
+fn default_skip_special_tokens() -> bool {
+    true
+}
+
+/// Parameters of a request that sets none of them, with the same defaults as deserialization
+impl Default for GenerateParameters {
+    fn default() -> Self {
+        Self {
+            best_of: None,
+            temperature: None,
+            temperature_schedule: None,
+            repetition_penalty: None,
+            frequency_penalty: None,
+            logit_bias: None,
+            top_k: None,
+            top_p: None,
+            typical_p: None,
+            do_sample: false,
+            max_new_tokens: default_max_new_tokens(),
+            return_full_text: None,
+            stop: Vec::new(),
+            truncate: None,
+            watermark: false,
+            details: false,
+            decoder_input_details: false,
+            seed: None,
+            top_n_tokens: None,
+            grammar: None,
+            skip_special_tokens: default_skip_special_tokens(),
+            raw_tokens: false,
+            stop_after_tool_call: false,
+            eos_probability_threshold: None,
+            eos_probability_window: None,
+            scheduling: false,
+            output_destination: None,
+            quality_of_service: None,
+            normalize_inputs: None,
+            return_statistics: false,
+            return_parsed: false,
+            preset: None,
+            adapter_id: None,
+            adapter_parameters: None,
+            extensions: std::collections::HashMap::new(),
+        }
+    }
+}
+
 fn default_parameters() -> GenerateParameters {
     GenerateParameters {
         best_of: None,
         temperature: None,
+        temperature_schedule: None,
         repetition_penalty: None,
         frequency_penalty: None,
+        logit_bias: None,
         top_k: None,
         top_p: None,
         typical_p: None,
@@ -263,6 +604,21 @@ fn default_parameters() -> GenerateParameters {
         seed: None,
         top_n_tokens: None,
         grammar: None,
+        skip_special_tokens: default_skip_special_tokens(),
+        raw_tokens: false,
+        stop_after_tool_call: false,
+        eos_probability_threshold: None,
+        eos_probability_window: None,
+        scheduling: false,
+        output_destination: None,
+        quality_of_service: None,
+        normalize_inputs: None,
+        return_statistics: false,
+        return_parsed: false,
+        preset: None,
+        adapter_id: None,
+        adapter_parameters: None,
+        extensions: std::collections::HashMap::new(),
     }
 }
 
@@ -313,6 +669,41 @@ pub struct CompletionRequest {
     #[serde(default)]
     #[schema(example = "1.0")]
     pub frequency_penalty: Option<f32>,
+
+    /// Modify the likelihood of specified tokens appearing in the completion. Accepts a JSON object that maps tokens
+    /// (specified by their token ID in the tokenizer) to an associated bias value from -100 to 100. The bias is added
+    /// to the logits generated by the model prior to sampling: -100 bans a token, 100 selects it exclusively.
+    #[serde(default)]
+    #[schema(nullable = true, example = json ! ({"50256": -100.0}))]
+    pub logit_bias: Option<std::collections::HashMap<u32, f32>>,
+
+    /// Echo back the prompt in addition to the completion.
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub echo: bool,
+
+    /// Include the log probabilities of the chosen tokens and of the `logprobs` most likely tokens at each position.
+    /// With `echo`, the log probabilities of the prompt tokens are returned too.
+    #[serde(default)]
+    #[schema(nullable = true, example = 1)]
+    pub logprobs: Option<u32>,
+
+    /// Named preset of decoding parameters, e.g. `creative` or `precise`, used for the parameters the request does
+    /// not set. The presets are listed by `/info`.
+    #[serde(default)]
+    #[schema(nullable = true, example = "creative")]
+    pub preset: Option<String>,
+
+    /// LoRA adapter generating the completion, one of the adapters listed by `/info`. The base model when unset,
+    /// unless `model` is an adapter.
+    #[serde(default)]
+    #[schema(nullable = true, example = "predibase/customer_support")]
+    pub adapter_id: Option<String>,
+
+    /// Weighted blend of the adapters listed by `/info` generating the completion, instead of `adapter_id`.
+    #[serde(default)]
+    #[schema(nullable = true)]
+    pub adapter_parameters: Option<AdapterParameters>,
 }
 
 #[derive(Clone, Deserialize, Serialize, ToSchema, Default)]
@@ -326,16 +717,88 @@ pub(crate) struct Completion {
     pub system_fingerprint: String,
     pub choices: Vec<CompletionComplete>,
     pub usage: Usage,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub safety: Option<SafetyReport>,
+    /// What the server ignored or adjusted in the request
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schema(example = json ! (["`max_new_tokens` was capped from 512 to 64 to meet the deadline"]))]
+    pub warnings: Vec<String>,
 }
 
 #[derive(Clone, Deserialize, Serialize, ToSchema)]
 pub(crate) struct CompletionComplete {
     pub index: u32,
     pub text: String,
-    pub logprobs: Option<Vec<f32>>,
+    pub logprobs: Option<CompletionLogprobs>,
     pub finish_reason: String,
 }
 
+/// OpenAI text completion log probabilities, one entry per token
+#[derive(Clone, Debug, Default, Deserialize, Serialize, ToSchema)]
+pub(crate) struct CompletionLogprobs {
+    pub tokens: Vec<String>,
+    /// `null` for the first prompt token
+    pub token_logprobs: Vec<Option<f32>>,
+    /// The prompt positions only list the prompt token
+    pub top_logprobs: Vec<Option<std::collections::HashMap<String, f32>>>,
+    /// Offset of each token in the text, in characters
+    pub text_offset: Vec<usize>,
+}
+
+impl CompletionLogprobs {
+    /// Log probabilities of the generated tokens, preceded by the prompt tokens with `echo`
+    pub(crate) fn new(details: &Details, echo: bool) -> Self {
+        let mut logprobs = Self::default();
+        let mut offset = 0;
+
+        if echo {
+            for token in &details.prefill {
+                // The first prompt token has no log probability
+                let logprob = Some(token.logprob).filter(|logprob| !logprob.is_nan());
+                let top = logprob.map(|logprob| {
+                    let mut top: std::collections::HashMap<String, f32> = token
+                        .top_tokens
+                        .iter()
+                        .map(|top_token| (top_token.text.clone(), top_token.logprob))
+                        .collect();
+                    top.insert(token.text.clone(), logprob);
+                    top
+                });
+                logprobs.push(&token.text, logprob, top, &mut offset);
+            }
+        }
+
+        for (i, token) in details.tokens.iter().enumerate() {
+            let top = details
+                .top_tokens
+                .get(i)
+                .filter(|top_tokens| !top_tokens.is_empty())
+                .map(|top_tokens| {
+                    top_tokens
+                        .iter()
+                        .map(|top_token| (top_token.text.clone(), top_token.logprob))
+                        .collect()
+                });
+            logprobs.push(&token.text, Some(token.logprob), top, &mut offset);
+        }
+        logprobs
+    }
+
+    fn push(
+        &mut self,
+        text: &str,
+        logprob: Option<f32>,
+        top: Option<std::collections::HashMap<String, f32>>,
+        offset: &mut usize,
+    ) {
+        self.tokens.push(text.to_string());
+        self.token_logprobs.push(logprob);
+        self.top_logprobs.push(top);
+        self.text_offset.push(*offset);
+        *offset += text.chars().count();
+    }
+}
+
 #[derive(Clone, Deserialize, Serialize, ToSchema)]
 pub(crate) struct ChatCompletion {
     pub id: String,
@@ -347,6 +810,28 @@ pub(crate) struct ChatCompletion {
     pub system_fingerprint: String,
     pub choices: Vec<ChatCompletionComplete>,
     pub usage: Usage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_tokens: Option<ChatInputTokens>,
+    /// Indices of the messages dropped to fit the conversation in the context
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dropped_messages: Vec<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub safety: Option<SafetyReport>,
+    /// What the server ignored or adjusted in the request
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schema(example = json ! (["`n` = 2 is not supported, a single choice was generated"]))]
+    pub warnings: Vec<String>,
+}
+
+#[derive(Clone, Deserialize, Serialize, ToSchema)]
+pub(crate) struct ChatInputTokens {
+    /// Number of tokens of the prompt once the chat template and tools are applied
+    #[schema(example = 12)]
+    pub count: u32,
+    /// Token ids of the prompt, with `return_input_token_ids`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = json ! ([1, 4093, 349]))]
+    pub ids: Option<Vec<u32>>,
 }
 
 #[derive(Clone, Deserialize, Serialize, ToSchema)]
@@ -423,9 +908,41 @@ pub(crate) struct Usage {
     pub prompt_tokens: u32,
     pub completion_tokens: u32,
     pub total_tokens: u32,
+    /// Cost estimated from the token prices, if configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 0.0042)]
+    pub estimated_cost: Option<f64>,
+}
+
+impl Usage {
+    pub(crate) fn new(
+        prompt_tokens: u32,
+        completion_tokens: u32,
+        pricing: Option<Pricing>,
+    ) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            estimated_cost: pricing
+                .map(|pricing| pricing.estimate(prompt_tokens, completion_tokens)),
+        }
+    }
+}
+
+impl From<&StreamDetails> for Usage {
+    fn from(details: &StreamDetails) -> Self {
+        Self {
+            prompt_tokens: details.input_length,
+            completion_tokens: details.generated_tokens,
+            total_tokens: details.input_length + details.generated_tokens,
+            estimated_cost: details.estimated_cost,
+        }
+    }
 }
 
 impl ChatCompletion {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         model: String,
         system_fingerprint: String,
@@ -434,6 +951,7 @@ impl ChatCompletion {
         details: Details,
         return_logprobs: bool,
         tool_calls: Option<ToolCall>,
+        pricing: Option<Pricing>,
     ) -> Self {
         Self {
             id: String::new(),
@@ -448,16 +966,22 @@ impl ChatCompletion {
                     content: output,
                     name: None,
                     tool_calls,
+                    priority: None,
+                    parsed: None,
                 },
                 logprobs: return_logprobs
                     .then(|| ChatCompletionLogprobs::from((details.tokens, details.top_tokens))),
                 finish_reason: details.finish_reason.to_string(),
             }],
-            usage: Usage {
-                prompt_tokens: details.prefill.len() as u32,
-                completion_tokens: details.generated_tokens,
-                total_tokens: details.prefill.len() as u32 + details.generated_tokens,
-            },
+            usage: Usage::new(
+                details.prefill.len() as u32,
+                details.generated_tokens,
+                pricing,
+            ),
+            input_tokens: None,
+            dropped_messages: Vec::new(),
+            safety: None,
+            warnings: Vec::new(),
         }
     }
 }
@@ -469,6 +993,9 @@ pub(crate) struct CompletionCompleteChunk {
     pub choices: Vec<CompletionComplete>,
     pub model: String,
     pub system_fingerprint: String,
+    /// Only sent with the last chunk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
 }
 #[derive(Clone, Deserialize, Serialize, ToSchema)]
 pub(crate) struct ChatCompletionChunk {
@@ -480,6 +1007,15 @@ pub(crate) struct ChatCompletionChunk {
     pub model: String,
     pub system_fingerprint: String,
     pub choices: Vec<ChatCompletionChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_tokens: Option<ChatInputTokens>,
+    /// Indices of the messages dropped to fit the conversation in the context. Only sent with
+    /// the first chunk
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dropped_messages: Vec<usize>,
+    /// Only sent with the last chunk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
 }
 
 #[derive(Clone, Deserialize, Serialize, ToSchema)]
@@ -514,6 +1050,10 @@ pub(crate) struct DeltaToolCall {
 pub(crate) struct Function {
     pub name: Option<String>,
     pub arguments: String,
+    /// Arguments completed by this chunk, keyed by name, with `stream_tool_arguments`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>, example = json ! ({"location": "Paris"}))]
+    pub parsed_arguments: Option<serde_json::Map<String, serde_json::Value>>,
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -546,12 +1086,16 @@ impl ChatCompletionChunk {
                         function: Function {
                             name: None,
                             arguments: tc[0].to_string(),
+                            parsed_arguments: None,
                         },
                     }),
                 },
                 logprobs,
                 finish_reason,
             }],
+            input_tokens: None,
+            dropped_messages: Vec::new(),
+            usage: None,
         }
     }
 }
@@ -562,7 +1106,8 @@ pub(crate) struct ChatRequest {
     /// [UNUSED] ID of the model to use. See the model endpoint compatibility table for details on which models work with the Chat API.
     pub model: String,
 
-    /// A list of messages comprising the conversation so far.
+    /// A list of messages comprising the conversation so far, or a string treated as a single user message.
+    #[serde(deserialize_with = "deserialize_messages")]
     #[schema(example = "[{\"role\": \"user\", \"content\": \"What is Deep Learning?\"}]")]
     pub messages: Vec<Message>,
 
@@ -572,14 +1117,14 @@ pub(crate) struct ChatRequest {
     #[schema(example = "1.0")]
     pub frequency_penalty: Option<f32>,
 
-    /// UNUSED
     /// Modify the likelihood of specified tokens appearing in the completion. Accepts a JSON object that maps tokens
     /// (specified by their token ID in the tokenizer) to an associated bias value from -100 to 100. Mathematically,
     /// the bias is added to the logits generated by the model prior to sampling. The exact effect will vary per model,
     /// but values between -1 and 1 should decrease or increase likelihood of selection; values like -100 or 100 should
     /// result in a ban or exclusive selection of the relevant token.
     #[serde(default)]
-    pub logit_bias: Option<Vec<f32>>,
+    #[schema(nullable = true, example = json ! ({"50256": -100.0}))]
+    pub logit_bias: Option<std::collections::HashMap<u32, f32>>,
 
     /// Whether to return log probabilities of the output tokens or not. If true, returns the log probabilities of each
     /// output token returned in the content of message.
@@ -637,8 +1182,9 @@ pub(crate) struct ChatRequest {
     pub top_p: Option<f32>,
 
     /// A list of tools the model may call. Currently, only functions are supported as a tool. Use this to provide a list of
-    /// functions the model may generate JSON inputs for.
-    #[serde(default)]
+    /// functions the model may generate JSON inputs for. The tools declared by the server (see `/info`) can be given by
+    /// name, e.g. `["get_weather", {"type": "function", "function": {...}}]`.
+    #[serde(default, deserialize_with = "deserialize_tools")]
     #[schema(nullable = true, example = "null")]
     pub tools: Option<Vec<Tool>>,
 
@@ -650,11 +1196,131 @@ pub(crate) struct ChatRequest {
     )]
     pub tool_prompt: Option<String>,
 
-    /// A specific tool to use. If not provided, the model will default to use any of the tools provided in the tools parameter.
-    #[serde(default)]
+    /// A specific tool to use, `required` to call any of the tools or `none` to ignore them. If not provided (`auto`), the
+    /// model either calls one of the tools or answers in text.
+    #[serde(default = "default_tool_choice")]
     #[schema(nullable = true, example = "null")]
     #[serde(deserialize_with = "deserialize_tool_choice::deserialize")]
     pub tool_choice: Option<ToolType>,
+
+    /// Return the number of tokens of the prompt once the chat template and the tools have been applied.
+    /// Requires a fast tokenizer.
+    #[serde(default)]
+    #[schema(default = "false", example = true)]
+    pub return_input_tokens: bool,
+
+    /// Also return the token ids of the prompt with `return_input_tokens`.
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub return_input_token_ids: bool,
+
+    /// Stop the generation as soon as the tool call JSON object is complete, instead of letting the model
+    /// keep writing until `max_tokens`. Only used with `tools`.
+    #[serde(default)]
+    #[schema(default = "false", example = true)]
+    pub stop_after_tool_call: bool,
+
+    /// Skip the chat template: the prompt is the content of the messages as is. Meant to be used with a `messages`
+    /// string to control the exact prompt text while keeping the chat completion response.
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub raw: bool,
+
+    /// The messages hold a prompt the client already rendered with the chat template, tools included: it is used as
+    /// is, without the chat template nor the `tool_prompt`. The tool calls are still constrained and parsed.
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub prompt_already_templated: bool,
+
+    /// Constrain the answer with a `json` or `regex` grammar, e.g. to extract structured data. Can not be combined
+    /// with `tools`.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub response_format: Option<GrammarType>,
+
+    /// Include the answer parsed as JSON in `message.parsed`, once validated against the schema of the `json`
+    /// `response_format`. Rejected when streaming.
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub return_parsed: bool,
+
+    /// Named preset of decoding parameters, e.g. `creative` or `precise`, used for the parameters the request does
+    /// not set. The presets are listed by `/info`.
+    #[serde(default)]
+    #[schema(nullable = true, example = "creative")]
+    pub preset: Option<String>,
+
+    /// LoRA adapter generating the chat completion, one of the adapters listed by `/info`. The base model when
+    /// unset, unless `model` is an adapter.
+    #[serde(default)]
+    #[schema(nullable = true, example = "predibase/customer_support")]
+    pub adapter_id: Option<String>,
+
+    /// Weighted blend of the adapters listed by `/info` generating the chat completion, instead of
+    /// `adapter_id`.
+    #[serde(default)]
+    #[schema(nullable = true)]
+    pub adapter_parameters: Option<AdapterParameters>,
+
+    /// When streaming tool calls, include the arguments completed by each chunk, keyed by name, in
+    /// `tool_calls.function.parsed_arguments`, to render them before the end of the call.
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub stream_tool_arguments: bool,
+}
+
+/// `messages` may be a plain string, treated as a single user message
+fn deserialize_messages<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Message>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Messages {
+        Prompt(String),
+        Messages(Vec<Message>),
+    }
+
+    Ok(match Messages::deserialize(deserializer)? {
+        Messages::Prompt(content) => vec![Message {
+            role: "user".to_string(),
+            content: Some(content),
+            name: None,
+            tool_calls: None,
+            priority: None,
+            parsed: None,
+        }],
+        Messages::Messages(messages) => messages,
+    })
+}
+
+/// Tools may be given by name, resolved from the tools declared by the server
+fn deserialize_tools<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<Tool>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ToolOrName {
+        Name(String),
+        Tool(Tool),
+    }
+
+    let tools: Option<Vec<ToolOrName>> = Option::deserialize(deserializer)?;
+    Ok(tools.map(|tools| {
+        tools
+            .into_iter()
+            .map(|tool| match tool {
+                // Without parameters, the tool is looked up in the declared tools
+                ToolOrName::Name(name) => Tool {
+                    r#type: "function".to_string(),
+                    function: FunctionDefinition {
+                        name,
+                        ..Default::default()
+                    },
+                },
+                ToolOrName::Tool(tool) => tool,
+            })
+            .collect()
+    }))
 }
 
 fn default_tool_prompt() -> Option<String> {
@@ -662,10 +1328,17 @@ fn default_tool_prompt() -> Option<String> {
         "\nBased on the conversation, please choose the most appropriate tool to use: ".to_string(),
     )
 }
+
+fn default_tool_choice() -> Option<ToolType> {
+    Some(ToolType::Auto)
+}
+
 #[derive(Clone, Deserialize, ToSchema, Serialize)]
 enum ToolType {
     FunctionName(String),
     OneOf,
+    /// Call one of the tools or answer in text
+    Auto,
 }
 
 /// Deserialize the tool choice from the JSON input or from the function name ("none" is allowed but mapped to None)
@@ -684,7 +1357,8 @@ mod deserialize_tool_choice {
         match value {
             Value::String(s) => match s.as_str() {
                 "none" => Ok(None),
-                "auto" => Ok(Some(ToolType::OneOf)),
+                "auto" => Ok(Some(ToolType::Auto)),
+                "required" => Ok(Some(ToolType::OneOf)),
                 _ => Ok(Some(ToolType::FunctionName(s))),
             },
             Value::Object(map) => {
@@ -698,7 +1372,7 @@ mod deserialize_tool_choice {
                     Err(de::Error::custom("function key not found in tool choice"))
                 }
             }
-            Value::Null => Ok(Some(ToolType::OneOf)),
+            Value::Null => Ok(Some(ToolType::Auto)),
             _ => Err(de::Error::custom("invalid token format")),
         }
     }
@@ -711,6 +1385,31 @@ pub struct Tools {
     properties: Properties,
 }
 
+impl Tools {
+    /// JSON schema of a call to one of `tools`
+    pub(crate) fn new(tools: &[Tool]) -> Self {
+        let functions: std::collections::HashMap<String, serde_json::Value> = tools
+            .iter()
+            .map(|tool| {
+                let func = tool.function.clone();
+                (func.name, func.parameters)
+            })
+            .collect();
+
+        Self {
+            functions_map: FunctionsMap { functions },
+            properties: Properties {
+                function: tools
+                    .iter()
+                    .map(|tool| FunctionRef {
+                        ref_path: format!("#/$functions/{}", tool.function.name.clone()),
+                    })
+                    .collect(),
+            },
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct FunctionsMap {
     #[serde(rename = "$functions")]
@@ -744,6 +1443,9 @@ pub(crate) struct FunctionDefinition {
     #[serde(default)]
     pub description: Option<String>,
     pub name: String,
+    /// JSON schema of the arguments. Can be omitted for the tools of the `--tools-file`,
+    /// referenced by name.
+    #[serde(default)]
     pub parameters: serde_json::Value,
 }
 
@@ -783,21 +1485,53 @@ pub(crate) struct Message {
     pub name: Option<String>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub tool_calls: Option<ToolCall>,
+    /// Truncation hint: when the conversation exceeds the context, the turns with the lowest
+    /// priority are dropped first, then the oldest ones. A user message is dropped with its
+    /// answers. The latest message is always kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 10)]
+    pub priority: Option<i32>,
+    /// Answer parsed as JSON, with `return_parsed`
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>, nullable = true, example = json ! ({"location": "Paris"}))]
+    pub parsed: Option<serde_json::Value>,
 }
 
 #[derive(Clone, Debug, Deserialize, ToSchema)]
 pub(crate) struct GenerateRequest {
     #[schema(example = "My name is Olivier and I")]
     pub inputs: String,
-    #[serde(default = "default_parameters")]
+    #[serde(
+        default = "default_parameters",
+        deserialize_with = "warnings::deserialize_parameters"
+    )]
+    pub parameters: GenerateParameters,
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct GenerateSamplesRequest {
+    #[schema(example = "My name is Olivier and I")]
+    pub inputs: String,
+    /// Generation parameters shared by all samples. `seed` is used as the base seed from which
+    /// the seed of each sample is derived.
+    #[serde(
+        default = "default_parameters",
+        deserialize_with = "warnings::deserialize_parameters"
+    )]
     pub parameters: GenerateParameters,
+    /// Number of independent samples to generate
+    #[schema(exclusive_minimum = 0, example = 4)]
+    pub n: usize,
 }
 
 #[derive(Clone, Debug, Deserialize, ToSchema)]
 pub(crate) struct CompatGenerateRequest {
     #[schema(example = "My name is Olivier and I")]
     pub inputs: String,
-    #[serde(default = "default_parameters")]
+    #[serde(
+        default = "default_parameters",
+        deserialize_with = "warnings::deserialize_parameters"
+    )]
     pub parameters: GenerateParameters,
     #[serde(default)]
     #[schema(default = "false")]
@@ -813,28 +1547,39 @@ impl From<CompatGenerateRequest> for GenerateRequest {
     }
 }
 
-#[derive(Debug, Serialize, ToSchema)]
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone)]
 pub struct PrefillToken {
     #[schema(example = 0)]
     id: u32,
     #[schema(example = "test")]
     text: String,
+    #[serde(deserialize_with = "deserialize_logprob")]
     #[schema(nullable = true, example = - 0.34)]
     logprob: f32,
+    /// Alternatives considered at this prompt position, with `top_n_tokens` and
+    /// `decoder_input_details`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    top_tokens: Vec<Token>,
 }
 
-#[derive(Debug, Serialize, ToSchema, Clone)]
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone)]
 pub struct Token {
     #[schema(example = 0)]
     id: u32,
     #[schema(example = "test")]
     text: String,
+    #[serde(deserialize_with = "deserialize_logprob")]
     #[schema(nullable = true, example = - 0.34)]
     logprob: f32,
     #[schema(example = "false")]
     special: bool,
 }
 
+/// NaN logprobs are serialized as null
+fn deserialize_logprob<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+    Ok(Option::<f32>::deserialize(deserializer)?.unwrap_or(f32::NAN))
+}
+
 #[derive(Debug, Serialize, ToSchema)]
 pub struct SimpleToken {
     #[schema(example = 0)]
@@ -847,8 +1592,8 @@ pub struct SimpleToken {
     stop: usize,
 }
 
-#[derive(Serialize, ToSchema)]
-#[serde(rename_all(serialize = "snake_case"))]
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
 #[schema(example = "Length")]
 pub(crate) enum FinishReason {
     #[schema(rename = "length")]
@@ -858,6 +1603,22 @@ pub(crate) enum FinishReason {
     EndOfSequenceToken,
     #[schema(rename = "stop_sequence")]
     StopSequence,
+    /// The end of sequence token was likely enough over the last steps
+    #[schema(rename = "eos_probability")]
+    EosProbability,
+    /// The generation was aborted through the admin API
+    #[schema(rename = "aborted")]
+    Aborted,
+    /// The generation was cancelled by its client
+    #[schema(rename = "cancelled")]
+    Cancelled,
+    /// The generation was ended by the server shutdown while its stream was still open
+    #[schema(rename = "shutdown")]
+    Shutdown,
+    /// The generation was preempted by the scheduler. Running requests are never preempted for
+    /// now
+    #[schema(rename = "preempted")]
+    Preempted,
 }
 
 impl std::fmt::Display for FinishReason {
@@ -866,11 +1627,16 @@ impl std::fmt::Display for FinishReason {
             FinishReason::Length => write!(f, "length"),
             FinishReason::EndOfSequenceToken => write!(f, "eos_token"),
             FinishReason::StopSequence => write!(f, "stop_sequence"),
+            FinishReason::EosProbability => write!(f, "eos_probability"),
+            FinishReason::Aborted => write!(f, "aborted"),
+            FinishReason::Cancelled => write!(f, "cancelled"),
+            FinishReason::Shutdown => write!(f, "shutdown"),
+            FinishReason::Preempted => write!(f, "preempted"),
         }
     }
 }
 
-#[derive(Serialize, ToSchema)]
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
 pub(crate) struct BestOfSequence {
     #[schema(example = "test")]
     pub generated_text: String,
@@ -882,11 +1648,11 @@ pub(crate) struct BestOfSequence {
     pub seed: Option<u64>,
     pub prefill: Vec<PrefillToken>,
     pub tokens: Vec<Token>,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub top_tokens: Vec<Vec<Token>>,
 }
 
-#[derive(Serialize, ToSchema)]
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
 pub(crate) struct Details {
     #[schema(example = "length")]
     pub finish_reason: FinishReason,
@@ -898,8 +1664,232 @@ pub(crate) struct Details {
     pub tokens: Vec<Token>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub best_of_sequences: Option<Vec<BestOfSequence>>,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub top_tokens: Vec<Vec<Token>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scheduling: Option<Scheduling>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speculation: Option<Speculation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retokenization: Option<Retokenization>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normalization: Option<NormalizationReport>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub statistics: Option<GenerationStatistics>,
+    /// Number of prompt tokens that fell outside the sliding attention window of the model: the
+    /// last generated tokens did not attend to them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 12)]
+    pub tokens_outside_window: Option<u32>,
+}
+
+/// How the request went through the queue
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub(crate) struct Scheduling {
+    /// Unix timestamp, in milliseconds, at which the request was admitted in the queue
+    #[schema(example = 1706270835000u64)]
+    pub admitted_at: u64,
+    /// Number of batches created while the request was waiting in the queue
+    #[schema(example = 2)]
+    pub batching_cycles: u32,
+    /// Id of the batch the request joined
+    #[schema(example = 42)]
+    pub batch_id: u64,
+    /// Number of times the request was preempted. Running requests are never preempted for
+    /// now
+    #[schema(example = 0)]
+    pub preemptions: u32,
+}
+
+impl Scheduling {
+    pub(crate) fn new(queued: Instant, batching_cycles: u32, batch_id: u64) -> Self {
+        let admitted_at = SystemTime::now() - queued.elapsed();
+        Self {
+            admitted_at: admitted_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            batching_cycles,
+            batch_id,
+            preemptions: 0,
+        }
+    }
+}
+
+/// Speculative decoding of the request
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub(crate) struct Speculation {
+    /// Speculator proposing the tokens: `n-gram` or the Medusa model id
+    #[schema(nullable = true, example = "n-gram")]
+    pub speculator: Option<String>,
+    /// Number of speculative tokens verified by the model
+    #[schema(example = 20)]
+    pub proposed_tokens: u32,
+    /// Number of speculative tokens accepted by the model
+    #[schema(example = 12)]
+    pub accepted_tokens: u32,
+    #[schema(example = 0.6)]
+    pub acceptance_rate: f32,
+}
+
+impl Speculation {
+    /// Speculation of a request generating `generated_tokens` tokens in `generations` forward
+    /// passes, each of them proposing `speculate` tokens verified by the next one
+    pub(crate) fn new(
+        speculator: Option<String>,
+        speculate: u32,
+        generated_tokens: u32,
+        generations: u32,
+    ) -> Self {
+        let proposed_tokens = speculate * generations.saturating_sub(1);
+        // The tokens following a stop sequence are not returned
+        let accepted_tokens = generated_tokens
+            .saturating_sub(generations)
+            .min(proposed_tokens);
+        let acceptance_rate = match proposed_tokens {
+            0 => 0.0,
+            _ => accepted_tokens as f32 / proposed_tokens as f32,
+        };
+        Self {
+            speculator,
+            proposed_tokens,
+            accepted_tokens,
+            acceptance_rate,
+        }
+    }
+}
+
+/// Consistency check of the generated tokens with the re-tokenization of the generated text.
+/// A mismatch flags a detokenization drift, e.g. a tokenizer not matching the model.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, ToSchema)]
+pub(crate) struct Retokenization {
+    /// Whether the generated text re-tokenizes to the generated tokens
+    #[schema(example = true)]
+    pub consistent: bool,
+    /// Number of tokens of the re-tokenized text
+    #[schema(example = 20)]
+    pub retokenized_tokens: u32,
+    /// Index of the first generated token differing from the re-tokenization
+    #[schema(nullable = true, example = "null")]
+    pub first_mismatch: Option<u32>,
+}
+
+impl Retokenization {
+    /// Compare the ids of the generated (non special) tokens with the ids of the re-tokenized
+    /// text
+    pub(crate) fn new(generated_ids: &[u32], retokenized_ids: &[u32]) -> Self {
+        let first_mismatch = generated_ids
+            .iter()
+            .zip(retokenized_ids)
+            .position(|(generated, retokenized)| generated != retokenized)
+            .or_else(|| {
+                (generated_ids.len() != retokenized_ids.len())
+                    .then(|| generated_ids.len().min(retokenized_ids.len()))
+            })
+            .map(|index| index as u32);
+        Self {
+            consistent: first_mismatch.is_none(),
+            retokenized_tokens: retokenized_ids.len() as u32,
+            first_mismatch,
+        }
+    }
+}
+
+/// Log probability under which a generated token is counted as low confidence: ln(0.1)
+const LOW_CONFIDENCE_LOGPROB: f32 = -std::f32::consts::LN_10;
+
+/// Aggregate statistics of the generated tokens log probabilities
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, ToSchema)]
+pub(crate) struct GenerationStatistics {
+    /// Mean log probability of the generated tokens
+    #[schema(example = -0.42)]
+    pub mean_logprob: f32,
+    /// Mean entropy (in nats) of the generated positions, computed over the `top_n_tokens`
+    /// alternatives. Only returned with `top_n_tokens`
+    #[schema(nullable = true, example = 0.9)]
+    pub mean_entropy: Option<f32>,
+    /// Share of the generated tokens with a probability below 10%
+    #[schema(example = 0.05)]
+    pub low_confidence_ratio: f32,
+}
+
+impl GenerationStatistics {
+    pub(crate) fn new(tokens: &[Token], top_tokens: &[Vec<Token>]) -> Option<Self> {
+        let mut statistics = TokenStatistics::default();
+        for (index, token) in tokens.iter().enumerate() {
+            statistics.add(token, top_tokens.get(index).map_or(&[], Vec::as_slice));
+        }
+        statistics.finish()
+    }
+}
+
+/// Running sums of the generated tokens log probabilities, e.g. while streaming
+#[derive(Debug, Default)]
+pub(crate) struct TokenStatistics {
+    tokens: u32,
+    logprob_sum: f32,
+    low_confidence: u32,
+    entropy_positions: u32,
+    entropy_sum: f32,
+}
+
+impl TokenStatistics {
+    pub(crate) fn add(&mut self, token: &Token, top_tokens: &[Token]) {
+        if !token.logprob.is_finite() {
+            return;
+        }
+        self.tokens += 1;
+        self.logprob_sum += token.logprob;
+        if token.logprob < LOW_CONFIDENCE_LOGPROB {
+            self.low_confidence += 1;
+        }
+        if !top_tokens.is_empty() {
+            self.entropy_positions += 1;
+            self.entropy_sum -= top_tokens
+                .iter()
+                .filter(|token| token.logprob.is_finite())
+                .map(|token| token.logprob.exp() * token.logprob)
+                .sum::<f32>();
+        }
+    }
+
+    pub(crate) fn finish(self) -> Option<GenerationStatistics> {
+        (self.tokens > 0).then(|| GenerationStatistics {
+            mean_logprob: self.logprob_sum / self.tokens as f32,
+            mean_entropy: (self.entropy_positions > 0)
+                .then(|| self.entropy_sum / self.entropy_positions as f32),
+            low_confidence_ratio: self.low_confidence as f32 / self.tokens as f32,
+        })
+    }
+}
+
+/// Page of the generated tokens details
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct DetailsPagination {
+    pub details_offset: Option<usize>,
+    pub details_limit: Option<usize>,
+}
+
+impl DetailsPagination {
+    /// Only keep the requested page of the generated tokens and of their top tokens
+    pub(crate) fn paginate(&self, details: &mut Details) {
+        if self.details_offset.is_none() && self.details_limit.is_none() {
+            return;
+        }
+        let offset = self.details_offset.unwrap_or(0);
+        let limit = self.details_limit.unwrap_or(usize::MAX);
+
+        details.tokens = std::mem::take(&mut details.tokens)
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .collect();
+        details.top_tokens = std::mem::take(&mut details.top_tokens)
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .collect();
+    }
 }
 
 #[derive(Serialize, ToSchema)]
@@ -908,12 +1898,137 @@ pub(crate) struct GenerateResponse {
     pub generated_text: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub details: Option<Details>,
+    /// Manifest of the object the generated text was written to, when the request set an
+    /// `output_destination`. `generated_text` is then empty
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<OutputManifest>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub safety: Option<SafetyReport>,
+    /// Generated text parsed as JSON, with `return_parsed`. Absent when the text does not match
+    /// the schema of the grammar, e.g. when cut by `max_new_tokens`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>, nullable = true, example = json ! ({"location": "Paris"}))]
+    pub parsed: Option<serde_json::Value>,
+    /// What the server ignored or adjusted in the request, e.g. an unknown parameter
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[schema(example = json ! (["unknown parameter `max_token` was ignored"]))]
+    pub warnings: Vec<String>,
+}
+
+/// Personally identifiable information found in the generated text, when the server scans the
+/// responses
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, ToSchema)]
+pub(crate) struct SafetyReport {
+    /// Categories found in the generated text
+    #[schema(example = json ! (["email"]))]
+    pub pii: Vec<PiiCategory>,
+    /// What was done with the detections: `tag`, `mask` or `block`
+    #[schema(example = "mask")]
+    pub action: PiiAction,
+}
+
+/// Object written by a request with an `output_destination`
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct OutputManifest {
+    #[schema(example = "s3://datasets/synthetic/run-1.txt")]
+    pub destination: String,
+    /// Number of parts of the multipart upload
+    #[schema(example = 3)]
+    pub parts: u32,
+    /// Size of the object
+    #[schema(example = 20971520)]
+    pub bytes: u64,
+    #[schema(example = "length")]
+    pub finish_reason: FinishReason,
+    #[schema(example = 1)]
+    pub generated_tokens: u32,
+    #[schema(nullable = true, example = 42)]
+    pub seed: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct GeneratedSample {
+    #[schema(example = "test")]
+    pub generated_text: String,
+    #[schema(nullable = true, example = 42)]
+    pub seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Details>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct GenerateSamplesResponse {
+    pub samples: Vec<GeneratedSample>,
 }
 
 #[derive(Serialize, ToSchema)]
 #[serde(transparent)]
 pub(crate) struct TokenizeResponse(Vec<SimpleToken>);
 
+/// `/tokenize` inputs: a generate request, or chat messages rendered with the chat template first
+#[derive(Deserialize, ToSchema)]
+#[serde(untagged)]
+pub(crate) enum TokenizeRequest {
+    Generate(GenerateRequest),
+    Chat(ChatRequest),
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ChatTokenizeResponse {
+    /// Tokens of the prompt rendered with the chat template, followed by the tools prompt
+    pub tokens: Vec<SimpleToken>,
+    /// Tokens of each message, with the template tokens before its content
+    pub messages: Vec<MessageTokens>,
+}
+
+/// Tokens `start..stop` of a message in the rendered prompt. The tokens after the last message,
+/// e.g. the generation prompt, belong to no message
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+pub(crate) struct MessageTokens {
+    #[schema(example = "user")]
+    pub role: String,
+    #[schema(example = 0)]
+    pub start: usize,
+    #[schema(example = 12)]
+    pub stop: usize,
+}
+
+impl ChatTokenizeResponse {
+    /// Split the `tokens` of the rendered `prompt` at the end of the content of each message. A
+    /// message whose content is not found in the prompt, e.g. rewritten by the template, gets no
+    /// token
+    pub(crate) fn new(prompt: &str, messages: &[Message], tokens: Vec<SimpleToken>) -> Self {
+        // Byte offset of the end of the previous content
+        let mut searched = 0;
+        let mut start = 0;
+        let messages = messages
+            .iter()
+            .map(|message| {
+                if let Some(content) = message.content.as_deref().filter(|c| !c.is_empty()) {
+                    if let Some(found) = prompt[searched..].find(content) {
+                        searched += found + content.len();
+                    }
+                }
+                // The token offsets are in chars
+                let end = prompt[..searched].chars().count();
+                let stop = tokens
+                    .iter()
+                    .position(|token| token.start >= end)
+                    .unwrap_or(tokens.len())
+                    .max(start);
+                let message_tokens = MessageTokens {
+                    role: message.role.clone(),
+                    start,
+                    stop,
+                };
+                start = stop;
+                message_tokens
+            })
+            .collect();
+        Self { tokens, messages }
+    }
+}
+
 #[derive(Serialize, ToSchema)]
 pub(crate) struct StreamDetails {
     #[schema(example = "length")]
@@ -922,6 +2037,25 @@ pub(crate) struct StreamDetails {
     pub generated_tokens: u32,
     #[schema(nullable = true, example = 42)]
     pub seed: Option<u64>,
+    /// Number of prompt tokens
+    #[schema(example = 1)]
+    pub input_length: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speculation: Option<Speculation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retokenization: Option<Retokenization>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub normalization: Option<NormalizationReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub statistics: Option<GenerationStatistics>,
+    /// Number of prompt tokens that fell outside the sliding attention window of the model
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 12)]
+    pub tokens_outside_window: Option<u32>,
+    /// Cost estimated from the token prices, if configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 0.0042)]
+    pub estimated_cost: Option<f64>,
 }
 
 #[derive(Serialize, ToSchema)]
@@ -934,9 +2068,82 @@ pub(crate) struct StreamResponse {
     pub generated_text: Option<String>,
     #[schema(nullable = true, default = "null")]
     pub details: Option<StreamDetails>,
+    /// Sent with the last token, when the server scans the responses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub safety: Option<SafetyReport>,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct ShardStatus {
+    #[schema(example = 0)]
+    pub rank: u32,
+    #[schema(example = true)]
+    pub healthy: bool,
+    /// Last error raised by the shard, or the reason it did not answer the health check
+    #[schema(nullable = true, example = "Decode: CUDA out of memory")]
+    pub last_error: Option<String>,
+    /// Number of restarts seen by the router
+    #[schema(example = 0)]
+    pub restarts: u32,
+    #[schema(nullable = true, example = 3600)]
+    pub uptime_secs: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, ToSchema)]
+pub(crate) struct TokenizerReloadRequest {
+    /// Revision of the model repository, defaults to the revision the router was started with
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "a1b2c3d")]
+    pub revision: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct TokenizerReloadResponse {
+    #[schema(nullable = true, example = "a1b2c3d")]
+    pub revision: Option<String>,
+    /// Whether a fast tokenizer was loaded
+    #[schema(example = true)]
+    pub fast_tokenizer: bool,
+    /// Whether the tokenizer config has a chat template
+    #[schema(example = true)]
+    pub chat_template: bool,
+    #[schema(example = "2.0.0-native-1")]
+    pub system_fingerprint: String,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct AbortResponse {
+    #[schema(example = "4bf92f3577b34da6a3ce929d0e0e4736")]
+    pub request_id: String,
+    /// Whether an in-flight generation was found and aborted
+    #[schema(example = true)]
+    pub aborted: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct ModelObject {
+    #[schema(example = "mistralai/Mistral-7B-Instruct-v0.2")]
+    pub id: String,
+    #[schema(example = "model")]
+    pub object: String,
+    #[schema(example = "1706270835")]
+    pub created: u64,
+    #[schema(example = "text-generation-inference")]
+    pub owned_by: String,
+    /// Model of a LoRA adapter
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "mistralai/Mistral-7B-Instruct-v0.2")]
+    pub parent: Option<String>,
 }
 
 #[derive(Serialize, ToSchema)]
+pub(crate) struct ModelList {
+    #[schema(example = "list")]
+    pub object: String,
+    pub data: Vec<ModelObject>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
 pub(crate) struct ErrorResponse {
     pub error: String,
     pub error_type: String,
@@ -955,6 +2162,215 @@ mod tests {
         Tokenizer::from_file(filename).unwrap()
     }
 
+    #[test]
+    fn test_generate_parameters_default() {
+        let parameters = GenerateParameters::default();
+        let deserialized: GenerateParameters = serde_json::from_str("{}").unwrap();
+        assert_eq!(format!("{parameters:?}"), format!("{deserialized:?}"));
+        assert!(parameters.skip_special_tokens);
+    }
+
+    #[test]
+    fn test_completion_logprobs_echo() {
+        let token = |id: u32, text: &str, logprob: f32| Token {
+            id,
+            text: text.to_string(),
+            logprob,
+            special: false,
+        };
+        let details = Details {
+            finish_reason: FinishReason::Length,
+            generated_tokens: 1,
+            seed: None,
+            prefill: vec![
+                PrefillToken {
+                    id: 0,
+                    text: "Hello".to_string(),
+                    logprob: f32::NAN,
+                    top_tokens: vec![],
+                },
+                PrefillToken {
+                    id: 1,
+                    text: " world".to_string(),
+                    logprob: -2.0,
+                    top_tokens: vec![token(1, " world", -2.0), token(4, " there", -2.5)],
+                },
+            ],
+            tokens: vec![token(2, "!", -0.5)],
+            best_of_sequences: None,
+            top_tokens: vec![vec![token(2, "!", -0.5), token(3, ".", -1.0)]],
+            scheduling: None,
+            speculation: None,
+            retokenization: None,
+            normalization: None,
+            statistics: None,
+            tokens_outside_window: None,
+        };
+
+        let logprobs = CompletionLogprobs::new(&details, true);
+        assert_eq!(logprobs.tokens, vec!["Hello", " world", "!"]);
+        assert_eq!(logprobs.token_logprobs, vec![None, Some(-2.0), Some(-0.5)]);
+        assert_eq!(logprobs.text_offset, vec![0, 5, 11]);
+        assert!(logprobs.top_logprobs[0].is_none());
+        assert_eq!(logprobs.top_logprobs[1].as_ref().unwrap()[" world"], -2.0);
+        assert_eq!(logprobs.top_logprobs[1].as_ref().unwrap()[" there"], -2.5);
+        assert_eq!(logprobs.top_logprobs[2].as_ref().unwrap()["."], -1.0);
+
+        // Without echo, only the generated tokens are returned
+        let logprobs = CompletionLogprobs::new(&details, false);
+        assert_eq!(logprobs.tokens, vec!["!"]);
+        assert_eq!(logprobs.text_offset, vec![0]);
+    }
+
+    #[test]
+    fn test_retokenization() {
+        let retokenization = Retokenization::new(&[1, 2, 3], &[1, 2, 3]);
+        assert!(retokenization.consistent);
+        assert_eq!(retokenization.retokenized_tokens, 3);
+        assert_eq!(retokenization.first_mismatch, None);
+
+        // The text re-tokenizes to other tokens, e.g. merged pieces
+        let retokenization = Retokenization::new(&[1, 2, 3], &[1, 4]);
+        assert!(!retokenization.consistent);
+        assert_eq!(retokenization.first_mismatch, Some(1));
+
+        // The text lost its last tokens
+        let retokenization = Retokenization::new(&[1, 2, 3], &[1, 2]);
+        assert_eq!(retokenization.first_mismatch, Some(2));
+    }
+
+    #[test]
+    fn test_generation_statistics() {
+        let token = |logprob: f32| Token {
+            id: 0,
+            text: String::new(),
+            logprob,
+            special: false,
+        };
+        let tokens = vec![token(-0.5), token(-3.0), token(f32::NAN), token(-1.0)];
+
+        let statistics = GenerationStatistics::new(&tokens, &[]).unwrap();
+        assert_eq!(statistics.mean_logprob, -1.5);
+        assert_eq!(statistics.mean_entropy, None);
+        assert_eq!(statistics.low_confidence_ratio, 1.0 / 3.0);
+
+        // Two equally likely alternatives: ln(2) nats
+        let top_tokens = vec![vec![
+            token(-std::f32::consts::LN_2),
+            token(-std::f32::consts::LN_2),
+        ]];
+        let statistics = GenerationStatistics::new(&tokens, &top_tokens).unwrap();
+        let mean_entropy = statistics.mean_entropy.unwrap();
+        assert!((mean_entropy - std::f32::consts::LN_2).abs() < 1e-6);
+
+        assert_eq!(GenerationStatistics::new(&[], &[]), None);
+    }
+
+    #[test]
+    fn test_speculation() {
+        // 10 tokens in 4 forward passes proposing 3 tokens each: 9 verified, 6 accepted
+        let speculation = Speculation::new(Some("n-gram".to_string()), 3, 10, 4);
+        assert_eq!(speculation.proposed_tokens, 9);
+        assert_eq!(speculation.accepted_tokens, 6);
+        assert!((speculation.acceptance_rate - 6.0 / 9.0).abs() < 1e-6);
+
+        // The speculation of the prefill is never verified
+        let speculation = Speculation::new(None, 3, 1, 1);
+        assert_eq!(speculation.proposed_tokens, 0);
+        assert_eq!(speculation.acceptance_rate, 0.0);
+    }
+
+    #[test]
+    fn test_chat_request_tool_names() {
+        let request: ChatRequest = serde_json::from_str(
+            r#"{"model": "tgi", "messages": "Weather in Paris?", "tools": [
+                "get_weather",
+                {"type": "function", "function": {"name": "search", "parameters": {"type": "object"}}}
+            ]}"#,
+        )
+        .unwrap();
+        // Without a tool choice the model may also answer in text
+        assert!(matches!(request.tool_choice, Some(ToolType::Auto)));
+        let tools = request.tools.unwrap();
+        assert_eq!(tools[0].function.name, "get_weather");
+        assert!(tools[0].function.parameters.is_null());
+        assert_eq!(tools[1].function.name, "search");
+        assert!(tools[1].function.parameters.is_object());
+    }
+
+    #[test]
+    fn test_chat_request_messages_string() {
+        let request: ChatRequest = serde_json::from_str(
+            r#"{"model": "tgi", "messages": "What is Deep Learning?", "raw": true}"#,
+        )
+        .unwrap();
+        assert!(request.raw);
+        assert_eq!(request.messages.len(), 1);
+        assert_eq!(request.messages[0].role, "user");
+        assert_eq!(
+            request.messages[0].content.as_deref(),
+            Some("What is Deep Learning?")
+        );
+
+        let request: ChatRequest = serde_json::from_str(
+            r#"{"model": "tgi", "messages": [{"role": "system", "content": "Be brief"}]}"#,
+        )
+        .unwrap();
+        assert!(!request.raw);
+        assert!(!request.prompt_already_templated);
+        assert_eq!(request.messages[0].role, "system");
+
+        let request: ChatRequest = serde_json::from_str(
+            r#"{"model": "tgi", "messages": "<s>[INST] Hi [/INST]", "prompt_already_templated": true}"#,
+        )
+        .unwrap();
+        assert!(request.prompt_already_templated);
+        assert_eq!(
+            request.messages[0].content.as_deref(),
+            Some("<s>[INST] Hi [/INST]")
+        );
+    }
+
+    #[test]
+    fn test_chat_tokenize_response() {
+        let prompt = "<s>[INST] Be brief Hi [/INST] Hello</s>";
+        // Whitespace pre-tokenization
+        let mut tokens = Vec::new();
+        let mut offset = 0;
+        for word in prompt.split(' ') {
+            tokens.push(SimpleToken {
+                id: tokens.len() as u32,
+                text: word.to_string(),
+                start: offset,
+                stop: offset + word.len(),
+            });
+            offset += word.len() + 1;
+        }
+        let message = |role: &str, content: &str| Message {
+            role: role.to_string(),
+            content: Some(content.to_string()),
+            name: None,
+            tool_calls: None,
+            priority: None,
+            parsed: None,
+        };
+        let messages = vec![
+            message("system", "Be brief"),
+            message("user", "Hi"),
+            message("assistant", "Hello"),
+            message("user", "Not rendered"),
+        ];
+
+        let response = ChatTokenizeResponse::new(prompt, &messages, tokens);
+        let boundaries: Vec<(usize, usize)> = response
+            .messages
+            .iter()
+            .map(|message| (message.start, message.stop))
+            .collect();
+        // `<s>[INST] Be brief` | ` Hi` | ` [/INST] Hello</s>` | nothing
+        assert_eq!(boundaries, vec![(0, 3), (3, 4), (4, 6), (6, 6)]);
+    }
+
     #[test]
     fn test_hub_nested_tokens_tokenizer_config() {
         // this is a subset of the tokenizer.json file
@@ -1007,4 +2423,42 @@ mod tests {
         );
         assert_eq!(config.eos_token, Some("<｜end▁of▁sentence｜>".to_string()));
     }
+
+    #[test]
+    fn test_details_pagination() {
+        let token = |id| Token {
+            id,
+            text: String::new(),
+            logprob: 0.0,
+            special: false,
+        };
+        let mut details = Details {
+            finish_reason: FinishReason::Length,
+            generated_tokens: 5,
+            seed: None,
+            prefill: vec![],
+            tokens: (0..5).map(token).collect(),
+            best_of_sequences: None,
+            top_tokens: (0..5).map(|id| vec![token(id)]).collect(),
+            scheduling: None,
+            speculation: None,
+            retokenization: None,
+            normalization: None,
+            statistics: None,
+            tokens_outside_window: None,
+        };
+
+        DetailsPagination {
+            details_offset: Some(1),
+            details_limit: Some(2),
+        }
+        .paginate(&mut details);
+        assert_eq!(
+            details.tokens.iter().map(|t| t.id).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(details.top_tokens.len(), 2);
+        assert_eq!(details.top_tokens[0][0].id, 1);
+        assert_eq!(details.generated_tokens, 5);
+    }
 }
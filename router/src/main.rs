@@ -1,19 +1,22 @@
 use axum::http::HeaderValue;
 use clap::Parser;
-use hf_hub::api::tokio::{Api, ApiBuilder, ApiRepo};
+use hf_hub::api::tokio::{ApiBuilder, ApiRepo};
 use hf_hub::{Repo, RepoType};
-use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::propagation::{
+    BaggagePropagator, TextMapCompositePropagator, TraceContextPropagator,
+};
 use opentelemetry::sdk::trace;
-use opentelemetry::sdk::trace::Sampler;
 use opentelemetry::sdk::Resource;
 use opentelemetry::{global, KeyValue};
 use opentelemetry_otlp::WithExportConfig;
-use std::fs::File;
-use std::io::BufReader;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::Path;
-use text_generation_client::{ClientError, ShardedClient};
-use text_generation_router::{server, HubModelInfo, HubTokenizerConfig};
+use text_generation_client::{ClientError, ShardInfo, ShardedClient};
+use text_generation_router::{
+    server, AuditKeys, DeclaredTools, DisabledEndpoints, ErrorCatalogs, Experiments, HubModelInfo,
+    ObjectStore, PiiScanner, Presets, StreamTransforms, SystemPromptPolicy, TenantKeys,
+    TokenizerSource, TraceSampler,
+};
 use thiserror::Error;
 use tokenizers::Tokenizer;
 use tower_http::cors::AllowOrigin;
@@ -29,6 +32,8 @@ struct Args {
     max_concurrent_requests: usize,
     #[clap(default_value = "2", long, env)]
     max_best_of: usize,
+    #[clap(default_value = "8", long, env)]
+    max_samples: usize,
     #[clap(default_value = "4", long, env)]
     max_stop_sequences: usize,
     #[clap(default_value = "5", long, env)]
@@ -77,6 +82,162 @@ struct Args {
     messages_api_enabled: bool,
     #[clap(long, env, default_value_t = false)]
     disable_grammar_support: bool,
+    #[clap(long, env)]
+    memory_pressure_threshold: Option<f32>,
+    #[clap(default_value = "1024", long, env)]
+    memory_pressure_max_prefill_tokens: u32,
+    #[clap(long, env, value_delimiter = ',')]
+    baggage_keys: Vec<String>,
+    #[clap(long, env)]
+    hedging_endpoint: Option<String>,
+    #[clap(default_value = "1000", long, env)]
+    hedging_latency_slo: u64,
+    #[clap(default_value = "8", long, env)]
+    hedging_max_concurrent: usize,
+    #[clap(default_value = "0.1", long, env)]
+    hedging_max_ratio: f32,
+    #[clap(long, env)]
+    experiments_config: Option<String>,
+    #[clap(default_value = "0", long, env)]
+    audit_store_size: usize,
+    #[clap(long, env, default_value_t = false)]
+    openai_error_format: bool,
+    #[clap(long, env, value_delimiter = ',')]
+    model_aliases: Vec<String>,
+    #[clap(long, env, default_value_t = false)]
+    enforce_model_name: bool,
+    #[clap(long, env)]
+    circuit_breaker_error_rate: Option<f32>,
+    #[clap(default_value = "20", long, env)]
+    circuit_breaker_window: usize,
+    #[clap(default_value = "30", long, env)]
+    circuit_breaker_cooldown: u64,
+    #[clap(long, env)]
+    max_concurrent_streams_per_client: Option<usize>,
+    #[clap(long, env)]
+    max_request_memory_mb: Option<usize>,
+    #[clap(long, env, default_value_t = false)]
+    no_backend: bool,
+    #[clap(long, env, hide_env_values = true)]
+    audit_encryption_keys: Option<String>,
+    #[clap(long, env)]
+    audit_encryption_keys_file: Option<String>,
+    #[clap(long, env)]
+    sticky_session_window: Option<u64>,
+    #[clap(long, env, value_delimiter = ',')]
+    suppress_tokens: Vec<String>,
+    #[clap(long, env)]
+    tools_file: Option<String>,
+    #[clap(long, env)]
+    completion_time_slo: Option<u64>,
+    #[clap(long, env, default_value_t = false)]
+    retokenization_check: bool,
+    #[clap(long, env, value_delimiter = ',')]
+    output_destination_prefixes: Vec<String>,
+    #[clap(long, env)]
+    shutdown_grace_period: Option<u64>,
+    #[clap(default_value = "4", long, env)]
+    latency_max_waiting_tokens: usize,
+    #[clap(long, env)]
+    alert_webhook_url: Option<String>,
+    #[clap(default_value = "60", long, env)]
+    alert_webhook_interval: u64,
+    #[clap(default_value = "262144", long, env)]
+    max_grammar_size: usize,
+    #[clap(default_value = "1048576", long, env)]
+    max_grammar_regex_size: usize,
+    #[clap(default_value = "32", long, env)]
+    max_grammar_schema_depth: usize,
+    #[clap(long, env)]
+    shadow_tokenizer: Option<String>,
+    #[clap(default_value = "0.01", long, env)]
+    shadow_tokenizer_sample_rate: f32,
+    #[clap(long, env, hide_env_values = true)]
+    abort_api_key: Option<String>,
+    #[clap(long, env, hide_env_values = true)]
+    admin_api_key: Option<String>,
+    #[clap(long, env, value_delimiter = ',')]
+    adapter_allowlist: Vec<String>,
+    #[clap(long, env)]
+    max_chat_top_logprobs: Option<u32>,
+    #[clap(long, env)]
+    max_completions_logprobs: Option<u32>,
+    #[clap(long, env)]
+    max_logprob_payload: Option<u32>,
+    #[clap(default_value = "0", long, env)]
+    incomplete_generation_retries: usize,
+    #[clap(long, env)]
+    pii_action: Option<String>,
+    #[clap(long, env, value_delimiter = ',')]
+    pii_categories: Vec<String>,
+    #[clap(long, env)]
+    vertex_max_body_size: Option<usize>,
+    #[clap(long, env)]
+    vertex_max_instances: Option<usize>,
+    #[clap(long, env)]
+    vertex_max_concurrent_requests: Option<usize>,
+    #[clap(long, env)]
+    invocations_max_body_size: Option<usize>,
+    #[clap(long, env)]
+    invocations_max_concurrent_requests: Option<usize>,
+    #[clap(long, env)]
+    fallback_endpoint: Option<String>,
+    #[clap(long, env)]
+    fallback_model_id: Option<String>,
+    #[clap(long, env)]
+    coalesce_requests: bool,
+    #[clap(long, env)]
+    slo_first_token_ms: Option<u64>,
+    #[clap(long, env)]
+    slo_time_per_token_ms: Option<u64>,
+    #[clap(default_value = "0.99", long, env)]
+    slo_objective: f64,
+    #[clap(long, env)]
+    waiting_room_size: Option<usize>,
+    #[clap(long, env)]
+    tokenization_cache_size_mb: Option<usize>,
+    #[clap(long, env)]
+    input_token_price_per_1k: Option<f64>,
+    #[clap(long, env)]
+    output_token_price_per_1k: Option<f64>,
+    #[clap(long, env, value_delimiter = ',')]
+    disable_endpoints: Vec<String>,
+    #[clap(default_value = "1.0", long, env)]
+    otlp_sample_ratio: f64,
+    #[clap(long, env)]
+    otlp_parent_based_sampling: bool,
+    #[clap(long, env, value_delimiter = ',')]
+    otlp_route_sample_ratios: Vec<String>,
+    #[clap(long, env)]
+    enable_mcp: bool,
+    #[clap(long, env)]
+    error_catalogs_dir: Option<String>,
+    #[clap(long, env)]
+    chaos: bool,
+    #[clap(long, env)]
+    presets_file: Option<String>,
+    #[clap(default_value = "0", long, env)]
+    flight_recorder_size: usize,
+    #[clap(long, env, default_value_t = false)]
+    trust_tenant_header: bool,
+    #[clap(long, env)]
+    tenant_api_keys_file: Option<String>,
+    #[clap(long, env)]
+    rate_limit_requests_per_second: Option<f64>,
+    #[clap(long, env)]
+    rate_limit_tokens_per_minute: Option<u64>,
+    #[clap(default_value = "0", long, env)]
+    debug_capture_size: usize,
+    #[clap(long, env)]
+    stream_transforms_file: Option<String>,
+    #[clap(long, env)]
+    canary_interval: Option<u64>,
+    #[clap(long, env)]
+    template_cache_size_mb: Option<usize>,
+    #[clap(long, env)]
+    system_prompt_outside_window: Option<String>,
+    #[clap(long, env)]
+    metrics_exemplars: bool,
 }
 
 #[tokio::main]
@@ -87,6 +248,7 @@ async fn main() -> Result<(), RouterError> {
     let Args {
         max_concurrent_requests,
         max_best_of,
+        max_samples,
         max_stop_sequences,
         max_top_n_tokens,
         max_input_length,
@@ -111,10 +273,94 @@ async fn main() -> Result<(), RouterError> {
         ngrok_edge,
         messages_api_enabled,
         disable_grammar_support,
+        memory_pressure_threshold,
+        memory_pressure_max_prefill_tokens,
+        baggage_keys,
+        hedging_endpoint,
+        hedging_latency_slo,
+        hedging_max_concurrent,
+        hedging_max_ratio,
+        experiments_config,
+        audit_store_size,
+        openai_error_format,
+        model_aliases,
+        enforce_model_name,
+        circuit_breaker_error_rate,
+        circuit_breaker_window,
+        circuit_breaker_cooldown,
+        max_concurrent_streams_per_client,
+        max_request_memory_mb,
+        no_backend,
+        audit_encryption_keys,
+        audit_encryption_keys_file,
+        sticky_session_window,
+        suppress_tokens,
+        tools_file,
+        completion_time_slo,
+        retokenization_check,
+        output_destination_prefixes,
+        shutdown_grace_period,
+        latency_max_waiting_tokens,
+        alert_webhook_url,
+        alert_webhook_interval,
+        max_grammar_size,
+        max_grammar_regex_size,
+        max_grammar_schema_depth,
+        shadow_tokenizer,
+        shadow_tokenizer_sample_rate,
+        abort_api_key,
+        admin_api_key,
+        adapter_allowlist,
+        max_chat_top_logprobs,
+        max_completions_logprobs,
+        max_logprob_payload,
+        incomplete_generation_retries,
+        pii_action,
+        pii_categories,
+        vertex_max_body_size,
+        vertex_max_instances,
+        vertex_max_concurrent_requests,
+        invocations_max_body_size,
+        invocations_max_concurrent_requests,
+        fallback_endpoint,
+        fallback_model_id,
+        coalesce_requests,
+        slo_first_token_ms,
+        slo_time_per_token_ms,
+        slo_objective,
+        waiting_room_size,
+        tokenization_cache_size_mb,
+        input_token_price_per_1k,
+        output_token_price_per_1k,
+        disable_endpoints,
+        otlp_sample_ratio,
+        otlp_parent_based_sampling,
+        otlp_route_sample_ratios,
+        enable_mcp,
+        error_catalogs_dir,
+        chaos,
+        presets_file,
+        flight_recorder_size,
+        trust_tenant_header,
+        tenant_api_keys_file,
+        rate_limit_requests_per_second,
+        rate_limit_tokens_per_minute,
+        debug_capture_size,
+        stream_transforms_file,
+        canary_interval,
+        template_cache_size_mb,
+        system_prompt_outside_window,
+        metrics_exemplars,
     } = args;
 
     // Launch Tokio runtime
-    init_logging(otlp_endpoint, json_output);
+    let trace_sampler = TraceSampler::new(
+        otlp_sample_ratio,
+        otlp_parent_based_sampling,
+        &otlp_route_sample_ratios,
+    )
+    .map_err(|err| RouterError::ArgumentValidation(format!("Invalid trace sampling: {err}")))?;
+    init_logging(otlp_endpoint, trace_sampler, json_output);
 
     // Validate args
     if max_input_length >= max_total_tokens {
@@ -126,6 +372,226 @@ async fn main() -> Result<(), RouterError> {
         return Err(RouterError::ArgumentValidation(format!("`max_batch_prefill_tokens` must be >= `max_input_length`. Given: {max_batch_prefill_tokens} and {max_input_length}")));
     }
 
+    if let Some(memory_pressure_threshold) = memory_pressure_threshold {
+        if !(0.0..=1.0).contains(&memory_pressure_threshold) {
+            return Err(RouterError::ArgumentValidation(format!("`memory_pressure_threshold` must be between 0.0 and 1.0. Given: {memory_pressure_threshold}")));
+        }
+    }
+
+    if !(0.0..=1.0).contains(&hedging_max_ratio) {
+        return Err(RouterError::ArgumentValidation(format!(
+            "`hedging_max_ratio` must be between 0.0 and 1.0. Given: {hedging_max_ratio}"
+        )));
+    }
+
+    if let Some(error_rate) = circuit_breaker_error_rate {
+        if error_rate <= 0.0 || error_rate > 1.0 {
+            return Err(RouterError::ArgumentValidation(format!(
+                "`circuit_breaker_error_rate` must be > 0.0 and <= 1.0. Given: {error_rate}"
+            )));
+        }
+        if circuit_breaker_window == 0 {
+            return Err(RouterError::ArgumentValidation(
+                "`circuit_breaker_window` must be > 0".to_string(),
+            ));
+        }
+    }
+
+    if max_concurrent_streams_per_client == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`max_concurrent_streams_per_client` must be > 0".to_string(),
+        ));
+    }
+
+    if let Some(rate) = rate_limit_requests_per_second {
+        if rate.is_nan() || rate <= 0.0 {
+            return Err(RouterError::ArgumentValidation(format!(
+                "`rate_limit_requests_per_second` must be > 0. Given: {rate}"
+            )));
+        }
+    }
+
+    if rate_limit_tokens_per_minute == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`rate_limit_tokens_per_minute` must be > 0".to_string(),
+        ));
+    }
+
+    if canary_interval == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`canary_interval` must be > 0".to_string(),
+        ));
+    }
+
+    if template_cache_size_mb == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`template_cache_size_mb` must be > 0".to_string(),
+        ));
+    }
+
+    if max_request_memory_mb == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`max_request_memory_mb` must be > 0".to_string(),
+        ));
+    }
+
+    if !(0.0..=1.0).contains(&shadow_tokenizer_sample_rate) {
+        return Err(RouterError::ArgumentValidation(
+            "`shadow_tokenizer_sample_rate` must be >= 0.0 and <= 1.0".to_string(),
+        ));
+    }
+
+    if sticky_session_window == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`sticky_session_window` must be > 0".to_string(),
+        ));
+    }
+
+    for (name, limit) in [
+        ("vertex_max_body_size", vertex_max_body_size),
+        ("vertex_max_instances", vertex_max_instances),
+        (
+            "vertex_max_concurrent_requests",
+            vertex_max_concurrent_requests,
+        ),
+        ("invocations_max_body_size", invocations_max_body_size),
+        (
+            "invocations_max_concurrent_requests",
+            invocations_max_concurrent_requests,
+        ),
+    ] {
+        if limit == Some(0) {
+            return Err(RouterError::ArgumentValidation(format!(
+                "`{name}` must be > 0"
+            )));
+        }
+    }
+
+    if fallback_endpoint.is_some() != fallback_model_id.is_some() {
+        return Err(RouterError::ArgumentValidation(
+            "`fallback_endpoint` and `fallback_model_id` must be set together".to_string(),
+        ));
+    }
+
+    if !(slo_objective > 0.0 && slo_objective < 1.0) {
+        return Err(RouterError::ArgumentValidation(
+            "`slo_objective` must be > 0 and < 1".to_string(),
+        ));
+    }
+
+    if waiting_room_size == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`waiting_room_size` must be > 0".to_string(),
+        ));
+    }
+    if tokenization_cache_size_mb == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`tokenization_cache_size_mb` must be > 0".to_string(),
+        ));
+    }
+    if [input_token_price_per_1k, output_token_price_per_1k]
+        .into_iter()
+        .flatten()
+        .any(|price| !(price >= 0.0 && price.is_finite()))
+    {
+        return Err(RouterError::ArgumentValidation(
+            "token prices must be >= 0".to_string(),
+        ));
+    }
+    let disabled_endpoints = DisabledEndpoints::new(&disable_endpoints).map_err(|err| {
+        RouterError::ArgumentValidation(format!("Invalid disabled endpoints: {err}"))
+    })?;
+    let error_catalogs = error_catalogs_dir
+        .map(|dir| {
+            ErrorCatalogs::from_dir(Path::new(&dir)).map_err(|err| {
+                RouterError::ArgumentValidation(format!("Invalid error catalogs: {err}"))
+            })
+        })
+        .transpose()?;
+
+    let presets = match presets_file {
+        Some(path) => Presets::from_file(Path::new(&path)).map_err(|err| {
+            RouterError::ArgumentValidation(format!("Invalid presets file: {err}"))
+        })?,
+        None => Presets::default(),
+    };
+
+    let stream_transforms = stream_transforms_file
+        .map(|path| {
+            StreamTransforms::from_file(Path::new(&path)).map_err(|err| {
+                RouterError::ArgumentValidation(format!("Invalid stream transforms file: {err}"))
+            })
+        })
+        .transpose()?;
+
+    let tenant_keys = match tenant_api_keys_file {
+        Some(path) => TenantKeys::from_file(Path::new(&path)).map_err(|err| {
+            RouterError::ArgumentValidation(format!("Invalid tenant API keys file: {err}"))
+        })?,
+        None => TenantKeys::default(),
+    };
+
+    let experiments = match experiments_config {
+        Some(path) => Experiments::from_file(Path::new(&path)).map_err(|err| {
+            RouterError::ArgumentValidation(format!("Invalid experiments config: {err}"))
+        })?,
+        None => Experiments::default(),
+    };
+
+    let declared_tools = match tools_file {
+        Some(_) if disable_grammar_support => {
+            return Err(RouterError::ArgumentValidation(
+                "`tools_file` requires grammar support".to_string(),
+            ));
+        }
+        Some(path) => DeclaredTools::from_file(Path::new(&path))
+            .map_err(|err| RouterError::ArgumentValidation(format!("Invalid tools file: {err}")))?,
+        None => DeclaredTools::default(),
+    };
+
+    let object_store = match output_destination_prefixes.is_empty() {
+        true => None,
+        false => Some(
+            ObjectStore::new(output_destination_prefixes).map_err(|err| {
+                RouterError::ArgumentValidation(format!("Invalid output destinations: {err}"))
+            })?,
+        ),
+    };
+
+    let audit_keys = match (audit_encryption_keys, audit_encryption_keys_file) {
+        (Some(_), Some(_)) => {
+            return Err(RouterError::ArgumentValidation(
+                "`audit_encryption_keys` and `audit_encryption_keys_file` are mutually exclusive"
+                    .to_string(),
+            ));
+        }
+        (Some(keys), None) => Some(AuditKeys::from_value(&keys)),
+        (None, Some(path)) => Some(AuditKeys::from_file(Path::new(&path))),
+        (None, None) => None,
+    }
+    .transpose()
+    .map_err(|err| {
+        RouterError::ArgumentValidation(format!("Invalid audit encryption keys: {err}"))
+    })?;
+    if audit_keys.is_some() && audit_store_size == 0 {
+        tracing::warn!("Audit encryption keys are ignored as the audit store is disabled");
+    }
+
+    let pii_scanner = pii_action
+        .map(|action| PiiScanner::new(&action, &pii_categories))
+        .transpose()
+        .map_err(|err| RouterError::ArgumentValidation(format!("Invalid PII scan: {err}")))?;
+    if pii_scanner.is_none() && !pii_categories.is_empty() {
+        tracing::warn!("`pii_categories` are ignored as `pii_action` is not set");
+    }
+
+    let system_prompt_outside_window = system_prompt_outside_window
+        .map(|policy| policy.parse::<SystemPromptPolicy>())
+        .transpose()
+        .map_err(|err| {
+            RouterError::ArgumentValidation(format!("Invalid system prompt policy: {err}"))
+        })?;
+
     if validation_workers == 0 {
         return Err(RouterError::ArgumentValidation(
             "`validation_workers` must be > 0".to_string(),
@@ -190,16 +656,13 @@ async fn main() -> Result<(), RouterError> {
         None
     };
 
-    // Load tokenizer and model info
-    let (tokenizer, model_info) = if local_model {
-        let tokenizer = Tokenizer::from_file(local_path.join("tokenizer.json")).ok();
-        let model_info = HubModelInfo {
+    // Load model info
+    let model_info = if local_model {
+        HubModelInfo {
             model_id: tokenizer_name.to_string(),
             sha: None,
             pipeline_tag: None,
-        };
-
-        (tokenizer, model_info)
+        }
     } else if let Some(api) = api.clone() {
         let api_repo = api.repo(Repo::with_revision(
             tokenizer_name.to_string(),
@@ -207,21 +670,14 @@ async fn main() -> Result<(), RouterError> {
             revision.clone().unwrap_or_else(|| "main".to_string()),
         ));
 
-        let tokenizer = match api_repo.get("tokenizer.json").await {
-            Ok(tokenizer_filename) => Tokenizer::from_file(tokenizer_filename).ok(),
-            Err(_) => get_base_tokenizer(&api, &api_repo).await,
-        };
-
-        let model_info = get_model_info(&api_repo).await.unwrap_or_else(|| {
+        get_model_info(&api_repo).await.unwrap_or_else(|| {
             tracing::warn!("Could not retrieve model info from the Hugging Face hub.");
             HubModelInfo {
                 model_id: tokenizer_name.to_string(),
                 sha: None,
                 pipeline_tag: None,
             }
-        });
-
-        (tokenizer, model_info)
+        })
     } else {
         // No API and no local model
         return Err(RouterError::ArgumentValidation(
@@ -229,43 +685,41 @@ async fn main() -> Result<(), RouterError> {
         ));
     };
 
-    // Load tokenizer config if found locally, or check if we can get it from the API if needed
-    let tokenizer_config = if let Some(path) = tokenizer_config_path {
-        tracing::info!("Using local tokenizer config from user specified path");
-        HubTokenizerConfig::from_file(&std::path::PathBuf::from(path))
-    } else if local_model {
-        tracing::info!("Using local tokenizer config");
-        HubTokenizerConfig::from_file(&local_path.join("tokenizer_config.json"))
-    } else {
-        match api {
-            Some(api) => {
-                tracing::info!("Using the Hugging Face API to retrieve tokenizer config");
-                let repo = Repo::with_revision(
-                    tokenizer_name.to_string(),
-                    RepoType::Model,
-                    revision.unwrap_or("main".to_string()),
-                );
-                get_tokenizer_config(&api.repo(repo))
-                    .await
-                    .unwrap_or_else(|| {
-                        tracing::warn!(
-                            "Could not retrieve tokenizer config from the Hugging Face hub."
-                        );
-                        HubTokenizerConfig::default()
-                    })
-            }
-            None => {
-                tracing::warn!("Could not find tokenizer config locally and no API specified");
-                HubTokenizerConfig::default()
+    // Load the shadow tokenizer compared with the tokenizer on a sample of the requests
+    let shadow_tokenizer = match shadow_tokenizer {
+        Some(shadow_tokenizer_name) => {
+            let shadow_source =
+                TokenizerSource::new(shadow_tokenizer_name.clone(), None, None, api.clone());
+            match shadow_source.load(false).await {
+                (Some(shadow_tokenizer), _) => {
+                    tracing::info!("Comparing the tokenizer with {shadow_tokenizer_name}");
+                    Some(shadow_tokenizer)
+                }
+                (None, _) => {
+                    return Err(RouterError::ArgumentValidation(format!(
+                        "Could not load the shadow tokenizer {shadow_tokenizer_name}"
+                    )));
+                }
             }
         }
+        None => None,
     };
 
+    // Load tokenizer and tokenizer config
+    let tokenizer_source =
+        TokenizerSource::new(tokenizer_name.clone(), revision, tokenizer_config_path, api);
+    let (tokenizer, tokenizer_config) = tokenizer_source.load(false).await;
+
     if tokenizer.is_none() {
         tracing::warn!("Could not find a fast tokenizer implementation for {tokenizer_name}");
         tracing::warn!("Rust input length validation and truncation is disabled");
     }
 
+    let suppressed_tokens =
+        suppressed_token_ids(&suppress_tokens, tokenizer.as_ref()).map_err(|err| {
+            RouterError::ArgumentValidation(format!("Invalid `suppress_tokens`: {err}"))
+        })?;
+
     // if pipeline-tag == text-generation we default to return_full_text = true
     let compat_return_full_text = match &model_info.pipeline_tag {
         None => {
@@ -275,6 +729,205 @@ async fn main() -> Result<(), RouterError> {
         Some(pipeline_tag) => pipeline_tag.as_str() == "text-generation",
     };
 
+    let (sharded_client, shard_info, max_supported_batch_total_tokens) = if no_backend {
+        // Only serve the routes that do not need the model shards
+        tracing::warn!("Starting without model shards: the generation routes are disabled");
+        let shard_info = ShardInfo {
+            requires_padding: false,
+            dtype: "none".to_string(),
+            device_type: "none".to_string(),
+            window_size: None,
+            speculate: 0,
+            speculator: None,
+            shared_prefill: false,
+            adapters: Vec::new(),
+        };
+        let max_batch_total_tokens = max_batch_total_tokens
+            .unwrap_or(16000.max((max_total_tokens as u32).max(max_batch_prefill_tokens)));
+        (
+            ShardedClient::without_shards(),
+            shard_info,
+            max_batch_total_tokens,
+        )
+    } else {
+        connect_shards(
+            master_shard_uds_path,
+            max_input_length,
+            max_total_tokens,
+            max_batch_prefill_tokens,
+            max_batch_total_tokens,
+            max_batch_size,
+        )
+        .await?
+    };
+
+    // Determine the server port based on the feature and environment variable.
+    let port = if cfg!(feature = "google") {
+        std::env::var("AIP_HTTP_PORT")
+            .map(|aip_http_port| aip_http_port.parse::<u16>().unwrap_or(port))
+            .unwrap_or(port)
+    } else {
+        port
+    };
+
+    let addr = match hostname.parse() {
+        Ok(ip) => SocketAddr::new(ip, port),
+        Err(_) => {
+            tracing::warn!("Invalid hostname, defaulting to 0.0.0.0");
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port)
+        }
+    };
+
+    // Run server
+    server::run(
+        model_info,
+        shard_info,
+        compat_return_full_text,
+        max_concurrent_requests,
+        max_best_of,
+        max_samples,
+        max_stop_sequences,
+        max_top_n_tokens,
+        max_input_length,
+        max_total_tokens,
+        waiting_served_ratio,
+        max_batch_prefill_tokens,
+        max_supported_batch_total_tokens,
+        max_waiting_tokens,
+        max_batch_size,
+        sharded_client,
+        tokenizer,
+        validation_workers,
+        addr,
+        cors_allow_origin,
+        ngrok,
+        ngrok_authtoken,
+        ngrok_edge,
+        tokenizer_config,
+        messages_api_enabled,
+        disable_grammar_support,
+        memory_pressure_threshold,
+        memory_pressure_max_prefill_tokens,
+        baggage_keys,
+        hedging_endpoint,
+        hedging_latency_slo,
+        hedging_max_concurrent,
+        hedging_max_ratio,
+        experiments,
+        audit_store_size,
+        openai_error_format,
+        model_aliases,
+        enforce_model_name,
+        circuit_breaker_error_rate,
+        circuit_breaker_window,
+        circuit_breaker_cooldown,
+        max_concurrent_streams_per_client,
+        max_request_memory_mb,
+        tokenizer_source,
+        no_backend,
+        audit_keys,
+        sticky_session_window,
+        suppressed_tokens,
+        declared_tools,
+        completion_time_slo,
+        retokenization_check,
+        object_store,
+        shutdown_grace_period,
+        latency_max_waiting_tokens,
+        alert_webhook_url,
+        alert_webhook_interval,
+        max_grammar_size,
+        max_grammar_regex_size,
+        max_grammar_schema_depth,
+        shadow_tokenizer,
+        shadow_tokenizer_sample_rate,
+        abort_api_key,
+        admin_api_key,
+        adapter_allowlist,
+        max_chat_top_logprobs,
+        max_completions_logprobs,
+        max_logprob_payload,
+        incomplete_generation_retries,
+        pii_scanner,
+        vertex_max_body_size,
+        vertex_max_instances,
+        vertex_max_concurrent_requests,
+        invocations_max_body_size,
+        invocations_max_concurrent_requests,
+        fallback_endpoint,
+        fallback_model_id,
+        coalesce_requests,
+        slo_first_token_ms,
+        slo_time_per_token_ms,
+        slo_objective,
+        waiting_room_size,
+        tokenization_cache_size_mb,
+        input_token_price_per_1k,
+        output_token_price_per_1k,
+        disabled_endpoints,
+        enable_mcp,
+        error_catalogs,
+        chaos,
+        presets,
+        flight_recorder_size,
+        trust_tenant_header,
+        tenant_keys,
+        rate_limit_requests_per_second,
+        rate_limit_tokens_per_minute,
+        debug_capture_size,
+        stream_transforms,
+        canary_interval,
+        template_cache_size_mb,
+        system_prompt_outside_window,
+        metrics_exemplars,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Resolve the tokens suppressed at sampling, given as ids or as tokens of the vocabulary
+fn suppressed_token_ids(
+    tokens: &[String],
+    tokenizer: Option<&Tokenizer>,
+) -> Result<Vec<u32>, String> {
+    let vocab_size = tokenizer.map(|tokenizer| tokenizer.get_vocab_size(true) as u32);
+    let mut ids = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        let id = match (token.parse::<u32>(), tokenizer) {
+            (Ok(id), _) => id,
+            (Err(_), Some(tokenizer)) => tokenizer
+                .token_to_id(token)
+                .ok_or_else(|| format!("`{token}` is not a token of the vocabulary"))?,
+            (Err(_), None) => {
+                return Err(format!(
+                    "`{token}` can only be resolved with a fast tokenizer, give its id instead"
+                ))
+            }
+        };
+        if let Some(vocab_size) = vocab_size {
+            if id >= vocab_size {
+                return Err(format!(
+                    "token id {id} is out of the vocabulary of {vocab_size} tokens"
+                ));
+            }
+        }
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    Ok(ids)
+}
+
+/// Connect to the model shards and warm them up.
+/// Returns the client, the shards info and the max batch total tokens supported by the shards.
+async fn connect_shards(
+    master_shard_uds_path: String,
+    max_input_length: usize,
+    max_total_tokens: usize,
+    max_batch_prefill_tokens: u32,
+    max_batch_total_tokens: Option<u32>,
+    max_batch_size: Option<usize>,
+) -> Result<(ShardedClient, ShardInfo, u32), RouterError> {
     // Instantiate sharded client from the master unix socket
     let mut sharded_client = ShardedClient::connect_uds(master_shard_uds_path)
         .await
@@ -328,60 +981,14 @@ async fn main() -> Result<(), RouterError> {
     tracing::info!("Setting max batch total tokens to {max_supported_batch_total_tokens}");
     tracing::info!("Connected");
 
-    // Determine the server port based on the feature and environment variable.
-    let port = if cfg!(feature = "google") {
-        std::env::var("AIP_HTTP_PORT")
-            .map(|aip_http_port| aip_http_port.parse::<u16>().unwrap_or(port))
-            .unwrap_or(port)
-    } else {
-        port
-    };
-
-    let addr = match hostname.parse() {
-        Ok(ip) => SocketAddr::new(ip, port),
-        Err(_) => {
-            tracing::warn!("Invalid hostname, defaulting to 0.0.0.0");
-            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port)
-        }
-    };
-
-    // Run server
-    server::run(
-        model_info,
-        shard_info,
-        compat_return_full_text,
-        max_concurrent_requests,
-        max_best_of,
-        max_stop_sequences,
-        max_top_n_tokens,
-        max_input_length,
-        max_total_tokens,
-        waiting_served_ratio,
-        max_batch_prefill_tokens,
-        max_supported_batch_total_tokens,
-        max_waiting_tokens,
-        max_batch_size,
-        sharded_client,
-        tokenizer,
-        validation_workers,
-        addr,
-        cors_allow_origin,
-        ngrok,
-        ngrok_authtoken,
-        ngrok_edge,
-        tokenizer_config,
-        messages_api_enabled,
-        disable_grammar_support,
-    )
-    .await?;
-    Ok(())
+    Ok((sharded_client, shard_info, max_supported_batch_total_tokens))
 }
 
 /// Init logging using env variables LOG_LEVEL and LOG_FORMAT:
 ///     - otlp_endpoint is an optional URL to an Open Telemetry collector
 ///     - LOG_LEVEL may be TRACE, DEBUG, INFO, WARN or ERROR (default to INFO)
 ///     - LOG_FORMAT may be TEXT or JSON (default to TEXT)
-fn init_logging(otlp_endpoint: Option<String>, json_output: bool) {
+fn init_logging(otlp_endpoint: Option<String>, trace_sampler: TraceSampler, json_output: bool) {
     let mut layers = Vec::new();
 
     // STDOUT/STDERR layer
@@ -395,10 +1002,14 @@ fn init_logging(otlp_endpoint: Option<String>, json_output: bool) {
     };
     layers.push(fmt_layer);
 
+    // Propagate the trace context and the baggage to the shards
+    global::set_text_map_propagator(TextMapCompositePropagator::new(vec![
+        Box::new(TraceContextPropagator::new()),
+        Box::new(BaggagePropagator::new()),
+    ]));
+
     // OpenTelemetry tracing layer
     if let Some(otlp_endpoint) = otlp_endpoint {
-        global::set_text_map_propagator(TraceContextPropagator::new());
-
         let tracer = opentelemetry_otlp::new_pipeline()
             .tracing()
             .with_exporter(
@@ -412,7 +1023,7 @@ fn init_logging(otlp_endpoint: Option<String>, json_output: bool) {
                         "service.name",
                         "text-generation-inference.router",
                     )]))
-                    .with_sampler(Sampler::AlwaysOn),
+                    .with_sampler(trace_sampler),
             )
             .install_batch(opentelemetry::runtime::Tokio);
 
@@ -451,50 +1062,6 @@ pub async fn get_model_info(api: &ApiRepo) -> Option<HubModelInfo> {
     }
 }
 
-/// get base tokenizer
-pub async fn get_base_tokenizer(api: &Api, api_repo: &ApiRepo) -> Option<Tokenizer> {
-    let config_filename = api_repo.get("config.json").await.ok()?;
-
-    // Open the file in read-only mode with buffer.
-    let file = File::open(config_filename).ok()?;
-    let reader = BufReader::new(file);
-
-    // Read the JSON contents of the file as an instance of `User`.
-    let config: serde_json::Value = serde_json::from_reader(reader).ok()?;
-
-    if let Some(serde_json::Value::String(base_model_id)) = config.get("base_model_name_or_path") {
-        let api_base_repo = api.repo(Repo::with_revision(
-            base_model_id.to_string(),
-            RepoType::Model,
-            "main".to_string(),
-        ));
-
-        let tokenizer_filename = api_base_repo.get("tokenizer.json").await.ok()?;
-        Tokenizer::from_file(tokenizer_filename).ok()
-    } else {
-        None
-    }
-}
-
-/// get tokenizer_config from the Huggingface Hub
-pub async fn get_tokenizer_config(api_repo: &ApiRepo) -> Option<HubTokenizerConfig> {
-    let tokenizer_config_filename = api_repo.get("tokenizer_config.json").await.ok()?;
-
-    // Open the file in read-only mode with buffer.
-    let file = File::open(tokenizer_config_filename).ok()?;
-    let reader = BufReader::new(file);
-
-    // Read the JSON contents of the file as an instance of 'HubTokenizerConfig'.
-    let tokenizer_config: HubTokenizerConfig = serde_json::from_reader(reader)
-        .map_err(|e| {
-            tracing::warn!("Unable to parse tokenizer config: {}", e);
-            e
-        })
-        .ok()?;
-
-    Some(tokenizer_config)
-}
-
 #[derive(Debug, Error)]
 enum RouterError {
     #[error("Argument validation error: {0}")]
@@ -0,0 +1,437 @@
+/// Experimental Model Context Protocol (MCP) server: JSON-RPC 2.0 requests on `POST /mcp` listing
+/// and calling the `generate` and `chat` tools. The chat tool lets the model call the tools
+/// declared by the server
+use crate::declared_tools::DeclaredTools;
+use crate::openai_batch::{BatchRequestBody, Dispatch};
+use axum::body::HttpBody;
+use axum::response::Response;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+use utoipa::ToSchema;
+
+/// Revisions of the protocol, the latest first
+const PROTOCOL_VERSIONS: [&str; 2] = ["2025-03-26", "2024-11-05"];
+const JSONRPC_VERSION: &str = "2.0";
+
+#[derive(Clone)]
+pub(crate) struct Mcp {
+    /// Run the tool calls through the chat and text completions handlers
+    dispatch: Dispatch,
+    declared_tools: DeclaredTools,
+    model_id: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub(crate) struct JsonRpcRequest {
+    #[schema(example = "2.0")]
+    pub jsonrpc: String,
+    /// Absent for the notifications, which are not answered
+    #[serde(default)]
+    #[schema(value_type = Option<Object>, example = 1)]
+    pub id: Option<Value>,
+    #[schema(example = "tools/call")]
+    pub method: String,
+    #[serde(default)]
+    #[schema(value_type = Object, example = json ! ({"name": "chat", "arguments": {"messages": [{"role": "user", "content": "What is Deep Learning?"}]}}))]
+    pub params: Value,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct JsonRpcResponse {
+    #[schema(example = "2.0")]
+    pub jsonrpc: String,
+    #[schema(value_type = Option<Object>, example = 1)]
+    pub id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<JsonRpcError>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct JsonRpcError {
+    #[schema(example = -32601)]
+    pub code: i32,
+    #[schema(example = "Method not found: `resources/list`")]
+    pub message: String,
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum McpError {
+    #[error("Parse error: {0}")]
+    Parse(String),
+    #[error("Unsupported JSON-RPC version `{0}`")]
+    Version(String),
+    #[error("Method not found: `{0}`")]
+    MethodNotFound(String),
+    #[error("Unknown tool `{0}`")]
+    UnknownTool(String),
+    #[error("Invalid params: {0}")]
+    InvalidParams(String),
+}
+
+impl McpError {
+    /// JSON-RPC error code
+    fn code(&self) -> i32 {
+        match self {
+            McpError::Parse(_) => -32700,
+            McpError::Version(_) => -32600,
+            McpError::MethodNotFound(_) => -32601,
+            McpError::UnknownTool(_) | McpError::InvalidParams(_) => -32602,
+        }
+    }
+}
+
+impl JsonRpcResponse {
+    fn new(id: Value, result: Result<Value, McpError>) -> Self {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(err) => (
+                None,
+                Some(JsonRpcError {
+                    code: err.code(),
+                    message: err.to_string(),
+                }),
+            ),
+        };
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ToolCallParams {
+    name: String,
+    #[serde(default)]
+    arguments: Map<String, Value>,
+}
+
+impl Mcp {
+    pub(crate) fn new(dispatch: Dispatch, declared_tools: DeclaredTools, model_id: String) -> Self {
+        Self {
+            dispatch,
+            declared_tools,
+            model_id,
+        }
+    }
+
+    /// Answer a JSON-RPC message, `None` for the notifications
+    pub(crate) async fn handle(&self, body: &[u8]) -> Option<JsonRpcResponse> {
+        let request: JsonRpcRequest = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(err) => {
+                return Some(JsonRpcResponse::new(
+                    Value::Null,
+                    Err(McpError::Parse(err.to_string())),
+                ))
+            }
+        };
+        let id = request.id.clone()?;
+        metrics::increment_counter!("tgi_mcp_request_count", "method" => method_label(&request.method));
+        Some(JsonRpcResponse::new(id, self.call(request).await))
+    }
+
+    async fn call(&self, request: JsonRpcRequest) -> Result<Value, McpError> {
+        if request.jsonrpc != JSONRPC_VERSION {
+            return Err(McpError::Version(request.jsonrpc));
+        }
+        match request.method.as_str() {
+            "initialize" => {
+                let requested = request
+                    .params
+                    .get("protocolVersion")
+                    .and_then(Value::as_str);
+                // Answer with the revision of the client when supported, the latest otherwise
+                let protocol_version = PROTOCOL_VERSIONS
+                    .into_iter()
+                    .find(|version| Some(*version) == requested)
+                    .unwrap_or(PROTOCOL_VERSIONS[0]);
+                Ok(json!({
+                    "protocolVersion": protocol_version,
+                    "capabilities": {"tools": {}},
+                    "serverInfo": {
+                        "name": "text-generation-inference",
+                        "version": env!("CARGO_PKG_VERSION"),
+                    },
+                }))
+            }
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(json!({ "tools": self.tools() })),
+            "tools/call" => {
+                let params = serde_json::from_value(request.params)
+                    .map_err(|err| McpError::InvalidParams(err.to_string()))?;
+                self.call_tool(params).await
+            }
+            _ => Err(McpError::MethodNotFound(request.method)),
+        }
+    }
+
+    /// Tools of the server, with their JSON schema
+    fn tools(&self) -> Value {
+        let sampling = json!({
+            "max_tokens": {
+                "type": "integer",
+                "minimum": 1,
+                "description": "Maximum number of generated tokens",
+            },
+            "temperature": {"type": "number", "minimum": 0},
+        });
+
+        let mut generate_properties = sampling.as_object().cloned().unwrap_or_default();
+        generate_properties.insert(
+            "prompt".to_string(),
+            json!({"type": "string", "description": "Text to continue"}),
+        );
+
+        let mut chat_properties = sampling.as_object().cloned().unwrap_or_default();
+        chat_properties.insert(
+            "messages".to_string(),
+            json!({
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "role": {"type": "string", "enum": ["system", "user", "assistant"]},
+                        "content": {"type": "string"},
+                    },
+                    "required": ["role", "content"],
+                },
+            }),
+        );
+        chat_properties.insert(
+            "stop".to_string(),
+            json!({
+                "type": "array",
+                "items": {"type": "string"},
+                "description": "Sequences stopping the generation",
+            }),
+        );
+        let mut chat_description = "Answer a conversation with the served model".to_string();
+        let declared = self.declared_tools.names();
+        if !declared.is_empty() {
+            chat_description.push_str(&format!(
+                ". The model may call the tools declared by the server ({}) given in `tools`, \
+                the call is then returned as JSON",
+                declared.join(", ")
+            ));
+            chat_properties.insert(
+                "tools".to_string(),
+                json!({"type": "array", "items": {"type": "string", "enum": declared}}),
+            );
+        }
+
+        json!([
+            {
+                "name": "generate",
+                "description": format!("Continue a text with `{}`", self.model_id),
+                "inputSchema": {
+                    "type": "object",
+                    "properties": generate_properties,
+                    "required": ["prompt"],
+                },
+            },
+            {
+                "name": "chat",
+                "description": chat_description,
+                "inputSchema": {
+                    "type": "object",
+                    "properties": chat_properties,
+                    "required": ["messages"],
+                },
+            },
+        ])
+    }
+
+    /// Run a tool. The generation errors are part of the result, with `isError`
+    async fn call_tool(&self, params: ToolCallParams) -> Result<Value, McpError> {
+        let ToolCallParams {
+            name,
+            mut arguments,
+        } = params;
+        arguments.insert("model".to_string(), Value::String(self.model_id.clone()));
+        arguments.insert("stream".to_string(), Value::Bool(false));
+        let arguments = Value::Object(arguments);
+        let invalid = |err: serde_json::Error| McpError::InvalidParams(err.to_string());
+        let body = match name.as_str() {
+            "generate" => BatchRequestBody::Completions(Box::new(
+                serde_json::from_value(arguments).map_err(invalid)?,
+            )),
+            "chat" => BatchRequestBody::ChatCompletions(Box::new(
+                serde_json::from_value(arguments).map_err(invalid)?,
+            )),
+            _ => return Err(McpError::UnknownTool(name)),
+        };
+
+        let response = (self.dispatch)(body).await;
+        let success = response.status().is_success();
+        let content = read_body(response).await;
+        let text = match success {
+            true => generated_text(&content),
+            false => error_message(&content),
+        };
+        Ok(json!({
+            "content": [{"type": "text", "text": text}],
+            "isError": !success,
+        }))
+    }
+}
+
+/// Label of the method in the metrics, the unknown methods are grouped
+fn method_label(method: &str) -> &'static str {
+    match method {
+        "initialize" => "initialize",
+        "ping" => "ping",
+        "tools/list" => "tools/list",
+        "tools/call" => "tools/call",
+        _ => "other",
+    }
+}
+
+async fn read_body(response: Response) -> Vec<u8> {
+    let mut body = response.into_body();
+    let mut content = Vec::new();
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) => content.extend_from_slice(&chunk),
+            Err(_) => break,
+        }
+    }
+    content
+}
+
+/// Text of the first choice of a chat or text completion, or its tool call as JSON
+fn generated_text(content: &[u8]) -> String {
+    let completion: Value = serde_json::from_slice(content).unwrap_or_default();
+    let choice = &completion["choices"][0];
+    if let Some(text) = choice["text"].as_str() {
+        return text.to_string();
+    }
+    let message = &choice["message"];
+    match &message["tool_calls"] {
+        Value::Null => message["content"].as_str().unwrap_or_default().to_string(),
+        tool_calls => tool_calls.to_string(),
+    }
+}
+
+/// Message of an error response. Rejections are plain text
+fn error_message(content: &[u8]) -> String {
+    match serde_json::from_slice::<Value>(content) {
+        Ok(Value::Object(mut body)) => match body.remove("error") {
+            Some(Value::String(error)) => error,
+            error => {
+                if let Some(error) = error {
+                    body.insert("error".to_string(), error);
+                }
+                Value::Object(body).to_string()
+            }
+        },
+        _ => String::from_utf8_lossy(content).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::IntoResponse;
+    use std::sync::Arc;
+
+    fn mcp() -> Mcp {
+        // Echo the prompt of the text completions, reject the chat completions
+        let dispatch: Dispatch = Arc::new(|body| {
+            Box::pin(async move {
+                match body {
+                    BatchRequestBody::Completions(req) => {
+                        axum::Json(json!({"choices": [{"text": req.prompt}]})).into_response()
+                    }
+                    BatchRequestBody::ChatCompletions(_) => (
+                        axum::http::StatusCode::UNPROCESSABLE_ENTITY,
+                        axum::Json(
+                            json!({"error": "Input validation error", "error_type": "validation"}),
+                        ),
+                    )
+                        .into_response(),
+                }
+            })
+        });
+        Mcp::new(dispatch, DeclaredTools::default(), "gpt2".to_string())
+    }
+
+    async fn handle(body: Value) -> Option<Value> {
+        let response = mcp().handle(body.to_string().as_bytes()).await;
+        response.map(|response| serde_json::to_value(response).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_mcp() {
+        let response = handle(json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "initialize",
+            "params": {"protocolVersion": "2024-11-05"},
+        }))
+        .await
+        .unwrap();
+        assert_eq!(response["result"]["protocolVersion"], "2024-11-05");
+
+        // Notifications are not answered
+        let response = handle(json!({"jsonrpc": "2.0", "method": "notifications/initialized"}));
+        assert!(response.await.is_none());
+
+        let response = handle(json!({"jsonrpc": "2.0", "id": 2, "method": "tools/list"}))
+            .await
+            .unwrap();
+        let tools = response["result"]["tools"].as_array().unwrap();
+        assert_eq!(tools[0]["name"], "generate");
+        assert_eq!(tools[1]["name"], "chat");
+        assert!(tools[1]["inputSchema"]["properties"].get("tools").is_none());
+
+        let response = handle(json!({
+            "jsonrpc": "2.0",
+            "id": 3,
+            "method": "tools/call",
+            "params": {"name": "generate", "arguments": {"prompt": "Hello"}},
+        }))
+        .await
+        .unwrap();
+        assert_eq!(response["result"]["content"][0]["text"], "Hello");
+        assert_eq!(response["result"]["isError"], false);
+
+        let response = handle(json!({
+            "jsonrpc": "2.0",
+            "id": 4,
+            "method": "tools/call",
+            "params": {"name": "chat", "arguments": {"messages": "Hello"}},
+        }))
+        .await
+        .unwrap();
+        assert_eq!(
+            response["result"]["content"][0]["text"],
+            "Input validation error"
+        );
+        assert_eq!(response["result"]["isError"], true);
+
+        let response = handle(json!({
+            "jsonrpc": "2.0",
+            "id": 5,
+            "method": "tools/call",
+            "params": {"name": "search"},
+        }))
+        .await
+        .unwrap();
+        assert_eq!(response["error"]["code"], -32602);
+
+        let response = handle(json!({"jsonrpc": "2.0", "id": 6, "method": "resources/list"}))
+            .await
+            .unwrap();
+        assert_eq!(response["error"]["code"], -32601);
+
+        let response = mcp().handle(b"{").await.unwrap();
+        assert_eq!(response.error.unwrap().code, -32700);
+    }
+}
@@ -0,0 +1,132 @@
+/// Newline-delimited JSON alternative to the Server-Sent Events streams
+use axum::body::{Bytes, HttpBody, StreamBody};
+use axum::http::header::{ACCEPT, CONTENT_LENGTH, CONTENT_TYPE};
+use axum::http::{HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+
+pub(crate) const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Prefix of the comment ending the event streams with the final timings, followed by a JSON
+/// object of the timing headers of the non-streaming responses
+pub(crate) const TRAILER_COMMENT: &str = "trailer ";
+
+/// Middleware re-framing the event streams as one JSON object per line for the clients
+/// sending `Accept: application/x-ndjson`.
+/// The trailer comment becomes a final `{"trailer": {...}}` line. The other comments
+/// (keep-alives) and the OpenAI `[DONE]` event are dropped.
+pub(crate) async fn ndjson_stream<B>(request: Request<B>, next: Next<B>) -> Response {
+    let accepts_ndjson = request
+        .headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(|accept| accept.contains(NDJSON_CONTENT_TYPE))
+        .unwrap_or(false);
+
+    let response = next.run(request).await;
+    let is_stream = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .map(|content_type| content_type.as_bytes().starts_with(b"text/event-stream"))
+        .unwrap_or(false);
+    if !accepts_ndjson || !is_stream {
+        return response;
+    }
+
+    let (mut parts, mut body) = response.into_parts();
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static(NDJSON_CONTENT_TYPE));
+    parts.headers.remove(CONTENT_LENGTH);
+
+    let body = async_stream::stream! {
+        let mut parser = EventParser::default();
+        while let Some(chunk) = body.data().await {
+            match chunk {
+                Ok(chunk) => {
+                    let lines = parser.push(&chunk);
+                    if !lines.is_empty() {
+                        yield Ok(Bytes::from(lines));
+                    }
+                }
+                Err(err) => {
+                    yield Err(err);
+                    break;
+                }
+            }
+        }
+    };
+    Response::from_parts(parts, axum::body::boxed(StreamBody::new(body)))
+}
+
+/// Incremental parser of the Server-Sent Events
+#[derive(Debug, Default)]
+pub(crate) struct EventParser {
+    /// Bytes of the incomplete event
+    buffer: Vec<u8>,
+}
+
+impl EventParser {
+    /// Parse a chunk of the event stream, returning the data of the completed events as
+    /// newline-delimited JSON
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> String {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut lines = String::new();
+        while let Some(end) = self.buffer.windows(2).position(|w| w == b"\n\n") {
+            let event: Vec<u8> = self.buffer.drain(..end + 2).collect();
+            let event = String::from_utf8_lossy(&event);
+
+            let trailer = event.lines().find_map(|line| {
+                let comment = line.strip_prefix(':')?;
+                comment
+                    .strip_prefix(' ')
+                    .unwrap_or(comment)
+                    .strip_prefix(TRAILER_COMMENT)
+            });
+            if let Some(trailer) = trailer {
+                lines.push_str(&format!("{{\"trailer\":{trailer}}}\n"));
+                continue;
+            }
+
+            let data: Vec<&str> = event
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(|data| data.strip_prefix(' ').unwrap_or(data))
+                .collect();
+            if data.is_empty() || data == ["[DONE]"] {
+                continue;
+            }
+            lines.push_str(&data.join("\n"));
+            lines.push('\n');
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_parser() {
+        let mut parser = EventParser::default();
+        // Keep-alive comment
+        assert_eq!(parser.push(b":\n\n"), "");
+        // Events split across chunks
+        assert_eq!(
+            parser.push(b"data: {\"index\":1}\n\ndata:{\"in"),
+            "{\"index\":1}\n"
+        );
+        assert_eq!(
+            parser.push(b"dex\":2}\n\ndata: [DONE]\n\n"),
+            "{\"index\":2}\n"
+        );
+        // Final timings
+        assert_eq!(
+            parser.push(b": trailer {\"x-total-time\":\"12\"}\n\n"),
+            "{\"trailer\":{\"x-total-time\":\"12\"}}\n"
+        );
+        assert!(parser.buffer.is_empty());
+    }
+}
@@ -0,0 +1,23 @@
+/// Router started without model shards (`--no-backend`): only the tokenizer and utility routes
+/// are served
+use crate::ErrorResponse;
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+
+/// Middleware rejecting the generation routes
+pub(crate) async fn reject<B>(_request: Request<B>, _next: Next<B>) -> Response {
+    metrics::increment_counter!("tgi_request_failure", "err" => "no_backend");
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(ErrorResponse {
+            error: "This router was started without model shards and cannot generate".to_string(),
+            error_type: "no_backend".to_string(),
+        }),
+    )
+        .into_response()
+}
+
+/// Health check: there are no shards to check
+pub(crate) async fn health() {}
@@ -0,0 +1,80 @@
+/// Opt-in normalization of the inputs, e.g. text extracted from PDFs or scraped pages
+use crate::{InputNormalization, NormalizationReport};
+use unicode_normalization::UnicodeNormalization;
+
+/// Byte order mark, also found as a zero width no-break space in the middle of the text
+const BOM: char = '\u{feff}';
+
+/// Normalize `inputs` with the enabled `options` and report what was changed
+pub(crate) fn normalize(
+    inputs: String,
+    options: &InputNormalization,
+) -> (String, NormalizationReport) {
+    let mut report = NormalizationReport::default();
+    let mut normalized = String::with_capacity(inputs.len());
+    for c in inputs.chars() {
+        if options.strip_bom && c == BOM {
+            report.bom_removed += 1;
+        } else if options.strip_control_chars && is_stray_control(c) {
+            report.control_chars_removed += 1;
+        } else if let Some(quote) = ascii_quote(c).filter(|_| options.ascii_quotes) {
+            report.quotes_replaced += 1;
+            normalized.push(quote);
+        } else {
+            normalized.push(c);
+        }
+    }
+
+    if options.nfc && !unicode_normalization::is_nfc(&normalized) {
+        normalized = normalized.nfc().collect();
+        report.nfc_applied = true;
+    }
+    (normalized, report)
+}
+
+/// Control characters other than the whitespaces
+fn is_stray_control(c: char) -> bool {
+    c.is_control() && !matches!(c, '\t' | '\n' | '\r')
+}
+
+/// ASCII replacement of the typographic quotes
+fn ascii_quote(c: char) -> Option<char> {
+    match c {
+        '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{201b}' => Some('\''),
+        '\u{201c}' | '\u{201d}' | '\u{201e}' | '\u{201f}' => Some('"'),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let inputs = "\u{feff}\u{201c}Cafe\u{301}\u{201d}\u{0}\tit\u{2019}s\n".to_string();
+
+        // Nothing is changed by default
+        let (normalized, report) = normalize(inputs.clone(), &InputNormalization::default());
+        assert_eq!(normalized, inputs);
+        assert_eq!(report, NormalizationReport::default());
+
+        let options = InputNormalization {
+            nfc: true,
+            strip_bom: true,
+            strip_control_chars: true,
+            ascii_quotes: true,
+        };
+        let (normalized, report) = normalize(inputs, &options);
+        assert_eq!(normalized, "\"Caf\u{e9}\"\tit's\n");
+        assert_eq!(
+            report,
+            NormalizationReport {
+                bom_removed: 1,
+                control_chars_removed: 1,
+                quotes_replaced: 3,
+                nfc_applied: true,
+            }
+        );
+    }
+}
@@ -0,0 +1,817 @@
+/// OpenAI Batch API: JSONL files of chat or text completion requests uploaded to `/v1/files`
+/// and processed in the background by `/v1/batches`, with the low queue priority of batch files
+use crate::batch_files::{self, BATCH_FILE_CONCURRENCY, MAX_BATCH_FILE_LINES};
+use crate::{ChatRequest, CompletionRequest};
+use axum::body::HttpBody;
+use axum::response::Response;
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use opentelemetry::trace::FutureExt;
+use opentelemetry::Context;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use utoipa::ToSchema;
+
+/// Maximum number of uploaded files kept, the oldest evictable ones are evicted first
+const MAX_FILES: usize = 128;
+/// Maximum number of batches kept with their output and error files, the oldest expired ones
+/// are evicted first
+const MAX_BATCHES: usize = 64;
+/// Time (in seconds) the finished batches and the unused uploaded files are kept at least
+const RETENTION_SECS: u64 = 24 * 60 * 60;
+/// Only `purpose` of the uploaded files
+const BATCH_PURPOSE: &str = "batch";
+
+/// Run the body of a request of a batch on its endpoint, e.g. through the chat completions
+/// handler
+pub(crate) type Dispatch =
+    Arc<dyn Fn(BatchRequestBody) -> BoxFuture<'static, Response> + Send + Sync>;
+
+/// Body of a request of a batch
+pub(crate) enum BatchRequestBody {
+    ChatCompletions(Box<ChatRequest>),
+    Completions(Box<CompletionRequest>),
+}
+
+/// Files and batches of the OpenAI Batch API. They belong to the client that created them,
+/// the other clients do not find them
+#[derive(Clone)]
+pub(crate) struct OpenAIBatches {
+    dispatch: Dispatch,
+    files: Arc<Mutex<HashMap<String, StoredFile>>>,
+    batches: Arc<Mutex<HashMap<String, StoredBatch>>>,
+}
+
+struct StoredFile {
+    file: OpenAIFile,
+    content: String,
+    client: String,
+    /// Whether a batch was created from the file: its requests are parsed, evicting it is
+    /// harmless
+    used: bool,
+}
+
+struct StoredBatch {
+    batch: OpenAIBatch,
+    client: String,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct OpenAIFile {
+    #[schema(example = "file-5f0e3c4b2a1d4e6f8a9b0c1d2e3f4a5b")]
+    pub id: String,
+    #[schema(example = "file")]
+    pub object: String,
+    #[schema(example = 1024)]
+    pub bytes: usize,
+    #[schema(example = 1706270835)]
+    pub created_at: u64,
+    #[schema(example = "requests.jsonl")]
+    pub filename: String,
+    /// `batch` for the uploaded input files, `batch_output` for the output and error files
+    #[schema(example = "batch")]
+    pub purpose: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum OpenAIBatchStatus {
+    InProgress,
+    Completed,
+    Cancelling,
+    Cancelled,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, ToSchema)]
+pub(crate) struct BatchRequestCounts {
+    #[schema(example = 100)]
+    pub total: usize,
+    #[schema(example = 95)]
+    pub completed: usize,
+    #[schema(example = 5)]
+    pub failed: usize,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct OpenAIBatch {
+    #[schema(example = "batch_5f0e3c4b2a1d4e6f8a9b0c1d2e3f4a5b")]
+    pub id: String,
+    #[schema(example = "batch")]
+    pub object: String,
+    #[schema(example = "/v1/chat/completions")]
+    pub endpoint: String,
+    #[schema(example = "file-5f0e3c4b2a1d4e6f8a9b0c1d2e3f4a5b")]
+    pub input_file_id: String,
+    #[schema(example = "24h")]
+    pub completion_window: String,
+    pub status: OpenAIBatchStatus,
+    /// File of the successful responses, once the batch completed
+    #[schema(nullable = true, example = "file-6a1f4d5c3b2e5f7a9b0c1d2e3f4a5b6c")]
+    pub output_file_id: Option<String>,
+    /// File of the failed requests, once the batch completed
+    #[schema(nullable = true, example = "null")]
+    pub error_file_id: Option<String>,
+    #[schema(example = 1706270835)]
+    pub created_at: u64,
+    #[schema(example = 1706270835)]
+    pub in_progress_at: u64,
+    #[schema(nullable = true, example = 1706271835)]
+    pub completed_at: Option<u64>,
+    #[schema(nullable = true, example = "null")]
+    pub cancelling_at: Option<u64>,
+    #[schema(nullable = true, example = "null")]
+    pub cancelled_at: Option<u64>,
+    pub request_counts: BatchRequestCounts,
+    #[schema(nullable = true)]
+    pub metadata: Option<Value>,
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct OpenAIBatchRequest {
+    #[schema(example = "file-5f0e3c4b2a1d4e6f8a9b0c1d2e3f4a5b")]
+    pub input_file_id: String,
+    /// `/v1/chat/completions` or `/v1/completions`
+    #[schema(example = "/v1/chat/completions")]
+    pub endpoint: String,
+    /// Accepted for compatibility, the batches are processed as soon as the queue allows
+    #[schema(example = "24h")]
+    pub completion_window: String,
+    #[serde(default)]
+    #[schema(nullable = true)]
+    pub metadata: Option<Value>,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct OpenAIBatchList {
+    #[schema(example = "list")]
+    pub object: String,
+    pub data: Vec<OpenAIBatch>,
+    pub has_more: bool,
+}
+
+/// A line of an input file
+#[derive(Deserialize)]
+struct BatchRequestLine {
+    custom_id: String,
+    method: String,
+    url: String,
+    body: Value,
+}
+
+/// A line of an output or error file
+#[derive(Serialize)]
+struct BatchResponseLine {
+    id: String,
+    custom_id: String,
+    response: Option<BatchResponse>,
+    error: Option<BatchLineError>,
+}
+
+#[derive(Serialize)]
+struct BatchResponse {
+    status_code: u16,
+    request_id: String,
+    body: Value,
+}
+
+#[derive(Serialize)]
+struct BatchLineError {
+    code: String,
+    message: String,
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum OpenAIBatchError {
+    #[error("Invalid multipart upload: {0}")]
+    Multipart(String),
+    #[error("Only the `batch` purpose is supported. Given: {0}")]
+    Purpose(String),
+    #[error("Invalid input file: {0}")]
+    Input(String),
+    #[error(
+        "Only the `/v1/chat/completions` and `/v1/completions` endpoints are supported. Given: {0}"
+    )]
+    Endpoint(String),
+    #[error("Batches support up to {0} requests. Given: {1}")]
+    TooManyLines(usize, usize),
+    #[error("Too many batches in progress, retry later")]
+    TooManyInProgress,
+    #[error("Too many files stored, retry later")]
+    TooManyFiles,
+    #[error("No file found with id {0}")]
+    FileNotFound(String),
+    #[error("No batch found with id {0}")]
+    BatchNotFound(String),
+    #[error("Batch {0} is not in progress and cannot be cancelled")]
+    NotCancellable(String),
+}
+
+impl OpenAIBatchError {
+    pub(crate) fn error_type(&self) -> &str {
+        match self {
+            OpenAIBatchError::Multipart(_)
+            | OpenAIBatchError::Purpose(_)
+            | OpenAIBatchError::Input(_)
+            | OpenAIBatchError::Endpoint(_)
+            | OpenAIBatchError::TooManyLines(_, _) => "validation",
+            OpenAIBatchError::TooManyInProgress | OpenAIBatchError::TooManyFiles => "overloaded",
+            OpenAIBatchError::FileNotFound(_) | OpenAIBatchError::BatchNotFound(_) => "not_found",
+            OpenAIBatchError::NotCancellable(_) => "conflict",
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+impl OpenAIBatches {
+    pub(crate) fn new(dispatch: Dispatch) -> Self {
+        Self {
+            dispatch,
+            files: Arc::new(Mutex::new(HashMap::new())),
+            batches: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Store the file of a `multipart/form-data` upload with `purpose` and `file` fields
+    pub(crate) fn upload(
+        &self,
+        client: &str,
+        content_type: Option<&str>,
+        body: &[u8],
+    ) -> Result<OpenAIFile, OpenAIBatchError> {
+        let fields = multipart_fields(content_type.unwrap_or_default(), body)?;
+        let purpose = fields
+            .iter()
+            .find(|field| field.name == "purpose")
+            .map(|field| String::from_utf8_lossy(&field.data).to_string())
+            .ok_or_else(|| OpenAIBatchError::Multipart("missing `purpose` field".to_string()))?;
+        if purpose != BATCH_PURPOSE {
+            return Err(OpenAIBatchError::Purpose(purpose));
+        }
+        let file = fields
+            .into_iter()
+            .find(|field| field.name == "file")
+            .ok_or_else(|| OpenAIBatchError::Multipart("missing `file` field".to_string()))?;
+        let content = String::from_utf8(file.data)
+            .map_err(|_| OpenAIBatchError::Input("the file must be UTF-8 encoded".to_string()))?;
+        let filename = file.filename.unwrap_or_else(|| "file.jsonl".to_string());
+
+        let mut files = self.files.lock().unwrap();
+        let uploaded = files
+            .values()
+            .filter(|stored| stored.file.purpose == BATCH_PURPOSE)
+            .count();
+        if uploaded >= MAX_FILES {
+            // Only the uploaded files already parsed by a batch or unused for the retention
+            // time are evicted. The output files are evicted with their batch
+            let now = now();
+            let oldest = files
+                .values()
+                .filter(|stored| stored.file.purpose == BATCH_PURPOSE)
+                .filter(|stored| stored.used || stored.file.created_at + RETENTION_SECS <= now)
+                .min_by_key(|stored| stored.file.created_at)
+                .map(|stored| stored.file.id.clone())
+                .ok_or(OpenAIBatchError::TooManyFiles)?;
+            files.remove(&oldest);
+        }
+        Ok(store(&mut files, client, filename, purpose, content))
+    }
+
+    pub(crate) fn file(&self, client: &str, id: &str) -> Result<OpenAIFile, OpenAIBatchError> {
+        self.files
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|stored| stored.client == client)
+            .map(|stored| stored.file.clone())
+            .ok_or_else(|| OpenAIBatchError::FileNotFound(id.to_string()))
+    }
+
+    pub(crate) fn file_content(&self, client: &str, id: &str) -> Result<String, OpenAIBatchError> {
+        self.files
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|stored| stored.client == client)
+            .map(|stored| stored.content.clone())
+            .ok_or_else(|| OpenAIBatchError::FileNotFound(id.to_string()))
+    }
+
+    /// Validate the requests of the input file and process them in the background
+    pub(crate) fn create(
+        &self,
+        client: &str,
+        request: OpenAIBatchRequest,
+    ) -> Result<OpenAIBatch, OpenAIBatchError> {
+        let content = self.file_content(client, &request.input_file_id)?;
+        let requests = parse_requests(&request.endpoint, &content)?;
+
+        let created_at = now();
+        let batch = OpenAIBatch {
+            id: format!("batch_{:032x}", thread_rng().gen::<u128>()),
+            object: "batch".to_string(),
+            endpoint: request.endpoint,
+            input_file_id: request.input_file_id,
+            completion_window: request.completion_window,
+            status: OpenAIBatchStatus::InProgress,
+            output_file_id: None,
+            error_file_id: None,
+            created_at,
+            in_progress_at: created_at,
+            completed_at: None,
+            cancelling_at: None,
+            cancelled_at: None,
+            request_counts: BatchRequestCounts {
+                total: requests.len(),
+                ..Default::default()
+            },
+            metadata: request.metadata,
+        };
+        {
+            let mut batches = self.batches.lock().unwrap();
+            if batches.len() >= MAX_BATCHES {
+                // The results of the finished batches are kept for the retention time, until
+                // their client collects them
+                let now = now();
+                let oldest = batches
+                    .values()
+                    .filter(|stored| {
+                        stored
+                            .batch
+                            .finished_at()
+                            .is_some_and(|finished_at| finished_at + RETENTION_SECS <= now)
+                    })
+                    .min_by_key(|stored| stored.batch.created_at)
+                    .map(|stored| stored.batch.id.clone())
+                    .ok_or(OpenAIBatchError::TooManyInProgress)?;
+                if let Some(evicted) = batches.remove(&oldest) {
+                    let mut files = self.files.lock().unwrap();
+                    for file_id in [evicted.batch.output_file_id, evicted.batch.error_file_id]
+                        .iter()
+                        .flatten()
+                    {
+                        files.remove(file_id);
+                    }
+                }
+            }
+            batches.insert(
+                batch.id.clone(),
+                StoredBatch {
+                    batch: batch.clone(),
+                    client: client.to_string(),
+                },
+            );
+        }
+        if let Some(stored) = self.files.lock().unwrap().get_mut(&batch.input_file_id) {
+            stored.used = true;
+        }
+
+        let batches = self.clone();
+        let id = batch.id.clone();
+        let client = client.to_string();
+        // The requests keep the context of the batch creation, e.g. its tenant
+        tokio::spawn(
+            async move { batches.process(&client, &id, requests).await }
+                .with_context(Context::current()),
+        );
+        Ok(batch)
+    }
+
+    async fn process(&self, client: &str, id: &str, requests: Vec<(String, BatchRequestBody)>) {
+        let mut responses = futures::stream::iter(requests)
+            .map(|(custom_id, body)| {
+                let batches = self.clone();
+                batch_files::in_background(async move {
+                    // The requests not started yet when the batch is cancelled are skipped
+                    if batches.cancelling(id) {
+                        return BatchResponseLine::cancelled(custom_id);
+                    }
+                    let response = (batches.dispatch)(body).await;
+                    BatchResponseLine::new(custom_id, response).await
+                })
+            })
+            .buffered(BATCH_FILE_CONCURRENCY);
+
+        let mut output = String::new();
+        let mut errors = String::new();
+        while let Some(line) = responses.next().await {
+            let success = line.success();
+            // Unwrap is safe here as the line only holds strings, numbers and JSON values
+            let line = serde_json::to_string(&line).unwrap();
+            let file = match success {
+                true => &mut output,
+                false => &mut errors,
+            };
+            file.push_str(&line);
+            file.push('\n');
+            self.update(id, |batch| match success {
+                true => batch.request_counts.completed += 1,
+                false => batch.request_counts.failed += 1,
+            });
+        }
+
+        let store_output = |name: &str, content: String| {
+            (!content.is_empty()).then(|| {
+                store(
+                    &mut self.files.lock().unwrap(),
+                    client,
+                    format!("{id}_{name}.jsonl"),
+                    "batch_output".to_string(),
+                    content,
+                )
+                .id
+            })
+        };
+        let output_file_id = store_output("output", output);
+        let error_file_id = store_output("error", errors);
+        self.update(id, |batch| {
+            batch.output_file_id = output_file_id;
+            batch.error_file_id = error_file_id;
+            match batch.status {
+                OpenAIBatchStatus::Cancelling => {
+                    batch.status = OpenAIBatchStatus::Cancelled;
+                    batch.cancelled_at = Some(now());
+                }
+                _ => {
+                    batch.status = OpenAIBatchStatus::Completed;
+                    batch.completed_at = Some(now());
+                }
+            }
+        });
+        tracing::info!("Batch {id} finished");
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut OpenAIBatch)) {
+        if let Some(stored) = self.batches.lock().unwrap().get_mut(id) {
+            f(&mut stored.batch);
+        }
+    }
+
+    fn cancelling(&self, id: &str) -> bool {
+        self.batches
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|stored| stored.batch.status == OpenAIBatchStatus::Cancelling)
+            .unwrap_or(true)
+    }
+
+    pub(crate) fn batch(&self, client: &str, id: &str) -> Result<OpenAIBatch, OpenAIBatchError> {
+        self.batches
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|stored| stored.client == client)
+            .map(|stored| stored.batch.clone())
+            .ok_or_else(|| OpenAIBatchError::BatchNotFound(id.to_string()))
+    }
+
+    /// Batches of `client`, newest first
+    pub(crate) fn list(&self, client: &str) -> OpenAIBatchList {
+        let mut data: Vec<OpenAIBatch> = self
+            .batches
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|stored| stored.client == client)
+            .map(|stored| stored.batch.clone())
+            .collect();
+        data.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        OpenAIBatchList {
+            object: "list".to_string(),
+            data,
+            has_more: false,
+        }
+    }
+
+    /// Skip the requests of the batch not started yet. The in-flight requests complete.
+    pub(crate) fn cancel(&self, client: &str, id: &str) -> Result<OpenAIBatch, OpenAIBatchError> {
+        let mut batches = self.batches.lock().unwrap();
+        let batch = batches
+            .get_mut(id)
+            .filter(|stored| stored.client == client)
+            .map(|stored| &mut stored.batch)
+            .ok_or_else(|| OpenAIBatchError::BatchNotFound(id.to_string()))?;
+        match batch.status {
+            OpenAIBatchStatus::InProgress => {
+                batch.status = OpenAIBatchStatus::Cancelling;
+                batch.cancelling_at = Some(now());
+                Ok(batch.clone())
+            }
+            OpenAIBatchStatus::Cancelling => Ok(batch.clone()),
+            _ => Err(OpenAIBatchError::NotCancellable(id.to_string())),
+        }
+    }
+}
+
+impl OpenAIBatch {
+    /// Unix timestamp (in seconds) of the completion or cancellation of a finished batch
+    fn finished_at(&self) -> Option<u64> {
+        self.completed_at.or(self.cancelled_at)
+    }
+}
+
+/// Store a file of `client`
+fn store(
+    files: &mut HashMap<String, StoredFile>,
+    client: &str,
+    filename: String,
+    purpose: String,
+    content: String,
+) -> OpenAIFile {
+    let file = OpenAIFile {
+        id: format!("file-{:032x}", thread_rng().gen::<u128>()),
+        object: "file".to_string(),
+        bytes: content.len(),
+        created_at: now(),
+        filename,
+        purpose,
+    };
+    files.insert(
+        file.id.clone(),
+        StoredFile {
+            file: file.clone(),
+            content,
+            client: client.to_string(),
+            used: false,
+        },
+    );
+    file
+}
+
+/// Parse the lines of an input file into the bodies of `endpoint`
+fn parse_requests(
+    endpoint: &str,
+    content: &str,
+) -> Result<Vec<(String, BatchRequestBody)>, OpenAIBatchError> {
+    if endpoint != "/v1/chat/completions" && endpoint != "/v1/completions" {
+        return Err(OpenAIBatchError::Endpoint(endpoint.to_string()));
+    }
+    let requests = content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let invalid = |err: String| OpenAIBatchError::Input(format!("line {}: {err}", i + 1));
+            let line: BatchRequestLine =
+                serde_json::from_str(line).map_err(|err| invalid(err.to_string()))?;
+            if line.method != "POST" {
+                return Err(invalid(format!("unsupported method `{}`", line.method)));
+            }
+            if line.url != endpoint {
+                return Err(invalid(format!(
+                    "`url` must be the endpoint of the batch `{endpoint}`"
+                )));
+            }
+            // The results are written once complete, streaming does not apply
+            let body = match endpoint {
+                "/v1/chat/completions" => {
+                    let mut request: ChatRequest = serde_json::from_value(line.body)
+                        .map_err(|err| invalid(err.to_string()))?;
+                    request.stream = false;
+                    BatchRequestBody::ChatCompletions(Box::new(request))
+                }
+                _ => {
+                    let mut request: CompletionRequest = serde_json::from_value(line.body)
+                        .map_err(|err| invalid(err.to_string()))?;
+                    request.stream = false;
+                    BatchRequestBody::Completions(Box::new(request))
+                }
+            };
+            Ok((line.custom_id, body))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if requests.is_empty() {
+        return Err(OpenAIBatchError::Input(
+            "the file has no request".to_string(),
+        ));
+    }
+    if requests.len() > MAX_BATCH_FILE_LINES {
+        return Err(OpenAIBatchError::TooManyLines(
+            MAX_BATCH_FILE_LINES,
+            requests.len(),
+        ));
+    }
+    Ok(requests)
+}
+
+impl BatchResponseLine {
+    async fn new(custom_id: String, response: Response) -> Self {
+        let status_code = response.status().as_u16();
+        let mut body = response.into_body();
+        let mut content = Vec::new();
+        while let Some(chunk) = body.data().await {
+            match chunk {
+                Ok(chunk) => content.extend_from_slice(&chunk),
+                Err(_) => break,
+            }
+        }
+        // Rejections are plain text
+        let body = serde_json::from_slice(&content)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&content).to_string()));
+        let request_id = format!("{:032x}", thread_rng().gen::<u128>());
+        Self {
+            id: format!("batch_req_{request_id}"),
+            custom_id,
+            response: Some(BatchResponse {
+                status_code,
+                request_id,
+                body,
+            }),
+            error: None,
+        }
+    }
+
+    fn cancelled(custom_id: String) -> Self {
+        Self {
+            id: format!("batch_req_{:032x}", thread_rng().gen::<u128>()),
+            custom_id,
+            response: None,
+            error: Some(BatchLineError {
+                code: "batch_cancelled".to_string(),
+                message: "The batch was cancelled before this request started".to_string(),
+            }),
+        }
+    }
+
+    fn success(&self) -> bool {
+        matches!(&self.response, Some(response) if response.status_code == 200)
+    }
+}
+
+/// A field of a `multipart/form-data` body
+#[derive(Debug)]
+struct MultipartField {
+    name: String,
+    filename: Option<String>,
+    data: Vec<u8>,
+}
+
+/// Fields of a `multipart/form-data` body
+fn multipart_fields(
+    content_type: &str,
+    body: &[u8],
+) -> Result<Vec<MultipartField>, OpenAIBatchError> {
+    let invalid = |message: &str| OpenAIBatchError::Multipart(message.to_string());
+    if !content_type.starts_with("multipart/form-data") {
+        return Err(invalid("the content type must be `multipart/form-data`"));
+    }
+    let boundary = content_type
+        .split(';')
+        .find_map(|param| param.trim().strip_prefix("boundary="))
+        .map(|boundary| boundary.trim_matches('"'))
+        .ok_or_else(|| invalid("missing boundary"))?;
+    let delimiter = format!("--{boundary}").into_bytes();
+
+    let mut fields = Vec::new();
+    let mut rest = match find(body, &delimiter) {
+        Some(start) => &body[start + delimiter.len()..],
+        None => return Err(invalid("missing boundary delimiter")),
+    };
+    // The closing delimiter is followed by `--`
+    while !rest.starts_with(b"--") {
+        let end = find(rest, &delimiter).ok_or_else(|| invalid("unterminated part"))?;
+        let part = rest[..end].strip_prefix(b"\r\n").unwrap_or(&rest[..end]);
+        let part = part.strip_suffix(b"\r\n").unwrap_or(part);
+        let separator = find(part, b"\r\n\r\n").ok_or_else(|| invalid("missing part headers"))?;
+        let headers = String::from_utf8_lossy(&part[..separator]);
+        let disposition = headers
+            .lines()
+            .find_map(|header| {
+                let (name, value) = header.split_once(':')?;
+                name.trim()
+                    .eq_ignore_ascii_case("content-disposition")
+                    .then_some(value)
+            })
+            .ok_or_else(|| invalid("missing part `Content-Disposition`"))?;
+        let param = |key: &str| {
+            disposition.split(';').find_map(|param| {
+                let (name, value) = param.split_once('=')?;
+                (name.trim() == key).then(|| value.trim().trim_matches('"').to_string())
+            })
+        };
+        fields.push(MultipartField {
+            name: param("name").ok_or_else(|| invalid("missing part name"))?,
+            filename: param("filename"),
+            data: part[separator + 4..].to_vec(),
+        });
+        rest = &rest[end + delimiter.len()..];
+    }
+    Ok(fields)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::IntoResponse;
+
+    #[test]
+    fn test_multipart_fields() {
+        let body = b"--xyz\r\n\
+            Content-Disposition: form-data; name=\"purpose\"\r\n\r\n\
+            batch\r\n\
+            --xyz\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"requests.jsonl\"\r\n\
+            Content-Type: application/octet-stream\r\n\r\n\
+            {\"a\": 1}\n{\"b\": 2}\n\r\n\
+            --xyz--\r\n";
+        let fields = multipart_fields("multipart/form-data; boundary=xyz", body).unwrap();
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[0].name, "purpose");
+        assert_eq!(fields[0].filename, None);
+        assert_eq!(fields[0].data, b"batch");
+        assert_eq!(fields[1].name, "file");
+        assert_eq!(fields[1].filename.as_deref(), Some("requests.jsonl"));
+        assert_eq!(fields[1].data, b"{\"a\": 1}\n{\"b\": 2}\n");
+
+        assert!(multipart_fields("application/json", body).is_err());
+        assert!(multipart_fields("multipart/form-data; boundary=abc", body).is_err());
+    }
+
+    fn upload(batches: &OpenAIBatches, client: &str) -> Result<OpenAIFile, OpenAIBatchError> {
+        let body = b"--xyz\r\n\
+            Content-Disposition: form-data; name=\"purpose\"\r\n\r\n\
+            batch\r\n\
+            --xyz\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"requests.jsonl\"\r\n\r\n\
+            {}\n\r\n\
+            --xyz--\r\n";
+        batches.upload(client, Some("multipart/form-data; boundary=xyz"), body)
+    }
+
+    #[test]
+    fn test_files_clients_and_eviction() {
+        let dispatch: Dispatch = Arc::new(|_| Box::pin(async { ().into_response() }));
+        let batches = OpenAIBatches::new(dispatch);
+        let file = upload(&batches, "tenant:a").unwrap();
+        assert_eq!(batches.file_content("tenant:a", &file.id).unwrap(), "{}\n");
+        // The files of the other clients are not found
+        assert!(matches!(
+            batches.file("tenant:b", &file.id),
+            Err(OpenAIBatchError::FileNotFound(_))
+        ));
+        assert!(batches.list("tenant:b").data.is_empty());
+
+        // The unused files are not evicted before the retention time
+        for _ in 1..MAX_FILES {
+            upload(&batches, "tenant:a").unwrap();
+        }
+        assert!(matches!(
+            upload(&batches, "tenant:b"),
+            Err(OpenAIBatchError::TooManyFiles)
+        ));
+        // The files used by a batch are
+        batches
+            .files
+            .lock()
+            .unwrap()
+            .get_mut(&file.id)
+            .unwrap()
+            .used = true;
+        upload(&batches, "tenant:b").unwrap();
+        assert!(batches.file("tenant:a", &file.id).is_err());
+    }
+
+    #[test]
+    fn test_parse_requests() {
+        let content = r#"{"custom_id": "a", "method": "POST", "url": "/v1/chat/completions", "body": {"model": "tgi", "messages": [{"role": "user", "content": "Hi"}], "stream": true}}
+
+{"custom_id": "b", "method": "POST", "url": "/v1/chat/completions", "body": {"model": "tgi", "messages": [{"role": "user", "content": "Bye"}]}}"#;
+        let requests = parse_requests("/v1/chat/completions", content).unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].0, "a");
+        match &requests[0].1 {
+            BatchRequestBody::ChatCompletions(request) => assert!(!request.stream),
+            BatchRequestBody::Completions(_) => panic!("expected a chat request"),
+        }
+
+        assert!(matches!(
+            parse_requests("/v1/embeddings", content),
+            Err(OpenAIBatchError::Endpoint(_))
+        ));
+        assert!(matches!(
+            parse_requests("/v1/completions", content),
+            Err(OpenAIBatchError::Input(_))
+        ));
+        assert!(matches!(
+            parse_requests("/v1/chat/completions", ""),
+            Err(OpenAIBatchError::Input(_))
+        ));
+    }
+}
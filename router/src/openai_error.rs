@@ -0,0 +1,179 @@
+/// OpenAI error envelope for the `/v1/*` routes
+use crate::ErrorResponse;
+use axum::body::HttpBody;
+use axum::http::{header, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub(crate) struct OpenAIErrorResponse {
+    pub error: OpenAIError,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct OpenAIError {
+    pub message: String,
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub param: Option<String>,
+    pub code: Option<String>,
+}
+
+/// Middleware rendering the errors of the `/v1/*` routes in the OpenAI error envelope
+pub(crate) async fn openai_error_envelope<B>(request: Request<B>, next: Next<B>) -> Response {
+    if !request.uri().path().starts_with("/v1/") {
+        return next.run(request).await;
+    }
+
+    let response = next.run(request).await;
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+
+    // Read the TGI error body, keeping the headers set by the handler, e.g. the request id,
+    // the rate limits and the warnings
+    let (mut parts, mut body) = response.into_parts();
+    let mut content = Vec::new();
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) => content.extend_from_slice(&chunk),
+            Err(_) => break,
+        }
+    }
+    let error = serde_json::from_slice::<ErrorResponse>(&content).unwrap_or_else(|_| {
+        // Rejections from the axum extractors are plain text
+        ErrorResponse {
+            error: String::from_utf8_lossy(&content).to_string(),
+            error_type: String::new(),
+        }
+    });
+
+    let (status, error) = openai_error(status, error);
+    parts.status = status;
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    let body = Json(OpenAIErrorResponse { error })
+        .into_response()
+        .into_body();
+    Response::from_parts(parts, body)
+}
+
+/// Map a TGI error to its OpenAI status code and error
+fn openai_error(status: StatusCode, error: ErrorResponse) -> (StatusCode, OpenAIError) {
+    let (status, error_type, code) = match (status, error.error_type.as_str()) {
+        (StatusCode::TOO_MANY_REQUESTS, _) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limit_error",
+            Some("rate_limit_exceeded"),
+        ),
+        (_, "model_not_found") => (
+            StatusCode::NOT_FOUND,
+            "invalid_request_error",
+            Some("model_not_found"),
+        ),
+        (_, "validation" | "template_error") | (StatusCode::UNPROCESSABLE_ENTITY, _) => {
+            (StatusCode::BAD_REQUEST, "invalid_request_error", None)
+        }
+        // The model does not support the request, e.g. chat without a chat template
+        (StatusCode::NOT_IMPLEMENTED, code) => (
+            StatusCode::NOT_IMPLEMENTED,
+            "invalid_request_error",
+            Some(code),
+        ),
+        (status, _) if status.is_server_error() || status == StatusCode::FAILED_DEPENDENCY => {
+            (StatusCode::INTERNAL_SERVER_ERROR, "server_error", None)
+        }
+        (status, _) => (status, "invalid_request_error", None),
+    };
+
+    // Validation errors name the invalid parameter first, e.g. "`temperature` must be ..."
+    let param = match (error_type, code) {
+        (_, Some("model_not_found")) => Some("model".to_string()),
+        ("invalid_request_error", _) => error
+            .error
+            .split('`')
+            .nth(1)
+            .filter(|param| !param.is_empty() && !param.contains(' '))
+            .map(String::from),
+        _ => None,
+    };
+
+    (
+        status,
+        OpenAIError {
+            message: error.error,
+            error_type: error_type.to_string(),
+            param,
+            code: code.map(String::from),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(error: &str, error_type: &str) -> ErrorResponse {
+        ErrorResponse {
+            error: error.to_string(),
+            error_type: error_type.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_openai_error() {
+        let (status, err) = openai_error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            error(
+                "Input validation error: `temperature` must be strictly positive",
+                "validation",
+            ),
+        );
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(err.error_type, "invalid_request_error");
+        assert_eq!(err.param, Some("temperature".to_string()));
+        assert_eq!(err.code, None);
+
+        let (status, err) = openai_error(
+            StatusCode::TOO_MANY_REQUESTS,
+            error("Model is overloaded", "overloaded"),
+        );
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(err.error_type, "rate_limit_error");
+        assert_eq!(err.code, Some("rate_limit_exceeded".to_string()));
+
+        let (status, err) = openai_error(
+            StatusCode::FAILED_DEPENDENCY,
+            error("Request failed during generation", "generation"),
+        );
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(err.error_type, "server_error");
+        assert_eq!(err.param, None);
+
+        let (status, err) = openai_error(
+            StatusCode::NOT_FOUND,
+            error("The model `gpt-4` does not exist", "model_not_found"),
+        );
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(err.param, Some("model".to_string()));
+        assert_eq!(err.code, Some("model_not_found".to_string()));
+
+        let (status, err) = openai_error(
+            StatusCode::NOT_IMPLEMENTED,
+            error(
+                "Chat is not supported: the tokenizer config of the model has no chat template",
+                "chat_template_not_found",
+            ),
+        );
+        assert_eq!(status, StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(err.error_type, "invalid_request_error");
+        assert_eq!(err.param, None);
+        assert_eq!(err.code, Some("chat_template_not_found".to_string()));
+    }
+}
@@ -0,0 +1,560 @@
+/// Opt-in scan of the generated texts for personally identifiable information (PII): the
+/// detections are reported in the `safety` field of the responses, masked or blocked. The
+/// streamed tokens are masked on the fly: the text that may still be part of a detection is
+/// held back until the next tokens
+use crate::{SafetyReport, Token};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PiiCategory {
+    Email,
+    Phone,
+    CreditCard,
+    NationalId,
+}
+
+impl PiiCategory {
+    const ALL: [PiiCategory; 4] = [
+        PiiCategory::Email,
+        PiiCategory::Phone,
+        PiiCategory::CreditCard,
+        PiiCategory::NationalId,
+    ];
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            PiiCategory::Email => "email",
+            PiiCategory::Phone => "phone",
+            PiiCategory::CreditCard => "credit_card",
+            PiiCategory::NationalId => "national_id",
+        }
+    }
+
+    /// Placeholder of the masked detections
+    fn mask(&self) -> &'static str {
+        match self {
+            PiiCategory::Email => "[EMAIL]",
+            PiiCategory::Phone => "[PHONE]",
+            PiiCategory::CreditCard => "[CREDIT_CARD]",
+            PiiCategory::NationalId => "[NATIONAL_ID]",
+        }
+    }
+
+    /// The digits are ASCII: `\d` would also match the other Unicode digits, not counted by the
+    /// validation
+    fn pattern(&self) -> &'static str {
+        match self {
+            PiiCategory::Email => r"(?i)\b[a-z0-9._%+-]+@[a-z0-9-]+(?:\.[a-z0-9-]+)*\.[a-z]{2,}\b",
+            PiiCategory::Phone => {
+                r"(?:\+[0-9]{1,3}[ .-]?)?(?:\([0-9]{2,4}\)[ .-]?|\b[0-9]{2,4}[ .-])[0-9]{2,4}[ .-]?[0-9]{3,4}\b"
+            }
+            PiiCategory::CreditCard => r"\b(?:[0-9][ -]?){12,18}[0-9]\b",
+            // US social security numbers
+            PiiCategory::NationalId => r"\b[0-9]{3}-[0-9]{2}-[0-9]{4}\b",
+        }
+    }
+
+    /// Discard the matches of the pattern that fail the checksum or the numbering rules
+    fn validate(&self, text: &str) -> bool {
+        let digits: Vec<u32> = text.chars().filter_map(|c| c.to_digit(10)).collect();
+        match self {
+            PiiCategory::Email => true,
+            PiiCategory::Phone => (10..=15).contains(&digits.len()),
+            PiiCategory::CreditCard => (13..=19).contains(&digits.len()) && luhn(&digits),
+            PiiCategory::NationalId => {
+                if digits.len() != 9 {
+                    return false;
+                }
+                let (area, group, serial) = (&digits[..3], &digits[3..5], &digits[5..]);
+                area != [0, 0, 0]
+                    && area != [6, 6, 6]
+                    && area[0] != 9
+                    && group != [0, 0]
+                    && serial != [0, 0, 0, 0]
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for PiiCategory {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        PiiCategory::ALL
+            .into_iter()
+            .find(|category| category.name() == name)
+            .ok_or_else(|| format!("unknown PII category `{name}`"))
+    }
+}
+
+/// Luhn checksum of the credit card numbers
+fn luhn(digits: &[u32]) -> bool {
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &digit)| match i % 2 {
+            0 => digit,
+            _ if digit > 4 => digit * 2 - 9,
+            _ => digit * 2,
+        })
+        .sum();
+    sum % 10 == 0
+}
+
+/// What is done with the responses holding PII
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PiiAction {
+    /// Only report the detections
+    Tag,
+    /// Replace the detections with the name of their category
+    Mask,
+    /// Reject the response
+    Block,
+}
+
+impl std::str::FromStr for PiiAction {
+    type Err = String;
+
+    fn from_str(action: &str) -> Result<Self, Self::Err> {
+        match action {
+            "tag" => Ok(PiiAction::Tag),
+            "mask" => Ok(PiiAction::Mask),
+            "block" => Ok(PiiAction::Block),
+            _ => Err(format!(
+                "unknown PII action `{action}`, expected `tag`, `mask` or `block`"
+            )),
+        }
+    }
+}
+
+/// Maximum number of characters of the detections masked in the streams
+const STREAM_WINDOW: usize = 64;
+
+/// PII found in a text
+#[derive(Debug, PartialEq)]
+struct Detection {
+    category: PiiCategory,
+    start: usize,
+    end: usize,
+}
+
+/// Scanner of the generated texts
+#[derive(Clone, Debug)]
+pub struct PiiScanner {
+    action: PiiAction,
+    detectors: Arc<Vec<(PiiCategory, Regex)>>,
+}
+
+impl PiiScanner {
+    /// Scan for the given `categories`, or all of them if empty, and apply `action`
+    pub fn new(action: &str, categories: &[String]) -> Result<Self, String> {
+        let action = action.parse()?;
+        let mut categories = categories
+            .iter()
+            .map(|category| category.parse())
+            .collect::<Result<Vec<PiiCategory>, String>>()?;
+        if categories.is_empty() {
+            categories = PiiCategory::ALL.to_vec();
+        }
+        // The numbers of the more specific categories are not also reported as phone numbers
+        categories.sort_by_key(|category| std::cmp::Reverse(*category));
+        categories.dedup();
+        let detectors = categories
+            .into_iter()
+            .map(|category| {
+                let regex = Regex::new(category.pattern()).expect("valid PII pattern");
+                (category, regex)
+            })
+            .collect();
+        Ok(Self {
+            action,
+            detectors: Arc::new(detectors),
+        })
+    }
+
+    /// Non overlapping detections in `text`, in order
+    fn detect(&self, text: &str) -> Vec<Detection> {
+        let mut detections: Vec<Detection> = Vec::new();
+        for (category, regex) in self.detectors.iter() {
+            for found in regex.find_iter(text) {
+                let overlaps = detections
+                    .iter()
+                    .any(|other| found.start() < other.end && other.start < found.end());
+                if !overlaps && category.validate(found.as_str()) {
+                    detections.push(Detection {
+                        category: *category,
+                        start: found.start(),
+                        end: found.end(),
+                    });
+                }
+            }
+        }
+        detections.sort_by_key(|detection| detection.start);
+        detections
+    }
+
+    /// Scan a generated text: return the text, masked if configured, and its report, or the
+    /// categories found if the response must be blocked
+    pub(crate) fn scan(&self, text: String) -> Result<(String, SafetyReport), Vec<PiiCategory>> {
+        let detections = self.detect(&text);
+        let mut pii: Vec<PiiCategory> = detections
+            .iter()
+            .map(|detection| detection.category)
+            .collect();
+        pii.sort();
+        pii.dedup();
+
+        let action = match self.action {
+            PiiAction::Tag => "tag",
+            PiiAction::Mask => "mask",
+            PiiAction::Block => "block",
+        };
+        for category in &pii {
+            metrics::increment_counter!("tgi_response_pii_detected", "category" => category.name(), "action" => action);
+        }
+
+        let text = match self.action {
+            PiiAction::Block if !pii.is_empty() => return Err(pii),
+            PiiAction::Mask if !detections.is_empty() => mask_detections(&text, &detections),
+            _ => text,
+        };
+        Ok((
+            text,
+            SafetyReport {
+                pii,
+                action: self.action,
+            },
+        ))
+    }
+
+    /// Mask the detections in `text` whatever the action, e.g. to scrub the stored payloads
+    pub(crate) fn mask(&self, text: &str) -> String {
+        mask_detections(text, &self.detect(text))
+    }
+
+    /// Masker of the tokens of a streamed generation, unless the detections are only tagged
+    pub(crate) fn stream_masker(&self) -> Option<StreamMasker> {
+        (self.action != PiiAction::Tag).then(|| StreamMasker {
+            scanner: self.clone(),
+            context: String::new(),
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Mask the text of the generated tokens, and of their top tokens, overlapping a detection
+    pub(crate) fn mask_tokens(&self, tokens: &mut Vec<Token>, top_tokens: &mut Vec<Vec<Token>>) {
+        if self.action != PiiAction::Mask {
+            return;
+        }
+        let mut masker = self.stream_masker().expect("masker");
+        let with_top_tokens = !top_tokens.is_empty();
+        let mut top_tokens_iter = std::mem::take(top_tokens).into_iter();
+        for token in std::mem::take(tokens) {
+            let token_top_tokens = top_tokens_iter.next().unwrap_or_default();
+            masker.pending.push_back((0, token, token_top_tokens));
+        }
+        // Unreachable error: the tokens are only blocked by the `block` action
+        for (_, token, token_top_tokens) in masker.release(true).unwrap_or_default() {
+            tokens.push(token);
+            if with_top_tokens {
+                top_tokens.push(token_top_tokens);
+            }
+        }
+    }
+}
+
+/// Streamed token: the index of its event, the token and its top tokens
+pub(crate) type StreamedToken = (u32, Token, Vec<Token>);
+
+/// Mask of the streamed tokens, holding back the tokens whose text may still be part of a
+/// detection. With the `block` action, the stream fails before its PII is sent
+#[derive(Debug)]
+pub(crate) struct StreamMasker {
+    scanner: PiiScanner,
+    /// Last characters of the released text, the context of the word boundaries of the patterns
+    context: String,
+    pending: VecDeque<StreamedToken>,
+}
+
+impl StreamMasker {
+    /// Tokens released by the next token, possibly none while a detection is pending
+    pub(crate) fn next(
+        &mut self,
+        token: StreamedToken,
+    ) -> Result<Vec<StreamedToken>, Vec<PiiCategory>> {
+        self.pending.push_back(token);
+        self.release(false)
+    }
+
+    /// Tokens released by the last token of the stream: all the tokens held back
+    pub(crate) fn finish(
+        &mut self,
+        token: StreamedToken,
+    ) -> Result<Vec<StreamedToken>, Vec<PiiCategory>> {
+        self.pending.push_back(token);
+        self.release(true)
+    }
+
+    fn release(&mut self, flush: bool) -> Result<Vec<StreamedToken>, Vec<PiiCategory>> {
+        // Spans of the pending tokens in their text following the context. The special tokens
+        // are not part of the generated text
+        let mut text = self.context.clone();
+        let spans: Vec<(usize, usize)> = self
+            .pending
+            .iter()
+            .map(|(_, token, _)| {
+                let start = text.len();
+                if !token.special {
+                    text.push_str(&token.text);
+                }
+                (start, text.len())
+            })
+            .collect();
+
+        // The text after the boundary may still be part of a longer detection
+        let pending_start = self.context.len();
+        let boundary = match flush {
+            true => text.len(),
+            false => text
+                .char_indices()
+                .rev()
+                .nth(STREAM_WINDOW - 2)
+                .map_or(0, |(index, _)| index)
+                .max(pending_start),
+        };
+        // The detections starting in the context were released with their tokens
+        let detections: Vec<Detection> = self
+            .scanner
+            .detect(&text)
+            .into_iter()
+            .filter(|detection| detection.start >= pending_start && detection.start < boundary)
+            .collect();
+        if self.scanner.action == PiiAction::Block && !detections.is_empty() {
+            let mut pii: Vec<PiiCategory> = detections
+                .iter()
+                .map(|detection| detection.category)
+                .collect();
+            pii.sort();
+            pii.dedup();
+            return Err(pii);
+        }
+
+        let overlapping = |(start, end): (usize, usize)| {
+            detections
+                .iter()
+                .filter(move |detection| detection.start < end && start < detection.end)
+        };
+        let ready = spans
+            .iter()
+            .take_while(|(start, end)| {
+                *end <= boundary || overlapping((*start, *end)).next().is_some()
+            })
+            .count();
+        let released: Vec<StreamedToken> = self
+            .pending
+            .drain(..ready)
+            .zip(&spans)
+            .map(|((index, mut token, mut top_tokens), &(start, end))| {
+                let Some(first) = overlapping((start, end)).next() else {
+                    return (index, token, top_tokens);
+                };
+                // The placeholder is sent with the token the detection starts in
+                let mut masked = String::new();
+                let mut last = start;
+                for detection in overlapping((start, end)) {
+                    masked.push_str(&text[last..detection.start.max(start)]);
+                    if detection.start >= start {
+                        masked.push_str(detection.category.mask());
+                    }
+                    last = detection.end.min(end);
+                }
+                masked.push_str(&text[last..end]);
+                token.text = masked;
+                for top_token in &mut top_tokens {
+                    top_token.text = first.category.mask().to_string();
+                }
+                (index, token, top_tokens)
+            })
+            .collect();
+
+        // Keep the last characters of the released text as context
+        let released_end = spans[..ready].last().map_or(pending_start, |(_, end)| *end);
+        let context_start = text[..released_end]
+            .char_indices()
+            .rev()
+            .nth(STREAM_WINDOW - 1)
+            .map_or(0, |(index, _)| index);
+        self.context = text[context_start..released_end].to_string();
+        Ok(released)
+    }
+}
+
+/// Replace the detections with the placeholder of their category
+fn mask_detections(text: &str, detections: &[Detection]) -> String {
+    let mut masked = String::with_capacity(text.len());
+    let mut last = 0;
+    for detection in detections {
+        masked.push_str(&text[last..detection.start]);
+        masked.push_str(detection.category.mask());
+        last = detection.end;
+    }
+    masked.push_str(&text[last..]);
+    masked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        let scanner = PiiScanner::new("tag", &[]).unwrap();
+        let text = "Mail john.doe@example.co.uk or call +1 (415) 555-0132. \
+                    Card 4111 1111 1111 1111, SSN 123-45-6789.";
+        let categories: Vec<PiiCategory> = scanner
+            .detect(text)
+            .into_iter()
+            .map(|detection| detection.category)
+            .collect();
+        assert_eq!(
+            categories,
+            vec![
+                PiiCategory::Email,
+                PiiCategory::Phone,
+                PiiCategory::CreditCard,
+                PiiCategory::NationalId
+            ]
+        );
+
+        // Failed checksums and invalid numbers
+        let text = "Order 4111111111111112 shipped, ref 000-12-3456, version 1.2.3";
+        assert!(scanner.detect(text).is_empty());
+        // Only the ASCII digits are numbers
+        assert!(scanner
+            .detect("SSN \u{661}\u{662}\u{663}-\u{664}\u{665}-\u{666}\u{667}\u{668}\u{669}")
+            .is_empty());
+    }
+
+    #[test]
+    fn test_scan() {
+        let text = "Write to jane@example.com about 378282246310005".to_string();
+
+        let scanner = PiiScanner::new("mask", &["email".to_string()]).unwrap();
+        let (masked, report) = scanner.scan(text.clone()).unwrap();
+        assert_eq!(masked, "Write to [EMAIL] about 378282246310005");
+        assert_eq!(report.pii, vec![PiiCategory::Email]);
+
+        let scanner = PiiScanner::new("tag", &[]).unwrap();
+        let (tagged, report) = scanner.scan(text.clone()).unwrap();
+        assert_eq!(tagged, text);
+        assert_eq!(
+            report.pii,
+            vec![PiiCategory::Email, PiiCategory::CreditCard]
+        );
+
+        let scanner = PiiScanner::new("block", &[]).unwrap();
+        assert_eq!(
+            scanner.scan(text),
+            Err(vec![PiiCategory::Email, PiiCategory::CreditCard])
+        );
+        assert!(scanner.scan("Nothing to see".to_string()).is_ok());
+
+        assert!(PiiScanner::new("redact", &[]).is_err());
+        assert!(PiiScanner::new("tag", &["passport".to_string()]).is_err());
+    }
+
+    fn tokens(texts: &[&str]) -> Vec<Token> {
+        texts
+            .iter()
+            .enumerate()
+            .map(|(id, text)| Token {
+                id: id as u32,
+                text: text.to_string(),
+                logprob: 0.0,
+                special: false,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_stream_masker() {
+        let texts = [
+            "Write",
+            " to",
+            " jane",
+            "@",
+            "example",
+            ".com",
+            " today",
+            " or",
+            " call",
+            " me",
+            " tomorrow",
+            " morning",
+            " before",
+            " the",
+            " meeting",
+            " starts",
+        ];
+        let scanner = PiiScanner::new("mask", &["email".to_string()]).unwrap();
+        let mut masker = scanner.stream_masker().unwrap();
+        let mut streamed = Vec::new();
+        let mut input = tokens(&texts).into_iter().enumerate().peekable();
+        while let Some((index, token)) = input.next() {
+            let item = (index as u32, token, Vec::new());
+            match input.peek() {
+                Some(_) => streamed.extend(masker.next(item).unwrap()),
+                None => {
+                    // The first tokens are sent before the end of the generation
+                    assert!(!streamed.is_empty());
+                    streamed.extend(masker.finish(item).unwrap())
+                }
+            }
+        }
+        // Every token is sent once, in order, the placeholder with the start of the address
+        let indexes: Vec<u32> = streamed.iter().map(|(index, _, _)| *index).collect();
+        assert_eq!(indexes, (0..texts.len() as u32).collect::<Vec<_>>());
+        let streamed: Vec<&str> = streamed
+            .iter()
+            .map(|(_, token, _)| token.text.as_str())
+            .collect();
+        let mut expected = texts.to_vec();
+        expected[2..6].copy_from_slice(&[" [EMAIL]", "", "", ""]);
+        assert_eq!(streamed, expected);
+
+        // Same masks on the whole generation
+        let mut generated = tokens(&texts);
+        let mut top_tokens = vec![tokens(&["x"]); texts.len()];
+        scanner.mask_tokens(&mut generated, &mut top_tokens);
+        let masked: Vec<&str> = generated.iter().map(|token| token.text.as_str()).collect();
+        assert_eq!(masked, expected);
+        assert_eq!(top_tokens[3][0].text, "[EMAIL]");
+        assert_eq!(top_tokens[6][0].text, "x");
+
+        // Blocked before the address is sent
+        let scanner = PiiScanner::new("block", &[]).unwrap();
+        let mut masker = scanner.stream_masker().unwrap();
+        for (index, token) in tokens(&texts[..6]).into_iter().enumerate() {
+            assert!(masker
+                .next((index as u32, token, Vec::new()))
+                .unwrap()
+                .is_empty());
+        }
+        let last = tokens(&[" today"]).remove(0);
+        assert_eq!(
+            masker.finish((6, last, Vec::new())).unwrap_err(),
+            vec![PiiCategory::Email]
+        );
+        assert!(PiiScanner::new("tag", &[])
+            .unwrap()
+            .stream_masker()
+            .is_none());
+    }
+}
@@ -0,0 +1,10 @@
+/// Minimal single-page playground served at `/playground` with the `playground` feature
+use axum::response::Html;
+
+/// The page builds its parameter controls from `/api-doc/openapi.json` and calls
+/// `/generate_stream` and `/v1/chat/completions`
+const PLAYGROUND: &str = include_str!("playground.html");
+
+pub(crate) async fn playground() -> Html<&'static str> {
+    Html(PLAYGROUND)
+}
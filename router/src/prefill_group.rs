@@ -0,0 +1,27 @@
+/// Shared prefill of the `best_of` candidates: the candidates of a request have the same inputs,
+/// the shards supporting it prefill them once and fork the cache for each candidate
+use opentelemetry::trace::{FutureExt, WithContext};
+use opentelemetry::Context;
+use rand::{thread_rng, Rng};
+use std::future::Future;
+
+/// Prefill group of the current request, stored in the current OpenTelemetry context
+#[derive(Clone, Copy, Debug)]
+struct PrefillGroup(u64);
+
+/// New prefill group, never 0 as 0 is no group
+pub(crate) fn new_group() -> u64 {
+    thread_rng().gen_range(1..=u64::MAX)
+}
+
+/// Run `future` with its requests in the prefill `group`
+pub(crate) fn in_group<F: Future>(group: u64, future: F) -> WithContext<F> {
+    future.with_context(Context::current_with_value(PrefillGroup(group)))
+}
+
+/// Prefill group of the current request, 0 if it does not share its prefill
+pub(crate) fn current() -> u64 {
+    Context::current()
+        .get::<PrefillGroup>()
+        .map_or(0, |group| group.0)
+}
@@ -0,0 +1,173 @@
+/// Named presets of decoding parameters, e.g. `"preset": "creative"`, expanded before the
+/// requests are generated: the parameters set by the request take precedence over the preset
+use crate::validation::ValidationError;
+use crate::GenerateParameters;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+/// Parameters used when the request did not set them
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PresetParameters {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 0.9)]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 50)]
+    top_k: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 0.95)]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 0.95)]
+    typical_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 1.1)]
+    repetition_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 0.1)]
+    frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = json ! (["\n\n"]))]
+    stop: Option<Vec<String>>,
+}
+
+impl PresetParameters {
+    fn apply(&self, parameters: &mut GenerateParameters) {
+        // A temperature schedule can not be combined with a temperature
+        if parameters.temperature_schedule.is_none() {
+            parameters.temperature = parameters.temperature.or(self.temperature);
+        }
+        parameters.top_k = parameters.top_k.or(self.top_k);
+        parameters.top_p = parameters.top_p.or(self.top_p);
+        parameters.typical_p = parameters.typical_p.or(self.typical_p);
+        parameters.repetition_penalty = parameters.repetition_penalty.or(self.repetition_penalty);
+        parameters.frequency_penalty = parameters.frequency_penalty.or(self.frequency_penalty);
+        if parameters.stop.is_empty() {
+            parameters.stop = self.stop.clone().unwrap_or_default();
+        }
+    }
+}
+
+/// Built-in presets, and the presets of the `--presets-file`
+#[derive(Clone, Debug)]
+pub struct Presets(Arc<BTreeMap<String, PresetParameters>>);
+
+impl Default for Presets {
+    fn default() -> Self {
+        Self(Arc::new(Self::builtin()))
+    }
+}
+
+impl Presets {
+    fn builtin() -> BTreeMap<String, PresetParameters> {
+        BTreeMap::from([
+            (
+                "creative".to_string(),
+                PresetParameters {
+                    temperature: Some(0.9),
+                    top_p: Some(0.95),
+                    repetition_penalty: Some(1.1),
+                    ..Default::default()
+                },
+            ),
+            (
+                "precise".to_string(),
+                PresetParameters {
+                    temperature: Some(0.2),
+                    top_p: Some(0.9),
+                    repetition_penalty: Some(1.03),
+                    ..Default::default()
+                },
+            ),
+        ])
+    }
+
+    /// Load the presets of a TOML file, one table per preset, e.g. `[support]` followed by its
+    /// parameters. A preset named after a built-in one replaces it
+    pub fn from_file(filename: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(filename).map_err(|err| err.to_string())?;
+        Self::from_toml(&content)
+    }
+
+    fn from_toml(content: &str) -> Result<Self, String> {
+        let custom: BTreeMap<String, PresetParameters> =
+            toml::from_str(content).map_err(|err| err.to_string())?;
+        let mut presets = Self::builtin();
+        presets.extend(custom);
+        Ok(Self(Arc::new(presets)))
+    }
+
+    /// Parameters of each preset, returned by `/info`
+    pub(crate) fn parameters(&self) -> BTreeMap<String, PresetParameters> {
+        self.0.as_ref().clone()
+    }
+
+    /// Apply the preset of the parameters
+    pub(crate) fn expand(
+        &self,
+        parameters: &mut GenerateParameters,
+    ) -> Result<(), ValidationError> {
+        let Some(name) = &parameters.preset else {
+            return Ok(());
+        };
+        let preset = self.0.get(name).ok_or_else(|| {
+            let names: Vec<&str> = self.0.keys().map(String::as_str).collect();
+            ValidationError::UnknownPreset(name.clone(), names.join("`, `"))
+        })?;
+        preset.apply(parameters);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets() {
+        let presets = Presets::from_toml(
+            r#"
+            [creative]
+            temperature = 1.2
+
+            [support]
+            top_k = 20
+            stop = ["\nUser:"]
+            "#,
+        )
+        .unwrap();
+
+        let mut parameters = GenerateParameters {
+            preset: Some("creative".to_string()),
+            top_p: Some(0.5),
+            ..Default::default()
+        };
+        presets.expand(&mut parameters).unwrap();
+        assert_eq!(parameters.temperature, Some(1.2));
+        assert_eq!(parameters.top_p, Some(0.5));
+        assert_eq!(parameters.repetition_penalty, None);
+
+        let mut parameters = GenerateParameters {
+            preset: Some("support".to_string()),
+            ..Default::default()
+        };
+        presets.expand(&mut parameters).unwrap();
+        assert_eq!(parameters.top_k, Some(20));
+        assert_eq!(parameters.stop, vec!["\nUser:".to_string()]);
+
+        let mut parameters = GenerateParameters {
+            preset: Some("creatve".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            presets.expand(&mut parameters).unwrap_err().to_string(),
+            "unknown preset `creatve`, expected one of `creative`, `precise`, `support`"
+        );
+
+        assert!(Presets::from_toml("[creative]\nseed = 42").is_err());
+    }
+}
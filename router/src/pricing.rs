@@ -0,0 +1,41 @@
+/// Prices of a thousand tokens: the cost of the requests is estimated from them, returned to the
+/// clients and accounted per tenant for chargeback
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Pricing {
+    input_per_1k: f64,
+    output_per_1k: f64,
+}
+
+impl Pricing {
+    /// Prices of a thousand input and generated tokens, `None` if no price is configured. A
+    /// missing price is free
+    pub(crate) fn new(input_per_1k: Option<f64>, output_per_1k: Option<f64>) -> Option<Self> {
+        if input_per_1k.is_none() && output_per_1k.is_none() {
+            return None;
+        }
+        Some(Self {
+            input_per_1k: input_per_1k.unwrap_or(0.0),
+            output_per_1k: output_per_1k.unwrap_or(0.0),
+        })
+    }
+
+    pub(crate) fn estimate(&self, input_tokens: u32, generated_tokens: u32) -> f64 {
+        (input_tokens as f64 * self.input_per_1k + generated_tokens as f64 * self.output_per_1k)
+            / 1000.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate() {
+        let pricing = Pricing::new(Some(0.5), Some(1.5)).unwrap();
+        assert_eq!(pricing.estimate(2000, 1000), 2.5);
+        assert_eq!(pricing.estimate(0, 0), 0.0);
+        let pricing = Pricing::new(None, Some(1.5)).unwrap();
+        assert_eq!(pricing.estimate(2000, 1000), 1.5);
+        assert!(Pricing::new(None, None).is_none());
+    }
+}
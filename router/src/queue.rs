@@ -1,9 +1,19 @@
+use crate::abort::Interruption;
+use crate::flight_recorder::{
+    DecisionEntry, DecisionOutcome, FlightRecorder, SchedulingDecision, WaitReason,
+};
 use crate::infer::InferError;
 use crate::infer::InferStreamResponse;
+use crate::rate_limit::RateLimitedClient;
+use crate::tenant::Tenant;
 use crate::validation::ValidGenerateRequest;
+use crate::QualityOfService;
 use nohash_hasher::{BuildNoHashHasher, IntMap};
 use std::cmp::min;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use text_generation_client::{Batch, Request};
 use tokio::sync::{mpsc, oneshot};
 use tokio::time::Instant;
@@ -24,6 +34,28 @@ pub(crate) struct Entry {
     pub queue_time: Instant,
     /// Instant when this entry was added to a batch
     pub batch_time: Option<Instant>,
+    /// Number of batches created while this entry was waiting in the queue
+    pub batching_cycles: u32,
+    /// Id of the batch this entry was added to
+    pub batch_id: Option<u64>,
+    /// Number of generations (forward passes) received for this entry
+    pub generations: u32,
+    /// W3C baggage entries forwarded to the shards
+    pub baggage: Vec<(String, String)>,
+    /// Follow-up request of a session, batched before the other entries
+    pub priority: bool,
+    /// Request of a batch file, batched after the other entries
+    pub background: bool,
+    /// Tenant the usage of the request is accounted to
+    pub tenant: Option<Tenant>,
+    /// Group of the requests sharing their prefill, 0 if none
+    pub prefill_group: u64,
+    /// Client whose token rate limit the usage of the request is accounted to
+    pub rate_limited_client: Option<RateLimitedClient>,
+    /// Set by the router to end the generation at its next token
+    pub interruption: Interruption,
+    /// Number of tokens generated so far
+    pub generated_tokens: u32,
 }
 
 /// Request Queue
@@ -31,6 +63,51 @@ pub(crate) struct Entry {
 pub(crate) struct Queue {
     /// Channel to communicate with the background queue task
     queue_sender: mpsc::UnboundedSender<QueueCommand>,
+    /// Statistics updated by the background queue task
+    stats: Arc<QueueStats>,
+}
+
+/// Queue statistics used to estimate the queue time of new requests
+#[derive(Debug, Default)]
+struct QueueStats {
+    /// Number of queued entries
+    length: AtomicUsize,
+    /// Number of queued entries of the latency class
+    latency_length: AtomicUsize,
+    /// Exponential moving average of the queue time of the last batched entries (in microseconds)
+    queue_time: AtomicU64,
+}
+
+impl QueueStats {
+    fn update(&self, state: &State, batch_entries: Option<&IntMap<u64, Entry>>) {
+        let latency_length = state
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.request.quality_of_service == QualityOfService::Latency)
+            .count();
+        self.length.store(state.entries.len(), Ordering::Relaxed);
+        self.latency_length.store(latency_length, Ordering::Relaxed);
+        if let Some(batch_entries) = batch_entries {
+            let mut queue_time = self.queue_time.load(Ordering::Relaxed);
+            for entry in batch_entries.values() {
+                let sample = entry
+                    .batch_time
+                    .map(|batch_time| batch_time - entry.queue_time)
+                    .unwrap_or_default()
+                    .as_micros() as u64;
+                queue_time = (queue_time * 7 + sample) / 8;
+            }
+            self.queue_time.store(queue_time, Ordering::Relaxed);
+        }
+    }
+
+    /// Recent queue time if entries are waiting in the queue
+    fn estimated_queue_time(&self) -> Duration {
+        match self.length.load(Ordering::Relaxed) {
+            0 => Duration::ZERO,
+            _ => Duration::from_micros(self.queue_time.load(Ordering::Relaxed)),
+        }
+    }
 }
 
 impl Queue {
@@ -39,9 +116,11 @@ impl Queue {
         block_size: u32,
         window_size: Option<u32>,
         speculate: u32,
+        flight_recorder: Option<FlightRecorder>,
     ) -> Self {
         // Create channel
         let (queue_sender, queue_receiver) = mpsc::unbounded_channel();
+        let stats = Arc::new(QueueStats::default());
 
         // Launch background queue task
         tokio::spawn(queue_task(
@@ -49,10 +128,25 @@ impl Queue {
             block_size,
             window_size,
             speculate,
+            flight_recorder,
             queue_receiver,
+            stats.clone(),
         ));
 
-        Self { queue_sender }
+        Self {
+            queue_sender,
+            stats,
+        }
+    }
+
+    /// Estimated time a new entry will spend in the queue
+    pub(crate) fn estimated_queue_time(&self) -> Duration {
+        self.stats.estimated_queue_time()
+    }
+
+    /// Whether entries of the latency class are waiting in the queue
+    pub(crate) fn latency_waiting(&self) -> bool {
+        self.stats.latency_length.load(Ordering::Relaxed) > 0
     }
 
     /// Append an entry to the queue
@@ -100,14 +194,18 @@ async fn queue_task(
     block_size: u32,
     window_size: Option<u32>,
     speculate: u32,
+    flight_recorder: Option<FlightRecorder>,
     mut receiver: mpsc::UnboundedReceiver<QueueCommand>,
+    stats: Arc<QueueStats>,
 ) {
     let mut state = State::new(requires_padding, block_size, window_size, speculate);
+    state.flight_recorder = flight_recorder;
 
     while let Some(cmd) = receiver.recv().await {
         match cmd {
             QueueCommand::Append(entry, span) => {
                 span.in_scope(|| state.append(*entry));
+                stats.update(&state, None);
                 metrics::increment_gauge!("tgi_queue_size", 1.0);
             }
             QueueCommand::NextBatch {
@@ -120,6 +218,7 @@ async fn queue_task(
             } => span.in_scope(|| {
                 let next_batch =
                     state.next_batch(min_size, max_size, prefill_token_budget, token_budget);
+                stats.update(&state, next_batch.as_ref().map(|(entries, _, _)| entries));
                 response_sender.send(next_batch).unwrap();
                 metrics::gauge!("tgi_queue_size", state.entries.len() as f64);
             }),
@@ -150,6 +249,12 @@ struct State {
 
     /// Speculation amount
     speculate: u32,
+
+    /// Recorder of the scheduling decisions
+    flight_recorder: Option<FlightRecorder>,
+
+    /// Queue length of the last recorded `NotEnoughEntries` decision, until another decision
+    not_enough_entries: Option<usize>,
 }
 
 impl State {
@@ -167,17 +272,39 @@ impl State {
             block_size,
             window_size,
             speculate,
+            flight_recorder: None,
+            not_enough_entries: None,
         }
     }
 
+    /// Record a scheduling decision if the flight recorder is enabled
+    fn record(&self, decision: impl FnOnce() -> SchedulingDecision) {
+        if let Some(flight_recorder) = &self.flight_recorder {
+            flight_recorder.record(decision());
+        }
+    }
+
+    /// First entry of the queue, for the flight recorder
+    fn next_entry(&self) -> Option<DecisionEntry> {
+        self.entries
+            .front()
+            .map(|(id, entry)| DecisionEntry::new(*id, entry))
+    }
+
     /// Append an entry to the queue
     fn append(&mut self, mut entry: Entry) {
         // Create a span that will live as long as the entry is in the queue waiting to be batched
         let queue_span = info_span!(parent: &entry.span, "queued");
         entry.temp_span = Some(queue_span);
 
-        // Push entry in the queue
-        self.entries.push_back((self.next_id, entry));
+        // Push entry in the queue, after the other priority entries and before the background ones
+        let position = match (entry.priority, entry.background) {
+            (true, _) => self.entries.iter().position(|(_, entry)| !entry.priority),
+            (false, false) => self.entries.iter().position(|(_, entry)| entry.background),
+            (false, true) => None,
+        }
+        .unwrap_or(self.entries.len());
+        self.entries.insert(position, (self.next_id, entry));
         self.next_id += 1;
     }
 
@@ -192,13 +319,31 @@ impl State {
         if self.entries.is_empty() {
             return None;
         }
+        let queued = self.entries.len();
+        let new_decision = |outcome| {
+            SchedulingDecision::new(
+                outcome,
+                queued,
+                min_size,
+                max_size,
+                prefill_token_budget,
+                token_budget,
+            )
+        };
 
         // Check if we have enough entries
         if let Some(min_size) = min_size {
             if self.entries.len() < min_size {
+                // The batching task polls the queue on every step: only recorded once per queue
+                // length, not to push the other decisions out of the recorder
+                if self.not_enough_entries != Some(queued) {
+                    self.not_enough_entries = Some(queued);
+                    self.record(|| new_decision(DecisionOutcome::NotEnoughEntries));
+                }
                 return None;
             }
         }
+        self.not_enough_entries = None;
 
         // Create span for this batch to add context to inference calls
         let next_batch_span = info_span!(parent: None, "batch", batch_size = tracing::field::Empty);
@@ -211,6 +356,7 @@ impl State {
         let mut max_input_length = 0;
         let mut prefill_tokens: u32 = 0;
         let mut decode_tokens: u32 = 0;
+        let mut wait_reason = None;
 
         // Pop entries starting from the front of the queue
         while let Some((id, mut entry)) = self.entries.pop_front() {
@@ -253,6 +399,10 @@ impl State {
                 || (prefill_tokens + decode_tokens + self.speculate) > token_budget
             {
                 // Entry is over budget
+                wait_reason = Some(match prefill_tokens > prefill_token_budget {
+                    true => WaitReason::PrefillTokenBudget,
+                    false => WaitReason::TokenBudget,
+                });
                 // Add it back to the front
                 self.entries.push_front((id, entry));
                 break;
@@ -274,6 +424,11 @@ impl State {
                 parameters: Some(entry.request.parameters.clone()),
                 stopping_parameters: Some(entry.request.stopping_parameters.clone()),
                 top_n_tokens: entry.request.top_n_tokens,
+                skip_special_tokens: entry.request.skip_special_tokens,
+                extensions: entry.request.extensions.clone(),
+                prefill_group: entry.prefill_group,
+                adapter_id: entry.request.adapter_id.clone(),
+                adapter_parameters: entry.request.adapter_parameters.clone(),
             });
             // Set batch_time
             entry.batch_time = Some(Instant::now());
@@ -282,12 +437,22 @@ impl State {
 
             // Check if max_size
             if Some(batch_requests.len()) == max_size {
+                if !self.entries.is_empty() {
+                    wait_reason = Some(WaitReason::MaxBatchSize);
+                }
                 break;
             }
         }
 
         // Empty batch
         if batch_requests.is_empty() {
+            self.record(|| SchedulingDecision {
+                prefill_tokens,
+                decode_tokens,
+                wait_reason,
+                next_entry: self.next_entry(),
+                ..new_decision(DecisionOutcome::NoEntry)
+            });
             return None;
         }
 
@@ -295,6 +460,14 @@ impl State {
         if let Some(min_size) = min_size {
             // Batch is too small
             if batch_requests.len() < min_size {
+                self.record(|| SchedulingDecision {
+                    batched: decision_entries(&batch_requests, &batch_entries),
+                    prefill_tokens,
+                    decode_tokens,
+                    wait_reason,
+                    next_entry: self.next_entry(),
+                    ..new_decision(DecisionOutcome::BatchTooSmall)
+                });
                 // Add back entries to the queue in the correct order
                 for r in batch_requests.into_iter().rev() {
                     let id = r.id;
@@ -316,6 +489,22 @@ impl State {
             size,
             max_tokens: (prefill_tokens + decode_tokens),
         };
+        for entry in batch_entries.values_mut() {
+            entry.batch_id = Some(batch.id);
+        }
+        // The entries left in the queue waited one more batching cycle
+        for (_, entry) in self.entries.iter_mut() {
+            entry.batching_cycles += 1;
+        }
+        self.record(|| SchedulingDecision {
+            batch_id: Some(batch.id),
+            batched: decision_entries(&batch.requests, &batch_entries),
+            prefill_tokens,
+            decode_tokens,
+            wait_reason,
+            next_entry: self.next_entry(),
+            ..new_decision(DecisionOutcome::Batched)
+        });
         // Increment batch id
         self.next_batch_id += 1;
 
@@ -325,6 +514,14 @@ impl State {
     }
 }
 
+/// Entries of a batch, in the batch order, for the flight recorder
+fn decision_entries(requests: &[Request], entries: &IntMap<u64, Entry>) -> Vec<DecisionEntry> {
+    requests
+        .iter()
+        .map(|request| DecisionEntry::new(request.id, &entries[&request.id]))
+        .collect()
+}
+
 type NextBatch = (IntMap<u64, Entry>, Batch, Span);
 
 #[derive(Debug)]
@@ -372,19 +569,44 @@ mod tests {
                     watermark: false,
                     grammar: String::new(),
                     grammar_type: ProtoGrammarType::None as i32,
+                    suppressed_tokens: vec![],
+                    temperature_schedule: vec![],
+                    logit_bias: HashMap::new(),
                 },
                 stopping_parameters: StoppingCriteriaParameters {
                     ignore_eos_token: false,
+                    stop_after_tool_call: false,
                     max_new_tokens: 1,
                     stop_sequences: vec![],
+                    deadline: None,
+                    eos_probability_threshold: None,
+                    eos_probability_window: 0,
                 },
                 top_n_tokens: 0,
+                skip_special_tokens: true,
+                quality_of_service: QualityOfService::Throughput,
+                normalization: None,
+                extensions: HashMap::new(),
+                adapter_id: String::new(),
+                adapter_parameters: None,
+                adapter_leases: Vec::new(),
             },
             response_tx,
             span: info_span!("entry"),
             temp_span: None,
             queue_time: Instant::now(),
             batch_time: None,
+            batching_cycles: 0,
+            batch_id: None,
+            generations: 0,
+            baggage: vec![],
+            priority: false,
+            background: false,
+            tenant: None,
+            prefill_group: 0,
+            rate_limited_client: None,
+            interruption: Interruption::default(),
+            generated_tokens: 0,
         };
         (entry, receiver_tx)
     }
@@ -405,6 +627,38 @@ mod tests {
         assert_eq!(id, 0);
     }
 
+    #[test]
+    fn test_append_priority() {
+        let mut state = State::new(false, 1, None, 0);
+        let (entry1, _guard1) = default_entry();
+        let (mut entry2, _guard2) = default_entry();
+        let (mut entry3, _guard3) = default_entry();
+        entry2.priority = true;
+        entry3.priority = true;
+        state.append(entry1);
+        state.append(entry2);
+        state.append(entry3);
+
+        let ids: Vec<u64> = state.entries.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn test_append_background() {
+        let mut state = State::new(false, 1, None, 0);
+        let (mut entry1, _guard1) = default_entry();
+        let (entry2, _guard2) = default_entry();
+        let (mut entry3, _guard3) = default_entry();
+        entry1.background = true;
+        entry3.priority = true;
+        state.append(entry1);
+        state.append(entry2);
+        state.append(entry3);
+
+        let ids: Vec<u64> = state.entries.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![2, 1, 0]);
+    }
+
     #[test]
     fn test_next_batch_empty() {
         let mut state = State::new(false, 1, None, 0);
@@ -465,6 +719,54 @@ mod tests {
         assert_eq!(state.next_batch_id, 1);
     }
 
+    #[test]
+    fn test_next_batch_flight_recorder() {
+        let mut state = State::new(false, 1, None, 0);
+        let flight_recorder = FlightRecorder::new(4);
+        state.flight_recorder = Some(flight_recorder.clone());
+        let (entry1, _guard1) = default_entry();
+        let (entry2, _guard2) = default_entry();
+        state.append(entry1);
+        state.append(entry2);
+
+        // Recorded once while the queue does not change
+        assert!(state.next_batch(Some(3), None, 2, 2).is_none());
+        assert!(state.next_batch(Some(3), None, 2, 2).is_none());
+        state.next_batch(None, Some(1), 2, 2).unwrap();
+        assert!(state.next_batch(None, None, 2, 0).is_none());
+
+        let decisions = flight_recorder.decisions();
+        assert_eq!(decisions.len(), 3);
+        assert_eq!(decisions[2].outcome, DecisionOutcome::NotEnoughEntries);
+        assert_eq!(decisions[0].outcome, DecisionOutcome::NoEntry);
+        assert_eq!(decisions[0].wait_reason, Some(WaitReason::TokenBudget));
+        assert_eq!(decisions[0].next_entry.as_ref().unwrap().id, 1);
+        assert_eq!(decisions[1].outcome, DecisionOutcome::Batched);
+        assert_eq!(decisions[1].batch_id, Some(0));
+        assert_eq!(decisions[1].batched[0].id, 0);
+        assert_eq!(decisions[1].wait_reason, Some(WaitReason::MaxBatchSize));
+    }
+
+    #[test]
+    fn test_next_batch_batching_cycles() {
+        let mut state = State::new(false, 1, None, 0);
+        let (entry1, _guard1) = default_entry();
+        let (entry2, _guard2) = default_entry();
+        state.append(entry1);
+        state.append(entry2);
+
+        let (entries, _, _) = state.next_batch(None, Some(1), 2, 2).unwrap();
+        let entry = entries.get(&0).unwrap();
+        assert_eq!(entry.batching_cycles, 0);
+        assert_eq!(entry.batch_id, Some(0));
+        assert_eq!(state.entries[0].1.batching_cycles, 1);
+
+        let (entries, _, _) = state.next_batch(None, Some(1), 2, 2).unwrap();
+        let entry = entries.get(&1).unwrap();
+        assert_eq!(entry.batching_cycles, 1);
+        assert_eq!(entry.batch_id, Some(1));
+    }
+
     #[test]
     fn test_next_batch_token_budget() {
         let mut state = State::new(false, 1, None, 0);
@@ -500,14 +802,14 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_append() {
-        let queue = Queue::new(false, 1, None, 0);
+        let queue = Queue::new(false, 1, None, 0, None);
         let (entry, _guard) = default_entry();
         queue.append(entry);
     }
 
     #[tokio::test]
     async fn test_queue_next_batch_empty() {
-        let queue = Queue::new(false, 1, None, 0);
+        let queue = Queue::new(false, 1, None, 0, None);
 
         assert!(queue.next_batch(None, None, 1, 1).await.is_none());
         assert!(queue.next_batch(Some(1), None, 1, 1).await.is_none());
@@ -515,7 +817,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_next_batch_min_size() {
-        let queue = Queue::new(false, 1, None, 0);
+        let queue = Queue::new(false, 1, None, 0, None);
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         queue.append(entry1);
@@ -548,7 +850,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_next_batch_max_size() {
-        let queue = Queue::new(false, 1, None, 0);
+        let queue = Queue::new(false, 1, None, 0, None);
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         queue.append(entry1);
@@ -562,9 +864,51 @@ mod tests {
         assert_eq!(batch.size, 1);
     }
 
+    #[tokio::test]
+    async fn test_queue_estimated_queue_time() {
+        let queue = Queue::new(false, 1, None, 0, None);
+        assert_eq!(queue.estimated_queue_time(), Duration::ZERO);
+
+        let (entry1, _guard1) = default_entry();
+        let (entry2, _guard2) = default_entry();
+        queue.append(entry1);
+        queue.append(entry2);
+
+        let (entries, _, _) = queue.next_batch(None, Some(1), 2, 2).await.unwrap();
+        let entry = entries.get(&0).unwrap();
+        let queue_time = entry.batch_time.unwrap() - entry.queue_time;
+        assert_eq!(
+            queue.estimated_queue_time(),
+            Duration::from_micros(queue_time.as_micros() as u64 / 8)
+        );
+
+        // Empty queue
+        queue.next_batch(None, None, 2, 2).await.unwrap();
+        assert_eq!(queue.estimated_queue_time(), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_queue_latency_waiting() {
+        let queue = Queue::new(false, 1, None, 0, None);
+        let (entry1, _guard1) = default_entry();
+        let (mut entry2, _guard2) = default_entry();
+        entry2.request.quality_of_service = QualityOfService::Latency;
+        queue.append(entry1);
+        // The stats are updated by the background task, the entries stay queued below `min_size`
+        assert!(queue.next_batch(Some(3), None, 2, 2).await.is_none());
+        assert!(!queue.latency_waiting());
+
+        queue.append(entry2);
+        assert!(queue.next_batch(Some(3), None, 2, 2).await.is_none());
+        assert!(queue.latency_waiting());
+
+        queue.next_batch(None, None, 2, 2).await.unwrap();
+        assert!(!queue.latency_waiting());
+    }
+
     #[tokio::test]
     async fn test_queue_next_batch_token_budget() {
-        let queue = Queue::new(false, 1, None, 0);
+        let queue = Queue::new(false, 1, None, 0, None);
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         queue.append(entry1);
@@ -589,7 +933,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_next_batch_token_speculate() {
-        let queue = Queue::new(false, 1, None, 2);
+        let queue = Queue::new(false, 1, None, 2, None);
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         queue.append(entry1);
@@ -608,7 +952,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_next_batch_dropped_receiver() {
-        let queue = Queue::new(false, 1, None, 0);
+        let queue = Queue::new(false, 1, None, 0, None);
         let (entry, _) = default_entry();
         queue.append(entry);
 
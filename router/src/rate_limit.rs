@@ -0,0 +1,301 @@
+/// Per-client rate limits: token buckets of requests per second and of tokens per minute,
+/// enforced before the validation of the requests
+use crate::stream_limit::client_id;
+use crate::tenant::Tenant;
+use crate::ErrorResponse;
+use axum::extract::{ConnectInfo, Extension};
+use axum::http::header::RETRY_AFTER;
+use axum::http::{Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use opentelemetry::trace::FutureExt;
+use opentelemetry::Context;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Number of clients above which the buckets of the least recently seen half are dropped
+const MAX_CLIENTS: usize = 10_000;
+
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    refill_per_second: f64,
+    available: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, refill_per_second: f64, now: Instant) -> Self {
+        Self {
+            capacity,
+            refill_per_second,
+            available: capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.available = (self.available + elapsed * self.refill_per_second).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Time until `amount` tokens are available
+    fn wait_time(&self, amount: f64) -> Duration {
+        Duration::from_secs_f64(((amount - self.available) / self.refill_per_second).max(0.0))
+    }
+}
+
+#[derive(Debug)]
+struct ClientBuckets {
+    requests: Option<TokenBucket>,
+    tokens: Option<TokenBucket>,
+    /// Last request of the client
+    used: Instant,
+}
+
+impl ClientBuckets {
+    fn refill(&mut self, now: Instant) {
+        self.requests
+            .iter_mut()
+            .for_each(|bucket| bucket.refill(now));
+        self.tokens.iter_mut().for_each(|bucket| bucket.refill(now));
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub(crate) enum RateLimitError {
+    #[error("Rate limit exceeded: at most {0} requests per second are allowed per client")]
+    Requests(f64, Duration),
+    #[error("Rate limit exceeded: at most {0} tokens per minute are allowed per client")]
+    Tokens(u64, Duration),
+}
+
+impl RateLimitError {
+    /// Limit label of the metrics
+    fn limit(&self) -> &'static str {
+        match self {
+            RateLimitError::Requests(..) => "requests",
+            RateLimitError::Tokens(..) => "tokens",
+        }
+    }
+
+    fn retry_after(&self) -> Duration {
+        match self {
+            RateLimitError::Requests(_, retry_after) | RateLimitError::Tokens(_, retry_after) => {
+                *retry_after
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct RateLimiter {
+    requests_per_second: Option<f64>,
+    tokens_per_minute: Option<u64>,
+    clients: Arc<Mutex<HashMap<String, ClientBuckets>>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(requests_per_second: Option<f64>, tokens_per_minute: Option<u64>) -> Self {
+        Self {
+            requests_per_second,
+            tokens_per_minute,
+            clients: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn new_buckets(&self, now: Instant) -> ClientBuckets {
+        ClientBuckets {
+            // Bursts of one second of requests, and at least one request
+            requests: self
+                .requests_per_second
+                .map(|rate| TokenBucket::new(rate.max(1.0), rate, now)),
+            tokens: self
+                .tokens_per_minute
+                .map(|rate| TokenBucket::new(rate as f64, rate as f64 / 60.0, now)),
+            used: now,
+        }
+    }
+
+    /// Take a request from the buckets of a client
+    fn try_acquire(&self, client: &str, now: Instant) -> Result<(), RateLimitError> {
+        let mut clients = self.clients.lock().unwrap();
+        if !clients.contains_key(client) && clients.len() >= MAX_CLIENTS {
+            // Whatever the level of their buckets: the clients rotating their ids would
+            // otherwise grow the map with their drained buckets
+            let mut used: Vec<Instant> = clients.values().map(|buckets| buckets.used).collect();
+            let half = used.len() / 2;
+            let (_, cutoff, _) = used.select_nth_unstable(half);
+            let cutoff = *cutoff;
+            clients.retain(|_, buckets| buckets.used > cutoff);
+        }
+        let buckets = clients
+            .entry(client.to_string())
+            .or_insert_with(|| self.new_buckets(now));
+        buckets.refill(now);
+        buckets.used = now;
+
+        // The tokens of a request are only known once generated: the clients over their budget
+        // wait until their bucket is positive again
+        if let (Some(rate), Some(bucket)) = (self.tokens_per_minute, &buckets.tokens) {
+            if bucket.available <= 0.0 {
+                return Err(RateLimitError::Tokens(rate, bucket.wait_time(1.0)));
+            }
+        }
+        if let (Some(rate), Some(bucket)) = (self.requests_per_second, &mut buckets.requests) {
+            if bucket.available < 1.0 {
+                return Err(RateLimitError::Requests(rate, bucket.wait_time(1.0)));
+            }
+            bucket.available -= 1.0;
+        }
+        Ok(())
+    }
+
+    /// Take the prompt and generated tokens of a finished request from the bucket of a client
+    fn record_tokens(&self, client: &str, tokens: u32, now: Instant) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(bucket) = clients
+            .get_mut(client)
+            .and_then(|buckets| buckets.tokens.as_mut())
+        {
+            bucket.refill(now);
+            bucket.available -= tokens as f64;
+        }
+    }
+}
+
+/// Rate limited client of the current request, stored in the current OpenTelemetry context
+#[derive(Clone, Debug)]
+pub(crate) struct RateLimitedClient {
+    client: String,
+    limiter: RateLimiter,
+}
+
+impl RateLimitedClient {
+    /// Account the tokens of a finished generation
+    pub(crate) fn record_tokens(&self, input_tokens: u32, generated_tokens: u32) {
+        self.limiter.record_tokens(
+            &self.client,
+            input_tokens + generated_tokens,
+            Instant::now(),
+        );
+    }
+}
+
+/// Middleware rejecting the inference requests of the clients over their rate limits with a 429
+/// `rate_limited` error and a `Retry-After` header
+pub(crate) async fn limit_rate<B>(
+    Extension(limiter): Extension<RateLimiter>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    // Only the inference requests are limited, not the admin requests, health checks or metrics
+    // scrapes
+    if request.method() != Method::POST || request.uri().path().starts_with("/admin/") {
+        return next.run(request).await;
+    }
+
+    let client = client_id(request.extensions().get::<Tenant>(), connect_info)
+        .unwrap_or_else(|| "unknown".to_string());
+    if let Err(err) = limiter.try_acquire(&client, Instant::now()) {
+        metrics::increment_counter!("tgi_rate_limited_count", "limit" => err.limit());
+        metrics::increment_counter!("tgi_request_failure", "err" => "rate_limited");
+        tracing::warn!("{err} ({client})");
+        let retry_after = err.retry_after().as_secs_f64().ceil() as u64;
+        let mut response = (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(ErrorResponse {
+                error: err.to_string(),
+                error_type: "rate_limited".to_string(),
+            }),
+        )
+            .into_response();
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, retry_after.max(1).into());
+        return response;
+    }
+
+    let context = Context::current_with_value(RateLimitedClient { client, limiter });
+    next.run(request).with_context(context).await
+}
+
+/// Rate limited client of the current request
+pub(crate) fn current() -> Option<RateLimitedClient> {
+    Context::current().get::<RateLimitedClient>().cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tenant::{TenantKeys, Tenants};
+    use axum::http::header::AUTHORIZATION;
+    use axum::http::HeaderMap;
+
+    #[test]
+    fn test_rate_limiter() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(Some(2.0), Some(60));
+        assert!(limiter.try_acquire("a", start).is_ok());
+        assert!(limiter.try_acquire("a", start).is_ok());
+        assert_eq!(
+            limiter.try_acquire("a", start),
+            Err(RateLimitError::Requests(2.0, Duration::from_millis(500)))
+        );
+        // Other clients have their own buckets
+        assert!(limiter.try_acquire("b", start).is_ok());
+        assert!(limiter
+            .try_acquire("a", start + Duration::from_millis(500))
+            .is_ok());
+
+        // The tokens are accounted once generated, the bucket going negative
+        let now = start + Duration::from_secs(1);
+        limiter.record_tokens("a", 90, now);
+        assert_eq!(
+            limiter.try_acquire("a", now),
+            Err(RateLimitError::Tokens(60, Duration::from_secs(31)))
+        );
+        assert!(limiter
+            .try_acquire("a", now + Duration::from_secs(31))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_rate_limiter_unknown_keys() {
+        let now = Instant::now();
+        let limiter = RateLimiter::new(Some(1.0), None);
+        let tenants = Tenants::new(false, TenantKeys::default());
+        let addr = SocketAddr::from(([10, 0, 0, 1], 4242));
+        // A client rotating unknown API keys keeps the bucket of its IP address
+        for (i, key) in ["Bearer a", "Bearer b"].iter().enumerate() {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, key.parse().unwrap());
+            let client = client_id(Some(&tenants.tenant(&headers)), Some(ConnectInfo(addr)));
+            assert_eq!(client.as_deref(), Some("ip:10.0.0.1"));
+            assert_eq!(limiter.try_acquire(&client.unwrap(), now).is_ok(), i == 0);
+        }
+    }
+
+    #[test]
+    fn test_rate_limiter_eviction() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(Some(1.0), None);
+        for i in 0..MAX_CLIENTS {
+            let now = start + Duration::from_millis(i as u64);
+            assert!(limiter.try_acquire(&i.to_string(), now).is_ok());
+        }
+        // The drained buckets of the least recently seen clients are dropped too
+        let now = start + Duration::from_millis(MAX_CLIENTS as u64);
+        assert!(limiter.try_acquire("new", now).is_ok());
+        let clients = limiter.clients.lock().unwrap();
+        assert!(clients.len() <= MAX_CLIENTS / 2 + 1);
+        assert!(!clients.contains_key("0"));
+        assert!(clients.contains_key(&(MAX_CLIENTS - 1).to_string()));
+    }
+}
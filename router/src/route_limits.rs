@@ -0,0 +1,159 @@
+/// Limits of the cloud front door routes (`/vertex` and `/invocations`), kept apart from the
+/// native API so that misconfigured batch clients cannot starve it
+use crate::ErrorResponse;
+use axum::body::{Body, Bytes, HttpBody, StreamBody};
+use axum::extract::{DefaultBodyLimit, Extension};
+use axum::handler::Handler;
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::MethodRouter;
+use axum::Json;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+#[derive(Clone, Debug)]
+pub(crate) struct RouteLimits {
+    /// Route name of the metrics and errors
+    route: &'static str,
+    /// Maximum size of the request bodies, in bytes
+    max_body_size: Option<usize>,
+    /// Maximum number of instances of a Vertex request
+    pub max_instances: Option<usize>,
+    /// Maximum number of concurrent requests on the route, streams included
+    max_concurrent_requests: Option<usize>,
+    permits: Option<Arc<Semaphore>>,
+}
+
+impl RouteLimits {
+    pub(crate) fn new(
+        route: &'static str,
+        max_body_size: Option<usize>,
+        max_instances: Option<usize>,
+        max_concurrent_requests: Option<usize>,
+    ) -> Self {
+        Self {
+            route,
+            max_body_size,
+            max_instances,
+            max_concurrent_requests,
+            permits: max_concurrent_requests.map(|permits| Arc::new(Semaphore::new(permits))),
+        }
+    }
+
+    /// Body limit of the route, the default of axum if not set
+    pub(crate) fn max_body_size(&self) -> usize {
+        self.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE)
+    }
+
+    /// Apply the limits to the `post` route of `handler`
+    pub(crate) fn post<H, T>(&self, handler: H) -> MethodRouter
+    where
+        H: Handler<T, ()>,
+        T: 'static,
+    {
+        let mut route = axum::routing::post(handler);
+        if let Some(max_body_size) = self.max_body_size {
+            route = route.layer(DefaultBodyLimit::max(max_body_size));
+        }
+        if self.permits.is_some() {
+            route = route.layer(axum::middleware::from_fn(limit_route));
+        }
+        route.layer(Extension(self.clone()))
+    }
+}
+
+/// Middleware rejecting the requests over the concurrency limit of the route. The permit is held
+/// until the response body, e.g. a stream, is fully sent
+async fn limit_route<B>(
+    Extension(limits): Extension<RouteLimits>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(permit) = limits
+        .permits
+        .as_ref()
+        .and_then(|permits| permits.clone().try_acquire_owned().ok())
+    else {
+        metrics::increment_counter!("tgi_request_failure", "err" => "route_overloaded");
+        metrics::increment_counter!("tgi_route_rejected", "route" => limits.route);
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(ErrorResponse {
+                error: format!(
+                    "Too many concurrent requests on `{}`: at most {} are allowed",
+                    limits.route,
+                    limits.max_concurrent_requests.unwrap_or_default()
+                ),
+                error_type: "route_overloaded".to_string(),
+            }),
+        )
+            .into_response();
+    };
+
+    let response = next.run(request).await;
+
+    // Keep the permit as long as the body lives
+    let (parts, mut body) = response.into_parts();
+    let body = async_stream::stream! {
+        let _permit = permit;
+        while let Some(chunk) = body.data().await {
+            yield chunk;
+        }
+    };
+    Response::from_parts(parts, axum::body::boxed(StreamBody::new(body)))
+}
+
+/// Body limit of the routes without a `DefaultBodyLimit` of their own, the default of axum
+pub(crate) const DEFAULT_MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+/// Buffer the body of a request replayed by a middleware, rejected with a 413 over `limit`
+/// bytes: unlike the extractors of the route, the middlewares do not see its `DefaultBodyLimit`
+pub(crate) async fn buffer_body(mut body: Body, limit: usize) -> Result<Bytes, Response> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse {
+                    error: format!("Failed to read the request body: {err}"),
+                    error_type: "validation".to_string(),
+                }),
+            )
+                .into_response()
+        })?;
+        if bytes.len() + chunk.len() > limit {
+            metrics::increment_counter!("tgi_request_failure", "err" => "payload_too_large");
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(ErrorResponse {
+                    error: format!("The request body is larger than {limit} bytes"),
+                    error_type: "payload_too_large".to_string(),
+                }),
+            )
+                .into_response());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_limits() {
+        let limits = RouteLimits::new("/vertex", None, Some(8), Some(1));
+        let permits = limits.permits.clone().unwrap();
+        let permit = permits.clone().try_acquire_owned().unwrap();
+        // Clones of the limits, one per request, share the permits
+        assert!(limits.clone().permits.unwrap().try_acquire_owned().is_err());
+        drop(permit);
+        assert!(permits.try_acquire_owned().is_ok());
+
+        assert!(RouteLimits::new("/invocations", None, None, None)
+            .permits
+            .is_none());
+    }
+}
@@ -0,0 +1,155 @@
+/// Validation of the `model` field of the OpenAI requests
+use crate::{ErrorResponse, ModelList, ModelObject};
+use axum::http::StatusCode;
+use axum::Json;
+use std::sync::Arc;
+
+#[derive(Clone, Debug)]
+pub(crate) struct ServedModel {
+    /// Id of the served model
+    model_id: String,
+    /// Other names accepted for the served model, e.g. `gpt-3.5-turbo`
+    aliases: Arc<Vec<String>>,
+    /// Reject the requests for another model
+    enforce: bool,
+    /// Unix timestamp of the router start
+    created: u64,
+}
+
+impl ServedModel {
+    pub(crate) fn new(model_id: String, aliases: Vec<String>, enforce: bool) -> Self {
+        Self {
+            model_id,
+            aliases: Arc::new(aliases),
+            enforce,
+            created: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_else(|_| std::time::Duration::from_secs(0))
+                .as_secs(),
+        }
+    }
+
+    /// Check that a request targets the served model, one of its aliases or one of the LoRA
+    /// `adapters`, and return the adapter targeted
+    pub(crate) fn check(
+        &self,
+        model: &str,
+        adapters: &[String],
+    ) -> Result<Option<String>, (StatusCode, Json<ErrorResponse>)> {
+        if adapters.iter().any(|adapter| adapter == model) {
+            return Ok(Some(model.to_string()));
+        }
+        if !self.enforce || model == self.model_id || self.aliases.iter().any(|a| a == model) {
+            return Ok(None);
+        }
+        metrics::increment_counter!("tgi_request_failure", "err" => "model_not_found");
+        Err(not_found(model))
+    }
+
+    /// OpenAI list of the served model, its aliases and the LoRA `adapters`, children of the
+    /// served model
+    pub(crate) fn list(&self, adapters: &[String]) -> ModelList {
+        let data = std::iter::once(&self.model_id)
+            .chain(self.aliases.iter())
+            .map(|id| self.model_object(id, None))
+            .chain(
+                adapters
+                    .iter()
+                    .map(|adapter| self.model_object(adapter, Some(self.model_id.clone()))),
+            )
+            .collect();
+        ModelList {
+            object: "list".to_string(),
+            data,
+        }
+    }
+
+    /// OpenAI description of the served model, one of its aliases or one of the LoRA `adapters`
+    pub(crate) fn get(
+        &self,
+        model: &str,
+        adapters: &[String],
+    ) -> Result<ModelObject, (StatusCode, Json<ErrorResponse>)> {
+        self.list(adapters)
+            .data
+            .into_iter()
+            .find(|model_object| model_object.id == model)
+            .ok_or_else(|| not_found(model))
+    }
+
+    fn model_object(&self, id: &str, parent: Option<String>) -> ModelObject {
+        ModelObject {
+            id: id.to_string(),
+            object: "model".to_string(),
+            created: self.created,
+            owned_by: "text-generation-inference".to_string(),
+            parent,
+        }
+    }
+}
+
+fn not_found(model: &str) -> (StatusCode, Json<ErrorResponse>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(ErrorResponse {
+            error: format!("The model `{model}` does not exist"),
+            error_type: "model_not_found".to_string(),
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_served_model_check() {
+        let served_model = ServedModel::new(
+            "mistralai/Mistral-7B-Instruct-v0.2".to_string(),
+            vec!["gpt-3.5-turbo".to_string()],
+            true,
+        );
+        assert_eq!(
+            served_model
+                .check("mistralai/Mistral-7B-Instruct-v0.2", &[])
+                .ok(),
+            Some(None)
+        );
+        assert_eq!(served_model.check("gpt-3.5-turbo", &[]).ok(), Some(None));
+
+        let (status, Json(err)) = served_model.check("gpt-4", &[]).unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(err.error_type, "model_not_found");
+
+        // The LoRA adapters are selected by the model field
+        let adapters = vec!["my-org/support-lora".to_string()];
+        assert_eq!(
+            served_model.check("my-org/support-lora", &adapters).ok(),
+            Some(Some("my-org/support-lora".to_string()))
+        );
+
+        // The model field is ignored when not enforced
+        let served_model = ServedModel::new("gpt2".to_string(), vec![], false);
+        assert_eq!(served_model.check("tgi", &[]).ok(), Some(None));
+    }
+
+    #[test]
+    fn test_served_model_list() {
+        let served_model =
+            ServedModel::new("gpt2".to_string(), vec!["gpt-3.5-turbo".to_string()], false);
+        let adapters = vec!["my-org/support-lora".to_string()];
+        let list = served_model.list(&adapters);
+        let ids: Vec<&str> = list.data.iter().map(|model| model.id.as_str()).collect();
+        assert_eq!(ids, vec!["gpt2", "gpt-3.5-turbo", "my-org/support-lora"]);
+
+        let adapter = served_model.get("my-org/support-lora", &adapters).ok();
+        assert_eq!(
+            adapter.and_then(|model| model.parent).as_deref(),
+            Some("gpt2")
+        );
+        let model = served_model.get("gpt2", &adapters).ok();
+        assert_eq!(model.map(|model| model.parent), Some(None));
+        let (status, _) = served_model.get("gpt-4", &adapters).unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
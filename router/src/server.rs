@@ -1,47 +1,121 @@
 /// HTTP Server logic
+use crate::abort::{self, AbortApiKey, AdminApiKey};
+use crate::adapters::{Adapter, AdapterError, Adapters, LoadAdapterRequest};
+use crate::alerts::Alerts;
+use crate::attention_window::{self, AttentionWindow, SystemPromptPolicy};
+use crate::audit::{self, AuditStore, RequestRecord, RequestStatus, RequestsPage, RequestsQuery};
+use crate::audit_keys::AuditKeys;
+use crate::baggage::{self, BaggageKeys};
+use crate::batch_files::{
+    self, BatchFileError, BatchFileSource, BatchFileState, BatchFileStatus, BatchFiles,
+    MAX_BATCH_FILE_SIZE,
+};
+use crate::canary;
+use crate::chaos::{Chaos, ChaosConfig};
+use crate::chat_truncation;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::deadline;
+use crate::debug_capture::{
+    self, CapturedRequest, DebugCapture, DebugCaptureConfig, DebugCaptureState,
+};
+use crate::declared_tools::DeclaredTools;
+use crate::disabled_endpoints::{DisabledEndpoints, Endpoint};
+use crate::error_catalog::{self, ErrorCatalogs};
+use crate::exemplars::{self, Exemplars};
+use crate::experiment::ExperimentRoute;
+use crate::fallback::{self, Fallback};
+use crate::fields;
+use crate::flight_recorder::{
+    DecisionEntry, DecisionOutcome, FlightRecorder, SchedulingDecision, WaitReason,
+};
+use crate::generation_stream::{GenerationStream, StreamEvent};
 use crate::health::Health;
-use crate::infer::{InferError, InferResponse, InferStreamResponse};
-use crate::validation::ValidationError;
+use crate::hedging::{self, Hedging};
+use crate::infer::{interrupted, InferError, InferResponse};
+use crate::mcp::{JsonRpcError, JsonRpcRequest, JsonRpcResponse, Mcp};
+use crate::ndjson;
+use crate::object_store::{ObjectStore, ObjectStoreError};
+use crate::openai_batch::{
+    BatchRequestBody, Dispatch, OpenAIBatch, OpenAIBatchError, OpenAIBatchList, OpenAIBatchRequest,
+    OpenAIBatchStatus, OpenAIBatches, OpenAIFile,
+};
+use crate::openai_error;
+use crate::pii::{PiiAction, PiiCategory};
+use crate::presets::{PresetParameters, Presets};
+use crate::pricing::Pricing;
+use crate::rate_limit::{self, RateLimiter};
+use crate::route_limits::RouteLimits;
+use crate::served_model::ServedModel;
+use crate::shadow_tokenizer::ShadowTokenizer;
+use crate::slo::Slo;
+use crate::sticky::{self, StickySessions};
+use crate::stream_limit::{self, StreamLimiter};
+use crate::stream_transforms::StreamTransforms;
+use crate::template_cache::{self, Conversation, TemplateCache};
+use crate::tenant::{self, Tenant, TenantKeys, TenantSummary, Tenants};
+use crate::tokenization_cache::TokenizationCache;
+use crate::tokenizer_source::TokenizerSource;
+use crate::tool_arguments::ToolArgumentsStream;
+use crate::tool_choice::{self, AutoToolStream};
+use crate::top_n_tokens::{self, on_endpoint, TopNTokensLimits};
+use crate::validation::{
+    capturing_input_tokens, parse_json_output, GrammarLimits, InputTokensCapture, ValidationError,
+};
+use crate::waiting_room::{self, TicketResponse, WaitingRoom};
+use crate::warnings;
 use crate::{
-    BestOfSequence, Details, ErrorResponse, FinishReason, GenerateParameters, GenerateRequest,
-    GenerateResponse, GrammarType, HubModelInfo, HubTokenizerConfig, Infer, Info, Message,
-    PrefillToken, SimpleToken, StreamDetails, StreamResponse, Token, TokenizeResponse, Usage,
-    Validation,
+    AbortResponse, AdapterParameters, BestOfSequence, Details, DetailsPagination, ErrorResponse,
+    FinishReason, GenerateParameters, GenerateRequest, GenerateResponse, GenerateSamplesRequest,
+    GenerateSamplesResponse, GeneratedSample, GenerationStatistics, GrammarType, HubModelInfo,
+    HubTokenizerConfig, Infer, Info, InputNormalization, Message, ModelList, ModelObject,
+    NormalizationReport, OutputManifest, PiiScanner, PrefillToken, Retokenization, SafetyReport,
+    Scheduling, ShardStatus, SimpleToken, Speculation, StreamDetails, StreamResponse,
+    TemperatureStep, Token, TokenStatistics, TokenizeRequest, TokenizeResponse,
+    TokenizerReloadRequest, TokenizerReloadResponse, Usage, Validation,
 };
 use crate::{
     ChatCompletion, ChatCompletionChoice, ChatCompletionChunk, ChatCompletionComplete,
     ChatCompletionDelta, ChatCompletionLogprob, ChatCompletionLogprobs, ChatCompletionTopLogprob,
-    ChatRequest, CompatGenerateRequest, Completion, CompletionComplete, CompletionCompleteChunk,
-    CompletionRequest, VertexRequest, VertexResponse,
+    ChatInputTokens, ChatRequest, ChatTokenizeResponse, CompatGenerateRequest, Completion,
+    CompletionComplete, CompletionCompleteChunk, CompletionLogprobs, CompletionRequest,
+    Experiments, MessageTokens, VertexPrediction, VertexRequest, VertexResponse,
 };
-use crate::{FunctionDefinition, FunctionRef, FunctionsMap, Properties, ToolCall, ToolType, Tools};
-use axum::extract::Extension;
+use crate::{FunctionDefinition, Tool, ToolCall, ToolType, Tools};
+use axum::body::{Bytes, StreamBody};
+use axum::extract::{ConnectInfo, DefaultBodyLimit, Extension, Path, Query};
 use axum::http::{HeaderMap, Method, StatusCode};
 use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
-use axum::routing::{get, post};
+use axum::routing::{delete, get, post, MethodRouter};
 use axum::{http, Json, Router};
 use axum_tracing_opentelemetry::middleware::OtelAxumLayer;
 use futures::stream::FuturesUnordered;
-use futures::stream::StreamExt;
 use futures::Stream;
-use futures::TryStreamExt;
+use futures::{StreamExt, TryStreamExt};
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use opentelemetry::trace::{FutureExt, TraceContextExt, TraceId};
+use opentelemetry::Context;
+use rand::{thread_rng, Rng};
 use serde_json::Value;
 use std::collections::HashMap;
 use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
+use std::time::Duration;
 use text_generation_client::{ShardInfo, ShardedClient};
 use tokenizers::Tokenizer;
 use tokio::signal;
 use tokio::time::Instant;
 use tower_http::cors::{AllowOrigin, CorsLayer};
 use tracing::{info_span, instrument, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
+/// Time left to the streams to send their final event once the requests are aborted
+const SHUTDOWN_FLUSH_DELAY: Duration = Duration::from_millis(500);
+
 /// Generate tokens if `stream == false` or a stream of token if `stream == true`
 #[utoipa::path(
 post,
@@ -69,6 +143,7 @@ async fn compat_generate(
     Extension(default_return_full_text): Extension<bool>,
     infer: Extension<Infer>,
     compute_type: Extension<ComputeType>,
+    object_store: Option<Extension<ObjectStore>>,
     Json(mut req): Json<CompatGenerateRequest>,
 ) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
     // default return_full_text given the pipeline_tag
@@ -82,7 +157,17 @@ async fn compat_generate(
             .await
             .into_response())
     } else {
-        let (headers, Json(generation)) = generate(infer, compute_type, Json(req.into())).await?;
+        let (headers, Json(generation)) = generate(
+            infer,
+            compute_type,
+            None,
+            object_store,
+            None,
+            None,
+            Query(DetailsPagination::default()),
+            Json(req.into()),
+        )
+        .await?;
         // wrap generation inside a Vec to match api-inference
         Ok((headers, Json(vec![generation])).into_response())
     }
@@ -96,8 +181,50 @@ path = "/info",
 responses((status = 200, description = "Served model info", body = Info))
 )]
 #[instrument]
-async fn get_model_info(info: Extension<Info>) -> Json<Info> {
-    Json(info.0)
+async fn get_model_info(info: Extension<Info>, infer: Extension<Infer>) -> Json<Info> {
+    // The chat template may have been reloaded since startup
+    let mut info = info.0;
+    info.chat_enabled = infer.chat_support().is_ok();
+    info.tools_enabled = infer.tools_support();
+    info.adapters = infer.adapters();
+    Json(info)
+}
+
+/// OpenAI compatible list of the served model, its aliases and its LoRA adapters
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/v1/models",
+responses((status = 200, description = "Served model, its aliases and its adapters", body = ModelList))
+)]
+#[instrument(skip_all)]
+async fn openai_models(
+    Extension(infer): Extension<Infer>,
+    Extension(served_model): Extension<ServedModel>,
+) -> Json<ModelList> {
+    Json(served_model.list(&infer.adapters()))
+}
+
+/// OpenAI compatible description of the served model, one of its aliases or one of its LoRA
+/// adapters
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/v1/models/{model_id}",
+params(("model_id" = String, Path, description = "Id of the model")),
+responses(
+(status = 200, description = "Model", body = ModelObject),
+(status = 404, description = "Unknown model", body = ErrorResponse,
+example = json ! ({"error": "The model `gpt-4` does not exist", "error_type": "model_not_found"})),
+)
+)]
+#[instrument(skip_all)]
+async fn openai_model(
+    Extension(infer): Extension<Infer>,
+    Extension(served_model): Extension<ServedModel>,
+    Path(model_id): Path<String>,
+) -> Result<Json<ModelObject>, (StatusCode, Json<ErrorResponse>)> {
+    Ok(Json(served_model.get(&model_id, &infer.adapters())?))
 }
 
 #[utoipa::path(
@@ -113,6 +240,15 @@ example = json ! ({"error": "unhealthy", "error_type": "healthcheck"})),
 #[instrument(skip(health))]
 /// Health check method
 async fn health(mut health: Extension<Health>) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    if health.circuit_open() {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ErrorResponse {
+                error: "circuit breaker open".to_string(),
+                error_type: "upstream_unhealthy".to_string(),
+            }),
+        ));
+    }
     match health.check().await {
         true => Ok(()),
         false => Err((
@@ -125,12 +261,29 @@ async fn health(mut health: Extension<Health>) -> Result<(), (StatusCode, Json<E
     }
 }
 
+/// Status of the model shards
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/admin/shards",
+responses((status = 200, description = "Rank, health, last error, restarts and uptime of each shard",
+body = [ShardStatus]))
+)]
+#[instrument(skip(health))]
+async fn shards(mut health: Extension<Health>) -> Json<Vec<ShardStatus>> {
+    Json(health.shards().await)
+}
+
 /// Generate tokens
 #[utoipa::path(
 post,
 tag = "Text Generation Inference",
 path = "/generate",
 request_body = GenerateRequest,
+params(
+("details_offset" = Option<usize>, Query, description = "Index of the first generated token returned in the details"),
+("details_limit" = Option<usize>, Query, description = "Maximum number of generated tokens returned in the details"),
+),
 responses(
 (status = 200, description = "Generated Text", body = GenerateResponse),
 (status = 424, description = "Generation Error", body = ErrorResponse,
@@ -158,12 +311,72 @@ seed,
 async fn generate(
     infer: Extension<Infer>,
     Extension(ComputeType(compute_type)): Extension<ComputeType>,
+    audit: Option<Extension<AuditStore>>,
+    object_store: Option<Extension<ObjectStore>>,
+    tenant: Option<Extension<Tenant>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Query(pagination): Query<DetailsPagination>,
     Json(req): Json<GenerateRequest>,
 ) -> Result<(HeaderMap, Json<GenerateResponse>), (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
     let start_time = Instant::now();
     metrics::increment_counter!("tgi_request_count");
 
+    // Job-style requests: the generated text is written to the object store, not returned
+    if let Some(destination) = req.parameters.output_destination.clone() {
+        let Some(Extension(object_store)) = object_store else {
+            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            return Err(InferError::from(ValidationError::OutputDestinationDisabled).into());
+        };
+        if req.parameters.best_of.is_some_and(|best_of| best_of != 1) {
+            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            return Err(InferError::from(ValidationError::OutputDestinationUnsupported).into());
+        }
+        let output = object_store
+            .generate(&infer, req, destination)
+            .await
+            .map_err(|err| {
+                if let InferError::ObjectStore(_) = err {
+                    metrics::increment_counter!("tgi_request_failure", "err" => "object_store");
+                    tracing::error!("{err}");
+                }
+                err
+            })?;
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-request-id", request_id(&span).parse().unwrap());
+        headers.insert("x-compute-type", compute_type.parse().unwrap());
+        headers.insert(
+            "x-total-time",
+            start_time
+                .elapsed()
+                .as_millis()
+                .to_string()
+                .parse()
+                .unwrap(),
+        );
+        headers.insert("x-generated-tokens", output.generated_tokens.into());
+        metrics::increment_counter!("tgi_request_success");
+        exemplars::histogram(
+            infer.exemplars(),
+            "tgi_request_duration",
+            &[],
+            start_time.elapsed().as_secs_f64(),
+            &span,
+        );
+        tracing::info!("Output written to {}", output.destination);
+
+        let response = GenerateResponse {
+            generated_text: String::new(),
+            details: None,
+            output: Some(output),
+            safety: None,
+            parsed: None,
+            warnings: warnings::current(),
+        };
+        return Ok((headers, Json(response)));
+    }
+
     tracing::debug!("Input: {}", req.inputs);
 
     let compute_characters = req.inputs.chars().count();
@@ -172,20 +385,57 @@ async fn generate(
         add_prompt = Some(req.inputs.clone());
     }
 
-    let details: bool = req.parameters.details || req.parameters.decoder_input_details;
+    // Prefill-only requests always return their prefill details
+    let details: bool = req.parameters.details
+        || req.parameters.decoder_input_details
+        || req.parameters.max_new_tokens == Some(0);
+    let scheduling = req.parameters.scheduling;
+    let return_statistics = req.parameters.return_statistics;
+    let grammar = grammar_label(&req.parameters);
+    let parsed_schema = match &req.parameters.grammar {
+        Some(GrammarType::Json(schema)) if req.parameters.return_parsed => Some(schema.clone()),
+        _ => None,
+    };
+    let estimated_completion_time = req
+        .parameters
+        .max_new_tokens
+        .and_then(|max_new_tokens| infer.estimated_completion_time(max_new_tokens));
 
-    // Inference
-    let (response, best_of_responses) = match req.parameters.best_of {
+    // Inference, abortable by request id
+    let request_id = request_id(&span);
+    let context = abort::with_request_id(request_id.clone());
+    let (mut response, best_of_responses) = match req.parameters.best_of {
         Some(best_of) if best_of > 1 => {
-            let (response, best_of_responses) = infer.generate_best_of(req, best_of).await?;
+            let (response, best_of_responses) = infer
+                .generate_best_of(req, best_of)
+                .with_context(context)
+                .await?;
             (response, Some(best_of_responses))
         }
-        _ => (infer.generate(req).await?, None),
+        _ => (infer.generate(req).with_context(context).await?, None),
     };
 
+    // Personally identifiable information in the transformed generated text and its tokens,
+    // and in the other sequences
+    response.generated_text.text =
+        infer.transform_output(std::mem::take(&mut response.generated_text.text));
+    let safety = infer.scan_response(&mut response)?;
+    let generated_text = std::mem::take(&mut response.generated_text.text);
+    let best_of_responses = best_of_responses
+        .map(|responses| {
+            responses
+                .into_iter()
+                .map(|mut response| {
+                    infer.scan_response(&mut response)?;
+                    Ok(response)
+                })
+                .collect::<Result<Vec<InferResponse>, InferError>>()
+        })
+        .transpose()?;
+
     // Token details
     let input_length = response._input_length;
-    let details = match details {
+    let mut details = match details {
         true => {
             // convert best_of_responses
             let best_of_sequences = best_of_responses.map(|responses: Vec<InferResponse>| {
@@ -213,6 +463,10 @@ async fn generate(
                     .collect()
             });
 
+            let statistics = match return_statistics {
+                true => GenerationStatistics::new(&response.tokens, &response.top_tokens),
+                false => None,
+            };
             Some(Details {
                 finish_reason: FinishReason::from(response.generated_text.finish_reason),
                 generated_tokens: response.generated_text.generated_tokens,
@@ -221,17 +475,38 @@ async fn generate(
                 seed: response.generated_text.seed,
                 best_of_sequences,
                 top_tokens: response.top_tokens,
+                scheduling: scheduling.then(|| {
+                    Scheduling::new(response.queued, response.batching_cycles, response.batch_id)
+                }),
+                speculation: response.speculation,
+                retokenization: response.retokenization,
+                normalization: response.normalization,
+                statistics,
+                tokens_outside_window: infer
+                    .tokens_outside_window(input_length, response.generated_text.generated_tokens),
             })
         }
         false => None,
     };
 
+    // Store the full details so they can be fetched page by page, by the same client only
+    let client = stream_limit::client_id(tenant.as_deref(), connect_info);
+    if let (Some(Extension(audit)), Some(details), Some(client)) = (audit, &details, client) {
+        audit.insert_details(client, request_id.clone(), details.clone());
+    }
+    if let Some(details) = details.as_mut() {
+        pagination.paginate(details);
+    }
+
     // Timings
     let total_time = start_time.elapsed();
     let validation_time = response.queued - start_time;
     let queue_time = response.start - response.queued;
     let inference_time = Instant::now() - response.start;
-    let time_per_token = inference_time / response.generated_text.generated_tokens;
+    // Prefill-only requests do not generate any token
+    let time_per_token = inference_time
+        .checked_div(response.generated_text.generated_tokens)
+        .unwrap_or_default();
 
     // Tracing metadata
     span.record("total_time", format!("{total_time:?}"));
@@ -243,6 +518,7 @@ async fn generate(
 
     // Headers
     let mut headers = HeaderMap::new();
+    headers.insert("x-request-id", request_id.parse().unwrap());
     headers.insert("x-compute-type", compute_type.parse().unwrap());
     headers.insert(
         "x-compute-time",
@@ -274,48 +550,929 @@ async fn generate(
     );
     headers.insert("x-prompt-tokens", input_length.into());
     headers.insert(
-        "x-generated-tokens",
-        response.generated_text.generated_tokens.into(),
+        "x-generated-tokens",
+        response.generated_text.generated_tokens.into(),
+    );
+    if let Some(cost) = infer.estimate_cost(input_length, response.generated_text.generated_tokens)
+    {
+        headers.insert("x-estimated-cost", format!("{cost:.6}").parse().unwrap());
+    }
+    if let Some(estimated_completion_time) = estimated_completion_time {
+        headers.insert(
+            "x-estimated-completion-time",
+            estimated_completion_time
+                .as_millis()
+                .to_string()
+                .parse()
+                .unwrap(),
+        );
+    }
+
+    // Metrics
+    metrics::increment_counter!("tgi_request_success");
+    exemplars::histogram(
+        infer.exemplars(),
+        "tgi_request_duration",
+        &[],
+        total_time.as_secs_f64(),
+        &span,
+    );
+    metrics::histogram!(
+        "tgi_request_validation_duration",
+        validation_time.as_secs_f64()
+    );
+    metrics::histogram!("tgi_request_queue_duration", queue_time.as_secs_f64());
+    metrics::histogram!(
+        "tgi_request_inference_duration",
+        inference_time.as_secs_f64()
+    );
+    if response.generated_text.generated_tokens > 0 {
+        metrics::histogram!(
+            "tgi_request_mean_time_per_token_duration",
+            time_per_token.as_secs_f64()
+        );
+        if let Some(slo) = infer.slo() {
+            slo.time_per_token(time_per_token);
+        }
+        metrics::histogram!(
+            "tgi_grammar_mean_time_per_token_duration",
+            time_per_token.as_secs_f64(),
+            "grammar" => grammar
+        );
+    }
+    metrics::histogram!(
+        "tgi_request_generated_tokens",
+        response.generated_text.generated_tokens as f64
+    );
+
+    // Generated text parsed as JSON
+    let parsed = parsed_schema.and_then(|schema| {
+        parse_json_output(&generated_text, &schema)
+            .map_err(|err| {
+                metrics::increment_counter!("tgi_request_parse_failure");
+                tracing::warn!("Generated text does not match the schema: {err}");
+            })
+            .ok()
+    });
+
+    // Send response
+    let mut output_text = generated_text;
+    if let Some(prompt) = add_prompt {
+        output_text = prompt + &output_text;
+    }
+
+    tracing::debug!("Output: {}", output_text);
+    tracing::info!("Success");
+
+    let response = GenerateResponse {
+        generated_text: output_text,
+        details,
+        output: None,
+        safety,
+        parsed,
+        warnings: warnings::current(),
+    };
+    Ok((headers, Json(response)))
+}
+
+/// Get a page of the details of a completed generation. Only the client that sent the request,
+/// identified by its tenant or else by its IP address, can get its details
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/results/{request_id}/details",
+params(
+("request_id" = String, Path, description = "Request id sent in the `x-request-id` header"),
+("details_offset" = Option<usize>, Query, description = "Index of the first generated token returned"),
+("details_limit" = Option<usize>, Query, description = "Maximum number of generated tokens returned"),
+),
+responses(
+(status = 200, description = "Generation details", body = Details),
+(status = 404, description = "Unknown request", body = ErrorResponse,
+example = json ! ({"error": "Unknown request id"})),
+)
+)]
+#[instrument(skip_all)]
+async fn get_details(
+    audit: Option<Extension<AuditStore>>,
+    Extension(tenants): Extension<Tenants>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Path(request_id): Path<String>,
+    Query(pagination): Query<DetailsPagination>,
+) -> Result<Json<Details>, (StatusCode, Json<ErrorResponse>)> {
+    let client = stream_limit::client_id(tenants.find(&headers).as_ref(), connect_info);
+    let mut details = audit
+        .zip(client)
+        .and_then(|(Extension(audit), client)| audit.details(&client, &request_id))
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                Json(ErrorResponse {
+                    error: "Unknown request id".to_string(),
+                    error_type: "not_found".to_string(),
+                }),
+            )
+        })?;
+    pagination.paginate(&mut details);
+    Ok(Json(details))
+}
+
+/// Last completed requests, newest first
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/admin/requests",
+params(
+("status" = Option<RequestStatus>, Query, description = "Only return the successful or the failed requests"),
+("since" = Option<u64>, Query, description = "Only return the requests started at or after this unix timestamp (in seconds)"),
+("limit" = Option<usize>, Query, description = "Maximum number of requests returned"),
+("cursor" = Option<u64>, Query, description = "`next_cursor` of the previous page"),
+),
+responses(
+(status = 200, description = "Completed requests", body = RequestsPage),
+(status = 404, description = "Audit store disabled", body = ErrorResponse,
+example = json ! ({"error": "Audit store disabled"})),
+)
+)]
+#[instrument(skip_all)]
+async fn get_requests(
+    audit: Option<Extension<AuditStore>>,
+    Query(query): Query<RequestsQuery>,
+) -> Result<Json<RequestsPage>, (StatusCode, Json<ErrorResponse>)> {
+    match audit {
+        Some(Extension(audit)) => Ok(Json(audit.requests(&query))),
+        None => Err((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: "Audit store disabled".to_string(),
+                error_type: "not_found".to_string(),
+            }),
+        )),
+    }
+}
+
+/// Live counters of the tenants, identified by their API key or named by a trusted proxy
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/admin/tenants",
+responses(
+(status = 200, description = "Tenants counters", body = Vec<TenantSummary>),
+)
+)]
+#[instrument(skip_all)]
+async fn get_tenants(Extension(tenants): Extension<Tenants>) -> Json<Vec<TenantSummary>> {
+    Json(tenants.summary())
+}
+
+/// Submit a batch file of requests, processed in the background. Upload the JSONL (one
+/// `GenerateRequest` with an optional `custom_id` per line) or CSV (an `inputs` column and one
+/// column per parameter) file, or send the `input_uri` of a file in the object store.
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/batch_files",
+request_body(content = BatchFileSource, description = "JSONL or CSV file, or the `input_uri` of a file in the object store",
+content_type = "application/jsonl"),
+responses(
+(status = 202, description = "Batch file accepted", body = BatchFileStatus),
+(status = 422, description = "Invalid batch file", body = ErrorResponse,
+example = json ! ({"error": "Invalid batch file: line 2: missing field `inputs`"})),
+(status = 429, description = "Too many batch files in progress", body = ErrorResponse,
+example = json ! ({"error": "Too many batch files in progress, retry later"})),
+)
+)]
+#[instrument(skip_all)]
+async fn submit_batch_file(
+    Extension(infer): Extension<Infer>,
+    Extension(batch_files): Extension<BatchFiles>,
+    object_store: Option<Extension<ObjectStore>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<(StatusCode, Json<BatchFileStatus>), (StatusCode, Json<ErrorResponse>)> {
+    let content_type = headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let object_store = object_store.map(|Extension(object_store)| object_store);
+    let lines = batch_files::read_lines(content_type, body, object_store.as_ref()).await?;
+    let status = batch_files.submit(infer, lines)?;
+    Ok((StatusCode::ACCEPTED, Json(status)))
+}
+
+/// Progress of a batch file
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/batch_files/{batch_id}",
+params(("batch_id" = String, Path, description = "`id` returned when submitting the batch file")),
+responses(
+(status = 200, description = "Batch file progress", body = BatchFileStatus),
+(status = 404, description = "Unknown batch file", body = ErrorResponse,
+example = json ! ({"error": "Unknown batch file"})),
+)
+)]
+#[instrument(skip_all)]
+async fn get_batch_file(
+    Extension(batch_files): Extension<BatchFiles>,
+    Path(batch_id): Path<String>,
+) -> Result<Json<BatchFileStatus>, (StatusCode, Json<ErrorResponse>)> {
+    Ok(Json(batch_files.status(&batch_id)?))
+}
+
+/// Download the results of a completed batch file, one JSON object per line in the order of the
+/// requests
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/batch_files/{batch_id}/results",
+params(("batch_id" = String, Path, description = "`id` returned when submitting the batch file")),
+responses(
+(status = 200, description = "Batch file results", content_type = "application/jsonl", body = String),
+(status = 404, description = "Unknown batch file", body = ErrorResponse,
+example = json ! ({"error": "Unknown batch file"})),
+(status = 409, description = "Batch file still in progress", body = ErrorResponse,
+example = json ! ({"error": "Batch file is still in progress"})),
+)
+)]
+#[instrument(skip_all)]
+async fn get_batch_file_results(
+    Extension(batch_files): Extension<BatchFiles>,
+    Path(batch_id): Path<String>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let results = batch_files.results(&batch_id)?;
+    let disposition = format!("attachment; filename=\"{batch_id}.jsonl\"");
+    Ok((
+        [
+            (http::header::CONTENT_TYPE, "application/jsonl".to_string()),
+            (http::header::CONTENT_DISPOSITION, disposition),
+        ],
+        results,
+    )
+        .into_response())
+}
+
+/// Upload a JSONL file of requests for the OpenAI Batch API, as the `file` field of a
+/// `multipart/form-data` body with the `batch` purpose
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/v1/files",
+request_body(content = String, description = "`purpose` and `file` fields", content_type = "multipart/form-data"),
+responses(
+(status = 200, description = "Uploaded file", body = OpenAIFile),
+(status = 422, description = "Invalid upload", body = ErrorResponse,
+example = json ! ({"error": "Only the `batch` purpose is supported. Given: fine-tune"})),
+(status = 429, description = "Too many files stored", body = ErrorResponse,
+example = json ! ({"error": "Too many files stored, retry later"})),
+)
+)]
+#[instrument(skip_all)]
+async fn upload_file(
+    Extension(openai_batches): Extension<OpenAIBatches>,
+    Extension(tenants): Extension<Tenants>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<OpenAIFile>, (StatusCode, Json<ErrorResponse>)> {
+    let client = batch_client(&tenants, &headers, connect_info);
+    let content_type = headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    Ok(Json(openai_batches.upload(&client, content_type, &body)?))
+}
+
+/// Uploaded file, or output file of a batch
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/v1/files/{file_id}",
+params(("file_id" = String, Path, description = "Id of the file")),
+responses(
+(status = 200, description = "File", body = OpenAIFile),
+(status = 404, description = "Unknown file", body = ErrorResponse,
+example = json ! ({"error": "No file found with id file-abc"})),
+)
+)]
+#[instrument(skip_all)]
+async fn get_file(
+    Extension(openai_batches): Extension<OpenAIBatches>,
+    Extension(tenants): Extension<Tenants>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Path(file_id): Path<String>,
+) -> Result<Json<OpenAIFile>, (StatusCode, Json<ErrorResponse>)> {
+    let client = batch_client(&tenants, &headers, connect_info);
+    Ok(Json(openai_batches.file(&client, &file_id)?))
+}
+
+/// Content of an uploaded file, or of the output file of a batch
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/v1/files/{file_id}/content",
+params(("file_id" = String, Path, description = "Id of the file")),
+responses(
+(status = 200, description = "JSONL content of the file", content_type = "application/jsonl", body = String),
+(status = 404, description = "Unknown file", body = ErrorResponse,
+example = json ! ({"error": "No file found with id file-abc"})),
+)
+)]
+#[instrument(skip_all)]
+async fn get_file_content(
+    Extension(openai_batches): Extension<OpenAIBatches>,
+    Extension(tenants): Extension<Tenants>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Path(file_id): Path<String>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let client = batch_client(&tenants, &headers, connect_info);
+    let content = openai_batches.file_content(&client, &file_id)?;
+    Ok(([(http::header::CONTENT_TYPE, "application/jsonl")], content).into_response())
+}
+
+/// Create a batch of the requests of an uploaded file. The requests are queued after the
+/// interactive requests.
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/v1/batches",
+request_body = OpenAIBatchRequest,
+responses(
+(status = 200, description = "Created batch", body = OpenAIBatch),
+(status = 404, description = "Unknown input file", body = ErrorResponse,
+example = json ! ({"error": "No file found with id file-abc"})),
+(status = 422, description = "Invalid input file", body = ErrorResponse,
+example = json ! ({"error": "Invalid input file: line 2: missing field `custom_id`"})),
+(status = 429, description = "Too many batches in progress or kept for their results", body = ErrorResponse,
+example = json ! ({"error": "Too many batches in progress, retry later"})),
+)
+)]
+#[instrument(skip_all)]
+async fn create_batch(
+    Extension(openai_batches): Extension<OpenAIBatches>,
+    Extension(tenants): Extension<Tenants>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Json(req): Json<OpenAIBatchRequest>,
+) -> Result<Json<OpenAIBatch>, (StatusCode, Json<ErrorResponse>)> {
+    let client = batch_client(&tenants, &headers, connect_info);
+    Ok(Json(openai_batches.create(&client, req)?))
+}
+
+/// Batches, newest first
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/v1/batches",
+responses(
+(status = 200, description = "Batches", body = OpenAIBatchList),
+)
+)]
+#[instrument(skip_all)]
+async fn list_batches(
+    Extension(openai_batches): Extension<OpenAIBatches>,
+    Extension(tenants): Extension<Tenants>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+) -> Json<OpenAIBatchList> {
+    let client = batch_client(&tenants, &headers, connect_info);
+    Json(openai_batches.list(&client))
+}
+
+/// Client owning the files and batches of the OpenAI Batch API: the tenant of the request, or
+/// else its IP address. Without both, e.g. behind an ngrok tunnel, the files and batches are
+/// shared
+fn batch_client(
+    tenants: &Tenants,
+    headers: &HeaderMap,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+) -> String {
+    stream_limit::client_id(tenants.find(headers).as_ref(), connect_info)
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Progress of a batch
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/v1/batches/{batch_id}",
+params(("batch_id" = String, Path, description = "Id of the batch")),
+responses(
+(status = 200, description = "Batch", body = OpenAIBatch),
+(status = 404, description = "Unknown batch", body = ErrorResponse,
+example = json ! ({"error": "No batch found with id batch_abc"})),
+)
+)]
+#[instrument(skip_all)]
+async fn get_batch(
+    Extension(openai_batches): Extension<OpenAIBatches>,
+    Extension(tenants): Extension<Tenants>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Path(batch_id): Path<String>,
+) -> Result<Json<OpenAIBatch>, (StatusCode, Json<ErrorResponse>)> {
+    let client = batch_client(&tenants, &headers, connect_info);
+    Ok(Json(openai_batches.batch(&client, &batch_id)?))
+}
+
+/// Cancel a batch: its requests not started yet are skipped, the in-flight ones complete
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/v1/batches/{batch_id}/cancel",
+params(("batch_id" = String, Path, description = "Id of the batch")),
+responses(
+(status = 200, description = "Cancelling batch", body = OpenAIBatch),
+(status = 404, description = "Unknown batch", body = ErrorResponse,
+example = json ! ({"error": "No batch found with id batch_abc"})),
+(status = 409, description = "Finished batch", body = ErrorResponse,
+example = json ! ({"error": "Batch batch_abc is not in progress and cannot be cancelled"})),
+)
+)]
+#[instrument(skip_all)]
+async fn cancel_batch(
+    Extension(openai_batches): Extension<OpenAIBatches>,
+    Extension(tenants): Extension<Tenants>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Path(batch_id): Path<String>,
+) -> Result<Json<OpenAIBatch>, (StatusCode, Json<ErrorResponse>)> {
+    let client = batch_client(&tenants, &headers, connect_info);
+    Ok(Json(openai_batches.cancel(&client, &batch_id)?))
+}
+
+/// Experimental Model Context Protocol (MCP) endpoint: JSON-RPC 2.0 messages listing and calling
+/// the `generate` and `chat` tools. The chat tool can call the tools declared by the server.
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/mcp",
+request_body = JsonRpcRequest,
+responses(
+(status = 200, description = "JSON-RPC response", body = JsonRpcResponse),
+(status = 202, description = "Notification received"),
+)
+)]
+#[instrument(skip_all)]
+async fn mcp(Extension(mcp): Extension<Mcp>, body: Bytes) -> Response {
+    match mcp.handle(&body).await {
+        Some(response) => Json(response).into_response(),
+        None => StatusCode::ACCEPTED.into_response(),
+    }
+}
+
+/// Last scheduling decisions of the queue, newest first: the batched entries, why the others
+/// waited and the budgets of each decision
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/admin/flight_recorder",
+responses(
+(status = 200, description = "Scheduling decisions", body = Vec<SchedulingDecision>),
+(status = 404, description = "Flight recorder disabled", body = ErrorResponse,
+example = json ! ({"error": "Flight recorder disabled"})),
+)
+)]
+#[instrument(skip_all)]
+async fn get_flight_recorder(
+    flight_recorder: Option<Extension<FlightRecorder>>,
+) -> Result<Json<Vec<SchedulingDecision>>, (StatusCode, Json<ErrorResponse>)> {
+    match flight_recorder {
+        Some(Extension(flight_recorder)) => Ok(Json(flight_recorder.decisions())),
+        None => Err((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: "Flight recorder disabled".to_string(),
+                error_type: "not_found".to_string(),
+            }),
+        )),
+    }
+}
+
+/// Armed debug capture and the payloads of the captured failing requests, their PII masked
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/admin/debug_capture",
+responses(
+(status = 200, description = "Debug capture", body = DebugCaptureState),
+(status = 404, description = "Debug capture disabled", body = ErrorResponse,
+example = json ! ({"error": "Debug capture disabled"})),
+)
+)]
+#[instrument(skip_all)]
+async fn get_debug_capture(
+    debug_capture: Option<Extension<DebugCapture>>,
+) -> Result<Json<DebugCaptureState>, (StatusCode, Json<ErrorResponse>)> {
+    match debug_capture {
+        Some(Extension(debug_capture)) => Ok(Json(debug_capture.state())),
+        None => Err(debug_capture_disabled()),
+    }
+}
+
+/// Capture the payloads of the next `count` requests failing with `status`, e.g. to reproduce
+/// the 424 reported by a user. A `count` of 0 disarms the capture.
+#[utoipa::path(
+put,
+tag = "Text Generation Inference",
+path = "/admin/debug_capture",
+request_body = DebugCaptureConfig,
+responses(
+(status = 200, description = "Debug capture", body = DebugCaptureState),
+(status = 404, description = "Debug capture disabled", body = ErrorResponse,
+example = json ! ({"error": "Debug capture disabled"})),
+(status = 422, description = "Invalid status code", body = ErrorResponse,
+example = json ! ({"error": "`status` must be an error status code (4xx or 5xx). Given: 200"})),
+)
+)]
+#[instrument(skip_all)]
+async fn configure_debug_capture(
+    debug_capture: Option<Extension<DebugCapture>>,
+    Json(config): Json<DebugCaptureConfig>,
+) -> Result<Json<DebugCaptureState>, (StatusCode, Json<ErrorResponse>)> {
+    let Some(Extension(debug_capture)) = debug_capture else {
+        return Err(debug_capture_disabled());
+    };
+    debug_capture.configure(config).map(Json).map_err(|error| {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ErrorResponse {
+                error,
+                error_type: "debug_capture".to_string(),
+            }),
+        )
+    })
+}
+
+fn debug_capture_disabled() -> (StatusCode, Json<ErrorResponse>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(ErrorResponse {
+            error: "Debug capture disabled".to_string(),
+            error_type: "not_found".to_string(),
+        }),
+    )
+}
+
+/// Faults injected by the chaos mode
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/admin/chaos",
+responses((status = 200, description = "Injected faults", body = ChaosConfig))
+)]
+#[instrument(skip_all)]
+async fn get_chaos(infer: Extension<Infer>) -> Json<ChaosConfig> {
+    Json(
+        infer
+            .chaos()
+            .map(|chaos| chaos.config())
+            .unwrap_or_default(),
+    )
+}
+
+/// Configure the faults injected by the chaos mode: random queue delays, synthetic shard errors
+/// and dropped stream chunks. Only served with `--chaos`.
+#[utoipa::path(
+put,
+tag = "Text Generation Inference",
+path = "/admin/chaos",
+request_body = ChaosConfig,
+responses(
+(status = 200, description = "Injected faults", body = ChaosConfig),
+(status = 422, description = "Invalid ratio", body = ErrorResponse,
+example = json ! ({"error": "`shard_error_ratio` must be >= 0 and <= 1. Given: 2"})),
+)
+)]
+#[instrument(skip_all)]
+async fn configure_chaos(
+    infer: Extension<Infer>,
+    Json(config): Json<ChaosConfig>,
+) -> Result<Json<ChaosConfig>, (StatusCode, Json<ErrorResponse>)> {
+    match infer.chaos().map(|chaos| chaos.configure(config)) {
+        Some(Ok(config)) => Ok(Json(config)),
+        Some(Err(error)) => Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ErrorResponse {
+                error,
+                error_type: "chaos".to_string(),
+            }),
+        )),
+        None => Err((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: "Chaos mode disabled".to_string(),
+                error_type: "not_found".to_string(),
+            }),
+        )),
+    }
+}
+
+/// Reload the tokenizer and the tokenizer config, e.g. after the shards were updated to a new
+/// revision. The in-flight requests are not dropped.
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/admin/tokenizer/reload",
+request_body = TokenizerReloadRequest,
+responses(
+(status = 200, description = "Reloaded tokenizer", body = TokenizerReloadResponse),
+(status = 422, description = "Invalid tokenizer or chat template", body = ErrorResponse,
+example = json ! ({"error": "Could not load a fast tokenizer"})),
+)
+)]
+#[instrument(skip_all)]
+async fn reload_tokenizer(
+    Extension(infer): Extension<Infer>,
+    Extension(info): Extension<Info>,
+    Extension(tokenizer_source): Extension<TokenizerSource>,
+    Json(req): Json<TokenizerReloadRequest>,
+) -> Result<Json<TokenizerReloadResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let tokenizer_source = tokenizer_source.with_revision(req.revision);
+    let (tokenizer, tokenizer_config) = tokenizer_source.load(true).await;
+
+    // Do not silently disable the input validation
+    if tokenizer.is_none() && infer.fast_tokenizer() {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ErrorResponse {
+                error: "Could not load a fast tokenizer".to_string(),
+                error_type: "tokenizer_reload".to_string(),
+            }),
+        ));
+    }
+
+    let fast_tokenizer = tokenizer.is_some();
+    let chat_template = tokenizer_config.chat_template.is_some();
+    infer.reload_tokenizer(tokenizer, tokenizer_config)?;
+    tracing::info!(
+        "Reloaded tokenizer at revision {}",
+        tokenizer_source.revision().unwrap_or("main")
+    );
+
+    Ok(Json(TokenizerReloadResponse {
+        revision: tokenizer_source.revision().map(String::from),
+        fast_tokenizer,
+        chat_template,
+        system_fingerprint: infer.system_fingerprint(&info),
+    }))
+}
+
+/// LoRA adapters resident on the shards and the memory of their weights
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/admin/adapters",
+responses(
+(status = 200, description = "Resident adapters", body = Vec<Adapter>),
+(status = 424, description = "Shard error", body = ErrorResponse,
+example = json ! ({"error": "Shards failed to update the adapters: Server error: ..."})),
+)
+)]
+#[instrument(skip_all)]
+async fn get_adapters(
+    Extension(infer): Extension<Infer>,
+    Extension(adapters): Extension<Adapters>,
+) -> Result<Json<Vec<Adapter>>, (StatusCode, Json<ErrorResponse>)> {
+    Ok(Json(adapters.list(&infer).await?))
+}
+
+/// Load a LoRA adapter on the shards without restarting. The requests can select it in their
+/// `adapter_id` once loaded.
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/admin/adapters",
+request_body = LoadAdapterRequest,
+responses(
+(status = 200, description = "Resident adapters", body = Vec<Adapter>),
+(status = 403, description = "Adapter not in the allowlist", body = ErrorResponse,
+example = json ! ({"error": "LoRA adapter `my-org/support-lora` is not in the adapter allowlist"})),
+(status = 422, description = "Invalid adapter id", body = ErrorResponse,
+example = json ! ({"error": "`adapter_id` must not be empty"})),
+(status = 424, description = "Shard error", body = ErrorResponse,
+example = json ! ({"error": "Shards failed to update the adapters: Server error: ..."})),
+)
+)]
+#[instrument(skip_all, fields(adapter_id = %req.adapter_id))]
+async fn load_adapter(
+    Extension(infer): Extension<Infer>,
+    Extension(adapters): Extension<Adapters>,
+    Json(req): Json<LoadAdapterRequest>,
+) -> Result<Json<Vec<Adapter>>, (StatusCode, Json<ErrorResponse>)> {
+    Ok(Json(adapters.load(&infer, req.adapter_id).await?))
+}
+
+/// Evict a LoRA adapter from the shards. The new requests selecting it are rejected. The
+/// adapters still used by queued or running requests are not evicted (409).
+#[utoipa::path(
+delete,
+tag = "Text Generation Inference",
+path = "/admin/adapters/{adapter_id}",
+params(("adapter_id" = String, Path, description = "Adapter to evict")),
+responses(
+(status = 200, description = "Resident adapters", body = Vec<Adapter>),
+(status = 404, description = "Adapter not loaded", body = ErrorResponse,
+example = json ! ({"error": "LoRA adapter `my-org/support-lora` is not loaded"})),
+(status = 409, description = "Adapter used by requests in flight", body = ErrorResponse,
+example = json ! ({"error": "LoRA adapter `my-org/support-lora` is used by 2 requests in flight"})),
+(status = 424, description = "Shard error", body = ErrorResponse,
+example = json ! ({"error": "Shards failed to update the adapters: Server error: ..."})),
+)
+)]
+#[instrument(skip_all, fields(adapter_id))]
+async fn unload_adapter(
+    Extension(infer): Extension<Infer>,
+    Extension(adapters): Extension<Adapters>,
+    Path(adapter_id): Path<String>,
+) -> Result<Json<Vec<Adapter>>, (StatusCode, Json<ErrorResponse>)> {
+    tracing::Span::current().record("adapter_id", adapter_id.as_str());
+    Ok(Json(adapters.unload(&infer, adapter_id).await?))
+}
+
+/// Abort an in-flight generation, e.g. a stream flagged by a moderation system. The generation
+/// ends at its next token with the `aborted` finish reason.
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/abort/{request_id}",
+params(
+("request_id" = String, Path, description = "Request id sent in the `x-request-id` header"),
+),
+responses(
+(status = 200, description = "Whether the generation was found", body = AbortResponse),
+(status = 401, description = "Missing or invalid API key", body = ErrorResponse,
+example = json ! ({"error": "Invalid abort API key"})),
+)
+)]
+#[instrument(skip_all)]
+async fn abort_request(
+    Extension(infer): Extension<Infer>,
+    Extension(AbortApiKey(api_key)): Extension<AbortApiKey>,
+    headers: HeaderMap,
+    Path(request_id): Path<String>,
+) -> Result<Json<AbortResponse>, (StatusCode, Json<ErrorResponse>)> {
+    if !abort::authorized(&headers, &api_key) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            Json(ErrorResponse {
+                error: "Invalid abort API key".to_string(),
+                error_type: "unauthorized".to_string(),
+            }),
+        ));
+    }
+
+    let aborted = infer.abort(&request_id);
+    Ok(Json(AbortResponse {
+        request_id,
+        aborted,
+    }))
+}
+
+/// Generate multiple independent samples of the same prompt
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/generate_samples",
+request_body = GenerateSamplesRequest,
+responses(
+(status = 200, description = "Generated Samples", body = GenerateSamplesResponse),
+(status = 424, description = "Generation Error", body = ErrorResponse,
+example = json ! ({"error": "Request failed during generation"})),
+(status = 429, description = "Model is overloaded", body = ErrorResponse,
+example = json ! ({"error": "Model is overloaded"})),
+(status = 422, description = "Input validation error", body = ErrorResponse,
+example = json ! ({"error": "Input validation error"})),
+(status = 500, description = "Incomplete generation", body = ErrorResponse,
+example = json ! ({"error": "Incomplete generation"})),
+)
+)]
+#[instrument(
+skip_all,
+fields(
+parameters = ? req.parameters,
+n = req.n,
+total_time,
+)
+)]
+async fn generate_samples(
+    infer: Extension<Infer>,
+    Extension(ComputeType(compute_type)): Extension<ComputeType>,
+    Json(req): Json<GenerateSamplesRequest>,
+) -> Result<(HeaderMap, Json<GenerateSamplesResponse>), (StatusCode, Json<ErrorResponse>)> {
+    let span = tracing::Span::current();
+    let start_time = Instant::now();
+    metrics::increment_counter!("tgi_request_count");
+
+    tracing::debug!("Input: {}", req.inputs);
+
+    let compute_characters = req.inputs.chars().count();
+    let mut add_prompt = None;
+    if req.parameters.return_full_text.unwrap_or(false) {
+        add_prompt = Some(req.inputs.clone());
+    }
+
+    let details: bool = req.parameters.details || req.parameters.decoder_input_details;
+    let scheduling = req.parameters.scheduling;
+    let return_statistics = req.parameters.return_statistics;
+
+    // Inference
+    let n = req.n;
+    let request = GenerateRequest {
+        inputs: req.inputs,
+        parameters: req.parameters,
+    };
+    let mut responses = infer.generate_samples(request, n).await?;
+    // Personally identifiable information in the samples
+    for response in responses.iter_mut() {
+        infer.scan_response(response)?;
+    }
+
+    let mut generated_tokens = 0;
+    let samples = responses
+        .into_iter()
+        .map(|response: InferResponse| {
+            generated_tokens += response.generated_text.generated_tokens;
+
+            // Add prompt if return_full_text
+            let mut output_text = response.generated_text.text;
+            if let Some(prompt) = &add_prompt {
+                output_text = prompt.clone() + &output_text;
+            }
+
+            let statistics = match return_statistics {
+                true => GenerationStatistics::new(&response.tokens, &response.top_tokens),
+                false => None,
+            };
+            let details = details.then(|| Details {
+                finish_reason: FinishReason::from(response.generated_text.finish_reason),
+                generated_tokens: response.generated_text.generated_tokens,
+                prefill: response.prefill,
+                tokens: response.tokens,
+                seed: response.generated_text.seed,
+                best_of_sequences: None,
+                top_tokens: response.top_tokens,
+                scheduling: scheduling.then(|| {
+                    Scheduling::new(response.queued, response.batching_cycles, response.batch_id)
+                }),
+                speculation: response.speculation,
+                retokenization: response.retokenization,
+                normalization: response.normalization,
+                statistics,
+                tokens_outside_window: infer.tokens_outside_window(
+                    response._input_length,
+                    response.generated_text.generated_tokens,
+                ),
+            });
+
+            GeneratedSample {
+                generated_text: output_text,
+                seed: response.generated_text.seed,
+                details,
+            }
+        })
+        .collect();
+
+    // Timings
+    let total_time = start_time.elapsed();
+    span.record("total_time", format!("{total_time:?}"));
+
+    // Headers
+    let mut headers = HeaderMap::new();
+    headers.insert("x-compute-type", compute_type.parse().unwrap());
+    headers.insert(
+        "x-compute-time",
+        total_time.as_secs_f64().to_string().parse().unwrap(),
+    );
+    headers.insert(
+        "x-compute-characters",
+        compute_characters.to_string().parse().unwrap(),
+    );
+    headers.insert(
+        "x-total-time",
+        total_time.as_millis().to_string().parse().unwrap(),
     );
+    headers.insert("x-generated-tokens", generated_tokens.into());
 
     // Metrics
     metrics::increment_counter!("tgi_request_success");
-    metrics::histogram!("tgi_request_duration", total_time.as_secs_f64());
-    metrics::histogram!(
-        "tgi_request_validation_duration",
-        validation_time.as_secs_f64()
-    );
-    metrics::histogram!("tgi_request_queue_duration", queue_time.as_secs_f64());
-    metrics::histogram!(
-        "tgi_request_inference_duration",
-        inference_time.as_secs_f64()
-    );
-    metrics::histogram!(
-        "tgi_request_mean_time_per_token_duration",
-        time_per_token.as_secs_f64()
-    );
-    metrics::histogram!(
-        "tgi_request_generated_tokens",
-        response.generated_text.generated_tokens as f64
+    exemplars::histogram(
+        infer.exemplars(),
+        "tgi_request_duration",
+        &[],
+        total_time.as_secs_f64(),
+        &span,
     );
+    metrics::histogram!("tgi_request_samples", n as f64);
 
-    // Send response
-    let mut output_text = response.generated_text.text;
-    if let Some(prompt) = add_prompt {
-        output_text = prompt + &output_text;
-    }
-
-    tracing::debug!("Output: {}", output_text);
     tracing::info!("Success");
 
-    let response = GenerateResponse {
-        generated_text: output_text,
-        details,
-    };
-    Ok((headers, Json(response)))
+    Ok((headers, Json(GenerateSamplesResponse { samples })))
 }
 
-/// Generate a stream of token using Server-Sent Events
+/// Generate a stream of token using Server-Sent Events. The stream ends with a
+/// `: trailer {...}` comment holding the timing headers of `/generate`, e.g. `x-queue-time`
 #[utoipa::path(
 post,
 tag = "Text Generation Inference",
@@ -368,6 +1525,37 @@ async fn generate_stream(
     (headers, sse)
 }
 
+/// Metrics label of grammar constrained requests
+fn grammar_label(parameters: &GenerateParameters) -> &'static str {
+    match parameters.grammar {
+        Some(_) => "constrained",
+        None => "unconstrained",
+    }
+}
+
+/// Request id sent in the `x-request-id` header: the trace id if the request is traced
+fn request_id(span: &tracing::Span) -> String {
+    let trace_id = span.context().span().span_context().trace_id();
+    if trace_id != TraceId::INVALID {
+        trace_id.to_string()
+    } else {
+        format!("{:032x}", thread_rng().gen::<u128>())
+    }
+}
+
+/// Comment ending a stream with its final timings, unknown when the stream headers were sent
+fn stream_trailer(headers: &[(&str, String)]) -> Event {
+    let trailer: serde_json::Map<String, serde_json::Value> = headers
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.clone().into()))
+        .collect();
+    Event::default().comment(format!(
+        "{}{}",
+        ndjson::TRAILER_COMMENT,
+        serde_json::Value::Object(trailer)
+    ))
+}
+
 async fn generate_stream_internal(
     infer: Infer,
     ComputeType(compute_type): ComputeType,
@@ -375,12 +1563,17 @@ async fn generate_stream_internal(
     on_message_callback: impl Fn(StreamResponse) -> Event,
 ) -> (HeaderMap, impl Stream<Item = Result<Event, Infallible>>) {
     let span = tracing::Span::current();
+    // The stream is polled outside of the handler: keep the baggage of the request, and its id
+    // to abort it
+    let request_id = request_id(&span);
+    let context = abort::with_request_id(request_id.clone());
     let start_time = Instant::now();
     metrics::increment_counter!("tgi_request_count");
 
     tracing::debug!("Input: {}", req.inputs);
 
     let compute_characters = req.inputs.chars().count();
+    let grammar = grammar_label(&req.parameters);
 
     let mut headers = HeaderMap::new();
     headers.insert("x-compute-type", compute_type.parse().unwrap());
@@ -389,17 +1582,33 @@ async fn generate_stream_internal(
         compute_characters.to_string().parse().unwrap(),
     );
     headers.insert("X-Accel-Buffering", "no".parse().unwrap());
+    headers.insert("x-request-id", request_id.parse().unwrap());
+    if let Some(estimated_completion_time) = req
+        .parameters
+        .max_new_tokens
+        .and_then(|max_new_tokens| infer.estimated_completion_time(max_new_tokens))
+    {
+        headers.insert(
+            "x-estimated-completion-time",
+            estimated_completion_time
+                .as_millis()
+                .to_string()
+                .parse()
+                .unwrap(),
+        );
+    }
 
     let stream = async_stream::stream! {
-        // Inference
-        let mut end_reached = false;
-        let mut error = false;
+        // Send an empty comment so the response headers are flushed before the prefill
+        yield Ok(Event::default().comment(""));
 
         let mut add_prompt = None;
         if req.parameters.return_full_text.unwrap_or(false) {
             add_prompt = Some(req.inputs.clone());
         }
         let details = req.parameters.details;
+        let return_statistics = req.parameters.return_statistics;
+        let raw_tokens = req.parameters.raw_tokens;
 
         let best_of = req.parameters.best_of.unwrap_or(1);
         if best_of != 1 {
@@ -412,132 +1621,263 @@ async fn generate_stream_internal(
             metrics::increment_counter!("tgi_request_failure", "err" => "validation");
             tracing::error!("{err}");
             yield Ok(Event::from(err));
+        } else if req.parameters.max_new_tokens == Some(0) {
+            let err = InferError::from(ValidationError::PrefillOnlyStream);
+            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            tracing::error!("{err}");
+            yield Ok(Event::from(err));
+        } else if req.parameters.output_destination.is_some() {
+            let err = InferError::from(ValidationError::OutputDestinationUnsupported);
+            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            tracing::error!("{err}");
+            yield Ok(Event::from(err));
+        } else if req.parameters.return_parsed {
+            let err = InferError::from(ValidationError::ReturnParsedStream);
+            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            tracing::error!("{err}");
+            yield Ok(Event::from(err));
         } else {
-            match infer.generate_stream(req).instrument(info_span!(parent: &span, "async_stream")).await {
-                // Keep permit as long as generate_stream lives
-                Ok((_permit, _input_length, mut response_stream)) => {
-                    let mut index = 0;
+            // Inference
+            match infer.generate_stream(req).instrument(info_span!(parent: &span, "async_stream")).with_context(context).await {
+                // The generation holds the permit until it is finished or failed
+                Ok((permit, input_length, response_stream)) => {
+                    let mut generation = GenerationStream::new(permit, response_stream, infer.chaos());
+                    // Buffer the tokens holding partial UTF-8 sequences
+                    let mut detokenizer = match raw_tokens {
+                        true => None,
+                        false => infer.detokenizer(),
+                    };
+                    // Hold back the text of the pending matches of the stream transforms
+                    let mut transformer = match raw_tokens {
+                        true => None,
+                        false => infer.stream_transformer(),
+                    };
+                    // Hold back the tokens that may be part of personally identifiable information
+                    let mut masker = infer.pii_masker();
+                    // Ids of the generated tokens, for the re-tokenization check
+                    let mut generated_ids = Vec::new();
+                    let mut statistics = return_statistics.then(TokenStatistics::default);
+                    let mut first_token = true;
                     // Server-Sent Event stream
-                    while let Some(response) = response_stream.next().await {
-                        index += 1;
-                        match response {
-                            Ok(response) => {
-                                match response {
-                                    // Prefill is ignored
-                                    InferStreamResponse::Prefill(_) => {}
-                                    // Yield event for every new token
-                                    InferStreamResponse::Intermediate{
+                    while let Some(event) = generation.next().await {
+                        // Time to first token
+                        if first_token && !matches!(event, StreamEvent::Error(_)) {
+                            first_token = false;
+                            exemplars::histogram(infer.exemplars(), "tgi_request_first_token_duration", &[], start_time.elapsed().as_secs_f64(), &span);
+                            if let Some(slo) = infer.slo() {
+                                slo.first_token(start_time.elapsed());
+                            }
+                        }
+                        match event {
+                            // Yield event for every new token
+                            StreamEvent::Token {
+                                index,
+                                mut token,
+                                top_tokens,
+                            } => {
+                                tracing::debug!(parent: &span, "Token: {:?}", token);
+                                if !token.special {
+                                    generated_ids.push(token.id);
+                                }
+                                if let Some(statistics) = statistics.as_mut() {
+                                    statistics.add(&token, &top_tokens);
+                                }
+                                if let Some(detokenizer) = detokenizer.as_mut() {
+                                    token.text = detokenizer.next(token.id, &token.text);
+                                }
+                                if let Some(transformer) = transformer.as_mut() {
+                                    token.text = transformer.next(&token.text);
+                                }
+                                let released = match masker.as_mut() {
+                                    Some(masker) => match masker.next((index, token, top_tokens)) {
+                                        Ok(released) => released,
+                                        Err(pii) => {
+                                            yield Ok(Event::from(infer.pii_blocked(pii)));
+                                            break;
+                                        }
+                                    },
+                                    None => vec![(index, token, top_tokens)],
+                                };
+
+                                // StreamResponse
+                                for (index, token, top_tokens) in released {
+                                    let stream_token = StreamResponse {
+                                        index,
                                         token,
                                         top_tokens,
-                                    } => {
-                                        tracing::debug!(parent: &span, "Token: {:?}", token);
-
-                                        // StreamResponse
-                                        let stream_token = StreamResponse {
-                                            index,
-                                            token,
-                                            top_tokens,
-                                            generated_text: None,
-                                            details: None,
-                                        };
-                                        let event = on_message_callback(stream_token);
-                                        yield Ok(event);
+                                        generated_text: None,
+                                        details: None,
+                                        safety: None,
+                                    };
+                                    let event = on_message_callback(stream_token);
+                                    yield Ok(event);
+                                }
+                            }
+                            // Yield event for last token and compute timings
+                            StreamEvent::End {
+                                index,
+                                mut token,
+                                top_tokens,
+                                mut generated_text,
+                                start,
+                                queued,
+                                generations,
+                                normalization,
+                            } => {
+                                if !token.special {
+                                    generated_ids.push(token.id);
+                                }
+                                // The text of the generations interrupted by the router
+                                if interrupted(&generated_text) {
+                                    generated_text.text = infer.decode(&generated_ids);
+                                }
+                                // Personally identifiable information in the generated text
+                                let (scanned_text, safety) = match infer.scan_output(infer.transform_output(generated_text.text.clone())) {
+                                    Ok(scanned) => scanned,
+                                    Err(err) => {
+                                        yield Ok(Event::from(err));
+                                        break;
                                     }
-                                    // Yield event for last token and compute timings
-                                    InferStreamResponse::End {
-                                        token,
-                                        generated_text,
-                                        start,
-                                        queued,
-                                        top_tokens,
-                                    } => {
-                                        // Token details
-                                        let details = match details {
-                                            true => Some(StreamDetails {
-                                                finish_reason: FinishReason::from(generated_text.finish_reason),
-                                                generated_tokens: generated_text.generated_tokens,
-                                                seed: generated_text.seed,
-                                            }),
-                                            false => None,
-                                        };
-
-                                        // Timings
-                                        let total_time = start_time.elapsed();
-                                        let validation_time = queued - start_time;
-                                        let queue_time = start - queued;
-                                        let inference_time = Instant::now() - start;
-                                        let time_per_token = inference_time / generated_text.generated_tokens;
-
-                                        // Tracing metadata
-                                        span.record("total_time", format!("{total_time:?}"));
-                                        span.record("validation_time", format!("{validation_time:?}"));
-                                        span.record("queue_time", format!("{queue_time:?}"));
-                                        span.record("inference_time", format!("{inference_time:?}"));
-                                        span.record("time_per_token", format!("{time_per_token:?}"));
-                                        span.record("seed", format!("{:?}", generated_text.seed));
-
-                                        // Metrics
-                                        metrics::increment_counter!("tgi_request_success");
-                                        metrics::histogram!("tgi_request_duration", total_time.as_secs_f64());
-                                        metrics::histogram!("tgi_request_validation_duration", validation_time.as_secs_f64());
-                                        metrics::histogram!("tgi_request_queue_duration", queue_time.as_secs_f64());
-                                        metrics::histogram!("tgi_request_inference_duration", inference_time.as_secs_f64());
-                                        metrics::histogram!("tgi_request_mean_time_per_token_duration", time_per_token.as_secs_f64());
-                                        metrics::histogram!("tgi_request_generated_tokens", generated_text.generated_tokens as f64);
-
-                                        // StreamResponse
-                                        end_reached = true;
-
-                                        let mut output_text = generated_text.text;
-                                        if let Some(prompt) = add_prompt {
-                                            output_text = prompt + &output_text;
+                                };
+                                // Token details
+                                let speculation = infer.speculation(generated_text.generated_tokens, generations);
+                                if let Some(statistics) = statistics.as_mut() {
+                                    statistics.add(&token, &top_tokens);
+                                }
+                                let retokenization = infer.retokenization(&generated_text.text, &generated_ids);
+                                let details = match details {
+                                    true => Some(StreamDetails {
+                                        finish_reason: FinishReason::from(generated_text.finish_reason),
+                                        generated_tokens: generated_text.generated_tokens,
+                                        seed: generated_text.seed,
+                                        input_length,
+                                        speculation,
+                                        retokenization,
+                                        normalization,
+                                        statistics: statistics.take().and_then(TokenStatistics::finish),
+                                        tokens_outside_window: infer.tokens_outside_window(input_length, generated_text.generated_tokens),
+                                        estimated_cost: infer.estimate_cost(input_length, generated_text.generated_tokens),
+                                    }),
+                                    false => None,
+                                };
+
+                                // Timings
+                                let total_time = start_time.elapsed();
+                                let validation_time = queued - start_time;
+                                let queue_time = start - queued;
+                                let inference_time = Instant::now() - start;
+                                let time_per_token = inference_time.checked_div(generated_text.generated_tokens).unwrap_or_default();
+
+                                // Tracing metadata
+                                span.record("total_time", format!("{total_time:?}"));
+                                span.record("validation_time", format!("{validation_time:?}"));
+                                span.record("queue_time", format!("{queue_time:?}"));
+                                span.record("inference_time", format!("{inference_time:?}"));
+                                span.record("time_per_token", format!("{time_per_token:?}"));
+                                span.record("seed", format!("{:?}", generated_text.seed));
+
+                                // Metrics
+                                metrics::increment_counter!("tgi_request_success");
+                                exemplars::histogram(infer.exemplars(), "tgi_request_duration", &[], total_time.as_secs_f64(), &span);
+                                metrics::histogram!("tgi_request_validation_duration", validation_time.as_secs_f64());
+                                metrics::histogram!("tgi_request_queue_duration", queue_time.as_secs_f64());
+                                metrics::histogram!("tgi_request_inference_duration", inference_time.as_secs_f64());
+                                metrics::histogram!("tgi_request_mean_time_per_token_duration", time_per_token.as_secs_f64());
+                                metrics::histogram!("tgi_grammar_mean_time_per_token_duration", time_per_token.as_secs_f64(), "grammar" => grammar);
+                                if let Some(slo) = infer.slo().filter(|_| generated_text.generated_tokens > 0) {
+                                    slo.time_per_token(time_per_token);
+                                }
+                                metrics::histogram!("tgi_request_generated_tokens", generated_text.generated_tokens as f64);
+
+                                // Emit the tokens still buffered with the last token
+                                if let Some(detokenizer) = detokenizer.as_mut() {
+                                    token.text = detokenizer.next(token.id, &token.text) + &detokenizer.flush();
+                                }
+                                if let Some(transformer) = transformer.as_mut() {
+                                    token.text = transformer.next(&token.text) + &transformer.flush();
+                                }
+                                let (index, token, top_tokens) = match masker.as_mut() {
+                                    Some(masker) => match masker.finish((index, token, top_tokens)) {
+                                        Ok(mut released) => {
+                                            let last = released.pop().expect("last token");
+                                            for (index, token, top_tokens) in released {
+                                                let stream_token = StreamResponse {
+                                                    index,
+                                                    token,
+                                                    top_tokens,
+                                                    generated_text: None,
+                                                    details: None,
+                                                    safety: None,
+                                                };
+                                                yield Ok(on_message_callback(stream_token));
+                                            }
+                                            last
+                                        }
+                                        Err(pii) => {
+                                            yield Ok(Event::from(infer.pii_blocked(pii)));
+                                            break;
                                         }
+                                    },
+                                    None => (index, token, top_tokens),
+                                };
 
-                                        tracing::debug!(parent: &span, "Output: {}", output_text);
-                                        tracing::info!(parent: &span, "Success");
+                                let mut output_text = scanned_text;
+                                if let Some(prompt) = add_prompt {
+                                    output_text = prompt + &output_text;
+                                }
 
-                                        let stream_token = StreamResponse {
-                                            index,
-                                            token,
-                                            top_tokens,
-                                            generated_text: Some(output_text),
-                                            details
-                                        };
+                                tracing::debug!(parent: &span, "Output: {}", output_text);
+                                tracing::info!(parent: &span, "Success");
 
+                                let stream_token = StreamResponse {
+                                    index,
+                                    token,
+                                    top_tokens,
+                                    generated_text: Some(output_text),
+                                    details,
+                                    safety,
+                                };
+                                let event = on_message_callback(stream_token);
+                                yield Ok(event);
 
-                                        let event = on_message_callback(stream_token);
-                                        yield Ok(event);
-                                        break;
-                                    }
+                                // The timing headers of the non-streaming responses
+                                let mut trailer = vec![
+                                    ("x-compute-time", total_time.as_secs_f64().to_string()),
+                                    ("x-total-time", total_time.as_millis().to_string()),
+                                    ("x-validation-time", validation_time.as_millis().to_string()),
+                                    ("x-queue-time", queue_time.as_millis().to_string()),
+                                    ("x-inference-time", inference_time.as_millis().to_string()),
+                                    ("x-time-per-token", time_per_token.as_millis().to_string()),
+                                    ("x-prompt-tokens", input_length.to_string()),
+                                    ("x-generated-tokens", generated_text.generated_tokens.to_string()),
+                                ];
+                                if let Some(cost) = infer.estimate_cost(input_length, generated_text.generated_tokens) {
+                                    trailer.push(("x-estimated-cost", format!("{cost:.6}")));
+                                }
+                                let warnings = warnings::current();
+                                if !warnings.is_empty() {
+                                    trailer.push((warnings::WARNINGS_HEADER, warnings.join("; ")));
                                 }
+                                yield Ok(stream_trailer(&trailer));
+                                break;
                             }
                             // yield error
-                            Err(err) => {
-                                error = true;
+                            StreamEvent::Error(err) => {
                                 yield Ok(Event::from(err));
-                                break;
                             }
                         }
                     }
                 },
                 // yield error
                 Err(err) => {
-                    error = true;
                     yield Ok(Event::from(err));
                 }
             }
-            // Check if generation reached the end
-            // Skip if we already sent an error
-            if !end_reached && !error {
-                let err = InferError::IncompleteGeneration;
-                metrics::increment_counter!("tgi_request_failure", "err" => "incomplete");
-                tracing::error!("{err}");
-                yield Ok(Event::from(err));
-            }
         }
     };
 
-    (headers, stream)
+    // Poll the stream in the context of the request, e.g. to translate its errors
+    (headers, stream.with_context(Context::current()))
 }
 
 /// Generate tokens
@@ -574,13 +1914,31 @@ async fn completions(
     Extension(infer): Extension<Infer>,
     Extension(compute_type): Extension<ComputeType>,
     Extension(info): Extension<Info>,
+    Extension(experiments): Extension<Experiments>,
+    Extension(served_model): Extension<ServedModel>,
     Json(req): Json<CompletionRequest>,
 ) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
     metrics::increment_counter!("tgi_request_count");
 
+    let model_adapter = served_model.check(&req.model, &infer.adapters())?;
+
     let stream = req.stream;
     let max_new_tokens = req.max_tokens.or(Some(100));
     let seed = req.seed;
+    let echo = req.echo;
+    let logprobs = req.logprobs;
+
+    // The prompt tokens are only returned in the final response
+    if echo && stream {
+        metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ErrorResponse {
+                error: "`echo` is not supported when streaming tokens".to_string(),
+                error_type: "validation".to_string(),
+            }),
+        ));
+    }
 
     // if suffix is present throw an error
     if req.suffix.is_some() {
@@ -596,30 +1954,61 @@ async fn completions(
     }
 
     // build the request passing some parameters
-    let generate_request = GenerateRequest {
+    let mut generate_request = GenerateRequest {
         inputs: req.prompt.to_string(),
         parameters: GenerateParameters {
             best_of: None,
             temperature: req.temperature,
             repetition_penalty: req.repetition_penalty,
             frequency_penalty: req.frequency_penalty,
+            logit_bias: req.logit_bias,
             top_k: None,
             top_p: req.top_p,
             typical_p: None,
             do_sample: true,
             max_new_tokens,
-            return_full_text: None,
+            return_full_text: Some(echo),
             stop: Vec::new(),
             truncate: None,
             watermark: false,
             details: true,
             decoder_input_details: !stream,
             seed,
-            top_n_tokens: None,
+            top_n_tokens: logprobs.filter(|top_n_tokens| *top_n_tokens > 0),
             grammar: None,
+            skip_special_tokens: true,
+            raw_tokens: false,
+            stop_after_tool_call: false,
+            eos_probability_threshold: None,
+            eos_probability_window: None,
+            scheduling: false,
+            output_destination: None,
+            quality_of_service: None,
+            normalize_inputs: None,
+            return_statistics: false,
+            return_parsed: false,
+            preset: req.preset,
+            adapter_id: req.adapter_id.or(model_adapter),
+            adapter_parameters: req.adapter_parameters,
+            extensions: HashMap::new(),
+            temperature_schedule: None,
         },
     };
 
+    // Expand the preset before the canary overrides, the parameters set by the request taking
+    // precedence
+    infer
+        .expand_preset(&mut generate_request.parameters)
+        .map_err(InferError::from)?;
+
+    // Canary parameter overrides
+    let experiment = experiments.assign(
+        ExperimentRoute::Completions,
+        &mut generate_request.parameters,
+    );
+    let system_fingerprint = infer.system_fingerprint(&info);
+    let pricing = infer.pricing();
+
     if stream {
         let on_message_callback = move |stream_token: StreamResponse| {
             let event = Event::default();
@@ -629,6 +2018,10 @@ async fn completions(
                 .unwrap_or_else(|_| std::time::Duration::from_secs(0))
                 .as_secs();
 
+            // Usage is computed from the validated input length: prefill details are not
+            // returned when streaming
+            let usage = stream_token.details.as_ref().map(Usage::from);
+
             event
                 .json_data(CompletionCompleteChunk {
                     id: "".to_string(),
@@ -643,11 +2036,8 @@ async fn completions(
                     }],
 
                     model: info.model_id.clone(),
-                    system_fingerprint: format!(
-                        "{}-{}",
-                        info.version,
-                        info.docker_label.unwrap_or("native")
-                    ),
+                    system_fingerprint: system_fingerprint.clone(),
+                    usage,
                 })
                 .map_or_else(
                     |e| {
@@ -658,21 +2048,36 @@ async fn completions(
                 )
         };
 
-        let (headers, response_stream) = generate_stream_internal(
-            infer,
-            compute_type,
-            Json(generate_request),
-            on_message_callback,
+        let (mut headers, response_stream) = on_endpoint(
+            top_n_tokens::Endpoint::Completions,
+            generate_stream_internal(
+                infer,
+                compute_type,
+                Json(generate_request),
+                on_message_callback,
+            ),
         )
         .await;
 
+        if let Some(experiment) = experiment {
+            headers.insert("x-experiment", experiment.parse().unwrap());
+        }
+
         let sse = Sse::new(response_stream).keep_alive(KeepAlive::default());
         Ok((headers, sse).into_response())
     } else {
-        let (headers, Json(generation)) = generate(
-            Extension(infer),
-            Extension(compute_type),
-            Json(generate_request),
+        let (mut headers, Json(generation)) = on_endpoint(
+            top_n_tokens::Endpoint::Completions,
+            generate(
+                Extension(infer),
+                Extension(compute_type),
+                None,
+                None,
+                None,
+                None,
+                Query(DetailsPagination::default()),
+                Json(generate_request),
+            ),
         )
         .await?;
 
@@ -695,28 +2100,101 @@ async fn completions(
             object: "text_completion".to_string(),
             created: current_time,
             model: info.model_id.clone(),
-            system_fingerprint: format!(
-                "{}-{}",
-                info.version,
-                info.docker_label.unwrap_or("native")
-            ),
+            system_fingerprint,
             choices: vec![CompletionComplete {
                 finish_reason: details.finish_reason.to_string(),
                 index: 0,
-                logprobs: None,
+                logprobs: logprobs.map(|_| CompletionLogprobs::new(&details, echo)),
                 text: generation.generated_text,
             }],
-            usage: Usage {
-                prompt_tokens: details.prefill.len() as u32,
-                completion_tokens: details.generated_tokens,
-                total_tokens: details.prefill.len() as u32 + details.generated_tokens,
-            },
+            usage: Usage::new(
+                details.prefill.len() as u32,
+                details.generated_tokens,
+                pricing,
+            ),
+            safety: generation.safety,
+            warnings: warnings::current(),
         };
 
+        if let Some(experiment) = experiment {
+            headers.insert("x-experiment", experiment.parse().unwrap());
+        }
+
         Ok((headers, Json(response)).into_response())
     }
 }
 
+/// Grammar of the tools of a chat request, the prompt appended to the rendered chat template and
+/// whether the model may answer with text instead (`auto` tool choice)
+fn chat_tools(
+    declared_tools: &DeclaredTools,
+    req_tools: Vec<Tool>,
+    tool_choice: ToolType,
+    tool_prompt: Option<String>,
+) -> Result<(GrammarType, String, bool), (StatusCode, Json<ErrorResponse>)> {
+    let start_time = Instant::now();
+    let tool_prompt = tool_prompt.unwrap_or_default();
+    // Tools declared by the server can be given by name only
+    let req_tools = declared_tools.resolve(req_tools).map_err(|err| {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ErrorResponse {
+                error: err,
+                error_type: "validation".to_string(),
+            }),
+        )
+    })?;
+    let mut auto_tool = false;
+    let tools_to_use = match tool_choice {
+        ToolType::FunctionName(name) => {
+            vec![req_tools
+                .iter()
+                .find(|tool| tool.function.name == *name)
+                .ok_or_else(|| {
+                    (
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        Json(ErrorResponse {
+                            error: "Tool choice not found in tool names".to_string(),
+                            error_type: "Tool not found".to_string(),
+                        }),
+                    )
+                })?
+                .clone()]
+        }
+        ToolType::OneOf => req_tools.to_owned(),
+        ToolType::Auto => {
+            auto_tool = true;
+            let mut tools = req_tools.to_owned();
+            tools.push(tool_choice::no_tool());
+            tools
+        }
+    };
+
+    let tools = Tools::new(&tools_to_use);
+
+    let tools_str = serde_json::to_string(&tools).map_err(|e| {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ErrorResponse {
+                error: e.to_string(),
+                error_type: "Input validation error".to_string(),
+            }),
+        )
+    })?;
+    let tools_prompt = format!("{tool_prompt}{tools_str}");
+    let grammar = GrammarType::Json(serde_json::json!(tools));
+
+    let duration = start_time.elapsed();
+    metrics::histogram!("tgi_chat_tool_grammar_duration", duration.as_secs_f64());
+    tracing::debug!(
+        ?duration,
+        num_tools = tools_to_use.len(),
+        schema_size = tools_str.len(),
+        "Tool grammar built"
+    );
+    Ok((grammar, tools_prompt, auto_tool))
+}
+
 /// Generate tokens
 #[utoipa::path(
     post,
@@ -733,6 +2211,8 @@ async fn completions(
     example = json ! ({"error": "Input validation error"})),
     (status = 500, description = "Incomplete generation", body = ErrorResponse,
     example = json ! ({"error": "Incomplete generation"})),
+    (status = 501, description = "The model does not support chat", body = ErrorResponse,
+    example = json ! ({"error": "Chat is not supported: the tokenizer config of the model has no chat template", "error_type": "chat_template_not_found"})),
     )
     )]
 #[instrument(
@@ -751,10 +2231,21 @@ async fn chat_completions(
     Extension(infer): Extension<Infer>,
     Extension(compute_type): Extension<ComputeType>,
     Extension(info): Extension<Info>,
+    Extension(experiments): Extension<Experiments>,
+    Extension(served_model): Extension<ServedModel>,
+    Extension(declared_tools): Extension<DeclaredTools>,
     Json(req): Json<ChatRequest>,
 ) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
     metrics::increment_counter!("tgi_request_count");
 
+    let model_adapter = served_model.check(&req.model, &infer.adapters())?;
+
+    if let Some(n) = req.n.filter(|n| *n > 1) {
+        warnings::warn(format!(
+            "`n` = {n} is not supported, a single choice was generated"
+        ));
+    }
+
     let stream = req.stream;
     let max_new_tokens = req.max_tokens.or(Some(100));
     let repetition_penalty = req
@@ -765,109 +2256,181 @@ async fn chat_completions(
     let seed = req.seed;
     let stop = req.stop.unwrap_or_default();
 
+    // The parsed output is only returned in the final response
+    if req.return_parsed && stream {
+        metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+        return Err(InferError::from(ValidationError::ReturnParsedStream).into());
+    }
+
+    // The tools have their own grammar
+    let response_format = req.response_format;
+    if response_format.is_some() && req.tools.is_some() {
+        metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ErrorResponse {
+                error: "`response_format` can not be combined with `tools`".to_string(),
+                error_type: "validation".to_string(),
+            }),
+        ));
+    }
+
+    let (tool_grammar, mut tools_prompt, auto_tool) = match req.tools.zip(req.tool_choice) {
+        Some((req_tools, tool_choice)) => {
+            match chat_tools(&declared_tools, req_tools, tool_choice, req.tool_prompt) {
+                Ok((grammar, tools_prompt, auto_tool)) => (Some(grammar), tools_prompt, auto_tool),
+                Err(err) => {
+                    metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+                    return Err(err);
+                }
+            }
+        }
+        None => (None, String::new(), false),
+    };
+
+    // the client rendered the template and the tools itself
+    if req.prompt_already_templated {
+        tools_prompt.clear();
+    }
+
+    // build the parameters before the prompt: the conversation is truncated to leave room for
+    // the `max_new_tokens` resolved by the preset and the experiments
+    let mut parameters = GenerateParameters {
+        best_of: None,
+        temperature: req.temperature,
+        repetition_penalty,
+        frequency_penalty: req.frequency_penalty,
+        logit_bias: req.logit_bias,
+        top_k: None,
+        top_p: req.top_p,
+        typical_p: None,
+        do_sample: true,
+        max_new_tokens,
+        return_full_text: None,
+        stop,
+        truncate: None,
+        watermark: false,
+        details: true,
+        decoder_input_details: !stream,
+        seed,
+        top_n_tokens: req.top_logprobs.filter(|_| logprobs),
+        grammar: tool_grammar.clone().or(response_format),
+        skip_special_tokens: true,
+        raw_tokens: false,
+        stop_after_tool_call: req.stop_after_tool_call && tool_grammar.is_some(),
+        eos_probability_threshold: None,
+        eos_probability_window: None,
+        scheduling: false,
+        output_destination: None,
+        quality_of_service: None,
+        normalize_inputs: None,
+        return_statistics: false,
+        return_parsed: req.return_parsed && tool_grammar.is_none(),
+        preset: req.preset,
+        adapter_id: req.adapter_id.or(model_adapter),
+        adapter_parameters: req.adapter_parameters,
+        extensions: HashMap::new(),
+        temperature_schedule: None,
+    };
+
+    // Expand the preset before the canary overrides, the parameters set by the request taking
+    // precedence
+    infer
+        .expand_preset(&mut parameters)
+        .map_err(InferError::from)?;
+
+    // Canary parameter overrides
+    let experiment = experiments.assign(ExperimentRoute::Chat, &mut parameters);
+
     // apply chat template to flatten the request into a single input
-    let mut inputs = match infer.apply_chat_template(req.messages) {
-        Ok(inputs) => inputs,
+    let raw = req.raw || req.prompt_already_templated;
+    let render = |messages: Vec<Message>| -> Result<String, InferError> {
+        let inputs: String = match raw {
+            // raw prompt: the messages are used as is
+            true => messages
+                .into_iter()
+                .filter_map(|message| message.content)
+                .collect(),
+            false => infer.apply_chat_template(messages)?,
+        };
+        Ok(format!("{inputs}{tools_prompt}"))
+    };
+    // the next turns of the conversation only tokenize their new messages
+    let conversation = Conversation::new(&req.messages);
+    // the system prompt is checked against the attention window of the model
+    let system_prompt = req
+        .messages
+        .first()
+        .is_some_and(|message| message.role == "system");
+    // drop the low priority messages if the conversation does not fit in the context
+    let chat_template = match req
+        .messages
+        .iter()
+        .any(|message| message.priority.is_some())
+    {
+        true => {
+            let budget = infer.input_budget(parameters.max_new_tokens.unwrap_or_default());
+            chat_truncation::truncate_messages(&infer, req.messages, budget, render).await
+        }
+        false => render(req.messages).map(|inputs| (inputs, Vec::new())),
+    };
+    let (inputs, dropped_messages) = match chat_template {
+        Ok(chat_template) => chat_template,
+        // Not a client error: the model does not support chat
+        Err(err @ (InferError::TokenizerNotFound | InferError::ChatTemplateNotFound)) => {
+            metrics::increment_counter!("tgi_request_failure", "err" => "chat_unsupported");
+            return Err(err.into());
+        }
         Err(err) => {
             metrics::increment_counter!("tgi_request_failure", "err" => "validation");
             tracing::error!("{err}");
             return Err((
                 StatusCode::UNPROCESSABLE_ENTITY,
                 Json(ErrorResponse {
-                    error: err.to_string(),
+                    error: error_catalog::message(&err),
                     error_type: err.error_type().to_string(),
                 }),
             ));
         }
     };
 
-    let tool_grammar = if let Some((req_tools, tool_choice)) = req.tools.zip(req.tool_choice) {
-        let tool_prompt = req.tool_prompt.unwrap_or_default();
-        let tools_to_use = match tool_choice {
-            ToolType::FunctionName(name) => {
-                vec![req_tools
-                    .iter()
-                    .find(|tool| tool.function.name == *name)
-                    .ok_or_else(|| {
-                        (
-                            StatusCode::UNPROCESSABLE_ENTITY,
-                            Json(ErrorResponse {
-                                error: "Tool choice not found in tool names".to_string(),
-                                error_type: "Tool not found".to_string(),
-                            }),
-                        )
-                    })?
-                    .clone()]
-            }
-            ToolType::OneOf => req_tools.to_owned(),
-        };
-
-        let functions: HashMap<String, Value> = tools_to_use
-            .iter()
-            .map(|tool| {
-                let func = tool.function.clone();
-                (func.name, func.parameters)
-            })
-            .collect();
-
-        let tools = Tools {
-            functions_map: FunctionsMap { functions },
-            properties: Properties {
-                function: tools_to_use
-                    .iter()
-                    .map(|tool| FunctionRef {
-                        ref_path: format!("#/$functions/{}", tool.function.name.clone()),
-                    })
-                    .collect(),
-            },
-        };
+    // the truncated conversations are not continued by their next turns
+    let conversation = dropped_messages.is_empty().then_some(conversation);
 
-        let tools_str = serde_json::to_string(&tools).map_err(|e| {
-            (
-                StatusCode::UNPROCESSABLE_ENTITY,
-                Json(ErrorResponse {
-                    error: e.to_string(),
-                    error_type: "Input validation error".to_string(),
-                }),
-            )
-        })?;
-        inputs = format!("{inputs}{tool_prompt}{tools_str}");
-        Some(GrammarType::Json(serde_json::json!(tools)))
-    } else {
-        None
-    };
+    // the tokens of the rendered prompt are captured from its encoding by the fast tokenizer
+    if req.return_input_tokens && !infer.fast_tokenizer() {
+        metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: "No fast tokenizer or tokenizer.json for this model".to_string(),
+                error_type: "no fast tokenizer".to_string(),
+            }),
+        ));
+    }
+    // capture the tokens of the rendered prompt when validated, if the client asked for them
+    let input_tokens = req
+        .return_input_tokens
+        .then(|| InputTokensCapture::new(req.return_input_token_ids));
 
-    // build the request passing some parameters
-    let generate_request = GenerateRequest {
+    let mut generate_request = GenerateRequest {
         inputs: inputs.to_string(),
-        parameters: GenerateParameters {
-            best_of: None,
-            temperature: req.temperature,
-            repetition_penalty,
-            frequency_penalty: req.frequency_penalty,
-            top_k: None,
-            top_p: req.top_p,
-            typical_p: None,
-            do_sample: true,
-            max_new_tokens,
-            return_full_text: None,
-            stop,
-            truncate: None,
-            watermark: false,
-            details: true,
-            decoder_input_details: !stream,
-            seed,
-            top_n_tokens: None,
-            grammar: tool_grammar.clone(),
-        },
+        parameters,
     };
 
     // static values that will be returned in all cases
     let model_id = info.model_id.clone();
-    let system_fingerprint = format!("{}-{}", info.version, info.docker_label.unwrap_or("native"));
+    let system_fingerprint = infer.system_fingerprint(&info);
+    let pricing = infer.pricing();
 
     // switch on stream
     if stream {
+        // Tells the text answers from the tool calls of the `auto` tool choice
+        let auto_tool_stream = auto_tool.then(|| std::sync::Mutex::new(AutoToolStream::default()));
+        // Parses the streamed tool calls to emit their completed arguments
+        let tool_arguments_stream = (tool_grammar.is_some() && req.stream_tool_arguments)
+            .then(|| std::sync::Mutex::new(ToolArgumentsStream::default()));
+        let capture = input_tokens.clone();
         // pass this callback to the stream generation and build the required event structure
         let on_message_callback = move |stream_token: StreamResponse| {
             let event = Event::default();
@@ -882,47 +2445,109 @@ async fn chat_completions(
             });
 
             // replace the content with the tool calls if grammar is present
-            let (content, tool_calls) = if tool_grammar.is_some() {
-                (None, Some(vec![stream_token.token.text]))
-            } else {
-                (Some(stream_token.token.text), None)
+            let (content, tool_calls) = match &auto_tool_stream {
+                // The text answers of the `auto` tool choice are streamed as content
+                Some(auto_tool_stream) => {
+                    let mut auto_tool_stream = auto_tool_stream.lock().unwrap();
+                    let mut delta = auto_tool_stream.push(&stream_token.token.text);
+                    if stream_token.generated_text.is_some() {
+                        delta.tool_call = delta
+                            .tool_call
+                            .into_iter()
+                            .chain(auto_tool_stream.finish())
+                            .reduce(|tool_call, rest| tool_call + &rest);
+                    }
+                    (
+                        delta.content,
+                        delta.tool_call.map(|tool_call| vec![tool_call]),
+                    )
+                }
+                None if tool_grammar.is_some() => (None, Some(vec![stream_token.token.text])),
+                None => (Some(stream_token.token.text), None),
             };
 
-            event
-                .json_data(ChatCompletionChunk::new(
-                    model_id.clone(),
-                    system_fingerprint.clone(),
-                    content,
-                    tool_calls,
-                    current_time,
-                    stream_token.index,
-                    logprobs,
-                    stream_token.details.map(|d| d.finish_reason.to_string()),
-                ))
-                .map_or_else(
-                    |e| {
-                        println!("Failed to serialize ChatCompletionChunk: {:?}", e);
-                        Event::default()
-                    },
-                    |data| data,
-                )
+            // Usage is computed from the validated input length: prefill details are not
+            // returned when streaming
+            let usage = stream_token.details.as_ref().map(Usage::from);
+
+            let mut chunk = ChatCompletionChunk::new(
+                model_id.clone(),
+                system_fingerprint.clone(),
+                content,
+                tool_calls,
+                current_time,
+                stream_token.index,
+                logprobs,
+                stream_token.details.map(|d| d.finish_reason.to_string()),
+            );
+            chunk.usage = usage;
+            if let Some(tool_arguments_stream) = &tool_arguments_stream {
+                if let Some(tool_call) = chunk.choices[0].delta.tool_calls.as_mut() {
+                    let arguments = tool_arguments_stream
+                        .lock()
+                        .unwrap()
+                        .push(&tool_call.function.arguments);
+                    tool_call.function.parsed_arguments =
+                        (!arguments.is_empty()).then_some(arguments);
+                }
+            }
+            // the prompt tokens are only sent with the first chunk
+            if stream_token.index == 1 {
+                chunk.input_tokens = input_tokens.as_ref().and_then(InputTokensCapture::get);
+                chunk.dropped_messages = dropped_messages.clone();
+            }
+
+            event.json_data(chunk).map_or_else(
+                |e| {
+                    println!("Failed to serialize ChatCompletionChunk: {:?}", e);
+                    Event::default()
+                },
+                |data| data,
+            )
         };
 
-        let (headers, response_stream) = generate_stream_internal(
-            infer,
-            compute_type,
-            Json(generate_request),
-            on_message_callback,
-        )
+        let (mut headers, response_stream) = capturing_input_tokens(capture, || {
+            template_cache::in_conversation(conversation, || {
+                attention_window::with_system_prompt(system_prompt, || {
+                    on_endpoint(
+                        top_n_tokens::Endpoint::ChatCompletions,
+                        generate_stream_internal(
+                            infer,
+                            compute_type,
+                            Json(generate_request),
+                            on_message_callback,
+                        ),
+                    )
+                })
+            })
+        })
         .await;
+        if let Some(experiment) = experiment {
+            headers.insert("x-experiment", experiment.parse().unwrap());
+        }
+
         let sse = Sse::new(response_stream).keep_alive(KeepAlive::default());
         Ok((headers, sse).into_response())
     } else {
-        let (headers, Json(generation)) = generate(
-            Extension(infer),
-            Extension(compute_type),
-            Json(generate_request),
-        )
+        let (mut headers, Json(generation)) = capturing_input_tokens(input_tokens.clone(), || {
+            template_cache::in_conversation(conversation, || {
+                attention_window::with_system_prompt(system_prompt, || {
+                    on_endpoint(
+                        top_n_tokens::Endpoint::ChatCompletions,
+                        generate(
+                            Extension(infer),
+                            Extension(compute_type),
+                            None,
+                            None,
+                            None,
+                            None,
+                            Query(DetailsPagination::default()),
+                            Json(generate_request),
+                        ),
+                    )
+                })
+            })
+        })
         .await?;
 
         let current_time = std::time::SystemTime::now()
@@ -943,34 +2568,43 @@ async fn chat_completions(
                     )
                 })?;
 
-            let tool_call = Some(ToolCall {
-                id: 0,
-                r#type: "function".to_string(),
-                function: FunctionDefinition {
-                    description: None,
-                    name: "tools".to_string(),
-                    parameters: gen_text_value.get("function").map_or_else(
-                        || {
-                            serde_json::from_str(&generation.generated_text).map_err(|e| {
-                                (
-                                    StatusCode::UNPROCESSABLE_ENTITY,
-                                    Json(ErrorResponse {
-                                        error: e.to_string(),
-                                        error_type: "Input validation error".to_string(),
-                                    }),
-                                )
-                            })
+            match auto_tool
+                .then(|| tool_choice::text_answer(&gen_text_value))
+                .flatten()
+            {
+                // The model answered in text through the `no_tool` pseudo tool
+                Some(text) => (None, Some(text)),
+                None => {
+                    let tool_call = Some(ToolCall {
+                        id: 0,
+                        r#type: "function".to_string(),
+                        function: FunctionDefinition {
+                            description: None,
+                            name: "tools".to_string(),
+                            parameters: gen_text_value.get("function").map_or_else(
+                                || {
+                                    serde_json::from_str(&generation.generated_text).map_err(|e| {
+                                        (
+                                            StatusCode::UNPROCESSABLE_ENTITY,
+                                            Json(ErrorResponse {
+                                                error: e.to_string(),
+                                                error_type: "Input validation error".to_string(),
+                                            }),
+                                        )
+                                    })
+                                },
+                                |f| Ok(f.clone()),
+                            )?,
                         },
-                        |f| Ok(f.clone()),
-                    )?,
-                },
-            });
-            (tool_call, None)
+                    });
+                    (tool_call, None)
+                }
+            }
         } else {
             (None, Some(generation.generated_text))
         };
         // build the complete response object with the full text
-        let response = ChatCompletion::new(
+        let mut response = ChatCompletion::new(
             model_id,
             system_fingerprint,
             output,
@@ -978,9 +2612,19 @@ async fn chat_completions(
             generation.details.unwrap(),
             logprobs,
             tool_calls,
+            pricing,
         );
+        response.input_tokens = input_tokens.as_ref().and_then(InputTokensCapture::get);
+        response.dropped_messages = dropped_messages;
+        response.safety = generation.safety;
+        response.choices[0].message.parsed = generation.parsed;
+        response.warnings = warnings::current();
 
         // wrap generation inside a Vec to match api-inference
+        if let Some(experiment) = experiment {
+            headers.insert("x-experiment", experiment.parse().unwrap());
+        }
+
         Ok((headers, Json(response)).into_response())
     }
 }
@@ -992,7 +2636,11 @@ async fn chat_completions(
     path = "/vertex",
     request_body = VertexRequest,
     responses(
-    (status = 200, description = "Generated Text", body = VertexResponse),
+    (status = 200, description = "Generated Text, or the predictions as they complete",
+    content(
+    ("application/json" = VertexResponse),
+    ("application/x-ndjson" = VertexPrediction),
+    )),
     (status = 424, description = "Generation Error", body = ErrorResponse,
     example = json ! ({"error": "Request failed during generation"})),
     (status = 429, description = "Model is overloaded", body = ErrorResponse,
@@ -1017,10 +2665,21 @@ async fn chat_completions(
 async fn vertex_compatibility(
     Extension(infer): Extension<Infer>,
     Extension(compute_type): Extension<ComputeType>,
+    Extension(limits): Extension<RouteLimits>,
+    headers: HeaderMap,
     Json(req): Json<VertexRequest>,
 ) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
     metrics::increment_counter!("tgi_request_count");
 
+    if let Some(max_instances) = limits
+        .max_instances
+        .filter(|&max_instances| req.instances.len() > max_instances)
+    {
+        metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+        let err = ValidationError::Instances(max_instances, req.instances.len());
+        return Err(InferError::from(err).into());
+    }
+
     // check that theres at least one instance
     if req.instances.is_empty() {
         return Err((
@@ -1035,8 +2694,9 @@ async fn vertex_compatibility(
     // Process all instances
     let predictions = req
         .instances
-        .iter()
-        .map(|instance| {
+        .into_iter()
+        .enumerate()
+        .map(|(index, instance)| {
             let generate_request = GenerateRequest {
                 inputs: instance.inputs.clone(),
                 parameters: GenerateParameters {
@@ -1045,18 +2705,64 @@ async fn vertex_compatibility(
                     seed: instance.parameters.as_ref().and_then(|p| p.seed),
                     details: true,
                     decoder_input_details: true,
+                    skip_special_tokens: true,
+                    raw_tokens: false,
+                    stop_after_tool_call: false,
                     ..Default::default()
                 },
             };
 
-            async {
-                generate(
-                    Extension(infer.clone()),
-                    Extension(compute_type.clone()),
+            let infer = infer.clone();
+            let compute_type = compute_type.clone();
+            async move {
+                let prediction = generate(
+                    Extension(infer),
+                    Extension(compute_type),
+                    None,
+                    None,
+                    None,
+                    None,
+                    Query(DetailsPagination::default()),
                     Json(generate_request),
                 )
                 .await
-                .map(|(_, Json(generation))| generation.generated_text)
+                .map(|(_, Json(generation))| generation.generated_text);
+                (index, prediction)
+            }
+        })
+        .collect::<FuturesUnordered<_>>();
+
+    // Stream each prediction as it completes, without waiting for the slowest instance
+    let streams_ndjson = headers
+        .get(http::header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains(ndjson::NDJSON_CONTENT_TYPE));
+    if streams_ndjson {
+        let lines = predictions.map(|(index, prediction)| {
+            let (prediction, error) = match prediction {
+                Ok(prediction) => (Some(prediction), None),
+                Err((_, Json(error))) => (None, Some(error)),
+            };
+            let mut line = serde_json::to_vec(&VertexPrediction {
+                index,
+                prediction,
+                error,
+            })
+            .unwrap_or_default();
+            line.push(b'\n');
+            Ok::<_, Infallible>(Bytes::from(line))
+        });
+        return Ok((
+            [(http::header::CONTENT_TYPE, ndjson::NDJSON_CONTENT_TYPE)],
+            StreamBody::new(lines),
+        )
+            .into_response());
+    }
+
+    let mut predictions = predictions
+        .map(|(index, prediction)| {
+            prediction
+                .map(|prediction| (index, prediction))
                 .map_err(|_| {
                     (
                         StatusCode::INTERNAL_SERVER_ERROR,
@@ -1066,13 +2772,18 @@ async fn vertex_compatibility(
                         }),
                     )
                 })
-            }
         })
-        .collect::<FuturesUnordered<_>>()
         .try_collect::<Vec<_>>()
         .await?;
+    // In the order of the instances
+    predictions.sort_by_key(|(index, _)| *index);
 
-    let response = VertexResponse { predictions };
+    let response = VertexResponse {
+        predictions: predictions
+            .into_iter()
+            .map(|(_, prediction)| prediction)
+            .collect(),
+    };
     Ok((HeaderMap::new(), Json(response)).into_response())
 }
 
@@ -1081,9 +2792,9 @@ async fn vertex_compatibility(
     post,
     tag = "Text Generation Inference",
     path = "/tokenize",
-    request_body = GenerateRequest,
+    request_body = TokenizeRequest,
     responses(
-    (status = 200, description = "Tokenized ids", body = TokenizeResponse),
+    (status = 200, description = "Tokenized ids. The chat messages get a ChatTokenizeResponse", body = TokenizeResponse),
     (status = 404, description = "No tokenizer found", body = ErrorResponse,
     example = json ! ({"error": "No fast tokenizer available"})),
     )
@@ -1091,8 +2802,49 @@ async fn vertex_compatibility(
 #[instrument(skip_all)]
 async fn tokenize(
     Extension(infer): Extension<Infer>,
-    Json(req): Json<GenerateRequest>,
-) -> Result<Json<TokenizeResponse>, (StatusCode, Json<ErrorResponse>)> {
+    Extension(declared_tools): Extension<DeclaredTools>,
+    Json(req): Json<TokenizeRequest>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    match req {
+        TokenizeRequest::Generate(req) => {
+            let tokens = tokenize_inputs(&infer, req).await?;
+            Ok(Json(TokenizeResponse(tokens)).into_response())
+        }
+        TokenizeRequest::Chat(req) => {
+            // the tools prompt follows the chat template, as in `chat_completions`
+            let tools_prompt = match req.tools.zip(req.tool_choice) {
+                Some((req_tools, tool_choice)) if !req.prompt_already_templated => {
+                    chat_tools(&declared_tools, req_tools, tool_choice, req.tool_prompt)?.1
+                }
+                _ => String::new(),
+            };
+            let inputs: String = match req.raw || req.prompt_already_templated {
+                true => req
+                    .messages
+                    .iter()
+                    .filter_map(|message| message.content.as_deref())
+                    .collect(),
+                false => infer.apply_chat_template(req.messages.clone())?,
+            };
+            let prompt = format!("{inputs}{tools_prompt}");
+            let tokens = tokenize_inputs(
+                &infer,
+                GenerateRequest {
+                    inputs: prompt.clone(),
+                    parameters: GenerateParameters::default(),
+                },
+            )
+            .await?;
+            Ok(Json(ChatTokenizeResponse::new(&prompt, &req.messages, tokens)).into_response())
+        }
+    }
+}
+
+/// Tokens of the inputs of a generate request, with their text
+async fn tokenize_inputs(
+    infer: &Infer,
+    req: GenerateRequest,
+) -> Result<Vec<SimpleToken>, (StatusCode, Json<ErrorResponse>)> {
     let input = req.inputs.clone();
     let encoding = infer.tokenize(req).await?;
     if let Some(encoding) = encoding {
@@ -1110,7 +2862,7 @@ async fn tokenize(
                 }
             })
             .collect();
-        Ok(Json(TokenizeResponse(tokens)))
+        Ok(tokens)
     } else {
         Err((
             StatusCode::NOT_FOUND,
@@ -1129,8 +2881,35 @@ tag = "Text Generation Inference",
 path = "/metrics",
 responses((status = 200, description = "Prometheus Metrics", body = String))
 )]
-async fn metrics(prom_handle: Extension<PrometheusHandle>) -> String {
-    prom_handle.render()
+async fn metrics(
+    prom_handle: Extension<PrometheusHandle>,
+    exemplars: Option<Extension<Exemplars>>,
+    slo: Option<Extension<Arc<Slo>>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(Extension(slo)) = slo {
+        slo.export();
+    }
+    // The exemplars are only part of the OpenMetrics exposition, served once enabled
+    let openmetrics = headers
+        .get(http::header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/openmetrics-text"));
+    let rendered = prom_handle.render();
+    match exemplars
+        .filter(|_| openmetrics)
+        .map(|exemplars| exemplars.annotate(&rendered))
+    {
+        Some(openmetrics) => (
+            [(
+                http::header::CONTENT_TYPE,
+                exemplars::OPENMETRICS_CONTENT_TYPE,
+            )],
+            openmetrics,
+        )
+            .into_response(),
+        None => rendered.into_response(),
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -1144,6 +2923,7 @@ pub async fn run(
     compat_return_full_text: bool,
     max_concurrent_requests: usize,
     max_best_of: usize,
+    max_samples: usize,
     max_stop_sequences: usize,
     max_top_n_tokens: u32,
     max_input_length: usize,
@@ -1164,24 +2944,161 @@ pub async fn run(
     tokenizer_config: HubTokenizerConfig,
     messages_api_enabled: bool,
     grammar_support: bool,
+    memory_pressure_threshold: Option<f32>,
+    memory_pressure_max_prefill_tokens: u32,
+    baggage_keys: Vec<String>,
+    hedging_endpoint: Option<String>,
+    hedging_latency_slo: u64,
+    hedging_max_concurrent: usize,
+    hedging_max_ratio: f32,
+    experiments: Experiments,
+    audit_store_size: usize,
+    openai_error_format: bool,
+    model_aliases: Vec<String>,
+    enforce_model_name: bool,
+    circuit_breaker_error_rate: Option<f32>,
+    circuit_breaker_window: usize,
+    circuit_breaker_cooldown: u64,
+    max_concurrent_streams_per_client: Option<usize>,
+    max_request_memory_mb: Option<usize>,
+    tokenizer_source: TokenizerSource,
+    no_backend: bool,
+    audit_keys: Option<AuditKeys>,
+    sticky_session_window: Option<u64>,
+    suppressed_tokens: Vec<u32>,
+    declared_tools: DeclaredTools,
+    completion_time_slo: Option<u64>,
+    retokenization_check: bool,
+    object_store: Option<ObjectStore>,
+    shutdown_grace_period: Option<u64>,
+    latency_max_waiting_tokens: usize,
+    alert_webhook_url: Option<String>,
+    alert_webhook_interval: u64,
+    max_grammar_size: usize,
+    max_grammar_regex_size: usize,
+    max_grammar_schema_depth: usize,
+    shadow_tokenizer: Option<Tokenizer>,
+    shadow_tokenizer_sample_rate: f32,
+    abort_api_key: Option<String>,
+    admin_api_key: Option<String>,
+    adapter_allowlist: Vec<String>,
+    max_chat_top_logprobs: Option<u32>,
+    max_completions_logprobs: Option<u32>,
+    max_logprob_payload: Option<u32>,
+    incomplete_generation_retries: usize,
+    pii_scanner: Option<PiiScanner>,
+    vertex_max_body_size: Option<usize>,
+    vertex_max_instances: Option<usize>,
+    vertex_max_concurrent_requests: Option<usize>,
+    invocations_max_body_size: Option<usize>,
+    invocations_max_concurrent_requests: Option<usize>,
+    fallback_endpoint: Option<String>,
+    fallback_model_id: Option<String>,
+    coalesce_requests: bool,
+    slo_first_token_ms: Option<u64>,
+    slo_time_per_token_ms: Option<u64>,
+    slo_objective: f64,
+    waiting_room_size: Option<usize>,
+    tokenization_cache_size_mb: Option<usize>,
+    input_token_price_per_1k: Option<f64>,
+    output_token_price_per_1k: Option<f64>,
+    disabled_endpoints: DisabledEndpoints,
+    enable_mcp: bool,
+    error_catalogs: Option<ErrorCatalogs>,
+    chaos: bool,
+    presets: Presets,
+    flight_recorder_size: usize,
+    trust_tenant_header: bool,
+    tenant_keys: TenantKeys,
+    rate_limit_requests_per_second: Option<f64>,
+    rate_limit_tokens_per_minute: Option<u64>,
+    debug_capture_size: usize,
+    stream_transforms: Option<StreamTransforms>,
+    canary_interval: Option<u64>,
+    template_cache_size_mb: Option<usize>,
+    system_prompt_outside_window: Option<SystemPromptPolicy>,
+    metrics_exemplars: bool,
 ) -> Result<(), axum::BoxError> {
     // OpenAPI documentation
     #[derive(OpenApi)]
     #[openapi(
     paths(
     health,
+    shards,
     get_model_info,
+    openai_models,
+    openai_model,
     compat_generate,
     generate,
+    get_details,
+    get_requests,
+    get_tenants,
+    reload_tokenizer,
+    get_chaos,
+    configure_chaos,
+    get_flight_recorder,
+    get_debug_capture,
+    configure_debug_capture,
+    get_adapters,
+    load_adapter,
+    unload_adapter,
+    abort_request,
+    submit_batch_file,
+    get_batch_file,
+    get_batch_file_results,
+    upload_file,
+    get_file,
+    get_file_content,
+    create_batch,
+    list_batches,
+    get_batch,
+    cancel_batch,
+    mcp,
+    generate_samples,
     generate_stream,
     chat_completions,
     completions,
     tokenize,
+    waiting_room::poll,
     metrics,
     ),
     components(
     schemas(
     Info,
+    TicketResponse,
+    ModelList,
+    ModelObject,
+    ShardStatus,
+    RequestRecord,
+    RequestStatus,
+    RequestsPage,
+    TenantSummary,
+    BatchFileSource,
+    BatchFileState,
+    BatchFileStatus,
+    OpenAIFile,
+    OpenAIBatch,
+    OpenAIBatchStatus,
+    OpenAIBatchRequest,
+    OpenAIBatchList,
+    BatchRequestCounts,
+    JsonRpcRequest,
+    JsonRpcResponse,
+    JsonRpcError,
+    TokenizerReloadRequest,
+    ChaosConfig,
+    PresetParameters,
+    SchedulingDecision,
+    DecisionOutcome,
+    DecisionEntry,
+    WaitReason,
+    DebugCaptureConfig,
+    DebugCaptureState,
+    CapturedRequest,
+    Adapter,
+    LoadAdapterRequest,
+    TokenizerReloadResponse,
+    AbortResponse,
     CompatGenerateRequest,
     GenerateRequest,
     GrammarType,
@@ -1195,17 +3112,37 @@ pub async fn run(
     ChatCompletionLogprobs,
     ChatCompletionTopLogprob,
     ChatCompletion,
+    ChatInputTokens,
     CompletionRequest,
     CompletionComplete,
     CompletionCompleteChunk,
+    CompletionLogprobs,
     GenerateParameters,
+    AdapterParameters,
+    TemperatureStep,
+    InputNormalization,
+    NormalizationReport,
+    GenerationStatistics,
     PrefillToken,
     Token,
     GenerateResponse,
+    OutputManifest,
+    SafetyReport,
+    PiiCategory,
+    PiiAction,
+    GenerateSamplesRequest,
+    GenerateSamplesResponse,
+    GeneratedSample,
     TokenizeResponse,
+    TokenizeRequest,
+    ChatTokenizeResponse,
+    MessageTokens,
     SimpleToken,
     BestOfSequence,
     Details,
+    Scheduling,
+    Speculation,
+    Retokenization,
     FinishReason,
     StreamResponse,
     StreamDetails,
@@ -1228,18 +3165,110 @@ pub async fn run(
     struct ApiDoc;
 
     // Create state
+    // The chat and completions caps default to the `top_n_tokens` cap of `/generate`
+    let top_n_tokens_limits = TopNTokensLimits {
+        generate: max_top_n_tokens,
+        chat_completions: max_chat_top_logprobs.unwrap_or(max_top_n_tokens),
+        completions: max_completions_logprobs.unwrap_or(max_top_n_tokens),
+        max_logprob_payload,
+    };
     let validation = Validation::new(
         validation_workers,
         tokenizer,
         max_best_of,
+        max_samples,
         max_stop_sequences,
-        max_top_n_tokens,
+        top_n_tokens_limits,
         max_input_length,
         max_total_tokens,
         grammar_support,
-    );
+        max_request_memory_mb,
+        suppressed_tokens,
+        GrammarLimits {
+            max_size: max_grammar_size,
+            max_regex_size: max_grammar_regex_size,
+            max_schema_depth: max_grammar_schema_depth,
+        },
+    )
+    .with_shadow_tokenizer(
+        shadow_tokenizer
+            .map(|tokenizer| ShadowTokenizer::new(tokenizer, shadow_tokenizer_sample_rate)),
+    )
+    .with_tokenization_cache(
+        tokenization_cache_size_mb.map(|size_mb| TokenizationCache::new(size_mb * 1024 * 1024)),
+    )
+    .with_template_cache(
+        template_cache_size_mb.map(|size_mb| TemplateCache::new(size_mb * 1024 * 1024)),
+    )
+    .with_attention_window(
+        shard_info
+            .window_size
+            .zip(system_prompt_outside_window)
+            .map(|(window_size, policy)| AttentionWindow {
+                window_size,
+                policy,
+            }),
+    )
+    .with_adapters(shard_info.adapters.clone());
+    if system_prompt_outside_window.is_some() && shard_info.window_size.is_none() {
+        tracing::warn!(
+            "`system_prompt_outside_window` is ignored as the model attends to the whole sequence"
+        );
+    }
     let generation_health = Arc::new(AtomicBool::new(false));
-    let health_ext = Health::new(client.clone(), generation_health.clone());
+    // Duration buckets
+    let n_duration_buckets = 35;
+    let mut duration_buckets = Vec::with_capacity(n_duration_buckets);
+    // Minimum duration in seconds
+    let mut value = 0.0001;
+    for _ in 0..n_duration_buckets {
+        // geometric sequence
+        value *= 1.5;
+        duration_buckets.push(value);
+    }
+    // Link the latency histograms to example traces
+    let exemplars = metrics_exemplars.then(|| Exemplars::new(duration_buckets.clone()));
+    // Track the SLOs over rolling windows
+    let slo = Slo::new(
+        slo_first_token_ms.map(Duration::from_millis),
+        slo_time_per_token_ms.map(Duration::from_millis),
+        slo_objective,
+    )
+    .map(Arc::new);
+    // Estimate the cost of the requests from the token prices
+    let pricing = Pricing::new(input_token_price_per_1k, output_token_price_per_1k);
+    // Record the last scheduling decisions of the queue
+    let flight_recorder =
+        (flight_recorder_size > 0).then(|| FlightRecorder::new(flight_recorder_size));
+    // Capture the payloads of the failing requests, masked with the PII categories of the scanner
+    let debug_capture = match debug_capture_size {
+        0 => None,
+        size => Some(DebugCapture::new(
+            size,
+            pii_scanner
+                .clone()
+                .unwrap_or_else(|| PiiScanner::new("mask", &[]).expect("valid PII scanner")),
+        )),
+    };
+    // Page the operators on shard errors and circuit breaker trips
+    let alerts =
+        alert_webhook_url.map(|url| Alerts::new(url, Duration::from_secs(alert_webhook_interval)));
+    // Fail fast while the shards are failing
+    let circuit_breaker = circuit_breaker_error_rate.map(|error_rate| {
+        CircuitBreaker::new(
+            error_rate,
+            circuit_breaker_window,
+            Duration::from_secs(circuit_breaker_cooldown),
+        )
+        .with_alerts(alerts.clone())
+    });
+    // Load and evict the LoRA adapters at runtime
+    let adapters = Adapters::new(client.clone(), adapter_allowlist);
+    let health_ext = Health::new(
+        client.clone(),
+        generation_health.clone(),
+        circuit_breaker.clone(),
+    );
     let infer = Infer::new(
         client,
         validation,
@@ -1252,21 +3281,58 @@ pub async fn run(
         shard_info.requires_padding,
         shard_info.window_size,
         shard_info.speculate,
+        shard_info.speculator.clone(),
+        shard_info.shared_prefill,
         generation_health,
         tokenizer_config,
+        memory_pressure_threshold,
+        memory_pressure_max_prefill_tokens,
+        circuit_breaker,
+        completion_time_slo.map(Duration::from_millis),
+        retokenization_check,
+        latency_max_waiting_tokens,
+        alerts,
+        incomplete_generation_retries,
+        pii_scanner,
+        stream_transforms,
+        chaos.then(Chaos::default),
+        exemplars.clone(),
+        pricing,
+        presets.clone(),
+        slo.clone(),
+        coalesce_requests,
+        flight_recorder.clone(),
+    );
+
+    // Periodic synthetic generations
+    if let Some(canary_interval) = canary_interval {
+        tokio::spawn(canary::canary_task(
+            infer.clone(),
+            Duration::from_secs(canary_interval),
+        ));
+    }
+
+    // Compile the grammars of the declared tools before serving
+    if !no_backend && !declared_tools.names().is_empty() {
+        declared_tools.prewarm(&infer).await;
+    }
+
+    // Report the missing chat support at startup rather than on the first chat request
+    if let Err(err) = infer.chat_support() {
+        if messages_api_enabled {
+            return Err(format!("{err}: the Messages API can not be enabled").into());
+        }
+        tracing::warn!("{err}: `/v1/chat/completions` answers 501");
+    }
+
+    // Abort the in-flight requests once the shutdown grace period elapsed
+    let (shutdown, shutdown_deadline) = graceful_shutdown(
+        infer.clone(),
+        shutdown_grace_period.map(Duration::from_secs),
     );
 
     // Duration buckets
     let duration_matcher = Matcher::Suffix(String::from("duration"));
-    let n_duration_buckets = 35;
-    let mut duration_buckets = Vec::with_capacity(n_duration_buckets);
-    // Minimum duration in seconds
-    let mut value = 0.0001;
-    for _ in 0..n_duration_buckets {
-        // geometric sequence
-        value *= 1.5;
-        duration_buckets.push(value);
-    }
     // Input Length buckets
     let input_length_matcher = Matcher::Full(String::from("tgi_request_input_length"));
     let input_length_buckets: Vec<f64> = (0..100)
@@ -1306,6 +3372,7 @@ pub async fn run(
     let prom_handle = builder
         .install_recorder()
         .expect("failed to install metrics recorder");
+    let preset_parameters = presets.parameters();
 
     // CORS layer
     let allow_origin = allow_origin.unwrap_or(AllowOrigin::any());
@@ -1314,6 +3381,13 @@ pub async fn run(
         .allow_headers([http::header::CONTENT_TYPE])
         .allow_origin(allow_origin);
 
+    // Names accepted in the `model` field of the OpenAI requests
+    let served_model = ServedModel::new(
+        model_info.model_id.clone(),
+        model_aliases,
+        enforce_model_name,
+    );
+
     // Endpoint info
     let info = Info {
         model_id: model_info.model_id,
@@ -1324,6 +3398,10 @@ pub async fn run(
         max_concurrent_requests,
         max_best_of,
         max_stop_sequences,
+        max_top_n_tokens: top_n_tokens_limits.generate,
+        max_chat_top_logprobs: top_n_tokens_limits.chat_completions,
+        max_completions_logprobs: top_n_tokens_limits.completions,
+        max_logprob_payload,
         max_input_length,
         max_total_tokens,
         waiting_served_ratio,
@@ -1331,13 +3409,21 @@ pub async fn run(
         max_waiting_tokens,
         max_batch_size,
         validation_workers,
+        speculate: shard_info.speculate,
+        speculator: shard_info.speculator,
+        window_size: shard_info.window_size,
+        tools: declared_tools.names(),
+        chat_enabled: infer.chat_support().is_ok(),
+        tools_enabled: infer.tools_support(),
+        presets: preset_parameters,
+        adapters: shard_info.adapters,
         version: env!("CARGO_PKG_VERSION"),
         sha: option_env!("VERGEN_GIT_SHA"),
         docker_label: option_env!("DOCKER_LABEL"),
     };
 
     // Define VertextApiDoc conditionally only if the "google" feature is enabled
-    let doc = {
+    let mut doc = {
         // avoid `mut` if possible
         #[cfg(feature = "google")]
         {
@@ -1346,7 +3432,12 @@ pub async fn run(
             #[derive(OpenApi)]
             #[openapi(
                 paths(vertex_compatibility),
-                components(schemas(VertexInstance, VertexRequest, VertexResponse))
+                components(schemas(
+                    VertexInstance,
+                    VertexRequest,
+                    VertexResponse,
+                    VertexPrediction
+                ))
             )]
             struct VertextApiDoc;
 
@@ -1358,40 +3449,218 @@ pub async fn run(
         #[cfg(not(feature = "google"))]
         ApiDoc::openapi()
     };
+    disabled_endpoints.undocument(&mut doc);
+    let enable_mcp = enable_mcp && !disabled_endpoints.contains(Endpoint::Mcp);
+    if !enable_mcp {
+        doc.paths.paths.remove("/mcp");
+    }
+    if !chaos {
+        doc.paths.paths.remove("/admin/chaos");
+    }
 
     // Configure Swagger UI
     let swagger_ui = SwaggerUi::new("/docs").url("/api-doc/openapi.json", doc);
 
     // Define base and health routes
-    let base_routes = Router::new()
-        .route("/", post(compat_generate))
-        .route("/", get(health))
+    // Hedge `/generate` requests to the secondary deployment
+    let hedging = hedging_endpoint.map(|endpoint| {
+        Hedging::new(
+            endpoint,
+            Duration::from_millis(hedging_latency_slo),
+            hedging_max_concurrent,
+            hedging_max_ratio,
+        )
+    });
+    let generate_route = match hedging {
+        Some(_) => post(generate).layer(axum::middleware::from_fn(hedging::hedge)),
+        None => post(generate),
+    };
+
+    // Re-issue the failed generations to the fallback model, outside of the hedging
+    let fallback = fallback_endpoint
+        .zip(fallback_model_id)
+        .map(|(endpoint, model_id)| Fallback::new(endpoint, model_id));
+    let with_fallback = |route: MethodRouter| match fallback {
+        Some(_) => route.layer(axum::middleware::from_fn(fallback::fallback)),
+        None => route,
+    };
+
+    // Accept the non-streamed generations over the concurrency limit as tickets to poll
+    let waiting_room =
+        waiting_room_size.map(|size| WaitingRoom::new(size, max_concurrent_requests));
+    let with_waiting_room = |route: MethodRouter| match waiting_room {
+        Some(_) => route.layer(axum::middleware::from_fn(waiting_room::admit)),
+        None => route,
+    };
+
+    // Cloud front door routes, limited apart from the native API
+    let vertex_limits = RouteLimits::new(
+        "/vertex",
+        vertex_max_body_size,
+        vertex_max_instances,
+        vertex_max_concurrent_requests,
+    );
+    let invocations_limits = RouteLimits::new(
+        "/invocations",
+        invocations_max_body_size,
+        None,
+        invocations_max_concurrent_requests,
+    );
+
+    // Without model shards, the health check only reports that the router is up
+    let health_route = match no_backend {
+        true => get(no_backend::health),
+        false => get(health),
+    };
+
+    // The health checks can not be disabled
+    let mut base_routes = Router::new()
+        .route("/", health_route.clone())
         .route("/info", get(get_model_info))
-        .route("/generate", post(generate))
-        .route("/generate_stream", post(generate_stream))
-        .route("/v1/chat/completions", post(chat_completions))
-        .route("/v1/completions", post(completions))
-        .route("/vertex", post(vertex_compatibility))
-        .route("/tokenize", post(tokenize))
-        .route("/health", get(health))
-        .route("/ping", get(health))
-        .route("/metrics", get(metrics));
+        .route("/health", health_route.clone())
+        .route("/ping", health_route.clone());
+    for (endpoint, path, route) in [
+        (
+            Endpoint::Details,
+            "/results/:request_id/details",
+            get(get_details),
+        ),
+        (Endpoint::Models, "/v1/models", get(openai_models)),
+        // The model ids hold a `/`
+        (Endpoint::Models, "/v1/models/*model_id", get(openai_model)),
+        (
+            Endpoint::Batches,
+            "/v1/files",
+            post(upload_file).layer(DefaultBodyLimit::max(MAX_BATCH_FILE_SIZE)),
+        ),
+        (Endpoint::Batches, "/v1/files/:file_id", get(get_file)),
+        (
+            Endpoint::Batches,
+            "/v1/files/:file_id/content",
+            get(get_file_content),
+        ),
+        (Endpoint::Batches, "/v1/batches", get(list_batches)),
+        (Endpoint::Batches, "/v1/batches/:batch_id", get(get_batch)),
+        (
+            Endpoint::Batches,
+            "/v1/batches/:batch_id/cancel",
+            post(cancel_batch),
+        ),
+        (Endpoint::Tokenize, "/tokenize", post(tokenize)),
+        (
+            Endpoint::BatchFiles,
+            "/batch_files/:batch_id",
+            get(get_batch_file),
+        ),
+        (
+            Endpoint::BatchFiles,
+            "/batch_files/:batch_id/results",
+            get(get_batch_file_results),
+        ),
+        (Endpoint::Metrics, "/metrics", get(metrics)),
+    ] {
+        if !disabled_endpoints.contains(endpoint) {
+            base_routes = base_routes.route(path, route);
+        }
+    }
+
+    // Admin routes, only enabled with an API key
+    let admin_api_key = admin_api_key.filter(|_| !disabled_endpoints.contains(Endpoint::Admin));
+    if let Some(admin_api_key) = admin_api_key {
+        let mut admin_routes = Router::new()
+            .route("/shards", get(shards))
+            .route("/requests", get(get_requests))
+            .route("/tenants", get(get_tenants))
+            .route("/flight_recorder", get(get_flight_recorder))
+            .route(
+                "/debug_capture",
+                get(get_debug_capture).put(configure_debug_capture),
+            )
+            .route("/adapters", get(get_adapters).post(load_adapter))
+            // The hub ids of the adapters hold a `/`
+            .route("/adapters/*adapter_id", delete(unload_adapter))
+            .route("/tokenizer/reload", post(reload_tokenizer));
+        if chaos {
+            tracing::warn!("Chaos mode enabled: configure the injected faults at `/admin/chaos`");
+            admin_routes = admin_routes.route("/chaos", get(get_chaos).put(configure_chaos));
+        }
+        base_routes = base_routes.nest(
+            "/admin",
+            admin_routes
+                .route_layer(axum::middleware::from_fn(abort::authorize_admin))
+                .layer(Extension(AdminApiKey(admin_api_key))),
+        );
+    } else if chaos {
+        tracing::warn!("Chaos mode needs an `--admin-api-key` to configure the injected faults");
+    }
+    if waiting_room.is_some() && !disabled_endpoints.contains(Endpoint::WaitingRoom) {
+        base_routes = base_routes.route("/waiting_room/:ticket_id", get(waiting_room::poll));
+    }
 
     // Conditional AWS Sagemaker route
-    let aws_sagemaker_route = if messages_api_enabled {
-        Router::new().route("/invocations", post(chat_completions)) // Use 'chat_completions' for OAI_ENABLED
+    let invocations_route = if messages_api_enabled {
+        // Use 'chat_completions' for OAI_ENABLED
+        invocations_limits.post(chat_completions)
     } else {
-        Router::new().route("/invocations", post(compat_generate)) // Use 'compat_generate' otherwise
+        // Use 'compat_generate' otherwise
+        invocations_limits.post(compat_generate)
     };
 
-    let compute_type =
-        ComputeType(std::env::var("COMPUTE_TYPE").unwrap_or("gpu+optimized".to_string()));
+    let mut generation_routes = Router::new();
+    for (endpoint, path, route) in [
+        (
+            Endpoint::CompatGenerate,
+            "/",
+            with_waiting_room(with_fallback(post(compat_generate))),
+        ),
+        (
+            Endpoint::Generate,
+            "/generate",
+            with_waiting_room(with_fallback(generate_route)),
+        ),
+        (
+            Endpoint::GenerateSamples,
+            "/generate_samples",
+            post(generate_samples),
+        ),
+        (
+            Endpoint::GenerateStream,
+            "/generate_stream",
+            post(generate_stream),
+        ),
+        (
+            Endpoint::BatchFiles,
+            "/batch_files",
+            post(submit_batch_file).layer(DefaultBodyLimit::max(MAX_BATCH_FILE_SIZE)),
+        ),
+        (Endpoint::Batches, "/v1/batches", post(create_batch)),
+        (
+            Endpoint::ChatCompletions,
+            "/v1/chat/completions",
+            with_waiting_room(with_fallback(post(chat_completions))),
+        ),
+        (
+            Endpoint::Completions,
+            "/v1/completions",
+            with_waiting_room(with_fallback(post(completions))),
+        ),
+        (
+            Endpoint::Vertex,
+            "/vertex",
+            vertex_limits.post(vertex_compatibility),
+        ),
+        (Endpoint::Invocations, "/invocations", invocations_route),
+    ] {
+        if !disabled_endpoints.contains(endpoint) {
+            generation_routes = generation_routes.route(path, route);
+        }
+    }
 
-    // Combine routes and layers
-    let mut app = Router::new()
-        .merge(swagger_ui)
-        .merge(base_routes)
-        .merge(aws_sagemaker_route);
+    // Experimental MCP server
+    if enable_mcp {
+        tracing::info!("Serving the experimental MCP endpoint at `/mcp`");
+        generation_routes = generation_routes.route("/mcp", post(mcp));
+    }
 
     #[cfg(feature = "google")]
     {
@@ -1399,18 +3668,199 @@ pub async fn run(
         tracing::info!(
             "Environment variables `AIP_PREDICT_ROUTE` and `AIP_HEALTH_ROUTE` will be respected."
         );
-        if let Ok(env_predict_route) = std::env::var("AIP_PREDICT_ROUTE") {
-            app = app.route(&env_predict_route, post(vertex_compatibility));
+        let env_predict_route = std::env::var("AIP_PREDICT_ROUTE")
+            .ok()
+            .filter(|_| !disabled_endpoints.contains(Endpoint::Vertex));
+        if let Some(env_predict_route) = env_predict_route {
+            generation_routes = generation_routes
+                .route(&env_predict_route, vertex_limits.post(vertex_compatibility));
         }
+    }
+
+    // Abort of the in-flight generations, only enabled with an API key
+    let abort_api_key = abort_api_key.filter(|_| !disabled_endpoints.contains(Endpoint::Abort));
+    if let Some(abort_api_key) = abort_api_key {
+        base_routes = base_routes.route(
+            "/abort/:request_id",
+            post(abort_request).layer(Extension(AbortApiKey(abort_api_key))),
+        );
+    }
+
+    // Without model shards, the generation routes answer 503
+    if no_backend {
+        generation_routes =
+            generation_routes.route_layer(axum::middleware::from_fn(no_backend::reject));
+    }
+
+    let compute_type =
+        ComputeType(std::env::var("COMPUTE_TYPE").unwrap_or("gpu+optimized".to_string()));
+
+    // Combine routes and layers
+    let mut app = Router::new().merge(base_routes).merge(generation_routes);
+    if !disabled_endpoints.contains(Endpoint::Docs) {
+        app = app.merge(swagger_ui);
+    }
+
+    #[cfg(feature = "google")]
+    {
         if let Ok(env_health_route) = std::env::var("AIP_HEALTH_ROUTE") {
-            app = app.route(&env_health_route, get(health));
+            app = app.route(&env_health_route, health_route);
+        }
+    }
+
+    #[cfg(feature = "playground")]
+    {
+        if !disabled_endpoints.contains(Endpoint::Playground) {
+            tracing::info!(
+                "Built with `playground` feature, serving the playground at `/playground`"
+            );
+            app = app.route("/playground", get(crate::playground::playground));
+        }
+    }
+
+    // Forward the selected W3C baggage entries to the shards
+    if !baggage_keys.is_empty() {
+        app = app.layer(axum::middleware::from_fn(baggage::propagate));
+    }
+
+    // Honor the deadline set by the upstream gateways in the `x-deadline` header
+    app = app.layer(axum::middleware::from_fn(deadline::propagate));
+
+    // Tell the clients what was ignored or adjusted in their requests
+    app = app.layer(axum::middleware::from_fn(warnings::collect));
+
+    // Exemplars of the OpenMetrics scrapes
+    if let Some(exemplars) = exemplars.clone() {
+        app = app.layer(Extension(exemplars));
+    }
+
+    // SLO gauges of the scrapes
+    if let Some(slo) = slo.clone() {
+        app = app.layer(Extension(slo));
+    }
+
+    // Translate the validation errors in the languages of the `Accept-Language` header
+    if let Some(error_catalogs) = error_catalogs {
+        app = app
+            .layer(axum::middleware::from_fn(error_catalog::negotiate))
+            .layer(Extension(error_catalogs));
+    }
+
+    // Give queue priority to the follow-up requests of the sessions
+    if let Some(window) = sticky_session_window {
+        app = app
+            .layer(axum::middleware::from_fn(sticky::prioritize))
+            .layer(Extension(StickySessions::new(Duration::from_millis(
+                window,
+            ))));
+    }
+
+    if let Some(hedging) = hedging {
+        app = app.layer(Extension(hedging));
+    }
+    if let Some(fallback) = fallback {
+        app = app.layer(Extension(fallback));
+    }
+    if let Some(waiting_room) = waiting_room {
+        app = app.layer(Extension(waiting_room));
+    }
+    // Cap the concurrent streams of each client
+    if let Some(max_streams) = max_concurrent_streams_per_client {
+        app = app
+            .layer(axum::middleware::from_fn(stream_limit::limit_streams))
+            .layer(Extension(StreamLimiter::new(max_streams)));
+    }
+    // Rate limit the requests and tokens of each client, before the validation
+    if rate_limit_requests_per_second.is_some() || rate_limit_tokens_per_minute.is_some() {
+        app = app
+            .layer(axum::middleware::from_fn(rate_limit::limit_rate))
+            .layer(Extension(RateLimiter::new(
+                rate_limit_requests_per_second,
+                rate_limit_tokens_per_minute,
+            )));
+    }
+    // Capture the payloads of the failing requests once armed from the admin API, reading the
+    // error events of the streams before their NDJSON re-framing
+    if let Some(debug_capture) = debug_capture {
+        let body_limits = HashMap::from([
+            ("/batch_files".to_string(), MAX_BATCH_FILE_SIZE),
+            ("/v1/files".to_string(), MAX_BATCH_FILE_SIZE),
+            ("/vertex".to_string(), vertex_limits.max_body_size()),
+            (
+                "/invocations".to_string(),
+                invocations_limits.max_body_size(),
+            ),
+        ]);
+        app = app
+            .layer(axum::middleware::from_fn(debug_capture::capture))
+            .layer(Extension(debug_capture.with_body_limits(body_limits)));
+    }
+    // Stream as newline-delimited JSON when requested, after the stream limit
+    app = app.layer(axum::middleware::from_fn(ndjson::ndjson_stream));
+    // Prune the JSON responses to the fields requested by the client
+    app = app.layer(axum::middleware::from_fn(fields::filter_fields));
+    // Debug the scheduling decisions of the queue
+    if let Some(flight_recorder) = flight_recorder {
+        app = app.layer(Extension(flight_recorder));
+    }
+    // Store the outcome of the inference requests
+    if audit_store_size > 0 {
+        if let Some(audit_keys) = &audit_keys {
+            tracing::info!(
+                "Encrypting the audit details with key {}",
+                audit_keys.active_key_id()
+            );
+            audit_keys.watch();
         }
+        app = app
+            .layer(axum::middleware::from_fn(audit::record))
+            .layer(Extension(AuditStore::new(audit_store_size, audit_keys)));
+    }
+    // Write the outputs of the job-style requests to the object store
+    if let Some(object_store) = object_store {
+        app = app.layer(Extension(object_store));
     }
+    // Attribute the requests to their tenant, before the stream limit and the audit store
+    app = app
+        .layer(axum::middleware::from_fn(tenant::identify))
+        .layer(Extension(
+            Tenants::new(trust_tenant_header, tenant_keys).with_pricing(pricing),
+        ));
+
+    // Render the `/v1/*` errors in the OpenAI error envelope
+    if openai_error_format {
+        app = app.layer(axum::middleware::from_fn(
+            openai_error::openai_error_envelope,
+        ));
+    }
+
+    // Run the requests of the OpenAI batches and the MCP tool calls through the completions
+    // handlers
+    let dispatch = openai_batch_dispatch(
+        infer.clone(),
+        compute_type.clone(),
+        info.clone(),
+        experiments.clone(),
+        served_model.clone(),
+        declared_tools.clone(),
+        disabled_endpoints.clone(),
+    );
+    let openai_batches = OpenAIBatches::new(dispatch.clone());
+    let mcp_server = Mcp::new(dispatch, declared_tools.clone(), info.model_id.clone());
 
     // add layers after routes
     app = app
+        .layer(Extension(BaggageKeys(Arc::new(baggage_keys))))
         .layer(Extension(info))
+        .layer(Extension(experiments))
+        .layer(Extension(declared_tools))
+        .layer(Extension(served_model))
+        .layer(Extension(tokenizer_source))
+        .layer(Extension(BatchFiles::default()))
+        .layer(Extension(openai_batches))
+        .layer(Extension(mcp_server))
         .layer(Extension(health_ext.clone()))
+        .layer(Extension(adapters))
         .layer(Extension(compat_return_full_text))
         .layer(Extension(infer))
         .layer(Extension(compute_type))
@@ -1441,26 +3891,33 @@ pub async fn run(
             let listener = tunnel.listen().await.unwrap();
 
             // Run prom metrics and health locally too
+            let mut local_app = Router::new()
+                .route("/health", get(health))
+                .route("/metrics", get(metrics))
+                .layer(Extension(health_ext))
+                .layer(Extension(prom_handle));
+            if let Some(exemplars) = exemplars {
+                local_app = local_app.layer(Extension(exemplars));
+            }
+            if let Some(slo) = slo {
+                local_app = local_app.layer(Extension(slo));
+            }
             tokio::spawn(
                 axum::Server::bind(&addr)
-                    .serve(
-                        Router::new()
-                            .route("/health", get(health))
-                            .route("/metrics", get(metrics))
-                            .layer(Extension(health_ext))
-                            .layer(Extension(prom_handle))
-                            .into_make_service(),
-                    )
+                    .serve(local_app.into_make_service())
                     //Wait until all requests are finished to shut down
                     .with_graceful_shutdown(shutdown_signal()),
             );
 
             // Run server
-            axum::Server::builder(listener)
+            let server = axum::Server::builder(listener)
                 .serve(app.into_make_service())
-                //Wait until all requests are finished to shut down
-                .with_graceful_shutdown(shutdown_signal())
-                .await?;
+                // Wait until all requests are finished to shut down, at most for the grace period
+                .with_graceful_shutdown(shutdown);
+            tokio::select! {
+                result = server => result?,
+                _ = shutdown_deadline => {},
+            }
         }
         #[cfg(not(feature = "ngrok"))]
         {
@@ -1473,11 +3930,14 @@ pub async fn run(
         }
     } else {
         // Run server
-        axum::Server::bind(&addr)
-            .serve(app.into_make_service())
-            // Wait until all requests are finished to shut down
-            .with_graceful_shutdown(shutdown_signal())
-            .await?;
+        let server = axum::Server::bind(&addr)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            // Wait until all requests are finished to shut down, at most for the grace period
+            .with_graceful_shutdown(shutdown);
+        tokio::select! {
+            result = server => result?,
+            _ = shutdown_deadline => {},
+        }
     }
     Ok(())
 }
@@ -1510,6 +3970,108 @@ async fn shutdown_signal() {
     opentelemetry::global::shutdown_tracer_provider();
 }
 
+/// Run the requests of the OpenAI batches through the chat and text completions handlers, in the
+/// context of the caller, e.g. its tenant. The disabled endpoints are not found
+fn openai_batch_dispatch(
+    infer: Infer,
+    compute_type: ComputeType,
+    info: Info,
+    experiments: Experiments,
+    served_model: ServedModel,
+    declared_tools: DeclaredTools,
+    disabled_endpoints: DisabledEndpoints,
+) -> Dispatch {
+    Arc::new(move |body| {
+        let (endpoint, path) = match &body {
+            BatchRequestBody::ChatCompletions(_) => {
+                (Endpoint::ChatCompletions, "/v1/chat/completions")
+            }
+            BatchRequestBody::Completions(_) => (Endpoint::Completions, "/v1/completions"),
+        };
+        let disabled = disabled_endpoints.contains(endpoint);
+        let context = Context::current();
+        let infer = Extension(infer.clone());
+        let compute_type = Extension(compute_type.clone());
+        let info = Extension(info.clone());
+        let experiments = Extension(experiments.clone());
+        let served_model = Extension(served_model.clone());
+        let declared_tools = Extension(declared_tools.clone());
+        Box::pin(
+            async move {
+                if disabled {
+                    let response = (
+                        StatusCode::NOT_FOUND,
+                        Json(ErrorResponse {
+                            error: format!("Endpoint `{path}` is disabled"),
+                            error_type: "not_found".to_string(),
+                        }),
+                    );
+                    return response.into_response();
+                }
+                let response = match body {
+                    BatchRequestBody::ChatCompletions(req) => {
+                        chat_completions(
+                            infer,
+                            compute_type,
+                            info,
+                            experiments,
+                            served_model,
+                            declared_tools,
+                            Json(*req),
+                        )
+                        .await
+                    }
+                    BatchRequestBody::Completions(req) => {
+                        completions(
+                            infer,
+                            compute_type,
+                            info,
+                            experiments,
+                            served_model,
+                            Json(*req),
+                        )
+                        .await
+                    }
+                };
+                response.into_response()
+            }
+            .with_context(context),
+        )
+    })
+}
+
+/// Shutdown signal of the server, and a future completing `grace_period` after the signal once
+/// the in-flight requests were aborted. Without grace period, the requests are never aborted.
+fn graceful_shutdown(
+    infer: Infer,
+    grace_period: Option<Duration>,
+) -> (
+    impl std::future::Future<Output = ()>,
+    impl std::future::Future<Output = ()>,
+) {
+    let (sender, mut receiver) = tokio::sync::watch::channel(false);
+    let signal = async move {
+        shutdown_signal().await;
+        let _ = sender.send(true);
+    };
+    let deadline = async move {
+        let Some(grace_period) = grace_period else {
+            return std::future::pending().await;
+        };
+        if receiver.wait_for(|signaled| *signaled).await.is_err() {
+            return std::future::pending().await;
+        }
+        tokio::time::sleep(grace_period).await;
+        tracing::warn!(
+            "Shutdown grace period of {grace_period:?} elapsed, aborting the in-flight requests"
+        );
+        infer.abort_all();
+        // Let the streams send their final `shutdown` event
+        tokio::time::sleep(SHUTDOWN_FLUSH_DELAY).await;
+    };
+    (signal, deadline)
+}
+
 impl From<i32> for FinishReason {
     fn from(finish_reason: i32) -> Self {
         let finish_reason = text_generation_client::FinishReason::try_from(finish_reason).unwrap();
@@ -1517,19 +4079,110 @@ impl From<i32> for FinishReason {
             text_generation_client::FinishReason::Length => FinishReason::Length,
             text_generation_client::FinishReason::EosToken => FinishReason::EndOfSequenceToken,
             text_generation_client::FinishReason::StopSequence => FinishReason::StopSequence,
+            text_generation_client::FinishReason::EosProbability => FinishReason::EosProbability,
+            text_generation_client::FinishReason::Aborted => FinishReason::Aborted,
+            text_generation_client::FinishReason::Cancelled => FinishReason::Cancelled,
+            text_generation_client::FinishReason::Shutdown => FinishReason::Shutdown,
+            text_generation_client::FinishReason::Preempted => FinishReason::Preempted,
+        }
+    }
+}
+
+/// Status code of the responses failing with `err`
+fn status(err: &InferError) -> StatusCode {
+    match err {
+        InferError::GenerationError(_) => StatusCode::FAILED_DEPENDENCY,
+        InferError::Overloaded(_) => StatusCode::TOO_MANY_REQUESTS,
+        InferError::PrefillCapacity(_) => StatusCode::TOO_MANY_REQUESTS,
+        InferError::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        InferError::IncompleteGeneration => StatusCode::INTERNAL_SERVER_ERROR,
+        InferError::TemplateError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        InferError::TokenizerNotFound | InferError::ChatTemplateNotFound => {
+            StatusCode::NOT_IMPLEMENTED
         }
+        InferError::UpstreamUnhealthy => StatusCode::SERVICE_UNAVAILABLE,
+        InferError::CompletionTime(_, _) => StatusCode::TOO_MANY_REQUESTS,
+        InferError::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        InferError::Shutdown => StatusCode::SERVICE_UNAVAILABLE,
+        InferError::ObjectStore(ObjectStoreError::Destination(_)) => {
+            StatusCode::UNPROCESSABLE_ENTITY
+        }
+        InferError::ObjectStore(_) => StatusCode::BAD_GATEWAY,
+        InferError::PiiBlocked(_) => StatusCode::UNPROCESSABLE_ENTITY,
     }
 }
 
 /// Convert to Axum supported formats
 impl From<InferError> for (StatusCode, Json<ErrorResponse>) {
     fn from(err: InferError) -> Self {
+        (
+            status(&err),
+            Json(ErrorResponse {
+                error: error_catalog::message(&err),
+                error_type: err.error_type().to_string(),
+            }),
+        )
+    }
+}
+
+impl From<AdapterError> for (StatusCode, Json<ErrorResponse>) {
+    fn from(err: AdapterError) -> Self {
+        let status_code = match err {
+            AdapterError::EmptyId => StatusCode::UNPROCESSABLE_ENTITY,
+            AdapterError::NotAllowed(_) => StatusCode::FORBIDDEN,
+            AdapterError::NotLoaded(_) => StatusCode::NOT_FOUND,
+            AdapterError::InUse(_, _) => StatusCode::CONFLICT,
+            AdapterError::Shard(_) => StatusCode::FAILED_DEPENDENCY,
+        };
+
+        (
+            status_code,
+            Json(ErrorResponse {
+                error: err.to_string(),
+                error_type: err.error_type().to_string(),
+            }),
+        )
+    }
+}
+
+impl From<BatchFileError> for (StatusCode, Json<ErrorResponse>) {
+    fn from(err: BatchFileError) -> Self {
         let status_code = match err {
-            InferError::GenerationError(_) => StatusCode::FAILED_DEPENDENCY,
-            InferError::Overloaded(_) => StatusCode::TOO_MANY_REQUESTS,
-            InferError::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
-            InferError::IncompleteGeneration => StatusCode::INTERNAL_SERVER_ERROR,
-            InferError::TemplateError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            BatchFileError::Input(_)
+            | BatchFileError::TooManyLines(_, _)
+            | BatchFileError::InputUriDisabled
+            | BatchFileError::InputUri(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            BatchFileError::TooManyInProgress => StatusCode::TOO_MANY_REQUESTS,
+            BatchFileError::ObjectStore(_) => StatusCode::BAD_GATEWAY,
+            BatchFileError::NotFound => StatusCode::NOT_FOUND,
+            BatchFileError::InProgress => StatusCode::CONFLICT,
+        };
+
+        (
+            status_code,
+            Json(ErrorResponse {
+                error: err.to_string(),
+                error_type: err.error_type().to_string(),
+            }),
+        )
+    }
+}
+
+impl From<OpenAIBatchError> for (StatusCode, Json<ErrorResponse>) {
+    fn from(err: OpenAIBatchError) -> Self {
+        let status_code = match err {
+            OpenAIBatchError::Multipart(_)
+            | OpenAIBatchError::Purpose(_)
+            | OpenAIBatchError::Input(_)
+            | OpenAIBatchError::Endpoint(_)
+            | OpenAIBatchError::TooManyLines(_, _) => StatusCode::UNPROCESSABLE_ENTITY,
+            OpenAIBatchError::TooManyInProgress | OpenAIBatchError::TooManyFiles => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            OpenAIBatchError::FileNotFound(_) | OpenAIBatchError::BatchNotFound(_) => {
+                StatusCode::NOT_FOUND
+            }
+            OpenAIBatchError::NotCancellable(_) => StatusCode::CONFLICT,
         };
 
         (
@@ -1544,9 +4197,11 @@ impl From<InferError> for (StatusCode, Json<ErrorResponse>) {
 
 impl From<InferError> for Event {
     fn from(err: InferError) -> Self {
+        // Sent in a stream answered with a 200: the debug capture needs the status of the error
+        debug_capture::stream_error(status(&err));
         Event::default()
             .json_data(ErrorResponse {
-                error: err.to_string(),
+                error: error_catalog::message(&err),
                 error_type: err.error_type().to_string(),
             })
             .unwrap()
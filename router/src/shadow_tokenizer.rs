@@ -0,0 +1,65 @@
+/// Shadow tokenizer compared with the served tokenizer to validate tokenizer upgrades
+use crate::Retokenization;
+use rand::{thread_rng, Rng};
+use std::sync::Arc;
+use tokenizers::{Tokenizer, TruncationDirection};
+
+#[derive(Clone, Debug)]
+pub(crate) struct ShadowTokenizer {
+    tokenizer: Arc<Tokenizer>,
+    /// Fraction of the requests tokenized with both tokenizers
+    sample_rate: f64,
+}
+
+impl ShadowTokenizer {
+    pub(crate) fn new(tokenizer: Tokenizer, sample_rate: f32) -> Self {
+        Self {
+            tokenizer: Arc::new(tokenizer),
+            sample_rate: sample_rate as f64,
+        }
+    }
+
+    /// For a sampled fraction of the requests, tokenize `inputs` with the shadow tokenizer in
+    /// the background and log the mismatches with the `ids` of the served tokenizer. The
+    /// request is not affected.
+    pub(crate) fn compare(&self, inputs: &str, truncate: Option<usize>, ids: &[u32]) {
+        if !thread_rng().gen_bool(self.sample_rate) {
+            return;
+        }
+        metrics::increment_counter!("tgi_shadow_tokenizer_sampled");
+
+        let tokenizer = self.tokenizer.clone();
+        let inputs = inputs.to_string();
+        let ids = ids.to_vec();
+        tokio::task::spawn_blocking(move || {
+            let mut encoding = match tokenizer.encode(inputs, true) {
+                Ok(encoding) => encoding,
+                Err(err) => {
+                    metrics::increment_counter!("tgi_shadow_tokenizer_error");
+                    tracing::warn!("Shadow tokenizer error: {err}");
+                    return;
+                }
+            };
+            // Same truncation as the served tokenizer
+            if let Some(truncate) = truncate {
+                if truncate < encoding.len() {
+                    encoding.truncate(truncate, 0, TruncationDirection::Left);
+                }
+            }
+
+            let shadow_ids = encoding.get_ids();
+            let comparison = Retokenization::new(&ids, shadow_ids);
+            if let Some(index) = comparison.first_mismatch {
+                metrics::increment_counter!("tgi_shadow_tokenizer_mismatch");
+                let index = index as usize;
+                tracing::warn!(
+                    "Shadow tokenizer mismatch at token {index}: {:?} instead of {:?} ({} tokens instead of {})",
+                    shadow_ids.get(index),
+                    ids.get(index),
+                    shadow_ids.len(),
+                    ids.len()
+                );
+            }
+        });
+    }
+}
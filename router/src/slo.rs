@@ -0,0 +1,189 @@
+/// Service level objectives tracked over rolling windows inside the router: the fraction of the
+/// requests meeting the latency thresholds and the error budget burn rate are exported as gauges,
+/// so that alerting needs the same simple rules on every deployment
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Rolling windows of the exported series, in seconds
+const WINDOWS: [(&str, u64); 3] = [("1m", 60), ("5m", 300), ("1h", 3600)];
+
+/// Requests of one second
+#[derive(Clone, Copy, Debug, Default)]
+struct Bucket {
+    second: u64,
+    good: u64,
+    total: u64,
+}
+
+/// Good and total requests of the last hour, by second
+#[derive(Debug)]
+struct Sli {
+    /// Threshold of the good requests
+    threshold: Duration,
+    buckets: Mutex<Vec<Bucket>>,
+}
+
+impl Sli {
+    fn new(threshold: Duration) -> Self {
+        let seconds = WINDOWS[WINDOWS.len() - 1].1 as usize;
+        Self {
+            threshold,
+            buckets: Mutex::new(vec![Bucket::default(); seconds]),
+        }
+    }
+
+    fn record(&self, second: u64, latency: Duration) {
+        let mut buckets = self.buckets.lock().unwrap();
+        let size = buckets.len() as u64;
+        let bucket = &mut buckets[(second % size) as usize];
+        // The bucket held the requests of an older second
+        if bucket.second != second {
+            *bucket = Bucket {
+                second,
+                ..Default::default()
+            };
+        }
+        bucket.total += 1;
+        if latency <= self.threshold {
+            bucket.good += 1;
+        }
+    }
+
+    /// Good and total requests of the last `window` seconds
+    fn count(&self, now: u64, window: u64) -> (u64, u64) {
+        self.buckets
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|bucket| bucket.total > 0 && now.saturating_sub(bucket.second) < window)
+            .fold((0, 0), |(good, total), bucket| {
+                (good + bucket.good, total + bucket.total)
+            })
+    }
+}
+
+/// SLOs of the configured thresholds
+#[derive(Debug)]
+pub(crate) struct Slo {
+    start: Instant,
+    /// Target fraction of good requests
+    objective: f64,
+    first_token: Option<Sli>,
+    time_per_token: Option<Sli>,
+}
+
+impl Slo {
+    /// Track the SLOs of the configured thresholds, with the `objective` fraction of good
+    /// requests. `None` without any threshold
+    pub(crate) fn new(
+        first_token_threshold: Option<Duration>,
+        time_per_token_threshold: Option<Duration>,
+        objective: f64,
+    ) -> Option<Self> {
+        if first_token_threshold.is_none() && time_per_token_threshold.is_none() {
+            return None;
+        }
+        Some(Self {
+            start: Instant::now(),
+            objective,
+            first_token: first_token_threshold.map(Sli::new),
+            time_per_token: time_per_token_threshold.map(Sli::new),
+        })
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_secs()
+    }
+
+    fn slis(&self) -> impl Iterator<Item = (&'static str, &Sli)> {
+        [
+            ("first_token", self.first_token.as_ref()),
+            ("time_per_token", self.time_per_token.as_ref()),
+        ]
+        .into_iter()
+        .filter_map(|(name, sli)| Some((name, sli?)))
+    }
+
+    /// Good ratio and burn rate of each SLI and window. The burn rate is the rate the error
+    /// budget is spent at: 1 spends it exactly over the SLO period. Windows without requests spend
+    /// none of it
+    fn series(&self, now: u64) -> Vec<(&'static str, &'static str, f64, f64)> {
+        let error_budget = 1.0 - self.objective;
+        let mut series = Vec::new();
+        for (name, sli) in self.slis() {
+            for (window, seconds) in WINDOWS {
+                let good_ratio = match sli.count(now, seconds) {
+                    (_, 0) => 1.0,
+                    (good, total) => good as f64 / total as f64,
+                };
+                series.push((name, window, good_ratio, (1.0 - good_ratio) / error_budget));
+            }
+        }
+        series
+    }
+
+    /// Record the time to first token of a stream
+    pub(crate) fn first_token(&self, latency: Duration) {
+        if let Some(sli) = &self.first_token {
+            sli.record(self.now(), latency);
+        }
+    }
+
+    /// Record the mean time per generated token of a request
+    pub(crate) fn time_per_token(&self, latency: Duration) {
+        if let Some(sli) = &self.time_per_token {
+            sli.record(self.now(), latency);
+        }
+    }
+
+    /// Set the gauges of the SLOs, before a scrape
+    pub(crate) fn export(&self) {
+        for (sli, window, good_ratio, burn_rate) in self.series(self.now()) {
+            metrics::gauge!("tgi_slo_good_ratio", good_ratio, "sli" => sli, "window" => window);
+            metrics::gauge!("tgi_slo_burn_rate", burn_rate, "sli" => sli, "window" => window);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slo_series() {
+        let slo = Slo {
+            start: Instant::now(),
+            objective: 0.99,
+            first_token: Some(Sli::new(Duration::from_millis(500))),
+            time_per_token: None,
+        };
+        let sli = slo.first_token.as_ref().unwrap();
+        // An hour ago: one slow request, then 9 fast ones in the last minute
+        sli.record(0, Duration::from_secs(2));
+        for second in 3580..3589 {
+            sli.record(second, Duration::from_millis(100));
+        }
+        // Over the threshold, 4 minutes ago
+        sli.record(3360, Duration::from_secs(1));
+
+        let series = slo.series(3600);
+        assert_eq!(series.len(), 3);
+        let (_, window, good_ratio, burn_rate) = series[0];
+        assert_eq!((window, good_ratio, burn_rate), ("1m", 1.0, 0.0));
+        let (_, window, good_ratio, burn_rate) = series[1];
+        assert_eq!((window, good_ratio), ("5m", 0.9));
+        assert!((burn_rate - 10.0).abs() < 1e-9);
+        // The request of the first second left the hour window
+        let (_, window, good_ratio, _) = series[2];
+        assert_eq!((window, good_ratio), ("1h", 0.9));
+
+        // The bucket of the first second is reused
+        sli.record(3600, Duration::from_secs(1));
+        assert_eq!(sli.count(3600, 60), (9, 10));
+        assert_eq!(sli.count(3600, 3600), (9, 11));
+
+        // No request over the last minute
+        let (_, window, good_ratio, burn_rate) = slo.series(3700)[0];
+        assert_eq!((window, good_ratio, burn_rate), ("1m", 1.0, 0.0));
+    }
+}
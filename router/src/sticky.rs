@@ -0,0 +1,125 @@
+/// Queue priority of the follow-up requests of conversational sessions
+use axum::body::{HttpBody, StreamBody};
+use axum::extract::Extension;
+use axum::http::{Method, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::trace::FutureExt;
+use opentelemetry::Context;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Header identifying the session of a request
+const SESSION_HEADER: &str = "x-session-id";
+
+/// Marks the requests following a response of their session within the keep-warm window
+struct QueuePriority;
+
+/// Keep-warm window of the sessions
+#[derive(Clone, Debug)]
+pub(crate) struct StickySessions {
+    window: Duration,
+    /// End of the last response of each session
+    finished: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl StickySessions {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            finished: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Whether the last response of the session finished within the window
+    fn is_warm(&self, session: &str) -> bool {
+        let warm = self
+            .finished
+            .lock()
+            .unwrap()
+            .remove(session)
+            .map(|finished| finished.elapsed() <= self.window)
+            .unwrap_or(false);
+        match warm {
+            true => metrics::increment_counter!("tgi_sticky_session_hit"),
+            false => metrics::increment_counter!("tgi_sticky_session_miss"),
+        }
+        warm
+    }
+
+    /// Start the window of the session, dropping the expired windows
+    fn finish(&self, session: String) {
+        let mut finished = self.finished.lock().unwrap();
+        finished.retain(|_, finished| finished.elapsed() <= self.window);
+        finished.insert(session, Instant::now());
+    }
+}
+
+/// Middleware giving queue priority to the requests of a session whose previous response
+/// finished less than the window ago. The window starts once the response body, streamed or
+/// not, has been fully sent.
+pub(crate) async fn prioritize<B>(
+    Extension(sessions): Extension<StickySessions>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if request.method() != Method::POST {
+        return next.run(request).await;
+    }
+    let Some(session) = request
+        .headers()
+        .get(SESSION_HEADER)
+        .and_then(|session| session.to_str().ok())
+        .map(String::from)
+    else {
+        return next.run(request).await;
+    };
+
+    let response = match sessions.is_warm(&session) {
+        true => {
+            let context = Context::current().with_value(QueuePriority);
+            next.run(request).with_context(context).await
+        }
+        false => next.run(request).await,
+    };
+
+    let (parts, mut body) = response.into_parts();
+    let body = async_stream::stream! {
+        while let Some(chunk) = body.data().await {
+            let failed = chunk.is_err();
+            yield chunk;
+            if failed {
+                return;
+            }
+        }
+        sessions.finish(session);
+    };
+    Response::from_parts(parts, axum::body::boxed(StreamBody::new(body)))
+}
+
+/// Whether the current request has queue priority
+pub(crate) fn has_priority() -> bool {
+    Context::current().get::<QueuePriority>().is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sticky_sessions() {
+        let sessions = StickySessions::new(Duration::from_secs(60));
+        assert!(!sessions.is_warm("a"));
+
+        sessions.finish("a".to_string());
+        assert!(sessions.is_warm("a"));
+        // The window is consumed by the follow-up request
+        assert!(!sessions.is_warm("a"));
+
+        let sessions = StickySessions::new(Duration::ZERO);
+        sessions.finish("a".to_string());
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(!sessions.is_warm("a"));
+    }
+}
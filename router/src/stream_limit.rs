@@ -0,0 +1,177 @@
+/// Per-client limit of the concurrent streams
+use crate::tenant::Tenant;
+use crate::ErrorResponse;
+use axum::body::{HttpBody, StreamBody};
+use axum::extract::{ConnectInfo, Extension};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Debug)]
+pub(crate) struct StreamLimiter {
+    /// Maximum number of concurrent streams of a client
+    max_streams: usize,
+    /// Number of open streams by client
+    streams: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+/// Releases a stream of a client when dropped
+pub(crate) struct StreamGuard {
+    client: String,
+    streams: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(count) = streams.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                streams.remove(&self.client);
+            }
+        }
+    }
+}
+
+impl StreamLimiter {
+    pub(crate) fn new(max_streams: usize) -> Self {
+        Self {
+            max_streams,
+            streams: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Open a stream for a client if it is under the limit
+    fn try_acquire(&self, client: String) -> Option<StreamGuard> {
+        let mut streams = self.streams.lock().unwrap();
+        let count = streams.entry(client.clone()).or_insert(0);
+        if *count >= self.max_streams {
+            return None;
+        }
+        *count += 1;
+        Some(StreamGuard {
+            client,
+            streams: self.streams.clone(),
+        })
+    }
+}
+
+/// Clients are identified by their tenant, or by their IP address. `None` when neither is known
+pub(crate) fn client_id(
+    tenant: Option<&Tenant>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+) -> Option<String> {
+    match tenant.filter(|tenant| tenant.identified) {
+        Some(tenant) => Some(format!("tenant:{}", tenant.id)),
+        None => connect_info.map(|ConnectInfo(addr)| format!("ip:{}", addr.ip())),
+    }
+}
+
+/// Middleware rejecting the streams of the clients that already reached their limit.
+/// Streams are only generated once their body is polled: rejecting the response cancels them
+/// before they reach the queue.
+pub(crate) async fn limit_streams<B>(
+    Extension(limiter): Extension<StreamLimiter>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let client = client_id(request.extensions().get::<Tenant>(), connect_info)
+        .unwrap_or_else(|| "unknown".to_string());
+    let response = next.run(request).await;
+
+    let is_stream = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .map(|content_type| content_type.as_bytes().starts_with(b"text/event-stream"))
+        .unwrap_or(false);
+    if !is_stream {
+        return response;
+    }
+
+    let guard = match limiter.try_acquire(client) {
+        Some(guard) => guard,
+        None => {
+            metrics::increment_counter!("tgi_request_failure", "err" => "too_many_streams");
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                Json(ErrorResponse {
+                    error: format!(
+                        "Too many concurrent streams: at most {} are allowed per client",
+                        limiter.max_streams
+                    ),
+                    error_type: "too_many_streams".to_string(),
+                }),
+            )
+                .into_response();
+        }
+    };
+
+    // Keep the guard as long as the stream lives
+    let (parts, mut body) = response.into_parts();
+    let body = async_stream::stream! {
+        let _guard = guard;
+        while let Some(chunk) = body.data().await {
+            yield chunk;
+        }
+    };
+    Response::from_parts(parts, axum::body::boxed(StreamBody::new(body)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tenant::{TenantKeys, Tenants};
+    use axum::http::HeaderMap;
+
+    #[test]
+    fn test_stream_limiter() {
+        let limiter = StreamLimiter::new(2);
+        let first = limiter.try_acquire("a".to_string());
+        let second = limiter.try_acquire("a".to_string());
+        assert!(first.is_some());
+        assert!(second.is_some());
+        assert!(limiter.try_acquire("a".to_string()).is_none());
+        // Other clients are not limited
+        assert!(limiter.try_acquire("b".to_string()).is_some());
+
+        drop(first);
+        assert!(limiter.try_acquire("a".to_string()).is_some());
+
+        drop(second);
+        assert!(limiter.streams.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_client_id() {
+        let tenants = Tenants::new(true, TenantKeys::default());
+        let addr = Some(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4242))));
+        let client = |headers: &[(&'static str, &str)]| {
+            let mut map = HeaderMap::new();
+            for (name, value) in headers {
+                map.insert(*name, value.parse().unwrap());
+            }
+            client_id(Some(&tenants.tenant(&map)), addr)
+        };
+
+        assert_eq!(
+            client(&[("x-tenant-id", "team-a")]).as_deref(),
+            Some("tenant:team-a")
+        );
+        // The requests with unknown API keys share the streams of their IP address
+        assert_eq!(
+            client(&[("authorization", "Bearer a")]).as_deref(),
+            Some("ip:10.0.0.1")
+        );
+        assert_eq!(
+            client(&[("authorization", "Bearer b")]).as_deref(),
+            Some("ip:10.0.0.1")
+        );
+        assert_eq!(client_id(None, None), None);
+    }
+}
@@ -0,0 +1,223 @@
+/// Ordered regex replacements of the generated text, e.g. to strip `<|assistant|>` remnants or
+/// rewrite internal tool sentinels. The streamed tokens are transformed on the fly: the text that
+/// may still be part of a match is held back until the next tokens
+use regex::Regex;
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Default number of characters a match may span
+const DEFAULT_WINDOW: usize = 32;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TransformConfig {
+    pattern: String,
+    #[serde(default)]
+    replacement: String,
+    window: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TransformsConfig {
+    #[serde(default)]
+    transform: Vec<TransformConfig>,
+}
+
+#[derive(Clone, Debug)]
+struct Transform {
+    regex: Regex,
+    /// Replacement of the matches, `$1` or `${name}` expanding to their capture groups
+    replacement: String,
+    /// Maximum number of characters of a match
+    window: usize,
+}
+
+impl Transform {
+    /// Byte index of the text before which a match can start, the matches being at most
+    /// `window` characters long
+    fn boundary(&self, text: &str) -> usize {
+        let held = self.window - 1;
+        match text.chars().count().checked_sub(held) {
+            Some(0) | None => 0,
+            Some(boundary) => text
+                .char_indices()
+                .nth(boundary)
+                .map(|(index, _)| index)
+                .unwrap_or(text.len()),
+        }
+    }
+}
+
+/// Transforms of the `--stream-transforms-file`, applied in order
+#[derive(Clone, Debug, Default)]
+pub struct StreamTransforms(Arc<Vec<Transform>>);
+
+impl StreamTransforms {
+    /// Load the transforms of a TOML file, one `[[transform]]` table per transform with its
+    /// `pattern`, `replacement` and `window`
+    pub fn from_file(filename: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(filename).map_err(|err| err.to_string())?;
+        Self::from_toml(&content)
+    }
+
+    fn from_toml(content: &str) -> Result<Self, String> {
+        let config: TransformsConfig = toml::from_str(content).map_err(|err| err.to_string())?;
+        let transforms = config
+            .transform
+            .into_iter()
+            .map(|transform| {
+                let regex = Regex::new(&transform.pattern)
+                    .map_err(|err| format!("invalid pattern `{}`: {err}", transform.pattern))?;
+                let window = transform.window.unwrap_or(DEFAULT_WINDOW);
+                if window == 0 {
+                    return Err(format!("`window` of `{}` must be > 0", transform.pattern));
+                }
+                Ok(Transform {
+                    regex,
+                    replacement: transform.replacement,
+                    window,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Self(Arc::new(transforms)))
+    }
+
+    /// Transform a whole text
+    pub(crate) fn apply(&self, text: String) -> String {
+        self.0.iter().fold(text, |text, transform| {
+            transform
+                .regex
+                .replace_all(&text, transform.replacement.as_str())
+                .into_owned()
+        })
+    }
+
+    pub(crate) fn transformer(&self) -> StreamTransformer {
+        StreamTransformer {
+            stages: self
+                .0
+                .iter()
+                .map(|transform| Stage {
+                    transform: transform.clone(),
+                    pending: String::new(),
+                })
+                .collect(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Transform of a stream, holding back the text of its potential matches
+#[derive(Debug)]
+struct Stage {
+    transform: Transform,
+    pending: String,
+}
+
+impl Stage {
+    fn next(&mut self, text: &str) -> String {
+        let transform = &self.transform;
+        self.pending.push_str(text);
+        let boundary = transform.boundary(&self.pending);
+
+        let mut output = String::with_capacity(self.pending.len());
+        let mut last = 0;
+        for captures in transform.regex.captures_iter(&self.pending) {
+            let found = captures.get(0).expect("match");
+            // The text after the boundary may still be part of a longer match
+            if found.start() >= boundary {
+                break;
+            }
+            output.push_str(&self.pending[last..found.start()]);
+            captures.expand(&transform.replacement, &mut output);
+            last = found.end();
+        }
+        let cut = last.max(boundary);
+        output.push_str(&self.pending[last..cut]);
+        self.pending.drain(..cut);
+        output
+    }
+
+    fn flush(&mut self) -> String {
+        let pending = std::mem::take(&mut self.pending);
+        self.transform
+            .regex
+            .replace_all(&pending, self.transform.replacement.as_str())
+            .into_owned()
+    }
+}
+
+/// Transforms of the text of the streamed tokens, each transform feeding the next one
+#[derive(Debug)]
+pub(crate) struct StreamTransformer {
+    stages: Vec<Stage>,
+}
+
+impl StreamTransformer {
+    /// Transformed text of the next token, possibly empty while a match is pending
+    pub(crate) fn next(&mut self, text: &str) -> String {
+        self.stages
+            .iter_mut()
+            .fold(text.to_string(), |text, stage| stage.next(&text))
+    }
+
+    /// Text held back at the end of the stream
+    pub(crate) fn flush(&mut self) -> String {
+        self.stages.iter_mut().fold(String::new(), |text, stage| {
+            stage.next(&text) + &stage.flush()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_transforms() {
+        let transforms = StreamTransforms::from_toml(
+            r#"
+            [[transform]]
+            pattern = '<\|assistant\|>'
+
+            [[transform]]
+            pattern = '<tool>(\w+)</tool>'
+            replacement = '[tool: $1]'
+            window = 64
+            "#,
+        )
+        .unwrap();
+
+        let text = "<|assistant|>Let me check. <tool>search</tool> Done <|assistant|>";
+        let expected = "Let me check. [tool: search] Done ";
+        assert_eq!(transforms.apply(text.to_string()), expected);
+
+        // The patterns spanning the token boundaries are matched
+        for size in [1, 3, 7] {
+            let mut transformer = transforms.transformer();
+            let chars: Vec<char> = text.chars().collect();
+            let mut streamed = String::new();
+            for chunk in chars.chunks(size) {
+                streamed += &transformer.next(&chunk.iter().collect::<String>());
+            }
+            streamed += &transformer.flush();
+            assert_eq!(streamed, expected);
+        }
+
+        // The text is only held back while it may be part of a match
+        let mut transformer = transforms.transformer();
+        assert_eq!(transformer.next("Hé"), "");
+        assert_eq!(
+            transformer.next(&"x".repeat(100)),
+            format!("Hé{}", "x".repeat(6))
+        );
+
+        assert!(StreamTransforms::from_toml("[[transform]]\npattern = '('").is_err());
+        assert!(StreamTransforms::from_toml("[[transform]]\npattern = 'a'\nwindow = 0").is_err());
+    }
+}
@@ -0,0 +1,320 @@
+/// Least recently used cache of the rendered and tokenized conversations: the next turn of a
+/// conversation renders the same messages followed by the new ones, only the text following the
+/// cached template output is tokenized
+use crate::tokenization_cache::TOKEN_BYTES;
+use crate::Message;
+use opentelemetry::trace::{FutureExt, WithContext};
+use opentelemetry::Context;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokenizers::{Encoding, Tokenizer, TruncationDirection};
+
+/// Hashes of the successive prefixes of the messages of a chat request, the whole conversation
+/// last, stored in the current OpenTelemetry context
+#[derive(Clone, Debug)]
+pub(crate) struct Conversation(Arc<Vec<u64>>);
+
+impl Conversation {
+    pub(crate) fn new(messages: &[Message]) -> Self {
+        let mut hasher = DefaultHasher::new();
+        let hashes = messages
+            .iter()
+            .map(|message| {
+                serde_json::to_string(message)
+                    .unwrap_or_default()
+                    .hash(&mut hasher);
+                hasher.clone().finish()
+            })
+            .collect();
+        Self(Arc::new(hashes))
+    }
+}
+
+/// Create the future of `generation` with the `conversation` in its context, keeping the values
+/// of the current context. The context is attached to the future, polled after this returns
+pub(crate) fn in_conversation<F: Future>(
+    conversation: Option<Conversation>,
+    generation: impl FnOnce() -> F,
+) -> WithContext<F> {
+    let context = conversation.map_or_else(Context::current, |conversation| {
+        Context::current_with_value(conversation)
+    });
+    let _guard = context.clone().attach();
+    generation().with_context(context)
+}
+
+/// Conversation of the current chat request, if any
+pub(crate) fn current() -> Option<Conversation> {
+    Context::current().get::<Conversation>().cloned()
+}
+
+/// Template output of a conversation up to its last special token, and its encoding
+#[derive(Clone, Debug)]
+pub(crate) struct Prefix {
+    text: String,
+    encoding: Encoding,
+}
+
+impl Prefix {
+    /// Cut the encoding of a rendered conversation after its last special token, e.g. the end of
+    /// the last message before the generation prompt. The tokens of the following text can not
+    /// merge with the tokens of the prefix
+    pub(crate) fn cut(tokenizer: &Tokenizer, text: &str, mut encoding: Encoding) -> Option<Self> {
+        let special: HashSet<u32> = tokenizer
+            .get_added_tokens_decoder()
+            .into_iter()
+            .filter_map(|(id, token)| token.special.then_some(id))
+            .collect();
+        // The tokens added by the post-processor, e.g. the BOS token, are not in the text
+        let index = encoding
+            .get_ids()
+            .iter()
+            .zip(encoding.get_special_tokens_mask())
+            .rposition(|(id, mask)| *mask == 0 && special.contains(id))?;
+        let end = encoding.get_offsets()[index].1;
+        let text = text.get(..end).filter(|text| !text.is_empty())?.to_string();
+        encoding.truncate(index + 1, 0, TruncationDirection::Right);
+        encoding.take_overflowing();
+        Some(Self { text, encoding })
+    }
+
+    /// Encode `inputs` starting with the prefix, only tokenizing the text following it
+    pub(crate) fn encode(
+        self,
+        tokenizer: &Tokenizer,
+        inputs: &str,
+    ) -> tokenizers::Result<Encoding> {
+        let mut encoding = self.encoding;
+        let delta = tokenizer.encode(&inputs[self.text.len()..], false)?;
+        encoding.merge_with(delta, true);
+        Ok(encoding)
+    }
+
+    /// Whether encoding `text` from the prefix gives the tokens of its whole `encoding`. Not the
+    /// case for the tokenizers prepending a space to the text following the prefix, e.g.
+    /// Metaspace with `prepend_scheme: first`, or adding suffix tokens in their post-processor
+    pub(crate) fn verify(&self, tokenizer: &Tokenizer, text: &str, encoding: &Encoding) -> bool {
+        self.clone()
+            .encode(tokenizer, text)
+            .is_ok_and(|cached| cached.get_ids() == encoding.get_ids())
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    prefix: Prefix,
+    bytes: usize,
+    /// Tick of the last use
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    entries: HashMap<u64, Entry>,
+    /// Keys by tick of their last use, the least recently used first
+    order: BTreeMap<u64, u64>,
+    tick: u64,
+    bytes: usize,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct TemplateCache {
+    /// Maximum memory of the cached conversations, in bytes
+    max_bytes: usize,
+    lru: Arc<Mutex<Lru>>,
+    /// Cleared once a prefix fails its verification: the tokenizer does not encode the text
+    /// following a prefix as the whole text
+    exact: Arc<AtomicBool>,
+}
+
+impl TemplateCache {
+    pub(crate) fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            lru: Arc::new(Mutex::new(Lru::default())),
+            exact: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// Longest cached prefix of the conversation whose template output starts `inputs`
+    pub(crate) fn get(&self, conversation: &Conversation, inputs: &str) -> Option<Prefix> {
+        if !self.exact.load(Ordering::SeqCst) {
+            return None;
+        }
+        let mut lru = self.lru.lock().unwrap();
+        let found = conversation.0.iter().rev().find_map(|key| {
+            let entry = lru.entries.get(key)?;
+            inputs
+                .starts_with(&entry.prefix.text)
+                .then_some((*key, entry.used))
+        });
+        let Some((key, used)) = found else {
+            metrics::increment_counter!("tgi_template_cache_requests", "result" => "miss");
+            return None;
+        };
+        lru.tick += 1;
+        let tick = lru.tick;
+        lru.order.remove(&used);
+        lru.order.insert(tick, key);
+        let entry = lru.entries.get_mut(&key).expect("entry");
+        entry.used = tick;
+        metrics::increment_counter!("tgi_template_cache_requests", "result" => "hit");
+        metrics::histogram!(
+            "tgi_template_cache_reused_tokens",
+            entry.prefix.encoding.len() as f64
+        );
+        Some(entry.prefix.clone())
+    }
+
+    /// Stop caching the conversations of the current tokenizer, the cached prefixes would
+    /// change the tokens of the next turns
+    pub(crate) fn disable(&self) {
+        if self.exact.swap(false, Ordering::SeqCst) {
+            tracing::warn!("The template cache is disabled: the tokenizer does not encode the next turns of a conversation as their whole text");
+            metrics::increment_counter!("tgi_template_cache_disabled");
+        }
+        *self.lru.lock().unwrap() = Lru::default();
+        metrics::gauge!("tgi_template_cache_bytes", 0.0);
+    }
+
+    /// Cache the prefix of a whole conversation for its next turns, evicting the least recently
+    /// used conversations over the memory cap
+    pub(crate) fn insert(&self, conversation: &Conversation, prefix: Prefix) {
+        let Some(key) = conversation.0.last().copied() else {
+            return;
+        };
+        let bytes = prefix.text.len() + prefix.encoding.len() * TOKEN_BYTES;
+        if bytes > self.max_bytes || !self.exact.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut lru = self.lru.lock().unwrap();
+        lru.tick += 1;
+        let tick = lru.tick;
+        if let Some(previous) = lru.entries.insert(
+            key,
+            Entry {
+                prefix,
+                bytes,
+                used: tick,
+            },
+        ) {
+            lru.order.remove(&previous.used);
+            lru.bytes -= previous.bytes;
+        }
+        lru.order.insert(tick, key);
+        lru.bytes += bytes;
+
+        while lru.bytes > self.max_bytes {
+            let Some((_, evicted)) = lru.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = lru.entries.remove(&evicted) {
+                lru.bytes -= entry.bytes;
+                metrics::increment_counter!("tgi_template_cache_evictions");
+            }
+        }
+        metrics::gauge!("tgi_template_cache_bytes", lru.bytes as f64);
+    }
+
+    /// Forget the conversations of the previous tokenizer and chat template, the new tokenizer
+    /// being verified again
+    pub(crate) fn clear(&self) {
+        *self.lru.lock().unwrap() = Lru::default();
+        self.exact.store(true, Ordering::SeqCst);
+        metrics::gauge!("tgi_template_cache_bytes", 0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: Some(content.to_string()),
+            name: None,
+            tool_calls: None,
+            priority: None,
+            parsed: None,
+        }
+    }
+
+    fn prefix(text: &str, tokens: usize) -> Prefix {
+        let encoding = Encoding::new(
+            vec![0; tokens],
+            vec![0; tokens],
+            vec![String::new(); tokens],
+            vec![None; tokens],
+            vec![(0, 0); tokens],
+            vec![0; tokens],
+            vec![1; tokens],
+            Vec::new(),
+            HashMap::new(),
+        );
+        Prefix {
+            text: text.to_string(),
+            encoding,
+        }
+    }
+
+    #[test]
+    fn test_template_cache() {
+        let first_turn = vec![message("system", "Be brief"), message("user", "Hi")];
+        let mut second_turn = first_turn.clone();
+        second_turn.push(message("assistant", "Hello"));
+        second_turn.push(message("user", "How are you?"));
+
+        let cache = TemplateCache::new(1024 * 1024);
+        cache.insert(
+            &Conversation::new(&first_turn),
+            prefix("<s>Be brief</s>Hi</s>", 5),
+        );
+
+        // The next turn reuses the template output of the previous one
+        let conversation = Conversation::new(&second_turn);
+        let found = cache
+            .get(
+                &conversation,
+                "<s>Be brief</s>Hi</s>Hello</s>How are you?</s>",
+            )
+            .unwrap();
+        assert_eq!(found.text, "<s>Be brief</s>Hi</s>");
+        assert_eq!(found.encoding.len(), 5);
+
+        // The template output must start the inputs, e.g. not with a new system prompt
+        assert!(cache.get(&conversation, "<s>Be short</s>Hi</s>").is_none());
+        let mut edited = second_turn.clone();
+        edited[0] = message("system", "Be short");
+        assert!(cache
+            .get(&Conversation::new(&edited), "<s>Be brief</s>Hi</s>")
+            .is_none());
+
+        // Disabled until the next tokenizer
+        cache.disable();
+        cache.insert(
+            &Conversation::new(&first_turn),
+            prefix("<s>Be brief</s>Hi</s>", 5),
+        );
+        assert!(cache
+            .get(&conversation, "<s>Be brief</s>Hi</s>Hello</s>")
+            .is_none());
+        cache.clear();
+        cache.insert(
+            &Conversation::new(&first_turn),
+            prefix("<s>Be brief</s>Hi</s>", 5),
+        );
+        assert!(cache
+            .get(&conversation, "<s>Be brief</s>Hi</s>Hello</s>")
+            .is_some());
+
+        // Larger than the whole cache
+        let cache = TemplateCache::new(10);
+        cache.insert(&Conversation::new(&first_turn), prefix("<s>", 1));
+        assert!(cache.get(&conversation, "<s>").is_none());
+    }
+}
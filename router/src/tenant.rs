@@ -0,0 +1,397 @@
+/// Tenants of the router: the metrics, audit records, usage and stream limits of a request are
+/// all attributed to its tenant
+use crate::pricing::Pricing;
+use axum::body::{HttpBody, StreamBody};
+use axum::extract::Extension;
+use axum::http::header::AUTHORIZATION;
+use axum::http::{HeaderMap, Method, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::trace::FutureExt;
+use opentelemetry::Context;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::Instrument;
+use utoipa::ToSchema;
+
+/// Header naming the tenant of a request, set by a trusted proxy
+pub(crate) const TENANT_HEADER: &str = "x-tenant-id";
+/// Tenant of the requests without tenant header nor known API key
+const ANONYMOUS_TENANT: &str = "anonymous";
+/// Tenant of the requests of the tenants over `MAX_TENANTS`, bounding the metrics cardinality.
+/// Not identified: their limits apply per IP address
+const OTHER_TENANT: &str = "other";
+/// Maximum number of distinct tenants tracked
+const MAX_TENANTS: usize = 1024;
+
+/// Live counters of the tenants
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Tenants {
+    counters: Arc<Mutex<HashMap<String, Arc<TenantCounters>>>>,
+    /// Whether the `x-tenant-id` header is set by a trusted proxy. Otherwise any client could
+    /// name a new tenant in each request, escaping the limits of its own
+    trust_header: bool,
+    keys: TenantKeys,
+    /// Token prices the cost of the usage is estimated from, if configured
+    pricing: Option<Pricing>,
+}
+
+/// API keys identifying the tenants, by SHA-256 digest. The requests with another key are
+/// anonymous: any client could send a new key in each request, escaping the limits of its own
+#[derive(Clone, Debug, Default)]
+pub struct TenantKeys(Arc<HashSet<Vec<u8>>>);
+
+impl TenantKeys {
+    /// Keys read from a file, one per line. The empty lines and the lines starting with `#`
+    /// are ignored
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let keys = Self::parse(&content);
+        match keys.0.is_empty() {
+            true => Err("no API key found".to_string()),
+            false => Ok(keys),
+        }
+    }
+
+    fn parse(content: &str) -> Self {
+        let digests = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|key| Sha256::digest(key.as_bytes()).to_vec())
+            .collect();
+        Self(Arc::new(digests))
+    }
+
+    /// Whether the `Authorization: Bearer <key>` header holds a known key
+    fn contains(&self, authorization: &str) -> bool {
+        authorization
+            .strip_prefix("Bearer ")
+            .is_some_and(|key| self.0.contains(Sha256::digest(key.as_bytes()).as_slice()))
+    }
+}
+
+#[derive(Debug, Default)]
+struct TenantCounters {
+    requests: AtomicU64,
+    failures: AtomicU64,
+    in_flight: AtomicU64,
+    input_tokens: AtomicU64,
+    generated_tokens: AtomicU64,
+    /// Estimated cost, in millionths
+    estimated_cost: AtomicU64,
+}
+
+/// Tenant of a request
+#[derive(Clone, Debug)]
+pub(crate) struct Tenant {
+    pub id: String,
+    /// Whether the tenant was named by a trusted proxy or identified by a known API key
+    pub identified: bool,
+    counters: Arc<TenantCounters>,
+    pricing: Option<Pricing>,
+}
+
+/// Counters of a tenant, as listed by `/admin/tenants`
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub(crate) struct TenantSummary {
+    #[schema(example = "team-a")]
+    pub tenant: String,
+    #[schema(example = 1042)]
+    pub requests: u64,
+    #[schema(example = 12)]
+    pub failures: u64,
+    #[schema(example = 3)]
+    pub in_flight: u64,
+    #[schema(example = 52100)]
+    pub input_tokens: u64,
+    #[schema(example = 104200)]
+    pub generated_tokens: u64,
+    /// Cost estimated from the token prices, if configured
+    #[schema(example = 182.4)]
+    pub estimated_cost: f64,
+}
+
+impl Tenants {
+    pub(crate) fn new(trust_header: bool, keys: TenantKeys) -> Self {
+        Self {
+            trust_header,
+            keys,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn with_pricing(mut self, pricing: Option<Pricing>) -> Self {
+        self.pricing = pricing;
+        self
+    }
+
+    /// Tenant `id`, created on its first request
+    fn get(&self, id: Option<String>) -> Tenant {
+        let mut identified = id.is_some();
+        let mut id = id.unwrap_or_else(|| ANONYMOUS_TENANT.to_string());
+        let mut counters = self.counters.lock().unwrap();
+        if !counters.contains_key(&id) && counters.len() >= MAX_TENANTS {
+            id = OTHER_TENANT.to_string();
+            identified = false;
+        }
+        let tenant_counters = counters.entry(id.clone()).or_default().clone();
+        Tenant {
+            id,
+            identified,
+            counters: tenant_counters,
+            pricing: self.pricing,
+        }
+    }
+
+    /// Tenant of the request `headers`, created on its first request
+    pub(crate) fn tenant(&self, headers: &HeaderMap) -> Tenant {
+        self.get(self.tenant_id(headers))
+    }
+
+    /// Identified tenant of the request `headers`, if already tracked. Unlike the inference
+    /// requests, the other requests do not create their tenant
+    pub(crate) fn find(&self, headers: &HeaderMap) -> Option<Tenant> {
+        let id = self.tenant_id(headers)?;
+        let counters = self.counters.lock().unwrap().get(&id)?.clone();
+        Some(Tenant {
+            id,
+            identified: true,
+            counters,
+            pricing: self.pricing,
+        })
+    }
+
+    /// Counters of all the tenants, sorted by tenant
+    pub(crate) fn summary(&self) -> Vec<TenantSummary> {
+        let counters = self.counters.lock().unwrap();
+        let mut summary: Vec<TenantSummary> = counters
+            .iter()
+            .map(|(tenant, counters)| TenantSummary {
+                tenant: tenant.clone(),
+                requests: counters.requests.load(Ordering::Relaxed),
+                failures: counters.failures.load(Ordering::Relaxed),
+                in_flight: counters.in_flight.load(Ordering::Relaxed),
+                input_tokens: counters.input_tokens.load(Ordering::Relaxed),
+                generated_tokens: counters.generated_tokens.load(Ordering::Relaxed),
+                estimated_cost: counters.estimated_cost.load(Ordering::Relaxed) as f64 / 1e6,
+            })
+            .collect();
+        summary.sort_by(|a, b| a.tenant.cmp(&b.tenant));
+        summary
+    }
+
+    /// Tenants are identified by a fingerprint of their API key, if known, or named by the
+    /// `x-tenant-id` header of a trusted proxy
+    fn tenant_id(&self, headers: &HeaderMap) -> Option<String> {
+        if let Some(tenant) = headers
+            .get(TENANT_HEADER)
+            .filter(|_| self.trust_header)
+            .and_then(|tenant| tenant.to_str().ok())
+            .filter(|tenant| !tenant.is_empty())
+        {
+            return Some(tenant.to_string());
+        }
+        let key = headers.get(AUTHORIZATION)?;
+        if !key.to_str().is_ok_and(|key| self.keys.contains(key)) {
+            return None;
+        }
+        // The key itself must not end up in the metrics and logs. The fingerprint is stable
+        // across restarts and builds
+        let digest = Sha256::digest(key.as_bytes());
+        let fingerprint: String = digest[..8]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        Some(format!("key-{fingerprint}"))
+    }
+}
+
+impl Tenant {
+    /// Account the tokens of a finished generation
+    pub(crate) fn record_usage(&self, input_tokens: u32, generated_tokens: u32) {
+        self.counters
+            .input_tokens
+            .fetch_add(input_tokens as u64, Ordering::Relaxed);
+        self.counters
+            .generated_tokens
+            .fetch_add(generated_tokens as u64, Ordering::Relaxed);
+        metrics::counter!("tgi_tenant_input_tokens", input_tokens as u64, "tenant" => self.id.clone());
+        metrics::counter!("tgi_tenant_generated_tokens", generated_tokens as u64, "tenant" => self.id.clone());
+        if let Some(pricing) = self.pricing {
+            let cost = (pricing.estimate(input_tokens, generated_tokens) * 1e6).round() as u64;
+            self.counters
+                .estimated_cost
+                .fetch_add(cost, Ordering::Relaxed);
+            metrics::counter!("tgi_tenant_estimated_cost_micros", cost, "tenant" => self.id.clone());
+        }
+    }
+}
+
+/// Releases the in-flight request of a tenant when dropped
+struct InFlightGuard(Arc<TenantCounters>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Middleware attributing the request to its tenant. The tenant is added to the request
+/// extensions, to the OpenTelemetry context (for the usage accounting of the queue entries) and
+/// to the logs of the request.
+pub(crate) async fn identify<B>(
+    Extension(tenants): Extension<Tenants>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    // Only the inference requests are attributed, not the health checks or metrics scrapes
+    if request.method() != Method::POST {
+        return next.run(request).await;
+    }
+
+    let tenant = tenants.tenant(request.headers());
+    tenant.counters.requests.fetch_add(1, Ordering::Relaxed);
+    tenant.counters.in_flight.fetch_add(1, Ordering::Relaxed);
+    let guard = InFlightGuard(tenant.counters.clone());
+    metrics::increment_counter!("tgi_tenant_request_count", "tenant" => tenant.id.clone());
+
+    request.extensions_mut().insert(tenant.clone());
+    let span = tracing::info_span!("tenant", tenant = %tenant.id);
+    let context = Context::current().with_value(tenant.clone());
+    let response = next
+        .run(request)
+        .with_context(context)
+        .instrument(span)
+        .await;
+
+    let status = response.status();
+    if status.is_client_error() || status.is_server_error() {
+        tenant.counters.failures.fetch_add(1, Ordering::Relaxed);
+        metrics::increment_counter!("tgi_tenant_request_failure", "tenant" => tenant.id.clone(), "status" => status.as_str().to_string());
+    }
+
+    // The request is in flight until its body, streamed or not, has been sent
+    let (parts, mut body) = response.into_parts();
+    let body = async_stream::stream! {
+        let _guard = guard;
+        while let Some(chunk) = body.data().await {
+            yield chunk;
+        }
+    };
+    Response::from_parts(parts, axum::body::boxed(StreamBody::new(body)))
+}
+
+/// Tenant of the current request
+pub(crate) fn current() -> Option<Tenant> {
+    Context::current().get::<Tenant>().cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tenant_id() {
+        let headers = |headers: &[(&str, &str)]| {
+            let mut request = Request::builder();
+            for (name, value) in headers {
+                request = request.header(*name, *value);
+            }
+            request.body(()).unwrap().headers().clone()
+        };
+
+        let keys = TenantKeys::parse("# Team A\nsecret\n\n");
+        let proxy = Tenants::new(true, keys.clone());
+        let direct = Tenants::new(false, keys);
+
+        assert_eq!(proxy.tenant_id(&headers(&[])), None);
+        assert_eq!(
+            proxy.tenant_id(&headers(&[
+                ("x-tenant-id", "team-a"),
+                ("authorization", "k")
+            ])),
+            Some("team-a".to_string())
+        );
+        // Known API keys are fingerprinted
+        let key = proxy
+            .tenant_id(&headers(&[("authorization", "Bearer secret")]))
+            .unwrap();
+        assert_eq!(key, "key-bffde20413347b7a");
+        // The other keys are anonymous
+        assert_eq!(
+            proxy.tenant_id(&headers(&[("authorization", "Bearer other")])),
+            None
+        );
+        assert_eq!(
+            proxy.tenant_id(&headers(&[("authorization", "secret")])),
+            None
+        );
+        // The header is only trusted from a proxy
+        assert_eq!(
+            direct.tenant_id(&headers(&[
+                ("x-tenant-id", "team-a"),
+                ("authorization", "Bearer secret")
+            ])),
+            Some(key)
+        );
+        assert_eq!(
+            direct.tenant_id(&headers(&[("x-tenant-id", "team-a")])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_tenants_summary() {
+        let tenants = Tenants::default();
+        let tenant = tenants.get(Some("team-a".to_string()));
+        assert!(tenant.identified);
+        tenant.counters.requests.fetch_add(1, Ordering::Relaxed);
+        tenant.record_usage(10, 20);
+        // The same counters are shared by the requests of a tenant
+        tenants.get(Some("team-a".to_string())).record_usage(1, 2);
+        assert!(!tenants.get(None).identified);
+
+        assert_eq!(
+            tenants.summary(),
+            vec![
+                TenantSummary {
+                    tenant: "anonymous".to_string(),
+                    requests: 0,
+                    failures: 0,
+                    in_flight: 0,
+                    input_tokens: 0,
+                    generated_tokens: 0,
+                    estimated_cost: 0.0,
+                },
+                TenantSummary {
+                    tenant: "team-a".to_string(),
+                    requests: 1,
+                    failures: 0,
+                    in_flight: 0,
+                    input_tokens: 11,
+                    generated_tokens: 22,
+                    estimated_cost: 0.0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tenants_cardinality() {
+        let tenants = Tenants::default();
+        for i in 0..MAX_TENANTS {
+            tenants.get(Some(i.to_string()));
+        }
+        let other = tenants.get(Some("new".to_string()));
+        assert_eq!(other.id, "other");
+        // The tenants over the limit share the counters, not the limits of the identified tenants
+        assert!(!other.identified);
+        // Known tenants are still tracked
+        assert_eq!(tenants.get(Some("0".to_string())).id, "0");
+    }
+}
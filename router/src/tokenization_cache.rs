@@ -0,0 +1,179 @@
+/// Least recently used cache of the encodings of the repeated inputs, e.g. the same system
+/// prompt and few-shot examples sent by every request of an application
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use tokenizers::Encoding;
+
+/// Router memory held by a cached token: its id, type id, text, offsets and masks
+pub(crate) const TOKEN_BYTES: usize = 64;
+
+#[derive(Debug)]
+struct Entry {
+    /// Inputs and truncation of the request, compared on lookup: distinct inputs can share a key
+    inputs: String,
+    truncate: Option<usize>,
+    /// Encoding and inputs, truncated if requested
+    value: (Encoding, String),
+    bytes: usize,
+    /// Tick of the last use
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    entries: HashMap<u64, Entry>,
+    /// Keys by tick of their last use, the least recently used first
+    order: BTreeMap<u64, u64>,
+    tick: u64,
+    bytes: usize,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct TokenizationCache {
+    /// Maximum memory of the cached encodings, in bytes
+    max_bytes: usize,
+    lru: Arc<Mutex<Lru>>,
+}
+
+fn key(inputs: &str, truncate: Option<usize>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    inputs.hash(&mut hasher);
+    truncate.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl TokenizationCache {
+    pub(crate) fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            lru: Arc::new(Mutex::new(Lru::default())),
+        }
+    }
+
+    pub(crate) fn get(&self, inputs: &str, truncate: Option<usize>) -> Option<(Encoding, String)> {
+        let key = key(inputs, truncate);
+        let mut lru = self.lru.lock().unwrap();
+        lru.tick += 1;
+        let tick = lru.tick;
+        let Some(entry) = lru
+            .entries
+            .get_mut(&key)
+            .filter(|entry| entry.inputs == inputs && entry.truncate == truncate)
+        else {
+            metrics::increment_counter!("tgi_tokenization_cache_requests", "result" => "miss");
+            return None;
+        };
+        let used = std::mem::replace(&mut entry.used, tick);
+        let value = entry.value.clone();
+        lru.order.remove(&used);
+        lru.order.insert(tick, key);
+        metrics::increment_counter!("tgi_tokenization_cache_requests", "result" => "hit");
+        Some(value)
+    }
+
+    /// Cache the encoding of `inputs`, evicting the least recently used encodings over the
+    /// memory cap
+    pub(crate) fn insert(&self, inputs: &str, truncate: Option<usize>, value: (Encoding, String)) {
+        let bytes = inputs.len() + value.1.len() + value.0.len() * TOKEN_BYTES;
+        if bytes > self.max_bytes {
+            return;
+        }
+        let key = key(inputs, truncate);
+        let mut lru = self.lru.lock().unwrap();
+        lru.tick += 1;
+        let tick = lru.tick;
+        if let Some(previous) = lru.entries.insert(
+            key,
+            Entry {
+                inputs: inputs.to_string(),
+                truncate,
+                value,
+                bytes,
+                used: tick,
+            },
+        ) {
+            lru.order.remove(&previous.used);
+            lru.bytes -= previous.bytes;
+        }
+        lru.order.insert(tick, key);
+        lru.bytes += bytes;
+
+        while lru.bytes > self.max_bytes {
+            let Some((_, evicted)) = lru.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = lru.entries.remove(&evicted) {
+                lru.bytes -= entry.bytes;
+                metrics::increment_counter!("tgi_tokenization_cache_evictions");
+            }
+        }
+        metrics::gauge!("tgi_tokenization_cache_bytes", lru.bytes as f64);
+    }
+
+    /// Forget the encodings of the previous tokenizer
+    pub(crate) fn clear(&self) {
+        *self.lru.lock().unwrap() = Lru::default();
+        metrics::gauge!("tgi_tokenization_cache_bytes", 0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(tokens: usize) -> (Encoding, String) {
+        let encoding = Encoding::new(
+            vec![0; tokens],
+            vec![0; tokens],
+            vec![String::new(); tokens],
+            vec![None; tokens],
+            vec![(0, 0); tokens],
+            vec![0; tokens],
+            vec![1; tokens],
+            Vec::new(),
+            HashMap::new(),
+        );
+        (encoding, "inputs".to_string())
+    }
+
+    #[test]
+    fn test_tokenization_cache() {
+        // Room for two entries of 10 tokens
+        let entry_bytes = "a".len() + "inputs".len() + 10 * TOKEN_BYTES;
+        let cache = TokenizationCache::new(2 * entry_bytes);
+
+        cache.insert("a", None, value(10));
+        cache.insert("b", None, value(10));
+        assert_eq!(cache.get("a", None).unwrap().0.len(), 10);
+        // Another truncation is another encoding
+        assert!(cache.get("a", Some(5)).is_none());
+
+        // `b` is the least recently used
+        cache.insert("c", None, value(10));
+        assert!(cache.get("b", None).is_none());
+        assert!(cache.get("a", None).is_some());
+        assert!(cache.get("c", None).is_some());
+
+        // Larger than the whole cache
+        cache.insert("d", None, value(1000));
+        assert!(cache.get("d", None).is_none());
+        assert!(cache.get("a", None).is_some());
+
+        // Another input sharing the key of `a` is not served its encoding
+        let key_a = key("a", None);
+        cache
+            .lru
+            .lock()
+            .unwrap()
+            .entries
+            .get_mut(&key_a)
+            .unwrap()
+            .inputs = "e".to_string();
+        assert!(cache.get("a", None).is_none());
+
+        cache.clear();
+        assert!(cache.get("c", None).is_none());
+    }
+}
@@ -0,0 +1,182 @@
+/// Loading of the tokenizer and its config, at startup and on `/admin/tokenizer/reload`
+use crate::HubTokenizerConfig;
+use hf_hub::api::tokio::{Api, ApiError, ApiRepo};
+use hf_hub::{Repo, RepoType};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use tokenizers::Tokenizer;
+
+/// Location of the tokenizer files: a local model directory or a Hugging Face hub repository
+#[derive(Clone)]
+pub struct TokenizerSource {
+    tokenizer_name: String,
+    /// Revision of the hub repository
+    revision: Option<String>,
+    /// User specified path of the tokenizer config
+    tokenizer_config_path: Option<String>,
+    api: Option<Api>,
+}
+
+impl TokenizerSource {
+    pub fn new(
+        tokenizer_name: String,
+        revision: Option<String>,
+        tokenizer_config_path: Option<String>,
+        api: Option<Api>,
+    ) -> Self {
+        Self {
+            tokenizer_name,
+            revision,
+            tokenizer_config_path,
+            api,
+        }
+    }
+
+    /// Same source at another revision of the hub repository
+    pub(crate) fn with_revision(&self, revision: Option<String>) -> Self {
+        Self {
+            revision: revision.or_else(|| self.revision.clone()),
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn revision(&self) -> Option<&str> {
+        self.revision.as_deref()
+    }
+
+    fn local_path(&self) -> Option<&Path> {
+        let local_path = Path::new(&self.tokenizer_name);
+        (local_path.exists() && local_path.is_dir()).then_some(local_path)
+    }
+
+    fn api_repo(&self) -> Option<(&Api, ApiRepo)> {
+        self.api.as_ref().map(|api| {
+            let api_repo = api.repo(Repo::with_revision(
+                self.tokenizer_name.clone(),
+                RepoType::Model,
+                self.revision.clone().unwrap_or_else(|| "main".to_string()),
+            ));
+            (api, api_repo)
+        })
+    }
+
+    /// Load the tokenizer and the tokenizer config.
+    /// With `refresh`, the hub files are downloaded again instead of read from the cache.
+    pub async fn load(&self, refresh: bool) -> (Option<Tokenizer>, HubTokenizerConfig) {
+        // Load tokenizer
+        let tokenizer = if let Some(local_path) = self.local_path() {
+            tokenizer_from_file(local_path.join("tokenizer.json")).await
+        } else if let Some((api, api_repo)) = self.api_repo() {
+            match fetch(&api_repo, "tokenizer.json", refresh).await {
+                Ok(tokenizer_filename) => tokenizer_from_file(tokenizer_filename).await,
+                Err(_) => get_base_tokenizer(api, &api_repo).await,
+            }
+        } else {
+            None
+        };
+
+        // Load tokenizer config if found locally, or check if we can get it from the API if needed
+        let tokenizer_config = if let Some(path) = &self.tokenizer_config_path {
+            tracing::info!("Using local tokenizer config from user specified path");
+            config_from_file(PathBuf::from(path)).await
+        } else if let Some(local_path) = self.local_path() {
+            tracing::info!("Using local tokenizer config");
+            config_from_file(local_path.join("tokenizer_config.json")).await
+        } else {
+            match self.api_repo() {
+                Some((_, api_repo)) => {
+                    tracing::info!("Using the Hugging Face API to retrieve tokenizer config");
+                    get_tokenizer_config(&api_repo, refresh)
+                        .await
+                        .unwrap_or_else(|| {
+                            tracing::warn!(
+                                "Could not retrieve tokenizer config from the Hugging Face hub."
+                            );
+                            HubTokenizerConfig::default()
+                        })
+                }
+                None => {
+                    tracing::warn!("Could not find tokenizer config locally and no API specified");
+                    HubTokenizerConfig::default()
+                }
+            }
+        };
+
+        (tokenizer, tokenizer_config)
+    }
+}
+
+/// Parse a tokenizer file on the blocking threads: large vocabularies take a while
+async fn tokenizer_from_file(filename: PathBuf) -> Option<Tokenizer> {
+    tokio::task::spawn_blocking(move || Tokenizer::from_file(filename).ok())
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Read a tokenizer config file on the blocking threads
+async fn config_from_file(filename: PathBuf) -> HubTokenizerConfig {
+    tokio::task::spawn_blocking(move || HubTokenizerConfig::from_file(&filename))
+        .await
+        .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+}
+
+/// Get a file of the repository, from the cache unless `refresh` is set
+async fn fetch(api_repo: &ApiRepo, filename: &str, refresh: bool) -> Result<PathBuf, ApiError> {
+    match refresh {
+        true => api_repo.download(filename).await,
+        false => api_repo.get(filename).await,
+    }
+}
+
+/// get base tokenizer
+async fn get_base_tokenizer(api: &Api, api_repo: &ApiRepo) -> Option<Tokenizer> {
+    let config_filename = api_repo.get("config.json").await.ok()?;
+
+    // Open the file in read-only mode with buffer.
+    let file = File::open(config_filename).ok()?;
+    let reader = BufReader::new(file);
+
+    // Read the JSON contents of the file as an instance of `User`.
+    let config: serde_json::Value = serde_json::from_reader(reader).ok()?;
+
+    if let Some(serde_json::Value::String(base_model_id)) = config.get("base_model_name_or_path") {
+        let api_base_repo = api.repo(Repo::with_revision(
+            base_model_id.to_string(),
+            RepoType::Model,
+            "main".to_string(),
+        ));
+
+        let tokenizer_filename = api_base_repo.get("tokenizer.json").await.ok()?;
+        tokenizer_from_file(tokenizer_filename).await
+    } else {
+        None
+    }
+}
+
+/// get tokenizer_config from the Huggingface Hub
+async fn get_tokenizer_config(api_repo: &ApiRepo, refresh: bool) -> Option<HubTokenizerConfig> {
+    let tokenizer_config_filename = fetch(api_repo, "tokenizer_config.json", refresh)
+        .await
+        .ok()?;
+
+    tokio::task::spawn_blocking(move || {
+        // Open the file in read-only mode with buffer.
+        let file = File::open(tokenizer_config_filename).ok()?;
+        let reader = BufReader::new(file);
+
+        // Read the JSON contents of the file as an instance of 'HubTokenizerConfig'.
+        let tokenizer_config: HubTokenizerConfig = serde_json::from_reader(reader)
+            .map_err(|e| {
+                tracing::warn!("Unable to parse tokenizer config: {}", e);
+                e
+            })
+            .ok()?;
+
+        Some(tokenizer_config)
+    })
+    .await
+    .ok()
+    .flatten()
+}
@@ -0,0 +1,140 @@
+/// Incremental parsing of the streamed tool calls: the arguments of the generated
+/// `{"function": {"_name": ..., <arguments>}}` object are emitted, keyed by name, as soon as their
+/// value is complete, so that the clients can render them before the end of the call
+use serde_json::{Map, Value};
+
+#[derive(Debug)]
+enum Phase {
+    /// Waiting for the key of the next member
+    Key,
+    /// Scanning a member value, starting at the given byte offset
+    Value(usize),
+}
+
+/// Object or array being scanned
+#[derive(Debug)]
+struct Frame {
+    object: bool,
+    /// Key of the member being scanned
+    key: Option<String>,
+    phase: Phase,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ToolArgumentsStream {
+    text: String,
+    frames: Vec<Frame>,
+    /// Start of the string being scanned
+    string: Option<usize>,
+    escape: bool,
+}
+
+impl ToolArgumentsStream {
+    /// Add the text of a streamed tool call, returning the arguments it completes
+    pub(crate) fn push(&mut self, text: &str) -> Map<String, Value> {
+        let start = self.text.len();
+        self.text.push_str(text);
+        let mut arguments = Map::new();
+        for (index, c) in text.char_indices() {
+            let index = start + index;
+            if let Some(string) = self.string {
+                match (self.escape, c) {
+                    (true, _) => self.escape = false,
+                    (false, '\\') => self.escape = true,
+                    (false, '"') => {
+                        self.string = None;
+                        self.end_string(string, index);
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            match c {
+                '"' => self.string = Some(index),
+                '{' | '[' => self.frames.push(Frame {
+                    object: c == '{',
+                    key: None,
+                    phase: Phase::Key,
+                }),
+                '}' | ']' => {
+                    self.end_argument(index, &mut arguments);
+                    self.frames.pop();
+                }
+                ':' => {
+                    if let Some(frame) = self.frames.last_mut() {
+                        frame.phase = Phase::Value(index + 1);
+                    }
+                }
+                ',' => {
+                    self.end_argument(index, &mut arguments);
+                    if let Some(frame) = self.frames.last_mut() {
+                        frame.phase = Phase::Key;
+                    }
+                }
+                _ => {}
+            }
+        }
+        arguments
+    }
+
+    /// The keys are the strings of the objects scanned before a `:`
+    fn end_string(&mut self, start: usize, end: usize) {
+        let key = &self.text[start..=end];
+        if let Some(frame) = self.frames.last_mut() {
+            if frame.object && matches!(frame.phase, Phase::Key) {
+                frame.key = serde_json::from_str(key).ok();
+            }
+        }
+    }
+
+    /// Emit the member of the `function` object ending at `end`, if any
+    fn end_argument(&self, end: usize, arguments: &mut Map<String, Value>) {
+        let [root, function] = self.frames.as_slice() else {
+            return;
+        };
+        if root.key.as_deref() != Some("function") || !function.object {
+            return;
+        }
+        let (Some(key), Phase::Value(start)) = (&function.key, &function.phase) else {
+            return;
+        };
+        // The name of the tool is not an argument
+        if key == "_name" {
+            return;
+        }
+        if let Ok(value) = serde_json::from_str(self.text[*start..end].trim()) {
+            arguments.insert(key.clone(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_tool_arguments_stream() {
+        let generated = r#"{"function": {"_name": "get_weather", "location": "Paris, \"FR\"", "days": [1, {"x": 2}], "unit": "celsius"}}"#;
+        for size in [1, 4, 9] {
+            let mut stream = ToolArgumentsStream::default();
+            let chars: Vec<char> = generated.chars().collect();
+            let mut arguments = Map::new();
+            for chunk in chars.chunks(size) {
+                arguments.extend(stream.push(&chunk.iter().collect::<String>()));
+            }
+            assert_eq!(
+                Value::Object(arguments),
+                json!({"location": "Paris, \"FR\"", "days": [1, {"x": 2}], "unit": "celsius"})
+            );
+        }
+
+        // Each argument is emitted with the text completing it
+        let mut stream = ToolArgumentsStream::default();
+        assert!(stream
+            .push(r#"{"function": {"_name": "add", "a": 1"#)
+            .is_empty());
+        assert_eq!(Value::Object(stream.push(r#", "b": 2"#)), json!({"a": 1}));
+        assert_eq!(Value::Object(stream.push("}}")), json!({"b": 2}));
+    }
+}
@@ -0,0 +1,237 @@
+/// `auto` tool choice: the model either calls one of the tools or answers in text through the
+/// `no_tool` pseudo tool added to the grammar
+use crate::{FunctionDefinition, Tool};
+use serde_json::{json, Value};
+
+/// Name of the pseudo tool holding the text answers
+const NO_TOOL: &str = "no_tool";
+
+/// Beginning of the generated JSON when the model answers in text, ignoring the whitespace.
+/// The properties of the grammar are sorted: `_name` comes before `content`.
+const NO_TOOL_PREFIX: &str = r#"{"function":{"_name":"no_tool","content":""#;
+
+/// Pseudo tool chosen by the model to answer in text
+pub(crate) fn no_tool() -> Tool {
+    Tool {
+        r#type: "function".to_string(),
+        function: FunctionDefinition {
+            description: Some(
+                "Answer in text when none of the other tools is relevant".to_string(),
+            ),
+            name: NO_TOOL.to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "_name": {"type": "string", "const": NO_TOOL},
+                    "content": {
+                        "type": "string",
+                        "description": "The answer to the user"
+                    }
+                },
+                "required": ["_name", "content"]
+            }),
+        },
+    }
+}
+
+/// Text answer of a generated tool call, if the model chose the `no_tool` pseudo tool
+pub(crate) fn text_answer(generated: &Value) -> Option<String> {
+    let function = generated.get("function")?;
+    if function.get("_name")?.as_str()? != NO_TOOL {
+        return None;
+    }
+    function.get("content")?.as_str().map(str::to_string)
+}
+
+#[derive(Debug)]
+enum State {
+    /// The generated text could still be a `no_tool` call
+    Undecided(String),
+    /// The model calls a tool: the generated text is streamed as is
+    Tool,
+    /// The model answers in text: the `content` string is unescaped
+    Text { escape: Option<String> },
+    /// End of the text answer
+    Done,
+}
+
+/// Output of the stream for a generated token
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct AutoToolDelta {
+    pub content: Option<String>,
+    pub tool_call: Option<String>,
+}
+
+/// Incremental detection of the text answers in the streamed tool calls
+#[derive(Debug)]
+pub(crate) struct AutoToolStream {
+    state: State,
+}
+
+impl Default for AutoToolStream {
+    fn default() -> Self {
+        Self {
+            state: State::Undecided(String::new()),
+        }
+    }
+}
+
+impl AutoToolStream {
+    /// Add the text of a generated token
+    pub(crate) fn push(&mut self, text: &str) -> AutoToolDelta {
+        match &mut self.state {
+            State::Undecided(buffer) => {
+                buffer.push_str(text);
+                let buffer = std::mem::take(buffer);
+                match match_prefix(&buffer) {
+                    Prefix::Partial => {
+                        self.state = State::Undecided(buffer);
+                        AutoToolDelta::default()
+                    }
+                    Prefix::Mismatch => {
+                        self.state = State::Tool;
+                        AutoToolDelta {
+                            content: None,
+                            tool_call: Some(buffer),
+                        }
+                    }
+                    Prefix::Match(end) => {
+                        self.state = State::Text { escape: None };
+                        self.unescape(&buffer[end..])
+                    }
+                }
+            }
+            State::Tool => AutoToolDelta {
+                content: None,
+                tool_call: Some(text.to_string()),
+            },
+            State::Text { .. } => self.unescape(text),
+            State::Done => AutoToolDelta::default(),
+        }
+    }
+
+    /// End of the stream: the text still buffered is a tool call
+    pub(crate) fn finish(&mut self) -> Option<String> {
+        match std::mem::replace(&mut self.state, State::Done) {
+            State::Undecided(buffer) if !buffer.is_empty() => Some(buffer),
+            _ => None,
+        }
+    }
+
+    /// Unescape the JSON string of the text answer until its closing quote
+    fn unescape(&mut self, text: &str) -> AutoToolDelta {
+        let mut content = String::new();
+        for c in text.chars() {
+            let State::Text { escape } = &mut self.state else {
+                break;
+            };
+            match escape {
+                None => match c {
+                    '\\' => *escape = Some(String::new()),
+                    '"' => self.state = State::Done,
+                    c => content.push(c),
+                },
+                Some(sequence) => {
+                    sequence.push(c);
+                    let decoded = match sequence.as_str() {
+                        "n" => Some('\n'),
+                        "t" => Some('\t'),
+                        "r" => Some('\r'),
+                        "b" => Some('\u{8}'),
+                        "f" => Some('\u{c}'),
+                        "u" | "u0" | "u00" | "u000" => continue,
+                        s if s.starts_with('u') => u32::from_str_radix(&s[1..], 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .or(Some(char::REPLACEMENT_CHARACTER)),
+                        // `\"`, `\\` and `\/`
+                        s => s.chars().next(),
+                    };
+                    content.extend(decoded);
+                    *escape = None;
+                }
+            }
+        }
+        AutoToolDelta {
+            content: Some(content).filter(|content| !content.is_empty()),
+            tool_call: None,
+        }
+    }
+}
+
+enum Prefix {
+    /// The text is a prefix of `NO_TOOL_PREFIX`
+    Partial,
+    /// The text starts with `NO_TOOL_PREFIX`, which ends at the given byte offset
+    Match(usize),
+    Mismatch,
+}
+
+fn match_prefix(text: &str) -> Prefix {
+    let mut expected = NO_TOOL_PREFIX.chars().peekable();
+    for (offset, c) in text.char_indices() {
+        let Some(&next) = expected.peek() else {
+            return Prefix::Match(offset);
+        };
+        if c == next {
+            expected.next();
+        } else if !c.is_whitespace() {
+            return Prefix::Mismatch;
+        }
+    }
+    match expected.peek() {
+        Some(_) => Prefix::Partial,
+        None => Prefix::Match(text.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(tokens: &[&str]) -> (String, String) {
+        let mut stream = AutoToolStream::default();
+        let (mut content, mut tool_call) = (String::new(), String::new());
+        for token in tokens {
+            let delta = stream.push(token);
+            content.extend(delta.content);
+            tool_call.extend(delta.tool_call);
+        }
+        tool_call.extend(stream.finish());
+        (content, tool_call)
+    }
+
+    #[test]
+    fn test_auto_tool_stream() {
+        // Text answer, with escapes split across the tokens
+        let (content, tool_call) = stream(&[
+            "{\"function\": {",
+            "\"_name\": \"no_",
+            "tool\", \"content\": \"Hi",
+            " \\\"you\\",
+            "\"\\u00",
+            "e9\\n\"}}",
+        ]);
+        assert_eq!(content, "Hi \"you\"é\n");
+        assert!(tool_call.is_empty());
+
+        // Tool call streamed as is once it diverges from `no_tool`
+        let tokens = ["{\"function\": {", "\"_name\": \"get", "_weather\"}}"];
+        let (content, tool_call) = stream(&tokens);
+        assert!(content.is_empty());
+        assert_eq!(tool_call, tokens.concat());
+
+        // Stream ending before the decision
+        let (content, tool_call) = stream(&["{\"function\""]);
+        assert!(content.is_empty());
+        assert_eq!(tool_call, "{\"function\"");
+    }
+
+    #[test]
+    fn test_text_answer() {
+        let generated = json!({"function": {"_name": "no_tool", "content": "Hello"}});
+        assert_eq!(text_answer(&generated), Some("Hello".to_string()));
+        let generated = json!({"function": {"location": "Paris"}});
+        assert_eq!(text_answer(&generated), None);
+    }
+}
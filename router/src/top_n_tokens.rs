@@ -0,0 +1,127 @@
+/// Caps of the `top_n_tokens` of each endpoint and of the log probabilities returned by a request
+use crate::validation::ValidationError;
+use opentelemetry::trace::{FutureExt, WithContext};
+use opentelemetry::Context;
+use std::future::Future;
+
+/// Endpoints with their own `top_n_tokens` cap, stored in the current OpenTelemetry context
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Endpoint {
+    /// `top_n_tokens` of `/generate`, `/generate_stream` and their compatible routes
+    Generate,
+    /// `top_logprobs` of `/v1/chat/completions`
+    ChatCompletions,
+    /// `logprobs` of `/v1/completions`
+    Completions,
+}
+
+/// Run `future` with the `top_n_tokens` cap of `endpoint`
+pub(crate) fn on_endpoint<F: Future>(endpoint: Endpoint, future: F) -> WithContext<F> {
+    future.with_context(Context::current_with_value(endpoint))
+}
+
+/// Endpoint of the current request, `/generate` unless set by the handler
+fn current_endpoint() -> Endpoint {
+    Context::current()
+        .get::<Endpoint>()
+        .copied()
+        .unwrap_or(Endpoint::Generate)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TopNTokensLimits {
+    /// Maximum `top_n_tokens` of each endpoint
+    pub generate: u32,
+    pub chat_completions: u32,
+    pub completions: u32,
+    /// Maximum `top_n_tokens` × `max_new_tokens` of a request
+    pub max_logprob_payload: Option<u32>,
+}
+
+impl TopNTokensLimits {
+    /// Same cap on every endpoint and no payload budget
+    pub(crate) fn uniform(max_top_n_tokens: u32) -> Self {
+        Self {
+            generate: max_top_n_tokens,
+            chat_completions: max_top_n_tokens,
+            completions: max_top_n_tokens,
+            max_logprob_payload: None,
+        }
+    }
+
+    fn cap(&self, endpoint: Endpoint) -> u32 {
+        match endpoint {
+            Endpoint::Generate => self.generate,
+            Endpoint::ChatCompletions => self.chat_completions,
+            Endpoint::Completions => self.completions,
+        }
+    }
+
+    /// Validate the `top_n_tokens` of a request against the cap of the current endpoint
+    pub(crate) fn validate(&self, top_n_tokens: Option<u32>) -> Result<u32, ValidationError> {
+        let top_n_tokens = top_n_tokens.unwrap_or(0);
+        let cap = self.cap(current_endpoint());
+        if top_n_tokens > cap {
+            return Err(ValidationError::TopNTokens(cap, top_n_tokens));
+        }
+        Ok(top_n_tokens)
+    }
+
+    /// Reject the requests whose top tokens would exceed the log probability payload budget
+    pub(crate) fn check_payload(
+        &self,
+        top_n_tokens: u32,
+        max_new_tokens: u32,
+    ) -> Result<(), ValidationError> {
+        let Some(budget) = self.max_logprob_payload else {
+            return Ok(());
+        };
+        let payload = top_n_tokens as u64 * max_new_tokens as u64;
+        if payload > budget as u64 {
+            metrics::increment_counter!("tgi_request_logprob_payload_rejected");
+            return Err(ValidationError::LogprobPayload(
+                budget,
+                top_n_tokens,
+                max_new_tokens,
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_top_n_tokens_limits() {
+        let limits = TopNTokensLimits {
+            generate: 10,
+            chat_completions: 5,
+            completions: 2,
+            max_logprob_payload: Some(100),
+        };
+
+        assert_eq!(limits.validate(None).unwrap(), 0);
+        assert_eq!(limits.validate(Some(10)).unwrap(), 10);
+        {
+            let _guard = Context::current_with_value(Endpoint::ChatCompletions).attach();
+            assert!(matches!(
+                limits.validate(Some(10)),
+                Err(ValidationError::TopNTokens(5, 10))
+            ));
+        }
+        {
+            let _guard = Context::current_with_value(Endpoint::Completions).attach();
+            assert_eq!(limits.validate(Some(2)).unwrap(), 2);
+            assert!(limits.validate(Some(3)).is_err());
+        }
+
+        assert!(limits.check_payload(10, 10).is_ok());
+        assert!(matches!(
+            limits.check_payload(10, 11),
+            Err(ValidationError::LogprobPayload(100, 10, 11))
+        ));
+        assert!(TopNTokensLimits::uniform(5).check_payload(5, 1000).is_ok());
+    }
+}
@@ -0,0 +1,142 @@
+/// Sampling of the OpenTelemetry traces: a ratio of the traces, per route if needed, rather than
+/// every request. The shards follow the decision propagated in the trace context
+use opentelemetry::sdk::trace::{Sampler, ShouldSample};
+use opentelemetry::trace::{Link, SamplingResult, SpanKind, TraceContextExt, TraceId};
+use opentelemetry::{Context, KeyValue};
+
+#[derive(Clone, Debug)]
+pub struct TraceSampler {
+    /// Whether the decision of a remote parent, e.g. the `traceparent` of an upstream gateway,
+    /// is followed
+    parent_based: bool,
+    ratio: Sampler,
+    /// Ratios of the routes sampled apart, e.g. `/generate_stream`
+    routes: Vec<(String, Sampler)>,
+    follow_parent: Sampler,
+}
+
+fn ratio_sampler(ratio: f64) -> Result<Sampler, String> {
+    if !(0.0..=1.0).contains(&ratio) {
+        return Err(format!(
+            "sample ratio must be >= 0 and <= 1. Given: {ratio}"
+        ));
+    }
+    Ok(Sampler::TraceIdRatioBased(ratio))
+}
+
+impl TraceSampler {
+    /// Sample `ratio` of the traces, except the routes of `route_ratios` given as `ROUTE=RATIO`
+    pub fn new(ratio: f64, parent_based: bool, route_ratios: &[String]) -> Result<Self, String> {
+        let routes = route_ratios
+            .iter()
+            .map(|route_ratio| {
+                let (route, ratio) = route_ratio
+                    .split_once('=')
+                    .filter(|(route, _)| route.starts_with('/'))
+                    .ok_or_else(|| format!("expected `ROUTE=RATIO`. Given: `{route_ratio}`"))?;
+                let ratio = ratio
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("invalid sample ratio of `{route}`: `{ratio}`"))?;
+                Ok((route.trim().to_string(), ratio_sampler(ratio)?))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            parent_based,
+            ratio: ratio_sampler(ratio)?,
+            routes,
+            follow_parent: Sampler::ParentBased(Box::new(Sampler::AlwaysOn)),
+        })
+    }
+
+    /// Sampler of the root span of a request. The HTTP spans are named `METHOD ROUTE` and carry
+    /// the matched route
+    fn root_sampler(&self, name: &str, attributes: &[KeyValue]) -> &Sampler {
+        let route = attributes
+            .iter()
+            .find(|attribute| attribute.key.as_str() == "http.route")
+            .map(|attribute| attribute.value.as_str());
+        let name_route = name.rsplit(' ').next();
+        self.routes
+            .iter()
+            .find(|(path, _)| {
+                route.as_deref() == Some(path.as_str()) || name_route == Some(path.as_str())
+            })
+            .map_or(&self.ratio, |(_, sampler)| sampler)
+    }
+}
+
+impl ShouldSample for TraceSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &[KeyValue],
+        links: &[Link],
+    ) -> SamplingResult {
+        let remote_parent = parent_context
+            .filter(|context| context.has_active_span())
+            .map(|context| context.span().span_context().is_remote());
+        let sampler = match remote_parent {
+            // The spans of a request are all sampled or none of them
+            Some(false) => &self.follow_parent,
+            Some(true) if self.parent_based => &self.follow_parent,
+            _ => self.root_sampler(name, attributes),
+        };
+        sampler.should_sample(parent_context, trace_id, name, span_kind, attributes, links)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::SamplingDecision;
+
+    fn decision(sampler: &TraceSampler, name: &str, attributes: &[KeyValue]) -> SamplingDecision {
+        sampler
+            .should_sample(
+                None,
+                TraceId::from_u128(42),
+                name,
+                &SpanKind::Server,
+                attributes,
+                &[],
+            )
+            .decision
+    }
+
+    #[test]
+    fn test_trace_sampler() {
+        let sampler = TraceSampler::new(
+            1.0,
+            false,
+            &[
+                "/generate_stream=0".to_string(),
+                "/v1/chat/completions=0.0".to_string(),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            decision(&sampler, "POST /generate", &[]),
+            SamplingDecision::RecordAndSample
+        );
+        assert_eq!(
+            decision(&sampler, "POST /generate_stream", &[]),
+            SamplingDecision::Drop
+        );
+        assert_eq!(
+            decision(
+                &sampler,
+                "HTTP request",
+                &[KeyValue::new("http.route", "/v1/chat/completions")]
+            ),
+            SamplingDecision::Drop
+        );
+
+        assert!(TraceSampler::new(1.5, false, &[]).is_err());
+        assert!(TraceSampler::new(1.0, false, &["generate=0.1".to_string()]).is_err());
+        assert!(TraceSampler::new(1.0, false, &["/generate=high".to_string()]).is_err());
+    }
+}
@@ -1,45 +1,118 @@
 /// Payload validation logic
+use crate::adapters::{AdapterLease, AdapterLeases};
+use crate::attention_window::AttentionWindow;
+use crate::deadline;
+use crate::detokenizer::IncrementalDetokenizer;
+use crate::normalization;
+use crate::shadow_tokenizer::ShadowTokenizer;
+use crate::template_cache::{self, Prefix, TemplateCache};
+use crate::tokenization_cache::TokenizationCache;
+use crate::top_n_tokens::TopNTokensLimits;
 use crate::validation::ValidationError::{BestOfSampling, BestOfSeed, EmptyInput};
-use crate::{GenerateParameters, GenerateRequest, GrammarType};
+use crate::{
+    AdapterParameters, ChatInputTokens, GenerateParameters, GenerateRequest, GrammarType,
+    NormalizationReport, QualityOfService, TemperatureStep,
+};
 use jsonschema::{Draft, JSONSchema};
+use opentelemetry::trace::{FutureExt, WithContext};
+use opentelemetry::Context;
 use rand::{thread_rng, Rng};
 use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
 use text_generation_client::{
-    GrammarType as ProtoGrammarType, NextTokenChooserParameters, StoppingCriteriaParameters,
+    AdapterParameters as ProtoAdapterParameters, GrammarType as ProtoGrammarType,
+    NextTokenChooserParameters, StoppingCriteriaParameters, TemperatureSchedulePoint,
 };
 use thiserror::Error;
 use tokenizers::tokenizer::Tokenizer;
 use tokenizers::TruncationDirection;
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
+use tokio::time::Instant;
 use tracing::{instrument, Span};
 
+/// Router memory held by a response token: the `Token` struct, its text and its JSON
+/// serialization
+const TOKEN_MEMORY_BYTES: usize = 128;
+const MB: usize = 1024 * 1024;
+/// Maximum number of points of a temperature schedule
+const MAX_TEMPERATURE_SCHEDULE_POINTS: usize = 16;
+/// Maximum size (in bytes) of the JSON encoded vendor extension parameters of a request
+const MAX_EXTENSIONS_SIZE: usize = 16 * 1024;
+/// Steps over which the probability mass of the end of sequence token is summed
+const DEFAULT_EOS_PROBABILITY_WINDOW: u32 = 4;
+const MAX_EOS_PROBABILITY_WINDOW: u32 = 64;
+
+/// Limits protecting the serialization path and the shards FSM compilation from pathological
+/// grammars
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct GrammarLimits {
+    /// Maximum size (in bytes) of the serialized grammar
+    pub max_size: usize,
+    /// Maximum size (in bytes) of the compiled program of a `regex` grammar
+    pub max_regex_size: usize,
+    /// Maximum nesting depth of a `json` grammar
+    pub max_schema_depth: usize,
+}
+
+impl Default for GrammarLimits {
+    fn default() -> Self {
+        Self {
+            max_size: 256 * 1024,
+            max_regex_size: MB,
+            max_schema_depth: 32,
+        }
+    }
+}
+
 /// Validation
 #[derive(Debug, Clone)]
 pub struct Validation {
     /// Validation parameters
     max_best_of: usize,
+    max_samples: usize,
     max_stop_sequences: usize,
-    max_top_n_tokens: u32,
+    top_n_tokens_limits: TopNTokensLimits,
     max_input_length: usize,
     max_total_tokens: usize,
     disable_grammar_support: bool,
+    /// Maximum router memory (in MB) the tokens of a response may hold
+    max_request_memory_mb: Option<usize>,
+    /// Token ids never sampled
+    suppressed_tokens: Vec<u32>,
+    grammar_limits: GrammarLimits,
+    /// Tokenizer compared with the served tokenizer on a sample of the requests
+    shadow_tokenizer: Option<ShadowTokenizer>,
+    /// Encodings of the repeated inputs
+    tokenization_cache: Option<TokenizationCache>,
+    /// Rendered and tokenized conversations, continued by their next turns
+    template_cache: Option<TemplateCache>,
+    /// Sliding attention window of the model, checked against the chat system prompts
+    attention_window: Option<AttentionWindow>,
+    /// LoRA adapters loaded by the shards, updated on the loads and evictions at runtime
+    adapters: Arc<RwLock<Vec<String>>>,
+    /// Requests in flight of each adapter, the adapters in use are not evicted
+    adapter_leases: AdapterLeases,
+    /// Number of tokenization workers
+    workers: usize,
+    /// Tokenizer and its workers, swapped on tokenizer reloads
+    tokenization: Arc<RwLock<Tokenization>>,
+}
+
+#[derive(Debug)]
+struct Tokenization {
     /// Channel to communicate with the background tokenization task
     sender: Option<mpsc::UnboundedSender<TokenizerRequest>>,
+    /// Tokenizer used to detokenize the streamed tokens
+    tokenizer: Option<Arc<Tokenizer>>,
 }
 
-impl Validation {
-    #[allow(clippy::too_many_arguments)]
-    pub(crate) fn new(
-        workers: usize,
-        tokenizer: Option<Tokenizer>,
-        max_best_of: usize,
-        max_stop_sequences: usize,
-        max_top_n_tokens: u32,
-        max_input_length: usize,
-        max_total_tokens: usize,
-        disable_grammar_support: bool,
-    ) -> Self {
+impl Tokenization {
+    fn new(workers: usize, tokenizer: Option<Tokenizer>) -> Self {
+        let detokenizer_tokenizer = tokenizer.clone().map(Arc::new);
+
         // If we have a fast tokenizer
         let sender = if let Some(tokenizer) = tokenizer {
             // Create round robin channel
@@ -67,13 +140,130 @@ impl Validation {
         };
 
         Self {
-            max_best_of,
             sender,
+            tokenizer: detokenizer_tokenizer,
+        }
+    }
+}
+
+impl Validation {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        workers: usize,
+        tokenizer: Option<Tokenizer>,
+        max_best_of: usize,
+        max_samples: usize,
+        max_stop_sequences: usize,
+        top_n_tokens_limits: TopNTokensLimits,
+        max_input_length: usize,
+        max_total_tokens: usize,
+        disable_grammar_support: bool,
+        max_request_memory_mb: Option<usize>,
+        suppressed_tokens: Vec<u32>,
+        grammar_limits: GrammarLimits,
+    ) -> Self {
+        Self {
+            max_best_of,
+            max_samples,
             max_stop_sequences,
-            max_top_n_tokens,
+            top_n_tokens_limits,
             max_input_length,
             max_total_tokens,
             disable_grammar_support,
+            max_request_memory_mb,
+            suppressed_tokens,
+            grammar_limits,
+            shadow_tokenizer: None,
+            tokenization_cache: None,
+            template_cache: None,
+            attention_window: None,
+            adapters: Arc::new(RwLock::new(Vec::new())),
+            adapter_leases: AdapterLeases::default(),
+            workers,
+            tokenization: Arc::new(RwLock::new(Tokenization::new(workers, tokenizer))),
+        }
+    }
+
+    pub(crate) fn with_shadow_tokenizer(
+        mut self,
+        shadow_tokenizer: Option<ShadowTokenizer>,
+    ) -> Self {
+        self.shadow_tokenizer = shadow_tokenizer;
+        self
+    }
+
+    pub(crate) fn with_tokenization_cache(
+        mut self,
+        tokenization_cache: Option<TokenizationCache>,
+    ) -> Self {
+        self.tokenization_cache = tokenization_cache;
+        self
+    }
+
+    pub(crate) fn with_template_cache(mut self, template_cache: Option<TemplateCache>) -> Self {
+        self.template_cache = template_cache;
+        self
+    }
+
+    pub(crate) fn with_attention_window(
+        mut self,
+        attention_window: Option<AttentionWindow>,
+    ) -> Self {
+        self.attention_window = attention_window;
+        self
+    }
+
+    pub(crate) fn with_adapters(self, adapters: Vec<String>) -> Self {
+        self.set_adapters(adapters);
+        self
+    }
+
+    /// LoRA adapters accepted in the `adapter_id` of the requests
+    pub(crate) fn adapters(&self) -> Vec<String> {
+        self.adapters.read().unwrap().clone()
+    }
+
+    pub(crate) fn set_adapters(&self, adapters: Vec<String>) {
+        *self.adapters.write().unwrap() = adapters;
+    }
+
+    /// Stop accepting an adapter, unless requests in flight still use it: their number is
+    /// returned. The leases are acquired under the read lock of the adapters
+    pub(crate) fn remove_adapter(&self, adapter_id: &str) -> Result<(), usize> {
+        let mut adapters = self.adapters.write().unwrap();
+        match self.adapter_leases.in_flight(adapter_id) {
+            0 => {
+                adapters.retain(|loaded| loaded != adapter_id);
+                Ok(())
+            }
+            in_flight => Err(in_flight),
+        }
+    }
+
+    /// Incremental detokenizer for the streamed tokens, if we have a fast tokenizer
+    pub(crate) fn detokenizer(&self) -> Option<IncrementalDetokenizer> {
+        self.tokenizer().map(IncrementalDetokenizer::new)
+    }
+
+    /// Fast tokenizer, if we have one
+    pub(crate) fn tokenizer(&self) -> Option<Arc<Tokenizer>> {
+        self.tokenization.read().unwrap().tokenizer.clone()
+    }
+
+    pub(crate) fn grammar_support(&self) -> bool {
+        !self.disable_grammar_support
+    }
+
+    /// Swap the tokenizer. The requests already sent to the previous workers are still
+    /// tokenized, after which the previous workers stop.
+    pub(crate) fn reload_tokenizer(&self, tokenizer: Option<Tokenizer>) {
+        let tokenization = Tokenization::new(self.workers, tokenizer);
+        *self.tokenization.write().unwrap() = tokenization;
+        if let Some(tokenization_cache) = &self.tokenization_cache {
+            tokenization_cache.clear();
+        }
+        if let Some(template_cache) = &self.template_cache {
+            template_cache.clear();
         }
     }
 
@@ -84,18 +274,59 @@ impl Validation {
         truncate: Option<usize>,
     ) -> Result<Option<(tokenizers::Encoding, String)>, ValidationError> {
         // If we have a fast tokenizer
-        if let Some(sender) = &self.sender {
+        let sender = self.tokenization.read().unwrap().sender.clone();
+        if let Some(sender) = sender {
+            // Skip the tokenization of the repeated inputs
+            let cached = self
+                .tokenization_cache
+                .as_ref()
+                .and_then(|tokenization_cache| tokenization_cache.get(&inputs, truncate));
+            if let Some(encoding) = cached {
+                return Ok(Some(encoding));
+            }
+            let cache_inputs = self.tokenization_cache.as_ref().map(|_| inputs.clone());
+            // Only tokenize the new messages of a conversation
+            let conversation = self
+                .template_cache
+                .as_ref()
+                .and_then(|_| template_cache::current());
+            let prefix = match (&self.template_cache, &conversation) {
+                (Some(template_cache), Some(conversation)) => {
+                    template_cache.get(conversation, &inputs)
+                }
+                _ => None,
+            };
+            let cut_prefix = conversation.is_some();
+
             // Create response channel
             let (response_sender, response_receiver) = oneshot::channel();
             // Send request to the background validation task
             // Unwrap is safe here
             sender
-                .send(((inputs, truncate), response_sender, Span::current()))
+                .send((
+                    (inputs, truncate, prefix, cut_prefix),
+                    response_sender,
+                    Span::current(),
+                ))
                 .unwrap();
 
             // Await on response channel
             // Unwrap is safe here
-            let encoding = response_receiver.await.unwrap()?;
+            let (encoding, inputs, next_prefix) = response_receiver.await.unwrap()?;
+            let encoding = (encoding, inputs);
+            if let (Some(tokenization_cache), Some(inputs)) =
+                (&self.tokenization_cache, cache_inputs)
+            {
+                tokenization_cache.insert(&inputs, truncate, encoding.clone());
+            }
+            if let (Some(template_cache), Some(conversation), Some((prefix, consistent))) =
+                (&self.template_cache, &conversation, next_prefix)
+            {
+                match consistent {
+                    true => template_cache.insert(conversation, prefix),
+                    false => template_cache.disable(),
+                }
+            }
             Ok(Some(encoding))
         } else {
             Ok(None)
@@ -109,8 +340,15 @@ impl Validation {
         truncate: Option<usize>,
         max_new_tokens: Option<u32>,
     ) -> Result<(String, usize, u32), ValidationError> {
+        let tokenized = self.tokenize(inputs.clone(), truncate).await?;
+        if let (Some(shadow_tokenizer), Some((encoding, _))) = (&self.shadow_tokenizer, &tokenized)
+        {
+            shadow_tokenizer.compare(&inputs, truncate, encoding.get_ids());
+        }
+
         // If we have a fast tokenizer
-        if let Some((encoding, inputs)) = self.tokenize(inputs.clone(), truncate).await? {
+        if let Some((encoding, inputs)) = tokenized {
+            InputTokensCapture::record(&encoding);
             // Create response channel
             let input_length = encoding.len();
 
@@ -177,8 +415,10 @@ impl Validation {
         let GenerateParameters {
             best_of,
             temperature,
+            temperature_schedule,
             repetition_penalty,
             frequency_penalty,
+            logit_bias,
             top_k,
             top_p,
             typical_p,
@@ -191,6 +431,16 @@ impl Validation {
             decoder_input_details,
             top_n_tokens,
             grammar,
+            skip_special_tokens,
+            stop_after_tool_call,
+            eos_probability_threshold,
+            eos_probability_window,
+            return_parsed,
+            quality_of_service,
+            normalize_inputs,
+            extensions,
+            adapter_id,
+            adapter_parameters,
             ..
         } = request.parameters;
 
@@ -198,6 +448,7 @@ impl Validation {
         let best_of = best_of.unwrap_or(1);
         let sampling = do_sample
             || temperature.is_some()
+            || temperature_schedule.is_some()
             || top_k.is_some()
             || top_p.is_some()
             || typical_p.is_some();
@@ -206,6 +457,14 @@ impl Validation {
             return Err(BestOfSampling);
         }
 
+        let temperature_schedule = match temperature_schedule {
+            Some(_) if temperature.is_some() => {
+                return Err(ValidationError::TemperatureScheduleConflict)
+            }
+            Some(schedule) => validate_temperature_schedule(schedule)?,
+            None => Vec::new(),
+        };
+
         let temperature = temperature.unwrap_or(1.0);
         if temperature <= 0.0 {
             return Err(ValidationError::Temperature);
@@ -221,6 +480,24 @@ impl Validation {
             return Err(ValidationError::FrequencyPenalty);
         }
 
+        let logit_bias = logit_bias.unwrap_or_default();
+        if logit_bias
+            .values()
+            .any(|bias| !(-100.0..=100.0).contains(bias))
+        {
+            return Err(ValidationError::LogitBias);
+        }
+        // Out of vocabulary token ids would fail the batch in the shards
+        if let Some(max_token) = logit_bias.keys().max() {
+            let vocab_size = self
+                .tokenizer()
+                .map(|tokenizer| tokenizer.get_vocab_size(true))
+                .ok_or(ValidationError::LogitBiasTokenizer)?;
+            if *max_token as usize >= vocab_size {
+                return Err(ValidationError::LogitBiasToken(vocab_size, *max_token));
+            }
+        }
+
         // Different because the proto default value is not a valid value
         // for the user
         let top_p = top_p
@@ -250,8 +527,15 @@ impl Validation {
             })
             .unwrap_or(Ok(0))?;
 
-        if max_new_tokens == Some(0) {
-            return Err(ValidationError::NegativeMaxNewTokens);
+        // The probability mass over the window is at most the window size
+        let eos_probability_window =
+            eos_probability_window.unwrap_or(DEFAULT_EOS_PROBABILITY_WINDOW);
+        if !(1..=MAX_EOS_PROBABILITY_WINDOW).contains(&eos_probability_window)
+            || eos_probability_threshold.is_some_and(|threshold| {
+                !(threshold > 0.0 && threshold <= eos_probability_window as f32)
+            })
+        {
+            return Err(ValidationError::EosProbability(MAX_EOS_PROBABILITY_WINDOW));
         }
 
         if stop_sequences.len() > self.max_stop_sequences {
@@ -272,14 +556,8 @@ impl Validation {
             }
         };
 
-        let top_n_tokens = top_n_tokens
-            .map(|value| {
-                if value > self.max_top_n_tokens {
-                    return Err(ValidationError::TopNTokens(self.max_top_n_tokens, value));
-                }
-                Ok(value)
-            })
-            .unwrap_or(Ok(0))?;
+        // The cap depends on the endpoint of the request
+        let top_n_tokens = self.top_n_tokens_limits.validate(top_n_tokens)?;
 
         // Check if inputs is empty
         if request.inputs.is_empty() {
@@ -296,11 +574,52 @@ impl Validation {
             })
             .unwrap_or(Ok(None))?;
 
+        // Normalize the inputs before tokenization
+        let (inputs, normalization) = match normalize_inputs {
+            Some(options) => {
+                let (inputs, report) = normalization::normalize(request.inputs, &options);
+                if report != NormalizationReport::default() {
+                    metrics::increment_counter!("tgi_request_input_normalized");
+                }
+                (inputs, Some(report))
+            }
+            None => (request.inputs, None),
+        };
+
         // Validate inputs
         let (inputs, input_length, max_new_tokens) = self
-            .validate_input(request.inputs, truncate, max_new_tokens)
+            .validate_input(inputs, truncate, max_new_tokens)
             .await?;
 
+        self.top_n_tokens_limits
+            .check_payload(top_n_tokens, max_new_tokens)?;
+
+        // The system prompt is no longer attended once the window slid over it
+        if let Some(attention_window) = &self.attention_window {
+            attention_window.check(input_length as u32, max_new_tokens)?;
+        }
+
+        // Prefill-only requests score the inputs: always return the prefill details
+        let decoder_input_details = decoder_input_details || max_new_tokens == 0;
+
+        // Bound the router memory held by the tokens of the response
+        let request_memory = request_memory(
+            best_of,
+            max_new_tokens,
+            top_n_tokens,
+            input_length,
+            decoder_input_details,
+        );
+        metrics::histogram!("tgi_request_memory_estimate", request_memory as f64);
+        if let Some(max_request_memory_mb) = self.max_request_memory_mb {
+            if request_memory > max_request_memory_mb * MB {
+                return Err(ValidationError::RequestMemory(
+                    (request_memory + MB - 1) / MB,
+                    max_request_memory_mb,
+                ));
+            }
+        }
+
         // TODO: we should build the FSM here and pass the compiled FSM instead of the grammar
         // NOTE: this is currently difficult because we need the tokenizer in Python to build
         // the FSM and we'd have to load a copy of the tokenizer into our Pyo3 instance which
@@ -314,36 +633,94 @@ impl Validation {
                 if self.disable_grammar_support {
                     return Err(ValidationError::Grammar);
                 }
+                let limits = self.grammar_limits;
                 match grammar {
                     GrammarType::Json(json) => {
                         let json = match json {
                             // if value is a string, we need to parse it again to make sure its
                             // a valid json
-                            Value::String(s) => serde_json::from_str(&s)
-                                .map_err(|e| ValidationError::InvalidGrammar(e.to_string())),
+                            Value::String(s) => {
+                                check_grammar_size(&s, limits.max_size)?;
+                                serde_json::from_str(&s)
+                                    .map_err(|e| ValidationError::InvalidGrammar(e.to_string()))
+                            }
                             Value::Object(_) => Ok(json),
                             _ => Err(ValidationError::Grammar),
                         }?;
 
+                        // Serialize json to string
+                        let serialized = serde_json::to_string(&json)
+                            .map_err(|e| ValidationError::InvalidGrammar(e.to_string()))?;
+                        check_grammar_size(&serialized, limits.max_size)?;
+                        let depth = json_depth(&json);
+                        if depth > limits.max_schema_depth {
+                            metrics::increment_counter!("tgi_grammar_rejected", "limit" => "depth");
+                            return Err(ValidationError::GrammarDepth(
+                                limits.max_schema_depth,
+                                depth,
+                            ));
+                        }
+
                         // Check if the json is a valid JSONSchema
+                        let start_time = Instant::now();
                         JSONSchema::options()
                             .with_draft(Draft::Draft202012)
                             .compile(&json)
                             .map_err(|e| ValidationError::InvalidGrammar(e.to_string()))?;
+                        metrics::histogram!(
+                            "tgi_grammar_validation_duration",
+                            start_time.elapsed().as_secs_f64()
+                        );
 
-                        (
-                            // Serialize json to string
-                            serde_json::to_string(&json)
-                                .map_err(|e| ValidationError::InvalidGrammar(e.to_string()))?,
-                            ProtoGrammarType::Json.into(),
-                        )
+                        (serialized, ProtoGrammarType::Json.into())
+                    }
+                    GrammarType::Regex(regex) => {
+                        check_grammar_size(&regex, limits.max_size)?;
+                        check_regex_size(&regex, limits.max_regex_size)?;
+                        (regex, ProtoGrammarType::Regex.into())
                     }
-                    GrammarType::Regex(regex) => (regex, ProtoGrammarType::Regex.into()),
                 }
             }
             None => (String::new(), ProtoGrammarType::None.into()),
         };
 
+        // Forwarded opaquely to the shards
+        let extensions = encode_extensions(extensions)?;
+
+        let adapter_ids = match (&adapter_id, &adapter_parameters) {
+            (Some(_), Some(_)) => return Err(ValidationError::AdapterParametersWithId),
+            (Some(adapter_id), None) => vec![adapter_id.clone()],
+            (None, Some(adapter_parameters)) => {
+                validate_adapter_parameters(adapter_parameters)?;
+                adapter_parameters.ids.clone()
+            }
+            (None, None) => Vec::new(),
+        };
+        // The adapters can not be evicted while the request holds their leases
+        let adapter_leases = {
+            let adapters = self.adapters.read().unwrap();
+            if let Some(unknown) = adapter_ids.iter().find(|&id| !adapters.contains(id)) {
+                return Err(match adapters.is_empty() {
+                    true => ValidationError::AdaptersDisabled,
+                    false => {
+                        ValidationError::UnknownAdapter(unknown.clone(), adapters.join("`, `"))
+                    }
+                });
+            }
+            adapter_ids
+                .iter()
+                .map(|adapter_id| self.adapter_leases.acquire(adapter_id))
+                .collect()
+        };
+
+        // The end of a tool call is detected on the JSON object
+        if stop_after_tool_call && grammar_type != ProtoGrammarType::Json as i32 {
+            return Err(ValidationError::StopAfterToolCall);
+        }
+        if return_parsed && grammar_type != ProtoGrammarType::Json as i32 {
+            return Err(ValidationError::ReturnParsed);
+        }
+
         let parameters = NextTokenChooserParameters {
             temperature,
             repetition_penalty,
@@ -356,11 +733,20 @@ impl Validation {
             watermark,
             grammar,
             grammar_type,
+            suppressed_tokens: self.suppressed_tokens.clone(),
+            temperature_schedule,
+            logit_bias,
         };
         let stopping_parameters = StoppingCriteriaParameters {
-            max_new_tokens,
+            // The shards generate at least one token: it is dropped for the prefill-only requests
+            max_new_tokens: max_new_tokens.max(1),
             stop_sequences,
             ignore_eos_token: false,
+            stop_after_tool_call,
+            // Forward the deadline so that the shards stop at the boundary too
+            deadline: deadline::current_millis(),
+            eos_probability_threshold,
+            eos_probability_window,
         };
 
         metrics::histogram!("tgi_request_max_new_tokens", max_new_tokens as f64);
@@ -373,9 +759,29 @@ impl Validation {
             parameters,
             stopping_parameters,
             top_n_tokens,
+            skip_special_tokens,
+            quality_of_service: quality_of_service.unwrap_or_default(),
+            normalization,
+            extensions,
+            adapter_id: adapter_id.unwrap_or_default(),
+            adapter_parameters: adapter_parameters.map(|adapter_parameters| {
+                ProtoAdapterParameters {
+                    ids: adapter_parameters.ids,
+                    weights: adapter_parameters.weights,
+                }
+            }),
+            adapter_leases,
         })
     }
 
+    /// Maximum number of prompt tokens leaving room for `max_new_tokens`
+    pub(crate) fn input_budget(&self, max_new_tokens: u32) -> usize {
+        self.max_input_length.min(
+            self.max_total_tokens
+                .saturating_sub(max_new_tokens as usize),
+        )
+    }
+
     /// Validate the best_of parameter
     #[instrument(skip_all)]
     pub(crate) fn validate_best_of(&self, best_of: usize) -> Result<usize, ValidationError> {
@@ -389,6 +795,31 @@ impl Validation {
 
         Ok(best_of)
     }
+
+    /// Validate the number of samples of a multi-sample request
+    #[instrument(skip_all)]
+    pub(crate) fn validate_samples(
+        &self,
+        n: usize,
+        parameters: &GenerateParameters,
+    ) -> Result<usize, ValidationError> {
+        if n == 0 || n > self.max_samples {
+            return Err(ValidationError::Samples(self.max_samples, n));
+        }
+
+        // samples would all be identical with greedy decoding
+        let sampling = parameters.do_sample
+            || parameters.temperature.is_some()
+            || parameters.temperature_schedule.is_some()
+            || parameters.top_k.is_some()
+            || parameters.top_p.is_some()
+            || parameters.typical_p.is_some();
+        if n > 1 && !sampling {
+            return Err(ValidationError::SamplesSampling);
+        }
+
+        Ok(n)
+    }
 }
 
 /// Round robin tokenization task
@@ -409,25 +840,47 @@ async fn round_robin_task(
 /// Start tokenization workers
 fn tokenizer_worker(tokenizer: Tokenizer, mut receiver: mpsc::UnboundedReceiver<TokenizerRequest>) {
     // Loop over requests
-    while let Some(((inputs, truncate), response_tx, parent_span)) = receiver.blocking_recv() {
+    while let Some(((inputs, truncate, prefix, cut_prefix), response_tx, parent_span)) =
+        receiver.blocking_recv()
+    {
         parent_span.in_scope(|| {
             response_tx
-                .send(prepare_input(inputs, truncate, &tokenizer))
+                .send(prepare_input(
+                    inputs, truncate, prefix, cut_prefix, &tokenizer,
+                ))
                 .unwrap_or(())
         })
     }
 }
 
-/// Get input length and optionally truncate it
+/// Get input length and optionally truncate it. With `cut_prefix`, the template output of a
+/// conversation is also cut after its last special token for its next turns, with whether the
+/// prefix keeps the tokens of the whole text
 fn prepare_input(
     mut inputs: String,
     truncate: Option<usize>,
+    prefix: Option<Prefix>,
+    cut_prefix: bool,
     tokenizer: &Tokenizer,
-) -> Result<(tokenizers::Encoding, String), ValidationError> {
-    // Get the number of tokens in the input
-    let mut encoding = tokenizer
-        .encode(inputs.clone(), true)
-        .map_err(|err| ValidationError::Tokenizer(err.to_string()))?;
+) -> Result<PreparedInput, ValidationError> {
+    // Get the number of tokens in the input, reusing the tokens of a cached conversation prefix
+    let cache_hit = prefix.is_some();
+    let mut encoding = match prefix {
+        Some(prefix) => prefix.encode(tokenizer, &inputs),
+        None => tokenizer.encode(inputs.clone(), true),
+    }
+    .map_err(|err| ValidationError::Tokenizer(err.to_string()))?;
+
+    // The truncated conversations do not start their next turns. Checked against the encoding
+    // of the whole text, the cached prefixes must not change the tokens sent to the model
+    let truncated = truncate.is_some_and(|truncate| encoding.len() >= truncate);
+    let next_prefix = match cut_prefix && !truncated {
+        true => Prefix::cut(tokenizer, &inputs, encoding.clone()).map(|prefix| {
+            let consistent = cache_hit || prefix.verify(tokenizer, &inputs, &encoding);
+            (prefix, consistent)
+        }),
+        false => None,
+    };
 
     // Optionally truncate
     if let Some(truncate) = truncate {
@@ -439,12 +892,104 @@ fn prepare_input(
         }
     }
 
-    Ok((encoding, inputs))
+    Ok((encoding, inputs, next_prefix))
+}
+
+/// Check that a temperature schedule has increasing tokens and strictly positive temperatures
+fn validate_temperature_schedule(
+    schedule: Vec<TemperatureStep>,
+) -> Result<Vec<TemperatureSchedulePoint>, ValidationError> {
+    let valid = !schedule.is_empty()
+        && schedule.len() <= MAX_TEMPERATURE_SCHEDULE_POINTS
+        && schedule.iter().all(|step| step.temperature > 0.0)
+        && schedule
+            .windows(2)
+            .all(|steps| steps[0].token < steps[1].token);
+    if !valid {
+        return Err(ValidationError::TemperatureSchedule(
+            MAX_TEMPERATURE_SCHEDULE_POINTS,
+        ));
+    }
+    Ok(schedule
+        .into_iter()
+        .map(|step| TemperatureSchedulePoint {
+            token: step.token,
+            temperature: step.temperature,
+        })
+        .collect())
+}
+
+/// Estimate of the router memory held by the tokens of a response: the generated tokens
+/// and their top tokens for every sequence, and the prefill tokens when they are returned
+fn request_memory(
+    best_of: usize,
+    max_new_tokens: u32,
+    top_n_tokens: u32,
+    input_length: usize,
+    decoder_input_details: bool,
+) -> usize {
+    let generated_tokens = max_new_tokens as usize * (1 + top_n_tokens as usize);
+    let prefill_tokens = match decoder_input_details {
+        true => input_length,
+        false => 0,
+    };
+    best_of * (generated_tokens + prefill_tokens) * TOKEN_MEMORY_BYTES
+}
+
+/// Prompt tokens of the request validated in the current OpenTelemetry context, captured from
+/// its encoding for `return_input_tokens`
+#[derive(Clone, Debug, Default)]
+pub(crate) struct InputTokensCapture {
+    /// Whether the token ids are captured, and not only their number
+    ids: bool,
+    captured: Arc<Mutex<Option<ChatInputTokens>>>,
+}
+
+impl InputTokensCapture {
+    pub(crate) fn new(ids: bool) -> Self {
+        Self {
+            ids,
+            ..Default::default()
+        }
+    }
+
+    /// Prompt tokens captured, once the request is validated
+    pub(crate) fn get(&self) -> Option<ChatInputTokens> {
+        self.captured.lock().unwrap().clone()
+    }
+
+    fn record(encoding: &tokenizers::Encoding) {
+        let context = Context::current();
+        let Some(capture) = context.get::<Self>() else {
+            return;
+        };
+        *capture.captured.lock().unwrap() = Some(ChatInputTokens {
+            count: encoding.len() as u32,
+            ids: capture.ids.then(|| encoding.get_ids().to_vec()),
+        });
+    }
+}
+
+/// Create the future of `generation` with the `capture` of the prompt tokens of the request it
+/// validates in its context, keeping the values of the current context
+pub(crate) fn capturing_input_tokens<F: Future>(
+    capture: Option<InputTokensCapture>,
+    generation: impl FnOnce() -> F,
+) -> WithContext<F> {
+    let context = capture.map_or_else(Context::current, |capture| {
+        Context::current_with_value(capture)
+    });
+    let _guard = context.clone().attach();
+    generation().with_context(context)
 }
 
+/// Encoding of the (truncated) inputs, and the prefix of a conversation to cache with whether
+/// it keeps the tokens of the whole text
+type PreparedInput = (tokenizers::Encoding, String, Option<(Prefix, bool)>);
+
 type TokenizerRequest = (
-    (String, Option<usize>),
-    oneshot::Sender<Result<(tokenizers::Encoding, String), ValidationError>>,
+    (String, Option<usize>, Option<Prefix>, bool),
+    oneshot::Sender<Result<PreparedInput, ValidationError>>,
     Span,
 );
 
@@ -457,6 +1002,104 @@ pub(crate) struct ValidGenerateRequest {
     pub parameters: NextTokenChooserParameters,
     pub stopping_parameters: StoppingCriteriaParameters,
     pub top_n_tokens: u32,
+    pub skip_special_tokens: bool,
+    pub quality_of_service: QualityOfService,
+    pub normalization: Option<NormalizationReport>,
+    /// JSON encoded vendor extension parameters
+    pub extensions: HashMap<String, String>,
+    /// LoRA adapter of the request, the base model if empty
+    pub adapter_id: String,
+    /// Weighted blend of LoRA adapters generating the request, instead of `adapter_id`
+    pub adapter_parameters: Option<ProtoAdapterParameters>,
+    /// Keep the adapters resident until the request and its clones are dropped
+    pub adapter_leases: Vec<Arc<AdapterLease>>,
+}
+
+/// Validate the weights of a blend of adapters
+fn validate_adapter_parameters(
+    adapter_parameters: &AdapterParameters,
+) -> Result<(), ValidationError> {
+    let AdapterParameters { ids, weights } = adapter_parameters;
+    if ids.is_empty() {
+        return Err(ValidationError::EmptyAdapterParameters);
+    }
+    if ids.len() != weights.len() {
+        return Err(ValidationError::AdapterWeights(ids.len(), weights.len()));
+    }
+    if !weights.iter().all(|weight| weight.is_finite()) {
+        return Err(ValidationError::AdapterWeight);
+    }
+    Ok(())
+}
+
+/// Reject the grammars larger than `max_size` bytes
+fn check_grammar_size(grammar: &str, max_size: usize) -> Result<(), ValidationError> {
+    if grammar.len() > max_size {
+        metrics::increment_counter!("tgi_grammar_rejected", "limit" => "size");
+        return Err(ValidationError::GrammarSize(max_size, grammar.len()));
+    }
+    Ok(())
+}
+
+/// Reject the regexes compiling to a program larger than `max_size` bytes. The shards use the
+/// Python regex syntax: the patterns the Rust syntax does not support are left to the shards
+fn check_regex_size(regex: &str, max_size: usize) -> Result<(), ValidationError> {
+    match regex::RegexBuilder::new(regex).size_limit(max_size).build() {
+        Err(regex::Error::CompiledTooBig(_)) => {
+            metrics::increment_counter!("tgi_grammar_rejected", "limit" => "regex");
+            Err(ValidationError::GrammarRegexSize(max_size))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Nesting depth of the objects and arrays of a JSON value
+fn json_depth(value: &Value) -> usize {
+    match value {
+        Value::Object(object) => 1 + object.values().map(json_depth).max().unwrap_or(0),
+        Value::Array(array) => 1 + array.iter().map(json_depth).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// Parse a text generated with a `json` grammar and validate it against the schema of the grammar
+pub(crate) fn parse_json_output(text: &str, schema: &Value) -> Result<Value, String> {
+    let schema = match schema {
+        Value::String(schema) => serde_json::from_str(schema).map_err(|err| err.to_string())?,
+        schema => schema.clone(),
+    };
+    let compiled = JSONSchema::options()
+        .with_draft(Draft::Draft202012)
+        .compile(&schema)
+        .map_err(|err| err.to_string())?;
+    let value: Value = serde_json::from_str(text).map_err(|err| err.to_string())?;
+    if let Err(errors) = compiled.validate(&value) {
+        let errors: Vec<String> = errors.map(|err| err.to_string()).collect();
+        return Err(errors.join(", "));
+    }
+    Ok(value)
+}
+
+/// JSON encode the vendor extension parameters, rejecting the bags larger than
+/// `MAX_EXTENSIONS_SIZE` bytes
+fn encode_extensions(
+    extensions: HashMap<String, Value>,
+) -> Result<HashMap<String, String>, ValidationError> {
+    let extensions: HashMap<String, String> = extensions
+        .into_iter()
+        .map(|(name, value)| (name, value.to_string()))
+        .collect();
+    let size = extensions
+        .iter()
+        .map(|(name, value)| name.len() + value.len())
+        .sum();
+    if size > MAX_EXTENSIONS_SIZE {
+        return Err(ValidationError::ExtensionsSize(MAX_EXTENSIONS_SIZE, size));
+    }
+    if !extensions.is_empty() {
+        metrics::increment_counter!("tgi_request_extensions");
+    }
+    Ok(extensions)
 }
 
 #[derive(Error, Debug)]
@@ -471,18 +1114,40 @@ pub enum ValidationError {
     BestOfSeed,
     #[error("`best_of` != 1 is not supported when streaming tokens")]
     BestOfStream,
+    #[error("`output_destination` is not enabled on this server")]
+    OutputDestinationDisabled,
+    #[error("`output_destination` is not supported with `best_of` != 1 or when streaming tokens")]
+    OutputDestinationUnsupported,
+    #[error("`n` must be > 0 and <= {0}. Given: {1}")]
+    Samples(usize, usize),
+    #[error("you must use sampling when `n` is > 1")]
+    SamplesSampling,
     #[error("`top_n_tokens` must be >= 0 and <= {0}. Given: {1}")]
     TopNTokens(u32, u32),
+    #[error("`top_n_tokens` * `max_new_tokens` must be <= {0}. Given: {1} * {2}")]
+    LogprobPayload(u32, u32, u32),
     #[error("`top_n_tokens` != 0 is not allowed for this endpoint")]
     TopNTokensDisabled,
     #[error("`decoder_input_details` == true is not supported when streaming tokens")]
     PrefillDetailsStream,
+    #[error("`max_new_tokens` == 0 is not supported when streaming tokens")]
+    PrefillOnlyStream,
     #[error("`temperature` must be strictly positive")]
     Temperature,
+    #[error("`temperature_schedule` must have 1 to {0} points with increasing `token` and strictly positive `temperature`")]
+    TemperatureSchedule(usize),
+    #[error("`temperature_schedule` cannot be combined with `temperature`")]
+    TemperatureScheduleConflict,
     #[error("`repetition_penalty` must be strictly positive")]
     RepetitionPenalty,
     #[error("`frequency_penalty` must be >= -2.0 and <= 2.0")]
     FrequencyPenalty,
+    #[error("`logit_bias` values must be >= -100.0 and <= 100.0")]
+    LogitBias,
+    #[error("`logit_bias` token ids must be < {0}. Given: {1}")]
+    LogitBiasToken(usize, u32),
+    #[error("`logit_bias` requires a fast tokenizer to validate its token ids")]
+    LogitBiasTokenizer,
     #[error("`top_p` must be > 0.0 and < 1.0")]
     TopP,
     #[error("`top_k` must be strictly positive")]
@@ -493,12 +1158,12 @@ pub enum ValidationError {
     TypicalP,
     #[error("one of `max_new_tokens` or `truncate` must be set if a fast tokenizer is not in use")]
     UnsetMaxNewTokens,
-    #[error("`max_new_tokens` must be strictly positive")]
-    NegativeMaxNewTokens,
     #[error("`max_new_tokens` must be <= {0}. Given: {1}")]
     MaxNewTokens(usize, u32),
     #[error("`inputs` tokens + `max_new_tokens` must be <= {0}. Given: {1} `inputs` tokens and {2} `max_new_tokens`")]
     MaxTotalTokens(usize, usize, u32),
+    #[error("the system prompt must stay in the attention window: `inputs` tokens + `max_new_tokens` must be <= {0}. Given: {1} `inputs` tokens and {2} `max_new_tokens`")]
+    SystemPromptOutsideWindow(u32, u32, u32),
     #[error("`inputs` must have less than {0} tokens. Given: {1}")]
     InputLength(usize, usize),
     #[error("`inputs` cannot be empty")]
@@ -511,6 +1176,174 @@ pub enum ValidationError {
     Grammar,
     #[error("grammar is not valid: {0}")]
     InvalidGrammar(String),
+    #[error("grammar must be at most {0} bytes. Given: {1}")]
+    GrammarSize(usize, usize),
+    #[error("`regex` grammar must compile to at most {0} bytes")]
+    GrammarRegexSize(usize),
+    #[error("`json` grammar must be nested at most {0} levels deep. Given: {1}")]
+    GrammarDepth(usize, usize),
+    #[error("`stop_after_tool_call` requires a `json` grammar")]
+    StopAfterToolCall,
+    #[error("`return_parsed` requires a `json` grammar")]
+    ReturnParsed,
+    #[error("`return_parsed` is not supported when streaming tokens")]
+    ReturnParsedStream,
+    #[error("`eos_probability_window` must be > 0 and <= {0}, and `eos_probability_threshold` > 0 and <= `eos_probability_window`")]
+    EosProbability(u32),
+    #[error("`extensions` must be at most {0} bytes once JSON encoded. Given: {1}")]
+    ExtensionsSize(usize, usize),
+    #[error("`max_new_tokens`, `top_n_tokens`, `best_of` and `decoder_input_details` would hold about {0} MB of router memory, more than the {1} MB allowed per request")]
+    RequestMemory(usize, usize),
+    #[error("`instances` must have at most {0} elements. Given: {1}")]
+    Instances(usize, usize),
+    #[error("`adapter_id` is not supported: no LoRA adapter is loaded")]
+    AdaptersDisabled,
+    #[error("unknown adapter `{0}`, expected one of `{1}`")]
+    UnknownAdapter(String, String),
+    #[error("unknown preset `{0}`, expected one of `{1}`")]
+    UnknownPreset(String, String),
+    #[error("`adapter_parameters` can not be combined with `adapter_id`")]
+    AdapterParametersWithId,
+    #[error("`adapter_parameters` must have at least one adapter")]
+    EmptyAdapterParameters,
+    #[error("`adapter_parameters` must have one weight per adapter. Given: {0} adapters and {1} weights")]
+    AdapterWeights(usize, usize),
+    #[error("`adapter_parameters` weights must be finite")]
+    AdapterWeight,
+}
+
+impl ValidationError {
+    /// Code of the error in the message catalogs, and the values of its message in the order of
+    /// the `{0}`, `{1}`... placeholders
+    pub(crate) fn message_code(&self) -> (&'static str, Vec<String>) {
+        match self {
+            ValidationError::BestOf(limit, given) => {
+                ("best_of", vec![limit.to_string(), given.to_string()])
+            }
+            ValidationError::BestOfDisabled => ("best_of_disabled", vec![]),
+            ValidationError::BestOfSampling => ("best_of_sampling", vec![]),
+            ValidationError::BestOfSeed => ("best_of_seed", vec![]),
+            ValidationError::BestOfStream => ("best_of_stream", vec![]),
+            ValidationError::OutputDestinationDisabled => ("output_destination_disabled", vec![]),
+            ValidationError::OutputDestinationUnsupported => {
+                ("output_destination_unsupported", vec![])
+            }
+            ValidationError::Samples(limit, given) => {
+                ("samples", vec![limit.to_string(), given.to_string()])
+            }
+            ValidationError::SamplesSampling => ("samples_sampling", vec![]),
+            ValidationError::TopNTokens(limit, given) => {
+                ("top_n_tokens", vec![limit.to_string(), given.to_string()])
+            }
+            ValidationError::LogprobPayload(limit, top_n_tokens, max_new_tokens) => (
+                "logprob_payload",
+                vec![
+                    limit.to_string(),
+                    top_n_tokens.to_string(),
+                    max_new_tokens.to_string(),
+                ],
+            ),
+            ValidationError::TopNTokensDisabled => ("top_n_tokens_disabled", vec![]),
+            ValidationError::PrefillDetailsStream => ("prefill_details_stream", vec![]),
+            ValidationError::PrefillOnlyStream => ("prefill_only_stream", vec![]),
+            ValidationError::Temperature => ("temperature", vec![]),
+            ValidationError::TemperatureSchedule(limit) => {
+                ("temperature_schedule", vec![limit.to_string()])
+            }
+            ValidationError::TemperatureScheduleConflict => {
+                ("temperature_schedule_conflict", vec![])
+            }
+            ValidationError::RepetitionPenalty => ("repetition_penalty", vec![]),
+            ValidationError::FrequencyPenalty => ("frequency_penalty", vec![]),
+            ValidationError::LogitBias => ("logit_bias", vec![]),
+            ValidationError::LogitBiasToken(limit, given) => (
+                "logit_bias_token",
+                vec![limit.to_string(), given.to_string()],
+            ),
+            ValidationError::LogitBiasTokenizer => ("logit_bias_tokenizer", vec![]),
+            ValidationError::TopP => ("top_p", vec![]),
+            ValidationError::TopK => ("top_k", vec![]),
+            ValidationError::Truncate(limit, given) => {
+                ("truncate", vec![limit.to_string(), given.to_string()])
+            }
+            ValidationError::TypicalP => ("typical_p", vec![]),
+            ValidationError::UnsetMaxNewTokens => ("unset_max_new_tokens", vec![]),
+            ValidationError::MaxNewTokens(limit, given) => {
+                ("max_new_tokens", vec![limit.to_string(), given.to_string()])
+            }
+            ValidationError::MaxTotalTokens(limit, input_length, max_new_tokens) => (
+                "max_total_tokens",
+                vec![
+                    limit.to_string(),
+                    input_length.to_string(),
+                    max_new_tokens.to_string(),
+                ],
+            ),
+            ValidationError::SystemPromptOutsideWindow(
+                window_size,
+                input_length,
+                max_new_tokens,
+            ) => (
+                "system_prompt_outside_window",
+                vec![
+                    window_size.to_string(),
+                    input_length.to_string(),
+                    max_new_tokens.to_string(),
+                ],
+            ),
+            ValidationError::InputLength(limit, given) => {
+                ("input_length", vec![limit.to_string(), given.to_string()])
+            }
+            ValidationError::EmptyInput => ("empty_input", vec![]),
+            ValidationError::StopSequence(limit, given) => {
+                ("stop_sequence", vec![limit.to_string(), given.to_string()])
+            }
+            ValidationError::Tokenizer(message) => ("tokenizer", vec![message.to_string()]),
+            ValidationError::Grammar => ("grammar", vec![]),
+            ValidationError::InvalidGrammar(message) => {
+                ("invalid_grammar", vec![message.to_string()])
+            }
+            ValidationError::GrammarSize(limit, given) => {
+                ("grammar_size", vec![limit.to_string(), given.to_string()])
+            }
+            ValidationError::GrammarRegexSize(limit) => {
+                ("grammar_regex_size", vec![limit.to_string()])
+            }
+            ValidationError::GrammarDepth(limit, given) => {
+                ("grammar_depth", vec![limit.to_string(), given.to_string()])
+            }
+            ValidationError::StopAfterToolCall => ("stop_after_tool_call", vec![]),
+            ValidationError::ReturnParsed => ("return_parsed", vec![]),
+            ValidationError::ReturnParsedStream => ("return_parsed_stream", vec![]),
+            ValidationError::EosProbability(limit) => ("eos_probability", vec![limit.to_string()]),
+            ValidationError::ExtensionsSize(limit, given) => (
+                "extensions_size",
+                vec![limit.to_string(), given.to_string()],
+            ),
+            ValidationError::RequestMemory(memory, limit) => (
+                "request_memory",
+                vec![memory.to_string(), limit.to_string()],
+            ),
+            ValidationError::Instances(limit, given) => {
+                ("instances", vec![limit.to_string(), given.to_string()])
+            }
+            ValidationError::AdaptersDisabled => ("adapters_disabled", vec![]),
+            ValidationError::UnknownAdapter(adapter_id, adapters) => (
+                "unknown_adapter",
+                vec![adapter_id.clone(), adapters.clone()],
+            ),
+            ValidationError::UnknownPreset(preset, presets) => {
+                ("unknown_preset", vec![preset.clone(), presets.clone()])
+            }
+            ValidationError::AdapterParametersWithId => ("adapter_parameters_with_id", vec![]),
+            ValidationError::EmptyAdapterParameters => ("empty_adapter_parameters", vec![]),
+            ValidationError::AdapterWeights(adapters, weights) => (
+                "adapter_weights",
+                vec![adapters.to_string(), weights.to_string()],
+            ),
+            ValidationError::AdapterWeight => ("adapter_weight", vec![]),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -523,21 +1356,28 @@ mod tests {
     async fn test_validation_max_new_tokens() {
         let tokenizer = None;
         let max_best_of = 2;
+        let max_samples = 4;
         let max_stop_sequence = 3;
         let max_top_n_tokens = 4;
         let max_input_length = 5;
         let max_total_tokens = 6;
         let workers = 1;
         let disable_grammar_support = true;
+        let max_request_memory_mb = None;
+        let suppressed_tokens = vec![];
         let validation = Validation::new(
             workers,
             tokenizer,
             max_best_of,
+            max_samples,
             max_stop_sequence,
-            max_top_n_tokens,
+            TopNTokensLimits::uniform(max_top_n_tokens),
             max_input_length,
             max_total_tokens,
             disable_grammar_support,
+            max_request_memory_mb,
+            suppressed_tokens,
+            GrammarLimits::default(),
         );
 
         let max_new_tokens = 10;
@@ -554,21 +1394,28 @@ mod tests {
     async fn test_validation_input_length() {
         let tokenizer = Some(get_tokenizer().await);
         let max_best_of = 2;
+        let max_samples = 4;
         let max_stop_sequence = 3;
         let max_top_n_tokens = 4;
         let max_input_length = 5;
         let max_total_tokens = 6;
         let disable_grammar_support = true;
+        let max_request_memory_mb = None;
+        let suppressed_tokens = vec![];
         let workers = 1;
         let validation = Validation::new(
             workers,
             tokenizer,
             max_best_of,
+            max_samples,
             max_stop_sequence,
-            max_top_n_tokens,
+            TopNTokensLimits::uniform(max_top_n_tokens),
             max_input_length,
             max_total_tokens,
             disable_grammar_support,
+            max_request_memory_mb,
+            suppressed_tokens,
+            GrammarLimits::default(),
         );
 
         let max_new_tokens = 10;
@@ -581,25 +1428,71 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_validation_input_tokens_capture() {
+        let tokenizer = Some(get_tokenizer().await);
+        let validation = Validation::new(
+            1,
+            tokenizer,
+            2,
+            4,
+            3,
+            TopNTokensLimits::uniform(4),
+            5,
+            6,
+            true,
+            None,
+            vec![],
+            GrammarLimits::default(),
+        );
+
+        for ids in [false, true] {
+            let capture = InputTokensCapture::new(ids);
+            capturing_input_tokens(Some(capture.clone()), || {
+                validation.validate_input("Hello".to_string(), None, Some(1))
+            })
+            .await
+            .unwrap();
+            let input_tokens = capture.get().unwrap();
+            assert_eq!(input_tokens.count, 1);
+            assert_eq!(input_tokens.ids.is_some(), ids);
+        }
+
+        // Nothing is captured outside of a capturing context
+        let capture = InputTokensCapture::new(true);
+        validation
+            .validate_input("Hello".to_string(), None, Some(1))
+            .await
+            .unwrap();
+        assert!(capture.get().is_none());
+    }
+
     #[tokio::test]
     async fn test_validation_best_of_sampling() {
         let tokenizer = Some(get_tokenizer().await);
         let max_best_of = 2;
+        let max_samples = 4;
         let max_stop_sequence = 3;
         let max_top_n_tokens = 4;
         let max_input_length = 5;
         let max_total_tokens = 6;
         let workers = 1;
         let disable_grammar_support = true;
+        let max_request_memory_mb = None;
+        let suppressed_tokens = vec![];
         let validation = Validation::new(
             workers,
             tokenizer,
             max_best_of,
+            max_samples,
             max_stop_sequence,
-            max_top_n_tokens,
+            TopNTokensLimits::uniform(max_top_n_tokens),
             max_input_length,
             max_total_tokens,
             disable_grammar_support,
+            max_request_memory_mb,
+            suppressed_tokens,
+            GrammarLimits::default(),
         );
         match validation
             .validate(GenerateRequest {
@@ -617,25 +1510,82 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_validation_samples() {
+        let tokenizer = None;
+        let max_best_of = 2;
+        let max_samples = 4;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 6;
+        let workers = 1;
+        let disable_grammar_support = true;
+        let max_request_memory_mb = None;
+        let suppressed_tokens = vec![];
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_samples,
+            max_stop_sequence,
+            TopNTokensLimits::uniform(max_top_n_tokens),
+            max_input_length,
+            max_total_tokens,
+            disable_grammar_support,
+            max_request_memory_mb,
+            suppressed_tokens,
+            GrammarLimits::default(),
+        );
+
+        match validation.validate_samples(5, &default_parameters()) {
+            Err(ValidationError::Samples(4, 5)) => (),
+            _ => panic!("Unexpected not samples"),
+        }
+
+        let greedy_parameters = GenerateParameters {
+            do_sample: false,
+            ..default_parameters()
+        };
+        match validation.validate_samples(2, &greedy_parameters) {
+            Err(ValidationError::SamplesSampling) => (),
+            _ => panic!("Unexpected not samples sampling"),
+        }
+
+        assert_eq!(
+            validation
+                .validate_samples(4, &default_parameters())
+                .unwrap(),
+            4
+        );
+    }
+
     #[tokio::test]
     async fn test_validation_top_p() {
         let tokenizer = Some(get_tokenizer().await);
         let max_best_of = 2;
+        let max_samples = 4;
         let max_stop_sequence = 3;
         let max_top_n_tokens = 4;
         let max_input_length = 5;
         let max_total_tokens = 106;
         let workers = 1;
         let disable_grammar_support = true;
+        let max_request_memory_mb = None;
+        let suppressed_tokens = vec![];
         let validation = Validation::new(
             workers,
             tokenizer,
             max_best_of,
+            max_samples,
             max_stop_sequence,
-            max_top_n_tokens,
+            TopNTokensLimits::uniform(max_top_n_tokens),
             max_input_length,
             max_total_tokens,
             disable_grammar_support,
+            max_request_memory_mb,
+            suppressed_tokens,
+            GrammarLimits::default(),
         );
         match validation
             .validate(GenerateRequest {
@@ -686,21 +1636,28 @@ mod tests {
     async fn test_validation_top_n_tokens() {
         let tokenizer = Some(get_tokenizer().await);
         let max_best_of = 2;
+        let max_samples = 4;
         let max_stop_sequences = 3;
         let max_top_n_tokens = 4;
         let max_input_length = 5;
         let max_total_tokens = 106;
         let workers = 1;
         let disable_grammar_support = true;
+        let max_request_memory_mb = None;
+        let suppressed_tokens = vec![];
         let validation = Validation::new(
             workers,
             tokenizer,
             max_best_of,
+            max_samples,
             max_stop_sequences,
-            max_top_n_tokens,
+            TopNTokensLimits::uniform(max_top_n_tokens),
             max_input_length,
             max_total_tokens,
             disable_grammar_support,
+            max_request_memory_mb,
+            suppressed_tokens,
+            GrammarLimits::default(),
         );
         match validation
             .validate(GenerateRequest {
@@ -755,4 +1712,681 @@ mod tests {
 
         assert_eq!(valid_request.top_n_tokens, 0);
     }
+
+    #[tokio::test]
+    async fn test_validation_stop_after_tool_call() {
+        let tokenizer = None;
+        let max_best_of = 2;
+        let max_samples = 4;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 6;
+        let workers = 1;
+        let disable_grammar_support = false;
+        let max_request_memory_mb = None;
+        let suppressed_tokens = vec![];
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_samples,
+            max_stop_sequence,
+            TopNTokensLimits::uniform(max_top_n_tokens),
+            max_input_length,
+            max_total_tokens,
+            disable_grammar_support,
+            max_request_memory_mb,
+            suppressed_tokens,
+            GrammarLimits::default(),
+        );
+
+        match validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    max_new_tokens: Some(1),
+                    stop_after_tool_call: true,
+                    ..default_parameters()
+                },
+            })
+            .await
+        {
+            Err(ValidationError::StopAfterToolCall) => (),
+            _ => panic!("Unexpected not stop after tool call"),
+        }
+
+        let request = validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    max_new_tokens: Some(1),
+                    stop_after_tool_call: true,
+                    grammar: Some(GrammarType::Json(serde_json::json!({"type": "object"}))),
+                    ..default_parameters()
+                },
+            })
+            .await
+            .unwrap();
+        assert!(request.stopping_parameters.stop_after_tool_call);
+    }
+
+    #[test]
+    fn test_parse_json_output() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {"location": {"type": "string"}},
+            "required": ["location"]
+        });
+        assert_eq!(
+            parse_json_output(r#"{"location": "Paris"}"#, &schema).unwrap(),
+            serde_json::json!({"location": "Paris"})
+        );
+        // Schemas given as strings, as accepted by the `json` grammar
+        let schema = Value::String(schema.to_string());
+        assert!(parse_json_output(r#"{"location": 75}"#, &schema).is_err());
+        // Cut by `max_new_tokens`
+        assert!(parse_json_output(r#"{"location": "Pa"#, &schema).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_validation_eos_probability() {
+        let tokenizer = None;
+        let max_best_of = 2;
+        let max_samples = 4;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 6;
+        let workers = 1;
+        let disable_grammar_support = true;
+        let max_request_memory_mb = None;
+        let suppressed_tokens = vec![];
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_samples,
+            max_stop_sequence,
+            TopNTokensLimits::uniform(max_top_n_tokens),
+            max_input_length,
+            max_total_tokens,
+            disable_grammar_support,
+            max_request_memory_mb,
+            suppressed_tokens,
+            GrammarLimits::default(),
+        );
+
+        // A mass over the window can not be reached
+        match validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    max_new_tokens: Some(1),
+                    eos_probability_threshold: Some(2.5),
+                    eos_probability_window: Some(2),
+                    ..default_parameters()
+                },
+            })
+            .await
+        {
+            Err(ValidationError::EosProbability(_)) => (),
+            _ => panic!("Unexpected not eos probability"),
+        }
+
+        let request = validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    max_new_tokens: Some(1),
+                    eos_probability_threshold: Some(1.5),
+                    ..default_parameters()
+                },
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            request.stopping_parameters.eos_probability_threshold,
+            Some(1.5)
+        );
+        assert_eq!(
+            request.stopping_parameters.eos_probability_window,
+            DEFAULT_EOS_PROBABILITY_WINDOW
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validation_grammar_limits() {
+        let tokenizer = None;
+        let max_best_of = 2;
+        let max_samples = 4;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 6;
+        let workers = 1;
+        let disable_grammar_support = false;
+        let max_request_memory_mb = None;
+        let suppressed_tokens = vec![];
+        let grammar_limits = GrammarLimits {
+            max_size: 64,
+            max_regex_size: 1024,
+            max_schema_depth: 3,
+        };
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_samples,
+            max_stop_sequence,
+            TopNTokensLimits::uniform(max_top_n_tokens),
+            max_input_length,
+            max_total_tokens,
+            disable_grammar_support,
+            max_request_memory_mb,
+            suppressed_tokens,
+            grammar_limits,
+        );
+        let request = |grammar: GrammarType| GenerateRequest {
+            inputs: "Hello".to_string(),
+            parameters: GenerateParameters {
+                max_new_tokens: Some(1),
+                grammar: Some(grammar),
+                ..default_parameters()
+            },
+        };
+
+        let schema = serde_json::json!({"type": "object", "properties": {"a": {"type": "string"}}});
+        assert!(validation
+            .validate(request(GrammarType::Json(schema)))
+            .await
+            .is_ok());
+
+        let schema = serde_json::json!({"description": "a".repeat(64)});
+        match validation
+            .validate(request(GrammarType::Json(schema)))
+            .await
+        {
+            Err(ValidationError::GrammarSize(64, _)) => (),
+            _ => panic!("Unexpected not grammar size"),
+        }
+
+        let schema = serde_json::json!({"properties": {"a": {"properties": {"b": {}}}}});
+        match validation
+            .validate(request(GrammarType::Json(schema)))
+            .await
+        {
+            Err(ValidationError::GrammarDepth(3, 5)) => (),
+            _ => panic!("Unexpected not grammar depth"),
+        }
+
+        match validation
+            .validate(request(GrammarType::Regex(r"\w{50}".to_string())))
+            .await
+        {
+            Err(ValidationError::GrammarRegexSize(1024)) => (),
+            _ => panic!("Unexpected not grammar regex size"),
+        }
+
+        // Python only syntax is left to the shards
+        assert!(validation
+            .validate(request(GrammarType::Regex(r"(?<=a)b".to_string())))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_validation_prefill_only() {
+        let tokenizer = None;
+        let max_best_of = 2;
+        let max_samples = 4;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 6;
+        let workers = 1;
+        let disable_grammar_support = true;
+        let max_request_memory_mb = None;
+        let suppressed_tokens = vec![];
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_samples,
+            max_stop_sequence,
+            TopNTokensLimits::uniform(max_top_n_tokens),
+            max_input_length,
+            max_total_tokens,
+            disable_grammar_support,
+            max_request_memory_mb,
+            suppressed_tokens,
+            GrammarLimits::default(),
+        );
+
+        let request = validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    max_new_tokens: Some(0),
+                    ..default_parameters()
+                },
+            })
+            .await
+            .unwrap();
+        assert!(request.decoder_input_details);
+        // The token generated by the shards is dropped by the router
+        assert_eq!(request.stopping_parameters.max_new_tokens, 1);
+    }
+
+    #[tokio::test]
+    async fn test_validation_temperature_schedule() {
+        let tokenizer = None;
+        let max_best_of = 2;
+        let max_samples = 4;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 6;
+        let workers = 1;
+        let disable_grammar_support = true;
+        let max_request_memory_mb = None;
+        let suppressed_tokens = vec![];
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_samples,
+            max_stop_sequence,
+            TopNTokensLimits::uniform(max_top_n_tokens),
+            max_input_length,
+            max_total_tokens,
+            disable_grammar_support,
+            max_request_memory_mb,
+            suppressed_tokens,
+            GrammarLimits::default(),
+        );
+        let schedule = |steps: &[(u32, f32)]| {
+            Some(
+                steps
+                    .iter()
+                    .map(|(token, temperature)| TemperatureStep {
+                        token: *token,
+                        temperature: *temperature,
+                    })
+                    .collect(),
+            )
+        };
+
+        for temperature_schedule in [
+            schedule(&[]),
+            schedule(&[(0, 1.0), (0, 0.7)]),
+            schedule(&[(0, 1.0), (50, 0.0)]),
+        ] {
+            match validation
+                .validate(GenerateRequest {
+                    inputs: "Hello".to_string(),
+                    parameters: GenerateParameters {
+                        max_new_tokens: Some(1),
+                        temperature_schedule,
+                        ..default_parameters()
+                    },
+                })
+                .await
+            {
+                Err(ValidationError::TemperatureSchedule(16)) => (),
+                _ => panic!("Unexpected valid temperature schedule"),
+            }
+        }
+
+        match validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    max_new_tokens: Some(1),
+                    temperature: Some(0.5),
+                    temperature_schedule: schedule(&[(0, 1.0)]),
+                    ..default_parameters()
+                },
+            })
+            .await
+        {
+            Err(ValidationError::TemperatureScheduleConflict) => (),
+            _ => panic!("Unexpected temperature and temperature schedule"),
+        }
+
+        let request = validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    max_new_tokens: Some(1),
+                    temperature_schedule: schedule(&[(0, 1.0), (50, 0.7)]),
+                    ..default_parameters()
+                },
+            })
+            .await
+            .unwrap();
+        assert_eq!(request.parameters.temperature, 1.0);
+        assert_eq!(
+            request.parameters.temperature_schedule,
+            vec![
+                TemperatureSchedulePoint {
+                    token: 0,
+                    temperature: 1.0
+                },
+                TemperatureSchedulePoint {
+                    token: 50,
+                    temperature: 0.7
+                }
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validation_request_memory() {
+        let tokenizer = None;
+        let max_best_of = 2;
+        let max_samples = 4;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 2005;
+        let workers = 1;
+        let disable_grammar_support = true;
+        let max_request_memory_mb = Some(1);
+        let suppressed_tokens = vec![];
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_samples,
+            max_stop_sequence,
+            TopNTokensLimits::uniform(max_top_n_tokens),
+            max_input_length,
+            max_total_tokens,
+            disable_grammar_support,
+            max_request_memory_mb,
+            suppressed_tokens,
+            GrammarLimits::default(),
+        );
+
+        match validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    top_n_tokens: Some(4),
+                    max_new_tokens: Some(2000),
+                    ..default_parameters()
+                },
+            })
+            .await
+        {
+            Err(ValidationError::RequestMemory(2, 1)) => (),
+            _ => panic!("Unexpected not request memory"),
+        }
+
+        validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    max_new_tokens: Some(2000),
+                    ..default_parameters()
+                },
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_validation_suppressed_tokens() {
+        let tokenizer = None;
+        let max_best_of = 2;
+        let max_samples = 4;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 106;
+        let workers = 1;
+        let disable_grammar_support = true;
+        let max_request_memory_mb = None;
+        let suppressed_tokens = vec![0, 2];
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_samples,
+            max_stop_sequence,
+            TopNTokensLimits::uniform(max_top_n_tokens),
+            max_input_length,
+            max_total_tokens,
+            disable_grammar_support,
+            max_request_memory_mb,
+            suppressed_tokens,
+            GrammarLimits::default(),
+        );
+
+        // Suppressed for every request, independently of their parameters
+        let request = validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    max_new_tokens: Some(5),
+                    ..default_parameters()
+                },
+            })
+            .await
+            .unwrap();
+        assert_eq!(request.parameters.suppressed_tokens, vec![0, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_validation_logit_bias() {
+        let validation = |tokenizer| {
+            Validation::new(
+                1,
+                tokenizer,
+                2,
+                4,
+                3,
+                TopNTokensLimits::uniform(4),
+                5,
+                106,
+                true,
+                None,
+                vec![],
+                GrammarLimits::default(),
+            )
+        };
+        // The token ids are the keys of a JSON object, as with OpenAI
+        let request = |logit_bias: &str| GenerateRequest {
+            inputs: "Hello".to_string(),
+            parameters: GenerateParameters {
+                max_new_tokens: Some(5),
+                logit_bias: serde_json::from_str(logit_bias).unwrap(),
+                ..default_parameters()
+            },
+        };
+
+        let validation_gpt2 = validation(Some(get_tokenizer().await));
+        let valid_request = validation_gpt2
+            .validate(request(r#"{"50256": -100, "13": 2.5}"#))
+            .await
+            .unwrap();
+        assert_eq!(
+            valid_request.parameters.logit_bias,
+            HashMap::from([(50256, -100.0), (13, 2.5)])
+        );
+
+        match validation_gpt2.validate(request(r#"{"50257": 1}"#)).await {
+            Err(ValidationError::LogitBiasToken(50257, 50257)) => (),
+            _ => panic!("Unexpected out of vocabulary token"),
+        }
+        match validation_gpt2.validate(request(r#"{"13": 101}"#)).await {
+            Err(ValidationError::LogitBias) => (),
+            _ => panic!("Unexpected bias"),
+        }
+        match validation(None).validate(request(r#"{"13": 1}"#)).await {
+            Err(ValidationError::LogitBiasTokenizer) => (),
+            _ => panic!("Unexpected unvalidated token ids"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_adapter() {
+        let validation = Validation::new(
+            1,
+            None,
+            2,
+            4,
+            3,
+            TopNTokensLimits::uniform(4),
+            5,
+            106,
+            true,
+            None,
+            vec![],
+            GrammarLimits::default(),
+        );
+        let request = |adapter_id: Option<&str>| GenerateRequest {
+            inputs: "Hello".to_string(),
+            parameters: GenerateParameters {
+                max_new_tokens: Some(5),
+                adapter_id: adapter_id.map(String::from),
+                ..default_parameters()
+            },
+        };
+
+        match validation.validate(request(Some("support"))).await {
+            Err(ValidationError::AdaptersDisabled) => (),
+            _ => panic!("Unexpected adapter"),
+        }
+
+        let validation = validation.with_adapters(vec!["support".to_string(), "sql".to_string()]);
+        let valid_request = validation.validate(request(Some("sql"))).await.unwrap();
+        assert_eq!(valid_request.adapter_id, "sql");
+        let valid_request = validation.validate(request(None)).await.unwrap();
+        assert_eq!(valid_request.adapter_id, "");
+        match validation.validate(request(Some("sales"))).await {
+            Err(err @ ValidationError::UnknownAdapter(..)) => assert_eq!(
+                err.to_string(),
+                "unknown adapter `sales`, expected one of `support`, `sql`"
+            ),
+            _ => panic!("Unexpected adapter"),
+        }
+
+        // The adapters evicted at runtime are rejected by all the clones
+        validation.clone().set_adapters(vec!["support".to_string()]);
+        match validation.validate(request(Some("sql"))).await {
+            Err(ValidationError::UnknownAdapter(..)) => (),
+            _ => panic!("Unexpected adapter"),
+        }
+
+        // The adapters used by the requests in flight, or their clones, are not evicted
+        let in_flight = validation.validate(request(Some("support"))).await.unwrap();
+        let retry = in_flight.clone();
+        assert_eq!(validation.remove_adapter("support"), Err(1));
+        drop(in_flight);
+        assert_eq!(validation.remove_adapter("support"), Err(1));
+        drop(retry);
+        assert_eq!(validation.remove_adapter("support"), Ok(()));
+        assert!(validation.adapters().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_validation_adapter_parameters() {
+        let validation = Validation::new(
+            1,
+            None,
+            2,
+            4,
+            3,
+            TopNTokensLimits::uniform(4),
+            5,
+            106,
+            true,
+            None,
+            vec![],
+            GrammarLimits::default(),
+        )
+        .with_adapters(vec!["support".to_string(), "sql".to_string()]);
+        let request = |adapter_id: Option<&str>, ids: &[&str], weights: &[f32]| GenerateRequest {
+            inputs: "Hello".to_string(),
+            parameters: GenerateParameters {
+                max_new_tokens: Some(5),
+                adapter_id: adapter_id.map(String::from),
+                adapter_parameters: Some(AdapterParameters {
+                    ids: ids.iter().map(|id| id.to_string()).collect(),
+                    weights: weights.to_vec(),
+                }),
+                ..default_parameters()
+            },
+        };
+
+        let blend = validation
+            .validate(request(None, &["support", "sql"], &[0.7, 0.3]))
+            .await
+            .unwrap();
+        let adapter_parameters = blend.adapter_parameters.clone().unwrap();
+        assert_eq!(adapter_parameters.ids, vec!["support", "sql"]);
+        assert_eq!(adapter_parameters.weights, vec![0.7, 0.3]);
+        assert_eq!(blend.adapter_id, "");
+
+        // All the blended adapters stay resident
+        assert_eq!(validation.remove_adapter("sql"), Err(1));
+        drop(blend);
+        assert_eq!(validation.remove_adapter("sql"), Ok(()));
+
+        match validation
+            .validate(request(None, &["support", "sql"], &[0.7, 0.3]))
+            .await
+        {
+            Err(ValidationError::UnknownAdapter(adapter_id, _)) => assert_eq!(adapter_id, "sql"),
+            _ => panic!("Unexpected adapter parameters"),
+        }
+        match validation
+            .validate(request(Some("support"), &["support"], &[1.0]))
+            .await
+        {
+            Err(ValidationError::AdapterParametersWithId) => (),
+            _ => panic!("Unexpected adapter parameters"),
+        }
+        match validation.validate(request(None, &[], &[])).await {
+            Err(ValidationError::EmptyAdapterParameters) => (),
+            _ => panic!("Unexpected adapter parameters"),
+        }
+        match validation
+            .validate(request(None, &["support"], &[0.5, 0.5]))
+            .await
+        {
+            Err(ValidationError::AdapterWeights(1, 2)) => (),
+            _ => panic!("Unexpected adapter parameters"),
+        }
+        match validation
+            .validate(request(None, &["support"], &[f32::NAN]))
+            .await
+        {
+            Err(ValidationError::AdapterWeight) => (),
+            _ => panic!("Unexpected adapter parameters"),
+        }
+    }
+
+    #[test]
+    fn test_encode_extensions() {
+        let extensions = HashMap::from([(
+            "my_kernel".to_string(),
+            serde_json::json!({"enabled": true}),
+        )]);
+        let extensions = encode_extensions(extensions).unwrap();
+        assert_eq!(extensions["my_kernel"], r#"{"enabled":true}"#);
+
+        let extensions = HashMap::from([(
+            "blob".to_string(),
+            Value::String("a".repeat(MAX_EXTENSIONS_SIZE)),
+        )]);
+        match encode_extensions(extensions) {
+            Err(ValidationError::ExtensionsSize(MAX_EXTENSIONS_SIZE, size)) => {
+                assert_eq!(size, "blob".len() + MAX_EXTENSIONS_SIZE + 2)
+            }
+            _ => panic!("Unexpected extensions size"),
+        }
+    }
 }
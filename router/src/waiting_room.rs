@@ -0,0 +1,342 @@
+/// Waiting room smoothing the overload bursts: the non-streamed requests arriving while all the
+/// concurrency permits are taken are accepted with a `202` and a ticket, generated once capacity
+/// frees, and collected by polling the ticket
+use crate::infer::Infer;
+use crate::route_limits::{buffer_body, DEFAULT_MAX_BODY_SIZE};
+use crate::ErrorResponse;
+use axum::body::{Body, Bytes, HttpBody};
+use axum::extract::{Extension, Path, Query};
+use axum::http::header::{CONTENT_TYPE, LOCATION};
+use axum::http::{HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use opentelemetry::trace::FutureExt;
+use opentelemetry::Context;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{watch, OwnedSemaphorePermit};
+use utoipa::ToSchema;
+
+/// Time a finished response waits to be collected
+const RESULT_TTL: Duration = Duration::from_secs(300);
+/// Maximum time a poll waits for the response
+const MAX_POLL_WAIT: u64 = 60;
+
+/// Response of a finished ticket
+#[derive(Debug)]
+struct Finished {
+    status: StatusCode,
+    content_type: Option<HeaderValue>,
+    body: Bytes,
+}
+
+#[derive(Debug)]
+struct Ticket {
+    /// Arrival order of the ticket
+    sequence: u64,
+    result: watch::Receiver<Option<Arc<Finished>>>,
+}
+
+impl Ticket {
+    fn finished(&self) -> Option<Arc<Finished>> {
+        self.result.borrow().clone()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct WaitingRoom {
+    /// Maximum number of tickets, waiting or holding a response not collected yet
+    size: usize,
+    /// Concurrency limit of the generations, for the ETAs
+    max_concurrent_requests: usize,
+    sequence: Arc<AtomicU64>,
+    tickets: Arc<Mutex<HashMap<String, Ticket>>>,
+}
+
+/// Accepted request
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub(crate) struct TicketResponse {
+    #[schema(example = "ticket_5f1c2a4b9e0d7c3a6b8f1e2d4c5a7b9e")]
+    pub ticket_id: String,
+    /// Number of waiting requests ahead of this one
+    #[schema(example = 3)]
+    pub position: usize,
+    /// Estimated time before the response is ready, unknown until requests finished
+    #[schema(nullable = true, example = 4.2)]
+    pub eta_seconds: Option<f64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct PollQuery {
+    /// Seconds to wait for the response before answering that the request is still waiting
+    #[serde(default)]
+    pub wait: u64,
+}
+
+impl WaitingRoom {
+    pub(crate) fn new(size: usize, max_concurrent_requests: usize) -> Self {
+        Self {
+            size,
+            max_concurrent_requests,
+            sequence: Arc::new(AtomicU64::new(0)),
+            tickets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Register a ticket, unless the waiting room is full. The responses not collected yet keep
+    /// their place until they expire: they are buffered in memory
+    fn enter(&self) -> Option<(String, watch::Sender<Option<Arc<Finished>>>)> {
+        let mut tickets = self.tickets.lock().unwrap();
+        if tickets.len() >= self.size {
+            return None;
+        }
+        let waiting = tickets
+            .values()
+            .filter(|ticket| ticket.finished().is_none())
+            .count();
+        let ticket_id = format!("ticket_{:032x}", thread_rng().gen::<u128>());
+        let (sender, result) = watch::channel(None);
+        tickets.insert(
+            ticket_id.clone(),
+            Ticket {
+                sequence: self.sequence.fetch_add(1, Ordering::SeqCst),
+                result,
+            },
+        );
+        metrics::gauge!("tgi_waiting_room_size", (waiting + 1) as f64);
+        Some((ticket_id, sender))
+    }
+
+    /// Position and ETA of a waiting ticket. The requests ahead are admitted
+    /// `max_concurrent_requests` at a time, each taking about the time of a default request
+    fn status(&self, infer: &Infer, ticket_id: &str) -> Option<TicketResponse> {
+        let tickets = self.tickets.lock().unwrap();
+        let sequence = tickets.get(ticket_id)?.sequence;
+        let position = tickets
+            .values()
+            .filter(|ticket| ticket.sequence < sequence && ticket.finished().is_none())
+            .count();
+        let rounds = (position / self.max_concurrent_requests.max(1) + 1) as u32;
+        let eta_seconds = infer
+            .estimated_completion_time(100)
+            .map(|completion_time| (completion_time * rounds).as_secs_f64());
+        Some(TicketResponse {
+            ticket_id: ticket_id.to_string(),
+            position,
+            eta_seconds,
+        })
+    }
+
+    fn finish(&self, sender: watch::Sender<Option<Arc<Finished>>>, finished: Finished) {
+        let _ = sender.send(Some(Arc::new(finished)));
+        let waiting = self
+            .tickets
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|ticket| ticket.finished().is_none())
+            .count();
+        metrics::gauge!("tgi_waiting_room_size", waiting as f64);
+    }
+
+    /// Remove and return the response of a finished ticket
+    fn collect(&self, ticket_id: &str) -> Option<Arc<Finished>> {
+        let mut tickets = self.tickets.lock().unwrap();
+        let finished = tickets.get(ticket_id)?.finished()?;
+        tickets.remove(ticket_id);
+        Some(finished)
+    }
+
+    fn result(&self, ticket_id: &str) -> Option<watch::Receiver<Option<Arc<Finished>>>> {
+        Some(self.tickets.lock().unwrap().get(ticket_id)?.result.clone())
+    }
+}
+
+/// Concurrency permit acquired in the waiting room for a ticket, stored in the OpenTelemetry
+/// context of its generation
+#[derive(Clone, Debug)]
+struct ReservedPermit(Arc<Mutex<Option<OwnedSemaphorePermit>>>);
+
+/// Take the concurrency permit acquired in the waiting room for the current request
+pub(crate) fn take_permit() -> Option<OwnedSemaphorePermit> {
+    Context::current()
+        .get::<ReservedPermit>()?
+        .0
+        .lock()
+        .unwrap()
+        .take()
+}
+
+/// Streamed requests get their `429` right away: their events cannot wait in a ticket
+fn is_stream(body: &Bytes) -> bool {
+    serde_json::from_slice::<serde_json::Value>(body)
+        .ok()
+        .and_then(|request| request.get("stream")?.as_bool())
+        .unwrap_or(false)
+}
+
+/// Middleware accepting the requests arriving over the concurrency limit in the waiting room
+pub(crate) async fn admit(
+    Extension(waiting_room): Extension<WaitingRoom>,
+    Extension(infer): Extension<Infer>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    if infer.has_capacity() {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    let body = match buffer_body(body, DEFAULT_MAX_BODY_SIZE).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+    let request = Request::from_parts(parts, Body::from(body.clone()));
+    if is_stream(&body) {
+        return next.run(request).await;
+    }
+    let Some((ticket_id, sender)) = waiting_room.enter() else {
+        metrics::increment_counter!("tgi_waiting_room_rejected");
+        return next.run(request).await;
+    };
+    metrics::increment_counter!("tgi_waiting_room_admitted");
+    tracing::info!("Request over the concurrency limit waiting as {ticket_id}");
+
+    let status = waiting_room.status(&infer, &ticket_id);
+    let location = format!("/waiting_room/{ticket_id}");
+    // The tenant and the other values of the context follow the request in its task
+    let context = Context::current();
+    tokio::spawn({
+        let ticket_id = ticket_id.clone();
+        async move {
+            // The permit is handed to the generation: released, it could be taken by another
+            // request before the ticket acquires it again
+            let permit = infer.wait_for_capacity().await;
+            let context = context.with_value(ReservedPermit(Arc::new(Mutex::new(Some(permit)))));
+            let response = next.run(request).with_context(context).await;
+            let status = response.status();
+            let content_type = response.headers().get(CONTENT_TYPE).cloned();
+            let mut body = response.into_body();
+            let mut bytes = Vec::new();
+            while let Some(chunk) = body.data().await {
+                match chunk {
+                    Ok(chunk) => bytes.extend_from_slice(&chunk),
+                    Err(err) => {
+                        tracing::error!("Could not read the response of {ticket_id}: {err}");
+                        break;
+                    }
+                }
+            }
+            waiting_room.finish(
+                sender,
+                Finished {
+                    status,
+                    content_type,
+                    body: bytes.into(),
+                },
+            );
+
+            // Forget the responses never collected
+            tokio::time::sleep(RESULT_TTL).await;
+            waiting_room.tickets.lock().unwrap().remove(&ticket_id);
+        }
+    });
+
+    (StatusCode::ACCEPTED, [(LOCATION, location)], Json(status)).into_response()
+}
+
+/// Poll a waiting room ticket
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/waiting_room/{ticket_id}",
+params(
+("ticket_id" = String, Path, description = "Ticket of the accepted request"),
+("wait" = Option<u64>, Query, description = "Seconds, at most 60, to wait for the response before answering that the request is still waiting"),
+),
+responses(
+(status = 200, description = "Response of the request, with its own status"),
+(status = 202, description = "The request is still waiting", body = TicketResponse),
+(status = 404, description = "Unknown or expired ticket", body = ErrorResponse,
+example = json ! ({"error": "Ticket not found", "error_type": "ticket_not_found"})),
+)
+)]
+pub(crate) async fn poll(
+    Extension(waiting_room): Extension<WaitingRoom>,
+    Extension(infer): Extension<Infer>,
+    Path(ticket_id): Path<String>,
+    Query(query): Query<PollQuery>,
+) -> Response {
+    let not_found = || {
+        (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: format!("Ticket `{ticket_id}` not found"),
+                error_type: "ticket_not_found".to_string(),
+            }),
+        )
+            .into_response()
+    };
+
+    // Long poll
+    if query.wait > 0 {
+        let Some(mut result) = waiting_room.result(&ticket_id) else {
+            return not_found();
+        };
+        let wait = Duration::from_secs(query.wait.min(MAX_POLL_WAIT));
+        let _ = tokio::time::timeout(wait, result.wait_for(Option::is_some)).await;
+    }
+
+    if let Some(finished) = waiting_room.collect(&ticket_id) {
+        let mut response = (finished.status, finished.body.clone()).into_response();
+        if let Some(content_type) = finished.content_type.clone() {
+            response.headers_mut().insert(CONTENT_TYPE, content_type);
+        }
+        return response;
+    }
+    match waiting_room.status(&infer, &ticket_id) {
+        Some(status) => (StatusCode::ACCEPTED, Json(status)).into_response(),
+        None => not_found(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_waiting_room() {
+        let waiting_room = WaitingRoom::new(2, 4);
+        let (first, sender) = waiting_room.enter().unwrap();
+        let (second, _second_sender) = waiting_room.enter().unwrap();
+        // Full
+        assert!(waiting_room.enter().is_none());
+        assert!(waiting_room.collect(&first).is_none());
+
+        waiting_room.finish(
+            sender,
+            Finished {
+                status: StatusCode::OK,
+                content_type: None,
+                body: Bytes::from_static(b"{}"),
+            },
+        );
+        // The response not collected yet keeps its place
+        assert!(waiting_room.enter().is_none());
+        assert_eq!(waiting_room.collect(&first).unwrap().status, StatusCode::OK);
+        assert!(waiting_room.collect(&first).is_none());
+        assert!(waiting_room.collect(&second).is_none());
+        // The collected ticket leaves room for another request
+        assert!(waiting_room.enter().is_some());
+        assert!(waiting_room.enter().is_none());
+
+        assert!(is_stream(&Bytes::from_static(b"{\"stream\": true}")));
+        assert!(!is_stream(&Bytes::from_static(b"{\"inputs\": \"Hi\"}")));
+        assert!(!is_stream(&Bytes::from_static(b"not json")));
+    }
+}
@@ -0,0 +1,102 @@
+/// Warnings channel: what the router ignored or adjusted in a request instead of rejecting it,
+/// e.g. an unknown parameter or a capped `max_new_tokens`, returned in the `warnings` of the
+/// responses and in the `x-warnings` header so that the clients notice them during integration
+use axum::http::{HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::trace::FutureExt;
+use opentelemetry::Context;
+use serde::{Deserialize, Deserializer};
+use std::sync::{Arc, Mutex};
+
+/// Header listing the warnings of a request, separated by `; `
+pub(crate) const WARNINGS_HEADER: &str = "x-warnings";
+
+/// Warnings of the request, stored in the current OpenTelemetry context
+#[derive(Clone, Debug, Default)]
+struct Warnings(Arc<Mutex<Vec<String>>>);
+
+/// Middleware collecting the warnings of the request, added to the `x-warnings` header of the
+/// response. The warnings of a stream raised after its headers are in its trailer
+pub(crate) async fn collect<B>(request: Request<B>, next: Next<B>) -> Response {
+    let warnings = Warnings::default();
+    let context = Context::current_with_value(warnings.clone());
+    let mut response = next.run(request).with_context(context).await;
+    if let Some(value) = header_value(&warnings.0.lock().unwrap()) {
+        response.headers_mut().insert(WARNINGS_HEADER, value);
+    }
+    response
+}
+
+/// Record a warning for the current request, once
+pub(crate) fn warn(warning: String) {
+    let context = Context::current();
+    let Some(warnings) = context.get::<Warnings>() else {
+        return;
+    };
+    let mut warnings = warnings.0.lock().unwrap();
+    if !warnings.contains(&warning) {
+        tracing::debug!("Warning: {warning}");
+        metrics::increment_counter!("tgi_request_warning");
+        warnings.push(warning);
+    }
+}
+
+/// Warnings recorded so far for the current request
+pub(crate) fn current() -> Vec<String> {
+    Context::current()
+        .get::<Warnings>()
+        .map(|warnings| warnings.0.lock().unwrap().clone())
+        .unwrap_or_default()
+}
+
+/// Deserialize the parameters of a request. The unknown parameters are ignored with a warning
+pub(crate) fn deserialize_parameters<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    serde_ignored::deserialize(deserializer, |path| {
+        warn(format!("unknown parameter `{path}` was ignored"))
+    })
+}
+
+/// Warnings joined in a header value, the characters not allowed in a header being escaped
+fn header_value(warnings: &[String]) -> Option<HeaderValue> {
+    if warnings.is_empty() {
+        return None;
+    }
+    let value: String = warnings
+        .join("; ")
+        .chars()
+        .flat_map(|c| match c.is_ascii_graphic() || c == ' ' {
+            true => vec![c],
+            false => c.escape_unicode().collect(),
+        })
+        .collect();
+    HeaderValue::from_str(&value).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warnings() {
+        // Outside of a request
+        warn("ignored".to_string());
+        assert!(current().is_empty());
+
+        let _guard = Context::current_with_value(Warnings::default()).attach();
+        warn("`n` is not supported".to_string());
+        warn("unknown parameter `températur`".to_string());
+        warn("`n` is not supported".to_string());
+        let warnings = current();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(
+            header_value(&warnings).unwrap(),
+            "`n` is not supported; unknown parameter `temp\\u{e9}ratur`"
+        );
+        assert_eq!(header_value(&[]), None);
+    }
+}
@@ -26,6 +26,7 @@ def default_pb_request(default_pb_parameters, default_pb_stop_parameters):
         id=0,
         inputs="Test",
         prefill_logprobs=True,
+        skip_special_tokens=True,
         truncate=100,
         parameters=default_pb_parameters,
         stopping_parameters=default_pb_stop_parameters,
@@ -168,6 +169,88 @@ def test_causal_lm_generate_token_completion(
     )
 
 
+def test_causal_lm_shared_prefill(default_causal_lm, default_pb_request, gpt2_tokenizer):
+    requests = []
+    for i in range(3):
+        request = copy(default_pb_request)
+        request.id = i
+        request.prefill_group = 1 if i < 2 else 0
+        requests.append(request)
+    batch_pb = generate_pb2.Batch(id=0, requests=requests, size=3)
+    batch = CausalLMBatch.from_pb(
+        batch_pb, gpt2_tokenizer, torch.float32, torch.device("cpu")
+    )
+    assert batch.prefill_sources == [0, 0, 2]
+
+    generations, next_batch, _ = default_causal_lm.generate_token(batch)
+    assert next_batch.prefill_sources is None
+    assert len({generation.tokens.token_ids[0] for generation in generations}) == 1
+    for layer in next_batch.past_key_values:
+        assert torch.equal(layer[0][0], layer[0][1])
+        assert torch.allclose(layer[0][0], layer[0][2])
+
+
+def test_causal_lm_adapters(
+    default_causal_lm, default_pb_request, gpt2_tokenizer, tmp_path
+):
+    peft = pytest.importorskip("peft")
+    from transformers import AutoModelForCausalLM
+
+    # Random LoRA weights, changing the logits of the model
+    adapter = peft.get_peft_model(
+        AutoModelForCausalLM.from_pretrained("gpt2"),
+        peft.LoraConfig(target_modules=["c_attn"], init_lora_weights=False),
+    )
+    adapter.save_pretrained(tmp_path)
+
+    model = CausalLM("gpt2")
+    model.load_adapters([str(tmp_path)])
+    assert model.info.adapters == [str(tmp_path)]
+
+    requests = []
+    for i, adapter_id in enumerate(["", str(tmp_path)]):
+        request = copy(default_pb_request)
+        request.id = i
+        request.adapter_id = adapter_id
+        requests.append(request)
+    batch_pb = generate_pb2.Batch(id=0, requests=requests, size=2)
+
+    def generate(model):
+        batch = CausalLMBatch.from_pb(
+            batch_pb, gpt2_tokenizer, torch.float32, torch.device("cpu")
+        )
+        generations, _, _ = model.generate_token(batch)
+        return [generation.tokens.logprobs[0] for generation in generations]
+
+    base, adapted = generate(model)
+    expected, _ = generate(default_causal_lm)
+    assert base == pytest.approx(expected)
+    assert adapted != pytest.approx(expected)
+    assert model.adapter_memory(str(tmp_path)) > 0
+
+    # Blends scale the weights of the adapter
+    for request, weight in zip(requests, [0.0, 1.0]):
+        request.adapter_id = ""
+        request.adapter_parameters.ids[:] = [str(tmp_path)]
+        request.adapter_parameters.weights[:] = [weight]
+    batch_pb = generate_pb2.Batch(id=0, requests=requests, size=2)
+    assert generate(model) == pytest.approx([base, adapted], abs=1e-4)
+    assert len(model.blend_names) == 2
+
+    # Evicted at runtime, the adapter rows fall back to the base model
+    model.unload_adapter(str(tmp_path))
+    assert model.info.adapters == []
+    assert model.adapter_memory(str(tmp_path)) == 0
+    assert model.blend_names == {}
+    base, evicted = generate(model)
+    assert evicted == pytest.approx(expected)
+    with pytest.raises(ValueError):
+        model.unload_adapter(str(tmp_path))
+
+    model.load_adapter(str(tmp_path))
+    assert model.adapter_names == {str(tmp_path): "adapter_0"}
+
+
 def test_causal_lm_generate_token_completion_multi(
     default_causal_lm, default_multi_requests_causal_lm_batch
 ):
@@ -9,6 +9,7 @@ from huggingface_hub import hf_api
 
 import text_generation_server.utils.hub
 from text_generation_server.utils.hub import (
+    download_adapter,
     weight_hub_files,
     download_weights,
     weight_files,
@@ -103,3 +104,13 @@ def test_weight_files_revision_error():
 def test_weight_files_not_cached_error(fresh_cache):
     with pytest.raises(LocalEntryNotFoundError):
         weight_files("bert-base-uncased")
+
+
+def test_download_adapter_safetensors_only(tmp_path):
+    (tmp_path / "adapter_config.json").write_text("{}")
+    (tmp_path / "adapter_model.bin").write_bytes(b"")
+    with pytest.raises(ValueError):
+        download_adapter(str(tmp_path))
+
+    (tmp_path / "adapter_model.safetensors").write_bytes(b"")
+    assert download_adapter(str(tmp_path)) == str(tmp_path)
@@ -0,0 +1,69 @@
+import pytest
+import torch
+
+wasmtime = pytest.importorskip("wasmtime")
+
+from text_generation_server.utils.logits_plugin import WasmLogitsPlugin
+
+# Bias token 3 by +10
+BIAS_WAT = """
+(module
+  (memory (export "memory") 1)
+  (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+  (func (export "logits_bias") (param $ptr i32) (param $k i32) (result i32)
+    (local $i i32)
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_s (local.get $i) (local.get $k)))
+        (f32.store
+          (i32.add
+            (local.get $ptr)
+            (i32.mul
+              (i32.add (local.get $i) (i32.mul (local.get $k) (i32.const 2)))
+              (i32.const 4)))
+          (select
+            (f32.const 10)
+            (f32.const 0)
+            (i32.eq
+              (i32.load
+                (i32.add (local.get $ptr) (i32.mul (local.get $i) (i32.const 4))))
+              (i32.const 3))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))
+    (i32.const 0)))
+"""
+
+# Never returns
+LOOP_WAT = """
+(module
+  (memory (export "memory") 1)
+  (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+  (func (export "logits_bias") (param i32) (param i32) (result i32)
+    (loop $forever (br $forever))
+    (i32.const 0)))
+"""
+
+
+def load_plugin(tmp_path, wat: str) -> WasmLogitsPlugin:
+    path = tmp_path / "plugin.wasm"
+    path.write_bytes(wasmtime.wat2wasm(wat))
+    return WasmLogitsPlugin(str(path), top_k=4, fuel=10_000)
+
+
+def test_logits_plugin_bias(tmp_path):
+    plugin = load_plugin(tmp_path, BIAS_WAT)
+    scores = torch.tensor([[0.0, 1.0, 2.0, 3.0, 4.0], [4.0, 3.0, 2.0, 0.0, 1.0]])
+
+    biased = plugin(scores)
+
+    assert biased.argmax(-1).tolist() == [3, 0]
+    assert biased[0, 3] == 13.0
+    # Token 3 is not in the top 4 of the second sequence
+    assert torch.equal(biased[1], scores[1])
+
+
+def test_logits_plugin_out_of_fuel(tmp_path):
+    plugin = load_plugin(tmp_path, LOOP_WAT)
+    scores = torch.tensor([[0.0, 1.0, 2.0, 3.0, 4.0]])
+
+    assert torch.equal(plugin(scores), scores)
@@ -1,9 +1,18 @@
+import math
+import time
 import torch
 from text_generation_server.utils.tokens import (
     StopSequenceCriteria,
     StoppingCriteria,
     FinishReason,
     batch_top_tokens,
+    prefill_top_tokens,
+)
+from text_generation_server.utils.logits_process import (
+    HeterogeneousLogitBiasLogitsProcessor,
+    HeterogeneousSuppressTokensLogitsProcessor,
+    HeterogeneousTemperatureScheduleLogitsWarper,
+    scheduled_temperature,
 )
 
 
@@ -37,6 +46,14 @@ def test_stopping_criteria_eos():
     assert criteria(0, "") == (True, FinishReason.FINISH_REASON_EOS_TOKEN)
 
 
+def test_stopping_criteria_tool_call():
+    criteria = StoppingCriteria(0, [], max_new_tokens=20, stop_after_tool_call=True)
+    assert criteria(1, '{"name": "get') == (False, None)
+    assert criteria(2, '_weather", "arguments": {"city": "}') == (False, None)
+    assert criteria(3, '"}') == (False, None)
+    assert criteria(4, "}") == (True, FinishReason.FINISH_REASON_EOS_TOKEN)
+
+
 def test_stopping_criteria_max():
     criteria = StoppingCriteria(0, [StopSequenceCriteria("/test;")], max_new_tokens=5)
     assert criteria(1, "") == (False, None)
@@ -46,6 +63,37 @@ def test_stopping_criteria_max():
     assert criteria(1, "") == (True, FinishReason.FINISH_REASON_LENGTH)
 
 
+def test_stopping_criteria_deadline():
+    criteria = StoppingCriteria(0, [], max_new_tokens=5, deadline=time.time() + 3600)
+    assert criteria(1, "") == (False, None)
+
+    criteria = StoppingCriteria(0, [], max_new_tokens=5, deadline=time.time() - 1)
+    assert criteria(1, "") == (True, FinishReason.FINISH_REASON_LENGTH)
+
+
+def test_stopping_criteria_eos_probability():
+    criteria = StoppingCriteria(
+        0,
+        [],
+        max_new_tokens=20,
+        eos_probability_threshold=1.0,
+        eos_probability_window=3,
+    )
+    assert criteria(1, "", eos_logprob=math.log(0.5)) == (False, None)
+    assert criteria(1, "", eos_logprob=math.log(0.1)) == (False, None)
+    # 0.5 + 0.1 + 0.2 over the window
+    assert criteria(1, "", eos_logprob=math.log(0.2)) == (False, None)
+    # 0.1 + 0.2 + 0.8
+    assert criteria(1, "", eos_logprob=math.log(0.8)) == (
+        True,
+        FinishReason.FINISH_REASON_EOS_PROBABILITY,
+    )
+
+    # Without threshold the probabilities are ignored
+    criteria = StoppingCriteria(0, [], max_new_tokens=20)
+    assert criteria(1, "", eos_logprob=0.0) == (False, None)
+
+
 def test_batch_top_tokens():
     top_n_tokens = [0, 2, 3, 4, 5]
     top_n_tokens_tensor = torch.tensor(top_n_tokens)
@@ -86,3 +134,83 @@ def test_batch_top_tokens():
     assert topn_tok_logprobs[2] == [[-1, -2, -3, -3]]
     assert topn_tok_logprobs[3] == [[-1, -2, -3, -3]]
     assert topn_tok_logprobs[4] == [[-1, -2, -3, -3, -4]]
+
+
+def test_prefill_top_tokens():
+    prefill_logprobs = torch.tensor(
+        [[-1.0, -3.0, -4.0, -2.0], [-4.0, -1.0, -2.0, -3.0]]
+    )
+
+    top_token_ids, top_token_logprobs = prefill_top_tokens(prefill_logprobs, 2)
+    # The first prompt token has no top tokens
+    assert top_token_ids == [[], [0, 3], [1, 2]]
+    assert top_token_logprobs == [[], [-1.0, -2.0], [-1.0, -2.0]]
+
+    # Capped to the vocabulary size
+    top_token_ids, _ = prefill_top_tokens(prefill_logprobs, 10)
+    assert top_token_ids[1] == [0, 3, 1, 2]
+
+
+def test_suppress_tokens():
+    processor = HeterogeneousSuppressTokensLogitsProcessor([[0, 2], [], [1]], "cpu")
+    scores = processor(None, torch.zeros((3, 4)))
+    assert torch.isinf(scores).tolist() == [
+        [True, False, True, False],
+        [False, False, False, False],
+        [False, True, False, False],
+    ]
+
+    # The requests without suppressed tokens are left untouched
+    assert processor.filter([1]) is None
+    processor = processor.filter([2, 0])
+    scores = processor(None, torch.zeros((2, 4)))
+    assert torch.isinf(scores).tolist() == [
+        [False, True, False, False],
+        [True, False, True, False],
+    ]
+
+
+def test_logit_bias():
+    processor = HeterogeneousLogitBiasLogitsProcessor(
+        [{0: -100.0, 3: 2.5}, {}, {1: 1.0}], torch.float32, "cpu"
+    )
+    scores = processor(None, torch.zeros((3, 4)))
+    assert scores.tolist() == [
+        [-100.0, 0.0, 0.0, 2.5],
+        [0.0, 0.0, 0.0, 0.0],
+        [0.0, 1.0, 0.0, 0.0],
+    ]
+
+    # The requests without bias are left untouched
+    assert processor.filter([1]) is None
+    processor = processor.filter([2, 0])
+    scores = processor(None, torch.zeros((2, 4)))
+    assert scores.tolist() == [
+        [0.0, 1.0, 0.0, 0.0],
+        [-100.0, 0.0, 0.0, 2.5],
+    ]
+
+
+def test_scheduled_temperature():
+    schedule = [(0, 1.0), (10, 0.5)]
+    assert scheduled_temperature(schedule, 0) == 1.0
+    assert scheduled_temperature(schedule, 5) == 0.75
+    assert scheduled_temperature(schedule, 10) == 0.5
+    assert scheduled_temperature(schedule, 100) == 0.5
+
+
+def test_temperature_schedule():
+    warper = HeterogeneousTemperatureScheduleLogitsWarper(
+        [[(0, 1.0), (10, 0.5)], []], [5, 0], torch.float32, "cpu"
+    )
+    scores = warper(torch.full((2, 2), 3.0))
+    assert scores.tolist() == [[4.0, 4.0], [3.0, 3.0]]
+
+    # Speculated tokens are scored further along the schedule
+    warper.advance([5, 1])
+    assert warper.steps == [10, 1]
+    scores = warper(torch.full((2, 2), 3.0), 1)
+    assert scores.tolist() == [[6.0, 6.0], [3.0, 3.0]]
+
+    # The requests without schedule are left untouched
+    assert warper.filter([1]) is None
@@ -5,7 +5,14 @@ from google.rpc import status_pb2, code_pb2
 from grpc_status import rpc_status
 from grpc_interceptor.server import AsyncServerInterceptor
 from loguru import logger
-from typing import Callable, Any
+from typing import Callable, Any, Optional
+
+# Last error raised by a gRPC method, reported by the health check
+_last_error: Optional[str] = None
+
+
+def get_last_error() -> Optional[str]:
+    return _last_error
 
 
 class ExceptionInterceptor(AsyncServerInterceptor):
@@ -20,8 +27,10 @@ class ExceptionInterceptor(AsyncServerInterceptor):
             response = method(request_or_iterator, context)
             return await response
         except Exception as err:
+            global _last_error
             method_name = method_name.split("/")[-1]
             logger.exception(f"Method {method_name} encountered an error.")
+            _last_error = f"{method_name}: {err}"
 
             if torch.cuda.is_available():
                 torch.cuda.empty_cache()
@@ -7,7 +7,11 @@ from huggingface_hub import hf_hub_download
 from typing import Optional
 from pathlib import Path
 
-from text_generation_server.utils.speculate import get_speculate, set_speculate
+from text_generation_server.utils.speculate import (
+    get_speculate,
+    set_speculate,
+    set_speculator,
+)
 from text_generation_server.models.model import Model
 from text_generation_server.models.causal_lm import CausalLM
 from text_generation_server.models.flash_causal_lm import FlashCausalLM
@@ -168,6 +172,7 @@ def get_model(
     speculate = get_speculate()
     if speculate > 0:
         logger.info(f"Using speculation {method} with {speculate} input ids.")
+        set_speculator(medusa_model_id if use_medusa is not None else method)
 
     model_type = config_dict.get("model_type", None)
     if model_type is None:
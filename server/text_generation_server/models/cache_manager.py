@@ -133,3 +133,11 @@ def get_cache_manager() -> CacheManager:
         raise RuntimeError("cache manager was not initialized")
 
     return CACHE_MANAGER
+
+
+def get_memory_pressure() -> Optional[float]:
+    # Fraction of the paged attention blocks currently allocated
+    if CACHE_MANAGER is None:
+        return None
+    free_blocks = int(CACHE_MANAGER.free_block_mask.sum())
+    return 1.0 - free_blocks / CACHE_MANAGER.num_blocks
@@ -1,13 +1,15 @@
+import itertools
 import torch
 import time
 
 from dataclasses import dataclass
+from loguru import logger
 from opentelemetry import trace
 from transformers import AutoTokenizer, AutoModelForCausalLM, PreTrainedTokenizerBase
-from typing import Optional, Tuple, List, Type, Dict
+from typing import Optional, Tuple, List, Type, Dict, Set
 
 from text_generation_server.models import Model
-from text_generation_server.utils.tokens import batch_top_tokens
+from text_generation_server.utils.tokens import batch_top_tokens, prefill_top_tokens
 from text_generation_server.models.types import (
     Batch,
     Tokens,
@@ -19,6 +21,9 @@ from text_generation_server.utils import NextTokenChooser, StoppingCriteria, Sam
 
 tracer = trace.get_tracer(__name__)
 
+# Blends of adapters kept next to the loaded adapters
+MAX_ADAPTER_BLENDS = 8
+
 
 @dataclass
 class CausalLMBatch(Batch):
@@ -56,6 +61,9 @@ class CausalLMBatch(Batch):
     # Past metadata
     keys_head_dim_last: bool = True
 
+    # Row prefilled for each request, set when requests share their prefill
+    prefill_sources: Optional[List[int]] = None
+
     def to_pb(self) -> generate_pb2.CachedBatch:
         return generate_pb2.CachedBatch(
             id=self.batch_id,
@@ -79,6 +87,8 @@ class CausalLMBatch(Batch):
         prefix_offsets = []
         read_offsets = []
         requests_idx_mapping = {}
+        prefill_sources = []
+        group_sources = {}
 
         # Parse batch
         max_truncation = 0
@@ -86,6 +96,10 @@ class CausalLMBatch(Batch):
         max_decode_tokens = 0
         for i, r in enumerate(pb.requests):
             requests_idx_mapping[r.id] = i
+            if r.prefill_group:
+                prefill_sources.append(group_sources.setdefault(r.prefill_group, i))
+            else:
+                prefill_sources.append(i)
             inputs.append(r.inputs)
             next_token_choosers.append(
                 NextTokenChooser.from_pb(r.parameters, device, tokenizer)
@@ -153,6 +167,9 @@ class CausalLMBatch(Batch):
             max_input_length=max_input_length.item(),
             padding_right_offset=padding_right_offset,
             max_tokens=max_tokens,
+            prefill_sources=(
+                prefill_sources if len(group_sources) < len(pb.requests) else None
+            ),
         )
 
     @tracer.start_as_current_span("filter")
@@ -477,6 +494,12 @@ class CausalLMBatch(Batch):
 
 
 class CausalLM(Model):
+    shared_prefill = True
+    # peft name of each loaded adapter
+    adapter_names: Dict[str, str] = {}
+    # peft name of each blend, by adapters and weights, the most recently used last
+    blend_names: Dict[Tuple[Tuple[str, float], ...], str] = {}
+
     def __init__(
         self,
         model_id: str,
@@ -552,8 +575,128 @@ class CausalLM(Model):
             generated_ids, skip_special_tokens=True, clean_up_tokenization_spaces=False
         )
 
+    def load_adapter(self, adapter_id: str, adapter_path: Optional[str] = None):
+        # The custom implementations are not `transformers` models peft can wrap
+        if type(self) is not CausalLM:
+            return super(CausalLM, self).load_adapter(adapter_id, adapter_path)
+        if adapter_id in self.adapter_names:
+            return
+
+        from peft import PeftModel
+
+        # Adapter names can not contain dots: the adapters are named by the first free index
+        names = set(self.adapter_names.values())
+        adapter_name = next(
+            f"adapter_{i}" for i in itertools.count() if f"adapter_{i}" not in names
+        )
+        logger.info(f"Loading LoRA adapter {adapter_id}")
+        # Downloaded beforehand out of the event loop when loaded at runtime
+        adapter_path = adapter_path or adapter_id
+        if isinstance(self.model, PeftModel):
+            self.model.load_adapter(adapter_path, adapter_name=adapter_name)
+        else:
+            self.model = PeftModel.from_pretrained(
+                self.model, adapter_path, adapter_name=adapter_name
+            )
+        self.model.eval()
+        self.adapter_names = {**self.adapter_names, adapter_id: adapter_name}
+        self.adapters = self.adapters + [adapter_id]
+
+    def unload_adapter(self, adapter_id: str):
+        if adapter_id not in self.adapter_names:
+            return super(CausalLM, self).unload_adapter(adapter_id)
+
+        logger.info(f"Unloading LoRA adapter {adapter_id}")
+        self.delete_blends(
+            [key for key in self.blend_names if adapter_id in dict(key)]
+        )
+        self.model.base_model.delete_adapter(self.adapter_names[adapter_id])
+        self.adapter_names = {
+            loaded: name
+            for loaded, name in self.adapter_names.items()
+            if loaded != adapter_id
+        }
+        self.adapters = [loaded for loaded in self.adapters if loaded != adapter_id]
+        if torch.cuda.is_available():
+            torch.cuda.empty_cache()
+
+    def adapter_memory(self, adapter_id: str) -> int:
+        adapter_name = self.adapter_names.get(adapter_id)
+        if adapter_name is None:
+            return 0
+        # e.g. `...lora_A.adapter_0.weight` or `...lora_embedding_A.adapter_0`
+        return sum(
+            parameter.numel() * parameter.element_size()
+            for name, parameter in self.model.named_parameters()
+            if adapter_name in name.split(".")
+        )
+
+    def blend_adapter(
+        self,
+        key: Tuple[Tuple[str, float], ...],
+        in_use: Set[Tuple[Tuple[str, float], ...]],
+    ) -> str:
+        """peft name of a weighted blend of loaded adapters, created on its first use.
+        The least recently used blends over `MAX_ADAPTER_BLENDS` are deleted, except the
+        blends `in_use` by the batch: a deleted blend is created again by its next batch
+        """
+        adapter_name = self.blend_names.get(key)
+        if adapter_name is None:
+            names = set(self.blend_names.values())
+            adapter_name = next(
+                f"blend_{i}" for i in itertools.count() if f"blend_{i}" not in names
+            )
+            # The concatenation of the LoRA matrices blends adapters of any rank
+            self.model.add_weighted_adapter(
+                [self.adapter_names[adapter_id] for adapter_id, _ in key],
+                [weight for _, weight in key],
+                adapter_name=adapter_name,
+                combination_type="cat",
+            )
+            self.model.eval()
+        self.blend_names = {
+            **{k: name for k, name in self.blend_names.items() if k != key},
+            key: adapter_name,
+        }
+        unused = [k for k in self.blend_names if k not in in_use]
+        self.delete_blends(unused[: max(len(self.blend_names) - MAX_ADAPTER_BLENDS, 0)])
+        return adapter_name
+
+    def delete_blends(self, keys: List[Tuple[Tuple[str, float], ...]]):
+        for key in keys:
+            self.model.base_model.delete_adapter(self.blend_names[key])
+        self.blend_names = {
+            key: name for key, name in self.blend_names.items() if key not in keys
+        }
+
+    def batch_adapter_names(self, batch: CausalLMBatch) -> List[str]:
+        """Adapter of each row of the batch, `__base__` for the base model"""
+        keys = [
+            tuple(zip(r.adapter_parameters.ids, r.adapter_parameters.weights))
+            for r in batch.requests
+        ]
+        # The blends of evicted adapters fall back to the base model
+        keys = [
+            key if all(a in self.adapter_names for a, _ in key) else ()
+            for key in keys
+        ]
+        in_use = {key for key in keys if key}
+        return [
+            (
+                self.blend_adapter(key, in_use)
+                if key
+                else self.adapter_names.get(r.adapter_id, "__base__")
+            )
+            for r, key in zip(batch.requests, keys)
+        ]
+
     def forward(
-        self, input_ids, attention_mask, position_ids, past_key_values: Optional = None
+        self,
+        input_ids,
+        attention_mask,
+        position_ids,
+        past_key_values: Optional = None,
+        adapter_names: Optional[List[str]] = None,
     ) -> Tuple[
         torch.Tensor, Optional[torch.Tensor], List[Tuple[torch.Tensor, torch.Tensor]]
     ]:
@@ -567,6 +710,9 @@ class CausalLM(Model):
         }
         if self.has_position_ids:
             kwargs["position_ids"] = position_ids
+        # Rows of different adapters are generated in the same forward
+        if adapter_names is not None:
+            kwargs["adapter_names"] = adapter_names
 
         outputs = self.model.forward(**kwargs)
         if isinstance(outputs, tuple):
@@ -575,6 +721,41 @@ class CausalLM(Model):
             speculative_logits = None
         return outputs.logits, speculative_logits, outputs.past_key_values
 
+    def shared_prefill_forward(
+        self, batch: CausalLMBatch, attention_mask: torch.Tensor
+    ) -> Tuple[
+        torch.Tensor, Optional[torch.Tensor], List[Tuple[torch.Tensor, torch.Tensor]]
+    ]:
+        """Prefill the distinct rows of the batch once and fork their cache for the requests of their group"""
+        sources = sorted(set(batch.prefill_sources))
+        rows = torch.tensor(sources, device=batch.input_ids.device)
+        fork = torch.tensor(
+            [sources.index(source) for source in batch.prefill_sources],
+            device=batch.input_ids.device,
+        )
+        kwargs = {}
+        if self.adapters:
+            adapter_names = self.batch_adapter_names(batch)
+            kwargs["adapter_names"] = [adapter_names[source] for source in sources]
+        logits, speculative_logits, past = self.forward(
+            batch.input_ids[rows],
+            attention_mask[rows],
+            batch.position_ids[rows],
+            **kwargs,
+        )
+        batch.prefill_sources = None
+
+        def fork_rows(tensor: torch.Tensor) -> torch.Tensor:
+            # Past tensors can be of dim [batch_size * num_heads, ...]
+            rows = tensor.reshape(len(sources), -1, *tensor.shape[1:])
+            return rows[fork].reshape(-1, *tensor.shape[1:])
+
+        logits = logits[fork]
+        if speculative_logits is not None:
+            speculative_logits = speculative_logits[fork]
+        past = [tuple(fork_rows(tensor) for tensor in layer) for layer in past]
+        return logits, speculative_logits, past
+
     @tracer.start_as_current_span("generate_token")
     def generate_token(
         self, batch: CausalLMBatch
@@ -583,12 +764,22 @@ class CausalLM(Model):
         # slice the attention mask to the correct shape
         attention_mask = batch.attention_mask[:, : -batch.padding_right_offset]
 
-        logits, speculative_logits, past = self.forward(
-            batch.input_ids,
-            attention_mask,
-            batch.position_ids,
-            batch.past_key_values,
-        )
+        if batch.past_key_values is None and batch.prefill_sources is not None:
+            logits, speculative_logits, past = self.shared_prefill_forward(
+                batch, attention_mask
+            )
+        else:
+            # Only the models with adapters take the adapter of each row
+            kwargs = {}
+            if self.adapters:
+                kwargs["adapter_names"] = self.batch_adapter_names(batch)
+            logits, speculative_logits, past = self.forward(
+                batch.input_ids,
+                attention_mask,
+                batch.position_ids,
+                batch.past_key_values,
+                **kwargs,
+            )
 
         # Results
         generations: List[Generation] = []
@@ -634,6 +825,14 @@ class CausalLM(Model):
             top_token_ids,
             top_token_logprobs,
         ) in enumerate(iterator):
+            # Probability of the end of sequence token in the distribution of the model,
+            # before the processors and warpers mask it or modify the logits in place
+            eos_logprob = (
+                torch.log_softmax(logits[-1], -1)[stopping_criteria.eos_token_id].item()
+                if stopping_criteria.tracks_eos_probability
+                else None
+            )
+
             # Select next token
             next_token_id, logprobs = next_token_chooser(
                 all_input_ids.view(1, -1), logits[-1:, :]
@@ -654,6 +853,7 @@ class CausalLM(Model):
             stop, reason = stopping_criteria(
                 next_token_id_squeezed,
                 next_token_text,
+                eos_logprob,
             )
 
             if not stop:
@@ -671,7 +871,7 @@ class CausalLM(Model):
                         - 1,
                         read_offset=len(all_input_ids)
                         - stopping_criteria.current_tokens,
-                        skip_special_tokens=True,
+                        skip_special_tokens=request.skip_special_tokens,
                     )
                     # Get seed
                     if isinstance(next_token_chooser.choice, Sampling):
@@ -687,12 +887,11 @@ class CausalLM(Model):
 
                 # Prefill
                 if stopping_criteria.current_tokens == 1 and request.prefill_logprobs:
+                    prefill_logprobs_tensor = torch.log_softmax(logits, -1)
                     # Remove generated token to only have prefill and add nan for first prompt token
-                    prefill_logprobs = [float("nan")] + torch.log_softmax(
-                        logits, -1
-                    ).gather(1, all_input_ids[1:]).squeeze(1)[
-                        -new_input_length:-1
-                    ].tolist()
+                    prefill_logprobs = [float("nan")] + prefill_logprobs_tensor.gather(
+                        1, all_input_ids[1:]
+                    ).squeeze(1)[-new_input_length:-1].tolist()
                     prefill_token_ids = all_input_ids[-new_input_length:-1]
                     prefill_texts = self.tokenizer.batch_decode(
                         prefill_token_ids,
@@ -705,8 +904,19 @@ class CausalLM(Model):
                         prefill_texts,
                         is_special=[],
                     )
+
+                    if top_n_tokens > 0:
+                        request_prefill_top_tokens = self.decode_top_tokens(
+                            *prefill_top_tokens(
+                                prefill_logprobs_tensor[-new_input_length:-1],
+                                top_n_tokens,
+                            )
+                        )
+                    else:
+                        request_prefill_top_tokens = None
                 else:
                     prefill_tokens = None
+                    request_prefill_top_tokens = None
 
                 if top_n_tokens > 0:
                     all_top_tokens = []
@@ -744,6 +954,7 @@ class CausalLM(Model):
                     ),
                     generated_text,
                     top_tokens,
+                    request_prefill_top_tokens,
                 )
 
                 generations.append(generation)
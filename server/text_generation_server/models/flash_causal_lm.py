@@ -14,7 +14,7 @@ from transformers import PreTrainedTokenizerBase
 from typing import Optional, Tuple, List, Type, Dict
 
 from text_generation_server.models import Model
-from text_generation_server.utils.tokens import batch_top_tokens
+from text_generation_server.utils.tokens import batch_top_tokens, prefill_top_tokens
 from text_generation_server.utils.speculate import get_speculate
 from text_generation_server.models.types import (
     Batch,
@@ -531,6 +531,7 @@ class FlashCausalLMBatch(Batch):
 
         next_token_chooser_parameters = []
         fsm_grammar_states = []
+        temperature_steps = []
         stopping_criterias = []
         top_n_tokens = []
 
@@ -580,6 +581,7 @@ class FlashCausalLMBatch(Batch):
 
             next_token_chooser_parameters.extend([r.parameters for r in batch.requests])
             fsm_grammar_states.extend(batch.next_token_chooser.fsm_grammar_states)
+            temperature_steps.extend(batch.next_token_chooser.temperature_steps)
             stopping_criterias.extend(batch.stopping_criterias)
 
             top_n_tokens.extend(batch.top_n_tokens)
@@ -596,6 +598,7 @@ class FlashCausalLMBatch(Batch):
             device=batches[0].next_token_chooser.device,
             tokenizer=batches[0].next_token_chooser.tokenizer,
             fsm_grammar_states=fsm_grammar_states,
+            temperature_steps=temperature_steps,
         )
 
         speculative_ids = (
@@ -956,6 +959,34 @@ class FlashCausalLM(Model):
         else:
             next_token_logits = out
 
+        # Probabilities of the end of sequence token of each request in the distribution
+        # of the model, before the processors and warpers mask it or modify the logits in
+        # place. Only computed for the batches with requests stopping on them
+        eos_logprobs = None
+        if any(
+            stopping_criteria.tracks_eos_probability
+            for stopping_criteria in batch.stopping_criterias
+        ):
+            eos_token_ids = torch.tensor(
+                [
+                    (
+                        stopping_criteria.eos_token_id
+                        if stopping_criteria.eos_token_id is not None
+                        else 0
+                    )
+                    for stopping_criteria in batch.stopping_criterias
+                ],
+                device=next_token_logits.device,
+            )
+            # One row per speculated token of each request
+            rows_per_request = next_token_logits.shape[0] // len(batch)
+            eos_logprobs = (
+                next_token_logits.gather(
+                    1, eos_token_ids.repeat_interleave(rows_per_request).unsqueeze(1)
+                ).float()
+                - torch.logsumexp(next_token_logits.float(), -1, keepdim=True)
+            ).view(len(batch), rows_per_request)
+
         speculate = get_speculate()
         (
             next_input_ids,
@@ -1056,6 +1087,8 @@ class FlashCausalLM(Model):
             prefill_logprobs = prefill_logprobs.view(-1).tolist()
 
         # GPU <-> CPU sync
+        if eos_logprobs is not None:
+            eos_logprobs = eos_logprobs.tolist()
         next_token_logprobs = next_token_logprobs.tolist()
         next_token_ids = next_input_ids.tolist()
         accepted_ids = accepted_ids.tolist()
@@ -1112,6 +1145,7 @@ class FlashCausalLM(Model):
                 stop, reason = stopping_criteria(
                     next_token_id,
                     next_token_text,
+                    eos_logprobs[i][j - index] if eos_logprobs is not None else None,
                 )
 
                 if stop:
@@ -1140,7 +1174,7 @@ class FlashCausalLM(Model):
                         - 1,
                         read_offset=len(all_input_ids)
                         - stopping_criteria.current_tokens,
-                        skip_special_tokens=True,
+                        skip_special_tokens=request.skip_special_tokens,
                     )
                     generated_text = GeneratedText(
                         output_text,
@@ -1173,8 +1207,21 @@ class FlashCausalLM(Model):
                         prefill_texts,
                         is_special=[],
                     )
+
+                    if top_n_tokens > 0:
+                        request_prefill_top_tokens = self.decode_top_tokens(
+                            *prefill_top_tokens(
+                                prefill_logprobs_tensor[
+                                    out_start_index : out_end_index - 1
+                                ],
+                                top_n_tokens,
+                            )
+                        )
+                    else:
+                        request_prefill_top_tokens = None
                 else:
                     prefill_tokens = None
+                    request_prefill_top_tokens = None
 
                 if top_n_tokens > 0:
                     all_top_tokens = []
@@ -1212,6 +1259,7 @@ class FlashCausalLM(Model):
                     ),
                     generated_text,
                     top_tokens,
+                    request_prefill_top_tokens,
                 )
 
                 generations.append(generation)
@@ -769,7 +769,7 @@ class IdeficsCausalLM(Model):
                         - 1,
                         read_offset=len(all_input_ids)
                         - stopping_criteria.current_tokens,
-                        skip_special_tokens=True,
+                        skip_special_tokens=request.skip_special_tokens,
                     )
                     # Get seed
                     if isinstance(next_token_chooser.choice, Sampling):
@@ -5,14 +5,19 @@ from abc import ABC, abstractmethod
 from typing import List, Tuple, Optional, TypeVar, Type
 from transformers import PreTrainedTokenizerBase, PretrainedConfig
 
-from text_generation_server.models.types import Batch, Generation
-from text_generation_server.utils.speculate import get_speculate
+from text_generation_server.models.types import Batch, Generation, Tokens
+from text_generation_server.utils.speculate import get_speculate, get_speculator
 from text_generation_server.pb.generate_pb2 import InfoResponse
 
 B = TypeVar("B", bound=Batch)
 
 
 class Model(ABC):
+    # Whether the requests of a prefill group are prefilled once
+    shared_prefill = False
+    # LoRA adapters selected by the `adapter_id` of the requests
+    adapters: List[str] = []
+
     def __init__(
         self,
         model: torch.nn.Module,
@@ -28,6 +33,10 @@ class Model(ABC):
         self.model = model.eval()
         self.tokenizer = tokenizer
         self.all_special_ids = set(tokenizer.all_special_ids)
+        # Added tokens flagged as special (e.g. tool call sentinels) are not always part of `all_special_ids`
+        for token_id, token in getattr(tokenizer, "added_tokens_decoder", {}).items():
+            if getattr(token, "special", False):
+                self.all_special_ids.add(token_id)
         self.requires_padding = requires_padding
         self.dtype = dtype
         self.device = device
@@ -57,8 +66,27 @@ class Model(ABC):
             device_type=self.device.type,
             window_size=self.sliding_window,
             speculate=self.speculate,
+            speculator=get_speculator() if self.speculate > 0 else None,
+            shared_prefill=self.shared_prefill,
+            adapters=self.adapters,
+        )
+
+    def load_adapters(self, adapter_ids: List[str]):
+        for adapter_id in adapter_ids:
+            self.load_adapter(adapter_id)
+
+    def load_adapter(self, adapter_id: str, adapter_path: Optional[str] = None):
+        raise ValueError(
+            f"LoRA adapters are not supported by {self.__class__.__name__}"
         )
 
+    def unload_adapter(self, adapter_id: str):
+        raise ValueError(f"LoRA adapter {adapter_id} is not loaded")
+
+    def adapter_memory(self, adapter_id: str) -> int:
+        """Memory of the weights of a loaded adapter, in bytes"""
+        return 0
+
     @property
     @abstractmethod
     def batch_type(self) -> Type[B]:
@@ -103,6 +131,21 @@ class Model(ABC):
         else:
             return "", prefix_offset, read_offset
 
+    def decode_top_tokens(
+        self, top_token_ids: List[List[int]], top_token_logprobs: List[List[float]]
+    ) -> List[Tokens]:
+        """Texts of the top tokens of each position"""
+        all_top_tokens = []
+        for token_ids, logprobs in zip(top_token_ids, top_token_logprobs):
+            texts = self.tokenizer.batch_decode(
+                token_ids,
+                clean_up_tokenization_spaces=False,
+                skip_special_tokens=False,
+            )
+            special = [token_id in self.all_special_ids for token_id in token_ids]
+            all_top_tokens.append(Tokens(token_ids, logprobs, texts, special))
+        return all_top_tokens
+
     def check_initialized(self):
         uninitialized_parameters = []
         for n, p in self.model.named_parameters():
@@ -738,7 +738,7 @@ class Seq2SeqLM(Model):
                         - decoder_input_length
                         - 1,
                         read_offset=len(all_decoder_input_ids) - decoder_input_length,
-                        skip_special_tokens=True,
+                        skip_special_tokens=request.skip_special_tokens,
                     )
 
                     # Get seed
@@ -84,6 +84,8 @@ class Generation:
     generated_text: Optional[GeneratedText]
     # Optional for now, since it's not yet supported for every model.
     top_tokens: Optional[List[Tokens]]
+    # Top tokens of the prompt positions, with `top_n_tokens` and `prefill_logprobs`
+    prefill_top_tokens: Optional[List[Tokens]] = None
 
     def to_pb(self) -> generate_pb2.Generation:
         return generate_pb2.Generation(
@@ -100,4 +102,9 @@ class Generation:
                 if self.top_tokens is not None
                 else None
             ),
+            prefill_top_tokens=(
+                [top_tokens.to_pb() for top_tokens in self.prefill_top_tokens]
+                if self.prefill_top_tokens is not None
+                else None
+            ),
         )
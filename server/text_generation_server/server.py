@@ -11,10 +11,13 @@ from pathlib import Path
 from typing import List, Optional
 
 from text_generation_server.cache import Cache
-from text_generation_server.interceptor import ExceptionInterceptor
+from text_generation_server.interceptor import ExceptionInterceptor, get_last_error
 from text_generation_server.models import Model, get_model
+from text_generation_server.models.cache_manager import get_memory_pressure
 from text_generation_server.pb import generate_pb2_grpc, generate_pb2
 from text_generation_server.tracing import UDSOpenTelemetryAioServerInterceptor
+from text_generation_server.utils.hub import download_adapter
+from text_generation_server.utils.logits_process import pop_grammar_compile_ns
 from text_generation_server.models.idefics_causal_lm import IdeficsCausalLMBatch
 
 
@@ -30,6 +33,7 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
         self.model = model
         self.quantize = quantize
         self.server_urls = server_urls
+        self.start_time = int(time.time())
         # For some reason, inference_mode does not work well with GLOO which we use on CPU
         if model.device.type == "cuda":
             # Force inference mode for the lifetime of TextGenerationService
@@ -41,7 +45,44 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
     async def Health(self, request, context):
         if self.model.device.type == "cuda":
             torch.zeros((2, 2)).cuda()
-        return generate_pb2.HealthResponse()
+        response = generate_pb2.HealthResponse(
+            rank=self.model.rank,
+            start_time=self.start_time,
+        )
+        memory_pressure = get_memory_pressure()
+        if memory_pressure is not None:
+            response.memory_pressure = memory_pressure
+        last_error = get_last_error()
+        if last_error is not None:
+            response.last_error = last_error
+        return response
+
+    def adapters_response(self):
+        return generate_pb2.AdaptersResponse(
+            adapters=[
+                generate_pb2.AdapterInfo(
+                    adapter_id=adapter_id,
+                    memory_bytes=self.model.adapter_memory(adapter_id),
+                )
+                for adapter_id in self.model.adapters
+            ]
+        )
+
+    async def Adapters(self, request, context):
+        return self.adapters_response()
+
+    async def LoadAdapter(self, request, context):
+        # The hub download must not block the generation of the running batches: only
+        # the injection of the downloaded weights runs on the event loop
+        adapter_path = await asyncio.get_running_loop().run_in_executor(
+            None, download_adapter, request.adapter_id
+        )
+        self.model.load_adapter(request.adapter_id, adapter_path)
+        return self.adapters_response()
+
+    async def UnloadAdapter(self, request, context):
+        self.model.unload_adapter(request.adapter_id)
+        return self.adapters_response()
 
     async def ServiceDiscovery(self, request, context):
         return generate_pb2.ServiceDiscoveryResponse(urls=self.server_urls)
@@ -121,6 +162,7 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
         return generate_pb2.PrefillResponse(
             generations=[generation.to_pb() for generation in generations],
             batch=next_batch.to_pb() if next_batch else None,
+            grammar_compile_ns=pop_grammar_compile_ns(),
             forward_ns=timings[0],
             decode_ns=timings[1],
             total_ns=time.time_ns() - start,
@@ -202,6 +244,9 @@ def serve(
                 dtype,
                 trust_remote_code,
             )
+            lora_adapters = os.getenv("LORA_ADAPTERS")
+            if lora_adapters:
+                model.load_adapters(lora_adapters.split(","))
         except Exception:
             logger.exception("Error when initializing model")
             raise
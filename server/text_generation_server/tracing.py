@@ -1,5 +1,7 @@
 import grpc
 
+from urllib.parse import unquote
+
 from opentelemetry import trace
 from opentelemetry.exporter.otlp.proto.grpc.trace_exporter import OTLPSpanExporter
 from opentelemetry.instrumentation.grpc._aio_server import (
@@ -43,6 +45,13 @@ class UDSOpenTelemetryAioServerInterceptor(OpenTelemetryAioServerInterceptor):
         if "user-agent" in metadata:
             attributes["rpc.user_agent"] = metadata["user-agent"]
 
+        # W3C baggage forwarded by the router (tenant, experiment id...)
+        if "baggage" in metadata:
+            for member in metadata["baggage"].split(","):
+                key, _, value = member.split(";", 1)[0].partition("=")
+                if key.strip() and value.strip():
+                    attributes[f"baggage.{key.strip()}"] = unquote(value.strip())
+
         # We use gRPC over a UNIX socket
         attributes.update({SpanAttributes.NET_TRANSPORT: "unix"})
 
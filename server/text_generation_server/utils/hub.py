@@ -6,7 +6,13 @@ from loguru import logger
 from pathlib import Path
 from typing import Optional, List
 
-from huggingface_hub import file_download, hf_api, HfApi, hf_hub_download
+from huggingface_hub import (
+    file_download,
+    hf_api,
+    HfApi,
+    hf_hub_download,
+    snapshot_download,
+)
 from huggingface_hub.constants import HUGGINGFACE_HUB_CACHE
 from huggingface_hub.utils import (
     LocalEntryNotFoundError,
@@ -235,3 +241,20 @@ def download_weights(
         files.append(file)
 
     return files
+
+
+# Only the safetensors weights of the adapters are loaded: unpickling `adapter_model.bin`
+# could run arbitrary code on the shards
+ADAPTER_FILES = ["adapter_config.json", "adapter_model.safetensors"]
+
+
+def download_adapter(adapter_id: str) -> str:
+    """Local directory of a LoRA adapter, downloaded from the hub if needed"""
+    if Path(adapter_id).is_dir():
+        adapter_path = adapter_id
+    else:
+        adapter_path = snapshot_download(adapter_id, allow_patterns=ADAPTER_FILES)
+    for filename in ADAPTER_FILES:
+        if not (Path(adapter_path) / filename).is_file():
+            raise ValueError(f"LoRA adapter {adapter_id} has no {filename}")
+    return adapter_path
@@ -0,0 +1,86 @@
+import os
+import struct
+
+import torch
+
+from functools import lru_cache
+from loguru import logger
+from typing import List, Optional
+
+LOGITS_PLUGIN_PATH = os.getenv("LOGITS_PLUGIN_PATH", None)
+LOGITS_PLUGIN_TOP_K = int(os.getenv("LOGITS_PLUGIN_TOP_K", "32"))
+LOGITS_PLUGIN_FUEL = int(os.getenv("LOGITS_PLUGIN_FUEL", "1000000"))
+
+
+class WasmLogitsPlugin:
+    """
+    Logits bias callback implemented by a user supplied WASM module.
+
+    The module must export:
+        - `memory`
+        - `alloc(size: i32) -> i32`, returning a buffer of `size` bytes
+        - `logits_bias(ptr: i32, k: i32) -> i32`, reading `k` token ids (i32) at `ptr`
+          and their `k` logits (f32) right after, writing `k` biases (f32) after the
+          logits and returning 0 on success
+
+    The module has no imports: it cannot reach the host. Each call is bounded by a fuel
+    budget (a number of WASM instructions) rather than by a wall-clock timeout so that
+    every shard makes the same decision.
+    """
+
+    def __init__(self, path: str, top_k: int, fuel: int):
+        from wasmtime import Config, Engine, Instance, Module, Store
+
+        config = Config()
+        config.consume_fuel = True
+        self.engine = Engine(config)
+        self.store = Store(self.engine)
+        instance = Instance(self.store, Module.from_file(self.engine, path), [])
+        exports = instance.exports(self.store)
+
+        self.memory = exports["memory"]
+        self.logits_bias = exports["logits_bias"]
+        self.top_k = top_k
+        self.fuel = fuel
+
+        # The buffer is allocated once and reused at every step
+        self.store.set_fuel(fuel)
+        self.ptr = exports["alloc"](self.store, 12 * top_k)
+
+    def bias(self, ids: List[int], logits: List[float]) -> Optional[List[float]]:
+        from wasmtime import Trap, WasmtimeError
+
+        k = len(ids)
+        try:
+            self.memory.write(
+                self.store, struct.pack(f"<{k}i{k}f", *ids, *logits), self.ptr
+            )
+            self.store.set_fuel(self.fuel)
+            if self.logits_bias(self.store, self.ptr, k) != 0:
+                return None
+            content = self.memory.read(self.store, self.ptr + 8 * k, self.ptr + 12 * k)
+        except (Trap, WasmtimeError) as e:
+            logger.warning(f"Logits plugin failed: {e}")
+            return None
+        return list(struct.unpack(f"<{k}f", content))
+
+    def __call__(self, scores: torch.Tensor) -> torch.Tensor:
+        k = min(self.top_k, scores.shape[-1])
+        top_logits, top_ids = torch.topk(scores, k, dim=-1)
+        biases = torch.zeros_like(top_logits)
+
+        for i, (ids, logits) in enumerate(zip(top_ids.tolist(), top_logits.tolist())):
+            bias = self.bias(ids, logits)
+            # The logits are left untouched when the plugin fails
+            if bias is not None:
+                biases[i] = torch.tensor(bias, dtype=biases.dtype, device=biases.device)
+
+        return scores.scatter_add(-1, top_ids, biases)
+
+
+@lru_cache(1)
+def get_logits_plugin() -> Optional[WasmLogitsPlugin]:
+    if LOGITS_PLUGIN_PATH is None:
+        return None
+    logger.info(f"Loading logits plugin {LOGITS_PLUGIN_PATH}")
+    return WasmLogitsPlugin(LOGITS_PLUGIN_PATH, LOGITS_PLUGIN_TOP_K, LOGITS_PLUGIN_FUEL)
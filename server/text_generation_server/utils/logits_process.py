@@ -8,7 +8,7 @@ from text_generation_server.pb.generate_pb2 import GrammarType
 from outlines.fsm.fsm import RegexFSM
 from outlines.fsm.json_schema import build_regex_from_object
 from functools import lru_cache
-from typing import List, Optional, DefaultDict
+from typing import List, Optional, DefaultDict, Tuple
 import time
 
 from transformers import (
@@ -22,6 +22,16 @@ from transformers import (
 
 mempool = torch.cuda.graph_pool_handle() if torch.cuda.is_available() else None
 
+# Time spent compiling grammars since the last call to `pop_grammar_compile_ns`
+_grammar_compile_ns = 0
+
+
+def pop_grammar_compile_ns() -> Optional[int]:
+    global _grammar_compile_ns
+    compile_ns = _grammar_compile_ns
+    _grammar_compile_ns = 0
+    return compile_ns if compile_ns > 0 else None
+
 
 class StaticWarper:
     def __init__(
@@ -179,6 +189,136 @@ class HeterogeneousFrequencyPenaltyLogitsProcessor(LogitsProcessor):
         return None
 
 
+class SuppressTokensLogitsProcessor(LogitsProcessor):
+    r"""
+    Token ids that are never sampled
+
+    Args:
+        suppressed_tokens (`List[int]`):
+            The token ids whose logits are set to `-inf`.
+    """
+
+    def __init__(self, suppressed_tokens: List[int], device: torch.device):
+        self.suppressed_tokens = torch.tensor(
+            suppressed_tokens, dtype=torch.long, device=device
+        )
+
+    def __call__(
+        self, input_ids: torch.LongTensor, scores: torch.FloatTensor
+    ) -> torch.FloatTensor:
+        scores[:, self.suppressed_tokens] = -math.inf
+        return scores
+
+
+class HeterogeneousSuppressTokensLogitsProcessor(LogitsProcessor):
+    r"""
+    Token ids that are never sampled
+
+    Args:
+        suppressed_tokens (`List[List[int]]`):
+            The token ids whose logits are set to `-inf`, for each request.
+    """
+
+    def __init__(self, suppressed_tokens: List[List[int]], device: torch.device):
+        self.suppressed_tokens = suppressed_tokens
+        self.device = device
+        # (row, token id) pairs of the suppressed logits
+        self.rows = torch.tensor(
+            [i for i, tokens in enumerate(suppressed_tokens) for _ in tokens],
+            dtype=torch.long,
+            device=device,
+        )
+        self.cols = torch.tensor(
+            [token for tokens in suppressed_tokens for token in tokens],
+            dtype=torch.long,
+            device=device,
+        )
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        scores[self.rows, self.cols] = -math.inf
+        return scores
+
+    def filter(self, indices):
+        suppressed_tokens = [self.suppressed_tokens[i] for i in indices]
+        if any(suppressed_tokens):
+            return HeterogeneousSuppressTokensLogitsProcessor(
+                suppressed_tokens, self.device
+            )
+        return None
+
+
+class LogitBiasLogitsProcessor(LogitsProcessor):
+    r"""
+    Bias added to the logits of token ids, as the OpenAI `logit_bias`
+
+    Args:
+        logit_bias (`Dict[int, float]`):
+            The bias of each token id, from -100 to 100.
+    """
+
+    def __init__(
+        self, logit_bias: Dict[int, float], dtype: torch.dtype, device: torch.device
+    ):
+        self.tokens = torch.tensor(
+            list(logit_bias.keys()), dtype=torch.long, device=device
+        )
+        self.bias = torch.tensor(list(logit_bias.values()), dtype=dtype, device=device)
+
+    def __call__(
+        self, input_ids: torch.LongTensor, scores: torch.FloatTensor
+    ) -> torch.FloatTensor:
+        scores[:, self.tokens] += self.bias.to(scores.dtype)
+        return scores
+
+
+class HeterogeneousLogitBiasLogitsProcessor(LogitsProcessor):
+    r"""
+    Bias added to the logits of token ids, as the OpenAI `logit_bias`
+
+    Args:
+        logit_bias (`List[Dict[int, float]]`):
+            The bias of each token id, from -100 to 100, for each request.
+    """
+
+    def __init__(
+        self,
+        logit_bias: List[Dict[int, float]],
+        dtype: torch.dtype,
+        device: torch.device,
+    ):
+        self.logit_bias = logit_bias
+        self.dtype = dtype
+        self.device = device
+        # (row, token id) pairs of the biased logits
+        self.rows = torch.tensor(
+            [i for i, bias in enumerate(logit_bias) for _ in bias],
+            dtype=torch.long,
+            device=device,
+        )
+        self.cols = torch.tensor(
+            [token for bias in logit_bias for token in bias],
+            dtype=torch.long,
+            device=device,
+        )
+        self.bias = torch.tensor(
+            [value for bias in logit_bias for value in bias.values()],
+            dtype=dtype,
+            device=device,
+        )
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        scores[self.rows, self.cols] += self.bias.to(scores.dtype)
+        return scores
+
+    def filter(self, indices):
+        logit_bias = [self.logit_bias[i] for i in indices]
+        if any(logit_bias):
+            return HeterogeneousLogitBiasLogitsProcessor(
+                logit_bias, self.dtype, self.device
+            )
+        return None
+
+
 class HeterogeneousTemperatureLogitsWarper:
     r"""
     [`LogitsWarper`] for temperature (exponential scaling output probability distribution).
@@ -210,6 +350,70 @@ class HeterogeneousTemperatureLogitsWarper:
         return None
 
 
+def scheduled_temperature(schedule: List[Tuple[int, float]], step: int) -> float:
+    """Temperature at `step` of a piecewise linear schedule of (token, temperature)"""
+    if step <= schedule[0][0]:
+        return schedule[0][1]
+    for (start, start_temperature), (end, end_temperature) in zip(
+        schedule, schedule[1:]
+    ):
+        if step < end:
+            progress = (step - start) / (end - start)
+            return start_temperature + (end_temperature - start_temperature) * progress
+    return schedule[-1][1]
+
+
+class HeterogeneousTemperatureScheduleLogitsWarper:
+    r"""
+    [`LogitsWarper`] for temperature schedules: the temperature of each sample follows a
+    piecewise linear schedule over its generated tokens. Samples without schedule keep
+    their logits.
+
+    Args:
+        schedules (`List[List[Tuple[int, float]]]`):
+            The (token, temperature) points of the schedule of each sample, empty if
+            none.
+        steps (`List[int]`):
+            The number of tokens already generated by each sample.
+    """
+
+    def __init__(
+        self,
+        schedules: List[List[Tuple[int, float]]],
+        steps: List[int],
+        dtype: torch.dtype,
+        device: torch.device,
+    ):
+        self.schedules = schedules
+        self.steps = steps
+        self.dtype = dtype
+        self.device = device
+
+    def __call__(self, scores: torch.Tensor, offset: int = 0) -> torch.Tensor:
+        # `offset` is the position of the speculated token being scored
+        temperatures = [
+            scheduled_temperature(schedule, step + offset) if schedule else 1.0
+            for schedule, step in zip(self.schedules, self.steps)
+        ]
+        temperature_tensor = torch.tensor(
+            temperatures, dtype=self.dtype, device=self.device
+        ).unsqueeze(1)
+        scores.div_(temperature_tensor)
+        return scores
+
+    def advance(self, accepted_ids: List[int]):
+        self.steps = [
+            step + accepted for step, accepted in zip(self.steps, accepted_ids)
+        ]
+
+    def filter(self, indices):
+        self.schedules = [self.schedules[i] for i in indices]
+        self.steps = [self.steps[i] for i in indices]
+        if any(self.schedules):
+            return self
+        return None
+
+
 class HeterogeneousTopPLogitsWarper(LogitsWarper):
     """
     [`LogitsWarper`] that performs top-p, i.e. restricting to top tokens summing to prob_cut_off <= prob_cut_off.
@@ -510,13 +714,16 @@ class GrammarLogitProcessor(LogitsProcessor):
     @staticmethod
     @lru_cache(maxsize=32, typed=True)
     def _cached_compile_fsm(grammar_type, schema, tokenizer):
-        start_time = time.time()
+        global _grammar_compile_ns
+        start_time = time.time_ns()
         if grammar_type == GrammarType.GRAMMAR_TYPE_JSON:
             schema = build_regex_from_object(schema)
         elif grammar_type == GrammarType.GRAMMAR_TYPE_REGEX:
             pass  # schema is already a regex just here for clarity
         fsm = RegexFSM(schema, tokenizer)
-        logger.debug(f"Compiled FSM in {time.time() - start_time:.2f}s")
+        compile_ns = time.time_ns() - start_time
+        _grammar_compile_ns += compile_ns
+        logger.debug(f"Compiled FSM in {compile_ns / 1e9:.2f}s")
         return fsm
 
     @staticmethod
@@ -1,4 +1,7 @@
+from typing import Optional
+
 SPECULATE = None
+SPECULATOR = None
 
 
 def get_speculate() -> int:
@@ -9,3 +12,13 @@ def get_speculate() -> int:
 def set_speculate(speculate: int):
     global SPECULATE
     SPECULATE = speculate
+
+
+def get_speculator() -> Optional[str]:
+    global SPECULATOR
+    return SPECULATOR
+
+
+def set_speculator(speculator: str):
+    global SPECULATOR
+    SPECULATOR = speculator
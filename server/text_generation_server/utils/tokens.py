@@ -1,5 +1,7 @@
 import re
-from typing import List, Optional, Tuple
+import time
+from collections import deque
+from typing import Dict, List, Optional, Tuple
 
 import math
 import torch
@@ -12,12 +14,19 @@ from text_generation_server.utils.logits_process import (
     HeterogeneousRepetitionPenaltyLogitsProcessor,
     HeterogeneousFrequencyPenaltyLogitsProcessor,
     HeterogeneousTemperatureLogitsWarper,
+    HeterogeneousTemperatureScheduleLogitsWarper,
     HeterogeneousTopKLogitsWarper,
     HeterogeneousTopPLogitsWarper,
     HeterogeneousTypicalLogitsWarper,
     HeterogeneousGrammarLogitProcessor,
+    HeterogeneousSuppressTokensLogitsProcessor,
+    HeterogeneousLogitBiasLogitsProcessor,
+    LogitBiasLogitsProcessor,
+    SuppressTokensLogitsProcessor,
+    scheduled_temperature,
     static_warper,
 )
+from text_generation_server.utils.logits_plugin import get_logits_plugin
 from text_generation_server.utils.watermark import WatermarkLogitsProcessor
 from transformers import PreTrainedTokenizerBase, RepetitionPenaltyLogitsProcessor
 
@@ -39,6 +48,9 @@ class NextTokenChooser:
         grammar: str = "",
         grammar_type: GrammarType = GrammarType.GRAMMAR_TYPE_NONE,
         fsm_grammar_state: int = 0,
+        suppressed_tokens: Optional[List[int]] = None,
+        temperature_schedule: Optional[List[Tuple[int, float]]] = None,
+        logit_bias: Optional[Dict[int, float]] = None,
     ):
         self.watermark_processor = (
             WatermarkLogitsProcessor(device=device) if watermark else None
@@ -53,6 +65,17 @@ class NextTokenChooser:
             if frequency_penalty and frequency_penalty != 0.0
             else None
         )
+        self.logit_bias_processor = (
+            LogitBiasLogitsProcessor(logit_bias, torch.float32, device)
+            if logit_bias
+            else None
+        )
+        self.suppress_processor = (
+            SuppressTokensLogitsProcessor(suppressed_tokens, device)
+            if suppressed_tokens
+            else None
+        )
+        self.plugin_processor = get_logits_plugin()
         self.grammar_processor = (
             GrammarLogitProcessor(tokenizer, device, grammar, grammar_type)
             if grammar != ""
@@ -73,7 +96,11 @@ class NextTokenChooser:
         else:
             self.static_warper = None
 
-        sampling = do_sample or has_warpers
+        # Number of tokens generated along the temperature schedule
+        self.temperature_schedule = temperature_schedule
+        self.temperature_step = 0
+
+        sampling = do_sample or has_warpers or bool(temperature_schedule)
 
         self.choice = Sampling(seed, device) if sampling else Greedy()
         self.fsm_grammar_state = fsm_grammar_state
@@ -86,8 +113,19 @@ class NextTokenChooser:
             scores = self.repetition_processor(input_ids, scores)
         if self.frequency_processor is not None:
             scores = self.frequency_processor(input_ids, scores)
+        if self.logit_bias_processor is not None:
+            scores = self.logit_bias_processor(input_ids, scores)
+        if self.suppress_processor is not None:
+            scores = self.suppress_processor(input_ids, scores)
+        if self.plugin_processor is not None:
+            scores = self.plugin_processor(scores)
         if self.grammar_processor is not None:
             scores = self.grammar_processor(scores, self.fsm_grammar_state)
+        if self.temperature_schedule:
+            scores = scores / scheduled_temperature(
+                self.temperature_schedule, self.temperature_step
+            )
+            self.temperature_step += 1
 
         if self.static_warper is None:
             next_logprob = torch.log_softmax(scores, -1)
@@ -126,6 +164,11 @@ class NextTokenChooser:
             tokenizer=tokenizer,
             grammar=pb.grammar,
             grammar_type=pb.grammar_type,
+            suppressed_tokens=list(pb.suppressed_tokens),
+            temperature_schedule=[
+                (point.token, point.temperature) for point in pb.temperature_schedule
+            ],
+            logit_bias=dict(pb.logit_bias),
         )
 
 
@@ -140,6 +183,34 @@ class StopSequenceCriteria:
         return False
 
 
+class JSONObjectCriteria:
+    """Detects the end of the first JSON object of the output"""
+
+    def __init__(self):
+        self.depth = 0
+        self.in_string = False
+        self.escaped = False
+
+    def __call__(self, last_output: str) -> bool:
+        for char in last_output:
+            if self.in_string:
+                if self.escaped:
+                    self.escaped = False
+                elif char == "\\":
+                    self.escaped = True
+                elif char == '"':
+                    self.in_string = False
+            elif char == '"':
+                self.in_string = True
+            elif char == "{":
+                self.depth += 1
+            elif char == "}":
+                self.depth -= 1
+                if self.depth == 0:
+                    return True
+        return False
+
+
 class StoppingCriteria:
     def __init__(
         self,
@@ -147,6 +218,10 @@ class StoppingCriteria:
         stop_sequence_criterias: List[StopSequenceCriteria],
         max_new_tokens: int = 20,
         ignore_eos_token: bool = False,
+        stop_after_tool_call: bool = False,
+        deadline: Optional[float] = None,
+        eos_probability_threshold: Optional[float] = None,
+        eos_probability_window: int = 1,
     ):
         self.eos_token_id = eos_token_id
         self.stop_sequence_criterias = stop_sequence_criterias
@@ -154,15 +229,50 @@ class StoppingCriteria:
         self.current_tokens = 0
         self.current_output = ""
         self.ignore_eos_token = ignore_eos_token
+        self.json_object_criteria = (
+            JSONObjectCriteria() if stop_after_tool_call else None
+        )
+        # Unix timestamp (in seconds) of the deadline of the request
+        self.deadline = deadline
+        self.eos_probability_threshold = eos_probability_threshold
+        self.eos_probabilities = deque(maxlen=max(eos_probability_window, 1))
+
+    @property
+    def tracks_eos_probability(self) -> bool:
+        return self.eos_probability_threshold is not None and self.eos_token_id is not None
 
-    def __call__(self, last_token: int, last_output: str) -> Tuple[bool, Optional[str]]:
+    def __call__(
+        self,
+        last_token: int,
+        last_output: str,
+        eos_logprob: Optional[float] = None,
+    ) -> Tuple[bool, Optional[str]]:
         self.current_tokens += 1
         if self.current_tokens >= self.max_new_tokens:
             return True, FinishReason.FINISH_REASON_LENGTH
 
+        # The latency budget of the request is spent: stop at the boundary
+        if self.deadline is not None and time.time() >= self.deadline:
+            return True, FinishReason.FINISH_REASON_LENGTH
+
         if not self.ignore_eos_token and last_token == self.eos_token_id:
             return True, FinishReason.FINISH_REASON_EOS_TOKEN
 
+        # The model keeps writing although it would rather end the sequence
+        if self.tracks_eos_probability and eos_logprob is not None:
+            self.eos_probabilities.append(math.exp(eos_logprob))
+            if (
+                len(self.eos_probabilities) == self.eos_probabilities.maxlen
+                and sum(self.eos_probabilities) > self.eos_probability_threshold
+            ):
+                return True, FinishReason.FINISH_REASON_EOS_PROBABILITY
+
+        # The tool call is complete: the model is done
+        if self.json_object_criteria is not None and self.json_object_criteria(
+            last_output
+        ):
+            return True, FinishReason.FINISH_REASON_EOS_TOKEN
+
         if self.stop_sequence_criterias:
             self.current_output += last_output
             # There is no need to keep an output that is too long
@@ -189,6 +299,14 @@ class StoppingCriteria:
             stop_sequence_criterias,
             pb.max_new_tokens,
             pb.ignore_eos_token,
+            pb.stop_after_tool_call,
+            pb.deadline / 1000 if pb.HasField("deadline") else None,
+            (
+                pb.eos_probability_threshold
+                if pb.HasField("eos_probability_threshold")
+                else None
+            ),
+            pb.eos_probability_window,
         )
 
 
@@ -234,6 +352,10 @@ class HeterogeneousNextTokenChooser:
         grammars: List[str],
         grammar_types: List[int],
         fsm_grammar_states=List[int],
+        suppressed_tokens: Optional[List[List[int]]] = None,
+        temperature_schedules: Optional[List[List[Tuple[int, float]]]] = None,
+        temperature_steps: Optional[List[int]] = None,
+        logit_bias: Optional[List[Dict[int, float]]] = None,
     ):
         warpers = []
 
@@ -265,6 +387,20 @@ class HeterogeneousNextTokenChooser:
             else None
         )
 
+        self.logit_bias_processor = (
+            HeterogeneousLogitBiasLogitsProcessor(logit_bias, dtype, device)
+            if logit_bias and any(logit_bias)
+            else None
+        )
+
+        self.suppress_processor = (
+            HeterogeneousSuppressTokensLogitsProcessor(suppressed_tokens, device)
+            if suppressed_tokens and any(suppressed_tokens)
+            else None
+        )
+
+        self.plugin_processor = get_logits_plugin()
+
         self.grammar_processor = (
             HeterogeneousGrammarLogitProcessor(
                 tokenizer, device, grammars, grammar_types
@@ -273,6 +409,26 @@ class HeterogeneousNextTokenChooser:
             else None
         )
 
+        if temperature_schedules and any(temperature_schedules):
+            do_sample = [
+                sample or bool(schedule)
+                for schedule, sample in zip(temperature_schedules, do_sample)
+            ]
+            self.temperature_schedule_processor = (
+                HeterogeneousTemperatureScheduleLogitsWarper(
+                    temperature_schedules,
+                    (
+                        temperature_steps
+                        if temperature_steps
+                        else [0] * len(temperature_schedules)
+                    ),
+                    dtype,
+                    device,
+                )
+            )
+        else:
+            self.temperature_schedule_processor = None
+
         if any([x != 1.0 for x in temperature]):
             do_sample = [
                 sample or x != 1.0 for x, sample in zip(temperature, do_sample)
@@ -337,8 +493,16 @@ class HeterogeneousNextTokenChooser:
                 _scores = self.repetition_processor(input_ids, _scores)
             if self.frequency_processor is not None:
                 _scores = self.frequency_processor(input_ids, _scores)
+            if self.logit_bias_processor is not None:
+                _scores = self.logit_bias_processor(input_ids, _scores)
+            if self.suppress_processor is not None:
+                _scores = self.suppress_processor(input_ids, _scores)
+            if self.plugin_processor is not None:
+                _scores = self.plugin_processor(_scores)
             if self.grammar_processor is not None:
                 _scores = self.grammar_processor(_scores, self.fsm_grammar_states)
+            if self.temperature_schedule_processor is not None:
+                _scores = self.temperature_schedule_processor(_scores, j)
             for warper in self.warpers:
                 _scores = warper(input_ids, _scores)
             _next_ids = self.choice(_scores)
@@ -384,6 +548,9 @@ class HeterogeneousNextTokenChooser:
 
         next_logprobs = torch.gather(logprobs, 1, next_ids.view(-1, 1)).view(-1)
 
+        if self.temperature_schedule_processor is not None:
+            self.temperature_schedule_processor.advance(accepted_ids.tolist())
+
         if speculate > 0:
             if speculative_scores is not None:
                 # Medusa provided some scores
@@ -398,6 +565,13 @@ class HeterogeneousNextTokenChooser:
 
         return next_ids, next_logprobs, alllogprobs, accepted_ids, speculative_ids
 
+    @property
+    def temperature_steps(self) -> List[int]:
+        """Tokens generated along the temperature schedules, kept on concatenation"""
+        if self.temperature_schedule_processor is None:
+            return [0] * len(self.seeds)
+        return self.temperature_schedule_processor.steps
+
     def advance_grammar(self, next_ids: List[int]):
         if self.grammar_processor is not None:
             other_new_states = self.grammar_processor.advance_batch(
@@ -427,9 +601,20 @@ class HeterogeneousNextTokenChooser:
         if self.frequency_processor is not None:
             self.frequency_processor = self.frequency_processor.filter(indices)
 
+        if self.logit_bias_processor is not None:
+            self.logit_bias_processor = self.logit_bias_processor.filter(indices)
+
+        if self.suppress_processor is not None:
+            self.suppress_processor = self.suppress_processor.filter(indices)
+
         if self.grammar_processor is not None:
             self.grammar_processor = self.grammar_processor.filter(indices)
 
+        if self.temperature_schedule_processor is not None:
+            self.temperature_schedule_processor = (
+                self.temperature_schedule_processor.filter(indices)
+            )
+
         filtered_warpers = []
         for warper in self.warpers:
             filtered_warper = warper.filter(indices)
@@ -467,6 +652,7 @@ class HeterogeneousNextTokenChooser:
         device: torch.device,
         tokenizer: PreTrainedTokenizerBase,
         fsm_grammar_states: Optional[List[int]] = None,
+        temperature_steps: Optional[List[int]] = None,
     ) -> "HeterogeneousNextTokenChooser":
         return HeterogeneousNextTokenChooser(
             watermark=[pb_.watermark for pb_ in pb],
@@ -486,6 +672,13 @@ class HeterogeneousNextTokenChooser:
             fsm_grammar_states=(
                 fsm_grammar_states if fsm_grammar_states else [0] * len(pb)
             ),
+            suppressed_tokens=[list(pb_.suppressed_tokens) for pb_ in pb],
+            temperature_schedules=[
+                [(point.token, point.temperature) for point in pb_.temperature_schedule]
+                for pb_ in pb
+            ],
+            temperature_steps=temperature_steps,
+            logit_bias=[dict(pb_.logit_bias) for pb_ in pb],
         )
 
 
@@ -629,3 +822,16 @@ def batch_top_tokens(
         batch_top_token_logprobs.append(row_top_token_logprobs)
 
     return batch_top_token_ids, batch_top_token_logprobs
+
+
+def prefill_top_tokens(
+    prefill_logprobs: torch.Tensor, top_n_tokens: int
+) -> Tuple[List[List[int]], List[List[float]]]:
+    """Find the top n most likely tokens of each prompt position.
+
+    `prefill_logprobs` holds the log probabilities predicted after each prompt token
+    but the last one. The first prompt token has no prediction, and no top tokens.
+    """
+    top_n_tokens = min(top_n_tokens, prefill_logprobs.size(-1))
+    top_k = torch.topk(prefill_logprobs, k=top_n_tokens, dim=-1, sorted=True)
+    return [[]] + top_k.indices.tolist(), [[]] + top_k.values.tolist()